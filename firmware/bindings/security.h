@@ -0,0 +1,2 @@
+#include "esp_flash_encrypt.h"
+#include "esp_secure_boot.h"
@@ -0,0 +1,6 @@
+#include "sdkconfig.h"
+
+// only the S3's sdkconfig turns the ULP-FSM on, without it there's no header
+#if CONFIG_ULP_COPROC_TYPE_FSM
+#include "ulp.h"
+#endif
@@ -1,3 +1,131 @@
+use std::{
+    env, fs,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Crates whose versions `device::inventory()` reports, with the feature
+/// an optional one comes with.
+const REPORTED: &[(&str, Option<&str>)] = &[
+    ("esp-idf-svc", None),
+    ("esp-idf-hal", None),
+    ("rustls", None),
+    ("ring", None),
+    ("webpki-roots", None),
+    ("tokio", None),
+    ("reqwest", Some("HTTP_REQWEST")),
+    ("rumqttc", Some("MQTT")),
+];
+
 fn main() {
     embuild::espidf::sysenv::output();
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| {
+            let feature = name.strip_prefix("CARGO_FEATURE_")?;
+            Some(feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=FIRMWARE_FEATURES={}", features.join(","));
+
+    // the versions the lock file settled on, which lists the optional
+    // dependencies left out as well
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    println!("cargo:rerun-if-changed=build.rs");
+    let lock = fs::read_to_string("Cargo.lock").unwrap_or_default();
+    let mut crates = Vec::new();
+    let mut name = "";
+    for line in lock.lines() {
+        if let Some(value) = line.strip_prefix("name = ") {
+            name = value.trim_matches('"');
+        } else if let Some(version) = line.strip_prefix("version = ") {
+            let built = REPORTED.iter().any(|(reported, feature)| {
+                *reported == name
+                    && feature.map_or(true, |feature| {
+                        env::var_os(format!("CARGO_FEATURE_{feature}")).is_some()
+                    })
+            });
+            if built {
+                crates.push(format!("{name} {}", version.trim_matches('"')));
+            }
+        }
+    }
+    println!("cargo:rustc-env=FIRMWARE_CRATES={}", crates.join(";"));
+
+    git();
+    built();
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    let version = output(&rustc, &["-V"]).unwrap_or_else(|| "unknown".into());
+    println!("cargo:rustc-env=FIRMWARE_RUSTC={version}");
+}
+
+/// The commit built and whether the tree had changes on top of it, for
+/// `buildinfo`. Outside a checkout both are unknown rather than an error.
+fn git() {
+    let hash = output("git", &["rev-parse", "--short=10", "HEAD"]);
+    let dirty = output("git", &["status", "--porcelain", "--untracked-files=no"])
+        .map(|status| !status.is_empty());
+    println!(
+        "cargo:rustc-env=FIRMWARE_GIT={}",
+        hash.as_deref().unwrap_or("unknown")
+    );
+    println!("cargo:rustc-env=FIRMWARE_DIRTY={}", dirty.unwrap_or(false));
+
+    // a new commit moves HEAD or the branch it's on, staging moves the
+    // index, and an edit that isn't staged yet only shows in the sources
+    println!("cargo:rerun-if-changed=src");
+    let mut watched = vec!["HEAD".to_owned(), "index".to_owned()];
+    watched.extend(output("git", &["symbolic-ref", "-q", "HEAD"]));
+    for path in watched {
+        if let Some(path) = output("git", &["rev-parse", "--git-path", &path]) {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+}
+
+/// The build time in UTC, from `SOURCE_DATE_EPOCH` for a reproducible
+/// build, as the date and the time the app descriptor has fields for.
+fn built() {
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let secs = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs())
+        });
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+    // days to a civil date, Howard Hinnant's days_from_civil run backwards
+    let days = days as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    println!("cargo:rustc-env=FIRMWARE_BUILD_DATE={year:04}-{month:02}-{day:02}");
+    println!(
+        "cargo:rustc-env=FIRMWARE_BUILD_TIME={:02}:{:02}:{:02}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    );
+}
+
+/// The trimmed output of a command that ran and succeeded.
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_owned())
 }
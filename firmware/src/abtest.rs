@@ -0,0 +1,178 @@
+//! The `ab` console command, for telling a TLS failure from a network or
+//! server one: polls of an `http://` or `https://` url go out over the
+//! scheme picked here instead, or over each in turn, and every poll is
+//! counted against the scheme it used. When plain http keeps working where
+//! https fails, with the same host, path and network, it's the TLS side.
+//!
+//! Only the scheme changes; an explicit port stays as written, so the
+//! endpoint has to serve both on their default ports.
+
+use crate::{console, heap};
+use anyhow::{bail, Result};
+use std::{
+    borrow::Cow,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+const COMMAND: &str = "ab";
+const USAGE: &str = "ab [http|https|alternate|off|reset]";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    Off,
+    Http,
+    Https,
+    Alternate,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheme {
+    Http,
+    Https,
+}
+
+impl Scheme {
+    fn prefix(self) -> &'static str {
+        match self {
+            Self::Http => "http://",
+            Self::Https => "https://",
+        }
+    }
+}
+
+struct Stats {
+    runs: u32,
+    failures: u32,
+    total: Duration,
+    slowest: Duration,
+    /// The least free heap right after one of the polls.
+    lowest_heap: Option<usize>,
+    last_error: Option<String>,
+}
+
+impl Stats {
+    const fn new() -> Self {
+        Self {
+            runs: 0,
+            failures: 0,
+            total: Duration::ZERO,
+            slowest: Duration::ZERO,
+            lowest_heap: None,
+            last_error: None,
+        }
+    }
+}
+
+struct State {
+    mode: Mode,
+    http: Stats,
+    https: Stats,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    mode: Mode::Off,
+    http: Stats::new(),
+    https: Stats::new(),
+});
+/// Which one `alternate` uses next.
+static NEXT_HTTPS: AtomicBool = AtomicBool::new(false);
+
+pub fn start() {
+    console::register(console::Command {
+        name: COMMAND,
+        usage: USAGE,
+        summary: "poll over http or https instead, and compare the two",
+        run: command,
+    });
+}
+
+fn command(_: &console::Console, args: &[&str]) -> Result<String> {
+    let mode = match args {
+        [] => return Ok(report()),
+        ["http"] => Mode::Http,
+        ["https"] => Mode::Https,
+        ["alternate"] => Mode::Alternate,
+        ["off"] => Mode::Off,
+        ["reset"] => {
+            let mut state = STATE.lock().unwrap();
+            state.http = Stats::new();
+            state.https = Stats::new();
+            return Ok(String::from("ab stats cleared"));
+        }
+        _ => bail!("usage: {USAGE}"),
+    };
+    STATE.lock().unwrap().mode = mode;
+    log::info!("ab: polls {mode:?}");
+    Ok(format!("polls now {mode:?}"))
+}
+
+/// Where a poll of `url` goes, and the scheme to record it under; `None`
+/// with `ab` off or a url of another scheme.
+pub fn target(url: &str) -> (Cow<'_, str>, Option<Scheme>) {
+    let rest = match (url.strip_prefix("http://"), url.strip_prefix("https://")) {
+        (Some(rest), _) | (_, Some(rest)) => rest,
+        _ => return (Cow::Borrowed(url), None),
+    };
+    let scheme = match STATE.lock().unwrap().mode {
+        Mode::Off => return (Cow::Borrowed(url), None),
+        Mode::Http => Scheme::Http,
+        Mode::Https => Scheme::Https,
+        Mode::Alternate => {
+            if NEXT_HTTPS.fetch_xor(true, Ordering::Relaxed) {
+                Scheme::Https
+            } else {
+                Scheme::Http
+            }
+        }
+    };
+    (
+        Cow::Owned(format!("{}{rest}", scheme.prefix())),
+        Some(scheme),
+    )
+}
+
+/// Counts a poll that went out over `scheme`.
+pub fn record(scheme: Scheme, result: &Result<()>, took: Duration) {
+    let free = heap::free();
+    let mut state = STATE.lock().unwrap();
+    let stats = match scheme {
+        Scheme::Http => &mut state.http,
+        Scheme::Https => &mut state.https,
+    };
+    stats.runs += 1;
+    stats.total += took;
+    stats.slowest = stats.slowest.max(took);
+    stats.lowest_heap = Some(stats.lowest_heap.map_or(free, |lowest| lowest.min(free)));
+    if let Err(err) = result {
+        stats.failures += 1;
+        stats.last_error = Some(format!("{err:#}"));
+    }
+}
+
+fn report() -> String {
+    let state = STATE.lock().unwrap();
+    let mut report = format!("mode {:?}", state.mode);
+    for (name, stats) in [("http", &state.http), ("https", &state.https)] {
+        let _ = write!(report, "\n{name}: {} polls", stats.runs);
+        if stats.runs == 0 {
+            continue;
+        }
+        let _ = write!(
+            report,
+            ", {} failed, {} ms average, {} ms slowest, {} bytes least free heap",
+            stats.failures,
+            stats.total.as_millis() / u128::from(stats.runs),
+            stats.slowest.as_millis(),
+            stats.lowest_heap.unwrap_or_default()
+        );
+        if let Some(err) = &stats.last_error {
+            let _ = write!(report, "\n  last error: {err}");
+        }
+    }
+    report
+}
@@ -0,0 +1,213 @@
+//! A PIR motion sensor or a light dependent resistor on GPIO3, as
+//! `ambient_sensor` says, the same pin on every board. Motion is published
+//! as `Motion`, at most once per `MOTION_HOLDOFF` however long someone
+//! stays in view, and light crossing `ambient_dark` as `LightChanged`.
+//! The jobs `ambient_trigger` names take those as triggers, see
+//! `Job::on()`: the polls for `fetch`, the telemetry reports for
+//! `telemetry`, so a change is reported when it happens rather than at the
+//! next interval.
+//!
+//! The LDR reads through ADC1, which the battery monitor holds in builds
+//! with `battery`; those have the PIR only.
+
+use crate::{
+    config::Config,
+    events::{self, Event},
+    telemetry,
+};
+use anyhow::{bail, Context, Result};
+use esp_idf_hal::{
+    adc::{
+        attenuation::DB_11,
+        oneshot::{
+            config::{AdcChannelConfig, Calibration},
+            AdcChannelDriver, AdcDriver,
+        },
+        ADC1,
+    },
+    gpio::{Gpio3, InterruptType, PinDriver, Pull},
+    task::notification::Notification,
+};
+use std::{
+    num::NonZeroU32,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+/// A second motion this soon after the last isn't published again.
+const MOTION_HOLDOFF: Duration = Duration::from_secs(30);
+const LIGHT_INTERVAL: Duration = Duration::from_secs(2);
+/// Readings averaged per light sample.
+const READINGS: u32 = 8;
+/// Light has to be this far past `ambient_dark` to count as changed, so a
+/// level sitting on the threshold doesn't flap.
+const HYSTERESIS_MV: u16 = 50;
+const STACK_SIZE: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Sensor {
+    Pir,
+    Ldr,
+}
+
+struct Settings {
+    sensor: Option<Sensor>,
+    fetch: bool,
+    telemetry: bool,
+    dark_mv: u16,
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+static MOTIONS: AtomicU32 = AtomicU32::new(0);
+
+pub struct Pins {
+    pub sense: Gpio3,
+}
+
+pub fn configure(config: &Config) -> Result<()> {
+    let sensor = match config.ambient_sensor.as_str() {
+        "" => None,
+        "pir" => Some(Sensor::Pir),
+        "ldr" => Some(Sensor::Ldr),
+        other => bail!("ambient_sensor {other} isn't pir or ldr"),
+    };
+    let (mut fetch, mut telemetry) = (false, false);
+    for trigger in config.ambient_trigger.split(',').map(str::trim) {
+        match trigger {
+            "fetch" => fetch = true,
+            "telemetry" => telemetry = true,
+            "" => {}
+            other => bail!("ambient_trigger {other} isn't fetch or telemetry"),
+        }
+    }
+    let _ = SETTINGS.set(Settings {
+        sensor,
+        fetch,
+        telemetry,
+        dark_mv: config.ambient_dark,
+    });
+    Ok(())
+}
+
+/// For `Job::on()` of the polls: an ambient event, when `ambient_trigger`
+/// has `fetch`.
+pub fn fetch_trigger(event: &Event) -> bool {
+    ambient(event) && SETTINGS.get().is_some_and(|settings| settings.fetch)
+}
+
+/// For `Job::on()` of the telemetry reports, with `telemetry`.
+pub fn telemetry_trigger(event: &Event) -> bool {
+    ambient(event) && SETTINGS.get().is_some_and(|settings| settings.telemetry)
+}
+
+fn ambient(event: &Event) -> bool {
+    matches!(event, Event::Motion | Event::LightChanged { .. })
+}
+
+/// Watches the sensor on its own thread once the config says which; `adc`
+/// is `None` when something else has ADC1.
+pub fn start(adc: Option<ADC1>, pins: Pins) -> Result<()> {
+    std::thread::Builder::new()
+        .name("ambient".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            if let Err(err) = run(adc, pins) {
+                log::error!("ambient sensor stopped: {err:#}");
+            }
+        })
+        .context("couldn't spawn ambient sensor")?;
+    Ok(())
+}
+
+fn run(adc: Option<ADC1>, pins: Pins) -> Result<()> {
+    let settings = loop {
+        match SETTINGS.get() {
+            Some(settings) => break settings,
+            None => std::thread::sleep(Duration::from_secs(1)),
+        }
+    };
+    match (settings.sensor, adc) {
+        (None, _) => Ok(()),
+        (Some(Sensor::Pir), _) => watch_motion(pins.sense),
+        (Some(Sensor::Ldr), Some(adc)) => watch_light(adc, pins.sense, settings.dark_mv),
+        (Some(Sensor::Ldr), None) => bail!("the ldr needs ADC1, which the battery monitor has"),
+    }
+}
+
+/// The PIR's output goes high while it sees motion; the edge interrupt
+/// wakes the thread, which sleeps otherwise.
+fn watch_motion(pin: Gpio3) -> Result<()> {
+    let mut pin = PinDriver::input(pin)?;
+    pin.set_pull(Pull::Down)?;
+    pin.set_interrupt_type(InterruptType::PosEdge)?;
+    let notification = Notification::new();
+    let notifier = notification.notifier();
+    // SAFETY: the callback only notifies a task, which is ISR safe
+    unsafe {
+        pin.subscribe(move || {
+            notifier.notify_and_yield(NonZeroU32::MIN);
+        })?;
+    }
+    log::info!("ambient: watching a pir");
+
+    let mut last: Option<Instant> = None;
+    loop {
+        // interrupts disarm themselves after firing
+        pin.enable_interrupt()?;
+        let _ = notification.wait(esp_idf_hal::delay::BLOCK);
+        if last.is_some_and(|last| last.elapsed() < MOTION_HOLDOFF) {
+            continue;
+        }
+        last = Some(Instant::now());
+        let motions = MOTIONS.fetch_add(1, Ordering::Relaxed) + 1;
+        log::info!("ambient: motion");
+        telemetry::set("motions", motions);
+        events::publish(Event::Motion);
+    }
+}
+
+fn watch_light(adc: ADC1, pin: Gpio3, dark_mv: u16) -> Result<()> {
+    let mut channel = AdcChannelDriver::new(
+        AdcDriver::new(adc)?,
+        pin,
+        &AdcChannelConfig {
+            attenuation: DB_11,
+            calibration: Calibration::Curve,
+            ..Default::default()
+        },
+    )?;
+    log::info!("ambient: watching an ldr, dark below {dark_mv} mV");
+
+    let mut dark: Option<bool> = None;
+    loop {
+        let mut total = 0;
+        for _ in 0..READINGS {
+            total += u32::from(channel.read()?);
+        }
+        let millivolts = (total / READINGS) as u16;
+        telemetry::set("light_mv", millivolts);
+
+        let now_dark = match dark {
+            Some(true) => millivolts < dark_mv.saturating_add(HYSTERESIS_MV),
+            Some(false) => millivolts.saturating_add(HYSTERESIS_MV) < dark_mv,
+            None => millivolts < dark_mv,
+        };
+        // the first reading is where the light is, not a change
+        if dark.is_some_and(|dark| dark != now_dark) {
+            log::info!(
+                "ambient: {} at {millivolts} mV",
+                if now_dark { "dark" } else { "light" }
+            );
+            events::publish(Event::LightChanged { dark: now_dark });
+        }
+        if dark != Some(now_dark) {
+            telemetry::set("dark", now_dark);
+            dark = Some(now_dark);
+        }
+
+        std::thread::sleep(LIGHT_INTERVAL);
+    }
+}
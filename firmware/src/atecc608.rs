@@ -0,0 +1,307 @@
+//! ATECC608A on the peripheral bus holding the mutual TLS client key. The
+//! key is generated inside the chip and never leaves it: rustls hands the
+//! handshake transcript to `Key`, the chip signs its digest, and only the
+//! certificate is kept in the config.
+//!
+//! The slot has to hold a P-256 private key with the config and data zones
+//! locked, the way the chip is provisioned for any TLS use. `atecc` on the
+//! console prints its public key to have the certificate issued for.
+
+use crate::{config::Config, i2c};
+use anyhow::{bail, ensure, Context, Result};
+use base64::Engine;
+use esp_idf_hal::{
+    delay::{FreeRtos, BLOCK},
+    i2c::I2cDriver,
+};
+use rustls::{
+    client::ResolvesClientCert,
+    pki_types::CertificateDer,
+    sign::{CertifiedKey, Signer, SigningKey, SingleCertAndKey},
+    SignatureAlgorithm, SignatureScheme,
+};
+use std::sync::{Arc, OnceLock};
+
+const ADDRESS: u8 = 0x60;
+/// Word addresses, the first byte of every write.
+const WORD_SLEEP: u8 = 0x01;
+const WORD_COMMAND: u8 = 0x03;
+
+const OP_NONCE: u8 = 0x16;
+const OP_GENKEY: u8 = 0x40;
+const OP_SIGN: u8 = 0x41;
+/// Nonce: the 32 bytes go into TempKey as they are.
+const NONCE_PASSTHROUGH: u8 = 0x03;
+/// Sign: the message is the digest in TempKey.
+const SIGN_EXTERNAL: u8 = 0x80;
+/// GenKey: the public key of the private key already in the slot.
+const GENKEY_PUBLIC: u8 = 0x00;
+
+/// Worst case execution times from the datasheet, in milliseconds.
+const NONCE_MS: u32 = 7;
+const SIGN_MS: u32 = 115;
+const GENKEY_MS: u32 = 115;
+/// tWHI is 1.5 ms.
+const WAKE_MS: u32 = 2;
+const WAKE_RESPONSE: [u8; 4] = [0x04, 0x11, 0x33, 0x43];
+
+const SIGNATURE_LEN: usize = 64;
+const PUBLIC_KEY_LEN: usize = 64;
+
+static CLIENT_CERT: OnceLock<Arc<SingleCertAndKey>> = OnceLock::new();
+static SLOT: OnceLock<u16> = OnceLock::new();
+
+/// Checks the chip, pairs its key with the configured certificate for
+/// `client_cert()`, and registers the `atecc` command. Without a
+/// certificate the TLS clients carry on without client auth.
+pub fn configure(config: &Config) {
+    let _ = SLOT.set(config.atecc_slot);
+    crate::console::register(crate::console::Command {
+        name: "atecc",
+        usage: "atecc",
+        summary: "the secure element's tls public key, to issue a certificate for",
+        run: |_, _| {
+            let slot = SLOT.get().copied().unwrap_or_default();
+            let key: String = public_key(slot)?
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect();
+            Ok(format!("slot {slot} public key 04{key}"))
+        },
+    });
+
+    if config.client_cert.is_empty() {
+        log::info!("atecc608: no client_cert, tls clients won't authenticate");
+        return;
+    }
+    match certified_key(config) {
+        Ok(key) => {
+            log::info!(
+                "atecc608: client certificate from slot {}",
+                config.atecc_slot
+            );
+            let _ = CLIENT_CERT.set(Arc::new(SingleCertAndKey::from(key)));
+        }
+        Err(err) => log::warn!("atecc608: no client auth: {err:#}"),
+    }
+}
+
+/// The resolver for `tls::client_config_with()`, once `configure()` found
+/// a certificate and a key that match.
+pub fn client_cert() -> Option<Arc<dyn ResolvesClientCert>> {
+    CLIENT_CERT
+        .get()
+        .map(|resolver| resolver.clone() as Arc<dyn ResolvesClientCert>)
+}
+
+fn certified_key(config: &Config) -> Result<CertifiedKey> {
+    let cert = base64::engine::general_purpose::STANDARD
+        .decode(config.client_cert.trim())
+        .context("client_cert isn't base64")?;
+    let public_key = public_key(config.atecc_slot)?;
+
+    // the uncompressed point sits in the certificate's SubjectPublicKeyInfo
+    let mut point = vec![0x04];
+    point.extend_from_slice(&public_key);
+    ensure!(
+        cert.windows(point.len()).any(|window| window == point),
+        "client_cert isn't for the key in slot {}",
+        config.atecc_slot
+    );
+
+    Ok(CertifiedKey::new(
+        vec![CertificateDer::from(cert)],
+        Arc::new(Key {
+            slot: config.atecc_slot,
+        }),
+    ))
+}
+
+/// The private key in `slot`, as rustls sees it.
+#[derive(Debug)]
+struct Key {
+    slot: u16,
+}
+
+impl SigningKey for Key {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+        offered
+            .contains(&SignatureScheme::ECDSA_NISTP256_SHA256)
+            .then(|| Box::new(Key { slot: self.slot }) as Box<dyn Signer>)
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        SignatureAlgorithm::ECDSA
+    }
+}
+
+impl Signer for Key {
+    /// Holds the handshake for the ~125 ms of a wake, a nonce and a sign,
+    /// the chip's part on the blocking pool.
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, rustls::Error> {
+        let digest = ring::digest::digest(&ring::digest::SHA256, message);
+        let slot = self.slot;
+        crate::runtime::wait_blocking(move || sign(slot, digest.as_ref()))
+            .and_then(|signature| signature)
+            .map(|signature| der_signature(&signature))
+            .map_err(|err| rustls::Error::General(format!("atecc608 sign failed: {err:#}")))
+    }
+
+    fn scheme(&self) -> SignatureScheme {
+        SignatureScheme::ECDSA_NISTP256_SHA256
+    }
+}
+
+fn public_key(slot: u16) -> Result<[u8; PUBLIC_KEY_LEN]> {
+    let mut key = [0; PUBLIC_KEY_LEN];
+    session(|bus| {
+        command(
+            bus,
+            OP_GENKEY,
+            GENKEY_PUBLIC,
+            slot,
+            &[],
+            GENKEY_MS,
+            &mut key,
+        )
+    })?;
+    Ok(key)
+}
+
+/// Signs a SHA-256 digest with the key in `slot`, returning R and S.
+fn sign(slot: u16, digest: &[u8]) -> Result<[u8; SIGNATURE_LEN]> {
+    let mut signature = [0; SIGNATURE_LEN];
+    session(|bus| {
+        // TempKey only lives until the chip sleeps, both go in one session
+        command(
+            bus,
+            OP_NONCE,
+            NONCE_PASSTHROUGH,
+            0,
+            digest,
+            NONCE_MS,
+            &mut [],
+        )?;
+        command(
+            bus,
+            OP_SIGN,
+            SIGN_EXTERNAL,
+            slot,
+            &[],
+            SIGN_MS,
+            &mut signature,
+        )
+    })?;
+    Ok(signature)
+}
+
+/// Wakes the chip, runs `f` and puts it back to sleep, well inside the
+/// watchdog's 1.3 s.
+fn session<T>(f: impl FnOnce(&mut I2cDriver<'static>) -> Result<T>) -> Result<T> {
+    i2c::with(|bus| {
+        // address 0 holds SDA low for the 60 us wake pulse, nothing acks it
+        let _ = bus.write(0x00, &[0x00], BLOCK);
+        FreeRtos::delay_ms(WAKE_MS);
+        let mut wake = [0; WAKE_RESPONSE.len()];
+        bus.read(ADDRESS, &mut wake, BLOCK)
+            .context("no atecc608 on the i2c bus")?;
+        ensure!(wake == WAKE_RESPONSE, "atecc608 woke with {wake:02x?}");
+
+        let result = f(bus);
+        if let Err(err) = bus.write(ADDRESS, &[WORD_SLEEP], BLOCK) {
+            log::warn!("atecc608 didn't go to sleep: {err}");
+        }
+        result
+    })
+}
+
+/// Sends one command and reads its response into `response`; an empty
+/// one expects just a success status.
+fn command(
+    bus: &mut I2cDriver<'static>,
+    opcode: u8,
+    param1: u8,
+    param2: u16,
+    data: &[u8],
+    exec_ms: u32,
+    response: &mut [u8],
+) -> Result<()> {
+    // count, opcode, param1, param2 and the CRC around the data
+    let mut packet = vec![WORD_COMMAND, (7 + data.len()) as u8, opcode, param1];
+    packet.extend_from_slice(&param2.to_le_bytes());
+    packet.extend_from_slice(data);
+    packet.extend_from_slice(&crc(&packet[1..]).to_le_bytes());
+    bus.write(ADDRESS, &packet, BLOCK)?;
+    FreeRtos::delay_ms(exec_ms);
+
+    let mut buf = vec![0; response.len().max(1) + 3];
+    bus.read(ADDRESS, &mut buf, BLOCK)
+        .with_context(|| format!("atecc608 command {opcode:#04x} didn't finish"))?;
+    let count = usize::from(buf[0]);
+    ensure!(
+        (4..=buf.len()).contains(&count),
+        "atecc608 response of {count} bytes"
+    );
+    let (body, checksum) = buf[..count].split_at(count - 2);
+    ensure!(
+        crc(body).to_le_bytes() == checksum,
+        "atecc608 response crc mismatch"
+    );
+
+    if count == 4 {
+        match buf[1] {
+            0x00 if response.is_empty() => return Ok(()),
+            0x00 => bail!("atecc608 command {opcode:#04x} returned no data"),
+            0x01 => bail!("atecc608 checkmac or verify miscompare"),
+            0x03 => bail!("atecc608 parse error, is slot {param2} a private key?"),
+            0x0f => bail!("atecc608 execution error, are the zones locked?"),
+            0xff => bail!("atecc608 saw a crc error"),
+            status => bail!("atecc608 status {status:#04x}"),
+        }
+    }
+    ensure!(
+        count == response.len() + 3,
+        "atecc608 response of {count} bytes"
+    );
+    response.copy_from_slice(&body[1..]);
+    Ok(())
+}
+
+/// CRC-16 with polynomial 0x8005 over the bits LSB first, as the chip
+/// computes it.
+fn crc(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        for bit in 0..8 {
+            let data_bit = (byte >> bit) & 1;
+            let crc_bit = (crc >> 15) as u8;
+            crc <<= 1;
+            if data_bit != crc_bit {
+                crc ^= 0x8005;
+            }
+        }
+    }
+    crc
+}
+
+/// R and S as the DER `ECDSA-Sig-Value` TLS carries.
+fn der_signature(raw: &[u8; SIGNATURE_LEN]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(SIGNATURE_LEN + 6);
+    for half in raw.chunks(SIGNATURE_LEN / 2) {
+        let start = half
+            .iter()
+            .position(|&byte| byte != 0)
+            .unwrap_or(half.len() - 1);
+        let value = &half[start..];
+        let pad = value[0] & 0x80 != 0;
+        body.push(0x02);
+        body.push((value.len() + usize::from(pad)) as u8);
+        if pad {
+            body.push(0);
+        }
+        body.extend_from_slice(value);
+    }
+    let mut der = vec![0x30, body.len() as u8];
+    der.extend_from_slice(&body);
+    der
+}
@@ -0,0 +1,448 @@
+//! Encrypted backups of what makes a device this device, for swapping a
+//! failed unit in the field: the `config`, `factory` and `oauth` NVS
+//! namespaces, which hold the settings, the provisioned id, WiFi and
+//! calibration, and the user's login, with the telemetry sample of the
+//! moment for the RMA record. `backup` on the serial console prints one
+//! as base64; `restore add` takes it back a line at a time, the console's
+//! lines being short, and `restore done` writes it and reboots. With
+//! `backup_secret` set the status server also has `POST /backup` and
+//! `POST /restore` for the raw blob.
+//!
+//! The blob is `MAGIC`, the PBKDF2 iterations, the salt and the nonce,
+//! then the JSON sealed with AES-256-GCM under a key PBKDF2-HMAC-SHA256
+//! derives from the passphrase, the header as associated data. What
+//! `identity` derives from the eFuse key or the MAC stays with the chip;
+//! a backend keying on it has to be told of the swap. The stats aren't
+//! written back, the new board counts from its own boot.
+
+use crate::{
+    config::{Config, Stored},
+    console, device, events, reboot, runtime,
+    secret::Secret,
+    security, telemetry,
+};
+use anyhow::{bail, ensure, Context, Result};
+use base64::Engine;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_sys::{self as sys, esp};
+use ring::{aead, pbkdf2, rand::SecureRandom};
+use serde_json::{json, Map, Value};
+use std::{
+    ffi::{CStr, CString},
+    fmt::Write,
+    num::NonZeroU32,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+const MAGIC: &[u8; 4] = b"DBK1";
+const FORMAT: u64 = 1;
+/// About a second of PBKDF2 on the chip; the blob says how many it took,
+/// so this can change without old backups becoming unreadable.
+const ITERATIONS: u32 = 100_000;
+/// More than that is a damaged header, not a stronger key.
+const MAX_ITERATIONS: u32 = 10_000_000;
+const SALT: usize = 16;
+const HEADER: usize = MAGIC.len() + 4 + SALT + aead::NONCE_LEN;
+/// The largest blob taken, config with a certificate chain and key fits
+/// several times over.
+pub const MAX_BLOB: usize = 32 * 1024;
+const MIN_PASSPHRASE: usize = 8;
+/// base64 per line of `backup`, short enough that `restore add` and the
+/// line fit the console's.
+const LINE: usize = 192;
+
+/// The namespaces backed up, and the keys in them left to the new board.
+const NAMESPACES: &[(&str, &[&str])] = &[
+    // the restore's own, see `write_namespace()`
+    ("config", &["journal"]),
+    // the new board's line may not have locked it yet
+    ("factory", &["locked"]),
+    ("oauth", &[]),
+];
+const PARTITION_NAME: &CStr = c"nvs";
+/// Lets the reply reach the terminal or the client before the reboot.
+const REBOOT_GRACE: Duration = Duration::from_secs(1);
+
+static STATE: OnceLock<(EspDefaultNvsPartition, Config)> = OnceLock::new();
+/// The base64 `restore add` has been given so far.
+static STAGED: Mutex<String> = Mutex::new(String::new());
+
+/// Registers `backup` and `restore`, before the console and the status
+/// server start. On the console they only answer on the UART, the TCP
+/// one has nothing but `console_password` in front of them.
+pub fn start(config: &Config, partition: EspDefaultNvsPartition) {
+    if STATE.set((partition, config.clone())).is_err() {
+        return;
+    }
+    console::register(console::Command {
+        name: "backup",
+        usage: "backup <passphrase>",
+        summary: "config, identity and stats encrypted as base64, for a device swap",
+        run: |console, args| {
+            ensure!(console.is_uart(), "backup is only on the serial console");
+            let blob = export(&args.join(" "))?;
+            let text = base64::engine::general_purpose::STANDARD.encode(&blob);
+            let mut reply = format!("backup of {}, {} bytes:", device::id(), blob.len());
+            for line in text.as_bytes().chunks(LINE) {
+                reply.push('\n');
+                reply.push_str(std::str::from_utf8(line)?);
+            }
+            Ok(reply)
+        },
+    });
+    console::register(console::Command {
+        name: "restore",
+        usage: "restore add <line> | done <passphrase> | clear",
+        summary: "takes a backup a line at a time, then writes it and reboots",
+        run: |console, args| {
+            ensure!(console.is_uart(), "restore is only on the serial console");
+            restore(args)
+        },
+    });
+}
+
+fn restore(args: &[&str]) -> Result<String> {
+    let mut staged = STAGED.lock().unwrap();
+    match args {
+        ["add", line] => {
+            ensure!(
+                staged.len() + line.len() <= MAX_BLOB * 4 / 3 + 4,
+                "that's more than a backup, restore clear to start over"
+            );
+            staged.push_str(line);
+            Ok(format!("{} characters so far", staged.len()))
+        }
+        ["done", passphrase @ ..] if !passphrase.is_empty() => {
+            let blob = base64::engine::general_purpose::STANDARD
+                .decode(staged.as_bytes())
+                .context("the lines added aren't base64, restore clear to start over")?;
+            let reply = import(&blob, &passphrase.join(" "))?;
+            staged.clear();
+            Ok(reply)
+        }
+        ["clear"] => {
+            staged.clear();
+            Ok(String::from("cleared"))
+        }
+        _ => bail!("usage: restore add <line> | done <passphrase> | clear"),
+    }
+}
+
+/// The device's backup, sealed under `passphrase`.
+pub fn export(passphrase: &str) -> Result<Vec<u8>> {
+    ensure!(
+        passphrase.len() >= MIN_PASSPHRASE,
+        "the passphrase needs at least {MIN_PASSPHRASE} characters"
+    );
+    let (partition, _) = STATE.get().context("backup not started")?;
+
+    let mut namespaces = Map::new();
+    for (namespace, kept) in NAMESPACES {
+        namespaces.insert(
+            namespace.to_string(),
+            read_namespace(partition.clone(), namespace, kept)
+                .with_context(|| format!("couldn't read the {namespace} namespace"))?
+                .into(),
+        );
+    }
+    let contents = json!({
+        "format": FORMAT,
+        "device": device::id(),
+        "firmware": device::firmware_version(),
+        "taken": events::state().time_synced.then(|| time::UtcDateTime::now().unix_timestamp()),
+        "nvs": namespaces,
+        "stats": telemetry::snapshot(),
+    });
+    let blob = seal(&serde_json::to_vec(&contents)?, passphrase)?;
+    log::warn!("backup of {} bytes exported", blob.len());
+    Ok(blob)
+}
+
+/// Writes the backup in `blob` over this device's namespaces and reboots.
+/// Nothing is written unless the whole backup opened and parsed. The
+/// config goes in through its journal, the next boot finishes it after a
+/// reset; the rest is mended by restoring again.
+pub fn import(blob: &[u8], passphrase: &str) -> Result<String> {
+    let (partition, config) = STATE.get().context("backup not started")?;
+    let contents: Value = serde_json::from_slice(&open(blob, passphrase)?)?;
+    ensure!(
+        contents["format"] == FORMAT,
+        "backup format {}, this firmware reads {FORMAT}",
+        contents["format"]
+    );
+    let namespaces = contents["nvs"]
+        .as_object()
+        .context("backup has no namespaces")?;
+    // the backup has the WiFi password and the config's secrets
+    security::ensure_secret_storage().context("not restoring a backup")?;
+
+    let mut restored = Vec::new();
+    for (namespace, kept) in NAMESPACES {
+        let Some(entries) = namespaces.get(*namespace).and_then(Value::as_object) else {
+            continue;
+        };
+        let entries = entries
+            .iter()
+            .filter(|(key, _)| !kept.contains(&key.as_str()))
+            .map(|(key, value)| Ok((key.clone(), entry(key, value)?)))
+            .collect::<Result<Vec<_>>>()?;
+        restored.push((namespace, kept, entries));
+    }
+    let mut written = 0;
+    for (namespace, kept, entries) in &restored {
+        write_namespace(partition.clone(), namespace, kept, entries)
+            .with_context(|| format!("couldn't restore the {namespace} namespace"))?;
+        written += entries.len();
+    }
+
+    let device = contents["device"].as_str().unwrap_or("?");
+    let firmware = contents["firmware"].as_str().unwrap_or("?");
+    log::warn!(
+        "restored the backup of {device}, its stats then: {}",
+        contents["stats"]
+    );
+    let mut reply = format!("restored {written} keys from the backup of {device}");
+    if firmware != device::firmware_version() {
+        let _ = write!(
+            reply,
+            ", taken on {firmware} and read as {} reads it",
+            device::firmware_version()
+        );
+    }
+    reply.push_str("; rebooting");
+
+    let config = config.clone();
+    runtime::spawn(async move {
+        tokio::time::sleep(REBOOT_GRACE).await;
+        reboot::restart(&config, "backup restored").await
+    });
+    Ok(reply)
+}
+
+fn key(passphrase: &str, salt: &[u8], iterations: NonZeroU32) -> Result<aead::LessSafeKey> {
+    let mut key = Secret::new([0; 32]);
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        passphrase.as_bytes(),
+        key.expose_mut(),
+    );
+    let key = aead::UnboundKey::new(&aead::AES_256_GCM, key.expose())
+        .map_err(|_| anyhow::anyhow!("couldn't make the backup key"))?;
+    Ok(aead::LessSafeKey::new(key))
+}
+
+fn seal(plaintext: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    let random = ring::rand::SystemRandom::new();
+    let mut salt = [0; SALT];
+    let mut nonce = [0; aead::NONCE_LEN];
+    random
+        .fill(&mut salt)
+        .and_then(|()| random.fill(&mut nonce))
+        .map_err(|_| anyhow::anyhow!("no randomness for the backup"))?;
+
+    let mut blob = Vec::with_capacity(HEADER + plaintext.len() + aead::AES_256_GCM.tag_len());
+    blob.extend_from_slice(MAGIC);
+    blob.extend_from_slice(&ITERATIONS.to_le_bytes());
+    blob.extend_from_slice(&salt);
+    blob.extend_from_slice(&nonce);
+    let mut sealed = plaintext.to_vec();
+    key(passphrase, &salt, NonZeroU32::new(ITERATIONS).unwrap())?
+        .seal_in_place_append_tag(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::from(&blob[..HEADER]),
+            &mut sealed,
+        )
+        .map_err(|_| anyhow::anyhow!("couldn't seal the backup"))?;
+    blob.append(&mut sealed);
+    ensure!(
+        blob.len() <= MAX_BLOB,
+        "backup of {} bytes is too large",
+        blob.len()
+    );
+    Ok(blob)
+}
+
+fn open(blob: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    ensure!(
+        blob.len() > HEADER && blob.starts_with(MAGIC),
+        "not a device backup"
+    );
+    let (header, sealed) = blob.split_at(HEADER);
+    let iterations = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let iterations = NonZeroU32::new(iterations)
+        .filter(|iterations| iterations.get() <= MAX_ITERATIONS)
+        .context("backup header is damaged")?;
+    let salt = &header[8..8 + SALT];
+    let nonce = aead::Nonce::try_assume_unique_for_key(&header[8 + SALT..])
+        .map_err(|_| anyhow::anyhow!("backup header is damaged"))?;
+
+    let mut plaintext = sealed.to_vec();
+    let len = key(passphrase, salt, iterations)?
+        .open_in_place(nonce, aead::Aad::from(header), &mut plaintext)
+        .map_err(|_| anyhow::anyhow!("wrong passphrase, or the backup is damaged"))?
+        .len();
+    plaintext.truncate(len);
+    Ok(plaintext)
+}
+
+/// The keys in `namespace` and their NVS types.
+fn keys_of(namespace: &str) -> Result<Vec<(String, sys::nvs_type_t)>> {
+    let namespace = CString::new(namespace)?;
+    let mut keys = Vec::new();
+    let mut iterator: sys::nvs_iterator_t = std::ptr::null_mut();
+    let mut found = unsafe {
+        sys::nvs_entry_find(
+            PARTITION_NAME.as_ptr(),
+            namespace.as_ptr(),
+            sys::nvs_type_t_NVS_TYPE_ANY,
+            &mut iterator,
+        )
+    };
+    while found == sys::ESP_OK as sys::esp_err_t {
+        let mut info = sys::nvs_entry_info_t::default();
+        if let Err(err) = esp!(unsafe { sys::nvs_entry_info(iterator, &mut info) }) {
+            unsafe { sys::nvs_release_iterator(iterator) };
+            return Err(err.into());
+        }
+        let key = unsafe { CStr::from_ptr(info.key.as_ptr()) };
+        keys.push((key.to_string_lossy().into_owned(), info.type_));
+        found = unsafe { sys::nvs_entry_next(&mut iterator) };
+    }
+    unsafe { sys::nvs_release_iterator(iterator) };
+    // what the iteration ends with
+    if found != sys::ESP_ERR_NVS_NOT_FOUND as sys::esp_err_t {
+        esp!(found)?;
+    }
+    Ok(keys)
+}
+
+/// The entries of `namespace` but `kept`, each by key as `{"<type>": value}`
+/// so the restore writes it back as the type it was.
+fn read_namespace(
+    partition: EspDefaultNvsPartition,
+    namespace: &str,
+    kept: &[&str],
+) -> Result<Map<String, Value>> {
+    let nvs = EspNvs::<NvsDefault>::new(partition, namespace, true)?;
+    let mut entries = Map::new();
+    for (key, kind) in keys_of(namespace)? {
+        if kept.contains(&key.as_str()) {
+            continue;
+        }
+        let value = match kind {
+            sys::nvs_type_t_NVS_TYPE_U8 => nvs.get_u8(&key)?.map(|value| json!({ "u8": value })),
+            sys::nvs_type_t_NVS_TYPE_U16 => nvs.get_u16(&key)?.map(|value| json!({ "u16": value })),
+            sys::nvs_type_t_NVS_TYPE_U32 => nvs.get_u32(&key)?.map(|value| json!({ "u32": value })),
+            sys::nvs_type_t_NVS_TYPE_STR => {
+                let mut buf = vec![0; nvs.str_len(&key)?.unwrap_or_default()];
+                nvs.get_str(&key, &mut buf)?
+                    .map(|value| json!({ "str": value }))
+            }
+            sys::nvs_type_t_NVS_TYPE_BLOB => {
+                let mut buf = vec![0; nvs.blob_len(&key)?.unwrap_or_default()];
+                nvs.get_blob(&key, &mut buf)?.map(|value| {
+                    json!({ "blob": base64::engine::general_purpose::STANDARD.encode(value) })
+                })
+            }
+            kind => {
+                log::warn!("backup skips {namespace} {key}, of nvs type {kind:#x}");
+                None
+            }
+        };
+        if let Some(value) = value {
+            entries.insert(key, value);
+        }
+    }
+    Ok(entries)
+}
+
+/// An NVS value as the backup has it.
+enum Entry {
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    Str(String),
+    Blob(Vec<u8>),
+}
+
+/// The `{"<type>": value}` `read_namespace()` made of `key`.
+fn entry(key: &str, value: &Value) -> Result<Entry> {
+    let (kind, value) = value
+        .as_object()
+        .and_then(|typed| typed.iter().next())
+        .with_context(|| format!("backup {key} has no type"))?;
+    let number = || {
+        value
+            .as_u64()
+            .with_context(|| format!("backup {key} isn't a number"))
+    };
+    let out_of_range = |_| anyhow::anyhow!("backup {key} out of range");
+    Ok(match kind.as_str() {
+        "u8" => Entry::U8(number()?.try_into().map_err(out_of_range)?),
+        "u16" => Entry::U16(number()?.try_into().map_err(out_of_range)?),
+        "u32" => Entry::U32(number()?.try_into().map_err(out_of_range)?),
+        "str" => Entry::Str(
+            value
+                .as_str()
+                .with_context(|| format!("backup {key} isn't a string"))?
+                .to_owned(),
+        ),
+        "blob" => Entry::Blob(
+            base64::engine::general_purpose::STANDARD
+                .decode(value.as_str().unwrap_or_default())
+                .with_context(|| format!("backup {key} isn't base64"))?,
+        ),
+        kind => bail!("backup {key} has unknown type {kind}"),
+    })
+}
+
+/// Writes `entries` into `namespace` and removes the keys it has that the
+/// backup doesn't, all but `kept`.
+fn write_namespace(
+    partition: EspDefaultNvsPartition,
+    namespace: &str,
+    kept: &[&str],
+    entries: &[(String, Entry)],
+) -> Result<()> {
+    let stale: Vec<String> = keys_of(namespace)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .filter(|key| !kept.contains(&key.as_str()) && !entries.iter().any(|(name, _)| name == key))
+        .collect();
+    // a half written config could fail to load, a reset mustn't leave one
+    if namespace == "config" {
+        let values = entries
+            .iter()
+            .map(|(key, entry)| match entry {
+                Entry::U16(value) => Ok((key.as_str(), Stored::U16(*value))),
+                Entry::Str(value) => Ok((key.as_str(), Stored::Str(value.clone()))),
+                _ => bail!("config key {key} is neither a string nor a u16"),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        return Config::restore(
+            partition,
+            values
+                .iter()
+                .map(|(key, value)| (*key, Some(value)))
+                .chain(stale.iter().map(|key| (key.as_str(), None))),
+        );
+    }
+
+    let mut nvs = EspNvs::<NvsDefault>::new(partition, namespace, true)?;
+    for (key, entry) in entries {
+        match entry {
+            Entry::U8(value) => nvs.set_u8(key, *value)?,
+            Entry::U16(value) => nvs.set_u16(key, *value)?,
+            Entry::U32(value) => nvs.set_u32(key, *value)?,
+            Entry::Str(value) => nvs.set_str(key, value)?,
+            Entry::Blob(value) => nvs.set_blob(key, value)?,
+        }
+    }
+    for key in &stale {
+        nvs.remove(key)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,131 @@
+use crate::{
+    events::{self, Event},
+    telemetry,
+};
+use anyhow::{Context, Result};
+use esp_idf_hal::{
+    adc::{
+        attenuation::DB_11,
+        oneshot::{
+            config::{AdcChannelConfig, Calibration},
+            AdcChannelDriver, AdcDriver,
+        },
+        ADC1,
+    },
+    gpio::Gpio1,
+};
+use std::{sync::OnceLock, time::Duration};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+/// Readings averaged per sample, the ADC is noisy at the bottom bits.
+const READINGS: u32 = 16;
+/// Below this the battery counts as low, until it's back above
+/// `LOW_MV + HYSTERESIS_MV`.
+const LOW_MV: u16 = 3400;
+const HYSTERESIS_MV: u16 = 100;
+const STACK_SIZE: usize = 4096;
+
+/// Single cell LiPo discharge curve at light load, millivolts to percent.
+const CURVE: &[(u16, u8)] = &[
+    (3300, 0),
+    (3500, 3),
+    (3600, 7),
+    (3700, 18),
+    (3800, 42),
+    (3900, 62),
+    (4000, 79),
+    (4100, 91),
+    (4200, 100),
+];
+
+/// Divider ratio from the config, `None` when monitoring is off.
+static DIVIDER: OnceLock<Option<f32>> = OnceLock::new();
+
+/// Battery sense wiring: the cell through a resistor divider into GPIO1 on
+/// ADC1, the same on every board.
+pub struct Pins {
+    pub sense: Gpio1,
+}
+
+pub fn configure(config: &crate::config::Config) -> Result<()> {
+    let divider = match config.battery_divider.as_str() {
+        "" => None,
+        divider => Some(divider.parse().context("battery_divider isn't a number")?),
+    };
+    let _ = DIVIDER.set(divider);
+    Ok(())
+}
+
+/// Samples the battery on its own thread every `SAMPLE_INTERVAL` once the
+/// config says how, into telemetry as `battery_mv` and `battery_pct`.
+pub fn start(adc: ADC1, pins: Pins) -> Result<()> {
+    std::thread::Builder::new()
+        .name("battery".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            if let Err(err) = run(adc, pins) {
+                log::error!("battery monitor stopped: {err:#}");
+            }
+        })
+        .context("couldn't spawn battery monitor")?;
+    Ok(())
+}
+
+fn run(adc: ADC1, pins: Pins) -> Result<()> {
+    let divider = loop {
+        match DIVIDER.get() {
+            Some(Some(divider)) => break *divider,
+            Some(None) => return Ok(()),
+            None => std::thread::sleep(Duration::from_secs(1)),
+        }
+    };
+
+    // curve fitting corrects each chip with the eFuse calibration, the
+    // divider ratio is the board's own trim on top
+    let mut channel = AdcChannelDriver::new(
+        AdcDriver::new(adc)?,
+        pins.sense,
+        &AdcChannelConfig {
+            attenuation: DB_11,
+            calibration: Calibration::Curve,
+            ..Default::default()
+        },
+    )?;
+    let mut low = false;
+
+    loop {
+        let mut total = 0;
+        for _ in 0..READINGS {
+            total += u32::from(channel.read()?);
+        }
+        let millivolts = (total as f32 / READINGS as f32 * divider) as u16;
+        let percent = charge(millivolts);
+        log::debug!("battery {millivolts} mV, {percent}%");
+        telemetry::set("battery_mv", millivolts);
+        telemetry::set("battery_pct", percent);
+
+        if !low && millivolts < LOW_MV {
+            low = true;
+            log::warn!("battery low at {millivolts} mV");
+            events::publish(Event::LowBattery { millivolts });
+        } else if low && millivolts > LOW_MV + HYSTERESIS_MV {
+            low = false;
+        }
+
+        std::thread::sleep(SAMPLE_INTERVAL);
+    }
+}
+
+/// State of charge, interpolated along `CURVE`.
+fn charge(millivolts: u16) -> u8 {
+    let Some(upper) = CURVE.iter().position(|(mv, _)| *mv >= millivolts) else {
+        return 100;
+    };
+    if upper == 0 {
+        return 0;
+    }
+    let ((low_mv, low_pct), (high_mv, high_pct)) = (CURVE[upper - 1], CURVE[upper]);
+    let span = f32::from(high_mv - low_mv);
+    let offset = f32::from(millivolts - low_mv);
+    (f32::from(low_pct) + offset / span * f32::from(high_pct - low_pct)) as u8
+}
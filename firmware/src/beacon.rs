@@ -0,0 +1,113 @@
+//! A discovery beacon for fleet tooling on the LAN. Every
+//! `beacon_interval` seconds the device broadcasts one datagram to
+//! `PORT`, and a datagram `discover [<nonce>]` sent there, to the device
+//! or the broadcast address, gets one back unicast with the nonce in it.
+//!
+//! The datagram is a JSON object, `{"id", "version", "ip", "uptime", and
+//! "nonce"}` when asked, a newline, and the hex HMAC-SHA256 of the JSON.
+//! The key is `beacon_key`, shared by the fleet, or else the device's
+//! `identity::secret("discovery-beacon")`, which only a backend holding
+//! the device's root can check. Broadcast but signed: anyone on the
+//! subnet sees the devices, nobody can pose as one, and a probe's nonce
+//! stops an answer being replayed.
+
+use crate::{config::Config, device, events, identity, net, runtime};
+use anyhow::{Context, Result};
+use ring::hmac;
+use std::{
+    fmt::Write,
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::net::UdpSocket;
+
+pub const PORT: u16 = 7470;
+const PROBE: &str = "discover";
+const LABEL: &str = "discovery-beacon";
+/// Enough for a probe with any sensible nonce.
+const MAX_PROBE: usize = 128;
+/// Longest nonce echoed back, the reply has to stay one small datagram.
+const MAX_NONCE: usize = 64;
+
+/// Starts broadcasting and answering probes, unless `beacon_interval` is 0.
+pub fn start(config: &Config) {
+    if config.beacon_interval == 0 {
+        return;
+    }
+    let interval = Duration::from_secs(config.beacon_interval.into());
+    let key = if config.beacon_key.expose().is_empty() {
+        hmac::Key::new(hmac::HMAC_SHA256, identity::secret(LABEL).expose())
+    } else {
+        hmac::Key::new(hmac::HMAC_SHA256, config.beacon_key.expose().as_bytes())
+    };
+    runtime::spawn_named("beacon", move || run(key.clone(), interval));
+}
+
+async fn run(key: hmac::Key, interval: Duration) -> Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, PORT))
+        .await
+        .with_context(|| format!("couldn't bind beacon port {PORT}"))?;
+    socket.set_broadcast(true)?;
+    log::info!("beacon on udp {PORT} every {interval:?}");
+
+    let mut tick = tokio::time::interval(interval);
+    let mut probe = [0; MAX_PROBE];
+    loop {
+        tokio::select! {
+            _ = tick.tick() => {
+                if !events::state().net_up {
+                    continue;
+                }
+                let beacon = beacon(&key, None);
+                let broadcast = SocketAddr::from((Ipv4Addr::BROADCAST, PORT));
+                if let Err(err) = socket.send_to(&beacon, broadcast).await {
+                    log::debug!("beacon broadcast failed: {err}");
+                }
+            }
+            received = socket.recv_from(&mut probe) => {
+                let (len, from) = received?;
+                let Some(nonce) = parse_probe(&probe[..len]) else {
+                    continue;
+                };
+                log::debug!("beacon probe from {from}");
+                if let Err(err) = socket.send_to(&beacon(&key, nonce), from).await {
+                    log::debug!("beacon answer to {from} failed: {err}");
+                }
+            }
+        }
+    }
+}
+
+/// The nonce of a `discover [<nonce>]` probe, `None` for anything else;
+/// the broadcasts of other devices come in here too.
+fn parse_probe(datagram: &[u8]) -> Option<Option<&str>> {
+    let text = std::str::from_utf8(datagram).ok()?.trim();
+    let nonce = text.strip_prefix(PROBE)?;
+    if !nonce.is_empty() && !nonce.starts_with(' ') {
+        return None;
+    }
+    let nonce = nonce.trim();
+    if nonce.is_empty() {
+        return Some(None);
+    }
+    (nonce.len() <= MAX_NONCE).then_some(Some(nonce))
+}
+
+fn beacon(key: &hmac::Key, nonce: Option<&str>) -> Vec<u8> {
+    let mut body = serde_json::json!({
+        "id": device::id(),
+        "version": device::firmware_version(),
+        "ip": net::ipv4().map(|ip| ip.to_string()),
+        "uptime": device::uptime().as_secs(),
+    });
+    if let Some(nonce) = nonce {
+        body["nonce"] = nonce.into();
+    }
+    let mut datagram = body.to_string();
+    let tag = hmac::sign(key, datagram.as_bytes());
+    datagram.push('\n');
+    for byte in tag.as_ref() {
+        let _ = write!(datagram, "{byte:02x}");
+    }
+    datagram.into_bytes()
+}
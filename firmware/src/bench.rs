@@ -0,0 +1,344 @@
+//! HTTPS downloads timed for the rustls against mbedtls comparison: some
+//! one after another, then some at once, each of `bench_url` with a given
+//! size. Reported are the handshake latencies and what they negotiated,
+//! the throughput of both runs, how much heap the run took at its peak and
+//! how busy the CPU was; `tls_ciphers` and `tls_groups` pick what's
+//! compared.
+//!
+//! It runs at boot when `bench` is set, to `on` or to a plan such as
+//! `sequential=10,concurrent=4,size=262144`, and from `bench` on the
+//! console. CPU usage is what the idle tasks didn't get, which needs the
+//! FreeRTOS run time stats in sdkconfig.defaults.
+
+use crate::{
+    chip::CHIP,
+    config::Config,
+    console,
+    events::{self, Event},
+    heap,
+    metrics::{self, Stage},
+    net::socks,
+    runtime, telemetry,
+    tls::{self, suites::Negotiated},
+};
+use anyhow::{bail, ensure, Context, Result};
+use rustls::{pki_types::ServerName, HandshakeKind};
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    task::JoinSet,
+};
+use tokio_rustls::TlsConnector;
+
+const COMMAND: &str = "bench";
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(120);
+/// How often free heap is sampled while the bench runs.
+const HEAP_SAMPLE_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Clone, Copy, Debug)]
+struct Plan {
+    sequential: u16,
+    concurrent: u16,
+    size: u32,
+}
+
+impl Default for Plan {
+    fn default() -> Self {
+        Self {
+            sequential: 5,
+            concurrent: 3,
+            size: 64 * 1024,
+        }
+    }
+}
+
+impl Plan {
+    fn parse(value: &str) -> Result<Self> {
+        let mut plan = Self::default();
+        for option in value
+            .split(',')
+            .map(str::trim)
+            .filter(|option| !option.is_empty() && *option != "on")
+        {
+            match option.split_once('=') {
+                Some(("sequential", count)) => {
+                    plan.sequential = count.parse().context("sequential takes a count")?;
+                }
+                Some(("concurrent", count)) => {
+                    plan.concurrent = count.parse().context("concurrent takes a count")?;
+                }
+                Some(("size", bytes)) => plan.size = bytes.parse().context("size takes bytes")?,
+                _ => bail!("unknown bench option {option}"),
+            }
+        }
+        Ok(plan)
+    }
+}
+
+/// Where `bench_url` points, split up for a request of our own.
+#[derive(Clone)]
+struct Target {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Target {
+    fn parse(url: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("https://")
+            .context("bench_url has to be https")?;
+        let (authority, path) = rest.find('/').map_or((rest, "/"), |at| rest.split_at(at));
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().context("invalid bench_url port")?),
+            None => (authority, 443),
+        };
+        Ok(Self {
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+        })
+    }
+}
+
+struct Download {
+    handshake: Duration,
+    resumed: bool,
+    negotiated: Negotiated,
+    transfer: Duration,
+    bytes: u64,
+}
+
+/// Registers the `bench` command, and runs the plan in `bench` once the
+/// network and clock are up.
+pub fn start(config: &Config) {
+    console::register(console::Command {
+        name: COMMAND,
+        usage: "bench [<sequential> <concurrent> <bytes>]",
+        summary: "time https downloads: handshakes, throughput, heap and cpu",
+        run: command,
+    });
+
+    let template = config.bench_url.clone();
+    let at_boot = (!config.bench.is_empty()).then(|| Plan::parse(&config.bench));
+    runtime::spawn(async move {
+        let mut plan = Plan::default();
+        match at_boot {
+            Some(Ok(boot_plan)) => {
+                plan = boot_plan;
+                report(plan, run(&template, plan).await);
+            }
+            Some(Err(err)) => log::warn!("bench not run at boot: {err:#}"),
+            None => {}
+        }
+
+        let mut events = events::subscribe();
+        loop {
+            let Ok(Event::Command(command)) = events.recv().await else {
+                continue;
+            };
+            let mut words = command.split_whitespace();
+            if words.next() != Some(COMMAND) {
+                continue;
+            }
+            let counts: Vec<u32> = words.filter_map(|word| word.parse().ok()).collect();
+            if let [sequential, concurrent, size] = counts[..] {
+                plan = Plan {
+                    sequential: sequential.try_into().unwrap_or(u16::MAX),
+                    concurrent: concurrent.try_into().unwrap_or(u16::MAX),
+                    size,
+                };
+            }
+            report(plan, run(&template, plan).await);
+        }
+    });
+}
+
+fn command(_: &console::Console, args: &[&str]) -> Result<String> {
+    match args {
+        [] => events::publish(Event::Command(String::from(COMMAND))),
+        [_, _, _] if args.iter().all(|arg| arg.parse::<u32>().is_ok()) => {
+            events::publish(Event::Command(format!("{COMMAND} {}", args.join(" "))))
+        }
+        _ => bail!("usage: bench [<sequential> <concurrent> <bytes>]"),
+    }
+    Ok(String::from("bench requested, the result is logged"))
+}
+
+fn report(plan: Plan, result: Result<Value>) {
+    match result {
+        Ok(summary) => {
+            log::info!("bench {plan:?}: {summary}");
+            telemetry::set("bench", summary);
+        }
+        Err(err) => {
+            log::warn!("bench failed: {err:#}");
+            telemetry::set("bench", format!("{err:#}"));
+        }
+    }
+}
+
+async fn run(template: &str, plan: Plan) -> Result<Value> {
+    events::wait_until(|state| state.net_up && state.time_synced).await;
+    let target = Target::parse(&template.replace("{size}", &plan.size.to_string()))?;
+    let connector = TlsConnector::from(tls::client_config());
+
+    let (free_before, lowest_before) = (heap::free(), lowest_free());
+    let idle_before = idle_counters();
+    let start = Instant::now();
+
+    let work = async {
+        let mut sequential = Vec::new();
+        for _ in 0..plan.sequential {
+            sequential.push(download(target.clone(), connector.clone()).await?);
+        }
+
+        let concurrent_start = Instant::now();
+        let mut downloads = JoinSet::new();
+        for _ in 0..plan.concurrent {
+            downloads.spawn(download(target.clone(), connector.clone()));
+        }
+        let mut concurrent = Vec::new();
+        while let Some(download) = downloads.join_next().await {
+            concurrent.push(download??);
+        }
+        anyhow::Ok((sequential, concurrent, concurrent_start.elapsed()))
+    };
+    tokio::pin!(work);
+    let mut sampled_free = free_before;
+    let mut sample = tokio::time::interval(HEAP_SAMPLE_INTERVAL);
+    let (sequential, concurrent, concurrent_time) = loop {
+        tokio::select! {
+            result = &mut work => break result?,
+            _ = sample.tick() => sampled_free = sampled_free.min(heap::free()),
+        }
+    };
+
+    let elapsed = start.elapsed();
+    // a low inside a handshake, between two samples, shows in the mark
+    let lowest_after = lowest_free();
+    let lowest = if lowest_after < lowest_before {
+        sampled_free.min(lowest_after)
+    } else {
+        sampled_free
+    };
+    let idle: Duration = idle_counters()
+        .iter()
+        .zip(idle_before)
+        .map(|(after, before)| Duration::from_micros(after.wrapping_sub(before).into()))
+        .sum();
+    let busy = 1.0 - idle.as_secs_f64() / (elapsed.as_secs_f64() * f64::from(CHIP.cores));
+
+    let all = || sequential.iter().chain(&concurrent);
+    let mut handshakes: Vec<Duration> = all().map(|download| download.handshake).collect();
+    handshakes.sort();
+    let percentile = |p: usize| {
+        handshakes
+            .get((handshakes.len() * p / 100).min(handshakes.len().saturating_sub(1)))
+            .map(|handshake| handshake.as_millis() as u64)
+    };
+    // a run under tls_ciphers or tls_groups shows what it was measured with
+    let mut negotiated = BTreeMap::new();
+    for download in all() {
+        let Negotiated { suite, group } = &download.negotiated;
+        *negotiated.entry(format!("{suite}/{group}")).or_insert(0) += 1;
+    }
+    let kbps = |bytes: u64, time: Duration| bytes as f64 * 8.0 / time.as_secs_f64() / 1000.0;
+
+    Ok(json!({
+        "handshake_ms": {
+            "min": percentile(0),
+            "median": percentile(50),
+            "p90": percentile(90),
+            "max": handshakes.last().map(|handshake| handshake.as_millis() as u64),
+        },
+        "resumed": all().filter(|download| download.resumed).count(),
+        "negotiated": negotiated,
+        "sequential_kbps": kbps(
+            sequential.iter().map(|download| download.bytes).sum(),
+            sequential.iter().map(|download| download.transfer).sum(),
+        ),
+        "concurrent_kbps": kbps(
+            concurrent.iter().map(|download| download.bytes).sum(),
+            concurrent_time,
+        ),
+        "heap_peak": free_before.saturating_sub(lowest),
+        "cpu_percent": (busy.clamp(0.0, 1.0) * 100.0).round(),
+        "elapsed_ms": elapsed.as_millis() as u64,
+    }))
+}
+
+async fn download(target: Target, connector: TlsConnector) -> Result<Download> {
+    tokio::time::timeout(DOWNLOAD_TIMEOUT, async {
+        let tcp = socks::connect(&target.host, target.port).await?;
+        let name = ServerName::try_from(target.host.clone())?;
+        let start = Instant::now();
+        let mut stream = {
+            let _boost = crate::power::boost();
+            connector
+                .connect(name, tcp)
+                .await
+                .inspect_err(|_| metrics::failed(&target.host, Stage::Tls("bench")))?
+        };
+        let handshake = start.elapsed();
+        metrics::reached(&target.host, Stage::Tls("bench"), handshake);
+        let resumed = stream.get_ref().1.handshake_kind() == Some(HandshakeKind::Resumed);
+        let negotiated = tls::suites::observe("bench", &target.host, stream.get_ref().1);
+
+        let start = Instant::now();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            target.path, target.host
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut buffer = vec![0; 4096];
+        let mut status = Vec::new();
+        let mut bytes = 0;
+        loop {
+            let read = match stream.read(&mut buffer).await {
+                Ok(0) => break,
+                Ok(read) => read,
+                // plenty of servers close without a close_notify
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err.into()),
+            };
+            if status.len() < 16 {
+                status.extend_from_slice(&buffer[..read.min(16)]);
+            }
+            bytes += read as u64;
+        }
+        let status = String::from_utf8_lossy(&status);
+        ensure!(
+            status.split_whitespace().nth(1) == Some("200"),
+            "unexpected response: {status}"
+        );
+
+        Ok(Download {
+            handshake,
+            resumed,
+            negotiated,
+            transfer: start.elapsed(),
+            bytes,
+        })
+    })
+    .await
+    .context("bench download timed out")?
+}
+
+fn lowest_free() -> usize {
+    unsafe { esp_idf_sys::esp_get_minimum_free_heap_size() as usize }
+}
+
+/// The idle tasks' run time in microseconds, by core.
+fn idle_counters() -> Vec<u32> {
+    (0..CHIP.cores)
+        .map(|core| unsafe {
+            let idle = esp_idf_sys::xTaskGetIdleTaskHandleForCore(core.into());
+            esp_idf_sys::ulTaskGetRunTimeCounter(idle) as u32
+        })
+        .collect()
+}
@@ -0,0 +1,295 @@
+use crate::{
+    device,
+    events::{self, Event},
+    heap, net,
+};
+use anyhow::Result;
+use enumset::enum_set;
+use esp_idf_hal::{modem::BluetoothModem, reset};
+use esp_idf_svc::{
+    bt::{
+        ble::{
+            gap::{
+                AdvConfiguration, AuthenticationRequest, BleGapEvent, EspBleGap, IOCapabilities,
+                KeyMask, SecurityConfiguration,
+            },
+            gatt::{
+                server::{ConnectionId, EspGatts, GattsEvent, TransferId},
+                AutoResponse, GattCharacteristic, GattId, GattInterface, GattResponse,
+                GattServiceId, GattStatus, Handle, Permission, Property,
+            },
+        },
+        Ble, BtDriver, BtStatus, BtUuid,
+    },
+    nvs::EspDefaultNvsPartition,
+    sys::EspError,
+};
+use std::sync::{Arc, Mutex};
+
+const _: () = assert!(
+    crate::chip::CHIP.ble,
+    "the `ble` feature needs a chip with BLE"
+);
+
+const APP_ID: u16 = 0;
+const SERVICE_UUID: u128 = 0x6d1c9a40_3f1e_4d6b_9a57_2c0e5b7f8a10;
+/// JSON status, read only.
+const STATUS_UUID: u128 = 0x6d1c9a41_3f1e_4d6b_9a57_2c0e5b7f8a10;
+/// Text commands: `fetch`, `reboot`, `provision`. Writable over a link
+/// bonded with `ble_passkey` only.
+const COMMAND_UUID: u128 = 0x6d1c9a42_3f1e_4d6b_9a57_2c0e5b7f8a10;
+const MAX_COMMAND_LEN: usize = 32;
+
+type Driver = Arc<BtDriver<'static, Ble>>;
+
+#[derive(Default)]
+struct Handles {
+    service: Option<Handle>,
+    status: Option<Handle>,
+    command: Option<Handle>,
+}
+
+struct Server {
+    gap: EspBleGap<'static, Ble, Driver>,
+    gatts: EspGatts<'static, Ble, Driver>,
+    handles: Mutex<Handles>,
+    /// Without one there's no command characteristic.
+    passkey: Option<u32>,
+}
+
+/// Runs a GATT server next to WiFi so the board can be inspected and
+/// controlled from a phone even while the network is down. Commands need
+/// the client to pair with the passkey first; the bond is kept in `nvs`.
+pub fn start(
+    modem: BluetoothModem,
+    nvs: EspDefaultNvsPartition,
+    passkey: Option<u32>,
+) -> Result<()> {
+    if passkey.is_none() {
+        log::warn!("no ble_passkey, ble commands disabled");
+    }
+    let driver = Arc::new(BtDriver::new(modem, Some(nvs))?);
+    let server = Arc::new(Server {
+        gap: EspBleGap::new(driver.clone())?,
+        gatts: EspGatts::new(driver)?,
+        handles: Mutex::default(),
+        passkey,
+    });
+    if passkey.is_some() {
+        // the board has no input, so the client enters the fixed passkey
+        server.gap.set_security_conf(&SecurityConfiguration {
+            auth_req_mode: AuthenticationRequest::SecureMitmBonding,
+            io_capabilities: IOCapabilities::DisplayOnly,
+            initiator_key: Some(KeyMask::Inner0011),
+            responder_key: Some(KeyMask::Inner0011),
+            max_key_size: Some(16),
+            min_key_size: Some(16),
+            static_passkey: passkey,
+            only_accept_specified_auth: true,
+            enable_oob: false,
+        })?;
+    }
+
+    let gap = server.clone();
+    server.gap.subscribe(move |event| {
+        if let Err(err) = gap.on_gap_event(event) {
+            log::warn!("ble gap event failed: {err}");
+        }
+    })?;
+
+    let gatts = server.clone();
+    server.gatts.subscribe(move |(gatt_if, event)| {
+        if let Err(err) = gatts.on_gatts_event(gatt_if, event) {
+            log::warn!("ble gatts event failed: {err}");
+        }
+    })?;
+
+    server.gatts.register_app(APP_ID)?;
+    log::info!("ble status service registered");
+
+    Ok(())
+}
+
+impl Server {
+    fn on_gap_event(&self, event: BleGapEvent) -> Result<(), EspError> {
+        match event {
+            BleGapEvent::AdvertisingConfigured(status) => {
+                if status != BtStatus::Success {
+                    log::warn!("ble advertising config failed: {status:?}");
+                }
+                self.gap.start_advertising()?;
+            }
+            BleGapEvent::AuthenticationComplete { bd_addr, status } => {
+                if status == BtStatus::Success {
+                    log::info!("ble client {bd_addr} paired");
+                } else {
+                    log::warn!("ble client {bd_addr} failed to pair: {status:?}");
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn on_gatts_event(&self, gatt_if: GattInterface, event: GattsEvent) -> Result<(), EspError> {
+        match event {
+            GattsEvent::ServiceRegistered { app_id, .. } if app_id == APP_ID => {
+                self.gap.set_device_name(device::id())?;
+                self.gap.set_adv_conf(&AdvConfiguration {
+                    include_name: true,
+                    service_uuid: Some(BtUuid::uuid128(SERVICE_UUID)),
+                    ..Default::default()
+                })?;
+                self.gatts.create_service(
+                    gatt_if,
+                    &GattServiceId {
+                        id: GattId {
+                            uuid: BtUuid::uuid128(SERVICE_UUID),
+                            inst_id: 0,
+                        },
+                        is_primary: true,
+                    },
+                    // service, plus declaration and value for each characteristic
+                    if self.passkey.is_some() { 5 } else { 3 },
+                )?;
+            }
+            GattsEvent::ServiceCreated { service_handle, .. } => {
+                self.handles.lock().unwrap().service = Some(service_handle);
+                self.gatts.start_service(service_handle)?;
+                self.add_characteristics(service_handle)?;
+            }
+            GattsEvent::CharacteristicAdded {
+                attr_handle,
+                char_uuid,
+                ..
+            } => {
+                let mut handles = self.handles.lock().unwrap();
+                if char_uuid == BtUuid::uuid128(STATUS_UUID) {
+                    handles.status = Some(attr_handle);
+                } else if char_uuid == BtUuid::uuid128(COMMAND_UUID) {
+                    handles.command = Some(attr_handle);
+                }
+            }
+            GattsEvent::Read {
+                conn_id,
+                trans_id,
+                handle,
+                offset,
+                need_rsp,
+                ..
+            } if need_rsp => self.read(gatt_if, conn_id, trans_id, handle, offset)?,
+            GattsEvent::Write {
+                conn_id,
+                trans_id,
+                handle,
+                need_rsp,
+                value,
+                ..
+            } => {
+                let status = self.write(handle, value);
+                if need_rsp {
+                    self.gatts
+                        .send_response(gatt_if, conn_id, trans_id, status, None)?;
+                }
+            }
+            // advertising stops once a client connects
+            GattsEvent::PeerDisconnected { .. } => self.gap.start_advertising()?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn add_characteristics(&self, service_handle: Handle) -> Result<(), EspError> {
+        self.gatts.add_characteristic(
+            service_handle,
+            &GattCharacteristic {
+                uuid: BtUuid::uuid128(STATUS_UUID),
+                permissions: enum_set!(Permission::Read),
+                properties: enum_set!(Property::Read),
+                max_len: 0,
+                auto_rsp: AutoResponse::ByApp,
+            },
+            &[],
+        )?;
+        if self.passkey.is_none() {
+            return Ok(());
+        }
+        // the stack refuses writes from a link that isn't authenticated
+        self.gatts.add_characteristic(
+            service_handle,
+            &GattCharacteristic {
+                uuid: BtUuid::uuid128(COMMAND_UUID),
+                permissions: enum_set!(Permission::WriteEncryptedMitm),
+                properties: enum_set!(Property::Write),
+                max_len: MAX_COMMAND_LEN,
+                auto_rsp: AutoResponse::ByApp,
+            },
+            &[],
+        )?;
+        Ok(())
+    }
+
+    /// Serves the status JSON; clients read past the MTU with increasing
+    /// offsets, so each read gets the remainder from `offset` on.
+    fn read(
+        &self,
+        gatt_if: GattInterface,
+        conn_id: ConnectionId,
+        trans_id: TransferId,
+        handle: Handle,
+        offset: u16,
+    ) -> Result<(), EspError> {
+        if self.handles.lock().unwrap().status != Some(handle) {
+            return self.gatts.send_response(
+                gatt_if,
+                conn_id,
+                trans_id,
+                GattStatus::ReqNotSupported,
+                None,
+            );
+        }
+
+        let status = status().to_string();
+        let Some(value) = status.as_bytes().get(usize::from(offset)..) else {
+            return self.gatts.send_response(
+                gatt_if,
+                conn_id,
+                trans_id,
+                GattStatus::InvalidOffset,
+                None,
+            );
+        };
+
+        let mut response = GattResponse::new();
+        response.attr_handle(handle).offset(offset).value(value)?;
+        self.gatts
+            .send_response(gatt_if, conn_id, trans_id, GattStatus::Ok, Some(&response))
+    }
+
+    fn write(&self, handle: Handle, value: &[u8]) -> GattStatus {
+        if self.handles.lock().unwrap().command != Some(handle) {
+            return GattStatus::ReqNotSupported;
+        }
+
+        let Ok(command) = std::str::from_utf8(value).map(str::trim) else {
+            return GattStatus::IllegalParam;
+        };
+        log::info!("ble command: {command}");
+
+        match command {
+            "reboot" => reset::restart(),
+            "fetch" | "provision" => events::publish(Event::Command(command.to_owned())),
+            _ => return GattStatus::IllegalParam,
+        }
+        GattStatus::Ok
+    }
+}
+
+fn status() -> serde_json::Value {
+    serde_json::json!({
+        "firmware": device::firmware_version(),
+        "free_heap": heap::free(),
+        "rssi": net::rssi(),
+        "phy": net::phy(),
+    })
+}
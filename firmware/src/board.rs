@@ -0,0 +1,221 @@
+//! Pin maps of the boards the firmware runs on, so the peripheral modules
+//! take whatever pins they're handed instead of naming GPIOs. The board is
+//! the `board` config field, or the build's default when that's empty:
+//! the devkit, or the carrier with the `board-carrier` feature.
+//!
+//! The battery sense pin isn't in here, an ADC channel has to stay a typed
+//! pin. It's GPIO1 on ADC1 on every board and chip.
+
+use esp_idf_hal::gpio::AnyIOPin;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+
+#[cfg(all(feature = "board-carrier", not(esp32s3)))]
+compile_error!("the carrier board is built around an ESP32-S3 module");
+
+#[derive(Clone, Copy, Debug)]
+pub struct I2cPins {
+    pub sda: i32,
+    pub scl: i32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct UartPins {
+    pub tx: i32,
+    pub rx: i32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SpiPins {
+    pub sclk: i32,
+    pub mosi: i32,
+    pub miso: i32,
+    pub cs: i32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct EthPins {
+    pub spi: SpiPins,
+    pub int: i32,
+    pub rst: i32,
+}
+
+/// The e-paper HAT only listens, so there's no MISO.
+#[derive(Clone, Copy, Debug)]
+pub struct EpaperPins {
+    pub sclk: i32,
+    pub mosi: i32,
+    pub cs: i32,
+    pub dc: i32,
+    pub rst: i32,
+    pub busy: i32,
+}
+
+/// GPIO numbers by function, `None` where the board has nothing wired.
+// each build only reads the pins of the features it has
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub struct Board {
+    pub name: &'static str,
+    /// Plain LED, active high.
+    pub led: Option<i32>,
+    /// WS2812 data line.
+    pub rgb: Option<i32>,
+    /// Passive piezo, driven with PWM.
+    pub buzzer: Option<i32>,
+    /// Push button to ground, active low.
+    pub button: Option<i32>,
+    /// Touch pad 1-14 that does what the button does, for boards without
+    /// one; the S3 is the only chip here with touch pads.
+    pub touch: Option<u32>,
+    /// The display's own bus.
+    pub oled: Option<I2cPins>,
+    /// The bus the sensors and the RTC share.
+    pub i2c: Option<I2cPins>,
+    /// microSD slot in SPI mode; nothing mounts it yet.
+    pub sd: Option<SpiPins>,
+    pub epaper: Option<EpaperPins>,
+    pub eth: Option<EthPins>,
+    pub console: UartPins,
+    pub cellular: Option<UartPins>,
+    pub gps: Option<UartPins>,
+}
+
+/// Espressif's DevKitC-1 with the add-ons wired as they've always been.
+#[cfg(esp32s3)]
+const DEVKIT_V1: Board = Board {
+    name: "devkit-v1",
+    led: Some(2),
+    rgb: Some(48),
+    buzzer: Some(47),
+    button: Some(0),
+    touch: None,
+    oled: Some(I2cPins { sda: 4, scl: 5 }),
+    i2c: Some(I2cPins { sda: 6, scl: 7 }),
+    sd: None,
+    epaper: Some(EpaperPins {
+        sclk: 39,
+        mosi: 40,
+        cs: 41,
+        dc: 42,
+        rst: 38,
+        busy: 21,
+    }),
+    eth: Some(EthPins {
+        spi: SpiPins {
+            sclk: 12,
+            mosi: 11,
+            miso: 13,
+            cs: 10,
+        },
+        int: 14,
+        rst: 9,
+    }),
+    console: UartPins { tx: 43, rx: 44 },
+    cellular: Some(UartPins { tx: 17, rx: 18 }),
+    gps: Some(UartPins { tx: 15, rx: 16 }),
+};
+
+/// DevKitM-1, the RGB LED on GPIO8 is the only light. Most of the add-ons
+/// need more pins than the C3 has to spare.
+#[cfg(esp32c3)]
+const DEVKIT_V1: Board = Board {
+    name: "devkit-v1",
+    led: None,
+    rgb: Some(8),
+    buzzer: None,
+    button: Some(9),
+    touch: None,
+    oled: Some(I2cPins { sda: 4, scl: 5 }),
+    i2c: Some(I2cPins { sda: 6, scl: 7 }),
+    sd: None,
+    epaper: None,
+    eth: None,
+    console: UartPins { tx: 21, rx: 20 },
+    cellular: None,
+    gps: None,
+};
+
+/// DevKitC-1, laid out like the C3 one.
+#[cfg(esp32c6)]
+const DEVKIT_V1: Board = Board {
+    name: "devkit-v1",
+    led: None,
+    rgb: Some(8),
+    buzzer: None,
+    button: Some(9),
+    touch: None,
+    oled: Some(I2cPins { sda: 4, scl: 5 }),
+    i2c: Some(I2cPins { sda: 6, scl: 7 }),
+    sd: None,
+    epaper: None,
+    eth: None,
+    console: UartPins { tx: 16, rx: 17 },
+    cellular: None,
+    gps: None,
+};
+
+/// The in-house carrier for the S3 module: status LED, microSD, the modem
+/// and GPS headers, and the sensor bus on the Qwiic connector.
+#[cfg(esp32s3)]
+const CARRIER: Board = Board {
+    name: "carrier",
+    led: Some(47),
+    rgb: None,
+    buzzer: None,
+    button: Some(0),
+    touch: None,
+    oled: None,
+    i2c: Some(I2cPins { sda: 8, scl: 9 }),
+    sd: Some(SpiPins {
+        sclk: 12,
+        mosi: 11,
+        miso: 13,
+        cs: 10,
+    }),
+    epaper: None,
+    eth: None,
+    console: UartPins { tx: 43, rx: 44 },
+    cellular: Some(UartPins { tx: 17, rx: 18 }),
+    gps: Some(UartPins { tx: 15, rx: 16 }),
+};
+
+const BOARDS: &[&Board] = &[
+    &DEVKIT_V1,
+    #[cfg(esp32s3)]
+    &CARRIER,
+];
+
+#[cfg(feature = "board-carrier")]
+const DEFAULT: &Board = &CARRIER;
+#[cfg(not(feature = "board-carrier"))]
+const DEFAULT: &Board = &DEVKIT_V1;
+
+/// The configured board, falling back to the default if the name is
+/// unknown or the config can't be read.
+pub fn current(partition: EspDefaultNvsPartition) -> &'static Board {
+    let board = match crate::config::board(partition) {
+        Ok(name) if name.is_empty() => DEFAULT,
+        Ok(name) => find(&name).unwrap_or_else(|| {
+            log::warn!("unknown board {name}, using {}", DEFAULT.name);
+            DEFAULT
+        }),
+        Err(err) => {
+            log::warn!("couldn't read the board, using {}: {err:#}", DEFAULT.name);
+            DEFAULT
+        }
+    };
+    log::info!("board {}", board.name);
+    crate::telemetry::set("board", board.name);
+    board
+}
+
+fn find(name: &str) -> Option<&'static Board> {
+    BOARDS.iter().copied().find(|board| board.name == name)
+}
+
+/// The pin as a peripheral for a driver.
+// every board pin goes to exactly one driver, once at boot, and the typed
+// pins in `Peripherals` are never used for the same GPIOs
+pub fn pin(gpio: i32) -> AnyIOPin {
+    unsafe { AnyIOPin::new(gpio) }
+}
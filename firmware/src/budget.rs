@@ -0,0 +1,331 @@
+//! RAM budgets of the subsystems that hold the most of it, so a change
+//! that makes the TLS or HTTP stack hungrier shows on the next run instead
+//! of as a low heap weeks later. Every budget is a static here with the
+//! bytes its subsystem is expected to keep live at most; the code of the
+//! subsystem runs inside it, `Budget::track()` for a future and
+//! `Budget::enter()` for a stretch of a task.
+//!
+//! With the `budgets` feature the global allocator charges every
+//! allocation to the budget its task is in, and the block remembers it, so
+//! a TLS session's buffers stay charged to TLS whoever frees them. A budget
+//! going over is logged, counted in `heap_budget_exceeded_total` and goes
+//! out with telemetry as `budget_exceeded`, once until it's back under.
+//! `budget` on the console lists them. Without the feature the scopes do
+//! nothing. Every block carries its owner in front, at least four bytes,
+//! which is why it's a feature.
+
+use crate::console;
+use anyhow::{bail, Result};
+#[cfg(feature = "budgets")]
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+    time::Duration,
+};
+use std::{
+    fmt::Write,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+pub static HTTP: Budget = Budget::new(1, "http", 40 * 1024);
+pub static TLS: Budget = Budget::new(2, "tls", 48 * 1024);
+pub static MQTT: Budget = Budget::new(3, "mqtt", 16 * 1024);
+
+/// Indexed by tag; 0 is everything outside a budget.
+const ALL: [&Budget; 3] = [&HTTP, &TLS, &MQTT];
+const TAGS: usize = ALL.len() + 1;
+
+/// How often a budget over its limit is looked for.
+#[cfg(feature = "budgets")]
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Tasks that can be inside a budget at once, others are charged to none.
+#[cfg(feature = "budgets")]
+const SLOTS: usize = 16;
+/// Room in front of every block for its tag.
+#[cfg(feature = "budgets")]
+const HEADER: usize = 4;
+
+#[cfg(feature = "budgets")]
+#[global_allocator]
+static ALLOCATOR: Counted = Counted;
+
+// consts of atomics make fresh ones, which is what the arrays need
+#[cfg(feature = "budgets")]
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+#[cfg(feature = "budgets")]
+#[allow(clippy::declare_interior_mutable_const)]
+const UNTAGGED: AtomicU8 = AtomicU8::new(0);
+#[cfg(feature = "budgets")]
+#[allow(clippy::declare_interior_mutable_const)]
+const UNDER: AtomicBool = AtomicBool::new(false);
+
+/// Live and most bytes ever live, by tag.
+#[cfg(feature = "budgets")]
+static LIVE: [AtomicUsize; TAGS] = [ZERO; TAGS];
+#[cfg(feature = "budgets")]
+static PEAK: [AtomicUsize; TAGS] = [ZERO; TAGS];
+/// Set by the allocator when a budget went over its limit.
+#[cfg(feature = "budgets")]
+static OVER: [AtomicBool; TAGS] = [UNDER; TAGS];
+/// The task handle in each slot, 0 for a free one, and its budget's tag.
+#[cfg(feature = "budgets")]
+static TASKS: [AtomicUsize; SLOTS] = [ZERO; SLOTS];
+#[cfg(feature = "budgets")]
+static CURRENT: [AtomicU8; SLOTS] = [UNTAGGED; SLOTS];
+
+pub struct Budget {
+    tag: u8,
+    name: &'static str,
+    limit: usize,
+}
+
+impl Budget {
+    const fn new(tag: u8, name: &'static str, limit: usize) -> Self {
+        Self { tag, name, limit }
+    }
+
+    /// Charges what the running task allocates to this budget until the
+    /// scope is dropped; nested scopes charge the innermost.
+    pub fn enter(&'static self) -> Scope {
+        #[cfg(feature = "budgets")]
+        if let Some(slot) = slot(true) {
+            let outer = CURRENT[slot].swap(self.tag, Ordering::Relaxed);
+            return Scope { slot, outer };
+        }
+        Scope {
+            slot: usize::MAX,
+            outer: 0,
+        }
+    }
+
+    /// `future`, every poll of it inside this budget.
+    pub fn track<F: Future>(&'static self, future: F) -> Tracked<F> {
+        Tracked {
+            budget: self,
+            future: Box::pin(future),
+        }
+    }
+}
+
+/// See `Budget::enter()`.
+pub struct Scope {
+    slot: usize,
+    outer: u8,
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        #[cfg(feature = "budgets")]
+        if self.slot < SLOTS {
+            CURRENT[self.slot].store(self.outer, Ordering::Relaxed);
+            if self.outer == 0 {
+                TASKS[self.slot].store(0, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// See `Budget::track()`.
+pub struct Tracked<F> {
+    budget: &'static Budget,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for Tracked<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let _scope = self.budget.enter();
+        self.future.as_mut().poll(cx)
+    }
+}
+
+/// The running task's slot, claiming a free one with `claim`.
+#[cfg(feature = "budgets")]
+fn slot(claim: bool) -> Option<usize> {
+    let task = unsafe { esp_idf_sys::xTaskGetCurrentTaskHandle() } as usize;
+    if let Some(slot) = TASKS
+        .iter()
+        .position(|known| known.load(Ordering::Relaxed) == task)
+    {
+        return Some(slot);
+    }
+    if !claim {
+        return None;
+    }
+    TASKS.iter().position(|known| {
+        known
+            .compare_exchange(0, task, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+    })
+}
+
+/// The tag to charge an allocation of the running task to.
+#[cfg(feature = "budgets")]
+fn current() -> u8 {
+    slot(false).map_or(0, |slot| CURRENT[slot].load(Ordering::Relaxed))
+}
+
+#[cfg(feature = "budgets")]
+fn charge(tag: u8, bytes: usize) {
+    let tag = usize::from(tag).min(TAGS - 1);
+    let live = LIVE[tag].fetch_add(bytes, Ordering::Relaxed) + bytes;
+    PEAK[tag].fetch_max(live, Ordering::Relaxed);
+    if tag > 0 && live > ALL[tag - 1].limit {
+        OVER[tag].store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature = "budgets")]
+fn refund(tag: u8, bytes: usize) {
+    let tag = usize::from(tag).min(TAGS - 1);
+    LIVE[tag].fetch_sub(bytes, Ordering::Relaxed);
+}
+
+/// How far a block starts past what the allocator underneath handed out,
+/// keeping its alignment.
+#[cfg(feature = "budgets")]
+fn offset(layout: Layout) -> usize {
+    layout.align().max(HEADER)
+}
+
+#[cfg(feature = "budgets")]
+fn outer(layout: Layout, size: usize) -> Option<Layout> {
+    Layout::from_size_align(size.checked_add(offset(layout))?, layout.align()).ok()
+}
+
+/// `heap`'s allocator underneath, charging every block to a budget.
+#[cfg(feature = "budgets")]
+struct Counted;
+
+#[cfg(all(feature = "budgets", esp32s3))]
+static BASE: crate::heap::CapsAllocator = crate::heap::CapsAllocator;
+#[cfg(all(feature = "budgets", not(esp32s3)))]
+static BASE: std::alloc::System = std::alloc::System;
+
+#[cfg(feature = "budgets")]
+unsafe impl GlobalAlloc for Counted {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(outer) = outer(layout, layout.size()) else {
+            return std::ptr::null_mut();
+        };
+        let base = BASE.alloc(outer);
+        if base.is_null() {
+            return base;
+        }
+        let ptr = base.add(offset(layout));
+        let tag = current();
+        ptr.sub(1).write(tag);
+        charge(tag, layout.size());
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        refund(ptr.sub(1).read(), layout.size());
+        let outer =
+            Layout::from_size_align_unchecked(layout.size() + offset(layout), layout.align());
+        BASE.dealloc(ptr.sub(offset(layout)), outer);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let Some(new_outer) = outer(layout, new_size) else {
+            return std::ptr::null_mut();
+        };
+        let tag = ptr.sub(1).read();
+        let outer =
+            Layout::from_size_align_unchecked(layout.size() + offset(layout), layout.align());
+        let base = BASE.realloc(ptr.sub(offset(layout)), outer, new_outer.size());
+        if base.is_null() {
+            return base;
+        }
+        // it stays with the budget that allocated it
+        refund(tag, layout.size());
+        charge(tag, new_size);
+        base.add(offset(layout))
+    }
+}
+
+/// Registers `budget`, and without the feature that's all.
+pub fn start() {
+    console::register(console::Command {
+        name: "budget",
+        usage: "budget",
+        summary: "live and peak heap of each subsystem against its budget",
+        run: command,
+    });
+    #[cfg(feature = "budgets")]
+    crate::runtime::spawn(async {
+        let mut reported = [false; TAGS];
+        loop {
+            crate::runtime::sleep(CHECK_INTERVAL).await;
+            for budget in ALL {
+                let tag = usize::from(budget.tag);
+                let live = LIVE[tag].load(Ordering::Relaxed);
+                if OVER[tag].swap(false, Ordering::Relaxed) && !reported[tag] {
+                    exceeded(budget, live);
+                }
+                reported[tag] = live > budget.limit;
+            }
+        }
+    });
+}
+
+#[cfg(feature = "budgets")]
+fn exceeded(budget: &Budget, live: usize) {
+    let peak = PEAK[usize::from(budget.tag)].load(Ordering::Relaxed);
+    log::warn!(
+        "budget: {} over its {} bytes, {live} live, {peak} at the most",
+        budget.name,
+        budget.limit
+    );
+    crate::metrics::HEAP_BUDGET_EXCEEDED.inc(&[("subsystem", budget.name)]);
+    crate::telemetry::set(
+        "budget_exceeded",
+        serde_json::json!({
+            "subsystem": budget.name,
+            "limit": budget.limit,
+            "live": live,
+            "peak": peak,
+        }),
+    );
+}
+
+fn command(_: &console::Console, args: &[&str]) -> Result<String> {
+    if !args.is_empty() {
+        bail!("usage: budget");
+    }
+    if !cfg!(feature = "budgets") {
+        return Ok(String::from("built without the budgets feature"));
+    }
+    let mut report = String::new();
+    for (tag, name, limit) in std::iter::once((0, "other", None)).chain(
+        ALL.iter()
+            .map(|budget| (budget.tag, budget.name, Some(budget.limit))),
+    ) {
+        let (live, peak) = usage(tag);
+        let _ = write!(report, "{name}: {live} live, {peak} peak");
+        if let Some(limit) = limit {
+            let _ = write!(report, " of {limit}");
+        }
+        report.push('\n');
+    }
+    report.pop();
+    Ok(report)
+}
+
+/// Live and peak bytes of tag `tag`.
+fn usage(tag: u8) -> (usize, usize) {
+    #[cfg(feature = "budgets")]
+    return (
+        LIVE[usize::from(tag)].load(Ordering::Relaxed),
+        PEAK[usize::from(tag)].load(Ordering::Relaxed),
+    );
+    #[cfg(not(feature = "budgets"))]
+    {
+        let _ = tag;
+        (0, 0)
+    }
+}
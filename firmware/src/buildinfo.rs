@@ -0,0 +1,95 @@
+//! What went into this image, from `build.rs`: the commit and whether the
+//! tree had uncommitted changes, when it was built and by which compiler,
+//! and the features on. A device in the field answers "which build is
+//! that" with `firmware_info()`, the boot log and its telemetry, and the
+//! app descriptor carries the same version, for `esptool image_info` and
+//! the OTA partitions to show.
+
+use esp_idf_sys::{const_format::formatcp, esp_app_desc_t};
+
+pub const GIT: &str = env!("FIRMWARE_GIT");
+pub const DIRTY: bool = matches!(env!("FIRMWARE_DIRTY").as_bytes(), b"true");
+/// UTC, `SOURCE_DATE_EPOCH` when that was set.
+pub const DATE: &str = env!("FIRMWARE_BUILD_DATE");
+pub const TIME: &str = env!("FIRMWARE_BUILD_TIME");
+pub const RUSTC: &str = env!("FIRMWARE_RUSTC");
+
+/// The package version with the commit, `0.1.0+1a2b3c4d5e`, `.dirty`
+/// after it for a tree with changes.
+pub const VERSION: &str = formatcp!(
+    "{}+{}{}",
+    env!("CARGO_PKG_VERSION"),
+    GIT,
+    if DIRTY { ".dirty" } else { "" }
+);
+
+/// One image as opposed to another, which `VERSION` isn't: two builds of
+/// a commit, with changes or without, differ in when they were built. The
+/// config snapshot keys on it to spot a rollback.
+pub const BUILD_ID: &str = formatcp!("{}@{}T{}", VERSION, DATE, TIME);
+
+/// In place of ESP-IDF's weak one, which only knows `PROJECT_VER`. The
+/// fields one IDF release has and another doesn't are left zeroed, as the
+/// bootloader expects of the reserved ones.
+#[no_mangle]
+#[used]
+#[link_section = ".rodata_desc"]
+// the symbol name the bootloader and esptool look for
+#[allow(non_upper_case_globals)]
+pub static esp_app_desc: esp_app_desc_t = esp_app_desc_t {
+    magic_word: esp_idf_sys::ESP_APP_DESC_MAGIC_WORD,
+    version: c_array(VERSION),
+    project_name: c_array(env!("CARGO_PKG_NAME")),
+    time: c_array(TIME),
+    date: c_array(DATE),
+    idf_ver: c_array(formatcp!(
+        "{}.{}.{}",
+        esp_idf_sys::ESP_IDF_VERSION_MAJOR,
+        esp_idf_sys::ESP_IDF_VERSION_MINOR,
+        esp_idf_sys::ESP_IDF_VERSION_PATCH
+    )),
+    // zeroed is valid for every field, all integers and arrays of them
+    ..unsafe { std::mem::zeroed() }
+};
+
+/// `text` as a NUL padded C string, cut to leave the last byte NUL.
+const fn c_array<const N: usize>(text: &str) -> [std::ffi::c_char; N] {
+    let bytes = text.as_bytes();
+    let mut array = [0; N];
+    let mut at = 0;
+    while at < bytes.len() && at < N - 1 {
+        array[at] = bytes[at] as _;
+        at += 1;
+    }
+    array
+}
+
+/// The features this image was built with.
+pub fn features() -> Vec<&'static str> {
+    env!("FIRMWARE_FEATURES")
+        .split(',')
+        .filter(|feature| !feature.is_empty())
+        .collect()
+}
+
+pub fn json() -> serde_json::Value {
+    serde_json::json!({
+        "git": GIT,
+        "dirty": DIRTY,
+        "built": format!("{DATE}T{TIME}Z"),
+        "rustc": RUSTC,
+        "features": features(),
+    })
+}
+
+/// Logs the build at boot, and tags telemetry with the commit.
+pub fn announce() {
+    log::info!(
+        "firmware {VERSION}, built {DATE} {TIME} UTC with {RUSTC}, features {}",
+        features().join(",")
+    );
+    if DIRTY {
+        log::warn!("built from a tree with uncommitted changes");
+    }
+    crate::telemetry::set("build", VERSION);
+}
@@ -0,0 +1,146 @@
+//! Buses several drivers share, each behind one lock instead of every
+//! driver bringing its own. Async code waits for a bus with `lock()`
+//! without holding up the executor; the drivers that run on threads, or
+//! inside a TLS handshake that can't await, take it with `with()`. Both
+//! give up after `LOCK_TIMEOUT` rather than hang on a driver that never
+//! let go.
+//!
+//! A driver crate that wants a bus of its own gets a `Device`, which takes
+//! the bus for each of its transactions, the way `shared-bus` does.
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+    ops::{Deref, DerefMut},
+    time::{Duration, Instant},
+};
+use tokio::sync::{Mutex, MutexGuard};
+
+/// Longest a transaction waits for the bus.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often `with()` looks whether the bus came free.
+const LOCK_POLL: Duration = Duration::from_millis(1);
+
+pub struct Bus<T> {
+    name: &'static str,
+    driver: Mutex<Option<T>>,
+}
+
+impl<T> Bus<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            driver: Mutex::const_new(None),
+        }
+    }
+
+    /// Puts `driver` on the bus, replacing any before it.
+    pub fn install(&self, driver: T) -> Result<()> {
+        *self.blocking()? = Some(driver);
+        Ok(())
+    }
+
+    /// Waits for the bus.
+    pub async fn lock(&self) -> Result<Guard<'_, T>> {
+        let guard = tokio::select! {
+            guard = self.driver.lock() => guard,
+            () = crate::runtime::sleep(LOCK_TIMEOUT) => return Err(self.busy()),
+        };
+        Guard::new(self.name, guard)
+    }
+
+    /// Runs `f` with the bus to itself, blocking until it's free.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> Result<R>) -> Result<R> {
+        let mut guard = Guard::new(self.name, self.blocking()?)?;
+        f(&mut guard)
+    }
+
+    /// The bus as a driver of its own, see `Device`.
+    pub fn device(&'static self) -> Device<T> {
+        Device { bus: self }
+    }
+
+    // tokio's blocking_lock() panics on the runtime's threads, which the
+    // TLS handshakes that sign on the secure element run on
+    fn blocking(&self) -> Result<MutexGuard<'_, Option<T>>> {
+        let deadline = Instant::now() + LOCK_TIMEOUT;
+        loop {
+            if let Ok(guard) = self.driver.try_lock() {
+                return Ok(guard);
+            }
+            if Instant::now() >= deadline {
+                return Err(self.busy());
+            }
+            std::thread::sleep(LOCK_POLL);
+        }
+    }
+
+    fn busy(&self) -> anyhow::Error {
+        anyhow!("{} still busy after {LOCK_TIMEOUT:?}", self.name)
+    }
+}
+
+/// A locked bus with its driver installed.
+pub struct Guard<'a, T>(MutexGuard<'a, Option<T>>);
+
+impl<'a, T> Guard<'a, T> {
+    fn new(name: &str, guard: MutexGuard<'a, Option<T>>) -> Result<Self> {
+        guard
+            .as_ref()
+            .with_context(|| format!("{name} not started"))?;
+        Ok(Self(guard))
+    }
+}
+
+impl<T> Deref for Guard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for Guard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.0.as_mut().unwrap()
+    }
+}
+
+/// One driver's share of a bus, handed to a driver crate in place of the
+/// bus itself.
+pub struct Device<T: 'static> {
+    bus: &'static Bus<T>,
+}
+
+#[derive(Debug)]
+pub enum DeviceError<E> {
+    /// The bus wasn't started or didn't come free in time.
+    Bus,
+    Driver(E),
+}
+
+impl<E: embedded_hal::i2c::Error> embedded_hal::i2c::Error for DeviceError<E> {
+    fn kind(&self) -> embedded_hal::i2c::ErrorKind {
+        match self {
+            Self::Bus => embedded_hal::i2c::ErrorKind::Other,
+            Self::Driver(err) => err.kind(),
+        }
+    }
+}
+
+impl<T: embedded_hal::i2c::ErrorType> embedded_hal::i2c::ErrorType for Device<T> {
+    type Error = DeviceError<T::Error>;
+}
+
+impl<T: embedded_hal::i2c::I2c> embedded_hal::i2c::I2c for Device<T> {
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [embedded_hal::i2c::Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let mut guard = self.bus.blocking().map_err(|_| DeviceError::Bus)?;
+        let driver = guard.as_mut().ok_or(DeviceError::Bus)?;
+        driver
+            .transaction(address, operations)
+            .map_err(DeviceError::Driver)
+    }
+}
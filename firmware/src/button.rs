@@ -0,0 +1,142 @@
+use crate::events::{self, Event};
+use anyhow::{Context, Result};
+use esp_idf_hal::{
+    delay::TickType,
+    gpio::{AnyIOPin, Input, InterruptType, PinDriver, Pull},
+    task::notification::Notification,
+};
+use std::{
+    num::NonZeroU32,
+    time::{Duration, Instant},
+};
+
+#[cfg(esp32s3)]
+mod touch;
+
+/// Contacts settle well within this after an edge.
+const DEBOUNCE: Duration = Duration::from_millis(30);
+/// Held this long it's a long press, released earlier a short one.
+const LONG_PRESS: Duration = Duration::from_secs(5);
+/// A second press starting within this of the release makes a double press.
+const DOUBLE_PRESS_GAP: Duration = Duration::from_millis(400);
+const STACK_SIZE: usize = 4096;
+
+/// What's pressed, the button's pin or a touch pad.
+trait Source {
+    /// Waits for the debounced input to be `pressed` (or released), up to
+    /// `timeout`; false if it timed out.
+    fn wait_for(&mut self, pressed: bool, timeout: Option<Duration>) -> Result<bool>;
+}
+
+/// Watches the board's button, active low, and publishes each press
+/// as `ShortPress`, `LongPress` or `DoublePress`. A short press fetches
+/// now, a double press enters provisioning and a long press is a factory
+/// reset.
+pub fn start(pin: AnyIOPin) -> Result<()> {
+    spawn("button", move || Button::new(pin))
+}
+
+/// The same presses from touch pad `pad`, for boards without a button.
+#[cfg(esp32s3)]
+pub fn start_touch(pad: u32) -> Result<()> {
+    spawn("touch", move || touch::Pad::new(pad))
+}
+
+/// Runs the presses of what `open` makes on a thread of its own, where
+/// it's made too.
+fn spawn<S: Source>(
+    name: &'static str,
+    open: impl FnOnce() -> Result<S> + Send + 'static,
+) -> Result<()> {
+    std::thread::Builder::new()
+        .name(name.into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            if let Err(err) = open().and_then(run) {
+                log::error!("{name} stopped: {err:#}");
+            }
+        })
+        .with_context(|| format!("couldn't spawn {name} task"))?;
+    Ok(())
+}
+
+fn run(mut button: impl Source) -> Result<()> {
+    loop {
+        button.wait_for(true, None)?;
+        let press = if !button.wait_for(false, Some(LONG_PRESS))? {
+            Event::LongPress
+        } else if button.wait_for(true, Some(DOUBLE_PRESS_GAP))? {
+            Event::DoublePress
+        } else {
+            Event::ShortPress
+        };
+        log::info!("button: {press:?}");
+        act(&press);
+        events::publish(press);
+
+        // a long or double press is still held
+        button.wait_for(false, None)?;
+    }
+}
+
+fn act(press: &Event) {
+    match press {
+        Event::ShortPress => events::publish(Event::Command(String::from("fetch"))),
+        Event::DoublePress => events::publish(Event::Command(String::from("provision"))),
+        Event::LongPress => crate::config::factory_reset(),
+        _ => {}
+    }
+}
+
+/// The pin plus the notification its edge interrupt wakes this thread with;
+/// the notification belongs to the task that created it.
+struct Button {
+    pin: PinDriver<'static, AnyIOPin, Input>,
+    notification: Notification,
+}
+
+impl Button {
+    fn new(pin: AnyIOPin) -> Result<Self> {
+        let mut pin = PinDriver::input(pin)?;
+        pin.set_pull(Pull::Up)?;
+        pin.set_interrupt_type(InterruptType::AnyEdge)?;
+
+        let notification = Notification::new();
+        let notifier = notification.notifier();
+        // SAFETY: the callback only notifies a task, which is ISR safe
+        unsafe {
+            pin.subscribe(move || {
+                notifier.notify_and_yield(NonZeroU32::MIN);
+            })?;
+        }
+        Ok(Self { pin, notification })
+    }
+}
+
+impl Source for Button {
+    fn wait_for(&mut self, pressed: bool, timeout: Option<Duration>) -> Result<bool> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            // interrupts disarm themselves after firing
+            self.pin.enable_interrupt()?;
+            std::thread::sleep(DEBOUNCE);
+            if self.pin.is_low() == pressed {
+                return Ok(true);
+            }
+
+            let ticks = match deadline {
+                None => esp_idf_hal::delay::BLOCK,
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Ok(false);
+                    }
+                    TickType::from(left).0
+                }
+            };
+            if self.notification.wait(ticks).is_none() {
+                return Ok(self.pin.is_low() == pressed);
+            }
+        }
+    }
+}
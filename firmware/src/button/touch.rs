@@ -0,0 +1,107 @@
+//! A touch pad in place of the button. Its idle reading is taken at start
+//! and follows the slow drift humidity and temperature cause while it
+//! isn't touched; a touch is a reading `THRESHOLD_RATIO` above it for
+//! `DEBOUNCE_SAMPLES` in a row, and it's released once the reading falls
+//! back below half that.
+
+use super::Source;
+use anyhow::{bail, Result};
+use esp_idf_sys::{self as sys, esp};
+use std::time::{Duration, Instant};
+
+const POLL: Duration = Duration::from_millis(20);
+/// Readings averaged into the first idle reading.
+const CALIBRATION_SAMPLES: u32 = 16;
+/// A finger raises the S3's reading far more than this share, a drop of
+/// water or a cable moving doesn't.
+const THRESHOLD_RATIO: f32 = 0.2;
+/// Samples in a row that have to agree before the state flips.
+const DEBOUNCE_SAMPLES: u32 = 3;
+/// How far the idle reading moves towards each untouched sample.
+const DRIFT: f32 = 1.0 / 64.0;
+
+pub struct Pad {
+    pad: u32,
+    idle: f32,
+    touched: bool,
+    /// Samples in a row that disagree with `touched`.
+    against: u32,
+}
+
+impl Pad {
+    pub fn new(pad: u32) -> Result<Self> {
+        if !(1..sys::touch_pad_t_TOUCH_PAD_MAX).contains(&pad) {
+            bail!("touch pad {pad} doesn't exist");
+        }
+        unsafe {
+            esp!(sys::touch_pad_init())?;
+            esp!(sys::touch_pad_config(pad))?;
+            esp!(sys::touch_pad_set_fsm_mode(
+                sys::touch_fsm_mode_t_TOUCH_FSM_MODE_TIMER
+            ))?;
+            esp!(sys::touch_pad_fsm_start())?;
+        }
+        let mut pad = Self {
+            pad,
+            idle: 0.0,
+            touched: false,
+            against: 0,
+        };
+        // the first readings after the start aren't settled
+        std::thread::sleep(POLL * 5);
+        let mut sum = 0.0;
+        for _ in 0..CALIBRATION_SAMPLES {
+            sum += pad.read()?;
+            std::thread::sleep(POLL);
+        }
+        pad.idle = sum / CALIBRATION_SAMPLES as f32;
+        log::info!("touch pad {}: idle at {:.0}", pad.pad, pad.idle);
+        Ok(pad)
+    }
+
+    fn read(&self) -> Result<f32> {
+        let mut raw = 0;
+        esp!(unsafe { sys::touch_pad_read_raw_data(self.pad, &mut raw) })?;
+        Ok(raw as f32)
+    }
+
+    /// Takes one sample and updates the debounced state.
+    fn sample(&mut self) -> Result<()> {
+        let raw = self.read()?;
+        let threshold = self.idle * THRESHOLD_RATIO;
+        let touching = if self.touched {
+            raw > self.idle + threshold / 2.0
+        } else {
+            raw > self.idle + threshold
+        };
+        if touching == self.touched {
+            self.against = 0;
+            if !self.touched {
+                self.idle += (raw - self.idle) * DRIFT;
+            }
+            return Ok(());
+        }
+        self.against += 1;
+        if self.against >= DEBOUNCE_SAMPLES {
+            self.touched = touching;
+            self.against = 0;
+        }
+        Ok(())
+    }
+}
+
+impl Source for Pad {
+    fn wait_for(&mut self, pressed: bool, timeout: Option<Duration>) -> Result<bool> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            if self.touched == pressed {
+                return Ok(true);
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(false);
+            }
+            std::thread::sleep(POLL);
+            self.sample()?;
+        }
+    }
+}
@@ -0,0 +1,249 @@
+//! A passive piezo on LEDC PWM, for installs without a screen: what the
+//! device is doing can be heard from the ladder. It plays on events from
+//! the bus, quiet during `quiet_hours`:
+//!
+//! - connected: two rising tones, lost: two falling ones
+//! - a button press: a click, a long press: a long low tone
+//! - an update pending: three short high beeps
+//! - an error: its code in beeps, `ERROR_*` below
+//!
+//! A sound is written in a small pattern language, words separated by spaces:
+//! `<ms>` is a tone of `DEFAULT_HZ`, `<ms>@<hz>` one of its own pitch,
+//! `_<ms>` a rest and `<n>*<tone>` the tone `n` times with rests as long
+//! between them. `beep <pattern>` on the console plays one.
+
+use crate::{
+    console,
+    events::{self, Event},
+};
+use anyhow::{bail, Context, Result};
+use esp_idf_hal::{
+    gpio::OutputPin,
+    ledc::{config::TimerConfig, LedcChannel, LedcDriver, LedcTimer, LedcTimerDriver},
+    peripheral::Peripheral,
+    units::FromValueType,
+};
+use esp_idf_sys::{self as sys, esp};
+use std::{
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        mpsc::{self, RecvTimeoutError, SyncSender, TrySendError},
+        Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::broadcast::error::TryRecvError;
+
+/// Around where most small piezos are loudest.
+const DEFAULT_HZ: u32 = 2700;
+/// How often the bus is looked at between sounds.
+const POLL: Duration = Duration::from_millis(50);
+/// Console sounds waiting to be played.
+const QUEUE: usize = 4;
+const STACK_SIZE: usize = 3072;
+
+/// The beep counts of the errors.
+const ERROR_WIFI: u32 = 1;
+const ERROR_FETCH: u32 = 2;
+const ERROR_HEAP: u32 = 3;
+const ERROR_BATTERY: u32 = 4;
+const ERROR_HEAT: u32 = 5;
+
+/// Until the config is loaded, the same as the config default.
+static VOLUME: AtomicU8 = AtomicU8::new(50);
+static SOUNDS: Mutex<Option<SyncSender<Vec<Tone>>>> = Mutex::new(None);
+
+/// One step of a sound, a rest when `hz` is 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Tone {
+    hz: u32,
+    millis: u32,
+}
+
+/// Reads a sound in the pattern language.
+fn parse(pattern: &str) -> Result<Vec<Tone>> {
+    let mut tones = Vec::new();
+    for word in pattern.split_whitespace() {
+        if let Some(rest) = word.strip_prefix('_') {
+            tones.push(Tone {
+                hz: 0,
+                millis: millis(rest)?,
+            });
+            continue;
+        }
+        let (count, tone) = match word.split_once('*') {
+            Some((count, tone)) => (
+                count
+                    .parse()
+                    .with_context(|| format!("{count} in {word} isn't a count"))?,
+                tone,
+            ),
+            None => (1, word),
+        };
+        let (length, hz) = match tone.split_once('@') {
+            Some((length, hz)) => (
+                length,
+                hz.parse()
+                    .with_context(|| format!("{hz} in {word} isn't a pitch"))?,
+            ),
+            None => (tone, DEFAULT_HZ),
+        };
+        if !(20..=20_000).contains(&hz) {
+            bail!("{hz} Hz in {word} can't be heard");
+        }
+        let millis = millis(length)?;
+        for n in 0..count {
+            if n > 0 {
+                tones.push(Tone { hz: 0, millis });
+            }
+            tones.push(Tone { hz, millis });
+        }
+    }
+    Ok(tones)
+}
+
+fn millis(value: &str) -> Result<u32> {
+    let millis = value
+        .parse()
+        .with_context(|| format!("{value} isn't milliseconds"))?;
+    if millis > 10_000 {
+        bail!("{millis} ms is longer than a beep");
+    }
+    Ok(millis)
+}
+
+/// Error `code` as that many beeps, with a pause after so that codes in a
+/// row can be counted apart.
+fn error(code: u32) -> String {
+    format!("{code}*150@2000 _800")
+}
+
+/// The sound for `event`, if it has one.
+fn sound(event: &Event) -> Option<String> {
+    let pattern = match event {
+        Event::NetUp => "80@2000 _40 120@3000",
+        Event::NetDown => "80@3000 _40 120@2000",
+        Event::ShortPress | Event::DoublePress => "15@4000",
+        Event::LongPress => "600@1000",
+        Event::OtaPending => "3*40@3500",
+        Event::WifiDead => return Some(error(ERROR_WIFI)),
+        Event::FetchDone { ok: false } => return Some(error(ERROR_FETCH)),
+        Event::LowHeap { .. } => return Some(error(ERROR_HEAP)),
+        Event::LowBattery { .. } => return Some(error(ERROR_BATTERY)),
+        Event::Overheat { .. } => return Some(error(ERROR_HEAT)),
+        _ => return None,
+    };
+    Some(pattern.to_owned())
+}
+
+pub struct Buzzer {
+    driver: LedcDriver<'static>,
+    timer: sys::ledc_timer_t,
+}
+
+impl Buzzer {
+    pub fn new<T: LedcTimer + 'static, C: LedcChannel<SpeedMode = T::SpeedMode>>(
+        timer: impl Peripheral<P = T> + 'static,
+        channel: impl Peripheral<P = C> + 'static,
+        pin: impl Peripheral<P = impl OutputPin> + 'static,
+    ) -> Result<Self> {
+        let timer_driver =
+            LedcTimerDriver::new(timer, &TimerConfig::new().frequency(DEFAULT_HZ.Hz()))?;
+        let mut driver = LedcDriver::new(channel, timer_driver, pin)?;
+        driver.set_duty(0)?;
+        Ok(Self {
+            driver,
+            timer: T::timer(),
+        })
+    }
+
+    fn play(&mut self, tones: &[Tone]) -> Result<()> {
+        let result = tones.iter().try_for_each(|tone| self.tone(tone));
+        self.driver.set_duty(0)?;
+        result
+    }
+
+    fn tone(&mut self, tone: &Tone) -> Result<()> {
+        let volume = u32::from(VOLUME.load(Ordering::Relaxed));
+        if tone.hz == 0 || volume == 0 {
+            self.driver.set_duty(0)?;
+        } else {
+            esp!(unsafe {
+                sys::ledc_set_freq(sys::ledc_mode_t_LEDC_LOW_SPEED_MODE, self.timer, tone.hz)
+            })?;
+            // a square wave is loudest at half duty, less is quieter
+            self.driver
+                .set_duty(self.driver.get_max_duty() * volume / 200)?;
+        }
+        std::thread::sleep(Duration::from_millis(tone.millis.into()));
+        Ok(())
+    }
+}
+
+pub fn configure(config: &crate::config::Config) {
+    VOLUME.store(
+        u8::try_from(config.buzzer_volume.min(100)).unwrap_or(100),
+        Ordering::Relaxed,
+    );
+}
+
+/// Plays the event bus's sounds, and the console's, on a thread of its own.
+pub fn start(mut buzzer: Buzzer) -> Result<()> {
+    let mut events = events::subscribe();
+    let (sender, sounds) = mpsc::sync_channel(QUEUE);
+    *SOUNDS.lock().unwrap() = Some(sender);
+
+    std::thread::Builder::new()
+        .name("buzzer".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || loop {
+            match sounds.recv_timeout(POLL) {
+                Ok(tones) => {
+                    if let Err(err) = buzzer.play(&tones) {
+                        log::warn!("buzzer: {err:#}");
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            loop {
+                let event = match events.try_recv() {
+                    Ok(event) => event,
+                    Err(TryRecvError::Lagged(_)) => continue,
+                    Err(_) => break,
+                };
+                let Some(pattern) = sound(&event) else {
+                    continue;
+                };
+                if events::state().quiet {
+                    continue;
+                }
+                if let Err(err) = parse(&pattern).and_then(|tones| buzzer.play(&tones)) {
+                    log::warn!("buzzer: {err:#}");
+                }
+            }
+        })
+        .context("couldn't spawn buzzer")?;
+
+    console::register(console::Command {
+        name: "beep",
+        usage: "beep <pattern>",
+        summary: "play a pattern on the buzzer, e.g. 3*100@2000 _500",
+        run: beep,
+    });
+    Ok(())
+}
+
+fn beep(_: &console::Console, args: &[&str]) -> Result<String> {
+    let tones = parse(&args.join(" "))?;
+    if tones.is_empty() {
+        bail!("usage: beep <pattern>");
+    }
+    let sounds = SOUNDS.lock().unwrap();
+    let sender = sounds.as_ref().context("no buzzer")?;
+    match sender.try_send(tones) {
+        Ok(()) => Ok(String::from("playing")),
+        Err(TrySendError::Full(_)) => bail!("the buzzer is still busy"),
+        Err(TrySendError::Disconnected(_)) => bail!("the buzzer stopped"),
+    }
+}
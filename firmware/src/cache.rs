@@ -0,0 +1,129 @@
+//! Small persistent cache on the filesystem for answers worth keeping
+//! across reboots, each with an expiry. Values are a file each, the index
+//! is kept in memory, least recently used first, and written out when an
+//! entry is added. Past `BUDGET` bytes the least recently used entries
+//! are evicted. Expiry is wall clock time, so nothing is served before the
+//! clock is synced.
+
+use crate::{events, fs};
+use anyhow::{ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{sync::Mutex, time::Duration};
+
+const INDEX: &str = "cache.json";
+/// Bytes of values kept in all, a slice of the storage partition.
+const BUDGET: usize = 64 * 1024;
+pub const MAX_VALUE: usize = 16 * 1024;
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Entry {
+    key: String,
+    file: String,
+    size: usize,
+    /// Unix seconds.
+    expires: i64,
+}
+
+/// `None` until the index is read from flash.
+static ENTRIES: Mutex<Option<Vec<Entry>>> = Mutex::new(None);
+
+/// The value under `key` and how long it has left, `None` if missing or
+/// expired.
+pub async fn get(key: &str) -> Option<(Vec<u8>, Duration)> {
+    let now = now()?;
+    let (file, expires) = index(|entries| {
+        let position = entries.iter().position(|entry| entry.key == key)?;
+        let entry = entries.remove(position);
+        let found = (entry.file.clone(), entry.expires);
+        if entry.expires > now {
+            entries.push(entry);
+        }
+        Some(found)
+    })
+    .await?;
+    if expires <= now {
+        let _ = fs::remove(&file).await;
+        return None;
+    }
+
+    match fs::read(&file).await {
+        Ok(value) => Some((value, Duration::from_secs((expires - now) as u64))),
+        Err(err) => {
+            log::debug!("cache: {key}: {err:#}");
+            index(|entries| entries.retain(|entry| entry.key != key)).await;
+            None
+        }
+    }
+}
+
+/// Stores `value` under `key` for `ttl`, evicting what no longer fits.
+pub async fn put(key: &str, value: Vec<u8>, ttl: Duration) -> Result<()> {
+    let now = now().context("no wall clock time to expire the entry by")?;
+    ensure!(
+        value.len() <= MAX_VALUE,
+        "a {} byte value exceeds the cache cap",
+        value.len()
+    );
+    let file = format!("cache-{:016x}", fnv1a(key));
+    let size = value.len();
+    fs::write(&file, value).await?;
+
+    let evicted = index(|entries| {
+        entries.retain(|entry| entry.key != key);
+        entries.push(Entry {
+            key: key.to_owned(),
+            file,
+            size,
+            expires: now + ttl.as_secs() as i64,
+        });
+        let mut evicted = Vec::new();
+        entries.retain(|entry| {
+            let live = entry.expires > now;
+            if !live {
+                evicted.push(entry.file.clone());
+            }
+            live
+        });
+        // the new entry is last and always fits the budget on its own
+        while entries.iter().map(|entry| entry.size).sum::<usize>() > BUDGET {
+            evicted.push(entries.remove(0).file);
+        }
+        evicted
+    })
+    .await;
+    for file in evicted {
+        let _ = fs::remove(&file).await;
+    }
+
+    let index = index(|entries| serde_json::to_vec(entries)).await?;
+    Ok(fs::write(INDEX, index).await?)
+}
+
+/// Runs `f` on the index, reading it in on first use.
+async fn index<T>(f: impl FnOnce(&mut Vec<Entry>) -> T) -> T {
+    if ENTRIES.lock().unwrap().is_none() {
+        let entries = match fs::read(INDEX).await {
+            Ok(index) => serde_json::from_slice(&index).unwrap_or_else(|err| {
+                log::warn!("cache: dropping an unreadable index: {err}");
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        ENTRIES.lock().unwrap().get_or_insert(entries);
+    }
+    let mut entries = ENTRIES.lock().unwrap();
+    f(entries.as_mut().unwrap())
+}
+
+fn now() -> Option<i64> {
+    events::state()
+        .time_synced
+        .then(|| time::UtcDateTime::now().unix_timestamp())
+}
+
+/// For file names, keys can hold anything.
+fn fnv1a(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
@@ -0,0 +1,290 @@
+use crate::{
+    events::{self, Event},
+    net::{self, NetTransport, NetworkManager},
+    runtime, telemetry,
+};
+use anyhow::{bail, Context, Result};
+use esp_idf_hal::{
+    delay::TickType,
+    gpio::AnyIOPin,
+    uart::{config::Config as UartConfig, UartDriver, UART1},
+    units::Hertz,
+};
+use esp_idf_svc::{
+    handle::RawHandle,
+    netif::{EspNetif, EspNetifDriver, NetifStack, PppConfiguration},
+    sys,
+};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+const BAUDRATE: u32 = 115_200;
+const DEFAULT_APN: &str = "internet";
+const AT_TIMEOUT: Duration = Duration::from_secs(2);
+const DIAL_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long WiFi may stay down before the modem dials on its own.
+const FALLBACK_DELAY: Duration = Duration::from_secs(120);
+const FALLBACK_POLL: Duration = Duration::from_secs(5);
+const LINK_POLL: Duration = Duration::from_secs(1);
+const PUMP_STACK_SIZE: usize = 4096;
+
+static APN: Mutex<String> = Mutex::new(String::new());
+
+/// SIM7600 wiring.
+pub struct Pins {
+    pub tx: AnyIOPin,
+    pub rx: AnyIOPin,
+}
+
+/// LTE modem on UART1 carrying a PPP session. It stays on standby while
+/// WiFi works and only dials once the watchdog gives up on WiFi, then
+/// hangs up again as soon as WiFi is back. Needs `CONFIG_LWIP_PPP_SUPPORT=y`.
+pub struct Cellular {
+    uart: Arc<UartDriver<'static>>,
+    ppp: Ppp,
+    online: Arc<AtomicBool>,
+    pump: Option<JoinHandle<()>>,
+}
+
+struct Ppp(EspNetifDriver<'static, EspNetif>);
+
+// the driver only hands esp_netif's own handle and the UART to the lwIP
+// thread, both of which are safe to use from any task
+unsafe impl Send for Ppp {}
+
+pub fn new(uart: UART1, pins: Pins) -> Result<Cellular> {
+    let uart = Arc::new(UartDriver::new(
+        uart,
+        pins.tx,
+        pins.rx,
+        Option::<AnyIOPin>::None,
+        Option::<AnyIOPin>::None,
+        &UartConfig::new().baudrate(Hertz(BAUDRATE)),
+    )?);
+
+    let tx = uart.clone();
+    let ppp = EspNetifDriver::new(
+        EspNetif::new(NetifStack::Ppp)?,
+        |netif| {
+            netif.set_ppp_conf(&PppConfiguration {
+                phase_events_enabled: false,
+                ..Default::default()
+            })
+        },
+        move |data| tx.write(data).map(|_| ()),
+    )?;
+
+    Ok(Cellular {
+        uart,
+        ppp: Ppp(ppp),
+        online: Arc::new(AtomicBool::new(false)),
+        pump: None,
+    })
+}
+
+pub fn configure(config: &crate::config::Config) {
+    *APN.lock().unwrap() = if config.cellular_apn.is_empty() {
+        String::from(DEFAULT_APN)
+    } else {
+        config.cellular_apn.clone()
+    };
+}
+
+impl NetTransport for Cellular {
+    fn netif(&self) -> &EspNetif {
+        self.ppp.0.netif()
+    }
+}
+
+impl NetworkManager for Cellular {
+    fn name(&self) -> &'static str {
+        "cellular"
+    }
+
+    async fn connect(&mut self) -> Result<()> {
+        standby().await;
+
+        let uart = self.uart.clone();
+        let apn = APN.lock().unwrap().clone();
+        runtime::run_blocking(move || dial(&uart, &apn)).await??;
+
+        self.online.store(true, Ordering::Relaxed);
+        let uart = self.uart.clone();
+        let online = self.online.clone();
+        let netif = self.netif().handle() as usize;
+        self.pump = Some(
+            std::thread::Builder::new()
+                .name("ppp-rx".into())
+                .stack_size(PUMP_STACK_SIZE)
+                .spawn(move || pump(&uart, netif as *mut sys::esp_netif_t, &online))
+                .context("couldn't spawn the ppp pump")?,
+        );
+        self.ppp.0.start()?;
+
+        let deadline = Instant::now() + DIAL_TIMEOUT;
+        while !self.netif().is_up()? {
+            if Instant::now() >= deadline {
+                self.hangup().await;
+                bail!("ppp negotiation timed out");
+            }
+            runtime::sleep(LINK_POLL).await;
+        }
+        Ok(())
+    }
+
+    async fn wait_disconnected(&mut self) -> Result<()> {
+        loop {
+            runtime::sleep(LINK_POLL).await;
+            if !self.netif().is_up()? {
+                break;
+            }
+            if net::link_up("wifi") {
+                log::info!("wifi is back, hanging up cellular");
+                break;
+            }
+        }
+        self.hangup().await;
+        Ok(())
+    }
+}
+
+impl Cellular {
+    async fn hangup(&mut self) {
+        // EspNetifDriver::stop() refuses to run since start() never records
+        // that it ran, so stop the netif directly
+        unsafe {
+            sys::esp_netif_action_stop(
+                self.netif().handle() as *mut _,
+                core::ptr::null_mut(),
+                0,
+                core::ptr::null_mut(),
+            );
+        }
+        self.online.store(false, Ordering::Relaxed);
+        if let Some(pump) = self.pump.take() {
+            let _ = runtime::run_blocking(move || pump.join()).await;
+        }
+
+        let uart = self.uart.clone();
+        let result = runtime::run_blocking(move || {
+            // +++ needs a second of silence on either side to be taken as
+            // an escape rather than data
+            std::thread::sleep(Duration::from_secs(1));
+            uart.write(b"+++")?;
+            std::thread::sleep(Duration::from_secs(1));
+            command(&uart, "ATH", "OK", AT_TIMEOUT)
+        })
+        .await;
+        if let Err(err) = result.and_then(|result| result.map(|_| ())) {
+            log::warn!("modem hangup failed: {err:#}");
+        }
+    }
+}
+
+/// Waits until cellular is actually needed: the watchdog declared WiFi
+/// dead, or WiFi has not been up for `FALLBACK_DELAY`.
+async fn standby() {
+    let mut events = events::subscribe();
+    let mut down_since = None;
+
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                if event == Ok(Event::WifiDead) {
+                    log::warn!("wifi declared dead, falling back to cellular");
+                    return;
+                }
+            }
+            _ = runtime::sleep(FALLBACK_POLL) => {
+                if net::link_up("wifi") {
+                    down_since = None;
+                } else if down_since.get_or_insert_with(Instant::now).elapsed() >= FALLBACK_DELAY {
+                    log::warn!("wifi down for {FALLBACK_DELAY:?}, falling back to cellular");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Takes the modem from command mode into a PPP data call.
+fn dial(uart: &UartDriver, apn: &str) -> Result<()> {
+    command(uart, "AT", "OK", AT_TIMEOUT).context("modem not responding")?;
+    command(uart, "ATE0", "OK", AT_TIMEOUT)?;
+
+    let rssi = signal_quality(uart)?;
+    log::info!("cellular signal {}", describe(rssi));
+    telemetry::set("cellular_rssi", rssi);
+
+    command(
+        uart,
+        &format!("AT+CGDCONT=1,\"IP\",\"{apn}\""),
+        "OK",
+        AT_TIMEOUT,
+    )?;
+    command(uart, "ATD*99#", "CONNECT", DIAL_TIMEOUT).context("modem didn't connect")?;
+    log::info!("cellular data call up on apn {apn}");
+    Ok(())
+}
+
+/// Signal strength in dBm from `AT+CSQ`, `None` while the modem can't tell.
+/// Only readable in command mode, so it's sampled each time the modem dials.
+fn signal_quality(uart: &UartDriver) -> Result<Option<i32>> {
+    let response = command(uart, "AT+CSQ", "OK", AT_TIMEOUT)?;
+    let rssi = response
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("+CSQ:"))
+        .and_then(|value| value.split(',').next())
+        .and_then(|value| value.trim().parse::<i32>().ok())
+        .context("malformed +CSQ response")?;
+
+    // 0..=31 maps onto -113..=-51 dBm, 99 means unknown
+    Ok((rssi != 99).then(|| -113 + 2 * rssi))
+}
+
+fn describe(rssi: Option<i32>) -> String {
+    match rssi {
+        Some(dbm) => format!("{dbm} dBm"),
+        None => String::from("unknown"),
+    }
+}
+
+/// Sends an AT command and collects the reply until `expect` shows up.
+fn command(uart: &UartDriver, command: &str, expect: &str, timeout: Duration) -> Result<String> {
+    uart.write(format!("{command}\r").as_bytes())?;
+
+    let deadline = Instant::now() + timeout;
+    let mut response = String::new();
+    let mut buf = [0; 64];
+    while Instant::now() < deadline {
+        let len = uart.read(&mut buf, TickType::new_millis(100).ticks())?;
+        response.push_str(&String::from_utf8_lossy(&buf[..len]));
+        if response.contains(expect) {
+            return Ok(response);
+        }
+        if response.contains("ERROR") || response.contains("NO CARRIER") {
+            bail!("{command} failed: {}", response.trim());
+        }
+    }
+    bail!("{command} timed out")
+}
+
+/// Feeds everything the modem sends into the PPP netif until hangup.
+fn pump(uart: &UartDriver, netif: *mut sys::esp_netif_t, online: &AtomicBool) {
+    let mut buf = [0; 512];
+    while online.load(Ordering::Relaxed) {
+        match uart.read(&mut buf, TickType::new_millis(100).ticks()) {
+            Ok(0) => {}
+            Ok(len) => unsafe {
+                sys::esp_netif_receive(netif, buf.as_mut_ptr().cast(), len, core::ptr::null_mut());
+            },
+            Err(err) => log::warn!("ppp uart read failed: {err}"),
+        }
+    }
+}
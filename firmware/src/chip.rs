@@ -0,0 +1,71 @@
+//! What the target chip can do, for the code that differs between the
+//! ESP32-S3, C3 and C6. The chip follows `MCU` and the target in
+//! .cargo/config.toml; esp-idf-sys turns it into the `esp32s3`, `esp32c3`
+//! or `esp32c6` cfg this module keys on.
+
+#[cfg(not(any(esp32s3, esp32c3, esp32c6)))]
+compile_error!("unsupported chip, set MCU to esp32s3, esp32c3 or esp32c6");
+
+#[derive(Clone, Copy, Debug)]
+pub struct Capabilities {
+    pub name: &'static str,
+    pub max_cpu_mhz: i32,
+    pub ble: bool,
+    /// RMT channels able to transmit, the indicator takes channel 0.
+    pub rmt_tx_channels: u8,
+    /// Octal PSRAM on the module, see sdkconfig.defaults.esp32s3.
+    pub psram: bool,
+    pub cores: u8,
+    /// The `chip_id` of an app image built for it, `esp_chip_id_t`.
+    pub image_id: u16,
+}
+
+#[cfg(esp32s3)]
+pub const CHIP: Capabilities = Capabilities {
+    name: "esp32s3",
+    max_cpu_mhz: 240,
+    ble: true,
+    rmt_tx_channels: 4,
+    psram: true,
+    cores: 2,
+    image_id: 9,
+};
+
+#[cfg(esp32c3)]
+pub const CHIP: Capabilities = Capabilities {
+    name: "esp32c3",
+    max_cpu_mhz: 160,
+    ble: true,
+    rmt_tx_channels: 2,
+    psram: false,
+    cores: 1,
+    image_id: 5,
+};
+
+#[cfg(esp32c6)]
+pub const CHIP: Capabilities = Capabilities {
+    name: "esp32c6",
+    max_cpu_mhz: 160,
+    ble: true,
+    rmt_tx_channels: 2,
+    psram: false,
+    cores: 1,
+    image_id: 13,
+};
+
+/// The silicon revision, `major * 100 + minor` as app images give theirs.
+pub fn revision() -> u16 {
+    let mut info = esp_idf_sys::esp_chip_info_t::default();
+    unsafe { esp_idf_sys::esp_chip_info(&mut info) };
+    info.revision
+}
+
+/// Core the async main thread is pinned to, `None` on single core chips.
+/// On the S3 the runtime gets the second core to itself and the WiFi and
+/// lwIP tasks keep the first.
+pub fn runtime_core() -> Option<esp_idf_hal::cpu::Core> {
+    #[cfg(esp32s3)]
+    return Some(esp_idf_hal::cpu::Core::Core1);
+    #[cfg(not(esp32s3))]
+    return None;
+}
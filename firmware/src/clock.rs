@@ -0,0 +1,76 @@
+use crate::{error::FirmwareError, events, telemetry};
+use std::{
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(feature = "ntp-auth")]
+mod ntp;
+#[cfg(feature = "sntp")]
+mod sntp;
+#[cfg(feature = "sntp")]
+pub use sntp::Sntp;
+
+/// Something that sets the system clock over the network.
+// NTP is the only one on the device
+#[cfg_attr(not(feature = "sntp"), allow(dead_code))]
+pub trait TimeSource: Send {
+    fn name(&self) -> &'static str;
+
+    /// Returns once the clock is set and `TimeSynced` is published.
+    fn sync(&mut self) -> impl Future<Output = Result<(), FirmwareError>> + Send;
+}
+
+/// How far a server's `Date` may be from the clock before it's a warning;
+/// the header has whole seconds and the response took a while to arrive.
+const SKEW_LIMIT: Duration = Duration::from_secs(60);
+
+/// Whether the last `Date` was past `SKEW_LIMIT`, warned about once.
+static SKEWED: AtomicBool = AtomicBool::new(false);
+
+/// Compares `date`, the `Date` header of an HTTPS response from `url`, with
+/// the synced clock. The skew goes into telemetry as `clock_skew_s`,
+/// positive when the clock is ahead, and a warning is logged when it goes
+/// past `SKEW_LIMIT`: an SNTP server that answers wrong or an RTC that
+/// drifted, which nothing else would notice.
+pub fn check_skew(url: &str, date: &str) {
+    if !events::state().time_synced {
+        return;
+    }
+    let (Some(server), Ok(now)) = (
+        parse_http_date(date),
+        SystemTime::now().duration_since(UNIX_EPOCH),
+    ) else {
+        return;
+    };
+    let skew = now.as_secs() as i64 - server as i64;
+    telemetry::set("clock_skew_s", skew);
+
+    let skewed = skew.unsigned_abs() > SKEW_LIMIT.as_secs();
+    if skewed != SKEWED.swap(skewed, Ordering::Relaxed) {
+        if skewed {
+            log::warn!("clock is {skew}s off the Date of {url}, check the time source");
+        } else {
+            log::info!("clock back within {SKEW_LIMIT:?} of the Date of {url}");
+        }
+    }
+}
+
+/// Unix seconds of an IMF-fixdate, `Sun, 06 Nov 1994 08:49:37 GMT`, the
+/// only form servers are to send.
+pub fn parse_http_date(value: &str) -> Option<u64> {
+    let format = time::macros::format_description!(
+        "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT"
+    );
+    let date = time::PrimitiveDateTime::parse(value.trim(), format).ok()?;
+    date.assume_utc().unix_timestamp().try_into().ok()
+}
+
+pub fn format_time() -> String {
+    time::UtcDateTime::now()
+        .format(time::macros::format_description!(
+            "[day]-[month repr:short]-[year] [hour]:[minute]:[second]"
+        ))
+        .unwrap_or(String::from("<invalid>"))
+}
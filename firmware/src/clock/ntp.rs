@@ -0,0 +1,207 @@
+//! NTP with a symmetric key, RFC 5905's MAC, for networks where a forged
+//! time would get expired or not yet valid certificates past verification.
+//! `ntp_key` is a line of a chrony key file, `<id> <SHA1|SHA256> <key>`,
+//! the key as text or `HEX:` and its bytes, the same the server has. The
+//! MAC is the digest of the key and the packet, cut to 20 bytes as chrony
+//! and ntpd do for SHA256; MD5 keys aren't taken.
+//!
+//! A request carries a random transmit timestamp, and only an answer that
+//! echoes it, from a synced server, under the key, sets the clock. Until
+//! one does the exchange is tried again, the way the SNTP client keeps
+//! polling.
+
+use crate::{
+    dns,
+    events::{self, Event},
+    runtime,
+    secret::{constant_time_eq, Secret},
+};
+use anyhow::{bail, ensure, Context, Result};
+use ring::digest;
+use std::{
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const PORT: u16 = 123;
+const PACKET: usize = 48;
+/// What the MAC is cut to.
+const MAC: usize = 20;
+/// Leap indicator 0, version 4, client mode.
+const REQUEST: u8 = 0x23;
+const SERVER_MODE: u8 = 4;
+/// Seconds from the NTP epoch, 1900, to the Unix one.
+const UNIX_OFFSET: i128 = 2_208_988_800;
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(2);
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// A longer round trip makes the offset too uncertain to take.
+const MAX_DELAY: Duration = Duration::from_secs(2);
+
+/// The key in `ntp_key`.
+#[derive(Clone)]
+pub struct Key {
+    id: u32,
+    algorithm: &'static digest::Algorithm,
+    secret: Secret<Vec<u8>>,
+}
+
+impl Key {
+    /// `None` when `value` is empty.
+    pub fn parse(value: &str) -> Result<Option<Self>> {
+        let value = value.trim();
+        if value.is_empty() {
+            return Ok(None);
+        }
+        let mut words = value.split_whitespace();
+        let (Some(id), Some(kind), Some(secret), None) =
+            (words.next(), words.next(), words.next(), words.next())
+        else {
+            bail!("ntp_key isn't <id> <type> <key>");
+        };
+        let id = id.parse().context("ntp_key id isn't a number")?;
+        let algorithm = match kind.to_ascii_uppercase().as_str() {
+            "SHA1" => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+            "SHA256" => &digest::SHA256,
+            other => bail!("ntp_key type {other} isn't SHA1 or SHA256"),
+        };
+        let secret = match secret.strip_prefix("HEX:") {
+            Some(hex) => unhex(hex).context("ntp_key HEX: isn't hex")?,
+            None => secret.as_bytes().to_vec(),
+        };
+        Ok(Some(Self {
+            id,
+            algorithm,
+            secret: Secret::new(secret),
+        }))
+    }
+
+    fn mac(&self, packet: &[u8]) -> [u8; MAC] {
+        let mut context = digest::Context::new(self.algorithm);
+        context.update(self.secret.expose());
+        context.update(packet);
+        let mut mac = [0; MAC];
+        mac.copy_from_slice(&context.finish().as_ref()[..MAC]);
+        mac
+    }
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    // an odd length runs past the end on the last pair
+    (0..text.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(text.get(at..at + 2)?, 16).ok())
+        .collect()
+}
+
+/// Sets the clock from `server` under `key`, retrying until it can.
+pub async fn sync(server: &str, key: &Key) -> Result<()> {
+    loop {
+        match exchange(server, key).await {
+            Ok(offset) => {
+                log::info!(
+                    "authenticated ntp sync, {offset:.3}s off, current time: {}",
+                    super::format_time()
+                );
+                events::publish(Event::TimeSynced);
+                return Ok(());
+            }
+            Err(err) => log::warn!("ntp {server}: {err:#}"),
+        }
+        runtime::sleep(RETRY_DELAY).await;
+    }
+}
+
+/// One request and answer, the clock set by it; the offset it was off.
+async fn exchange(server: &str, key: &Key) -> Result<f64> {
+    let ip = *dns::resolve(server)
+        .await?
+        .iter()
+        .find(|ip| ip.is_ipv4())
+        .context("no ipv4 address")?;
+    let key = key.clone();
+    runtime::run_blocking(move || {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_read_timeout(Some(ANSWER_TIMEOUT))?;
+        socket.connect(SocketAddr::new(ip, PORT))?;
+
+        let mut request = [0; PACKET + 4 + MAC];
+        request[0] = REQUEST;
+        let cookie: [u8; 8] = std::array::from_fn(|_| crate::device::random() as u8);
+        request[40..48].copy_from_slice(&cookie);
+        request[PACKET..PACKET + 4].copy_from_slice(&key.id.to_be_bytes());
+        let mac = key.mac(&request[..PACKET]);
+        request[PACKET + 4..].copy_from_slice(&mac);
+
+        let sent = now();
+        socket.send(&request)?;
+        let mut answer = [0; 128];
+        let len = socket.recv(&mut answer).context("no answer")?;
+        let received = now();
+        let answer = &answer[..len];
+
+        ensure!(len >= PACKET + 4 + MAC, "answer without a MAC");
+        let (packet, auth) = answer.split_at(len - 4 - MAC);
+        ensure!(
+            auth[..4] == key.id.to_be_bytes(),
+            "answer under key {}",
+            u32::from_be_bytes(auth[..4].try_into().unwrap())
+        );
+        ensure!(
+            constant_time_eq(&auth[4..], &key.mac(packet)),
+            "answer MAC doesn't verify"
+        );
+        ensure!(packet[24..32] == cookie, "answer isn't to our request");
+        ensure!(
+            packet[0] & 0x07 == SERVER_MODE,
+            "answer isn't from a server"
+        );
+        ensure!(packet[0] >> 6 != 3, "server isn't synced");
+        ensure!(
+            (1..16).contains(&packet[1]),
+            "server sent stratum {}",
+            packet[1]
+        );
+
+        let server_received = timestamp(&packet[32..40]);
+        let server_sent = timestamp(&packet[40..48]);
+        let delay = (received - sent) - (server_sent - server_received);
+        ensure!(
+            delay < MAX_DELAY.as_nanos() as i128,
+            "round trip of {delay}ns"
+        );
+        let offset = ((server_received - sent) + (server_sent - received)) / 2;
+        set_clock(now() + offset)?;
+        Ok(offset as f64 / 1e9)
+    })
+    .await?
+}
+
+/// Unix nanoseconds of the system clock.
+fn now() -> i128 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_nanos() as i128,
+        Err(err) => -(err.duration().as_nanos() as i128),
+    }
+}
+
+/// Unix nanoseconds of an NTP timestamp; one with the top bit clear is
+/// taken to be past 2036, in the next era.
+fn timestamp(bytes: &[u8]) -> i128 {
+    let mut seconds = i128::from(u32::from_be_bytes(bytes[..4].try_into().unwrap()));
+    if seconds < 0x8000_0000 {
+        seconds += 1 << 32;
+    }
+    let fraction = i128::from(u32::from_be_bytes(bytes[4..8].try_into().unwrap()));
+    (seconds - UNIX_OFFSET) * 1_000_000_000 + ((fraction * 1_000_000_000) >> 32)
+}
+
+fn set_clock(nanos: i128) -> Result<()> {
+    let tv = esp_idf_sys::timeval {
+        tv_sec: nanos.div_euclid(1_000_000_000) as _,
+        tv_usec: (nanos.rem_euclid(1_000_000_000) / 1000) as _,
+    };
+    if unsafe { esp_idf_sys::settimeofday(&tv, std::ptr::null()) } != 0 {
+        bail!("couldn't set the system time");
+    }
+    Ok(())
+}
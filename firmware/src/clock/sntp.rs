@@ -0,0 +1,76 @@
+use crate::{
+    config::Config,
+    error::{Failure, FirmwareError},
+    events::{self, Event},
+    runtime,
+};
+use anyhow::Result;
+use esp_idf_svc::sntp::{EspSntp, OperatingMode, SntpConf, SyncMode, SyncStatus};
+
+/// The `ntp_server` client: authenticated by `ntp_key` when the build has
+/// `ntp-auth` and the key is set, see `clock::ntp`.
+#[derive(Clone)]
+pub struct Sntp {
+    server: String,
+    #[cfg(feature = "ntp-auth")]
+    key: Option<super::ntp::Key>,
+}
+
+impl Sntp {
+    pub fn new(config: &Config) -> Result<Self> {
+        #[cfg(feature = "ntp-auth")]
+        let key = super::ntp::Key::parse(config.ntp_key.expose())?;
+        #[cfg(feature = "ntp-auth")]
+        if key.is_none() {
+            log::warn!(
+                "no ntp_key, the time from {} isn't authenticated",
+                config.ntp_server
+            );
+        }
+        Ok(Self {
+            server: config.ntp_server.clone(),
+            #[cfg(feature = "ntp-auth")]
+            key,
+        })
+    }
+}
+
+impl super::TimeSource for Sntp {
+    fn name(&self) -> &'static str {
+        "ntp"
+    }
+
+    /// Only starting the client can fail, while another sync still holds
+    /// it, which passes.
+    async fn sync(&mut self) -> Result<(), FirmwareError> {
+        #[cfg(feature = "ntp-auth")]
+        if let Some(key) = &self.key {
+            return super::ntp::sync(&self.server, key)
+                .await
+                .map_err(|err| FirmwareError::Time(Failure::retryable(err)));
+        }
+        sync(&self.server)
+            .await
+            .map_err(|err| FirmwareError::Time(Failure::retryable(err)))
+    }
+}
+
+async fn sync(server: &str) -> Result<()> {
+    let client = EspSntp::new(&SntpConf {
+        servers: [server],
+        operating_mode: OperatingMode::Poll,
+        sync_mode: SyncMode::Immediate,
+    })?;
+
+    while client.get_sync_status() != SyncStatus::Completed {
+        runtime::sleep(std::time::Duration::from_secs(1)).await;
+    }
+
+    log::info!(
+        "ntp syncing completed, current time: {}",
+        super::format_time()
+    );
+    events::publish(Event::TimeSynced);
+
+    Ok(())
+}
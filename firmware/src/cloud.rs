@@ -0,0 +1,90 @@
+//! Cloud backends: the device's own JWTs, OAuth2 for APIs that act on
+//! behalf of a user, and the AWS IoT Core, Azure IoT Hub and plain HTTPS
+//! connectors behind `CloudConnector`, with what they share.
+
+#[cfg(feature = "cloud")]
+pub mod auth;
+#[cfg(feature = "aws")]
+pub mod aws;
+#[cfg(feature = "azure")]
+pub mod azure;
+#[cfg(any(feature = "aws", feature = "azure", feature = "cloud-https"))]
+mod connector;
+#[cfg(feature = "cloud-https")]
+pub mod https;
+#[cfg(feature = "oauth")]
+pub mod oauth;
+
+#[cfg(any(feature = "aws", feature = "azure", feature = "cloud-https"))]
+pub use connector::{start, CloudConnector, OtaStatus};
+
+#[cfg(any(feature = "aws", feature = "azure"))]
+use crate::config::Config;
+#[cfg(any(feature = "aws", feature = "azure"))]
+use anyhow::{Context, Result};
+#[cfg(any(feature = "aws", feature = "azure"))]
+use rustls::{client::ResolvesClientCert, sign::SingleCertAndKey};
+#[cfg(any(feature = "aws", feature = "azure"))]
+use std::sync::{Arc, OnceLock};
+
+#[cfg(any(feature = "aws", feature = "azure"))]
+static CLIENT_CERT: OnceLock<Arc<SingleCertAndKey>> = OnceLock::new();
+
+/// Pairs `client_cert` with `client_key` for `client_cert()`, before the
+/// first TLS client builds its config. Either empty leaves TLS without a
+/// software client certificate.
+#[cfg(any(feature = "aws", feature = "azure"))]
+pub fn configure(config: &Config) -> Result<()> {
+    use base64::Engine;
+    use rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+        sign::CertifiedKey,
+    };
+
+    if config.client_cert.is_empty() || config.client_key.expose().is_empty() {
+        return Ok(());
+    }
+    let engine = base64::engine::general_purpose::STANDARD;
+    let cert = engine
+        .decode(config.client_cert.trim())
+        .context("client_cert isn't base64")?;
+    let key = engine
+        .decode(config.client_key.expose().trim())
+        .context("client_key isn't base64")?;
+    let key = rustls::crypto::ring::sign::any_supported_type(&PrivateKeyDer::Pkcs8(
+        PrivatePkcs8KeyDer::from(key),
+    ))
+    .context("client_key isn't a pkcs8 key rustls can sign with")?;
+    let key = CertifiedKey::new(vec![CertificateDer::from(cert)], key);
+    key.keys_match()
+        .context("client_key doesn't match client_cert")?;
+    let _ = CLIENT_CERT.set(Arc::new(SingleCertAndKey::from(key)));
+    log::info!("cloud: client certificate loaded");
+    Ok(())
+}
+
+/// The resolver for `tls::client_config_with()`, once `configure()` found
+/// a certificate and key.
+#[cfg(any(feature = "aws", feature = "azure"))]
+pub fn client_cert() -> Option<Arc<dyn ResolvesClientCert>> {
+    CLIENT_CERT
+        .get()
+        .map(|resolver| resolver.clone() as Arc<dyn ResolvesClientCert>)
+}
+
+/// Applies a shadow's or twin's desired fields through `Config::apply_remote()`,
+/// blocking on NVS, and returns the changed fields and what to report for
+/// every field asked for: its value now, secrets redacted.
+#[cfg(any(feature = "aws", feature = "azure"))]
+pub fn apply_desired(
+    nvs: esp_idf_svc::nvs::EspDefaultNvsPartition,
+    desired: &serde_json::Map<String, serde_json::Value>,
+) -> Result<(Vec<String>, serde_json::Map<String, serde_json::Value>)> {
+    let changed = Config::apply_remote(nvs.clone(), desired)?;
+    let config = Config::load(nvs)?.redacted();
+    let reported = desired
+        .keys()
+        .filter_map(|field| Some((field.clone(), config.get(field)?.clone())))
+        .collect();
+    Ok((changed, reported))
+}
@@ -0,0 +1,227 @@
+//! JWTs signed with the device key in `jwt_key`, the way GCP-style MQTT
+//! brokers take them as the password: `iat` and `exp` from the synced
+//! clock, `aud` from `jwt_audience` and the device id as `sub`. The
+//! algorithm follows the key, ES256 for P-256 and RS256 for RSA.
+//!
+//! A token is reused until `REFRESH_MARGIN` before it expires. A task
+//! mints the next one ahead of that, so a reconnect never waits on an RSA
+//! signature.
+
+use crate::{config::Config, console, device, events, runtime, secret::Secret};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::{
+    rand::SystemRandom,
+    signature::{self, EcdsaKeyPair, RsaKeyPair},
+};
+use std::{
+    sync::{Mutex, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// GCP accepts up to a day, an hour limits what a leaked token is good for.
+const LIFETIME: Duration = Duration::from_secs(60 * 60);
+const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+static AUTH: OnceLock<Auth> = OnceLock::new();
+
+struct Auth {
+    key: Key,
+    audience: String,
+    token: Mutex<Option<Token>>,
+}
+
+enum Key {
+    Es256(EcdsaKeyPair),
+    Rs256(RsaKeyPair),
+}
+
+struct Token {
+    jwt: Secret<String>,
+    /// Unix time of `exp`.
+    expires: u64,
+}
+
+/// Loads the key and starts refreshing tokens once the clock is set.
+/// Without a key the backends get no JWT.
+pub fn configure(config: &Config) -> Result<()> {
+    if config.jwt_key.expose().is_empty() {
+        return Ok(());
+    }
+    ensure!(
+        !config.jwt_audience.is_empty(),
+        "jwt_key needs a jwt_audience"
+    );
+    let key = Secret::new(
+        base64::engine::general_purpose::STANDARD
+            .decode(config.jwt_key.expose().trim())
+            .context("jwt_key isn't base64")?,
+    );
+    let rng = SystemRandom::new();
+    let key = match EcdsaKeyPair::from_pkcs8(
+        &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+        key.expose(),
+        &rng,
+    ) {
+        Ok(key) => Key::Es256(key),
+        Err(_) => Key::Rs256(
+            RsaKeyPair::from_pkcs8(key.expose())
+                .map_err(|err| anyhow!("jwt_key isn't a P-256 or RSA pkcs8 key: {err}"))?,
+        ),
+    };
+
+    let auth = Auth {
+        key,
+        audience: config.jwt_audience.clone(),
+        token: Mutex::new(None),
+    };
+    log::info!(
+        "cloud auth: {} jwts for {}",
+        auth.key.algorithm(),
+        auth.audience
+    );
+    if AUTH.set(auth).is_err() {
+        bail!("cloud auth already configured");
+    }
+
+    console::register(console::Command {
+        name: "jwt",
+        usage: "jwt [show]",
+        summary: "the backend jwt's expiry, or the token itself",
+        run: command,
+    });
+    runtime::spawn(refresh_ahead());
+    Ok(())
+}
+
+/// Whether `token()` has a key to sign with.
+pub fn configured() -> bool {
+    AUTH.get().is_some()
+}
+
+/// A token with more than `REFRESH_MARGIN` left, minted now if the cached
+/// one is closer to expiring than that.
+pub fn token() -> Result<String> {
+    let auth = AUTH.get().context("no jwt_key configured")?;
+    let mut token = auth.token.lock().unwrap();
+    if token.as_ref().map_or(true, |token| {
+        token.expires < now() + REFRESH_MARGIN.as_secs()
+    }) {
+        *token = Some(auth.mint()?);
+    }
+    Ok(token.as_ref().unwrap().jwt.expose().clone())
+}
+
+/// Unix time the cached token expires at, if there is one.
+fn expires() -> Option<u64> {
+    let auth = AUTH.get()?;
+    let token = auth.token.lock().unwrap();
+    token.as_ref().map(|token| token.expires)
+}
+
+async fn refresh_ahead() {
+    events::wait_until(|state| state.time_synced).await;
+    loop {
+        let delay = match runtime::run_blocking(token).await.and_then(|token| token) {
+            Ok(_) => {
+                let expires = expires().unwrap_or_default();
+                // wakes just inside the margin, when `token()` mints anew
+                Duration::from_secs(expires.saturating_sub(now() + REFRESH_MARGIN.as_secs()) + 1)
+            }
+            Err(err) => {
+                log::warn!("cloud auth: couldn't mint a jwt: {err:#}");
+                RETRY_DELAY
+            }
+        };
+        runtime::sleep(delay).await;
+    }
+}
+
+fn command(_: &console::Console, args: &[&str]) -> Result<String> {
+    match args {
+        [] => {
+            let auth = AUTH.get().context("no jwt_key configured")?;
+            Ok(match expires() {
+                Some(expires) => format!(
+                    "{} jwt for {}, expires in {}s",
+                    auth.key.algorithm(),
+                    auth.audience,
+                    expires.saturating_sub(now())
+                ),
+                None => format!(
+                    "{} jwt for {}, none minted yet",
+                    auth.key.algorithm(),
+                    auth.audience
+                ),
+            })
+        }
+        ["show"] => token(),
+        _ => bail!("usage: jwt [show]"),
+    }
+}
+
+impl Auth {
+    fn mint(&self) -> Result<Token> {
+        ensure!(
+            events::state().time_synced,
+            "the clock isn't set, a jwt would be stamped 1970"
+        );
+        let issued = now();
+        let expires = issued + LIFETIME.as_secs();
+        let header = serde_json::json!({ "alg": self.key.algorithm(), "typ": "JWT" });
+        let claims = serde_json::json!({
+            "iat": issued,
+            "exp": expires,
+            "aud": self.audience,
+            "sub": device::id(),
+        });
+        let mut jwt = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?)
+        );
+        let signature = self.key.sign(jwt.as_bytes())?;
+        jwt.push('.');
+        jwt.push_str(&URL_SAFE_NO_PAD.encode(signature));
+        log::info!("cloud auth: minted a jwt valid for {LIFETIME:?}");
+        Ok(Token {
+            jwt: Secret::new(jwt),
+            expires,
+        })
+    }
+}
+
+impl Key {
+    fn algorithm(&self) -> &'static str {
+        match self {
+            Self::Es256(_) => "ES256",
+            Self::Rs256(_) => "RS256",
+        }
+    }
+
+    /// ES256 is the raw R||S JWS wants, not DER.
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let rng = SystemRandom::new();
+        match self {
+            Self::Es256(key) => Ok(key
+                .sign(&rng, message)
+                .map_err(|_| anyhow!("es256 signing failed"))?
+                .as_ref()
+                .to_vec()),
+            Self::Rs256(key) => {
+                let mut signature = vec![0; key.public().modulus_len()];
+                key.sign(&signature::RSA_PKCS1_SHA256, &rng, message, &mut signature)
+                    .map_err(|_| anyhow!("rs256 signing failed"))?;
+                Ok(signature)
+            }
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
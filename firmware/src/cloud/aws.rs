@@ -0,0 +1,193 @@
+//! AWS IoT Core on top of the MQTT client, for `cloud = "aws"`:
+//! `mqtt_broker` is the account's `<prefix>-ats.iot.<region>.amazonaws.com`
+//! endpoint on 8883, the thing name is the device id, and the thing's
+//! certificate and key come from `client_cert` and `client_key` unless an
+//! ATECC608 holds the key.
+//!
+//! The thing shadow's `reported` state carries the telemetry sample and
+//! every config field the device has taken from `desired`; a delta goes
+//! through `Config::apply_remote()` like a remote config document. Jobs are
+//! `{"operation": "reboot"}`; an `ota` job is rejected, there's no updater
+//! to finish it yet and IN_PROGRESS would hang it until it timed out.
+
+use super::{CloudConnector, OtaStatus};
+use crate::{
+    device,
+    events::{self, Event},
+    mqtt, runtime, telemetry,
+};
+use anyhow::{Context, Result};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{sync::OnceLock, time::Duration};
+
+/// Lets the job's status update out before the restart.
+const REBOOT_GRACE: Duration = Duration::from_secs(2);
+
+static NVS: OnceLock<EspDefaultNvsPartition> = OnceLock::new();
+
+#[derive(Deserialize)]
+struct Execution {
+    #[serde(rename = "jobId")]
+    job_id: String,
+    #[serde(rename = "jobDocument", default)]
+    document: Value,
+}
+
+pub struct Aws {
+    nvs: EspDefaultNvsPartition,
+}
+
+impl Aws {
+    pub fn new(nvs: EspDefaultNvsPartition) -> Self {
+        Self { nvs }
+    }
+}
+
+impl CloudConnector for Aws {
+    fn name(&self) -> &'static str {
+        "aws"
+    }
+
+    /// Hooks the shadow and jobs topics into the MQTT session, which
+    /// `mqtt::start()` has to have opened to the AWS endpoint.
+    fn connect(&self) -> Result<()> {
+        mqtt::session().context("aws iot needs mqtt_broker set to the endpoint")?;
+        let _ = NVS.set(self.nvs.clone());
+        hook_topics();
+        log::info!("aws iot: thing {}", device::id());
+        Ok(())
+    }
+
+    /// Puts the telemetry sample into the shadow's `reported` state; the
+    /// MQTT client sends it to the telemetry topic as well.
+    fn publish_telemetry(&self) -> Result<()> {
+        mqtt::session()?.publish(
+            &shadow("update"),
+            serde_json::to_vec(&json!({ "state": { "reported": telemetry::snapshot() } }))?,
+        )
+    }
+
+    /// Nothing to report: no job is left waiting on an update, see
+    /// `run_job()`.
+    fn report_ota(&self, _: &OtaStatus) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn hook_topics() {
+    mqtt::on_message(shadow("get/accepted"), |payload| {
+        // a shadow without a delta is already in sync
+        if let Some(delta) = parse(payload).and_then(|shadow| shadow["state"].get("delta").cloned())
+        {
+            apply_delta(delta);
+        }
+    });
+    mqtt::on_message(shadow("update/delta"), |payload| {
+        if let Some(delta) = parse(payload).and_then(|delta| delta.get("state").cloned()) {
+            apply_delta(delta);
+        }
+    });
+    mqtt::on_message(shadow("get/rejected"), |payload| {
+        // 404 means no shadow yet, the first report creates it
+        log::debug!(
+            "aws shadow get rejected: {}",
+            String::from_utf8_lossy(payload)
+        );
+    });
+    mqtt::on_message(jobs("notify-next"), |payload| {
+        if parse(payload).is_some_and(|notify| notify.get("execution").is_some()) {
+            publish(&jobs("start-next"), &json!({}));
+        }
+    });
+    mqtt::on_message(jobs("start-next/accepted"), |payload| {
+        let Some(execution) =
+            parse(payload).and_then(|accepted| accepted.get("execution").cloned())
+        else {
+            return;
+        };
+        match serde_json::from_value::<Execution>(execution) {
+            Ok(execution) => run_job(execution),
+            Err(err) => log::warn!("aws job execution malformed: {err}"),
+        }
+    });
+    mqtt::on_connect(|| {
+        publish(&shadow("get"), &json!({}));
+        publish(&jobs("start-next"), &json!({}));
+    });
+}
+
+fn shadow(topic: &str) -> String {
+    format!("$aws/things/{}/shadow/{topic}", device::id())
+}
+
+fn jobs(topic: &str) -> String {
+    format!("$aws/things/{}/jobs/{topic}", device::id())
+}
+
+fn parse(payload: &[u8]) -> Option<Value> {
+    serde_json::from_slice(payload)
+        .map_err(|err| log::warn!("aws: malformed message: {err}"))
+        .ok()
+}
+
+fn publish(topic: &str, message: &Value) {
+    let result = serde_json::to_vec(message)
+        .map_err(anyhow::Error::from)
+        .and_then(|payload| mqtt::session()?.publish(topic, payload));
+    if let Err(err) = result {
+        log::warn!("aws: couldn't publish to {topic}: {err:#}");
+    }
+}
+
+/// Applies the desired fields off the MQTT task and reports them back so
+/// the delta clears. Secrets are reported redacted, so theirs stays.
+fn apply_delta(delta: Value) {
+    let Value::Object(changes) = delta else {
+        return;
+    };
+    let Some(nvs) = NVS.get().cloned() else {
+        return;
+    };
+    runtime::spawn(async move {
+        let result = runtime::run_blocking(move || super::apply_desired(nvs, &changes)).await;
+        match result.and_then(|result| result) {
+            Ok((changed, reported)) => {
+                publish(
+                    &shadow("update"),
+                    &json!({ "state": { "reported": reported } }),
+                );
+                if !changed.is_empty() {
+                    log::info!("aws shadow changed {}", changed.join(", "));
+                    events::publish(Event::ConfigChanged);
+                }
+            }
+            Err(err) => log::warn!("aws shadow delta not applied: {err:#}"),
+        }
+    });
+}
+
+fn run_job(execution: Execution) {
+    log::info!("aws job {}: {}", execution.job_id, execution.document);
+    let update = jobs(&format!("{}/update", execution.job_id));
+    match execution.document["operation"].as_str() {
+        Some("reboot") => {
+            publish(&update, &json!({ "status": "SUCCEEDED" }));
+            runtime::spawn(async {
+                runtime::sleep(REBOOT_GRACE).await;
+                esp_idf_hal::reset::restart();
+            });
+        }
+        Some("ota") => reject(&update, "ota isn't supported by this firmware"),
+        _ => reject(&update, "unknown operation"),
+    }
+}
+
+fn reject(update: &str, reason: &str) {
+    log::warn!("aws job rejected: {reason}");
+    publish(
+        update,
+        &json!({ "status": "REJECTED", "statusDetails": { "reason": reason } }),
+    );
+}
@@ -0,0 +1,462 @@
+//! Azure IoT Hub for `cloud = "azure"`, reached through the Device
+//! Provisioning Service: every boot registers the device id with DPS under
+//! `azure_id_scope`, and DPS answers with the hub and device id to connect
+//! as. Both take a SAS token signed with the enrollment key, or the
+//! `client_cert` for X.509 enrollments.
+//!
+//! The hub session is left for a fresh one before its token expires. The
+//! twin's desired properties go through `Config::apply_remote()` and are reported
+//! back; telemetry goes out as device-to-cloud messages and cloud-to-device
+//! messages come in as commands.
+
+use super::{CloudConnector, OtaStatus};
+use crate::{
+    config::Config,
+    device,
+    events::{self, Event},
+    mqtt, runtime, telemetry,
+};
+use anyhow::{bail, ensure, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use ring::hmac;
+use rumqttc::{AsyncClient, EventLoop, Packet, QoS};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::{
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const DPS_HOST: &str = "global.azure-devices-provisioning.net";
+const PORT: u16 = 8883;
+const DPS_API: &str = "2019-03-31";
+const HUB_API: &str = "2021-04-12";
+/// SAS tokens are signed for this long and the session renewed ahead of
+/// `RENEW_MARGIN` before.
+const TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+const RENEW_MARGIN: Duration = Duration::from_secs(5 * 60);
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const REQUEST_CAPACITY: usize = 10;
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+/// Registration polls wait this long when DPS leaves out `retry-after`.
+const DEFAULT_RETRY_AFTER: u64 = 3;
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(60);
+/// `$rid` of the twin GET, so its response isn't taken for a patch ack.
+const TWIN_GET_RID: &str = "get";
+
+static SESSION: Mutex<Option<(AsyncClient, String)>> = Mutex::new(None);
+
+#[derive(Clone)]
+struct Settings {
+    id_scope: String,
+    /// The signing key, already derived from a group key; `None` is X.509.
+    key: Option<Vec<u8>>,
+    nvs: EspDefaultNvsPartition,
+}
+
+/// Where DPS put the device.
+struct Assignment {
+    hub: String,
+    device_id: String,
+}
+
+#[derive(Deserialize)]
+struct Operation {
+    #[serde(rename = "operationId")]
+    operation_id: String,
+    status: String,
+    #[serde(rename = "registrationState")]
+    registration_state: Option<RegistrationState>,
+}
+
+#[derive(Deserialize)]
+struct RegistrationState {
+    #[serde(rename = "assignedHub")]
+    assigned_hub: Option<String>,
+    #[serde(rename = "deviceId")]
+    device_id: Option<String>,
+    #[serde(rename = "errorMessage")]
+    error_message: Option<String>,
+}
+
+pub struct Azure {
+    settings: Settings,
+}
+
+impl Azure {
+    pub fn new(config: &Config, nvs: EspDefaultNvsPartition) -> Result<Self> {
+        ensure!(
+            !config.azure_id_scope.is_empty(),
+            "the azure cloud needs azure_id_scope"
+        );
+        let key = match (
+            config.azure_device_key.expose().as_str(),
+            config.azure_group_key.expose().as_str(),
+        ) {
+            ("", "") if config.client_cert.is_empty() => {
+                bail!("azure needs azure_device_key, azure_group_key or client_cert")
+            }
+            ("", "") => None,
+            ("", group) => {
+                let group = STANDARD
+                    .decode(group.trim())
+                    .context("azure_group_key isn't base64")?;
+                // the group's key signs the registration id into the device's
+                let tag = hmac::sign(
+                    &hmac::Key::new(hmac::HMAC_SHA256, &group),
+                    device::id().as_bytes(),
+                );
+                Some(tag.as_ref().to_vec())
+            }
+            (device_key, _) => Some(
+                STANDARD
+                    .decode(device_key.trim())
+                    .context("azure_device_key isn't base64")?,
+            ),
+        };
+        Ok(Self {
+            settings: Settings {
+                id_scope: config.azure_id_scope.clone(),
+                key,
+                nvs,
+            },
+        })
+    }
+}
+
+impl CloudConnector for Azure {
+    fn name(&self) -> &'static str {
+        "azure"
+    }
+
+    /// Registers with DPS and keeps the hub session up in the background.
+    fn connect(&self) -> Result<()> {
+        let settings = self.settings.clone();
+        runtime::spawn(async move {
+            loop {
+                events::wait_until(|state| state.net_up && state.time_synced).await;
+                if let Err(err) = run(&settings).await {
+                    log::warn!("azure: {err:#}");
+                }
+                *SESSION.lock().unwrap() = None;
+                runtime::sleep(RETRY_DELAY).await;
+            }
+        });
+        Ok(())
+    }
+
+    /// Sends the telemetry sample as a device-to-cloud message.
+    fn publish_telemetry(&self) -> Result<()> {
+        let (client, device_id) = session()?;
+        client.try_publish(
+            format!("devices/{device_id}/messages/events/"),
+            QoS::AtLeastOnce,
+            false,
+            serde_json::to_vec(&telemetry::snapshot())?,
+        )?;
+        Ok(())
+    }
+
+    /// As the `firmware_update` reported property.
+    fn report_ota(&self, status: &OtaStatus) -> Result<()> {
+        let (client, _) = session()?;
+        let update = match status {
+            OtaStatus::Pending => json!({ "status": "pending" }),
+            OtaStatus::Succeeded => json!({ "status": "succeeded" }),
+            OtaStatus::Failed(reason) => json!({ "status": "failed", "reason": reason }),
+        };
+        report(&client, &json!({ "firmware_update": update }))
+    }
+}
+
+/// The connected hub client and the device id it's connected as.
+fn session() -> Result<(AsyncClient, String)> {
+    SESSION
+        .lock()
+        .unwrap()
+        .clone()
+        .context("azure not connected")
+}
+
+/// Patches the twin's reported properties.
+fn report(client: &AsyncClient, patch: &Value) -> Result<()> {
+    client.try_publish(
+        format!(
+            "$iothub/twin/PATCH/properties/reported/?$rid={}",
+            device::random()
+        ),
+        QoS::AtLeastOnce,
+        false,
+        serde_json::to_vec(patch)?,
+    )?;
+    Ok(())
+}
+
+/// One DPS registration, then hub sessions until one fails.
+async fn run(settings: &Settings) -> Result<()> {
+    let assignment = tokio::time::timeout(REGISTRATION_TIMEOUT, register(settings))
+        .await
+        .context("dps registration timed out")??;
+    log::info!(
+        "azure: assigned to {} as {}",
+        assignment.hub,
+        assignment.device_id
+    );
+
+    // the relay behind a socks proxy lives as long as the options
+    let mut options = mqtt::tls_options(&assignment.device_id, &assignment.hub, PORT)?;
+    options.set_keep_alive(KEEP_ALIVE);
+    let username = format!(
+        "{}/{}/?api-version={HUB_API}",
+        assignment.hub, assignment.device_id
+    );
+    loop {
+        let resource = format!("{}/devices/{}", assignment.hub, assignment.device_id);
+        options.set_credentials(
+            username.clone(),
+            settings.password(&resource, None).unwrap_or_default(),
+        );
+        let (client, mut eventloop) = AsyncClient::new(options.clone(), REQUEST_CAPACITY);
+        mqtt::tune(&mut eventloop);
+        *SESSION.lock().unwrap() = Some((client.clone(), assignment.device_id.clone()));
+        let renew = TOKEN_LIFETIME - RENEW_MARGIN;
+        tokio::select! {
+            result = hub_session(settings, &assignment.device_id, &client, eventloop) => result?,
+            _ = runtime::sleep(renew) => {
+                log::info!("azure: renewing the sas token");
+                let _ = client.try_disconnect();
+            }
+        }
+    }
+}
+
+async fn register(settings: &Settings) -> Result<Assignment> {
+    let registration_id = device::id();
+    let mut options = mqtt::tls_options(registration_id, DPS_HOST, PORT)?;
+    options.set_keep_alive(KEEP_ALIVE);
+    let resource = format!("{}/registrations/{registration_id}", settings.id_scope);
+    options.set_credentials(
+        format!(
+            "{}/registrations/{registration_id}/api-version={DPS_API}",
+            settings.id_scope
+        ),
+        settings
+            .password(&resource, Some("registration"))
+            .unwrap_or_default(),
+    );
+    let (client, mut eventloop) = AsyncClient::new(options, REQUEST_CAPACITY);
+    mqtt::tune(&mut eventloop);
+    client
+        .subscribe("$dps/registrations/res/#", QoS::AtLeastOnce)
+        .await?;
+    client
+        .publish(
+            "$dps/registrations/PUT/iotdps-register/?$rid=1",
+            QoS::AtLeastOnce,
+            false,
+            serde_json::to_vec(&json!({ "registrationId": registration_id }))?,
+        )
+        .await?;
+
+    let mut rid = 1;
+    loop {
+        let Packet::Publish(publish) = incoming(&mut eventloop).await? else {
+            continue;
+        };
+        // $dps/registrations/res/<status>/?$rid=<rid>[&retry-after=<secs>]
+        let Some(response) = publish.topic.strip_prefix("$dps/registrations/res/") else {
+            continue;
+        };
+        let (status, query) = response.split_once("/?").unwrap_or((response, ""));
+        let operation: Operation = serde_json::from_slice(&publish.payload).with_context(|| {
+            format!(
+                "dps answered {status}: {}",
+                String::from_utf8_lossy(&publish.payload)
+            )
+        })?;
+
+        match (status, operation.status.as_str()) {
+            ("200", "assigned") => {
+                let _ = client.disconnect().await;
+                let state = operation
+                    .registration_state
+                    .context("dps assigned without a registration state")?;
+                return Ok(Assignment {
+                    hub: state.assigned_hub.context("dps assigned no hub")?,
+                    device_id: state.device_id.context("dps assigned no device id")?,
+                });
+            }
+            ("200" | "202", "assigning" | "unassigned") => {
+                let retry_after = query
+                    .split('&')
+                    .find_map(|param| param.strip_prefix("retry-after="))
+                    .and_then(|secs| secs.parse().ok())
+                    .unwrap_or(DEFAULT_RETRY_AFTER);
+                runtime::sleep(Duration::from_secs(retry_after)).await;
+                rid += 1;
+                client
+                    .publish(
+                        format!(
+                            "$dps/registrations/GET/iotdps-get-operationstatus/?$rid={rid}&operationId={}",
+                            operation.operation_id
+                        ),
+                        QoS::AtLeastOnce,
+                        false,
+                        Vec::new(),
+                    )
+                    .await?;
+            }
+            (_, state) => bail!(
+                "dps registration {state}: {}",
+                operation
+                    .registration_state
+                    .and_then(|state| state.error_message)
+                    .unwrap_or_default()
+            ),
+        }
+    }
+}
+
+/// Runs the connected hub session: twin sync, cloud-to-device commands,
+/// and the acks for what `publish_telemetry()` sends.
+async fn hub_session(
+    settings: &Settings,
+    device_id: &str,
+    client: &AsyncClient,
+    mut eventloop: EventLoop,
+) -> Result<()> {
+    let devicebound = format!("devices/{device_id}/messages/devicebound/");
+    loop {
+        let publish = match incoming(&mut eventloop).await? {
+            Packet::ConnAck(_) => {
+                log::info!("azure: hub connected");
+                for topic in [
+                    String::from("$iothub/twin/res/#"),
+                    String::from("$iothub/twin/PATCH/properties/desired/#"),
+                    format!("{devicebound}#"),
+                ] {
+                    client.subscribe(topic, QoS::AtLeastOnce).await?;
+                }
+                client
+                    .publish(
+                        format!("$iothub/twin/GET/?$rid={TWIN_GET_RID}"),
+                        QoS::AtLeastOnce,
+                        false,
+                        Vec::new(),
+                    )
+                    .await?;
+                continue;
+            }
+            Packet::Publish(publish) => publish,
+            _ => continue,
+        };
+
+        let topic = publish.topic.as_str();
+        if topic.starts_with(&devicebound) {
+            let command = String::from_utf8_lossy(&publish.payload).into_owned();
+            log::info!("azure command: {command}");
+            events::publish(Event::Command(command));
+        } else if topic.starts_with("$iothub/twin/PATCH/properties/desired/") {
+            // one bad patch shouldn't drop the session and its subscriptions
+            match serde_json::from_slice(&publish.payload) {
+                Ok(desired) => apply_desired(settings, client, desired),
+                Err(err) => log::warn!("azure twin patch malformed, skipped: {err}"),
+            }
+        } else if let Some(response) = topic.strip_prefix("$iothub/twin/res/") {
+            let (status, query) = response.split_once("/?").unwrap_or((response, ""));
+            let get = query
+                .split('&')
+                .any(|param| param.strip_prefix("$rid=") == Some(TWIN_GET_RID));
+            match status {
+                "200" if get => match serde_json::from_slice::<Value>(&publish.payload) {
+                    Ok(twin) => apply_desired(settings, client, twin["desired"].clone()),
+                    Err(err) => log::warn!("azure twin malformed, skipped: {err}"),
+                },
+                "200" | "204" => {}
+                status => log::warn!("azure twin request answered {status}"),
+            }
+        }
+    }
+}
+
+/// The next packet from the broker, the connection errors as errors.
+async fn incoming(eventloop: &mut EventLoop) -> Result<Packet> {
+    loop {
+        if let rumqttc::Event::Incoming(packet) = eventloop.poll().await? {
+            return Ok(packet);
+        }
+    }
+}
+
+/// Applies the twin's desired properties off the session task and reports
+/// them back. The `$version` and `$metadata` keys aren't config fields.
+fn apply_desired(settings: &Settings, client: &AsyncClient, desired: Value) {
+    let Value::Object(desired) = desired else {
+        return;
+    };
+    let desired: Map<String, Value> = desired
+        .into_iter()
+        .filter(|(field, _)| !field.starts_with('$'))
+        .collect();
+    if desired.is_empty() {
+        return;
+    }
+    let nvs = settings.nvs.clone();
+    let client = client.clone();
+    runtime::spawn(async move {
+        let result = runtime::run_blocking(move || super::apply_desired(nvs, &desired)).await;
+        let (changed, reported) = match result.and_then(|result| result) {
+            Ok(applied) => applied,
+            Err(err) => {
+                log::warn!("azure twin desired properties not applied: {err:#}");
+                return;
+            }
+        };
+        if let Err(err) = report(&client, &Value::Object(reported)) {
+            log::warn!("azure: couldn't report the twin: {err:#}");
+        }
+        if !changed.is_empty() {
+            log::info!("azure twin changed {}", changed.join(", "));
+            events::publish(Event::ConfigChanged);
+        }
+    });
+}
+
+impl Settings {
+    /// A SAS token for `resource`, or `None` with X.509 where the
+    /// certificate is the credential.
+    fn password(&self, resource: &str, key_name: Option<&str>) -> Option<String> {
+        let key = self.key.as_ref()?;
+        let expiry = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            + TOKEN_LIFETIME.as_secs();
+        let resource = url_encode(resource);
+        let tag = hmac::sign(
+            &hmac::Key::new(hmac::HMAC_SHA256, key),
+            format!("{resource}\n{expiry}").as_bytes(),
+        );
+        let mut token = format!(
+            "SharedAccessSignature sr={resource}&sig={}&se={expiry}",
+            url_encode(&STANDARD.encode(tag))
+        );
+        if let Some(key_name) = key_name {
+            token.push_str(&format!("&skn={key_name}"));
+        }
+        Some(token)
+    }
+}
+
+/// Percent-encodes everything but RFC 3986's unreserved characters.
+fn url_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                char::from(byte).to_string()
+            }
+            byte => format!("%{byte:02X}"),
+        })
+        .collect()
+}
@@ -0,0 +1,66 @@
+use crate::{
+    config::Config,
+    events::{self, Event},
+    runtime,
+};
+use anyhow::{bail, Result};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use std::sync::Arc;
+
+/// Where a firmware update stands, as the backend is told.
+// only Pending is reported until there is an updater for the rest
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub enum OtaStatus {
+    Pending,
+    Succeeded,
+    Failed(String),
+}
+
+/// One vendor's backend, `cloud` in the config picks which. Commands the
+/// backend sends come out as `Event::Command` like every other channel's.
+pub trait CloudConnector: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Starts the session; it reconnects in the background from then on.
+    fn connect(&self) -> Result<()>;
+
+    /// Sends the telemetry sample. Errors only when it can't be queued.
+    fn publish_telemetry(&self) -> Result<()>;
+
+    fn report_ota(&self, status: &OtaStatus) -> Result<()>;
+}
+
+/// Connects the configured backend and tells it when an update is
+/// pending. `None` when `cloud` is empty.
+#[cfg_attr(not(any(feature = "aws", feature = "azure")), allow(unused_variables))]
+pub fn start(
+    config: &Config,
+    nvs: EspDefaultNvsPartition,
+) -> Result<Option<Arc<dyn CloudConnector>>> {
+    let connector: Arc<dyn CloudConnector> = match config.cloud.as_str() {
+        "" => return Ok(None),
+        #[cfg(feature = "aws")]
+        "aws" => Arc::new(super::aws::Aws::new(nvs)),
+        #[cfg(feature = "azure")]
+        "azure" => Arc::new(super::azure::Azure::new(config, nvs)?),
+        #[cfg(feature = "cloud-https")]
+        "https" => Arc::new(super::https::Https::new(config)?),
+        other => bail!("cloud {other} isn't built in"),
+    };
+    connector.connect()?;
+    log::info!("cloud: {} connector", connector.name());
+
+    let reporter = connector.clone();
+    runtime::spawn(async move {
+        let mut events = events::subscribe();
+        loop {
+            if let Ok(Event::OtaPending) = events.recv().await {
+                if let Err(err) = reporter.report_ota(&OtaStatus::Pending) {
+                    log::warn!("cloud: couldn't report the update: {err:#}");
+                }
+            }
+        }
+    });
+    Ok(Some(connector))
+}
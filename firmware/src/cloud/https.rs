@@ -0,0 +1,120 @@
+//! A backend of our own over plain HTTPS, for products not on a vendor's
+//! IoT service. Telemetry is POSTed to `<cloud_url>/telemetry`, commands
+//! are polled from `<cloud_url>/commands` as an array of strings, and
+//! update status goes to `<cloud_url>/ota`, all in JSON or the
+//! `cloud_format`. Every request carries the identity's signature headers.
+
+use super::{CloudConnector, OtaStatus};
+use crate::{
+    codec::Codec,
+    config::Config,
+    events::{self, Event},
+    identity, runtime, telemetry,
+};
+use anyhow::{ensure, Result};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+const COMMAND_POLL: Duration = Duration::from_secs(30);
+
+pub struct Https {
+    url: String,
+    client: reqwest::Client,
+    codec: Codec,
+}
+
+impl Https {
+    pub fn new(config: &Config) -> Result<Self> {
+        ensure!(
+            !config.cloud_url.is_empty(),
+            "the https cloud needs cloud_url"
+        );
+        Ok(Self {
+            url: config.cloud_url.trim_end_matches('/').to_owned(),
+            client: crate::http::client()?,
+            codec: Codec::parse(&config.cloud_format)?,
+        })
+    }
+
+    /// POSTs `body` to `path` in the background, the result is logged.
+    fn post(&self, path: &'static str, body: Value) {
+        let (client, codec) = (self.client.clone(), self.codec);
+        let url = format!("{}{path}", self.url);
+        runtime::spawn(async move {
+            if let Err(err) = send(client.post(&url), &url, "POST", codec, Some(&body)).await {
+                log::warn!("cloud post to {url} failed: {err:#}");
+            }
+        });
+    }
+}
+
+impl CloudConnector for Https {
+    fn name(&self) -> &'static str {
+        "https"
+    }
+
+    fn connect(&self) -> Result<()> {
+        let (client, codec) = (self.client.clone(), self.codec);
+        let url = format!("{}/commands", self.url);
+        runtime::spawn(async move {
+            loop {
+                events::wait_until(|state| state.net_up).await;
+                match poll(&client, &url, codec).await {
+                    Ok(commands) => {
+                        for command in commands {
+                            log::info!("cloud command: {command}");
+                            events::publish(Event::Command(command));
+                        }
+                    }
+                    Err(err) => log::warn!("cloud command poll failed: {err:#}"),
+                }
+                runtime::sleep(COMMAND_POLL).await;
+            }
+        });
+        Ok(())
+    }
+
+    fn publish_telemetry(&self) -> Result<()> {
+        self.post("/telemetry", telemetry::snapshot());
+        Ok(())
+    }
+
+    fn report_ota(&self, status: &OtaStatus) -> Result<()> {
+        self.post(
+            "/ota",
+            match status {
+                OtaStatus::Pending => json!({ "status": "pending" }),
+                OtaStatus::Succeeded => json!({ "status": "succeeded" }),
+                OtaStatus::Failed(reason) => json!({ "status": "failed", "reason": reason }),
+            },
+        );
+        Ok(())
+    }
+}
+
+async fn poll(client: &reqwest::Client, url: &str, codec: Codec) -> Result<Vec<String>> {
+    let response = send(client.get(url), url, "GET", codec, None).await?;
+    codec.decode(&response.bytes().await?)
+}
+
+async fn send(
+    mut request: reqwest::RequestBuilder,
+    url: &str,
+    method: &str,
+    codec: Codec,
+    body: Option<&Value>,
+) -> Result<reqwest::Response> {
+    use reqwest::header::{ACCEPT, CONTENT_TYPE};
+
+    for (name, value) in identity::sign_request(method, url) {
+        request = request.header(name, value);
+    }
+    request = request.header(ACCEPT, codec.content_type());
+    if let Some(body) = body {
+        request = request
+            .header(CONTENT_TYPE, codec.content_type())
+            .body(codec.encode(body)?);
+    }
+    crate::ratelimit::acquire("cloud").await?;
+    Ok(request.send().await?.error_for_status()?)
+}
@@ -0,0 +1,366 @@
+//! OAuth2 device authorization grant (RFC 8628), for APIs that act on
+//! behalf of a user rather than the device. The device asks for a code,
+//! the user enters it at the provider's page on a phone, and the device
+//! polls the token endpoint until they have.
+//!
+//! The refresh token outlives the boot in the `oauth` NVS namespace, under
+//! the same rule as the config's secrets: a release build only writes it
+//! to encrypted NVS and otherwise keeps it until the next restart. A new
+//! one is flushed before it's used, an old one may be all the provider
+//! still takes.
+//!
+//! `oauth get` is for checking a login from the console: it calls only
+//! under `oauth_api_url` and logs the status and size, never the body.
+
+use crate::{
+    config::{batch::Batched, Config, Store},
+    console,
+    events::{self, Event},
+    locale::PACK,
+    runtime,
+    secret::Secret,
+    security,
+};
+use anyhow::{bail, ensure, Context, Result};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use serde::Deserialize;
+use std::{
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+const COMMAND: &str = "oauth";
+const NAMESPACE: &str = "oauth";
+const REFRESH_KEY: &str = "refresh_token";
+const DEVICE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+/// The poll interval when the provider doesn't name one, per the RFC.
+const DEFAULT_INTERVAL: u64 = 5;
+/// Added to the interval on every `slow_down`.
+const SLOW_DOWN: Duration = Duration::from_secs(5);
+/// Access tokens this close to expiring are refreshed before use.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+/// Lifetime assumed when the token response leaves `expires_in` out.
+const DEFAULT_LIFETIME: u64 = 60 * 60;
+/// The most of an `expires_in` believed, a code or token is renewed
+/// before then anyway.
+const MAX_LIFETIME: u64 = 24 * 60 * 60;
+
+static OAUTH: OnceLock<OAuth> = OnceLock::new();
+
+struct OAuth {
+    client: reqwest::Client,
+    device_url: String,
+    token_url: String,
+    client_id: String,
+    client_secret: Secret<String>,
+    scope: String,
+    api_url: String,
+    store: Batched<EspNvs<NvsDefault>>,
+    refresh: Mutex<Option<Secret<String>>>,
+    access: Mutex<Option<Access>>,
+}
+
+struct Access {
+    token: Secret<String>,
+    expires: Instant,
+}
+
+#[derive(Deserialize)]
+struct DeviceCode {
+    device_code: String,
+    user_code: String,
+    // Google's endpoint still says url
+    #[serde(alias = "verification_url")]
+    verification_uri: String,
+    verification_uri_complete: Option<String>,
+    expires_in: u64,
+    #[serde(default = "default_interval")]
+    interval: u64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+    refresh_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+fn default_interval() -> u64 {
+    DEFAULT_INTERVAL
+}
+
+/// Loads the stored refresh token and registers the `oauth` command. A
+/// device that has none starts the login once the network is up.
+pub fn start(config: &Config, nvs: EspDefaultNvsPartition) -> Result<()> {
+    if config.oauth_token_url.is_empty() || config.oauth_client_id.is_empty() {
+        bail!("oauth_device_url needs oauth_token_url and oauth_client_id");
+    }
+    let store = Batched::new(open(nvs)?);
+    let refresh = store.get_str(REFRESH_KEY)?.map(Secret::new);
+    let logged_in = refresh.is_some();
+    let oauth = OAuth {
+        client: crate::http::client()?,
+        device_url: config.oauth_device_url.clone(),
+        token_url: config.oauth_token_url.clone(),
+        client_id: config.oauth_client_id.clone(),
+        client_secret: config.oauth_client_secret.clone(),
+        scope: config.oauth_scope.clone(),
+        api_url: config.oauth_api_url.clone(),
+        store,
+        refresh: Mutex::new(refresh),
+        access: Mutex::new(None),
+    };
+    if OAUTH.set(oauth).is_err() {
+        bail!("oauth already started");
+    }
+
+    console::register(console::Command {
+        name: COMMAND,
+        usage: "oauth [login | logout | get <url>]",
+        summary: "the user login, start or forget it, or call an api as the user",
+        run: command,
+    });
+
+    runtime::spawn(async move {
+        let mut events = events::subscribe();
+        if !logged_in {
+            events::wait_until(|state| state.net_up).await;
+            report(login().await);
+        }
+        loop {
+            let Ok(Event::Command(command)) = events.recv().await else {
+                continue;
+            };
+            let mut words = command.split_whitespace();
+            if words.next() != Some(COMMAND) {
+                continue;
+            }
+            if words.next() == Some("login") {
+                report(login().await);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// A bearer token for the user's APIs, refreshed when it's about to
+/// expire. Errors until the user has logged in.
+pub async fn access_token() -> Result<String> {
+    let oauth = OAUTH.get().context("oauth isn't configured")?;
+    if let Some(access) = oauth.access.lock().unwrap().as_ref() {
+        if access.expires > Instant::now() + EXPIRY_MARGIN {
+            return Ok(access.token.expose().clone());
+        }
+    }
+
+    let refresh = oauth
+        .refresh
+        .lock()
+        .unwrap()
+        .clone()
+        .context("no user logged in, run oauth login")?;
+    match oauth
+        .token(&[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh.expose().as_str()),
+        ])
+        .await?
+    {
+        Ok(response) => oauth.accept(response).await,
+        Err(error) if error == "invalid_grant" => {
+            oauth.forget().await?;
+            bail!("the user revoked the login, run oauth login")
+        }
+        Err(error) => bail!("token refresh failed: {error}"),
+    }
+}
+
+fn command(_: &console::Console, args: &[&str]) -> Result<String> {
+    let oauth = OAUTH.get().context("oauth isn't configured")?;
+    match args {
+        [] => Ok(String::from(if oauth.refresh.lock().unwrap().is_some() {
+            "logged in"
+        } else {
+            "no user logged in"
+        })),
+        ["login"] => {
+            events::publish(Event::Command(format!("{COMMAND} login")));
+            Ok(String::from("login started, the code is logged"))
+        }
+        ["logout"] => {
+            *oauth.refresh.lock().unwrap() = None;
+            *oauth.access.lock().unwrap() = None;
+            oauth.store.clone().remove(REFRESH_KEY)?;
+            Ok(String::from("logged out"))
+        }
+        // the console's own, not the bus's, so a user token only goes to
+        // the configured api
+        ["get", url] => {
+            ensure!(
+                under(&oauth.api_url, url),
+                "oauth get only calls under oauth_api_url"
+            );
+            let url = (*url).to_owned();
+            runtime::spawn(async move {
+                match get(&url).await {
+                    Ok(size) => log::info!("oauth get {url}: {size} bytes"),
+                    Err(err) => log::warn!("oauth get {url} failed: {err:#}"),
+                }
+            });
+            Ok(String::from("request sent, the outcome is logged"))
+        }
+        _ => bail!("usage: oauth [login | logout | get <url>]"),
+    }
+}
+
+/// Whether `url` is `base` or a path below it, not just a host that
+/// starts the same.
+fn under(base: &str, url: &str) -> bool {
+    let Some(rest) = url.strip_prefix(base).filter(|_| !base.is_empty()) else {
+        return false;
+    };
+    base.ends_with('/') || rest.is_empty() || rest.starts_with(['/', '?'])
+}
+
+fn report(result: Result<()>) {
+    #[cfg(feature = "display")]
+    crate::display::set_prompt(None);
+    match result {
+        Ok(()) => log::info!("oauth: logged in"),
+        Err(err) => log::warn!("oauth login failed: {err:#}"),
+    }
+}
+
+/// The whole device flow, from asking for a code to holding the tokens.
+async fn login() -> Result<()> {
+    let oauth = OAUTH.get().context("oauth isn't configured")?;
+    let mut form = vec![("client_id", oauth.client_id.as_str())];
+    if !oauth.scope.is_empty() {
+        form.push(("scope", oauth.scope.as_str()));
+    }
+    let code: DeviceCode = oauth
+        .client
+        .post(&oauth.device_url)
+        .form(&form)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+        .context("malformed device authorization response")?;
+
+    let login = match &code.verification_uri_complete {
+        Some(uri) => PACK.login(uri, None),
+        None => PACK.login(&code.verification_uri, Some(&code.user_code)),
+    };
+    log::warn!("oauth: {login}");
+    #[cfg(feature = "display")]
+    crate::display::set_prompt(Some(format!(
+        "{} {}",
+        code.verification_uri, code.user_code
+    )));
+
+    let deadline = Instant::now() + Duration::from_secs(code.expires_in.min(MAX_LIFETIME));
+    let mut interval = Duration::from_secs(code.interval);
+    loop {
+        runtime::sleep(interval).await;
+        if Instant::now() > deadline {
+            bail!("the code expired before the user entered it");
+        }
+        match oauth
+            .token(&[
+                ("grant_type", DEVICE_GRANT),
+                ("device_code", code.device_code.as_str()),
+            ])
+            .await?
+        {
+            Ok(response) => return oauth.accept(response).await.map(drop),
+            Err(error) if error == "authorization_pending" => {}
+            Err(error) if error == "slow_down" => interval += SLOW_DOWN,
+            Err(error) => bail!("authorization failed: {error}"),
+        }
+    }
+}
+
+/// `access_token()` with the bearer token, the size of the body.
+async fn get(url: &str) -> Result<usize> {
+    let oauth = OAUTH.get().context("oauth isn't configured")?;
+    let token = access_token().await?;
+    Ok(oauth
+        .client
+        .get(url)
+        .bearer_auth(token)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?
+        .len())
+}
+
+impl OAuth {
+    /// One token endpoint request: the tokens, or the OAuth error code.
+    async fn token(&self, grant: &[(&str, &str)]) -> Result<Result<TokenResponse, String>> {
+        let mut form = grant.to_vec();
+        form.push(("client_id", self.client_id.as_str()));
+        if !self.client_secret.expose().is_empty() {
+            form.push(("client_secret", self.client_secret.expose().as_str()));
+        }
+        crate::ratelimit::acquire("oauth").await?;
+        let response = self.client.post(&self.token_url).form(&form).send().await?;
+        if response.status().is_success() {
+            return Ok(Ok(response
+                .json()
+                .await
+                .context("malformed token response")?));
+        }
+        let status = response.status();
+        match response.json::<ErrorResponse>().await {
+            Ok(error) => Ok(Err(error.error)),
+            Err(_) => bail!("token endpoint answered {status}"),
+        }
+    }
+
+    /// Caches the access token and keeps a new refresh token, stored if
+    /// NVS may hold it.
+    async fn accept(&self, response: TokenResponse) -> Result<String> {
+        let lifetime = response
+            .expires_in
+            .unwrap_or(DEFAULT_LIFETIME)
+            .min(MAX_LIFETIME);
+        *self.access.lock().unwrap() = Some(Access {
+            token: Secret::new(response.access_token.clone()),
+            expires: Instant::now() + Duration::from_secs(lifetime),
+        });
+
+        // providers that don't rotate it leave it out, the old one stays
+        if let Some(refresh) = response.refresh_token {
+            let refresh = Secret::new(refresh);
+            *self.refresh.lock().unwrap() = Some(refresh.clone());
+            match security::ensure_secret_storage() {
+                Ok(()) => {
+                    self.store.clone().set_str(REFRESH_KEY, refresh.expose())?;
+                    self.store.flush().await?;
+                }
+                Err(err) => log::warn!("oauth: the login won't survive a restart: {err:#}"),
+            }
+        }
+        Ok(response.access_token)
+    }
+
+    async fn forget(&self) -> Result<()> {
+        *self.refresh.lock().unwrap() = None;
+        *self.access.lock().unwrap() = None;
+        self.store.clone().remove(REFRESH_KEY)?;
+        self.store.flush().await
+    }
+}
+
+fn open(partition: EspDefaultNvsPartition) -> Result<EspNvs<NvsDefault>> {
+    EspNvs::new(partition, NAMESPACE, true).context("couldn't open oauth nvs")
+}
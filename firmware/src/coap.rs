@@ -0,0 +1,206 @@
+use crate::{dns, events, http::Consumer};
+use anyhow::{bail, Context, Result};
+use coap_lite::{
+    block_handler::BlockValue, CoapOption, MessageClass, MessageType, Packet, RequestType,
+    ResponseType,
+};
+use std::time::Duration;
+use tokio::{net::UdpSocket, time::timeout};
+
+const DEFAULT_PORT: u16 = 5683;
+/// RFC 7252 transmission parameters.
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+pub const MAX_RETRANSMIT: u32 = 4;
+/// Largest block size that fits a single datagram without IP fragmentation.
+const BLOCK_SIZE: usize = 512;
+pub const MAX_DATAGRAM: usize = 1152;
+
+/// CoAP over UDP with confirmable requests and block-wise transfers, for
+/// backends that don't speak HTTPS.
+pub struct Client {
+    socket: UdpSocket,
+    message_id: u16,
+    token: u32,
+}
+
+impl Client {
+    pub async fn connect(host: &str, port: u16) -> Result<Self> {
+        events::wait_until(|state| state.net_up).await;
+
+        let addr = dns::resolve_addrs(host, port)
+            .await?
+            .into_iter()
+            .find(|addr| addr.is_ipv4())
+            .with_context(|| format!("{host} has no ipv4 address"))?;
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(addr).await?;
+
+        let seed = crate::device::random();
+        Ok(Self {
+            socket,
+            message_id: seed as u16,
+            token: seed.rotate_left(16),
+        })
+    }
+
+    /// GETs `path`, following Block2 until the server reports the last block.
+    /// Each block goes to `consumer` as it arrives.
+    pub async fn get(&mut self, path: &str, consumer: &mut impl Consumer) -> Result<()> {
+        let mut num = 0;
+
+        loop {
+            let mut request = self.request(RequestType::Get, path);
+            request.add_option_as(CoapOption::Block2, block(num, false)?);
+
+            let response = self.exchange(request).await?;
+            check(&response)?;
+            consumer.chunk(&response.payload)?;
+
+            match response.get_first_option_as::<BlockValue>(CoapOption::Block2) {
+                Some(Ok(block)) if block.more => num = usize::from(block.num) + 1,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// POSTs `payload` to `path`, split into Block1 transfers when it
+    /// doesn't fit one datagram.
+    pub async fn post(&mut self, path: &str, payload: &[u8]) -> Result<Vec<u8>> {
+        let blocks: Vec<&[u8]> = if payload.is_empty() {
+            vec![payload]
+        } else {
+            payload.chunks(BLOCK_SIZE).collect()
+        };
+
+        for (num, chunk) in blocks.iter().enumerate() {
+            let more = num + 1 < blocks.len();
+            let mut request = self.request(RequestType::Post, path);
+            if blocks.len() > 1 {
+                request.add_option_as(CoapOption::Block1, block(num, more)?);
+            }
+            request.payload = chunk.to_vec();
+
+            let response = self.exchange(request).await?;
+            check(&response)?;
+            if !more {
+                return Ok(response.payload);
+            }
+        }
+
+        unreachable!("at least one block is always sent")
+    }
+
+    fn request(&mut self, method: RequestType, path: &str) -> Packet {
+        self.message_id = self.message_id.wrapping_add(1);
+        self.token = self.token.wrapping_add(1);
+
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        packet.header.code = MessageClass::Request(method);
+        packet.header.message_id = self.message_id;
+        packet.set_token(self.token.to_be_bytes().to_vec());
+        for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+            packet.add_option(CoapOption::UriPath, segment.as_bytes().to_vec());
+        }
+        packet
+    }
+
+    /// Sends a confirmable request, retransmitting with exponential backoff
+    /// until it is acknowledged, and returns the matching response, either
+    /// piggybacked on the ACK or sent separately later.
+    async fn exchange(&self, request: Packet) -> Result<Packet> {
+        let bytes = request.to_bytes()?;
+        let mut wait = ACK_TIMEOUT;
+        let mut acked = false;
+        let mut buf = vec![0; MAX_DATAGRAM];
+
+        for _ in 0..=MAX_RETRANSMIT {
+            if !acked {
+                self.socket.send(&bytes).await?;
+            }
+
+            let deadline = tokio::time::Instant::now() + wait;
+            while let Ok(received) = timeout(
+                deadline.saturating_duration_since(tokio::time::Instant::now()),
+                self.socket.recv(&mut buf),
+            )
+            .await
+            {
+                let Ok(response) = Packet::from_bytes(&buf[..received?]) else {
+                    continue;
+                };
+
+                if response.header.code == MessageClass::Empty {
+                    if response.header.message_id == request.header.message_id {
+                        match response.header.get_type() {
+                            MessageType::Reset => bail!("coap request reset by server"),
+                            // separate response follows, stop retransmitting
+                            _ => acked = true,
+                        }
+                    }
+                    continue;
+                }
+                if response.get_token() != request.get_token() {
+                    continue;
+                }
+
+                if response.header.get_type() == MessageType::Confirmable {
+                    self.socket.send(&ack(&response).to_bytes()?).await?;
+                }
+                return Ok(response);
+            }
+
+            wait *= 2;
+        }
+
+        bail!("coap request timed out")
+    }
+}
+
+/// Splits a `coap://host[:port]/path` url into its parts.
+pub fn parse_url(url: &str) -> Result<(&str, u16, &str)> {
+    let rest = url.strip_prefix("coap://").context("not a coap url")?;
+    let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().context("invalid coap port")?),
+        None => (authority, DEFAULT_PORT),
+    };
+    Ok((host, port, path))
+}
+
+/// Fetches a `coap://host[:port]/path` url into `consumer`.
+pub async fn fetch(url: &str, consumer: &mut impl Consumer) -> Result<()> {
+    let (host, port, path) = parse_url(url)?;
+    Client::connect(host, port).await?.get(path, consumer).await
+}
+
+/// `fetch()` for the fetch, which picks it for `coap://` urls.
+pub struct Fetcher;
+
+impl crate::http::HttpFetcher for Fetcher {
+    async fn fetch<C: Consumer>(&self, url: &str, consumer: &mut C) -> Result<()> {
+        fetch(url, consumer).await
+    }
+}
+
+fn block(num: usize, more: bool) -> Result<BlockValue> {
+    BlockValue::new(num, more, BLOCK_SIZE).map_err(|err| anyhow::anyhow!("{err:?}"))
+}
+
+pub fn check(response: &Packet) -> Result<()> {
+    match response.header.code {
+        MessageClass::Response(code) if !code.is_error() => Ok(()),
+        MessageClass::Response(ResponseType::RequestEntityTooLarge) => {
+            bail!("coap server rejected block size")
+        }
+        code => bail!("coap request failed: {code}"),
+    }
+}
+
+pub fn ack(response: &Packet) -> Packet {
+    let mut packet = Packet::new();
+    packet.header.set_type(MessageType::Acknowledgement);
+    packet.header.code = MessageClass::Empty;
+    packet.header.message_id = response.header.message_id;
+    packet
+}
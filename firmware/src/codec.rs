@@ -0,0 +1,62 @@
+//! The encodings a payload can take on the wire, picked per endpoint by
+//! `mqtt_format` and `cloud_format`: JSON, or CBOR or MessagePack, which
+//! take a telemetry sample in around half the bytes. MessagePack needs the
+//! `msgpack` feature.
+
+use anyhow::{bail, Result};
+use serde::{de::DeserializeOwned, Serialize};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    Json,
+    Cbor,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+}
+
+impl Codec {
+    pub fn parse(name: &str) -> Result<Self> {
+        Ok(match name {
+            "" | "json" => Self::Json,
+            "cbor" => Self::Cbor,
+            #[cfg(feature = "msgpack")]
+            "msgpack" => Self::MessagePack,
+            #[cfg(not(feature = "msgpack"))]
+            "msgpack" => bail!("msgpack payloads need a build with the msgpack feature"),
+            other => bail!("unknown payload format {other}"),
+        })
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Cbor => "application/cbor",
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => "application/msgpack",
+        }
+    }
+
+    pub fn encode(self, value: &impl Serialize) -> Result<Vec<u8>> {
+        Ok(match self {
+            Self::Json => serde_json::to_vec(value)?,
+            Self::Cbor => {
+                let mut payload = Vec::new();
+                ciborium::into_writer(value, &mut payload)?;
+                payload
+            }
+            // with field names, so a map stays a map rather than an array
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => rmp_serde::to_vec_named(value)?,
+        })
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, payload: &[u8]) -> Result<T> {
+        Ok(match self {
+            Self::Json => serde_json::from_slice(payload)?,
+            Self::Cbor => ciborium::from_reader(payload)?,
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => rmp_serde::from_slice(payload)?,
+        })
+    }
+}
@@ -0,0 +1,965 @@
+use crate::{device, secret::Secret};
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+
+pub mod batch;
+mod bootargs;
+pub mod environment;
+mod journal;
+pub mod snapshot;
+// the simulator builds this module for the host, with its own store
+#[cfg(target_os = "espidf")]
+mod nvs;
+pub use journal::recover;
+#[cfg(all(target_os = "espidf", feature = "ble"))]
+pub use nvs::ble_passkey;
+#[cfg(all(target_os = "espidf", feature = "button"))]
+pub use nvs::factory_reset;
+#[cfg(target_os = "espidf")]
+pub use nvs::{board, flush_on_restart};
+
+const DEFAULT_NTP_SERVER: &str = "pool.ntp.org";
+const DEFAULT_DOWNLOAD_URL: &str = "http://example.com";
+const DEFAULT_MQTT_PORT: u16 = 8883;
+const DEFAULT_LED_BRIGHTNESS: u16 = 32;
+const DEFAULT_THERMAL_LIMIT: u16 = 80;
+const DEFAULT_POLL_LIMIT: u16 = 2;
+const DEFAULT_BENCH_URL: &str = "https://speed.cloudflare.com/__down?bytes={size}";
+const DEFAULT_RATE_LIMIT: &str = "60/60";
+const DEFAULT_PORTAL_URL: &str = "http://connectivitycheck.gstatic.com/generate_204";
+
+/// Fields never shown in logs or served by the status server, the
+/// `Secret` ones.
+const SECRET_FIELDS: &[&str] = &[
+    "mqtt_password",
+    "socks_proxy",
+    "wg_private_key",
+    "console_password",
+    "geo_api_key",
+    "jwt_key",
+    "oauth_client_secret",
+    "client_key",
+    "azure_device_key",
+    "azure_group_key",
+    "influx_token",
+    "trigger_secret",
+    "backup_secret",
+    "beacon_key",
+    "ntp_key",
+    "incident_token",
+    "ble_passkey",
+];
+
+/// Fields a remote config document may not touch, so a bad document can't
+/// cut the device off from the next, fixed one; see `Config::apply_remote()`.
+const LOCAL_FIELDS: &[&str] = &["config_url", "config_key"];
+
+#[derive(Clone, Serialize)]
+pub struct Config {
+    /// Pin map to boot with, see `board`; the build's default when empty.
+    pub board: String,
+    /// Name advertised on the LAN, the device id when empty.
+    pub device_name: String,
+    /// `error`, `warn`, `info`, `debug` or `trace`; the console's `log`
+    /// overrides it until the next change or reboot.
+    pub log_level: String,
+    pub ntp_server: String,
+    /// The key `ntp_server` signs its answers with, a chrony key file line
+    /// such as `1 SHA256 HEX:...`, see `clock::ntp`; with `ntp-auth` only.
+    pub ntp_key: Secret<String>,
+    pub download_url: String,
+    /// MQTT broker host, `mdns` for one found on the LAN, see `mdns`; MQTT
+    /// is disabled when empty.
+    pub mqtt_broker: String,
+    pub mqtt_port: u16,
+    pub mqtt_username: String,
+    pub mqtt_password: Secret<String>,
+    /// `tcp` for MQTT over TLS, `wss` to tunnel it through a WebSocket.
+    pub mqtt_transport: String,
+    /// `json`, `cbor` or `msgpack`, for telemetry and structured commands
+    /// over MQTT.
+    pub mqtt_format: String,
+    /// What every MQTT topic is made from, `{prefix}`, `{device_id}` and
+    /// `{channel}` filled in, see `mqtt::configure()`.
+    pub mqtt_topic: String,
+    /// What `{prefix}` stands for, possibly a few levels, `acme/site-4`.
+    pub mqtt_prefix: String,
+    /// Backend WebSocket url, the persistent channel is disabled when empty.
+    pub ws_url: String,
+    /// Backend Server-Sent Events url, the push stream is disabled when empty.
+    pub sse_url: String,
+    /// Backend long-poll url for commands, see `longpoll`; disabled when
+    /// empty.
+    pub longpoll_url: String,
+    /// `host:port` of the UDP telemetry collector, disabled when empty.
+    pub udp_collector: String,
+    /// InfluxDB write endpoint for line protocol telemetry, disabled when
+    /// empty.
+    pub influx_url: String,
+    /// Where line protocol goes while `influx_url` is failing, with the
+    /// same token; see `telemetry::endpoints`. None when empty.
+    pub influx_backup: String,
+    /// API token for `influx_url`, or v1's `user:password`.
+    pub influx_token: Secret<String>,
+    /// How batches are compressed, `gzip` or `x-delta-varint`, see
+    /// `telemetry::encoding`; the server's pick when empty.
+    pub influx_encoding: String,
+    /// gRPC backend url, status reports over gRPC are disabled when empty.
+    pub grpc_url: String,
+    /// SOCKS5 proxy for all outbound TCP, `[user:password@]host:port`,
+    /// connections are direct when empty.
+    pub socks_proxy: Secret<String>,
+    /// Proxy auto-discovery when `socks_proxy` is empty, see `net::wpad`:
+    /// `on`, the network's domain, or the script's url. Off when empty.
+    pub wpad: String,
+    /// Socket options for outbound TCP, see `net::sockopt`; the defaults
+    /// when empty.
+    pub tcp_options: String,
+    /// Static DNS entries, `host=ip[,ip];host=ip`, that win over lookups.
+    pub dns_overrides: String,
+    /// APN for the cellular data call, the carrier's generic one when empty.
+    pub cellular_apn: String,
+    /// WireGuard `host[:port]` of the peer, the tunnel is disabled when empty.
+    pub wg_endpoint: String,
+    /// Base64 keys and the device's `ip/prefix` inside the tunnel.
+    pub wg_private_key: Secret<String>,
+    pub wg_peer_key: String,
+    pub wg_address: String,
+    /// Comma separated `host[:port]` STUN servers, public ones when empty.
+    pub stun_servers: String,
+    /// Modbus TCP `host[:port][/unit]`, register polling is disabled when empty.
+    pub modbus_server: String,
+    /// Registers to poll, `name=addr[:kind][:input];...`.
+    pub modbus_map: String,
+    /// LwM2M management server, `coap://host[:port]`, disabled when empty
+    /// unless a bootstrap server is set.
+    pub lwm2m_server: String,
+    /// LwM2M bootstrap server, asked for the management server first.
+    pub lwm2m_bootstrap: String,
+    /// Password for the TCP debug console, the console is off when empty.
+    pub console_password: Secret<String>,
+    /// Six digit passkey BLE clients bond with before they may send
+    /// commands, status only when empty. Read at boot.
+    pub ble_passkey: Secret<String>,
+    /// Signed remote config document url, remote config is off when empty.
+    pub config_url: String,
+    /// Hex Ed25519 public key the remote config has to be signed with.
+    pub config_key: String,
+    /// Geolocation API url for WiFi positioning, disabled when empty.
+    pub geo_api_url: String,
+    pub geo_api_key: Secret<String>,
+    /// Deep sleep between duty cycles in seconds, the device stays awake at 0.
+    pub sleep_secs: u16,
+    /// A GPIO the ULP watches through deep sleep, `<gpio>=high|low`, the
+    /// wake it waits for; see `power::ulp`. Only the timer when empty.
+    pub ulp_wake: String,
+    /// Milliseconds between the ULP's looks at `ulp_wake`.
+    pub ulp_period: u16,
+    /// Battery voltage over ADC pin voltage, 2.0 for two equal resistors;
+    /// trimmed against a multimeter it's the calibration too. Battery
+    /// monitoring is off when empty.
+    pub battery_divider: String,
+    /// `pir` for a motion sensor on GPIO3, `ldr` for a light dependent
+    /// resistor divider into it; off when empty.
+    pub ambient_sensor: String,
+    /// What a motion or a change of light sets off right away, `fetch` for
+    /// the polls and `telemetry` for a report, comma separated.
+    pub ambient_trigger: String,
+    /// Millivolts on the LDR pin below which it's dark.
+    pub ambient_dark: u16,
+    /// RGB status LED brightness, 0-255.
+    pub led_brightness: u16,
+    /// Chip temperature in Celsius above which `Overheat` is published.
+    pub thermal_limit: u16,
+    /// Buzzer loudness, 0-100; 0 keeps it silent.
+    pub buzzer_volume: u16,
+    /// Client certificate for mutual TLS, base64 DER, whose private key
+    /// never leaves the ATECC608. No client auth when empty.
+    pub client_cert: String,
+    /// ATECC608 slot holding that key.
+    pub atecc_slot: u16,
+    /// The certificate's key as base64 PKCS#8, for builds without the
+    /// ATECC608 such as the AWS and Azure ones.
+    pub client_key: Secret<String>,
+    /// Base64 PKCS#8 P-256 or RSA key the backend JWTs are signed with,
+    /// no JWTs when empty.
+    pub jwt_key: Secret<String>,
+    /// The JWTs' `aud`, the project id for GCP-style brokers.
+    pub jwt_audience: String,
+    /// OAuth2 device authorization endpoint, no user login when empty.
+    pub oauth_device_url: String,
+    pub oauth_token_url: String,
+    pub oauth_client_id: String,
+    /// Only for providers that want one even from devices, Google among them.
+    pub oauth_client_secret: Secret<String>,
+    /// Space separated scopes to ask the user for.
+    pub oauth_scope: String,
+    /// Base url of the user's APIs, the only ones `oauth get` calls.
+    pub oauth_api_url: String,
+    /// Azure Device Provisioning Service ID scope.
+    pub azure_id_scope: String,
+    /// Base64 symmetric key of an individual enrollment, or of a group one
+    /// the device's key is derived from. X.509 with `client_cert` when
+    /// both are empty.
+    pub azure_device_key: Secret<String>,
+    pub azure_group_key: Secret<String>,
+    /// The backend the device reports to: `aws`, `azure` or `https`, none
+    /// when empty.
+    pub cloud: String,
+    /// Base URL of the `https` backend.
+    pub cloud_url: String,
+    /// What the `https` backend is sent and answers in, `json`, `cbor` or
+    /// `msgpack`.
+    pub cloud_format: String,
+    /// HTTPS downloads to run at boot, see `bench`; none when empty.
+    pub bench: String,
+    /// What the bench downloads, `{size}` replaced by the byte count.
+    pub bench_url: String,
+    /// At 1 the backend hosts are resolved and a connection to the download
+    /// host opened ahead of the first fetch, see `warmup`.
+    pub warmup: u16,
+    /// Urls polled on their own intervals,
+    /// `<secs>=<url>[ <backup url>][|<stage>...];...`, see `poller` and
+    /// `pipeline`; none when empty.
+    pub poll_urls: String,
+    /// Polls allowed to run at once.
+    pub poll_limit: u16,
+    /// Shared secret `POST /trigger` requests carry in `X-Trigger-Secret`,
+    /// the route is off when empty.
+    pub trigger_secret: Secret<String>,
+    /// Shared secret of `POST /backup` and `POST /restore`, in
+    /// `X-Backup-Secret`; off when empty. Apart from `trigger_secret`, as a
+    /// restore rewrites the whole device.
+    pub backup_secret: Secret<String>,
+    /// Plain http url answering an empty 204, fetched on every link up to
+    /// tell a captive portal; no check when empty.
+    pub portal_url: String,
+    /// Requests to backends allowed, `<requests>/<secs>`, see `ratelimit`;
+    /// `off` for no limit.
+    pub rate_limit: String,
+    /// Hosts outbound TLS may reach, `host;*.domain;...`, see
+    /// `tls::allowlist`; any host when empty.
+    pub tls_allowlist: String,
+    /// Named trust settings, `<name>:<option>,...;...`, see `tls::profile`.
+    pub tls_profiles: String,
+    /// Hosts sent to those, `<host>=<profile>;...`.
+    pub tls_routes: String,
+    /// Cipher suites TLS clients offer, `;`-separated in order of
+    /// preference, see `tls::suites`; ring's defaults when empty.
+    pub tls_ciphers: String,
+    /// Key exchange groups they offer, the same way.
+    pub tls_groups: String,
+    /// Url prefixes whose polls may go as TLS early data, `;`-separated,
+    /// see `http::early`; only for servers that shrug off a replayed GET.
+    pub early_data: String,
+    /// Feature flag rules, `;`-separated `<flag>=<rule>`, see `flags`; each
+    /// flag keeps its default when empty.
+    pub flags: String,
+    /// The rollout cohort the device is in, such as `canary`, for the
+    /// `@<cohort>` rules of `flags`.
+    pub cohort: String,
+    /// 1 to send the chain of the last server that failed verification
+    /// with telemetry, see `tls::chain`.
+    pub tls_report: u16,
+    /// What a stapled OCSP response has to say, `off`, `soft` or `hard`, see
+    /// `tls::ocsp`.
+    pub tls_ocsp: String,
+    /// Local time windows without polling or telemetry, `22:00-06:00;...`,
+    /// see `quiet`; none when empty.
+    pub quiet_hours: String,
+    /// 1 to turn WiFi off as well during quiet hours.
+    pub quiet_wifi: u16,
+    /// 802.11 modes the radio uses, `bgn`, `lr` for Espressif's long range
+    /// mode, which only another Espressif device at the far end speaks, or
+    /// `bgn+lr` for both; `bgn` when empty.
+    pub wifi_phy: String,
+    /// Local time against UTC, `+01:00`, for `quiet_hours`; UTC when empty.
+    pub utc_offset: String,
+    /// When to reboot, local `HH:MM` daily or `<day> HH:MM` weekly, see
+    /// `reboot`; never when empty.
+    pub reboot_at: String,
+    /// Minutes past `reboot_at` the fleet spreads its reboots over.
+    pub reboot_jitter: u16,
+    /// The bundle of `environments` in effect, see `config::environment`;
+    /// none when empty.
+    pub environment: String,
+    /// Named bundles of fields as JSON, `{"<name>": {"<field>": ...}}`.
+    pub environments: String,
+    /// 1 to keep cookies between requests, see `http::cookies`.
+    pub cookie_jar: u16,
+    /// Names of the cookies kept across restarts as well, `name,...`, `*`
+    /// for all of them.
+    pub cookie_persist: String,
+    /// Seconds between discovery beacons, see `beacon`; 0 for none.
+    pub beacon_interval: u16,
+    /// Fleet key the beacons are signed with, the device's own when empty.
+    pub beacon_key: Secret<String>,
+    /// Backend on the LAN tried before `download_url`, `mdns://...` or a
+    /// url, see `failover`; the cloud only when empty.
+    pub lan_url: String,
+    /// LAN fetches failed in a row before downloads go to the cloud.
+    pub lan_failures: u16,
+    /// Seconds between checks of the LAN backend while it isn't used.
+    pub lan_recheck: u16,
+    /// Failures in a row before an endpoint with a backup, `influx_backup`
+    /// or a poll's, is left for the other.
+    pub backup_failures: u16,
+    /// Seconds between probes of a primary endpoint while its backup is
+    /// used.
+    pub backup_recheck: u16,
+    /// Where an ESP-NOW gateway POSTs its nodes' frames, see
+    /// `espnow::gateway`; over MQTT when empty.
+    pub gateway_url: String,
+    /// MACs of the nodes the gateway forwards, comma separated; any node
+    /// when empty.
+    pub gateway_nodes: String,
+    /// Where `incident` uploads its diagnostic bundles, see `incident`;
+    /// nowhere when empty.
+    pub incident_url: String,
+    /// Bearer token for `incident_url`, none sent when empty.
+    pub incident_token: Secret<String>,
+    /// Debug flags for this device as a command line, `--bench
+    /// --loglevel=trace`, see `config::bootargs`.
+    pub boot_args: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            board: String::new(),
+            device_name: String::new(),
+            log_level: String::from("debug"),
+            ntp_server: String::from(DEFAULT_NTP_SERVER),
+            ntp_key: Secret::default(),
+            download_url: String::from(DEFAULT_DOWNLOAD_URL),
+            mqtt_broker: String::new(),
+            mqtt_port: DEFAULT_MQTT_PORT,
+            mqtt_username: String::new(),
+            mqtt_password: Secret::default(),
+            mqtt_transport: String::new(),
+            mqtt_format: String::new(),
+            mqtt_topic: String::from("{prefix}/{device_id}/{channel}"),
+            mqtt_prefix: String::from("devices"),
+            ws_url: String::new(),
+            sse_url: String::new(),
+            longpoll_url: String::new(),
+            udp_collector: String::new(),
+            influx_url: String::new(),
+            influx_backup: String::new(),
+            influx_token: Secret::default(),
+            influx_encoding: String::new(),
+            grpc_url: String::new(),
+            socks_proxy: Secret::default(),
+            wpad: String::new(),
+            tcp_options: String::new(),
+            dns_overrides: String::new(),
+            cellular_apn: String::new(),
+            wg_endpoint: String::new(),
+            wg_private_key: Secret::default(),
+            wg_peer_key: String::new(),
+            wg_address: String::new(),
+            stun_servers: String::new(),
+            modbus_server: String::new(),
+            modbus_map: String::new(),
+            lwm2m_server: String::new(),
+            lwm2m_bootstrap: String::new(),
+            console_password: Secret::default(),
+            ble_passkey: Secret::default(),
+            config_url: String::new(),
+            config_key: String::new(),
+            geo_api_url: String::new(),
+            geo_api_key: Secret::default(),
+            sleep_secs: 0,
+            ulp_wake: String::new(),
+            ulp_period: 100,
+            battery_divider: String::new(),
+            ambient_sensor: String::new(),
+            ambient_trigger: String::from("fetch"),
+            ambient_dark: 500,
+            led_brightness: DEFAULT_LED_BRIGHTNESS,
+            thermal_limit: DEFAULT_THERMAL_LIMIT,
+            buzzer_volume: 50,
+            client_cert: String::new(),
+            atecc_slot: 0,
+            client_key: Secret::default(),
+            jwt_key: Secret::default(),
+            jwt_audience: String::new(),
+            oauth_device_url: String::new(),
+            oauth_token_url: String::new(),
+            oauth_client_id: String::new(),
+            oauth_client_secret: Secret::default(),
+            oauth_scope: String::new(),
+            oauth_api_url: String::new(),
+            azure_id_scope: String::new(),
+            azure_device_key: Secret::default(),
+            azure_group_key: Secret::default(),
+            cloud: String::new(),
+            cloud_url: String::new(),
+            cloud_format: String::new(),
+            bench: String::new(),
+            bench_url: String::from(DEFAULT_BENCH_URL),
+            warmup: 0,
+            poll_urls: String::new(),
+            poll_limit: DEFAULT_POLL_LIMIT,
+            trigger_secret: Secret::default(),
+            backup_secret: Secret::default(),
+            portal_url: String::from(DEFAULT_PORTAL_URL),
+            rate_limit: String::from(DEFAULT_RATE_LIMIT),
+            tls_allowlist: String::new(),
+            tls_profiles: String::new(),
+            tls_routes: String::new(),
+            tls_ciphers: String::new(),
+            tls_groups: String::new(),
+            early_data: String::new(),
+            flags: String::new(),
+            cohort: String::new(),
+            tls_report: 0,
+            tls_ocsp: String::from("soft"),
+            quiet_hours: String::new(),
+            quiet_wifi: 0,
+            wifi_phy: String::new(),
+            utc_offset: String::new(),
+            reboot_at: String::new(),
+            reboot_jitter: 30,
+            environment: String::new(),
+            environments: String::new(),
+            cookie_jar: 0,
+            cookie_persist: String::new(),
+            beacon_interval: 60,
+            beacon_key: Secret::default(),
+            lan_url: String::new(),
+            lan_failures: 3,
+            lan_recheck: 300,
+            backup_failures: 3,
+            backup_recheck: 300,
+            gateway_url: String::new(),
+            gateway_nodes: String::new(),
+            incident_url: String::new(),
+            incident_token: Secret::default(),
+            boot_args: String::new(),
+        }
+    }
+}
+
+/// Where the config lives: the `config` NVS namespace on the device, a
+/// file in the simulator. Keys are the NVS ones, see `nvs_key()`.
+pub trait Store {
+    fn get_str(&self, key: &str) -> Result<Option<String>>;
+    fn get_u16(&self, key: &str) -> Result<Option<u16>>;
+    fn set_str(&mut self, key: &str, value: &str) -> Result<()>;
+    fn set_u16(&mut self, key: &str, value: u16) -> Result<()>;
+    fn remove(&mut self, key: &str) -> Result<()>;
+}
+
+impl Config {
+    /// Reads the config out of `store`, through the selected environment
+    /// and the boot args.
+    pub fn load_from(store: &impl Store) -> Result<Self> {
+        let args = store.get_str("boot_args")?.unwrap_or_default();
+        // a typo in them shouldn't keep the device from booting
+        let flags = bootargs::parse(&args).unwrap_or_else(|err| {
+            log::warn!("boot args ignored: {err:#}");
+            serde_json::Map::new()
+        });
+        match store
+            .get_str("environment")?
+            .filter(|name| !name.is_empty())
+        {
+            Some(name) => {
+                let environment = environment::Overlay::new(store, &name)?;
+                Self::read(&environment::Overlay::with(&environment, flags))
+            }
+            None => Self::read(&environment::Overlay::with(store, flags)),
+        }
+    }
+
+    /// The fields `boot_args` sets, by field name.
+    pub fn boot_flags(&self) -> Result<serde_json::Map<String, serde_json::Value>> {
+        bootargs::parse(&self.boot_args)
+    }
+
+    fn read(store: &impl Store) -> Result<Self> {
+        let mut config = Self::default();
+
+        if let Some(value) = store.get_str("board")? {
+            config.board = value;
+        }
+        if let Some(value) = store.get_str("device_name")? {
+            config.device_name = value;
+        }
+        if let Some(value) = store.get_str("log_level")? {
+            config.log_level = value;
+        }
+        if let Some(value) = store.get_str("ntp_server")? {
+            config.ntp_server = value;
+        }
+        if let Some(value) = store.get_str("ntp_key")? {
+            config.ntp_key = Secret::new(value);
+        }
+        if let Some(value) = store.get_str("download_url")? {
+            config.download_url = value;
+        }
+        if let Some(value) = store.get_str("mqtt_broker")? {
+            config.mqtt_broker = value;
+        }
+        if let Some(value) = store.get_u16("mqtt_port")? {
+            config.mqtt_port = value;
+        }
+        if let Some(value) = store.get_str("mqtt_username")? {
+            config.mqtt_username = value;
+        }
+        if let Some(value) = store.get_str("mqtt_password")? {
+            config.mqtt_password = Secret::new(value);
+        }
+        if let Some(value) = store.get_str("mqtt_transport")? {
+            config.mqtt_transport = value;
+        }
+        if let Some(value) = store.get_str("mqtt_format")? {
+            config.mqtt_format = value;
+        }
+        if let Some(value) = store.get_str("mqtt_topic")? {
+            config.mqtt_topic = value;
+        }
+        if let Some(value) = store.get_str("mqtt_prefix")? {
+            config.mqtt_prefix = value;
+        }
+        if let Some(value) = store.get_str("ws_url")? {
+            config.ws_url = value;
+        }
+        if let Some(value) = store.get_str("sse_url")? {
+            config.sse_url = value;
+        }
+        if let Some(value) = store.get_str("longpoll_url")? {
+            config.longpoll_url = value;
+        }
+        if let Some(value) = store.get_str("udp_collector")? {
+            config.udp_collector = value;
+        }
+        if let Some(value) = store.get_str("influx_url")? {
+            config.influx_url = value;
+        }
+        if let Some(value) = store.get_str("influx_backup")? {
+            config.influx_backup = value;
+        }
+        if let Some(value) = store.get_str("influx_token")? {
+            config.influx_token = Secret::new(value);
+        }
+        if let Some(value) = store.get_str("influx_encoding")? {
+            config.influx_encoding = value;
+        }
+        if let Some(value) = store.get_str("grpc_url")? {
+            config.grpc_url = value;
+        }
+        if let Some(value) = store.get_str("socks_proxy")? {
+            config.socks_proxy = Secret::new(value);
+        }
+        if let Some(value) = store.get_str("wpad")? {
+            config.wpad = value;
+        }
+        if let Some(value) = store.get_str("tcp_options")? {
+            config.tcp_options = value;
+        }
+        if let Some(value) = store.get_str("dns_overrides")? {
+            config.dns_overrides = value;
+        }
+        if let Some(value) = store.get_str("cellular_apn")? {
+            config.cellular_apn = value;
+        }
+        if let Some(value) = store.get_str("wg_endpoint")? {
+            config.wg_endpoint = value;
+        }
+        if let Some(value) = store.get_str("wg_private_key")? {
+            config.wg_private_key = Secret::new(value);
+        }
+        if let Some(value) = store.get_str("wg_peer_key")? {
+            config.wg_peer_key = value;
+        }
+        if let Some(value) = store.get_str("wg_address")? {
+            config.wg_address = value;
+        }
+        if let Some(value) = store.get_str("stun_servers")? {
+            config.stun_servers = value;
+        }
+        if let Some(value) = store.get_str("modbus_server")? {
+            config.modbus_server = value;
+        }
+        if let Some(value) = store.get_str("modbus_map")? {
+            config.modbus_map = value;
+        }
+        if let Some(value) = store.get_str("lwm2m_server")? {
+            config.lwm2m_server = value;
+        }
+        if let Some(value) = store.get_str("lwm2m_bootstrap")? {
+            config.lwm2m_bootstrap = value;
+        }
+        if let Some(value) = store.get_str("console_pass")? {
+            config.console_password = Secret::new(value);
+        }
+        if let Some(value) = store.get_str("ble_passkey")? {
+            config.ble_passkey = Secret::new(value);
+        }
+        if let Some(value) = store.get_str("config_url")? {
+            config.config_url = value;
+        }
+        if let Some(value) = store.get_str("config_key")? {
+            config.config_key = value;
+        }
+        if let Some(value) = store.get_str("geo_api_url")? {
+            config.geo_api_url = value;
+        }
+        if let Some(value) = store.get_str("geo_api_key")? {
+            config.geo_api_key = Secret::new(value);
+        }
+        if let Some(value) = store.get_u16("sleep_secs")? {
+            config.sleep_secs = value;
+        }
+        if let Some(value) = store.get_str("ulp_wake")? {
+            config.ulp_wake = value;
+        }
+        if let Some(value) = store.get_u16("ulp_period")? {
+            config.ulp_period = value;
+        }
+        if let Some(value) = store.get_str("battery_div")? {
+            config.battery_divider = value;
+        }
+        if let Some(value) = store.get_str("ambient_sensor")? {
+            config.ambient_sensor = value;
+        }
+        if let Some(value) = store.get_str("ambient_trigger")? {
+            config.ambient_trigger = value;
+        }
+        if let Some(value) = store.get_u16("ambient_dark")? {
+            config.ambient_dark = value;
+        }
+        if let Some(value) = store.get_u16("led_brightness")? {
+            config.led_brightness = value;
+        }
+        if let Some(value) = store.get_u16("thermal_limit")? {
+            config.thermal_limit = value;
+        }
+        if let Some(value) = store.get_u16("buzzer_volume")? {
+            config.buzzer_volume = value;
+        }
+        if let Some(value) = store.get_str("client_cert")? {
+            config.client_cert = value;
+        }
+        if let Some(value) = store.get_u16("atecc_slot")? {
+            config.atecc_slot = value;
+        }
+        if let Some(value) = store.get_str("client_key")? {
+            config.client_key = Secret::new(value);
+        }
+        if let Some(value) = store.get_str("jwt_key")? {
+            config.jwt_key = Secret::new(value);
+        }
+        if let Some(value) = store.get_str("jwt_audience")? {
+            config.jwt_audience = value;
+        }
+        if let Some(value) = store.get_str("oauth_device")? {
+            config.oauth_device_url = value;
+        }
+        if let Some(value) = store.get_str("oauth_token_url")? {
+            config.oauth_token_url = value;
+        }
+        if let Some(value) = store.get_str("oauth_client_id")? {
+            config.oauth_client_id = value;
+        }
+        if let Some(value) = store.get_str("oauth_secret")? {
+            config.oauth_client_secret = Secret::new(value);
+        }
+        if let Some(value) = store.get_str("oauth_scope")? {
+            config.oauth_scope = value;
+        }
+        if let Some(value) = store.get_str("oauth_api_url")? {
+            config.oauth_api_url = value;
+        }
+        if let Some(value) = store.get_str("azure_id_scope")? {
+            config.azure_id_scope = value;
+        }
+        if let Some(value) = store.get_str("azure_dev_key")? {
+            config.azure_device_key = Secret::new(value);
+        }
+        if let Some(value) = store.get_str("azure_group_key")? {
+            config.azure_group_key = Secret::new(value);
+        }
+        if let Some(value) = store.get_str("cloud")? {
+            config.cloud = value;
+        }
+        if let Some(value) = store.get_str("cloud_url")? {
+            config.cloud_url = value;
+        }
+        if let Some(value) = store.get_str("cloud_format")? {
+            config.cloud_format = value;
+        }
+        if let Some(value) = store.get_str("bench")? {
+            config.bench = value;
+        }
+        if let Some(value) = store.get_str("bench_url")? {
+            config.bench_url = value;
+        }
+        if let Some(value) = store.get_u16("warmup")? {
+            config.warmup = value;
+        }
+        if let Some(value) = store.get_str("poll_urls")? {
+            config.poll_urls = value;
+        }
+        if let Some(value) = store.get_u16("poll_limit")? {
+            config.poll_limit = value;
+        }
+        if let Some(value) = store.get_str("trigger_secret")? {
+            config.trigger_secret = Secret::new(value);
+        }
+        if let Some(value) = store.get_str("backup_secret")? {
+            config.backup_secret = Secret::new(value);
+        }
+        if let Some(value) = store.get_str("portal_url")? {
+            config.portal_url = value;
+        }
+        if let Some(value) = store.get_str("rate_limit")? {
+            config.rate_limit = value;
+        }
+        if let Some(value) = store.get_str("tls_allowlist")? {
+            config.tls_allowlist = value;
+        }
+        if let Some(value) = store.get_str("tls_profiles")? {
+            config.tls_profiles = value;
+        }
+        if let Some(value) = store.get_str("tls_routes")? {
+            config.tls_routes = value;
+        }
+        if let Some(value) = store.get_str("tls_ciphers")? {
+            config.tls_ciphers = value;
+        }
+        if let Some(value) = store.get_str("tls_groups")? {
+            config.tls_groups = value;
+        }
+        if let Some(value) = store.get_str("early_data")? {
+            config.early_data = value;
+        }
+        if let Some(value) = store.get_str("flags")? {
+            config.flags = value;
+        }
+        if let Some(value) = store.get_str("cohort")? {
+            config.cohort = value;
+        }
+        if let Some(value) = store.get_u16("tls_report")? {
+            config.tls_report = value;
+        }
+        if let Some(value) = store.get_str("tls_ocsp")? {
+            config.tls_ocsp = value;
+        }
+        if let Some(value) = store.get_str("quiet_hours")? {
+            config.quiet_hours = value;
+        }
+        if let Some(value) = store.get_u16("quiet_wifi")? {
+            config.quiet_wifi = value;
+        }
+        if let Some(value) = store.get_str("wifi_phy")? {
+            config.wifi_phy = value;
+        }
+        if let Some(value) = store.get_str("utc_offset")? {
+            config.utc_offset = value;
+        }
+        if let Some(value) = store.get_str("reboot_at")? {
+            config.reboot_at = value;
+        }
+        if let Some(value) = store.get_u16("reboot_jitter")? {
+            config.reboot_jitter = value;
+        }
+        if let Some(value) = store.get_str("environment")? {
+            config.environment = value;
+        }
+        if let Some(value) = store.get_str("environments")? {
+            config.environments = value;
+        }
+        if let Some(value) = store.get_u16("cookie_jar")? {
+            config.cookie_jar = value;
+        }
+        if let Some(value) = store.get_str("cookie_persist")? {
+            config.cookie_persist = value;
+        }
+        if let Some(value) = store.get_u16("beacon_interval")? {
+            config.beacon_interval = value;
+        }
+        if let Some(value) = store.get_str("beacon_key")? {
+            config.beacon_key = Secret::new(value);
+        }
+        if let Some(value) = store.get_str("lan_url")? {
+            config.lan_url = value;
+        }
+        if let Some(value) = store.get_u16("lan_failures")? {
+            config.lan_failures = value;
+        }
+        if let Some(value) = store.get_u16("lan_recheck")? {
+            config.lan_recheck = value;
+        }
+        if let Some(value) = store.get_u16("backup_failures")? {
+            config.backup_failures = value;
+        }
+        if let Some(value) = store.get_u16("backup_recheck")? {
+            config.backup_recheck = value;
+        }
+        if let Some(value) = store.get_str("gateway_url")? {
+            config.gateway_url = value;
+        }
+        if let Some(value) = store.get_str("gateway_nodes")? {
+            config.gateway_nodes = value;
+        }
+        if let Some(value) = store.get_str("incident_url")? {
+            config.incident_url = value;
+        }
+        if let Some(value) = store.get_str("incident_token")? {
+            config.incident_token = Secret::new(value);
+        }
+        if let Some(value) = store.get_str("boot_args")? {
+            config.boot_args = value;
+        }
+
+        log::info!("config loaded: {}", config.redacted());
+
+        Ok(config)
+    }
+
+    /// The config as JSON with secrets masked, safe to log or serve.
+    pub fn redacted(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        if let Some(fields) = value.as_object_mut() {
+            for field in SECRET_FIELDS {
+                if let Some(secret) = fields.get_mut(*field) {
+                    if secret.as_str().is_some_and(|secret| !secret.is_empty()) {
+                        *secret = "<redacted>".into();
+                    }
+                }
+            }
+            // a bundle can carry secrets of its own
+            let bundles = fields
+                .get("environments")
+                .and_then(serde_json::Value::as_str)
+                .and_then(|value| serde_json::from_str::<serde_json::Map<_, _>>(value).ok());
+            if let Some(mut bundles) = bundles {
+                for bundle in bundles
+                    .values_mut()
+                    .filter_map(serde_json::Value::as_object_mut)
+                {
+                    for field in SECRET_FIELDS {
+                        if let Some(secret) = bundle.get_mut(*field) {
+                            *secret = "<redacted>".into();
+                        }
+                    }
+                }
+                let bundles = serde_json::Value::Object(bundles).to_string();
+                fields.insert("environments".into(), bundles.into());
+            }
+            if let Ok(flags) = self.boot_flags() {
+                fields.insert("boot_flags".into(), flags.into());
+            }
+        }
+        value
+    }
+
+    /// Writes `changes`, field name to value, into `store` and returns the
+    /// fields that actually changed. Every field is checked against the
+    /// config before anything is written, and the previous values are put
+    /// back if a write or the reload afterwards fails. The writes go
+    /// through the `journal`, so a reset halfway through can't leave some
+    /// fields changed and others not.
+    pub fn apply_to(
+        store: &mut impl Store,
+        changes: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<Vec<String>> {
+        let current = serde_json::to_value(Self::load_from(store)?)?;
+
+        let mut updates = Vec::new();
+        for (field, value) in changes {
+            let Some(old) = current.get(field) else {
+                bail!("unknown config field {field}");
+            };
+            if old == value {
+                continue;
+            }
+            let value = match (old, value) {
+                (serde_json::Value::String(_), serde_json::Value::String(value)) => {
+                    Stored::Str(value.clone())
+                }
+                (serde_json::Value::Number(_), serde_json::Value::Number(value)) => Stored::U16(
+                    value
+                        .as_u64()
+                        .and_then(|value| u16::try_from(value).ok())
+                        .with_context(|| format!("config field {field} out of range"))?,
+                ),
+                _ => bail!("wrong type for config field {field}"),
+            };
+            updates.push((field.clone(), value));
+        }
+        if updates.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut previous = Vec::new();
+        for (field, _) in &updates {
+            let key = nvs_key(field);
+            let value = match current[field] {
+                serde_json::Value::Number(_) => store.get_u16(key)?.map(Stored::U16),
+                _ => store.get_str(key)?.map(Stored::Str),
+            };
+            previous.push((key, value));
+        }
+
+        journal::begin(
+            store,
+            updates
+                .iter()
+                .map(|(field, value)| (nvs_key(field), Some(value))),
+        )?;
+        let result = journal::finish(store).and_then(|()| Self::load_from(store).map(drop));
+        if let Err(err) = result {
+            let restored = journal::begin(
+                store,
+                previous.iter().map(|(key, value)| (*key, value.as_ref())),
+            )
+            .and_then(|()| journal::finish(store));
+            if let Err(err) = restored {
+                log::error!("couldn't roll back the config: {err:#}");
+            }
+            return Err(err.context("config rolled back"));
+        }
+
+        Ok(updates.into_iter().map(|(field, _)| field).collect())
+    }
+
+    pub fn device_name(&self) -> &str {
+        if self.device_name.is_empty() {
+            device::id()
+        } else {
+            &self.device_name
+        }
+    }
+}
+
+/// A config value as NVS stores it.
+pub(crate) enum Stored {
+    Str(String),
+    U16(u16),
+}
+
+impl Stored {
+    fn store(&self, store: &mut impl Store, key: &str) -> Result<()> {
+        match self {
+            Self::Str(value) => store.set_str(key, value),
+            Self::U16(value) => store.set_u16(key, *value),
+        }
+        .with_context(|| format!("couldn't write config key {key}"))
+    }
+}
+
+/// NVS keys are limited to 15 bytes, the few longer field names are cut.
+fn nvs_key(field: &str) -> &str {
+    match field {
+        "console_password" => "console_pass",
+        "battery_divider" => "battery_div",
+        "oauth_device_url" => "oauth_device",
+        "oauth_client_secret" => "oauth_secret",
+        "azure_device_key" => "azure_dev_key",
+        field => field,
+    }
+}
@@ -0,0 +1,160 @@
+//! A `Store` whose writes are held back and committed together on the
+//! blocking pool, for stores written from async code: an NVS commit takes
+//! tens of milliseconds, which the reactor would otherwise spend blocked.
+//! Reads see the held writes at once. A commit follows the first held
+//! write by at most `DELAY`, later writes don't push it back; `flush()`
+//! commits now, for writes that have to be on flash before going on, and
+//! `flush_all()` for a restart.
+
+use super::{Store, Stored};
+use crate::runtime;
+use anyhow::{Context, Result};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+/// Longest a write waits for its commit.
+const DELAY: Duration = Duration::from_secs(2);
+
+/// Every batched store, for `flush_all()`.
+static ALL: Mutex<Vec<Arc<dyn Commit>>> = Mutex::new(Vec::new());
+
+trait Commit: Send + Sync {
+    fn commit(&self) -> Result<()>;
+}
+
+pub struct Batched<S> {
+    shared: Arc<Shared<S>>,
+}
+
+struct Shared<S> {
+    store: Mutex<S>,
+    /// Writes not yet committed by key, `None` for a removal.
+    held: Mutex<BTreeMap<String, Option<Stored>>>,
+    due: Notify,
+}
+
+impl<S> Clone for Batched<S> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<S: Store + Send + 'static> Batched<S> {
+    /// `store` with its writes batched. Made on the runtime, which its
+    /// commits are scheduled on.
+    pub fn new(store: S) -> Self {
+        let shared = Arc::new(Shared {
+            store: Mutex::new(store),
+            held: Mutex::new(BTreeMap::new()),
+            due: Notify::new(),
+        });
+        ALL.lock().unwrap().push(shared.clone());
+
+        let flusher = shared.clone();
+        runtime::spawn(async move {
+            loop {
+                flusher.due.notified().await;
+                runtime::sleep(DELAY).await;
+                let shared = flusher.clone();
+                match runtime::run_blocking(move || shared.commit()).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) | Err(err) => log::warn!("batched store: {err:#}"),
+                }
+            }
+        });
+        Self { shared }
+    }
+
+    /// Commits the held writes now, on the blocking pool.
+    pub async fn flush(&self) -> Result<()> {
+        let shared = self.shared.clone();
+        runtime::run_blocking(move || shared.commit()).await?
+    }
+
+    fn hold(&self, key: &str, value: Option<Stored>) {
+        self.shared
+            .held
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), value);
+        self.shared.due.notify_one();
+    }
+}
+
+/// Commits what every batched store holds, blocking, for the restart that
+/// would lose it.
+pub fn flush_all() {
+    let all = ALL.lock().unwrap().clone();
+    for store in all {
+        if let Err(err) = store.commit() {
+            log::warn!("batched store: {err:#}");
+        }
+    }
+}
+
+impl<S: Store + Send> Commit for Shared<S> {
+    fn commit(&self) -> Result<()> {
+        let held = std::mem::take(&mut *self.held.lock().unwrap());
+        if held.is_empty() {
+            return Ok(());
+        }
+        let mut store = self.store.lock().unwrap();
+        let mut writes = held.into_iter();
+        while let Some((key, value)) = writes.next() {
+            let written = match &value {
+                Some(value) => value.store(&mut *store, &key),
+                None => store.remove(&key),
+            };
+            if let Err(err) = written {
+                // held again for the next commit, unless written again since
+                let mut held = self.held.lock().unwrap();
+                for (key, value) in std::iter::once((key, value)).chain(writes) {
+                    held.entry(key).or_insert(value);
+                }
+                return Err(err).context("commit failed, the writes are held for the next");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: Store + Send + 'static> Store for Batched<S> {
+    fn get_str(&self, key: &str) -> Result<Option<String>> {
+        match self.shared.held.lock().unwrap().get(key) {
+            Some(Some(Stored::Str(value))) => return Ok(Some(value.clone())),
+            Some(Some(Stored::U16(_)) | None) => return Ok(None),
+            None => {}
+        }
+        self.shared.store.lock().unwrap().get_str(key)
+    }
+
+    fn get_u16(&self, key: &str) -> Result<Option<u16>> {
+        match self.shared.held.lock().unwrap().get(key) {
+            Some(Some(Stored::U16(value))) => return Ok(Some(*value)),
+            Some(Some(Stored::Str(_)) | None) => return Ok(None),
+            None => {}
+        }
+        self.shared.store.lock().unwrap().get_u16(key)
+    }
+
+    fn set_str(&mut self, key: &str, value: &str) -> Result<()> {
+        self.hold(key, Some(Stored::Str(value.to_owned())));
+        Ok(())
+    }
+
+    fn set_u16(&mut self, key: &str, value: u16) -> Result<()> {
+        self.hold(key, Some(Stored::U16(value)));
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<()> {
+        self.hold(key, None);
+        Ok(())
+    }
+}
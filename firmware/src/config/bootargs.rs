@@ -0,0 +1,73 @@
+//! `boot_args`: flags in the style of a command line, `--bench
+//! --loglevel=trace --no-sleep`, for switching one device into a debug
+//! mode without a rebuild. Each flag sets a config field as the config is
+//! read, in front of the stored value and the environment's, and
+//! `/api/config` shows what they parsed to as `boot_flags`.
+//!
+//! `--<field>=<value>` sets a field, `--<field>` a number to 1 and a
+//! string to `on`, and `--no-<field>` clears it. Dashes and underscores
+//! don't count in the names, `--log-level` and `--loglevel` are both
+//! `log_level`, and a few fields have shorter ones. Secrets can't be set
+//! here, the string is stored and shown as it is.
+
+use super::{Config, LOCAL_FIELDS, SECRET_FIELDS};
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
+
+/// Short names, and the field they set.
+const ALIASES: &[(&str, &str)] = &[("sleep", "sleep_secs"), ("level", "log_level")];
+/// What a flag can't set besides the local and secret fields.
+const FIXED: &[&str] = &["boot_args", "environment", "environments"];
+
+/// The fields `args` sets, by field name.
+pub fn parse(args: &str) -> Result<Map<String, Value>> {
+    let Value::Object(known) = serde_json::to_value(Config::default())? else {
+        unreachable!("the config serializes as an object");
+    };
+    let mut flags = Map::new();
+    for arg in args.split_whitespace() {
+        let flag = arg
+            .strip_prefix("--")
+            .with_context(|| format!("boot arg {arg} doesn't start with --"))?;
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (flag, None),
+        };
+        let (name, cleared) = match (name.strip_prefix("no-"), value) {
+            (Some(name), None) => (name, true),
+            _ => (name, false),
+        };
+        let Some((field, current)) = field(name, &known) else {
+            bail!("boot arg {arg} names no config field");
+        };
+        if FIXED.contains(&field) || LOCAL_FIELDS.contains(&field) || SECRET_FIELDS.contains(&field)
+        {
+            bail!("boot args can't set {field}");
+        }
+        let value = match (current, value) {
+            (Value::Number(_), Some(value)) => Value::from(
+                value
+                    .parse::<u16>()
+                    .with_context(|| format!("boot arg {arg} needs a number"))?,
+            ),
+            (Value::Number(_), None) => Value::from(u16::from(!cleared)),
+            (_, Some(value)) => Value::from(value),
+            (_, None) => Value::from(if cleared { "" } else { "on" }),
+        };
+        flags.insert(field.to_owned(), value);
+    }
+    Ok(flags)
+}
+
+/// The field `name` stands for, and its default.
+fn field<'a>(name: &'a str, known: &'a Map<String, Value>) -> Option<(&'a str, &'a Value)> {
+    let name = ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == name)
+        .map_or(name, |(_, field)| field);
+    let squashed = |name: &str| name.replace(['-', '_'], "");
+    known
+        .iter()
+        .find(|(field, _)| squashed(field) == squashed(name))
+        .map(|(field, value)| (field.as_str(), value))
+}
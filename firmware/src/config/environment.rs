@@ -0,0 +1,101 @@
+//! Named bundles of fields, so one device can be moved between dev,
+//! staging and prod without reflashing. `environments` is a JSON object
+//! of them, `{"staging": {"download_url": "https://...", "log_level":
+//! "info", "poll_urls": "60=https://...", "tls_routes": "..."}}`, and
+//! `environment` picks one, none when empty. The picked bundle's fields
+//! take the place of the stored ones as the config is read, so a field
+//! the environment sets follows the environment.
+
+use super::{nvs_key, Config, Store, LOCAL_FIELDS};
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/// Besides `LOCAL_FIELDS`, what a bundle can't set: the selection itself.
+const FIXED: &[&str] = &["environment", "environments"];
+
+/// The names defined in `environments`, for listing.
+pub fn names(environments: &str) -> Result<Vec<String>> {
+    Ok(bundles(environments)?.keys().cloned().collect())
+}
+
+fn bundles(environments: &str) -> Result<Map<String, Value>> {
+    if environments.trim().is_empty() {
+        return Ok(Map::new());
+    }
+    serde_json::from_str(environments).context("environments isn't a JSON object")
+}
+
+/// `store`, with the fields of the environment `name` in front.
+pub struct Overlay<'a, S> {
+    store: &'a S,
+    /// By NVS key.
+    fields: HashMap<String, Value>,
+}
+
+impl<'a, S: Store> Overlay<'a, S> {
+    pub fn new(store: &'a S, name: &str) -> Result<Self> {
+        let mut bundles = bundles(&store.get_str("environments")?.unwrap_or_default())?;
+        let Some(Value::Object(bundle)) = bundles.remove(name) else {
+            bail!("environment {name} isn't defined in environments");
+        };
+        let known = serde_json::to_value(Config::default())?;
+        let mut fields = HashMap::new();
+        for (field, value) in bundle {
+            if FIXED.contains(&field.as_str()) || LOCAL_FIELDS.contains(&field.as_str()) {
+                bail!("environment {name} can't set {field}");
+            }
+            match (known.get(&field), &value) {
+                (None, _) => bail!("environment {name} sets unknown field {field}"),
+                (Some(Value::String(_)), Value::String(_))
+                | (Some(Value::Number(_)), Value::Number(_)) => {}
+                _ => bail!("environment {name} has the wrong type for {field}"),
+            }
+            fields.insert(nvs_key(&field).to_owned(), value);
+        }
+        Ok(Self { store, fields })
+    }
+
+    /// `store` with `fields`, by field name, in front.
+    pub fn with(store: &'a S, fields: Map<String, Value>) -> Self {
+        let fields = fields
+            .into_iter()
+            .map(|(field, value)| (nvs_key(&field).to_owned(), value))
+            .collect();
+        Self { store, fields }
+    }
+}
+
+impl<S: Store> Store for Overlay<'_, S> {
+    fn get_str(&self, key: &str) -> Result<Option<String>> {
+        match self.fields.get(key) {
+            Some(value) => Ok(value.as_str().map(String::from)),
+            None => self.store.get_str(key),
+        }
+    }
+
+    fn get_u16(&self, key: &str) -> Result<Option<u16>> {
+        match self.fields.get(key) {
+            Some(value) => value
+                .as_u64()
+                .and_then(|value| u16::try_from(value).ok())
+                .map(Some)
+                .with_context(|| format!("environment value for {key} out of range")),
+            None => self.store.get_u16(key),
+        }
+    }
+
+    // only ever read through, writes go to the store underneath
+
+    fn set_str(&mut self, key: &str, _: &str) -> Result<()> {
+        bail!("can't write {key} into an environment")
+    }
+
+    fn set_u16(&mut self, key: &str, _: u16) -> Result<()> {
+        bail!("can't write {key} into an environment")
+    }
+
+    fn remove(&mut self, key: &str) -> Result<()> {
+        bail!("can't remove {key} from an environment")
+    }
+}
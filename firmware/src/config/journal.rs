@@ -0,0 +1,82 @@
+//! A write-ahead journal for changes that span several keys of a `Store`.
+//! The new values go into a single entry first, which NVS writes as a
+//! whole or not at all, then out to their keys, and the entry is removed
+//! last. A brownout anywhere in between leaves the entry behind, and
+//! `recover()` on the next boot writes it out again, so the keys end up
+//! either all old or all new, never half of each.
+
+use super::{Store, Stored};
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
+
+const KEY: &str = "journal";
+/// The longest string NVS stores.
+const MAX_LEN: usize = 4000;
+
+/// Records `entries`, key to value or `None` to remove it. Nothing is
+/// changed when this fails.
+pub(super) fn begin<'a>(
+    store: &mut impl Store,
+    entries: impl IntoIterator<Item = (&'a str, Option<&'a Stored>)>,
+) -> Result<()> {
+    let entries: Map<String, Value> = entries
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Some(Stored::Str(value)) => Value::from(value.as_str()),
+                Some(Stored::U16(value)) => Value::from(*value),
+                None => Value::Null,
+            };
+            (key.to_owned(), value)
+        })
+        .collect();
+    let journal = Value::Object(entries).to_string();
+    if journal.len() > MAX_LEN {
+        bail!("change too large to apply at once, split it up");
+    }
+    store
+        .set_str(KEY, &journal)
+        .context("couldn't write the config journal")
+}
+
+/// Writes out what `begin()` recorded and drops the record.
+pub(super) fn finish(store: &mut impl Store) -> Result<()> {
+    let Some(journal) = store.get_str(KEY)? else {
+        return Ok(());
+    };
+    let entries: Map<String, Value> =
+        serde_json::from_str(&journal).context("unreadable config journal")?;
+    for (key, value) in entries {
+        match value {
+            Value::String(value) => Stored::Str(value).store(store, &key)?,
+            Value::Number(value) => {
+                let value = value
+                    .as_u64()
+                    .and_then(|value| u16::try_from(value).ok())
+                    .with_context(|| format!("journaled {key} out of range"))?;
+                Stored::U16(value).store(store, &key)?;
+            }
+            Value::Null => store.remove(&key)?,
+            _ => bail!("journaled {key} has the wrong type"),
+        }
+    }
+    store.remove(KEY)
+}
+
+/// Completes a change a power cut interrupted; call it once at boot,
+/// before the first `Config::load_from()`. A journal that can't be
+/// written out is dropped, so the keys stay as they were, old or half
+/// new, rather than every boot failing on it.
+pub fn recover(store: &mut impl Store) -> Result<()> {
+    if store.get_str(KEY)?.is_none() {
+        return Ok(());
+    }
+    log::warn!("finishing a config change interrupted by a reset");
+    if let Err(err) = finish(store) {
+        log::error!("dropping the interrupted config change: {err:#}");
+        store
+            .remove(KEY)
+            .context("couldn't drop the config journal")?;
+    }
+    Ok(())
+}
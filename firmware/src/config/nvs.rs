@@ -0,0 +1,164 @@
+use super::{journal, snapshot, Config, Store, Stored, LOCAL_FIELDS, SECRET_FIELDS};
+use crate::{buildinfo::BUILD_ID, security};
+use anyhow::{bail, Context, Result};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+
+const NAMESPACE: &str = "config";
+
+impl Store for EspNvs<NvsDefault> {
+    fn get_str(&self, key: &str) -> Result<Option<String>> {
+        let Some(len) = self.str_len(key)? else {
+            return Ok(None);
+        };
+
+        let mut buf = vec![0; len];
+        Ok(EspNvs::get_str(self, key, &mut buf)
+            .with_context(|| format!("couldn't read config key {key}"))?
+            .map(String::from))
+    }
+
+    fn get_u16(&self, key: &str) -> Result<Option<u16>> {
+        Ok(EspNvs::get_u16(self, key)?)
+    }
+
+    fn set_str(&mut self, key: &str, value: &str) -> Result<()> {
+        Ok(EspNvs::set_str(self, key, value)?)
+    }
+
+    fn set_u16(&mut self, key: &str, value: u16) -> Result<()> {
+        Ok(EspNvs::set_u16(self, key, value)?)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<()> {
+        EspNvs::remove(self, key)?;
+        Ok(())
+    }
+}
+
+fn open(partition: EspDefaultNvsPartition) -> Result<EspNvs<NvsDefault>> {
+    EspNvs::new(partition, NAMESPACE, true).context("couldn't open config nvs")
+}
+
+fn open_snapshot(partition: EspDefaultNvsPartition) -> Result<EspNvs<NvsDefault>> {
+    EspNvs::new(partition, snapshot::NAMESPACE, true).context("couldn't open config snapshot nvs")
+}
+
+impl Config {
+    pub fn load(partition: EspDefaultNvsPartition) -> Result<Self> {
+        let mut nvs = open(partition.clone())?;
+        // the config as it is beats none when the copy can't be checked
+        let checked = open_snapshot(partition)
+            .and_then(|mut snapshot| snapshot::check(&mut nvs, &mut snapshot, BUILD_ID));
+        if let Err(err) = checked {
+            log::warn!("config snapshot not checked: {err:#}");
+        }
+        Self::load_from(&nvs)
+    }
+
+    /// Finishes a config change a reset interrupted, see `config::journal`;
+    /// once at boot, before the first `load()`.
+    pub fn recover(partition: EspDefaultNvsPartition) -> Result<()> {
+        super::recover(&mut open(partition)?)
+    }
+
+    /// Writes `entries` into the config namespace as one journaled change,
+    /// `None` removing the key, so a reset halfway through is finished by
+    /// the next boot's `recover()`.
+    pub(crate) fn restore<'a>(
+        partition: EspDefaultNvsPartition,
+        entries: impl IntoIterator<Item = (&'a str, Option<&'a Stored>)>,
+    ) -> Result<()> {
+        let mut nvs = open(partition)?;
+        journal::begin(&mut nvs, entries)?;
+        journal::finish(&mut nvs)
+    }
+
+    /// Keeps a copy of the config as this firmware left it, for an OTA
+    /// update about to replace it, see `config::snapshot`.
+    pub fn snapshot(partition: EspDefaultNvsPartition) -> Result<()> {
+        let mut snapshot = open_snapshot(partition.clone())?;
+        snapshot::take(&open(partition)?, &mut snapshot, BUILD_ID)
+    }
+
+    /// `apply_to()` the config namespace, short of writing a secret where
+    /// `security::ensure_secret_storage()` says it isn't safe.
+    pub fn apply(
+        partition: EspDefaultNvsPartition,
+        changes: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<Vec<String>> {
+        // clearing one is always fine
+        if let Some((field, _)) = changes
+            .iter()
+            .find(|(field, value)| is_secret(field, value))
+        {
+            security::ensure_secret_storage().with_context(|| format!("not storing {field}"))?;
+        }
+        Self::apply_to(&mut open(partition)?, changes)
+    }
+
+    /// `apply()` for a change from off the device, which is refused if it
+    /// touches any of the `LOCAL_FIELDS`.
+    pub fn apply_remote(
+        partition: EspDefaultNvsPartition,
+        changes: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<Vec<String>> {
+        if let Some(field) = changes
+            .keys()
+            .find(|field| LOCAL_FIELDS.contains(&field.as_str()))
+        {
+            bail!("config field {field} can't be changed remotely");
+        }
+        Self::apply(partition, changes)
+    }
+
+    /// Whether any secret field is set, they all come out of NVS.
+    pub fn has_secrets(&self) -> bool {
+        let Ok(serde_json::Value::Object(fields)) = serde_json::to_value(self) else {
+            return false;
+        };
+        fields.iter().any(|(field, value)| is_secret(field, value))
+    }
+}
+
+fn is_secret(field: &str, value: &serde_json::Value) -> bool {
+    SECRET_FIELDS.contains(&field) && value.as_str().is_some_and(|value| !value.is_empty())
+}
+
+/// Has `esp_restart()` commit the `batch` stores first, whoever calls it.
+pub fn flush_on_restart() -> Result<()> {
+    extern "C" fn flush() {
+        super::batch::flush_all();
+    }
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_register_shutdown_handler(Some(flush)) })?;
+    Ok(())
+}
+
+/// Just the board field, the pins are handed out before the rest of the
+/// config is loaded.
+pub fn board(partition: EspDefaultNvsPartition) -> Result<String> {
+    Ok(open(partition)?.get_str("board")?.unwrap_or_default())
+}
+
+/// The BLE passkey, the GATT server starts before the config is loaded.
+#[cfg(feature = "ble")]
+pub fn ble_passkey(partition: EspDefaultNvsPartition) -> Result<Option<u32>> {
+    let passkey = open(partition)?.get_str("ble_passkey")?.unwrap_or_default();
+    if passkey.is_empty() {
+        return Ok(None);
+    }
+    if passkey.len() != 6 || !passkey.bytes().all(|byte| byte.is_ascii_digit()) {
+        bail!("ble_passkey isn't six digits");
+    }
+    Ok(passkey.parse().ok())
+}
+
+/// Wipes the whole default NVS partition, config and WiFi credentials
+/// alike, and restarts as a fresh device.
+#[cfg(feature = "button")]
+pub fn factory_reset() -> ! {
+    log::warn!("factory reset");
+    if let Err(err) = esp_idf_sys::esp!(unsafe { esp_idf_sys::nvs_flash_erase() }) {
+        log::error!("couldn't erase nvs: {err}");
+    }
+    esp_idf_hal::reset::restart()
+}
@@ -0,0 +1,85 @@
+//! A copy of the stored config taken before an OTA update, so a rollback
+//! to the old firmware also gets the config back as that firmware left
+//! it. The new image may add fields or rewrite old ones in a form the old
+//! one doesn't read, and an old image failing on its config has nothing to
+//! roll back to.
+//!
+//! The copy goes into its own namespace with the build it belongs to,
+//! `buildinfo::BUILD_ID`, which tells apart images of the same version.
+//! Another build booting marks it as left; the build it belongs to
+//! booting again after that is a rollback, and the copy is written back
+//! over the config. The writes are the same whatever was
+//! there before, so a reset halfway through just has them done again.
+
+use super::{nvs_key, Config, Store, Stored};
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+pub const NAMESPACE: &str = "cfgsnap";
+/// The build the copy belongs to.
+const VERSION: &str = "snap_fw";
+/// Set once another build has booted with the copy in place.
+const LEFT: &str = "snap_left";
+
+/// NVS key and whether it holds a number, for every config field.
+fn keys() -> Result<Vec<(String, bool)>> {
+    let Value::Object(fields) = serde_json::to_value(Config::default())? else {
+        unreachable!("the config serializes as an object");
+    };
+    Ok(fields
+        .iter()
+        .map(|(field, value)| (nvs_key(field).to_owned(), value.is_number()))
+        .collect())
+}
+
+fn copy(from: &impl Store, to: &mut impl Store) -> Result<()> {
+    for (key, number) in keys()? {
+        let key = key.as_str();
+        let value = if number {
+            from.get_u16(key)?.map(Stored::U16)
+        } else {
+            from.get_str(key)?.map(Stored::Str)
+        };
+        match value {
+            Some(value) => value.store(to, key)?,
+            None => to.remove(key)?,
+        }
+    }
+    Ok(())
+}
+
+/// Copies `config` into `snapshot` for `build`, the one running now,
+/// replacing an older copy.
+pub fn take(config: &impl Store, snapshot: &mut impl Store, build: &str) -> Result<()> {
+    // a copy half written can't be mistaken for a whole one
+    snapshot.remove(VERSION)?;
+    snapshot.remove(LEFT)?;
+    copy(config, snapshot).context("couldn't snapshot the config")?;
+    snapshot.set_str(VERSION, build)?;
+    log::info!("config snapshot taken for {build}");
+    Ok(())
+}
+
+/// Run at boot by `build` before the config is read: marks the copy left
+/// when it's another build's, writes it back when it's ours and another
+/// booted since. Whether the config was restored.
+pub fn check(config: &mut impl Store, snapshot: &mut impl Store, build: &str) -> Result<bool> {
+    let Some(owner) = snapshot.get_str(VERSION)? else {
+        return Ok(false);
+    };
+    let left = snapshot.get_u16(LEFT)? == Some(1);
+    if owner != build {
+        if !left {
+            log::info!("config snapshot of {owner} kept in case of a rollback");
+            snapshot.set_u16(LEFT, 1)?;
+        }
+        return Ok(false);
+    }
+    if !left {
+        return Ok(false);
+    }
+    log::warn!("rolled back to {build}, restoring its config");
+    copy(snapshot, config).context("couldn't restore the config snapshot")?;
+    snapshot.remove(LEFT)?;
+    Ok(true)
+}
@@ -0,0 +1,275 @@
+use crate::{
+    config::{self, Config},
+    events::{self, Event},
+    power, reload, telemetry,
+};
+use anyhow::{bail, Context, Result};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use log::LevelFilter;
+use std::{fmt::Write, sync::Mutex, time::Duration};
+
+pub mod tcp;
+#[cfg(feature = "serial-console")]
+pub mod uart;
+
+/// What the transport should do once the reply is written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Continue,
+    Quit,
+    Reboot,
+}
+
+/// Runs with the words after the command name; an error is the reply.
+pub type Handler = fn(&Console, &[&str]) -> Result<String>;
+
+/// One console command, listed by `help` as `usage` and `summary`.
+#[derive(Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub summary: &'static str,
+    pub run: Handler,
+}
+
+/// Lets the reply reach the terminal before the radio goes down.
+const SLEEP_GRACE: Duration = Duration::from_secs(1);
+
+const BUILTIN: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "help",
+        summary: "this list",
+        run: help,
+    },
+    Command {
+        name: "status",
+        usage: "status",
+        summary: "telemetry snapshot",
+        run: status,
+    },
+    Command {
+        name: "inventory",
+        usage: "inventory",
+        summary: "features, subsystems and versions built in",
+        run: inventory,
+    },
+    Command {
+        name: "config",
+        usage: "config [get <field> | set <field> <value>]",
+        summary: "running config, secrets redacted, or change a field",
+        run: config,
+    },
+    Command {
+        name: "env",
+        usage: "env [name | none]",
+        summary: "list the environments or switch to one",
+        run: environment,
+    },
+    Command {
+        name: "log",
+        usage: "log [level]",
+        summary: "show or set the log level (off, error, warn, info, debug, trace)",
+        run: log_level,
+    },
+    Command {
+        name: "loglevel",
+        usage: "loglevel [level]",
+        summary: "the same as log",
+        run: log_level,
+    },
+    Command {
+        name: "cpu",
+        usage: "cpu [profile]",
+        summary: "show the cpu frequency range or switch profile (performance, economy)",
+        run: cpu,
+    },
+    Command {
+        name: "sleep",
+        usage: "sleep <secs>",
+        summary: "deep sleep, waking on the timer",
+        run: sleep,
+    },
+];
+
+/// Commands other modules added with `register()`.
+static REGISTERED: Mutex<Vec<Command>> = Mutex::new(Vec::new());
+
+/// Adds a command to every console; a name already taken is shadowed.
+pub fn register(command: Command) {
+    REGISTERED.lock().unwrap().push(command);
+}
+
+/// Line-oriented debug commands, shared by every console transport.
+pub struct Console {
+    config: serde_json::Value,
+    nvs: EspDefaultNvsPartition,
+    /// Set by `uart::serve()`, for what only someone at the device may do.
+    uart: bool,
+}
+
+impl Console {
+    pub fn new(config: &Config, nvs: EspDefaultNvsPartition) -> Self {
+        Self {
+            config: config.redacted(),
+            nvs,
+            uart: false,
+        }
+    }
+
+    /// Whether this is the serial console, not one over the network.
+    pub fn is_uart(&self) -> bool {
+        self.uart
+    }
+
+    /// Runs one command line and returns the reply text.
+    pub fn execute(&self, line: &str) -> (String, Outcome) {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&name, args)) = words.split_first() else {
+            return (String::new(), Outcome::Continue);
+        };
+
+        match name {
+            "reboot" => return (String::from("rebooting"), Outcome::Reboot),
+            "quit" | "exit" => return (String::new(), Outcome::Quit),
+            _ => {}
+        }
+        let reply = match commands().into_iter().find(|command| command.name == name) {
+            Some(command) => (command.run)(self, args).unwrap_or_else(|err| format!("{err:#}")),
+            None => format!("unknown command {name}, try help"),
+        };
+        (reply, Outcome::Continue)
+    }
+}
+
+/// Registered commands first, so modules can override a built-in one.
+fn commands() -> Vec<Command> {
+    let mut commands = REGISTERED.lock().unwrap().clone();
+    commands.reverse();
+    commands.extend_from_slice(BUILTIN);
+    commands
+}
+
+fn help(_: &Console, _: &[&str]) -> Result<String> {
+    let mut reply = String::from("commands:");
+    let mut commands = commands();
+    commands.sort_by_key(|command| command.name);
+    commands.dedup_by_key(|command| command.name);
+    for command in commands {
+        let _ = write!(reply, "\n  {:<18}{}", command.usage, command.summary);
+    }
+    reply.push_str("\n  reboot            restart the device");
+    reply.push_str("\n  quit              end the session");
+    Ok(reply)
+}
+
+fn status(_: &Console, _: &[&str]) -> Result<String> {
+    Ok(pretty(&telemetry::snapshot()))
+}
+
+fn inventory(_: &Console, _: &[&str]) -> Result<String> {
+    Ok(pretty(&crate::device::inventory()))
+}
+
+fn config(console: &Console, args: &[&str]) -> Result<String> {
+    match args {
+        [] => Ok(pretty(&console.config)),
+        ["get", field] => console
+            .config
+            .get(*field)
+            .map(pretty)
+            .with_context(|| format!("unknown config field {field}")),
+        ["set", field, value @ ..] if !value.is_empty() => {
+            let value = value.join(" ");
+            let value = match console.config.get(*field) {
+                Some(serde_json::Value::Number(_)) => value
+                    .parse::<u64>()
+                    .with_context(|| format!("config field {field} takes a number"))?
+                    .into(),
+                _ => serde_json::Value::String(value),
+            };
+            let mut changes = serde_json::Map::new();
+            changes.insert(field.to_string(), value);
+            if Config::apply(console.nvs.clone(), &changes)?.is_empty() {
+                return Ok(format!("{field} unchanged"));
+            }
+            events::publish(Event::ConfigChanged);
+            if reload::LIVE.contains(field) {
+                Ok(format!("{field} saved and applied"))
+            } else {
+                Ok(format!("{field} saved, it applies after a reboot"))
+            }
+        }
+        _ => bail!("usage: config [get <field> | set <field> <value>]"),
+    }
+}
+
+fn environment(console: &Console, args: &[&str]) -> Result<String> {
+    match args {
+        [] => {
+            let field = |name: &str| console.config[name].as_str().unwrap_or_default();
+            let names = config::environment::names(field("environments"))?;
+            Ok(format!(
+                "environment {}, defined: {}",
+                Some(field("environment"))
+                    .filter(|name| !name.is_empty())
+                    .unwrap_or("none"),
+                if names.is_empty() {
+                    String::from("none")
+                } else {
+                    names.join(", ")
+                }
+            ))
+        }
+        [name] => reload::switch_environment(console.nvs.clone(), name),
+        _ => bail!("usage: env [name | none]"),
+    }
+}
+
+fn log_level(_: &Console, args: &[&str]) -> Result<String> {
+    match args.first() {
+        None => Ok(format!("log level {}", log::max_level())),
+        Some(level) => {
+            let level: LevelFilter = level
+                .parse()
+                .map_err(|_| anyhow::anyhow!("unknown log level {level}"))?;
+            log::set_max_level(level);
+            Ok(format!("log level set to {level}"))
+        }
+    }
+}
+
+fn cpu(_: &Console, args: &[&str]) -> Result<String> {
+    match args.first() {
+        None => Ok(format!("{:?}", power::configuration()?)),
+        Some(profile) => {
+            power::set_profile(profile.parse()?)?;
+            Ok(format!("power profile set to {profile}"))
+        }
+    }
+}
+
+fn sleep(_: &Console, args: &[&str]) -> Result<String> {
+    let [secs] = args else {
+        bail!("usage: sleep <secs>");
+    };
+    let secs: u64 = secs.parse().context("sleep takes seconds")?;
+    std::thread::Builder::new()
+        .name("console-sleep".into())
+        .spawn(move || {
+            std::thread::sleep(SLEEP_GRACE);
+            let config = power::SleepConfig {
+                timer: Some(Duration::from_secs(secs)),
+                ..Default::default()
+            };
+            if let Err(err) = power::sleep(&config) {
+                log::error!("console: couldn't sleep: {err:#}");
+            }
+        })
+        .context("couldn't spawn sleep")?;
+    Ok(format!("sleeping for {secs}s"))
+}
+
+fn pretty(value: &impl serde::Serialize) -> String {
+    serde_json::to_string_pretty(value).unwrap_or_else(|err| format!("{err}"))
+}
@@ -0,0 +1,160 @@
+use super::{Console, Outcome};
+use crate::secret::{constant_time_eq, Secret};
+use anyhow::{ensure, Context, Result};
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::{IpAddr, Ipv4Addr, TcpListener, TcpStream},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+pub const PORT: u16 = 23;
+
+const MAX_ATTEMPTS: usize = 3;
+/// Pause after each wrong password.
+const WRONG_DELAY: Duration = Duration::from_secs(1);
+/// A peer that used up its attempts is refused for this long, doubled
+/// every time it happens again.
+const LOCKOUT: Duration = Duration::from_secs(30);
+const MAX_LOCKOUT: Duration = Duration::from_secs(3600);
+/// Sessions left open without input are closed after this.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+const STACK_SIZE: usize = 8 * 1024;
+/// Longer input drops the session, the same cap as the UART's.
+const MAX_LINE: usize = 256;
+
+/// Serves the console over plain TCP, `nc <device> 23` or telnet. Peers
+/// outside the local network are refused and everyone else has to give
+/// the configured password first, and a peer that keeps getting it wrong
+/// is locked out for a while. One session at a time.
+pub fn start(console: Console, password: Secret<String>) -> Result<()> {
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, PORT))
+        .with_context(|| format!("couldn't bind console port {PORT}"))?;
+    let console = Arc::new(console);
+
+    std::thread::Builder::new()
+        .name("console".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            let mut lockouts = Lockouts::new();
+            for stream in listener.incoming() {
+                let result = stream
+                    .map_err(anyhow::Error::from)
+                    .and_then(|stream| session(stream, &console, password.expose(), &mut lockouts));
+                if let Err(err) = result {
+                    log::warn!("console session: {err:#}");
+                }
+            }
+        })
+        .context("couldn't spawn console listener")?;
+
+    log::info!("console listening on port {PORT}");
+    Ok(())
+}
+
+/// Peers that used up their attempts: how often, and until when they're
+/// refused.
+type Lockouts = HashMap<IpAddr, (u32, Instant)>;
+
+fn session(
+    mut stream: TcpStream,
+    console: &Console,
+    password: &str,
+    lockouts: &mut Lockouts,
+) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    if !local(peer.ip()) {
+        log::warn!("console: refused {peer}, not on the local network");
+        return Ok(());
+    }
+    if let Some((_, until)) = lockouts.get(&peer.ip()) {
+        if Instant::now() < *until {
+            log::warn!("console: refused {peer}, locked out");
+            return Ok(());
+        }
+    }
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut authenticated = false;
+    for _ in 0..MAX_ATTEMPTS {
+        stream.write_all(b"password: ")?;
+        let Some(line) = read_line(&mut reader)? else {
+            return Ok(());
+        };
+        if constant_time_eq(line.trim().as_bytes(), password.as_bytes()) {
+            authenticated = true;
+            break;
+        }
+        std::thread::sleep(WRONG_DELAY);
+        stream.write_all(b"wrong password\r\n")?;
+    }
+    if !authenticated {
+        let lockout = lock_out(lockouts, peer.ip());
+        log::warn!("console: {peer} failed to authenticate, locked out for {lockout:?}");
+        return Ok(());
+    }
+    lockouts.remove(&peer.ip());
+    log::info!("console: {peer} logged in");
+    write!(stream, "{} console, try help\r\n> ", crate::device::id())?;
+
+    while let Some(line) = read_line(&mut reader)? {
+        let (reply, outcome) = console.execute(&line);
+        if !reply.is_empty() {
+            stream.write_all(reply.replace('\n', "\r\n").as_bytes())?;
+            stream.write_all(b"\r\n")?;
+        }
+        match outcome {
+            Outcome::Continue => stream.write_all(b"> ")?,
+            Outcome::Quit => break,
+            Outcome::Reboot => {
+                log::warn!("console: reboot requested by {peer}");
+                esp_idf_hal::reset::restart();
+            }
+        }
+    }
+    log::info!("console: {peer} logged out");
+    Ok(())
+}
+
+/// Locks `ip` out for its next lockout and returns how long that is.
+fn lock_out(lockouts: &mut Lockouts, ip: IpAddr) -> Duration {
+    let now = Instant::now();
+    // a peer that has been quiet for a good while starts over
+    lockouts.retain(|_, (_, until)| now.saturating_duration_since(*until) < MAX_LOCKOUT);
+    let failures = lockouts.get(&ip).map_or(0, |(failures, _)| *failures) + 1;
+    let lockout = LOCKOUT
+        .saturating_mul(1 << (failures - 1).min(7))
+        .min(MAX_LOCKOUT);
+    lockouts.insert(ip, (failures, now + lockout));
+    lockout
+}
+
+/// One input line with non-printable bytes dropped, which also discards
+/// most of the option negotiation a telnet client sends; `None` once the
+/// peer hangs up, an error for a line over `MAX_LINE`.
+fn read_line(reader: &mut BufReader<impl Read>) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    let limit = MAX_LINE as u64 + 1;
+    if reader.by_ref().take(limit).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    ensure!(
+        line.len() <= MAX_LINE || line.ends_with(b"\n"),
+        "input line over {MAX_LINE} bytes"
+    );
+    line.retain(|byte| byte.is_ascii_graphic() || *byte == b' ');
+    Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
+
+fn local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+        // link-local and unique-local (fc00::/7)
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            first & 0xffc0 == 0xfe80 || first & 0xfe00 == 0xfc00 || ip.is_loopback()
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use super::{Console, Outcome};
+use anyhow::{Context, Result};
+use esp_idf_hal::{
+    delay::BLOCK,
+    gpio::AnyIOPin,
+    uart::{config::Config, UartDriver, UART0},
+    units::Hertz,
+};
+use std::{sync::OnceLock, time::Duration};
+
+const BAUDRATE: Hertz = Hertz(115_200);
+const MAX_LINE: usize = 256;
+const STACK_SIZE: usize = 8 * 1024;
+
+/// Set once the config is loaded; until then input waits in the FIFO.
+static CONSOLE: OnceLock<Console> = OnceLock::new();
+
+/// The chip's default console UART, shared with the boot log.
+pub struct Pins {
+    pub tx: AnyIOPin,
+    pub rx: AnyIOPin,
+}
+
+/// Takes over the log UART for a console with echo and line editing, so it
+/// works from a plain serial monitor. Anyone with a cable could reflash the
+/// device anyway, so there's no password. Commands run once `serve()` has
+/// handed over the console.
+pub fn start(uart: UART0, pins: Pins) -> Result<()> {
+    let uart = UartDriver::new(
+        uart,
+        pins.tx,
+        pins.rx,
+        None::<AnyIOPin>,
+        None::<AnyIOPin>,
+        &Config::new().baudrate(BAUDRATE),
+    )?;
+
+    std::thread::Builder::new()
+        .name("serial-console".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            let console = loop {
+                match CONSOLE.get() {
+                    Some(console) => break console,
+                    None => std::thread::sleep(Duration::from_secs(1)),
+                }
+            };
+            if let Err(err) = run(console, &uart) {
+                log::error!("serial console stopped: {err:#}");
+            }
+        })
+        .context("couldn't spawn serial console")?;
+    Ok(())
+}
+
+pub fn serve(mut console: Console) {
+    console.uart = true;
+    let _ = CONSOLE.set(console);
+}
+
+fn run(console: &Console, uart: &UartDriver) -> Result<()> {
+    write(uart, "> ")?;
+    let mut line = Vec::new();
+    let mut previous = 0;
+    let mut byte = [0];
+
+    loop {
+        if uart.read(&mut byte, BLOCK)? == 0 {
+            continue;
+        }
+        match byte[0] {
+            // a CR LF terminal ends the line once
+            b'\n' if previous == b'\r' => {}
+            b'\r' | b'\n' => {
+                write(uart, "\r\n")?;
+                let (reply, outcome) = console.execute(&String::from_utf8_lossy(&line));
+                line.clear();
+                if !reply.is_empty() {
+                    write(uart, &reply.replace('\n', "\r\n"))?;
+                    write(uart, "\r\n")?;
+                }
+                if outcome == Outcome::Reboot {
+                    log::warn!("serial console: reboot requested");
+                    esp_idf_hal::reset::restart();
+                }
+                // quit has nothing to end, the port stays open
+                write(uart, "> ")?;
+            }
+            // backspace and delete
+            0x08 | 0x7f => {
+                if line.pop().is_some() {
+                    write(uart, "\x08 \x08")?;
+                }
+            }
+            #[cfg(feature = "factory")]
+            crate::factory::START if crate::factory::enabled() => {
+                crate::factory::serve(uart)?;
+            }
+            printable if (printable.is_ascii_graphic() || printable == b' ') => {
+                if line.len() < MAX_LINE {
+                    line.push(printable);
+                    uart.write(&[printable])?;
+                }
+            }
+            _ => {}
+        }
+        previous = byte[0];
+    }
+}
+
+fn write(uart: &UartDriver, text: &str) -> Result<()> {
+    uart.write(text.as_bytes())?;
+    Ok(())
+}
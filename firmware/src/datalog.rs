@@ -0,0 +1,308 @@
+//! Append-only record log on the raw `datalog` partition, for telemetry
+//! sampled while offline. Sectors are filled in a ring: when the current
+//! one runs out the next is erased and taken, so every sector is erased
+//! equally often, and once the ring is full the oldest sector's records
+//! are dropped. A record is only ever appended; the broker's PUBACK for
+//! it clears its flag byte in place, which flash allows without an erase.
+
+use anyhow::{bail, ensure, Context, Result};
+use esp_idf_sys::{self as sys, esp};
+use std::{ffi::c_void, sync::Mutex};
+
+const SECTOR: u32 = 4096;
+/// Sector header: magic and a sequence number that grows every erase, so
+/// the highest one is the sector being appended to.
+const SECTOR_HEADER: u32 = 8;
+const MAGIC: u32 = u32::from_le_bytes(*b"LOG1");
+/// Record header: length, flag, padding and a CRC32 of the payload.
+const RECORD_HEADER: u32 = 8;
+const PENDING: u8 = 0xff;
+const SENT: u8 = 0x00;
+/// An erased length, nothing was written from here on.
+const ERASED: u16 = 0xffff;
+pub const MAX_RECORD: usize = 1024;
+/// The data subtype of the partition (the custom range starts at 0x40).
+const SUBTYPE: sys::esp_partition_subtype_t = 0x40;
+
+struct Log {
+    partition: *const sys::esp_partition_t,
+    sectors: u32,
+    /// The sector appended to, its sequence and its first free offset.
+    head: u32,
+    sequence: u32,
+    offset: u32,
+    /// Where the next drain starts looking for pending records.
+    cursor: (u32, u32),
+}
+
+// the partition table is static and esp_partition calls are thread safe
+unsafe impl Send for Log {}
+
+static LOG: Mutex<Option<Log>> = Mutex::new(None);
+
+/// Which record a drain handed out, for `acked()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Receipt {
+    address: u32,
+    /// Its sector's sequence; a sector erased since holds other records.
+    sequence: u32,
+}
+
+/// A record as read back.
+struct Record {
+    length: u16,
+    flag: u8,
+    crc: u32,
+}
+
+/// Finds the partition and the end of the log.
+pub fn open() -> Result<()> {
+    let partition = unsafe {
+        sys::esp_partition_find_first(
+            sys::esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+            SUBTYPE,
+            c"datalog".as_ptr(),
+        )
+    };
+    ensure!(!partition.is_null(), "no datalog partition");
+    let sectors = unsafe { (*partition).size } / SECTOR;
+    ensure!(sectors >= 2, "the datalog partition needs two sectors");
+
+    let mut log = Log {
+        partition,
+        sectors,
+        head: 0,
+        sequence: 0,
+        offset: SECTOR_HEADER,
+        cursor: (0, SECTOR_HEADER),
+    };
+    let headers = (0..sectors)
+        .map(|sector| Ok((sector, log.sector_sequence(sector)?)))
+        .collect::<Result<Vec<_>>>()?;
+    match headers
+        .iter()
+        .filter_map(|&(sector, sequence)| Some((sector, sequence?)))
+        .max_by_key(|&(_, sequence)| sequence)
+    {
+        None => {
+            log::info!("datalog: empty, formatting {sectors} sectors");
+            log.take(0, 1)?;
+        }
+        Some((head, sequence)) => {
+            // the oldest sector is the first valid one after the head
+            let oldest = (1..=sectors)
+                .map(|step| (head + step) % sectors)
+                .find(|&sector| headers[sector as usize].1.is_some())
+                .unwrap_or(head);
+            log.head = head;
+            log.sequence = sequence;
+            log.cursor = (oldest, SECTOR_HEADER);
+            log.offset = log.end_of(head)?;
+        }
+    }
+    log::info!(
+        "datalog: {sectors} sectors, appending to {} at {}",
+        log.head,
+        log.offset
+    );
+
+    *LOG.lock().unwrap() = Some(log);
+    Ok(())
+}
+
+/// Appends one record.
+pub fn append(payload: &[u8]) -> Result<()> {
+    ensure!(
+        payload.len() <= MAX_RECORD,
+        "a {} byte record exceeds the datalog cap",
+        payload.len()
+    );
+    let mut log = LOG.lock().unwrap();
+    let log = log.as_mut().context("datalog not open")?;
+
+    let size = aligned(payload.len());
+    if log.offset + size > SECTOR {
+        log.advance()?;
+    }
+    let mut record = Vec::with_capacity(size as usize);
+    record.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    record.extend_from_slice(&[PENDING, 0xff]);
+    record.extend_from_slice(&crc(payload).to_le_bytes());
+    record.extend_from_slice(payload);
+    record.resize(size as usize, 0xff);
+    log.write(log.head * SECTOR + log.offset, &record)?;
+    log.offset += size;
+    Ok(())
+}
+
+/// Hands every pending record, oldest first, to `send`, with the receipt
+/// to mark it sent by once the broker has it. Stops at the first `send`
+/// error, which is returned; the record stays pending for the next drain.
+/// One that was sent but never acknowledged goes again after a reboot.
+pub fn drain(mut send: impl FnMut(&[u8], Receipt) -> Result<()>) -> Result<usize> {
+    let mut log = LOG.lock().unwrap();
+    let log = log.as_mut().context("datalog not open")?;
+
+    let mut sent = 0;
+    loop {
+        let (sector, offset) = log.cursor;
+        if sector == log.head && offset >= log.offset {
+            break;
+        }
+        let address = sector * SECTOR + offset;
+        let record = match log.record(address)? {
+            Some(record) if offset + aligned(record.length.into()) <= SECTOR => record,
+            // the end of a full sector, or a torn write before a reboot
+            _ => {
+                log.cursor = ((sector + 1) % log.sectors, SECTOR_HEADER);
+                continue;
+            }
+        };
+        if record.flag == PENDING {
+            let mut payload = vec![0; record.length.into()];
+            log.read(address + RECORD_HEADER, &mut payload)?;
+            if crc(&payload) == record.crc {
+                let sequence = log.sector_sequence(sector)?.unwrap_or(u32::MAX);
+                send(&payload, Receipt { address, sequence })?;
+                sent += 1;
+            } else {
+                log::warn!("datalog: dropping a corrupt record at {address:#x}");
+            }
+        }
+        log.cursor.1 += aligned(record.length.into());
+    }
+    if sent > 0 {
+        log::info!("datalog: sending {sent} records");
+    }
+    Ok(sent)
+}
+
+/// Marks a drained record sent, unless its sector was reused meanwhile.
+pub fn acked(receipt: Receipt) -> Result<()> {
+    let mut log = LOG.lock().unwrap();
+    let log = log.as_mut().context("datalog not open")?;
+    if log.sector_sequence(receipt.address / SECTOR)? == Some(receipt.sequence) {
+        log.write(receipt.address + 2, &[SENT])?;
+    }
+    Ok(())
+}
+
+impl Log {
+    /// Moves on to the next sector, dropping its records if it held any.
+    fn advance(&mut self) -> Result<()> {
+        let next = (self.head + 1) % self.sectors;
+        if self.cursor.0 == next {
+            log::warn!("datalog: full, dropping the oldest sector");
+            self.cursor = ((next + 1) % self.sectors, SECTOR_HEADER);
+        }
+        self.take(next, self.sequence + 1)
+    }
+
+    /// Erases `sector` and makes it the head.
+    fn take(&mut self, sector: u32, sequence: u32) -> Result<()> {
+        esp!(unsafe {
+            sys::esp_partition_erase_range(
+                self.partition,
+                (sector * SECTOR) as usize,
+                SECTOR as usize,
+            )
+        })?;
+        let mut header = [0; SECTOR_HEADER as usize];
+        header[..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..].copy_from_slice(&sequence.to_le_bytes());
+        self.write(sector * SECTOR, &header)?;
+        (self.head, self.sequence, self.offset) = (sector, sequence, SECTOR_HEADER);
+        Ok(())
+    }
+
+    fn sector_sequence(&self, sector: u32) -> Result<Option<u32>> {
+        let mut header = [0; SECTOR_HEADER as usize];
+        self.read(sector * SECTOR, &mut header)?;
+        let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let sequence = u32::from_le_bytes(header[4..].try_into().unwrap());
+        Ok((magic == MAGIC && sequence != u32::MAX).then_some(sequence))
+    }
+
+    /// The first free offset in the head sector. A record that doesn't
+    /// check out was torn by a reset, the sector is left as it is and the
+    /// log carries on in the next one.
+    fn end_of(&mut self, sector: u32) -> Result<u32> {
+        let mut offset = SECTOR_HEADER;
+        while offset + RECORD_HEADER <= SECTOR {
+            let address = sector * SECTOR + offset;
+            let Some(record) = self.record(address)? else {
+                return Ok(offset);
+            };
+            let size = aligned(record.length.into());
+            if offset + size > SECTOR {
+                break;
+            }
+            let mut payload = vec![0; record.length.into()];
+            self.read(address + RECORD_HEADER, &mut payload)?;
+            if crc(&payload) != record.crc {
+                break;
+            }
+            offset += size;
+        }
+        if offset + RECORD_HEADER > SECTOR {
+            return Ok(offset);
+        }
+        log::warn!("datalog: torn record in sector {sector}");
+        self.advance()?;
+        Ok(self.offset)
+    }
+
+    /// The record header at `address`, `None` where nothing was written.
+    fn record(&self, address: u32) -> Result<Option<Record>> {
+        if address % SECTOR + RECORD_HEADER > SECTOR {
+            return Ok(None);
+        }
+        let mut header = [0; RECORD_HEADER as usize];
+        self.read(address, &mut header)?;
+        let length = u16::from_le_bytes([header[0], header[1]]);
+        if length == ERASED {
+            return Ok(None);
+        }
+        Ok(Some(Record {
+            length,
+            flag: header[2],
+            crc: u32::from_le_bytes(header[4..].try_into().unwrap()),
+        }))
+    }
+
+    fn read(&self, address: u32, buffer: &mut [u8]) -> Result<()> {
+        esp!(unsafe {
+            sys::esp_partition_read(
+                self.partition,
+                address as usize,
+                buffer.as_mut_ptr() as *mut c_void,
+                buffer.len(),
+            )
+        })
+        .with_context(|| format!("datalog read at {address:#x}"))
+    }
+
+    fn write(&self, address: u32, data: &[u8]) -> Result<()> {
+        if address % SECTOR + data.len() as u32 > SECTOR {
+            bail!("datalog write at {address:#x} crosses a sector");
+        }
+        esp!(unsafe {
+            sys::esp_partition_write(
+                self.partition,
+                address as usize,
+                data.as_ptr() as *const c_void,
+                data.len(),
+            )
+        })
+        .with_context(|| format!("datalog write at {address:#x}"))
+    }
+}
+
+/// Header plus payload, kept word aligned.
+fn aligned(length: usize) -> u32 {
+    (RECORD_HEADER + length as u32 + 3) & !3
+}
+
+fn crc(payload: &[u8]) -> u32 {
+    unsafe { sys::esp_rom_crc32_le(0, payload.as_ptr(), payload.len() as u32) }
+}
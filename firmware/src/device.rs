@@ -0,0 +1,97 @@
+use std::time::Duration;
+
+/// Stable identifier, e.g. `esp32-a1b2c3d4e5f6`, see `identity::id()`.
+pub fn id() -> &'static str {
+    crate::identity::id()
+}
+
+pub fn uptime() -> Duration {
+    Duration::from_micros(unsafe { esp_idf_sys::esp_timer_get_time() } as u64)
+}
+
+/// From the hardware RNG, random enough for jitter and ids.
+pub fn random() -> u32 {
+    unsafe { esp_idf_sys::esp_random() }
+}
+
+pub fn firmware_version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
+
+/// The version, the build it is, and what protects the image and the
+/// secrets beside it.
+pub fn firmware_info() -> serde_json::Value {
+    serde_json::json!({
+        "version": firmware_version(),
+        "build": crate::buildinfo::json(),
+        "debug": cfg!(debug_assertions),
+        "security": crate::security::status(),
+    })
+}
+
+/// What this build can do, for support to read off a device in the field:
+/// the features it was built with, which of the alternatives they pick,
+/// and the versions of the crates and ESP-IDF underneath.
+pub fn inventory() -> serde_json::Value {
+    let crates: Vec<&str> = env!("FIRMWARE_CRATES")
+        .split(';')
+        .filter(|version| !version.is_empty())
+        .collect();
+    let esp_idf = unsafe { std::ffi::CStr::from_ptr(esp_idf_sys::esp_get_idf_version()) };
+    serde_json::json!({
+        "version": firmware_version(),
+        "chip": crate::chip::CHIP.name,
+        "esp_idf": esp_idf.to_string_lossy(),
+        "features": crate::buildinfo::features(),
+        "subsystems": {
+            "runtime": if cfg!(feature = "tokio-rt") { "tokio" } else { "edge-executor" },
+            "tls": "rustls on ring",
+            "http": compiled(&[
+                ("reqwest", cfg!(feature = "http-reqwest")),
+                ("lite", cfg!(feature = "http-lite")),
+                ("quic", cfg!(feature = "quic")),
+            ]),
+            "links": compiled(&[
+                ("wifi", cfg!(feature = "wifi")),
+                ("eth", cfg!(feature = "eth")),
+                ("cellular", cfg!(feature = "cellular")),
+                ("open_eth", cfg!(feature = "qemu")),
+                ("wireguard", cfg!(feature = "wireguard")),
+            ]),
+            "channels": compiled(&[
+                ("mqtt", cfg!(feature = "mqtt")),
+                ("ws", cfg!(feature = "ws")),
+                ("sse", cfg!(feature = "sse")),
+                ("longpoll", cfg!(feature = "longpoll")),
+                ("grpc", cfg!(feature = "grpc")),
+                ("lwm2m", cfg!(feature = "lwm2m")),
+                ("cloud-https", cfg!(feature = "cloud-https")),
+                ("aws", cfg!(feature = "aws")),
+                ("azure", cfg!(feature = "azure")),
+                ("ble", cfg!(feature = "ble")),
+            ]),
+            "display": if cfg!(feature = "epaper") {
+                Some("epaper")
+            } else if cfg!(feature = "display") {
+                Some("oled")
+            } else {
+                None
+            },
+            "time": compiled(&[
+                ("sntp", cfg!(feature = "sntp")),
+                ("rtc", cfg!(feature = "rtc")),
+                ("gps", cfg!(feature = "gps")),
+            ]),
+        },
+        "crates": crates,
+    })
+}
+
+/// The names of the alternatives built in.
+fn compiled(alternatives: &[(&'static str, bool)]) -> Vec<&'static str> {
+    alternatives
+        .iter()
+        .filter(|(_, built)| *built)
+        .map(|(name, _)| *name)
+        .collect()
+}
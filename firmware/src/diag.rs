@@ -0,0 +1,100 @@
+use crate::telemetry;
+use anyhow::{Context, Result};
+use std::{
+    io::{Read, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    time::{Duration, Instant},
+};
+
+/// Everything sent here is counted and dropped, `iperf -c <device>` works
+/// against it as a plain TCP sink.
+pub const SINK_PORT: u16 = 5001;
+/// Everything sent here comes straight back.
+pub const ECHO_PORT: u16 = 5002;
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+const BUFFER_SIZE: usize = 1460;
+const STACK_SIZE: usize = 6 * 1024;
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Sink,
+    Echo,
+}
+
+/// Starts the link diagnostics listeners; only built into debug firmware.
+/// Connections are served one at a time, which is all a single laptop
+/// measuring the link needs.
+pub fn start() -> Result<()> {
+    listen(SINK_PORT, Mode::Sink)?;
+    listen(ECHO_PORT, Mode::Echo)?;
+    log::info!("diagnostics: tcp sink on {SINK_PORT}, echo on {ECHO_PORT}");
+    Ok(())
+}
+
+fn listen(port: u16, mode: Mode) -> Result<()> {
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))
+        .with_context(|| format!("couldn't bind diagnostics port {port}"))?;
+
+    std::thread::Builder::new()
+        .name(format!("diag-{port}"))
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream
+                    .map_err(anyhow::Error::from)
+                    .and_then(|stream| serve(stream, mode));
+                if let Err(err) = result {
+                    log::warn!("diagnostics on {port}: {err:#}");
+                }
+            }
+        })
+        .context("couldn't spawn diagnostics listener")?;
+    Ok(())
+}
+
+fn serve(mut stream: TcpStream, mode: Mode) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    stream.set_nodelay(true)?;
+    log::info!("diagnostics: {peer} connected");
+
+    let mut buf = [0; BUFFER_SIZE];
+    let start = Instant::now();
+    let (mut total, mut interval, mut since) = (0u64, 0u64, start);
+    loop {
+        let len = stream.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        if let Mode::Echo = mode {
+            stream.write_all(&buf[..len])?;
+        }
+        total += len as u64;
+        interval += len as u64;
+
+        if since.elapsed() >= REPORT_INTERVAL {
+            log::info!(
+                "diagnostics: {peer} {:>5.1}s {}",
+                start.elapsed().as_secs_f32(),
+                rate(interval, since.elapsed())
+            );
+            (interval, since) = (0, Instant::now());
+        }
+    }
+
+    let elapsed = start.elapsed();
+    log::info!(
+        "diagnostics: {peer} done, {total} bytes in {elapsed:.1?}, {}",
+        rate(total, elapsed)
+    );
+    telemetry::set("diag_kbps", kbps(total, elapsed));
+    Ok(())
+}
+
+fn kbps(bytes: u64, elapsed: Duration) -> u64 {
+    (bytes as f64 * 8.0 / 1000.0 / elapsed.as_secs_f64().max(f64::EPSILON)) as u64
+}
+
+fn rate(bytes: u64, elapsed: Duration) -> String {
+    format!("{} kbit/s", kbps(bytes, elapsed))
+}
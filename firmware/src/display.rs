@@ -0,0 +1,170 @@
+//! Status screen: the clock, WiFi network and signal, address and the start
+//! of the last fetched body, redrawn periodically and whenever something
+//! happens on the bus.
+
+use crate::{events, http, locale, net, replay};
+use anyhow::{anyhow, Context, Result};
+use embedded_graphics::{
+    mono_font::{iso_8859_1::FONT_6X10, MonoTextStyle},
+    prelude::*,
+    text::{Baseline, Text},
+};
+use std::{fmt, sync::Mutex, time::Duration};
+use tokio::sync::broadcast::error::TryRecvError;
+
+#[cfg(feature = "epaper")]
+mod epaper;
+#[cfg(not(feature = "epaper"))]
+mod oled;
+#[cfg(feature = "epaper")]
+pub use epaper::{Epaper, Pins};
+#[cfg(not(feature = "epaper"))]
+pub use oled::{Oled, Pins};
+
+/// How often the bus is checked between refreshes.
+const POLL: Duration = Duration::from_millis(100);
+/// A body line is kept to what a few rows can show.
+const FETCHED_CHARS: usize = 80;
+const STACK_SIZE: usize = 4096;
+
+/// First line of the last fetched body.
+static FETCHED: Mutex<String> = Mutex::new(String::new());
+/// Set while the fetched line is one `replay` kept from before, with when
+/// it was fetched if the clock knew.
+static STALE: Mutex<Option<Option<i64>>> = Mutex::new(None);
+/// Shown in the fetched body's place while there is one.
+static PROMPT: Mutex<Option<String>> = Mutex::new(None);
+
+/// Something that can show the status lines.
+pub trait Screen: Send {
+    /// Redraw interval when nothing happens on the bus.
+    fn refresh(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn show(&mut self, lines: &[String]) -> Result<()>;
+}
+
+pub fn set_fetched(body: &str) {
+    let line = body.lines().next().unwrap_or_default();
+    *FETCHED.lock().unwrap() = line.chars().take(FETCHED_CHARS).collect();
+}
+
+/// Marks the fetched line as replayed from flash, fetched at unix time
+/// `fetched`, until a poll gets a fresh one.
+pub fn set_stale(stale: bool, fetched: Option<i64>) {
+    *STALE.lock().unwrap() = stale.then_some(fetched);
+}
+
+/// Keeps what `set_fetched()` shows of a streamed body, setting it when the
+/// body is complete.
+#[derive(Default)]
+pub struct Fetched {
+    line: Vec<u8>,
+}
+
+impl http::Consumer for Fetched {
+    fn chunk(&mut self, chunk: &[u8]) -> Result<()> {
+        // up to four bytes a char, and the rest of the body isn't needed
+        let room = (FETCHED_CHARS * 4).saturating_sub(self.line.len());
+        if !self.line.contains(&b'\n') {
+            self.line.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        set_fetched(&String::from_utf8_lossy(&self.line));
+        Ok(())
+    }
+}
+
+/// Something the user has to act on, like a code to enter elsewhere, or
+/// `None` once it's done with.
+// only the oauth login asks for anything
+#[cfg_attr(not(feature = "oauth"), allow(dead_code))]
+pub fn set_prompt(prompt: Option<String>) {
+    *PROMPT.lock().unwrap() = prompt;
+}
+
+/// Keeps the screen updated on its own thread.
+pub fn start(mut screen: impl Screen + 'static) -> Result<()> {
+    let mut events = events::subscribe();
+
+    std::thread::Builder::new()
+        .name("display".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || loop {
+            if let Err(err) = screen.show(&lines()) {
+                log::warn!("display: {err:#}");
+            }
+
+            let mut waited = Duration::ZERO;
+            while waited < screen.refresh() {
+                std::thread::sleep(POLL);
+                waited += POLL;
+                match events.try_recv() {
+                    Ok(_) | Err(TryRecvError::Lagged(_)) => break,
+                    Err(_) => {}
+                }
+            }
+        })
+        .context("couldn't spawn display")?;
+    Ok(())
+}
+
+fn lines() -> [String; 5] {
+    [
+        locale::now(),
+        net::ssid().unwrap_or_else(|| String::from(locale::PACK.no_wifi)),
+        net::rssi()
+            .map(|rssi| format!("{rssi} dBm"))
+            .unwrap_or_default(),
+        net::ipv4().map(|ip| ip.to_string()).unwrap_or_default(),
+        PROMPT.lock().unwrap().clone().unwrap_or_else(fetched),
+    ]
+}
+
+fn fetched() -> String {
+    let line = FETCHED.lock().unwrap().clone();
+    let Some(fetched) = *STALE.lock().unwrap() else {
+        return line;
+    };
+    if line.is_empty() {
+        return line;
+    }
+    match replay::age(fetched) {
+        Some(age) => format!("{}: {line}", locale::PACK.age(age.as_secs())),
+        None => format!("{}: {line}", locale::PACK.old),
+    }
+}
+
+/// Draws `lines` top to bottom in `FONT_6X10`, the Latin-1 one for the
+/// language packs, wrapping at the target's width; whatever doesn't fit
+/// below is cut off.
+fn render<D>(target: &mut D, lines: &[String], color: D::Color) -> Result<()>
+where
+    D: DrawTarget,
+    D::Error: fmt::Debug,
+{
+    let style = MonoTextStyle::new(&FONT_6X10, color);
+    let glyph = FONT_6X10.character_size;
+    let columns = (target.bounding_box().size.width / glyph.width).max(1) as usize;
+
+    let mut y = 0;
+    for line in lines {
+        let chars: Vec<char> = line.chars().collect();
+        // an empty line still holds its row
+        for row in chars
+            .chunks(columns)
+            .chain(chars.is_empty().then_some(&[][..]))
+        {
+            let row: String = row.iter().collect();
+            Text::with_baseline(&row, Point::new(0, y), style, Baseline::Top)
+                .draw(target)
+                .map_err(|err| anyhow!("{err:?}"))?;
+            y += glyph.height as i32;
+        }
+    }
+    Ok(())
+}
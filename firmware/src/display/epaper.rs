@@ -0,0 +1,123 @@
+use super::Screen;
+use anyhow::{anyhow, Result};
+use embedded_graphics::prelude::*;
+use epd_waveshare::{
+    epd2in13_v2::{Display2in13, Epd2in13},
+    prelude::*,
+};
+use esp_idf_hal::{
+    delay::Delay,
+    gpio::{AnyIOPin, Input, Output, PinDriver},
+    spi::{SpiConfig, SpiDeviceDriver, SpiDriver, SpiDriverConfig, SpiError, SPI3},
+    units::Hertz,
+};
+use std::time::Duration;
+
+const BAUDRATE: Hertz = Hertz(4_000_000);
+/// A refresh takes a few hundred milliseconds of panel current, so only
+/// bus events and the minute update the clock.
+const REFRESH: Duration = Duration::from_secs(60);
+/// Partial refreshes leave ghosting behind, every this many the panel gets
+/// a full one.
+const FULL_REFRESH_EVERY: u32 = 20;
+
+/// Waveshare 2.13" HAT wiring on SPI3, SPI2 belongs to the W5500.
+pub struct Pins {
+    pub sclk: AnyIOPin,
+    pub mosi: AnyIOPin,
+    pub cs: AnyIOPin,
+    pub dc: AnyIOPin,
+    pub rst: AnyIOPin,
+    pub busy: AnyIOPin,
+}
+
+type Spi = SpiDeviceDriver<'static, SpiDriver<'static>>;
+
+/// Waveshare 2.13" V2 panel in landscape. It holds its image without power
+/// and sleeps between updates.
+pub struct Epaper {
+    spi: Spi,
+    epd: Epd2in13<
+        Spi,
+        PinDriver<'static, AnyIOPin, Input>,
+        PinDriver<'static, AnyIOPin, Output>,
+        PinDriver<'static, AnyIOPin, Output>,
+        Delay,
+    >,
+    delay: Delay,
+    frame: Display2in13,
+    shown: Vec<String>,
+    partials: u32,
+}
+
+impl Epaper {
+    pub fn new(spi: SPI3, pins: Pins) -> Result<Self> {
+        let mut spi = SpiDeviceDriver::new_single(
+            spi,
+            pins.sclk,
+            pins.mosi,
+            None::<AnyIOPin>,
+            Some(pins.cs),
+            &SpiDriverConfig::new(),
+            &SpiConfig::new().baudrate(BAUDRATE),
+        )?;
+        let mut delay = Delay::new_default();
+        let epd = Epd2in13::new(
+            &mut spi,
+            PinDriver::input(pins.busy)?,
+            PinDriver::output(pins.dc)?,
+            PinDriver::output(pins.rst)?,
+            &mut delay,
+            None,
+        )
+        .map_err(|err| anyhow!("e-paper didn't initialize: {err:?}"))?;
+        let mut frame = Display2in13::default();
+        frame.set_rotation(DisplayRotation::Rotate90);
+
+        Ok(Self {
+            spi,
+            epd,
+            delay,
+            frame,
+            shown: Vec::new(),
+            // the first update after power on is a full one
+            partials: FULL_REFRESH_EVERY,
+        })
+    }
+
+    fn update(&mut self) -> Result<(), SpiError> {
+        let refresh = if self.partials >= FULL_REFRESH_EVERY {
+            self.partials = 0;
+            RefreshLut::Full
+        } else {
+            self.partials += 1;
+            RefreshLut::Quick
+        };
+        self.epd.wake_up(&mut self.spi, &mut self.delay)?;
+        self.epd
+            .set_refresh(&mut self.spi, &mut self.delay, refresh)?;
+        self.epd
+            .update_and_display_frame(&mut self.spi, self.frame.buffer(), &mut self.delay)?;
+        self.epd.sleep(&mut self.spi, &mut self.delay)
+    }
+}
+
+impl Screen for Epaper {
+    fn refresh(&self) -> Duration {
+        REFRESH
+    }
+
+    fn show(&mut self, lines: &[String]) -> Result<()> {
+        if self.shown == lines {
+            return Ok(());
+        }
+        self.frame
+            .clear(Color::White)
+            .map_err(|err| anyhow!("{err:?}"))?;
+        super::render(&mut self.frame, lines, Color::Black)?;
+        self.update()
+            .map_err(|err| anyhow!("e-paper didn't update: {err:?}"))?;
+        self.shown = lines.to_vec();
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+use super::Screen;
+use crate::bus::{self, Bus};
+use anyhow::{anyhow, Result};
+use embedded_graphics::pixelcolor::BinaryColor;
+use esp_idf_hal::{
+    gpio::AnyIOPin,
+    i2c::{I2cConfig, I2cDriver, I2C0},
+    units::Hertz,
+};
+use ssd1306::{mode::BufferedGraphicsMode, prelude::*, I2CDisplayInterface, Ssd1306};
+
+const BAUDRATE: Hertz = Hertz(400_000);
+
+/// I2C0, the screen's; whatever else is wired onto it goes through here.
+pub static BUS: Bus<I2cDriver<'static>> = Bus::new("i2c0");
+
+/// The usual 0.96" module wiring, on I2C0.
+pub struct Pins {
+    pub sda: AnyIOPin,
+    pub scl: AnyIOPin,
+}
+
+/// 128x64 SSD1306, fast enough to redraw every second.
+pub struct Oled(
+    Ssd1306<
+        I2CInterface<bus::Device<I2cDriver<'static>>>,
+        DisplaySize128x64,
+        BufferedGraphicsMode<DisplaySize128x64>,
+    >,
+);
+
+impl Oled {
+    pub fn new(i2c: I2C0, pins: Pins) -> Result<Self> {
+        BUS.install(I2cDriver::new(
+            i2c,
+            pins.sda,
+            pins.scl,
+            &I2cConfig::new().baudrate(BAUDRATE),
+        )?)?;
+        let mut screen = Ssd1306::new(
+            I2CDisplayInterface::new(BUS.device()),
+            DisplaySize128x64,
+            DisplayRotation::Rotate0,
+        )
+        .into_buffered_graphics_mode();
+        screen
+            .init()
+            .map_err(|err| anyhow!("ssd1306 didn't initialize: {err:?}"))?;
+        Ok(Self(screen))
+    }
+}
+
+impl Screen for Oled {
+    fn show(&mut self, lines: &[String]) -> Result<()> {
+        self.0.clear_buffer();
+        super::render(&mut self.0, lines, BinaryColor::On)?;
+        self.0
+            .flush()
+            .map_err(|err| anyhow!("ssd1306 didn't update: {err:?}"))
+    }
+}
@@ -0,0 +1,549 @@
+use crate::{
+    config::Config,
+    error::{Failure, FirmwareError},
+    metrics::{self, Stage},
+    net, runtime,
+};
+use anyhow::{bail, ensure, Context, Result};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+const DNS_PORT: u16 = 53;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Bounds on record TTLs, so a zero TTL doesn't defeat the cache and a huge
+/// one doesn't pin a stale address for days.
+const MIN_TTL: Duration = Duration::from_secs(5);
+const MAX_TTL: Duration = Duration::from_secs(3600);
+/// TTL used when falling back to getaddrinfo, which doesn't report one.
+const FALLBACK_TTL: Duration = Duration::from_secs(60);
+const MAX_ENTRIES: usize = 32;
+/// Compression pointers followed in one name.
+const MAX_POINTERS: usize = 8;
+/// Answers living shorter than this aren't worth a flash write.
+const STORE_MIN_TTL: Duration = Duration::from_secs(5 * 60);
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+/// What follows the scheme of a url to find its host and port with
+/// `srv()`, as in `https+srv://_api._tcp.example.com/v1`.
+const SRV_SCHEME: &str = "+srv://";
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct Stats {
+    pub hits: u32,
+    pub misses: u32,
+    /// Misses answered from the flash cache rather than the network.
+    pub stored: u32,
+    pub expired: u32,
+    pub overridden: u32,
+    pub entries: usize,
+}
+
+struct Entry {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<String, Entry>,
+    overrides: HashMap<String, Vec<IpAddr>>,
+    stats: Stats,
+}
+
+fn cache() -> &'static Mutex<Cache> {
+    static CACHE: OnceLock<Mutex<Cache>> = OnceLock::new();
+    CACHE.get_or_init(Mutex::default)
+}
+
+/// Loads static entries from the `dns_overrides` config key, written as
+/// `host=ip[,ip];host=ip`. Overrides always win over real lookups, which is
+/// what air-gapped test benches without a DNS server need.
+pub fn configure(config: &Config) -> Result<()> {
+    let mut overrides = HashMap::new();
+    for entry in config.dns_overrides.split(';').map(str::trim) {
+        if entry.is_empty() {
+            continue;
+        }
+        let (host, addrs) = entry
+            .split_once('=')
+            .with_context(|| format!("invalid dns override {entry}"))?;
+        let addrs = addrs
+            .split(',')
+            .map(|addr| addr.trim().parse())
+            .collect::<Result<Vec<IpAddr>, _>>()
+            .with_context(|| format!("invalid dns override address for {host}"))?;
+        overrides.insert(host.trim().to_ascii_lowercase(), addrs);
+    }
+
+    if !overrides.is_empty() {
+        log::info!("dns overrides for {} hosts", overrides.len());
+    }
+    cache().lock().unwrap().overrides = overrides;
+    Ok(())
+}
+
+/// Resolves `host` through the shared cache, querying the network's DNS
+/// server on a miss and keeping the answer for as long as its TTL allows.
+/// IPv6 addresses come first when the device has a routable one, so
+/// Happy Eyeballs clients try them before falling back to IPv4. A name
+/// without addresses is a permanent failure, anything else on the way,
+/// a timeout most often, a retryable one.
+pub async fn resolve(host: &str) -> Result<Vec<IpAddr>, FirmwareError> {
+    lookup_cached(host)
+        .await
+        .map_err(|err| match err.downcast::<FirmwareError>() {
+            Ok(err) => err,
+            Err(err) => FirmwareError::Dns(Failure::retryable(err)),
+        })
+}
+
+async fn lookup_cached(host: &str) -> Result<Vec<IpAddr>> {
+    if let Ok(ip) = host.parse() {
+        return Ok(vec![ip]);
+    }
+    let host = host.to_ascii_lowercase();
+    #[cfg(feature = "faults")]
+    crate::faults::delay_dns().await;
+
+    {
+        let mut cache = cache().lock().unwrap();
+        if let Some(addrs) = cache.overrides.get(&host).cloned() {
+            cache.stats.overridden += 1;
+            return Ok(addrs);
+        }
+        match cache.entries.get(&host) {
+            Some(entry) if entry.expires > Instant::now() => {
+                let addrs = entry.addrs.clone();
+                cache.stats.hits += 1;
+                return Ok(addrs);
+            }
+            Some(_) => {
+                cache.entries.remove(&host);
+                cache.stats.expired += 1;
+            }
+            None => {}
+        }
+        cache.stats.misses += 1;
+    }
+
+    // the flash cache keeps answers across reboots
+    let key = format!("dns:{host}");
+    if let Some((addrs, ttl)) = crate::cache::get(&key)
+        .await
+        .and_then(|(value, ttl)| Some((decode(&value)?, ttl)))
+    {
+        cache().lock().unwrap().stats.stored += 1;
+        remember(host, addrs.clone(), ttl);
+        return Ok(addrs);
+    }
+
+    lookup_fresh(host).await
+}
+
+/// Asks the network for `host`, keeping the answer in both caches.
+async fn lookup_fresh(host: String) -> Result<Vec<IpAddr>> {
+    let key = format!("dns:{host}");
+    let name = host.clone();
+    let start = Instant::now();
+    let (addrs, ttl) = match runtime::run_blocking(move || lookup(&name)).await? {
+        Ok(answer) => answer,
+        Err(err) => {
+            metrics::failed(&host, Stage::Dns);
+            return Err(err);
+        }
+    };
+    metrics::reached(&host, Stage::Dns, start.elapsed());
+    if addrs.is_empty() {
+        let err = anyhow::anyhow!("{host} has no addresses");
+        return Err(FirmwareError::Dns(Failure::permanent(err)).into());
+    }
+    log::debug!("dns {host} -> {addrs:?} for {ttl:?}");
+
+    let ttl = ttl.clamp(MIN_TTL, MAX_TTL);
+    remember(host, addrs.clone(), ttl);
+    if ttl >= STORE_MIN_TTL {
+        if let Err(err) = crate::cache::put(&key, encode(&addrs), ttl).await {
+            log::debug!("dns: not stored: {err:#}");
+        }
+    }
+    Ok(addrs)
+}
+
+/// Adds an answer to the in-memory cache, pushing out the one closest to
+/// expiry when full.
+fn remember(host: String, addrs: Vec<IpAddr>, ttl: Duration) {
+    let mut cache = cache().lock().unwrap();
+    if cache.entries.len() >= MAX_ENTRIES {
+        if let Some(oldest) = cache
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.expires)
+            .map(|(host, _)| host.clone())
+        {
+            cache.entries.remove(&oldest);
+        }
+    }
+    cache.entries.insert(
+        host,
+        Entry {
+            addrs,
+            expires: Instant::now() + ttl,
+        },
+    );
+}
+
+fn encode(addrs: &[IpAddr]) -> Vec<u8> {
+    let addrs: Vec<String> = addrs.iter().map(IpAddr::to_string).collect();
+    addrs.join(",").into_bytes()
+}
+
+fn decode(value: &[u8]) -> Option<Vec<IpAddr>> {
+    std::str::from_utf8(value)
+        .ok()?
+        .split(',')
+        .map(|addr| addr.parse().ok())
+        .collect()
+}
+
+/// Drops every answer looked up so far and asks again for the hosts they
+/// were for, past the flash cache, after a move to another access point
+/// whose resolver may answer differently; SRV answers are asked for again
+/// when next needed.
+pub async fn refresh() {
+    let hosts: Vec<String> = cache()
+        .lock()
+        .unwrap()
+        .entries
+        .drain()
+        .map(|(host, _)| host)
+        .collect();
+    SRV.lock().unwrap().clear();
+    for host in hosts {
+        if let Err(err) = lookup_fresh(host.clone()).await {
+            log::debug!("dns {host} not looked up again: {err:#}");
+        }
+    }
+}
+
+/// `resolve()` paired with a port, in the shape socket APIs take.
+pub async fn resolve_addrs(host: &str, port: u16) -> Result<Vec<SocketAddr>, FirmwareError> {
+    Ok(resolve(host)
+        .await?
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect())
+}
+
+pub fn stats() -> Stats {
+    let cache = cache().lock().unwrap();
+    Stats {
+        entries: cache.entries.len(),
+        ..cache.stats
+    }
+}
+
+/// Where a service is offered, one SRV record.
+#[derive(Clone, Debug)]
+pub struct Srv {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    /// Empty for `.`, the service isn't offered.
+    pub target: String,
+}
+
+/// SRV answers by name, with when they expire.
+static SRV: Mutex<BTreeMap<String, (Vec<Srv>, Instant)>> = Mutex::new(BTreeMap::new());
+
+/// The targets of `name`'s SRV records, `_service._proto.domain`, in the
+/// order to try them: lowest priority first and, within one, shuffled by
+/// weight as RFC 2782 has it. Answers are kept for their TTL, moving the
+/// records moves the device on once it runs out.
+pub async fn srv(name: &str) -> Result<Vec<Srv>> {
+    let name = name.to_ascii_lowercase();
+    let cached = SRV
+        .lock()
+        .unwrap()
+        .get(&name)
+        .filter(|(_, expires)| *expires > Instant::now())
+        .map(|(records, _)| records.clone());
+    let records = match cached {
+        Some(records) => records,
+        None => {
+            let server = net::dns_server().context("no dns server to ask for SRV records")?;
+            let question = name.clone();
+            let (records, ttl) = runtime::run_blocking(move || {
+                let (response, len) = exchange(&question, server, TYPE_SRV)?;
+                parse_srv(&response[..len])
+                    .with_context(|| format!("bad dns answer for {question}"))
+            })
+            .await??;
+            let records: Vec<Srv> = records
+                .into_iter()
+                .filter(|record| !record.target.is_empty())
+                .collect();
+            ensure!(!records.is_empty(), "no {name} service offered");
+            log::debug!("dns srv {name} -> {records:?} for {ttl:?}");
+            let mut srv = SRV.lock().unwrap();
+            if srv.len() >= MAX_ENTRIES {
+                srv.pop_first();
+            }
+            let expires = Instant::now() + ttl.clamp(MIN_TTL, MAX_TTL);
+            srv.insert(name, (records.clone(), expires));
+            records
+        }
+    };
+    Ok(order(records))
+}
+
+fn order(mut records: Vec<Srv>) -> Vec<Srv> {
+    records.sort_by_key(|record| record.priority);
+    let mut ordered = Vec::with_capacity(records.len());
+    while let Some(first) = records.first() {
+        let priority = first.priority;
+        let end = records
+            .iter()
+            .position(|record| record.priority != priority)
+            .unwrap_or(records.len());
+        let mut group: Vec<Srv> = records.drain(..end).collect();
+        // weight 0 up front, where only a draw of 0 picks it
+        group.sort_by_key(|record| record.weight != 0);
+        while !group.is_empty() {
+            let total: u32 = group.iter().map(|record| u32::from(record.weight)).sum();
+            let draw = crate::device::random() % (total + 1);
+            let mut sum = 0;
+            let picked = group
+                .iter()
+                .position(|record| {
+                    sum += u32::from(record.weight);
+                    sum >= draw
+                })
+                .unwrap_or(0);
+            ordered.push(group.remove(picked));
+        }
+    }
+    ordered
+}
+
+/// `url` with an SRV name in place of its host, `https+srv://_api._tcp.
+/// example.com/v1`, as the plain scheme and the first target and port;
+/// other urls as they are.
+pub async fn resolve_srv_url(url: &str) -> Result<String> {
+    let Some((scheme, rest)) = url.split_once(SRV_SCHEME) else {
+        return Ok(url.to_owned());
+    };
+    let (name, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let first = srv(name).await?.remove(0);
+    Ok(format!("{scheme}://{}:{}{path}", first.target, first.port))
+}
+
+fn lookup(host: &str) -> Result<(Vec<IpAddr>, Duration)> {
+    // the DHCP server doesn't know `.local` names, lwIP asks for them
+    // over mDNS
+    let server = net::dns_server().filter(|_| !host.ends_with(".local"));
+    let Some(server) = server else {
+        // no server learned from DHCP yet, let lwIP work it out
+        let mut addrs: Vec<IpAddr> = (host, 0).to_socket_addrs()?.map(|addr| addr.ip()).collect();
+        addrs.sort_by_key(IpAddr::is_ipv4);
+        return Ok((addrs, FALLBACK_TTL));
+    };
+
+    let (mut addrs, mut ttl) = (Vec::new(), MAX_TTL);
+    if net::ipv6().is_some() {
+        match query(host, server, TYPE_AAAA) {
+            Ok((v6, v6_ttl)) if !v6.is_empty() => {
+                addrs = v6;
+                ttl = v6_ttl;
+            }
+            Ok(_) => {}
+            Err(err) => log::debug!("dns AAAA {host}: {err:#}"),
+        }
+    }
+    match query(host, server, TYPE_A) {
+        Ok((v4, v4_ttl)) if !v4.is_empty() => {
+            addrs.extend(v4);
+            ttl = ttl.min(v4_ttl);
+        }
+        // an IPv6-only answer is still an answer
+        Ok(_) => {}
+        Err(err) if !addrs.is_empty() => log::debug!("dns A {host}: {err:#}"),
+        Err(err) => return Err(err),
+    }
+
+    Ok((addrs, ttl))
+}
+
+/// One recursive query for `kind` records against `server`, returning the
+/// addresses and the smallest TTL among them. Blocks, and skips the cache.
+pub fn query(host: &str, server: Ipv4Addr, kind: u16) -> Result<(Vec<IpAddr>, Duration)> {
+    let (response, len) = exchange(host, server, kind)?;
+    parse(&response[..len]).with_context(|| format!("bad dns answer for {host}"))
+}
+
+/// Sends the question and waits for its answer, the message and its
+/// length.
+fn exchange(host: &str, server: Ipv4Addr, kind: u16) -> Result<([u8; 512], usize)> {
+    let id = crate::device::random() as u16;
+
+    let mut request = Vec::with_capacity(host.len() + 18);
+    request.extend_from_slice(&id.to_be_bytes());
+    // recursion desired, one question
+    request.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in host.split('.') {
+        ensure!(
+            !label.is_empty() && label.len() < 64,
+            "invalid host name {host}"
+        );
+        request.push(label.len() as u8);
+        request.extend_from_slice(label.as_bytes());
+    }
+    request.push(0);
+    request.extend_from_slice(&kind.to_be_bytes());
+    request.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    socket.connect((server, DNS_PORT))?;
+    socket.send(&request)?;
+
+    let mut response = [0; 512];
+    let len = loop {
+        let len = socket
+            .recv(&mut response)
+            .with_context(|| format!("no dns answer for {host} from {server}"))?;
+        // ignore stray answers to earlier queries
+        if len >= 12 && response[..2] == id.to_be_bytes() {
+            break len;
+        }
+    };
+    Ok((response, len))
+}
+
+/// One answer record: its type, TTL, and where its data starts, which a
+/// compressed name in it is relative to.
+pub(crate) struct Answer<'a> {
+    kind: u16,
+    ttl: Duration,
+    at: usize,
+    data: &'a [u8],
+}
+
+pub(crate) fn answers(message: &[u8]) -> Result<Vec<Answer<'_>>> {
+    let u16_at = |at: usize| -> Result<u16> {
+        let bytes = message.get(at..at + 2).context("truncated")?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+
+    let rcode = u16_at(2)? & 0x0f;
+    if rcode != 0 {
+        bail!("dns error code {rcode}");
+    }
+    let questions = u16_at(4)?;
+    let count = u16_at(6)?;
+
+    let mut at = 12;
+    for _ in 0..questions {
+        at = skip_name(message, at)? + 4;
+    }
+
+    let mut answers = Vec::with_capacity(usize::from(count));
+    for _ in 0..count {
+        at = skip_name(message, at)?;
+        let kind = u16_at(at)?;
+        let ttl = u32::from(u16_at(at + 4)?) << 16 | u32::from(u16_at(at + 6)?);
+        let len = usize::from(u16_at(at + 8)?);
+        let data = message.get(at + 10..at + 10 + len).context("truncated")?;
+        answers.push(Answer {
+            kind,
+            ttl: Duration::from_secs(ttl.into()),
+            at: at + 10,
+            data,
+        });
+        at += 10 + len;
+    }
+    Ok(answers)
+}
+
+pub(crate) fn parse(message: &[u8]) -> Result<(Vec<IpAddr>, Duration)> {
+    let mut addrs = Vec::new();
+    let mut ttl = MAX_TTL;
+    for answer in answers(message)? {
+        // CNAMEs are followed by the server, only the final records matter
+        let addr = match (answer.kind, answer.data.len()) {
+            (TYPE_A, 4) => IpAddr::from(<[u8; 4]>::try_from(answer.data)?),
+            (TYPE_AAAA, 16) => IpAddr::from(<[u8; 16]>::try_from(answer.data)?),
+            _ => continue,
+        };
+        addrs.push(addr);
+        ttl = ttl.min(answer.ttl);
+    }
+
+    Ok((addrs, ttl))
+}
+
+pub(crate) fn parse_srv(message: &[u8]) -> Result<(Vec<Srv>, Duration)> {
+    let mut records = Vec::new();
+    let mut ttl = MAX_TTL;
+    for answer in answers(message)? {
+        if answer.kind != TYPE_SRV || answer.data.len() < 7 {
+            continue;
+        }
+        let field = |at: usize| u16::from_be_bytes([answer.data[at], answer.data[at + 1]]);
+        records.push(Srv {
+            priority: field(0),
+            weight: field(2),
+            port: field(4),
+            target: read_name(message, answer.at + 6)?,
+        });
+        ttl = ttl.min(answer.ttl);
+    }
+    Ok((records, ttl))
+}
+
+/// The encoded name at `at`, following compression pointers; the root
+/// is empty.
+pub(crate) fn read_name(message: &[u8], mut at: usize) -> Result<String> {
+    let mut labels: Vec<String> = Vec::new();
+    // pointers only point back, a few are plenty for any real answer
+    for _ in 0..MAX_POINTERS {
+        loop {
+            let len = *message.get(at).context("truncated")?;
+            match len {
+                0 => return Ok(labels.join(".")),
+                len if len & 0xc0 == 0xc0 => {
+                    let low = *message.get(at + 1).context("truncated")?;
+                    at = usize::from(len & 0x3f) << 8 | usize::from(low);
+                    break;
+                }
+                len => {
+                    let label = message
+                        .get(at + 1..at + 1 + usize::from(len))
+                        .context("truncated")?;
+                    labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+                    at += 1 + usize::from(len);
+                }
+            }
+        }
+    }
+    bail!("dns name with too many pointers")
+}
+
+/// Skips an encoded name, which may end in a compression pointer.
+fn skip_name(message: &[u8], mut at: usize) -> Result<usize> {
+    loop {
+        let len = *message.get(at).context("truncated")?;
+        match len {
+            0 => return Ok(at + 1),
+            len if len & 0xc0 == 0xc0 => return Ok(at + 2),
+            len => at += 1 + usize::from(len),
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! The errors module boundaries hand out: which subsystem failed and
+//! whether trying again can help, on top of the `anyhow` chain saying why.
+//! Inside a module errors stay `anyhow`, and a `FirmwareError` goes into
+//! one with `?` like any other; `FirmwareError::of()` finds it again
+//! however much context was added on the way up. A boundary whose errors
+//! are all its own, DNS or storage, returns one; the fetch, which passes
+//! on its consumer's errors too, only tags those of the transfer.
+
+use std::fmt;
+
+#[derive(Debug)]
+pub enum FirmwareError {
+    Wifi(Failure),
+    Dns(Failure),
+    Tls(Failure),
+    Http(Failure),
+    Time(Failure),
+    Storage(Failure),
+}
+
+#[derive(Debug)]
+pub struct Failure {
+    /// Whether the same call may succeed later without anything changing
+    /// on the device: a timeout or a server error, not a bad config.
+    pub retryable: bool,
+    pub error: anyhow::Error,
+}
+
+impl Failure {
+    pub fn retryable(error: impl Into<anyhow::Error>) -> Self {
+        Self {
+            retryable: true,
+            error: error.into(),
+        }
+    }
+
+    pub fn permanent(error: impl Into<anyhow::Error>) -> Self {
+        Self {
+            retryable: false,
+            error: error.into(),
+        }
+    }
+
+    /// By an HTTP error status: a server asking to slow down or wait, or
+    /// one that is overloaded, may answer later; a refusal won't change.
+    pub fn status(status: u16, error: impl Into<anyhow::Error>) -> Self {
+        match status {
+            408 | 429 | 500..=599 => Self::retryable(error),
+            _ => Self::permanent(error),
+        }
+    }
+
+    /// By the I/O error down `error`'s chain: an interrupted or timed out
+    /// call can be retried, a missing file or a full partition can't.
+    pub fn io(error: anyhow::Error) -> Self {
+        use std::io::ErrorKind;
+        let kind = error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<std::io::Error>())
+            .map(std::io::Error::kind);
+        match kind {
+            Some(ErrorKind::Interrupted | ErrorKind::TimedOut | ErrorKind::WouldBlock) => {
+                Self::retryable(error)
+            }
+            _ => Self::permanent(error),
+        }
+    }
+}
+
+impl FirmwareError {
+    /// The first `FirmwareError` down `error`'s chain, the one closest to
+    /// the caller.
+    pub fn of(error: &anyhow::Error) -> Option<&Self> {
+        error.chain().find_map(|cause| cause.downcast_ref())
+    }
+
+    /// Whether `error` is worth retrying: as its `FirmwareError` says, and
+    /// yes for one without, as the callers retried everything before.
+    pub fn is_retryable(error: &anyhow::Error) -> bool {
+        Self::of(error).map_or(true, |error| error.failure().retryable)
+    }
+
+    pub fn subsystem(&self) -> &'static str {
+        match self {
+            Self::Wifi(_) => "wifi",
+            Self::Dns(_) => "dns",
+            Self::Tls(_) => "tls",
+            Self::Http(_) => "http",
+            Self::Time(_) => "time",
+            Self::Storage(_) => "storage",
+        }
+    }
+
+    pub fn failure(&self) -> &Failure {
+        match self {
+            Self::Wifi(failure)
+            | Self::Dns(failure)
+            | Self::Tls(failure)
+            | Self::Http(failure)
+            | Self::Time(failure)
+            | Self::Storage(failure) => failure,
+        }
+    }
+}
+
+/// The wrapped error's own message: the subsystem is for policy, the logs
+/// read as they did.
+impl fmt::Display for FirmwareError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&*self.failure().error, f)
+    }
+}
+
+impl std::error::Error for FirmwareError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.failure().error.source()
+    }
+}
@@ -0,0 +1,107 @@
+use anyhow::{ensure, Result};
+use esp_idf_svc::espnow::{EspNow, PeerInfo, BROADCAST};
+use std::sync::OnceLock;
+use tokio::sync::broadcast;
+
+#[cfg(feature = "espnow-gateway")]
+pub mod gateway;
+
+/// Largest payload ESP-NOW carries in one frame.
+pub const MAX_LEN: usize = 250;
+const CAPACITY: usize = 8;
+
+pub type Address = [u8; 6];
+
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub from: Address,
+    pub data: Vec<u8>,
+}
+
+struct Link {
+    espnow: EspNow<'static>,
+    received: broadcast::Sender<Message>,
+}
+
+static LINK: OnceLock<Link> = OnceLock::new();
+
+/// Brings up ESP-NOW next to the station connection. Peers have to share
+/// the AP's channel, so they're added on the current one rather than a
+/// fixed channel. Needs the radio to be started.
+pub fn start() -> Result<()> {
+    let espnow = EspNow::take()?;
+    let (received, _) = broadcast::channel(CAPACITY);
+
+    let sender = received.clone();
+    espnow.register_recv_cb(move |info, data| {
+        // no subscribers is fine, the frame is just dropped
+        let _ = sender.send(Message {
+            from: *info.src_addr,
+            data: data.to_vec(),
+        });
+    })?;
+
+    if LINK.set(Link { espnow, received }).is_err() {
+        anyhow::bail!("espnow already started");
+    }
+    add_peer(BROADCAST)?;
+
+    log::info!("espnow started");
+    Ok(())
+}
+
+fn link() -> Result<&'static Link> {
+    LINK.get()
+        .ok_or_else(|| anyhow::anyhow!("espnow not started"))
+}
+
+pub fn add_peer(addr: Address) -> Result<()> {
+    let espnow = &link()?.espnow;
+    if espnow.peer_exists(addr)? {
+        return Ok(());
+    }
+
+    espnow.add_peer(PeerInfo {
+        peer_addr: addr,
+        // 0 follows the channel the station is on
+        channel: 0,
+        ifidx: esp_idf_sys::wifi_interface_t_WIFI_IF_STA,
+        encrypt: false,
+        ..Default::default()
+    })?;
+    Ok(())
+}
+
+pub fn remove_peer(addr: Address) -> Result<()> {
+    Ok(link()?.espnow.del_peer(addr)?)
+}
+
+pub fn peers() -> Result<Vec<Address>> {
+    let espnow = &link()?.espnow;
+    let mut peers = Vec::new();
+    let mut from_head = true;
+    while let Ok(peer) = espnow.fetch_peer(from_head) {
+        peers.push(peer.peer_addr);
+        from_head = false;
+    }
+    Ok(peers)
+}
+
+pub fn send(addr: Address, data: &[u8]) -> Result<()> {
+    ensure!(
+        data.len() <= MAX_LEN,
+        "espnow payload of {} bytes too large",
+        data.len()
+    );
+    Ok(link()?.espnow.send(addr, data)?)
+}
+
+/// Sends to every board in range.
+pub fn broadcast(data: &[u8]) -> Result<()> {
+    send(BROADCAST, data)
+}
+
+/// Frames received from any peer, in arrival order.
+pub fn subscribe() -> Result<broadcast::Receiver<Message>> {
+    Ok(link()?.received.subscribe())
+}
@@ -0,0 +1,234 @@
+//! One board as the bridge for a swarm of ESP-NOW nodes that have no link
+//! of their own: every frame a node sends is forwarded upstream as a JSON
+//! record with who sent it and when. With `gateway_url` set records are
+//! POSTed there over HTTPS, signed like every other backend request;
+//! without, they're published over MQTT under `mqtt_topic` with the
+//! channel `nodes/<mac>`.
+//!
+//! `gateway_nodes` limits the nodes taken to a list of MACs, any node in
+//! range otherwise. The payload goes up as JSON when it is JSON, as text
+//! when it's UTF-8 and as hex when neither.
+
+use super::{Address, Message};
+use crate::{
+    config::Config, console, device, events, metrics::ESPNOW_FORWARDED, runtime, telemetry,
+};
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast::error::RecvError;
+
+/// A node not heard from for this long isn't counted in `gateway_nodes`
+/// any more.
+const NODE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+struct Node {
+    frames: u64,
+    heard: Instant,
+}
+
+static NODES: Mutex<BTreeMap<Address, Node>> = Mutex::new(BTreeMap::new());
+
+enum Uplink {
+    #[cfg(feature = "http-reqwest")]
+    Https {
+        client: reqwest::Client,
+        url: String,
+    },
+    #[cfg(feature = "mqtt")]
+    Mqtt,
+}
+
+impl Uplink {
+    fn new(config: &Config) -> Result<Self> {
+        if !config.gateway_url.is_empty() {
+            #[cfg(feature = "http-reqwest")]
+            return Ok(Self::Https {
+                client: crate::http::client()?,
+                url: config.gateway_url.clone(),
+            });
+            #[cfg(not(feature = "http-reqwest"))]
+            anyhow::bail!("gateway_url needs the http-reqwest feature");
+        }
+        #[cfg(feature = "mqtt")]
+        return Ok(Self::Mqtt);
+        #[cfg(not(feature = "mqtt"))]
+        anyhow::bail!("the espnow gateway needs gateway_url or the mqtt feature");
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "http-reqwest")]
+            Self::Https { .. } => "https",
+            #[cfg(feature = "mqtt")]
+            Self::Mqtt => "mqtt",
+        }
+    }
+
+    #[cfg_attr(not(feature = "mqtt"), allow(unused_variables))]
+    async fn forward(&self, node: Address, record: &Value) -> Result<()> {
+        match self {
+            #[cfg(feature = "http-reqwest")]
+            Self::Https { client, url } => {
+                let mut request = client.post(url).json(record);
+                for (name, value) in crate::identity::sign_request("POST", url) {
+                    request = request.header(name, value);
+                }
+                crate::ratelimit::acquire("gateway").await?;
+                request.send().await?.error_for_status()?;
+                Ok(())
+            }
+            #[cfg(feature = "mqtt")]
+            Self::Mqtt => {
+                let topic = crate::mqtt::topic(&format!("nodes/{}", hex(&node)));
+                crate::mqtt::session()?.publish(&topic, serde_json::to_vec(record)?)
+            }
+        }
+    }
+}
+
+/// Starts forwarding, after `espnow::start()`.
+pub fn start(config: &Config) -> Result<()> {
+    let allowed = allowed(&config.gateway_nodes)?;
+    let uplink = Uplink::new(config)?;
+    let mut received = super::subscribe()?;
+    console::register(console::Command {
+        name: "gateway",
+        usage: "gateway",
+        summary: "the espnow nodes heard lately and their frame counts",
+        run: |_, _| Ok(nodes()),
+    });
+    log::info!(
+        "espnow gateway forwarding over {}, {}",
+        uplink.name(),
+        match allowed.len() {
+            0 => String::from("any node"),
+            count => format!("{count} node(s)"),
+        }
+    );
+
+    runtime::spawn(async move {
+        loop {
+            let message = match received.recv().await {
+                Ok(message) => message,
+                Err(RecvError::Lagged(missed)) => {
+                    log::warn!("espnow gateway fell behind, {missed} frame(s) dropped");
+                    for _ in 0..missed {
+                        ESPNOW_FORWARDED.inc(&[("uplink", uplink.name()), ("outcome", "dropped")]);
+                    }
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            if !allowed.is_empty() && !allowed.contains(&message.from) {
+                ESPNOW_FORWARDED.inc(&[("uplink", uplink.name()), ("outcome", "refused")]);
+                continue;
+            }
+            let record = record(&message);
+            // nothing would take a record while the link is down
+            events::wait_until(|state| state.net_up).await;
+            let outcome = match uplink.forward(message.from, &record).await {
+                Ok(()) => "forwarded",
+                Err(err) => {
+                    log::warn!("espnow gateway: {}: {err:#}", mac(&message.from));
+                    "failed"
+                }
+            };
+            ESPNOW_FORWARDED.inc(&[("uplink", uplink.name()), ("outcome", outcome)]);
+        }
+    });
+    Ok(())
+}
+
+/// `gateway_nodes`, MACs split by commas.
+fn allowed(value: &str) -> Result<Vec<Address>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|node| !node.is_empty())
+        .map(|node| parse_mac(node).with_context(|| format!("gateway_nodes {node} isn't a MAC")))
+        .collect()
+}
+
+fn parse_mac(text: &str) -> Option<Address> {
+    let mut address = [0; 6];
+    let mut octets = text.split([':', '-']);
+    for byte in &mut address {
+        let octet = octets.next()?;
+        if octet.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(octet, 16).ok()?;
+    }
+    octets.next().is_none().then_some(address)
+}
+
+/// The record for a frame, counted against its node.
+fn record(message: &Message) -> Value {
+    let seq = {
+        let mut nodes = NODES.lock().unwrap();
+        let now = Instant::now();
+        let node = nodes.entry(message.from).or_insert(Node {
+            frames: 0,
+            heard: now,
+        });
+        node.frames += 1;
+        node.heard = now;
+        let seq = node.frames;
+        nodes.retain(|_, node| now.duration_since(node.heard) < NODE_TIMEOUT);
+        telemetry::set("gateway_nodes", nodes.len());
+        seq
+    };
+
+    let mut record = json!({
+        "node": mac(&message.from),
+        "gateway": device::id(),
+        "seq": seq,
+        "len": message.data.len(),
+    });
+    if events::state().time_synced {
+        record["time"] = time::UtcDateTime::now().unix_timestamp().into();
+    }
+    match serde_json::from_slice::<Value>(&message.data) {
+        Ok(data) => record["data"] = data,
+        Err(_) => match std::str::from_utf8(&message.data) {
+            Ok(text) => record["text"] = text.into(),
+            Err(_) => record["hex"] = hex(&message.data).into(),
+        },
+    }
+    record
+}
+
+fn mac(address: &Address) -> String {
+    let octets: Vec<String> = address.iter().map(|octet| format!("{octet:02x}")).collect();
+    octets.join(":")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut text, byte| {
+        let _ = write!(text, "{byte:02x}");
+        text
+    })
+}
+
+/// The nodes in the table, with how many frames each sent and how long
+/// ago the last was.
+fn nodes() -> String {
+    let nodes = NODES.lock().unwrap();
+    let mut reply = format!("{} node(s)", nodes.len());
+    for (address, node) in nodes.iter() {
+        let _ = write!(
+            reply,
+            "\n  {}  {:>6} frame(s)  {}s ago",
+            mac(address),
+            node.frames,
+            node.heard.elapsed().as_secs()
+        );
+    }
+    reply
+}
@@ -0,0 +1,138 @@
+//! Wired links: a W5500 on SPI with the `eth` feature, or the OpenCores MAC
+//! Espressif's QEMU emulates with the `qemu` feature. Both come up through
+//! the same `NetTransport`, so TLS and HTTP run unchanged in the emulator.
+//!
+//! A QEMU run, on Espressif's qemu-system-xtensa (S3) or qemu-system-riscv32
+//! (C3) build:
+//!
+//! ```text
+//! ESP_IDF_SDKCONFIG_DEFAULTS="sdkconfig.defaults;sdkconfig.defaults.qemu" \
+//!     cargo build --no-default-features --features qemu,tokio-rt,sntp,http-reqwest
+//! espflash save-image --chip esp32s3 --merge target/xtensa-esp32s3-espidf/debug/firmware qemu.bin
+//! qemu-system-xtensa -nographic -machine esp32s3 -drive file=qemu.bin,if=mtd,format=raw \
+//!     -nic user,model=open_eth,hostfwd=tcp::8080-:80
+//! ```
+
+use crate::net::{NetTransport, NetworkManager};
+use anyhow::{Context, Result};
+#[cfg(feature = "eth")]
+use esp_idf_hal::{
+    gpio::AnyIOPin,
+    spi::{config::DriverConfig, SpiDriver, SPI2},
+    units::FromValueType,
+};
+#[cfg(feature = "eth")]
+use esp_idf_svc::eth::{SpiEth, SpiEthChipset};
+use esp_idf_svc::{
+    eth::{AsyncEth, EspEth, EthDriver},
+    eventloop::EspSystemEventLoop,
+    netif::EspNetif,
+    timer::EspTaskTimerService,
+};
+
+#[cfg(all(feature = "qemu", feature = "wifi"))]
+compile_error!("QEMU has no radio, build `qemu` with --no-default-features");
+#[cfg(all(feature = "qemu", feature = "eth"))]
+compile_error!("QEMU emulates the OpenCores MAC, not a W5500");
+#[cfg(all(feature = "qemu", esp32c6))]
+compile_error!("Espressif's QEMU doesn't emulate the ESP32-C6");
+#[cfg(all(feature = "qemu", not(esp_idf_eth_use_openeth)))]
+compile_error!("`qemu` needs sdkconfig.defaults.qemu in ESP_IDF_SDKCONFIG_DEFAULTS");
+
+#[cfg(feature = "eth")]
+pub type Eth = AsyncEth<EspEth<'static, SpiEth<SpiDriver<'static>>>>;
+#[cfg(feature = "qemu")]
+pub type OpenEth = AsyncEth<EspEth<'static, esp_idf_svc::eth::OpenEth>>;
+
+/// W5500 wiring. The ENC28J60 isn't among ESP-IDF's built-in SPI MACs,
+/// so it would need its component added to the build.
+#[cfg(feature = "eth")]
+pub struct Pins {
+    pub sclk: AnyIOPin,
+    pub mosi: AnyIOPin,
+    pub miso: AnyIOPin,
+    pub cs: AnyIOPin,
+    pub int: AnyIOPin,
+    pub rst: AnyIOPin,
+}
+
+/// Sets up a W5500 on SPI2 as a second link next to WiFi. lwIP routes over
+/// whichever interface is up, so nothing above the netif changes.
+/// Needs `CONFIG_ETH_SPI_ETHERNET_W5500=y`.
+#[cfg(feature = "eth")]
+pub fn new(
+    spi: SPI2,
+    pins: Pins,
+    sys_loop: EspSystemEventLoop,
+    timer_service: EspTaskTimerService,
+) -> Result<Eth> {
+    let spi = SpiDriver::new(
+        spi,
+        pins.sclk,
+        pins.mosi,
+        Some(pins.miso),
+        &DriverConfig::new(),
+    )?;
+    let driver = EthDriver::new_spi(
+        spi,
+        pins.int,
+        Some(pins.cs),
+        Some(pins.rst),
+        SpiEthChipset::W5500,
+        20.MHz().into(),
+        None,
+        None,
+        sys_loop.clone(),
+    )
+    .context("couldn't start the w5500")?;
+
+    Ok(AsyncEth::wrap(
+        EspEth::wrap(driver)?,
+        sys_loop,
+        timer_service,
+    )?)
+}
+
+/// The emulated MAC, QEMU's user networking hands it an address over DHCP.
+#[cfg(feature = "qemu")]
+pub fn open_eth(
+    mac: esp_idf_hal::mac::MAC,
+    sys_loop: EspSystemEventLoop,
+    timer_service: EspTaskTimerService,
+) -> Result<OpenEth> {
+    let driver =
+        EthDriver::new_openeth(mac, sys_loop.clone()).context("couldn't start open_eth")?;
+
+    Ok(AsyncEth::wrap(
+        EspEth::wrap(driver)?,
+        sys_loop,
+        timer_service,
+    )?)
+}
+
+impl<T: 'static> NetworkManager for AsyncEth<EspEth<'static, T>> {
+    fn name(&self) -> &'static str {
+        "eth"
+    }
+
+    async fn connect(&mut self) -> Result<()> {
+        if !self.is_started()? {
+            self.start().await.context("eth couldn't start")?;
+        }
+        self.wait_connected()
+            .await
+            .context("eth link didn't come up")?;
+        self.wait_netif_up().await.context("eth netif_up failed")?;
+        Ok(())
+    }
+
+    async fn wait_disconnected(&mut self) -> Result<()> {
+        Ok(self.eth_wait_while(|eth| eth.is_connected(), None).await?)
+    }
+}
+
+impl<T: 'static> NetTransport for AsyncEth<EspEth<'static, T>> {
+    fn netif(&self) -> &EspNetif {
+        self.eth().netif()
+    }
+}
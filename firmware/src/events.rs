@@ -0,0 +1,154 @@
+use std::sync::OnceLock;
+use tokio::sync::{broadcast, watch};
+
+const CAPACITY: usize = 16;
+
+// not every event has a subscriber in every build
+#[allow(dead_code)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    NetUp,
+    NetDown,
+    /// A link came up behind a captive portal, see `net::portal`. `NetUp`
+    /// follows once the portal lets traffic through.
+    CaptivePortal,
+    /// The watchdog gave up on WiFi, other links may take over.
+    WifiDead,
+    /// The station associated with another access point of its network,
+    /// by BSSID, see `net::roamed()`.
+    Roamed {
+        from: String,
+        to: String,
+    },
+    TimeSynced,
+    ConfigChanged,
+    FetchStarted,
+    FetchDone {
+        ok: bool,
+    },
+    /// A `poller` url was fetched, `changed` when its body isn't the last
+    /// poll's.
+    Polled {
+        url: String,
+        ok: bool,
+        changed: bool,
+    },
+    OtaPending,
+    /// The update `OtaPending` announced was written, or failed.
+    OtaDone {
+        ok: bool,
+    },
+    LowHeap {
+        free: usize,
+    },
+    LowBattery {
+        millivolts: u16,
+    },
+    /// The user button, see `button`.
+    ShortPress,
+    LongPress,
+    DoublePress,
+    /// The PIR saw someone, or the light sensor went dark or light, see
+    /// `ambient`.
+    Motion,
+    LightChanged {
+        dark: bool,
+    },
+    /// The chip crossed `thermal_limit`, rounded to whole degrees.
+    Overheat {
+        celsius: i16,
+    },
+    /// This boot is a wake from deep sleep.
+    Wake(crate::power::WakeCause),
+    /// Text command received from the backend.
+    Command(String),
+    /// A `quiet_hours` window began or ended, see `quiet`.
+    QuietStarted,
+    QuietEnded,
+    /// A server asked for no requests to `host` for `secs`, see
+    /// `ratelimit::observe()`.
+    Throttled {
+        host: String,
+        secs: u64,
+    },
+    /// No host is throttled any more.
+    Unthrottled,
+    /// Downloads went to the LAN backend or back to the cloud, see
+    /// `failover`.
+    BackendChanged {
+        lan: bool,
+    },
+}
+
+/// Latest system state folded from the published events, so late
+/// subscribers can check readiness without having seen the event itself.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct State {
+    pub net_up: bool,
+    /// A link is up but held by a captive portal, `net_up` is still false.
+    pub captive_portal: bool,
+    pub time_synced: bool,
+    /// Inside a `quiet_hours` window.
+    pub quiet: bool,
+    /// Some server asked for fewer requests, see `Event::Throttled`.
+    pub throttled: bool,
+    /// The battery ran low this boot, see `Event::LowBattery`.
+    pub battery_low: bool,
+}
+
+struct Bus {
+    events: broadcast::Sender<Event>,
+    state: watch::Sender<State>,
+}
+
+fn bus() -> &'static Bus {
+    static BUS: OnceLock<Bus> = OnceLock::new();
+    BUS.get_or_init(|| Bus {
+        events: broadcast::channel(CAPACITY).0,
+        state: watch::Sender::new(State::default()),
+    })
+}
+
+pub fn publish(event: Event) {
+    log::debug!("event: {:?}", event);
+
+    let bus = bus();
+    bus.state.send_if_modified(|state| match event {
+        Event::NetUp => {
+            let portal = std::mem::replace(&mut state.captive_portal, false);
+            !std::mem::replace(&mut state.net_up, true) || portal
+        }
+        Event::NetDown => {
+            let portal = std::mem::replace(&mut state.captive_portal, false);
+            std::mem::replace(&mut state.net_up, false) || portal
+        }
+        // another link may have the network up already
+        Event::CaptivePortal => {
+            !state.net_up && !std::mem::replace(&mut state.captive_portal, true)
+        }
+        Event::TimeSynced => !std::mem::replace(&mut state.time_synced, true),
+        Event::QuietStarted => !std::mem::replace(&mut state.quiet, true),
+        Event::QuietEnded => std::mem::replace(&mut state.quiet, false),
+        Event::Throttled { .. } => !std::mem::replace(&mut state.throttled, true),
+        Event::Unthrottled => std::mem::replace(&mut state.throttled, false),
+        Event::LowBattery { .. } => !std::mem::replace(&mut state.battery_low, true),
+        _ => false,
+    });
+
+    // no subscribers is fine, the state above is still updated
+    let _ = bus.events.send(event);
+}
+
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    bus().events.subscribe()
+}
+
+pub fn state() -> State {
+    *bus().state.borrow()
+}
+
+pub async fn wait_until(mut condition: impl FnMut(&State) -> bool) {
+    let mut state = bus().state.subscribe();
+    // the sender lives in a static, so the channel can't close
+    let _ = state.wait_for(|state| condition(state)).await;
+}
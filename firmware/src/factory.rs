@@ -0,0 +1,210 @@
+//! Provisioning on the factory line, over the serial console's UART. A
+//! frame is `0x02`, a little-endian `u16` payload length, the payload and
+//! the CRC-32 of the length and payload, little-endian; the console takes
+//! the `0x02` as the start of one and hands the UART over until it's
+//! answered. The payload is an op byte and its body:
+//!
+//! - `0x01` info: the id, firmware version and whether the mode is locked,
+//!   as JSON
+//! - `0x02` set `key 0x00 value`: writes a factory key, `device_id`,
+//!   `wifi_ssid`, `wifi_pass` or a `cal_<name>` calibration value
+//! - `0x03` get `key`: reads one back, all but `wifi_pass`
+//! - `0x04` config with a JSON object: applies config fields, the client
+//!   certificate and key among them, and answers with the changed ones
+//! - `0x05` lock: turns the mode off for good
+//!
+//! The answer is framed the same way, a status byte of 0 for success or 1
+//! for an error followed by the result or the error's text. Once locked a
+//! start byte is just an unprintable character to the console again.
+
+use crate::{config::Config, device, security};
+use anyhow::{bail, ensure, Context, Result};
+use esp_idf_hal::{delay::TickType, uart::UartDriver};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use serde_json::json;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+/// Starts a frame, the console never sees it typed.
+pub const START: u8 = 0x02;
+
+const NAMESPACE: &str = "factory";
+const LOCKED_KEY: &str = "locked";
+/// Fits a base64 certificate chain and stays under NVS's string limit.
+const MAX_PAYLOAD: usize = 3968;
+/// Between two bytes of a frame, before it's given up on.
+const BYTE_TIMEOUT: Duration = Duration::from_secs(1);
+
+const INFO: u8 = 0x01;
+const SET: u8 = 0x02;
+const GET: u8 = 0x03;
+const CONFIG: u8 = 0x04;
+const LOCK: u8 = 0x05;
+
+const OK: u8 = 0;
+const ERROR: u8 = 1;
+
+static PARTITION: OnceLock<EspDefaultNvsPartition> = OnceLock::new();
+static LOCKED: AtomicBool = AtomicBool::new(true);
+
+/// Reads whether the line has locked the mode, before anything asks for a
+/// factory key.
+pub fn init(partition: EspDefaultNvsPartition) {
+    match open(partition.clone()).and_then(|nvs| Ok(nvs.get_u8(LOCKED_KEY)?)) {
+        Ok(locked) => {
+            let locked = locked == Some(1);
+            LOCKED.store(locked, Ordering::Relaxed);
+            if !locked {
+                log::warn!("factory provisioning is open on the serial console");
+            }
+        }
+        Err(err) => log::error!("factory provisioning off: {err:#}"),
+    }
+    let _ = PARTITION.set(partition);
+}
+
+/// Whether the console should hand frames over.
+pub fn enabled() -> bool {
+    !LOCKED.load(Ordering::Relaxed)
+}
+
+/// A value the line wrote, `None` when it didn't.
+pub fn get(key: &str) -> Option<String> {
+    let nvs = open(PARTITION.get()?.clone()).ok()?;
+    let len = nvs.str_len(key).ok()??;
+    let mut buf = vec![0; len];
+    nvs.get_str(key, &mut buf).ok()?.map(String::from)
+}
+
+/// Reads the frame after its start byte off `uart` and answers it.
+pub fn serve(uart: &UartDriver) -> Result<()> {
+    let (status, body) = match read_frame(uart).and_then(|payload| handle(&payload)) {
+        Ok(body) => (OK, body),
+        Err(err) => {
+            log::warn!("factory frame failed: {err:#}");
+            (ERROR, format!("{err:#}").into_bytes())
+        }
+    };
+
+    let mut payload = Vec::with_capacity(1 + body.len());
+    payload.push(status);
+    payload.extend_from_slice(&body);
+    payload.truncate(MAX_PAYLOAD);
+    let mut frame = vec![START];
+    frame.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    frame.extend_from_slice(&payload);
+    frame.extend_from_slice(&crc(&frame[1..]).to_le_bytes());
+    uart.write(&frame)?;
+    Ok(())
+}
+
+fn read_frame(uart: &UartDriver) -> Result<Vec<u8>> {
+    let mut header = [0; 2];
+    read_exact(uart, &mut header)?;
+    let len = u16::from_le_bytes(header) as usize;
+    ensure!(
+        len <= MAX_PAYLOAD,
+        "frame of {len} bytes, at most {MAX_PAYLOAD}"
+    );
+
+    let mut frame = vec![0; 2 + len + 4];
+    frame[..2].copy_from_slice(&header);
+    read_exact(uart, &mut frame[2..])?;
+    let checksum = frame.split_off(2 + len);
+    ensure!(
+        crc(&frame).to_le_bytes()[..] == checksum[..],
+        "frame crc mismatch"
+    );
+    Ok(frame.split_off(2))
+}
+
+fn read_exact(uart: &UartDriver, buf: &mut [u8]) -> Result<()> {
+    let timeout = TickType::from(BYTE_TIMEOUT).0;
+    let mut filled = 0;
+    while filled < buf.len() {
+        match uart.read(&mut buf[filled..], timeout)? {
+            0 => bail!("frame timed out after {filled} of {} bytes", buf.len()),
+            read => filled += read,
+        }
+    }
+    Ok(())
+}
+
+fn handle(payload: &[u8]) -> Result<Vec<u8>> {
+    // a frame could have been on its way while the lock was
+    ensure!(enabled(), "factory provisioning is locked");
+    let partition = PARTITION.get().cloned().context("factory nvs not open")?;
+    let (&op, body) = payload.split_first().context("empty frame")?;
+
+    match op {
+        INFO => Ok(serde_json::to_vec(&json!({
+            "id": device::id(),
+            "firmware": device::firmware_version(),
+            "locked": false,
+        }))?),
+        SET => {
+            let separator = body
+                .iter()
+                .position(|&byte| byte == 0)
+                .context("set takes key 0x00 value")?;
+            let key = std::str::from_utf8(&body[..separator]).context("key isn't utf-8")?;
+            let value = std::str::from_utf8(&body[separator + 1..]).context("value isn't utf-8")?;
+            ensure!(valid_key(key), "no factory key {key}");
+            if key == "wifi_pass" {
+                security::ensure_secret_storage().context("not storing wifi_pass")?;
+            }
+            open(partition)?.set_str(key, value)?;
+            log::info!("factory wrote {key}");
+            Ok(Vec::new())
+        }
+        GET => {
+            let key = std::str::from_utf8(body).context("key isn't utf-8")?;
+            ensure!(valid_key(key), "no factory key {key}");
+            ensure!(key != "wifi_pass", "wifi_pass can't be read back");
+            Ok(get(key)
+                .with_context(|| format!("{key} not set"))?
+                .into_bytes())
+        }
+        CONFIG => {
+            let changes: serde_json::Map<String, serde_json::Value> =
+                serde_json::from_slice(body).context("config takes a JSON object")?;
+            let changed = Config::apply(partition, &changes)?;
+            log::info!("factory changed config {}", changed.join(", "));
+            Ok(serde_json::to_vec(&json!({ "changed": changed }))?)
+        }
+        LOCK => {
+            open(partition)?.set_u8(LOCKED_KEY, 1)?;
+            LOCKED.store(true, Ordering::Relaxed);
+            log::warn!("factory provisioning locked");
+            Ok(Vec::new())
+        }
+        op => bail!("unknown op 0x{op:02x}"),
+    }
+}
+
+/// The keys above; NVS caps them at 15 bytes.
+fn valid_key(key: &str) -> bool {
+    match key.strip_prefix("cal_") {
+        Some(name) => {
+            !name.is_empty()
+                && key.len() <= 15
+                && name
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || byte == b'_')
+        }
+        None => matches!(key, "device_id" | "wifi_ssid" | "wifi_pass"),
+    }
+}
+
+fn open(partition: EspDefaultNvsPartition) -> Result<EspNvs<NvsDefault>> {
+    EspNvs::new(partition, NAMESPACE, true).context("couldn't open factory nvs")
+}
+
+fn crc(data: &[u8]) -> u32 {
+    unsafe { esp_idf_sys::esp_rom_crc32_le(0, data.as_ptr(), data.len() as u32) }
+}
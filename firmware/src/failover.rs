@@ -0,0 +1,173 @@
+//! Local-first downloads. With `lan_url` set, a backend on the LAN, found
+//! over mDNS with `mdns://_http._tcp/...` or at a fixed url, is fetched
+//! from first and `download_url` is the cloud fallback: a LAN fetch that
+//! fails is tried again in the cloud, and after `lan_failures` of them in
+//! a row the LAN is left alone.
+//!
+//! From then on the LAN backend is probed, a plain TCP connect, every
+//! `lan_recheck` seconds, and fetches only go back to it after
+//! `RECOVERIES` answers in a row. A backend that answers every other probe
+//! keeps the device in the cloud rather than flapping between the two.
+
+use crate::{
+    config::Config,
+    events::{self, Event},
+    mdns, runtime, telemetry,
+};
+use anyhow::Result;
+use std::{future::Future, sync::Mutex, time::Duration};
+
+/// Probes in a row the LAN backend has to answer to be used again.
+const RECOVERIES: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    Lan,
+    Cloud,
+}
+
+impl Backend {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Lan => "lan",
+            Self::Cloud => "cloud",
+        }
+    }
+}
+
+struct Policy {
+    lan: String,
+    max_failures: u32,
+    backend: Backend,
+    /// LAN fetches failed in a row.
+    failures: u32,
+    /// Probes answered in a row since going to the cloud.
+    recoveries: u32,
+}
+
+static POLICY: Mutex<Option<Policy>> = Mutex::new(None);
+
+/// Prefers the LAN backend from here on and starts probing it whenever
+/// the cloud is in use; nothing when `lan_url` is empty.
+pub fn start(config: &Config) {
+    if config.lan_url.is_empty() {
+        return;
+    }
+    *POLICY.lock().unwrap() = Some(Policy {
+        lan: config.lan_url.clone(),
+        max_failures: config.lan_failures.max(1).into(),
+        backend: Backend::Lan,
+        failures: 0,
+        recoveries: 0,
+    });
+    telemetry::set("backend", Backend::Lan.name());
+    log::info!("failover: lan backend {} first", config.lan_url);
+
+    let lan = config.lan_url.clone();
+    let recheck = Duration::from_secs(config.lan_recheck.max(1).into());
+    runtime::spawn(async move {
+        loop {
+            runtime::sleep(recheck).await;
+            if current() != Backend::Cloud || !events::state().net_up {
+                continue;
+            }
+            let result = probe(&lan).await;
+            if let Err(err) = &result {
+                log::debug!("failover: lan backend still down: {err:#}");
+            }
+            recovered(result.is_ok());
+        }
+    });
+}
+
+/// Where downloads go first.
+pub fn current() -> Backend {
+    POLICY
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map_or(Backend::Cloud, |policy| policy.backend)
+}
+
+/// Fetches with `fetch` from the LAN backend while it's in use and from
+/// `cloud` when it isn't, or when it just failed. A backend answering
+/// 503 or 404 has failed as much as one that's down: `fetch` has to
+/// return an error for any status but 2xx and 304, as `http::fetch()`
+/// and the CoAP fetcher do.
+pub async fn download<F, Fut>(cloud: &str, fetch: F) -> Result<()>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let lan = POLICY
+        .lock()
+        .unwrap()
+        .as_ref()
+        .filter(|policy| policy.backend == Backend::Lan)
+        .map(|policy| policy.lan.clone());
+    let Some(lan) = lan else {
+        return fetch(cloud.to_owned()).await;
+    };
+    match fetch(lan.clone()).await {
+        Ok(()) => {
+            fetched(true);
+            return Ok(());
+        }
+        Err(err) => {
+            log::warn!("failover: {lan} failed, trying the cloud: {err:#}");
+            fetched(false);
+        }
+    }
+    fetch(cloud.to_owned()).await
+}
+
+/// Counts a LAN fetch, going to the cloud after `max_failures` in a row.
+fn fetched(ok: bool) {
+    let mut policy = POLICY.lock().unwrap();
+    let Some(policy) = policy.as_mut() else {
+        return;
+    };
+    if ok {
+        policy.failures = 0;
+        return;
+    }
+    policy.failures += 1;
+    if policy.failures >= policy.max_failures {
+        log::warn!(
+            "failover: lan backend failed {} times, using the cloud",
+            policy.failures
+        );
+        switch(policy, Backend::Cloud);
+    }
+}
+
+/// Counts a probe, back to the LAN after `RECOVERIES` answers in a row.
+fn recovered(ok: bool) {
+    let mut policy = POLICY.lock().unwrap();
+    let Some(policy) = policy.as_mut() else {
+        return;
+    };
+    policy.recoveries = if ok { policy.recoveries + 1 } else { 0 };
+    if policy.recoveries >= RECOVERIES {
+        log::info!("failover: lan backend answers again, using it");
+        switch(policy, Backend::Lan);
+    }
+}
+
+fn switch(policy: &mut Policy, backend: Backend) {
+    policy.backend = backend;
+    policy.failures = 0;
+    policy.recoveries = 0;
+    telemetry::set("backend", backend.name());
+    events::publish(Event::BackendChanged {
+        lan: backend == Backend::Lan,
+    });
+}
+
+/// Opens and drops a connection to where `url` points, mDNS looked up
+/// again in case the backend moved.
+async fn probe(url: &str) -> Result<()> {
+    let url = url.to_owned();
+    let url = runtime::run_blocking(move || mdns::resolve_url(&url)).await??;
+    telemetry::endpoints::reachable(&url).await
+}
@@ -0,0 +1,246 @@
+//! Network faults injected from the console, so the recovery paths run on
+//! real hardware instead of waiting for the network to misbehave. The
+//! connection faults are one-shot: while one is armed, outbound
+//! connections go through a SOCKS5 proxy on loopback, and the next
+//! connection through it takes the fault.
+
+use crate::{
+    console, dns,
+    net::socks::{self, Proxy},
+    runtime,
+};
+use anyhow::{bail, ensure, Context, Result};
+use std::{
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+const PROXY_PORT: u16 = 1081;
+/// Far enough into the server's flight to land inside the certificate.
+const DEFAULT_RESET_AFTER: usize = 100;
+const BUFFER_SIZE: usize = 1460;
+const TLS_HEADER: usize = 5;
+
+static DNS_DELAY_MS: AtomicUsize = AtomicUsize::new(0);
+/// Bytes from the server after which the next connection is reset.
+static RESET_AFTER: AtomicUsize = AtomicUsize::new(0);
+/// Bytes of the next TLS record from the server that get flipped.
+static CORRUPT: AtomicUsize = AtomicUsize::new(0);
+static PROXY: OnceLock<Proxy> = OnceLock::new();
+
+/// Starts the proxy and registers the `fault` command.
+pub fn start() -> Result<()> {
+    if !cfg!(debug_assertions) {
+        log::warn!("fault injection is built into a release image");
+    }
+    let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, PROXY_PORT))
+        .context("couldn't bind the fault proxy")?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    let _ = PROXY.set(Proxy::loopback(PROXY_PORT));
+
+    runtime::spawn(async move {
+        loop {
+            let client = match listener.accept().await {
+                Ok((client, _)) => client,
+                Err(err) => {
+                    log::warn!("fault proxy accept failed: {err}");
+                    runtime::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            let faults = Faults {
+                reset_after: RESET_AFTER.swap(0, Ordering::Relaxed),
+                corrupt: CORRUPT.swap(0, Ordering::Relaxed),
+            };
+            runtime::spawn(async move {
+                if let Err(err) = proxy_connection(client, faults).await {
+                    log::info!("fault proxy: {err:#}");
+                }
+            });
+        }
+    });
+
+    console::register(console::Command {
+        name: "fault",
+        usage: "fault [wifi | dns <ms> | reset [bytes] | corrupt <bytes> | clear]",
+        summary: "inject a network fault, or list the armed ones",
+        run: command,
+    });
+    log::info!("fault proxy on 127.0.0.1:{PROXY_PORT}");
+    Ok(())
+}
+
+/// The loopback proxy while a connection fault is armed.
+pub fn proxy() -> Option<&'static Proxy> {
+    let armed = RESET_AFTER.load(Ordering::Relaxed) > 0 || CORRUPT.load(Ordering::Relaxed) > 0;
+    armed.then(|| PROXY.get()).flatten()
+}
+
+/// Holds up a DNS lookup for the injected delay.
+pub async fn delay_dns() {
+    let millis = DNS_DELAY_MS.load(Ordering::Relaxed);
+    if millis > 0 {
+        log::info!("fault: dns delayed {millis} ms");
+        runtime::sleep(Duration::from_millis(millis as u64)).await;
+    }
+}
+
+fn command(_: &console::Console, args: &[&str]) -> Result<String> {
+    let number = |value: &str| value.parse::<usize>().context("takes a number");
+    match args {
+        [] => Ok(format!(
+            "dns delay {} ms, reset after {} bytes, corrupt {} bytes",
+            DNS_DELAY_MS.load(Ordering::Relaxed),
+            RESET_AFTER.load(Ordering::Relaxed),
+            CORRUPT.load(Ordering::Relaxed),
+        )),
+        #[cfg(feature = "wifi")]
+        ["wifi"] => {
+            esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_wifi_disconnect() })?;
+            Ok(String::from("wifi dropped"))
+        }
+        ["dns", millis] => {
+            DNS_DELAY_MS.store(number(millis)?, Ordering::Relaxed);
+            Ok(format!("dns delayed {millis} ms"))
+        }
+        ["reset", bytes @ ..] => {
+            let bytes = match bytes {
+                [] => DEFAULT_RESET_AFTER,
+                [bytes] => number(bytes)?,
+                _ => bail!("usage: fault reset [bytes]"),
+            };
+            ensure!(bytes > 0, "reset takes at least one byte");
+            RESET_AFTER.store(bytes, Ordering::Relaxed);
+            Ok(format!("next connection resets after {bytes} bytes"))
+        }
+        ["corrupt", bytes] => {
+            let bytes = number(bytes)?;
+            ensure!(bytes > 0, "corrupt takes at least one byte");
+            CORRUPT.store(bytes, Ordering::Relaxed);
+            Ok(format!("next connection gets {bytes} bytes corrupted"))
+        }
+        ["clear"] => {
+            DNS_DELAY_MS.store(0, Ordering::Relaxed);
+            RESET_AFTER.store(0, Ordering::Relaxed);
+            CORRUPT.store(0, Ordering::Relaxed);
+            Ok(String::from("faults cleared"))
+        }
+        _ => bail!("usage: fault [wifi | dns <ms> | reset [bytes] | corrupt <bytes> | clear]"),
+    }
+}
+
+/// What one proxied connection does to the bytes from the server.
+#[derive(Clone, Copy)]
+struct Faults {
+    /// Zero for none.
+    reset_after: usize,
+    /// Zero for none.
+    corrupt: usize,
+}
+
+async fn proxy_connection(mut client: TcpStream, faults: Faults) -> Result<()> {
+    let target = socks::accept(&mut client).await?;
+    let upstream = TcpStream::connect(dns::resolve_addrs(&target.0, target.1).await?.as_slice())
+        .await
+        .with_context(|| format!("couldn't reach {}:{}", target.0, target.1))?;
+    socks::granted(&mut client).await?;
+
+    let (mut client_read, mut client_write) = client.into_split();
+    let (mut upstream_read, mut upstream_write) = upstream.into_split();
+    let outbound = async {
+        tokio::io::copy(&mut client_read, &mut upstream_write).await?;
+        upstream_write.shutdown().await?;
+        anyhow::Ok(())
+    };
+    let inbound = async {
+        let mut records = Records::new(faults.corrupt);
+        let mut buf = vec![0; BUFFER_SIZE];
+        let mut total = 0;
+        loop {
+            let len = upstream_read.read(&mut buf).await?;
+            if len == 0 {
+                client_write.shutdown().await?;
+                return anyhow::Ok(());
+            }
+            let mut chunk = &mut buf[..len];
+            if faults.reset_after > 0 && total + len >= faults.reset_after {
+                chunk = &mut chunk[..faults.reset_after - total];
+                records.corrupt(chunk);
+                client_write.write_all(chunk).await?;
+                bail!(
+                    "fault: {}:{} reset after {} bytes",
+                    target.0,
+                    target.1,
+                    faults.reset_after
+                );
+            }
+            records.corrupt(chunk);
+            client_write.write_all(chunk).await?;
+            total += len;
+        }
+    };
+    let result = tokio::try_join!(outbound, inbound).map(drop);
+    if result.is_err() {
+        // a zero linger turns the close into a RST, which is the fault
+        let client = client_read.reunite(client_write)?;
+        client.set_zero_linger()?;
+    }
+    result
+}
+
+/// Follows the TLS record framing from the server, flipping the first
+/// `left` payload bytes of the first record. The header stays intact so
+/// the client sees a record that fails to decrypt or parse, not garbage.
+struct Records {
+    left: usize,
+    header: [u8; TLS_HEADER],
+    header_len: usize,
+    payload_left: usize,
+}
+
+impl Records {
+    fn new(left: usize) -> Self {
+        Self {
+            left,
+            header: [0; TLS_HEADER],
+            header_len: 0,
+            payload_left: 0,
+        }
+    }
+
+    fn corrupt(&mut self, mut chunk: &mut [u8]) {
+        while self.left > 0 && !chunk.is_empty() {
+            if self.payload_left == 0 {
+                let take = (TLS_HEADER - self.header_len).min(chunk.len());
+                self.header[self.header_len..][..take].copy_from_slice(&chunk[..take]);
+                self.header_len += take;
+                chunk = &mut chunk[take..];
+                if self.header_len == TLS_HEADER {
+                    self.header_len = 0;
+                    self.payload_left = u16::from_be_bytes([self.header[3], self.header[4]]).into();
+                }
+                continue;
+            }
+            let take = self.payload_left.min(chunk.len());
+            let flip = take.min(self.left);
+            for byte in &mut chunk[..flip] {
+                *byte ^= 0xff;
+            }
+            if flip > 0 {
+                log::info!("fault: {flip} bytes of a tls record corrupted");
+            }
+            self.left -= flip;
+            self.payload_left -= take;
+            chunk = &mut chunk[take..];
+        }
+    }
+}
@@ -0,0 +1,162 @@
+//! Feature flags for rolling a risky subsystem out to part of the fleet.
+//! `flags` holds a rule for each flag, `;`-separated `<flag>=<rule>`. A
+//! rule is `on`, `off`, `<n>%` for that share of the fleet, or
+//! `@<cohort>` for the devices whose `cohort` is that. Alternatives are
+//! joined with `|`, so `early_data=@canary|10%` turns early data on for
+//! the canaries and a tenth of the rest. A flag with no rule keeps its
+//! default, the behaviour from before there were flags. The backend stages
+//! a rollout by raising the share in the remote config document and rolls
+//! it back with `off`. Both fields apply live, like the rest of that
+//! document.
+//!
+//! A device's share of a flag is a bucket from 0 to 99. It is hashed from
+//! the device id and the flag's name, so each flag picks its own tenth,
+//! and a device stays in as the share grows. FNV-1a keeps the buckets the
+//! same across toolchains. The state of each flag goes into telemetry as
+//! `flags`, next to `cohort`, so a dashboard can split by flag.
+//!
+//! Flags only gate; a switch that is read once at boot waits for the next
+//! one. That includes the cipher suites, fixed before the first client
+//! config, and the HTTP/3 fetch after the boot download.
+
+use crate::{config::Config, device, telemetry};
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flag {
+    /// Polls as TLS 1.3 early data under the `early_data` prefixes.
+    #[cfg(feature = "early-data")]
+    EarlyData,
+    /// The HTTP/3 fetch next to the boot download.
+    #[cfg(feature = "quic")]
+    Quic,
+    /// `tls_ciphers` and `tls_groups` in place of ring's defaults.
+    TlsSuites,
+}
+
+/// The flags this build has, each with its default.
+const ALL: &[(Flag, bool)] = &[
+    #[cfg(feature = "early-data")]
+    (Flag::EarlyData, true),
+    #[cfg(feature = "quic")]
+    (Flag::Quic, true),
+    (Flag::TlsSuites, true),
+];
+
+impl Flag {
+    pub fn name(self) -> &'static str {
+        match self {
+            #[cfg(feature = "early-data")]
+            Flag::EarlyData => "early_data",
+            #[cfg(feature = "quic")]
+            Flag::Quic => "quic",
+            Flag::TlsSuites => "tls_suites",
+        }
+    }
+
+    fn bit(self) -> u32 {
+        1 << ALL
+            .iter()
+            .position(|(flag, _)| *flag == self)
+            .unwrap_or_default()
+    }
+}
+
+/// The flags that are on, by `Flag::bit()`.
+static ON: AtomicU32 = AtomicU32::new(defaults());
+
+const fn defaults() -> u32 {
+    let mut on = 0;
+    let mut at = 0;
+    while at < ALL.len() {
+        if ALL[at].1 {
+            on |= 1 << at;
+        }
+        at += 1;
+    }
+    on
+}
+
+pub fn on(flag: Flag) -> bool {
+    ON.load(Ordering::Relaxed) & flag.bit() != 0
+}
+
+/// Reads `flags` against this device's id and `cohort`; can be called
+/// again to replace them. A bad rule leaves every flag at its default.
+pub fn configure(config: &Config) -> Result<()> {
+    let result = evaluate(&config.flags, &config.cohort);
+    let on = *result.as_ref().unwrap_or(&defaults());
+    ON.store(on, Ordering::Relaxed);
+
+    let states: Map<String, Value> = ALL
+        .iter()
+        .map(|(flag, _)| (flag.name().to_owned(), Value::Bool(on & flag.bit() != 0)))
+        .collect();
+    telemetry::set("flags", states);
+    telemetry::set("cohort", config.cohort.as_str());
+    result.map(|_| ())
+}
+
+fn evaluate(rules: &str, cohort: &str) -> Result<u32> {
+    let mut on = defaults();
+    for entry in rules
+        .split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let (name, rule) = entry
+            .split_once('=')
+            .with_context(|| format!("flag rule {entry} isn't <flag>=<rule>"))?;
+        let (name, rule) = (name.trim(), rule.trim());
+        let mut matched = false;
+        for alternative in rule.split('|').map(str::trim) {
+            matched |=
+                matches(name, alternative, cohort).with_context(|| format!("flag {name}"))?;
+        }
+        // a flag of another build is for the devices that have it
+        let Some((flag, _)) = ALL.iter().find(|(flag, _)| flag.name() == name) else {
+            log::debug!("no flag {name} in this build");
+            continue;
+        };
+        if matched {
+            on |= flag.bit();
+        } else {
+            on &= !flag.bit();
+        }
+    }
+    Ok(on)
+}
+
+fn matches(name: &str, rule: &str, cohort: &str) -> Result<bool> {
+    if let Some(wanted) = rule.strip_prefix('@') {
+        return Ok(!cohort.is_empty() && wanted == cohort);
+    }
+    Ok(match rule {
+        "on" => true,
+        "off" => false,
+        _ => {
+            let Some(percent) = rule.strip_suffix('%') else {
+                bail!("rule {rule} isn't on, off, <n>% or @<cohort>");
+            };
+            let percent: u32 = percent
+                .trim()
+                .parse()
+                .ok()
+                .filter(|percent| *percent <= 100)
+                .with_context(|| format!("{rule} isn't a share from 0% to 100%"))?;
+            bucket(name) < percent
+        }
+    })
+}
+
+/// Where this device falls for `flag`, 0 to 99.
+fn bucket(flag: &str) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in device::id().bytes().chain([b'/']).chain(flag.bytes()) {
+        hash ^= u32::from(byte);
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash % 100
+}
@@ -0,0 +1,119 @@
+//! LittleFS on the `storage` partition, mounted at `ROOT` during boot. A
+//! blank or corrupt partition is formatted, and a write and read back
+//! proves it usable before anything relies on it. File access goes through
+//! the blocking pool so flash erases don't stall the async tasks, and
+//! fails with `FirmwareError::Storage`.
+
+use crate::{
+    console,
+    error::{Failure, FirmwareError},
+    runtime, telemetry,
+};
+use anyhow::{bail, Context, Result};
+use esp_idf_sys::{esp, littlefs};
+use std::{ffi::CStr, path::PathBuf};
+
+pub const ROOT: &str = "/data";
+
+const BASE_PATH: &CStr = c"/data";
+const LABEL: &CStr = c"storage";
+const PROBE: &str = ".health";
+
+/// Mounts the partition, formatting it on the first boot or when it won't
+/// mount, then checks it.
+pub fn mount() -> Result<()> {
+    let mut conf = littlefs::esp_vfs_littlefs_conf_t {
+        base_path: BASE_PATH.as_ptr(),
+        partition_label: LABEL.as_ptr(),
+        ..Default::default()
+    };
+    conf.set_format_if_mount_failed(1);
+    esp!(unsafe { littlefs::esp_vfs_littlefs_register(&conf) })
+        .context("couldn't mount littlefs")?;
+
+    if let Err(err) = check() {
+        log::warn!("filesystem failed its check, formatting: {err:#}");
+        esp!(unsafe { littlefs::esp_littlefs_format(LABEL.as_ptr()) })
+            .context("couldn't format littlefs")?;
+        check()?;
+    }
+
+    let (total, used) = usage()?;
+    log::info!("littlefs on {ROOT}: {used} of {total} bytes used");
+    telemetry::set("fs_used_pct", used * 100 / total.max(1));
+
+    console::register(console::Command {
+        name: "fs",
+        usage: "fs",
+        summary: "filesystem usage and files",
+        run: |_, _| report(),
+    });
+    Ok(())
+}
+
+/// Total and used bytes.
+pub fn usage() -> Result<(usize, usize)> {
+    let (mut total, mut used) = (0, 0);
+    esp!(unsafe { littlefs::esp_littlefs_info(LABEL.as_ptr(), &mut total, &mut used) })?;
+    Ok((total, used))
+}
+
+/// Writes and reads back a probe file.
+fn check() -> Result<()> {
+    let probe = path(PROBE);
+    let written = crate::device::id().as_bytes();
+    std::fs::write(&probe, written)?;
+    if std::fs::read(&probe)? != written {
+        bail!("probe file read back differently");
+    }
+    Ok(())
+}
+
+fn report() -> Result<String> {
+    let (total, used) = usage()?;
+    let mut report = format!("{used} of {total} bytes used");
+    for entry in std::fs::read_dir(ROOT)? {
+        let entry = entry?;
+        report.push_str(&format!(
+            "\n  {:<32} {}",
+            entry.file_name().to_string_lossy(),
+            entry.metadata()?.len()
+        ));
+    }
+    Ok(report)
+}
+
+/// `name` under the mount point; names are relative to it.
+pub fn path(name: &str) -> PathBuf {
+    PathBuf::from(ROOT).join(name)
+}
+
+pub async fn read(name: &str) -> Result<Vec<u8>, FirmwareError> {
+    let path = path(name);
+    runtime::run_blocking(move || std::fs::read(&path).with_context(|| format!("{path:?}")))
+        .await
+        .and_then(|result| result)
+        .map_err(|err| FirmwareError::Storage(Failure::io(err)))
+}
+
+/// Replaces the file whole: the data goes to a temporary file that is
+/// renamed over the old one, so a power cut leaves either version.
+pub async fn write(name: &str, data: Vec<u8>) -> Result<(), FirmwareError> {
+    let path = path(name);
+    runtime::run_blocking(move || {
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, data)?;
+        std::fs::rename(&temporary, &path).with_context(|| format!("{path:?}"))
+    })
+    .await
+    .and_then(|result| result)
+    .map_err(|err| FirmwareError::Storage(Failure::io(err)))
+}
+
+pub async fn remove(name: &str) -> Result<(), FirmwareError> {
+    let path = path(name);
+    runtime::run_blocking(move || std::fs::remove_file(&path).with_context(|| format!("{path:?}")))
+        .await
+        .and_then(|result| result)
+        .map_err(|err| FirmwareError::Storage(Failure::io(err)))
+}
@@ -0,0 +1,122 @@
+//! Position from the surrounding access points, for deployments without
+//! GPS. Scan results go to a Google-style geolocation API,
+//! `POST {"wifiAccessPoints": [...]}` answered with
+//! `{"location": {"lat", "lng"}, "accuracy"}`, which Combain, HERE and
+//! Unwired Labs' compatible endpoint all speak.
+
+use crate::{config::Config, events, net, runtime, telemetry};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+/// Records kept from a scan, strongest first.
+const MAX_ACCESS_POINTS: usize = 20;
+/// The APIs won't position on fewer.
+const MIN_ACCESS_POINTS: usize = 2;
+/// Suffix owners add to their SSID to opt out of location services.
+const OPT_OUT_SUFFIX: &[u8] = b"_nomap";
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AccessPoint {
+    mac_address: String,
+    signal_strength: i8,
+    channel: u8,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Request {
+    consider_ip: bool,
+    wifi_access_points: Vec<AccessPoint>,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    location: Location,
+    accuracy: f64,
+}
+
+#[derive(Deserialize)]
+struct Location {
+    lat: f64,
+    lng: f64,
+}
+
+pub struct Locator {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl Locator {
+    pub fn new(config: &Config) -> Result<Self> {
+        let mut url = reqwest::Url::parse(&config.geo_api_url)?;
+        if !config.geo_api_key.expose().is_empty() {
+            url.query_pairs_mut()
+                .append_pair("key", config.geo_api_key.expose());
+        }
+        Ok(Self {
+            url: url.into(),
+            client: crate::http::client()?,
+        })
+    }
+
+    /// Scans, asks the API and puts the fix into telemetry as `location`.
+    pub async fn locate(&self) -> Result<()> {
+        events::wait_until(|state| state.net_up).await;
+
+        let access_points = runtime::run_blocking(access_points).await??;
+        if access_points.len() < MIN_ACCESS_POINTS {
+            bail!("only {} access points in range", access_points.len());
+        }
+        let request = Request {
+            consider_ip: false,
+            wifi_access_points: access_points,
+        };
+        let response: Response = self
+            .client
+            .post(&self.url)
+            .json(&request)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        log::info!(
+            "located at {:.5},{:.5} within {:.0}m from {} access points",
+            response.location.lat,
+            response.location.lng,
+            response.accuracy,
+            request.wifi_access_points.len()
+        );
+        telemetry::set(
+            "location",
+            serde_json::json!({
+                "lat": response.location.lat,
+                "lng": response.location.lng,
+                "accuracy_m": response.accuracy,
+            }),
+        );
+        Ok(())
+    }
+}
+
+fn access_points() -> Result<Vec<AccessPoint>> {
+    Ok(net::scan(MAX_ACCESS_POINTS)?
+        .iter()
+        .filter(|record| {
+            let len = record.ssid.iter().position(|byte| *byte == 0);
+            !record.ssid[..len.unwrap_or(record.ssid.len())].ends_with(OPT_OUT_SUFFIX)
+        })
+        .map(|record| AccessPoint {
+            mac_address: record
+                .bssid
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect::<Vec<_>>()
+                .join(":"),
+            signal_strength: record.rssi,
+            channel: record.primary,
+        })
+        .collect())
+}
@@ -0,0 +1,107 @@
+//! NMEA GPS receiver on UART2: a time source for networks without NTP and
+//! a position for telemetry. The first valid fix sets the clock and
+//! publishes `TimeSynced`, so everything gated on the time goes ahead
+//! whichever source is first.
+
+use crate::{
+    events::{self, Event},
+    telemetry,
+};
+use anyhow::{Context, Result};
+use esp_idf_hal::{
+    delay::BLOCK,
+    gpio::AnyIOPin,
+    uart::{config::Config, UartDriver, UART2},
+    units::Hertz,
+};
+
+mod nmea;
+
+use nmea::Sentence;
+
+/// What nearly every module ships configured for.
+const BAUDRATE: Hertz = Hertz(9600);
+/// NMEA caps sentences at 82 characters, anything longer is line noise.
+const MAX_LINE: usize = 128;
+const STACK_SIZE: usize = 4096;
+
+pub struct Pins {
+    pub tx: AnyIOPin,
+    pub rx: AnyIOPin,
+}
+
+/// Reads the receiver on its own thread. Fixes go into telemetry as
+/// `gps` with `lat`, `lng`, `satellites` and `hdop`.
+pub fn start(uart: UART2, pins: Pins) -> Result<()> {
+    let uart = UartDriver::new(
+        uart,
+        pins.tx,
+        pins.rx,
+        None::<AnyIOPin>,
+        None::<AnyIOPin>,
+        &Config::new().baudrate(BAUDRATE),
+    )?;
+
+    std::thread::Builder::new()
+        .name("gps".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            if let Err(err) = run(&uart) {
+                log::error!("gps stopped: {err:#}");
+            }
+        })
+        .context("couldn't spawn gps")?;
+    Ok(())
+}
+
+fn run(uart: &UartDriver) -> Result<()> {
+    let mut line = Vec::with_capacity(MAX_LINE);
+    let mut clock_set = false;
+    let mut buffer = [0; 64];
+
+    loop {
+        let len = uart.read(&mut buffer, BLOCK)?;
+        for &byte in &buffer[..len] {
+            if byte != b'\n' {
+                if line.len() < MAX_LINE {
+                    line.push(byte);
+                }
+                continue;
+            }
+            let sentence = std::str::from_utf8(&line).ok().and_then(nmea::parse);
+            line.clear();
+            match sentence {
+                Some(Sentence::Rmc { time: Some(time) }) if !clock_set => {
+                    clock_set = set_clock(time.assume_utc().unix_timestamp());
+                }
+                Some(Sentence::Gga { fix: Some(fix) }) => telemetry::set(
+                    "gps",
+                    serde_json::json!({
+                        "lat": fix.lat,
+                        "lng": fix.lng,
+                        "satellites": fix.satellites,
+                        "hdop": fix.hdop,
+                    }),
+                ),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn set_clock(unix: i64) -> bool {
+    let tv = esp_idf_sys::timeval {
+        tv_sec: unix as _,
+        tv_usec: 0,
+    };
+    if unsafe { esp_idf_sys::settimeofday(&tv, std::ptr::null()) } != 0 {
+        log::warn!("gps: couldn't set the system time");
+        return false;
+    }
+    log::info!("time from gps: {}", crate::clock::format_time());
+    crate::power::clock_synced();
+    #[cfg(feature = "rtc")]
+    crate::rtc::store();
+    events::publish(Event::TimeSynced);
+    true
+}
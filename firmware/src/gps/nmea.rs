@@ -0,0 +1,91 @@
+//! The two NMEA 0183 sentences a GPS fix needs: RMC for the date and time,
+//! GGA for position and fix quality. Any talker (GP, GN, GL, ...) is taken.
+
+use time::{Date, Month, PrimitiveDateTime, Time};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sentence {
+    /// Recommended minimum data; `time` is only set once the receiver
+    /// reports it valid.
+    Rmc { time: Option<PrimitiveDateTime> },
+    /// Position fix; `fix` is `None` while the receiver has none.
+    Gga { fix: Option<Fix> },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fix {
+    pub lat: f64,
+    pub lng: f64,
+    pub satellites: u8,
+    pub hdop: f32,
+}
+
+/// Parses one line, `$` to checksum; `None` for anything else, including
+/// lines that fail the checksum.
+pub fn parse(line: &str) -> Option<Sentence> {
+    let body = line.trim_end().strip_prefix('$')?;
+    let (body, checksum) = body.split_once('*')?;
+    let expected = u8::from_str_radix(checksum, 16).ok()?;
+    if body.bytes().fold(0, |sum, byte| sum ^ byte) != expected {
+        return None;
+    }
+
+    let mut fields = body.split(',');
+    let kind = fields.next()?.get(2..)?;
+    let fields: Vec<&str> = fields.collect();
+    match kind {
+        "RMC" => Some(Sentence::Rmc {
+            time: rmc_time(&fields),
+        }),
+        "GGA" => Some(Sentence::Gga {
+            fix: gga_fix(&fields),
+        }),
+        _ => None,
+    }
+}
+
+/// `hhmmss.ss,A,lat,N,lng,E,speed,course,ddmmyy,...`
+fn rmc_time(fields: &[&str]) -> Option<PrimitiveDateTime> {
+    if *fields.get(1)? != "A" {
+        return None;
+    }
+    let (clock, date) = (fields.first()?, fields.get(8)?);
+    let time = Time::from_hms(digits(clock, 0)?, digits(clock, 2)?, digits(clock, 4)?).ok()?;
+    let date = Date::from_calendar_date(
+        2000 + i32::from(digits(date, 4)?),
+        Month::try_from(digits(date, 2)?).ok()?,
+        digits(date, 0)?,
+    )
+    .ok()?;
+    Some(PrimitiveDateTime::new(date, time))
+}
+
+/// `hhmmss.ss,lat,N,lng,E,quality,satellites,hdop,...`
+fn gga_fix(fields: &[&str]) -> Option<Fix> {
+    if matches!(fields.get(5), None | Some(&"") | Some(&"0")) {
+        return None;
+    }
+    Some(Fix {
+        lat: coordinate(fields.get(1)?, fields.get(2)?, 2)?,
+        lng: coordinate(fields.get(3)?, fields.get(4)?, 3)?,
+        satellites: fields.get(6)?.parse().ok()?,
+        hdop: fields.get(7)?.parse().ok()?,
+    })
+}
+
+/// `ddmm.mmmm` (or `dddmm.mmmm`) and a hemisphere to signed degrees.
+fn coordinate(value: &str, hemisphere: &str, degree_digits: usize) -> Option<f64> {
+    let degrees: f64 = value.get(..degree_digits)?.parse().ok()?;
+    let minutes: f64 = value.get(degree_digits..)?.parse().ok()?;
+    let degrees = degrees + minutes / 60.0;
+    match hemisphere {
+        "N" | "E" => Some(degrees),
+        "S" | "W" => Some(-degrees),
+        _ => None,
+    }
+}
+
+/// Two decimal digits at `at`.
+fn digits(field: &str, at: usize) -> Option<u8> {
+    field.get(at..at + 2)?.parse().ok()
+}
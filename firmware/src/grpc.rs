@@ -0,0 +1,47 @@
+use crate::{events, net::socks, tls};
+use anyhow::{Context, Result};
+use hyper_util::rt::TokioIo;
+use std::sync::Arc;
+use tokio_rustls::TlsConnector;
+use tonic::transport::{Channel, Endpoint, Uri};
+
+pub mod device;
+
+/// Opens an HTTP/2 channel for gRPC clients. TLS goes through the shared
+/// rustls config with `h2` negotiated over ALPN rather than tonic's own TLS
+/// stack, so certificates are handled the same as everywhere else.
+pub async fn connect(url: &str) -> Result<Channel> {
+    events::wait_until(|state| state.net_up).await;
+
+    let endpoint = Endpoint::from_shared(url.to_owned()).context("invalid grpc url")?;
+    let https = url.starts_with("https://");
+    if https {
+        events::wait_until(|state| state.time_synced).await;
+    }
+
+    let mut config = (*tls::client_config()).clone();
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    let connector = TlsConnector::from(Arc::new(config));
+
+    let channel = endpoint
+        .connect_with_connector(tower::service_fn(move |uri: Uri| {
+            let connector = connector.clone();
+            async move {
+                let host = uri.host().context("grpc url has no host")?.to_owned();
+                let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+                let stream: Box<dyn Io> = if https {
+                    Box::new(socks::connect_tls(&host, port, &connector, "grpc").await?)
+                } else {
+                    Box::new(socks::connect(&host, port).await?)
+                };
+                anyhow::Ok(TokioIo::new(stream))
+            }
+        }))
+        .await?;
+
+    Ok(channel)
+}
+
+trait Io: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin {}
+
+impl<T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin> Io for T {}
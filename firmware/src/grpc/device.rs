@@ -0,0 +1,78 @@
+//! Client for the `device.v1.Device` service in `proto/device.proto`,
+//! written out by hand in the shape tonic-build would generate.
+
+use crate::{
+    device,
+    events::{self, Event},
+    heap, net,
+};
+use anyhow::Result;
+use tonic::{codec::ProstCodec, codegen::http::uri::PathAndQuery, transport::Channel};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Status {
+    #[prost(string, tag = "1")]
+    pub device_id: String,
+    #[prost(string, tag = "2")]
+    pub firmware: String,
+    #[prost(uint64, tag = "3")]
+    pub uptime_s: u64,
+    #[prost(uint32, tag = "4")]
+    pub free_heap: u32,
+    #[prost(sint32, optional, tag = "5")]
+    pub rssi: Option<i32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Ack {
+    #[prost(string, repeated, tag = "1")]
+    pub commands: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct DeviceClient {
+    inner: tonic::client::Grpc<Channel>,
+}
+
+impl DeviceClient {
+    pub fn new(channel: Channel) -> Self {
+        Self {
+            inner: tonic::client::Grpc::new(channel),
+        }
+    }
+
+    pub async fn report_status(&mut self, status: Status) -> Result<Ack, tonic::Status> {
+        self.inner
+            .ready()
+            .await
+            .map_err(|err| tonic::Status::unavailable(format!("grpc channel not ready: {err}")))?;
+        self.inner
+            .unary(
+                tonic::Request::new(status),
+                PathAndQuery::from_static("/device.v1.Device/ReportStatus"),
+                ProstCodec::default(),
+            )
+            .await
+            .map(tonic::Response::into_inner)
+    }
+}
+
+/// Reports the current status and hands any queued commands to the rest of
+/// the firmware.
+pub async fn report(url: &str) -> Result<()> {
+    let mut client = DeviceClient::new(super::connect(url).await?);
+    let ack = client
+        .report_status(Status {
+            device_id: device::id().to_owned(),
+            firmware: device::firmware_version().to_owned(),
+            uptime_s: device::uptime().as_secs(),
+            free_heap: heap::free() as u32,
+            rssi: net::rssi().map(i32::from),
+        })
+        .await?;
+
+    for command in ack.commands {
+        events::publish(Event::Command(command));
+    }
+    Ok(())
+}
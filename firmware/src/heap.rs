@@ -0,0 +1,307 @@
+use crate::{
+    chip::CHIP,
+    console,
+    events::{self, Event},
+    runtime,
+};
+use anyhow::{bail, Result};
+#[cfg(esp32s3)]
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::{
+    ffi::c_void,
+    fmt,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+/// Allocations this big or bigger go to PSRAM first: rustls's record
+/// buffers and hyper's read buffers, where the slower access costs little
+/// next to the radio. Smaller ones are the hot ones and get esp-idf's
+/// malloc, internal RAM unless it's out.
+#[cfg(esp32s3)]
+const EXTERNAL_THRESHOLD: usize = 4096;
+#[cfg(esp32s3)]
+const EXTERNAL_CAPS: u32 = esp_idf_sys::MALLOC_CAP_SPIRAM | esp_idf_sys::MALLOC_CAP_8BIT;
+/// What the heap aligns every block to, larger alignments are asked for.
+#[cfg(esp32s3)]
+const MIN_ALIGN: usize = 4;
+
+const COMMAND: &str = "heap";
+/// Where `heap tls` shakes hands when not given a host.
+const DEFAULT_HOST: &str = "www.google.com";
+/// Free block sizes by power of two, from under 32 bytes up to 64 KiB and
+/// over.
+const BUCKETS: usize = 13;
+
+/// Allocations the policy put in PSRAM.
+static EXTERNAL: AtomicUsize = AtomicUsize::new(0);
+/// Large allocations that didn't fit in PSRAM and went to malloc.
+static FALLBACKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Rust's allocations placed by size on chips with PSRAM, see
+/// `EXTERNAL_THRESHOLD`. C code keeps esp-idf's own placement, which only
+/// moves blocks over `CONFIG_SPIRAM_MALLOC_ALWAYSINTERNAL` out. With
+/// `budgets` it's the allocator under `budget`'s.
+#[cfg(all(esp32s3, not(feature = "budgets")))]
+#[global_allocator]
+static ALLOCATOR: CapsAllocator = CapsAllocator;
+
+#[cfg(esp32s3)]
+pub struct CapsAllocator;
+
+#[cfg(esp32s3)]
+unsafe fn allocate_external(layout: Layout) -> *mut u8 {
+    let ptr = if layout.align() <= MIN_ALIGN {
+        esp_idf_sys::heap_caps_malloc(layout.size(), EXTERNAL_CAPS)
+    } else {
+        esp_idf_sys::heap_caps_aligned_alloc(layout.align(), layout.size(), EXTERNAL_CAPS)
+    };
+    ptr.cast()
+}
+
+#[cfg(esp32s3)]
+unsafe impl GlobalAlloc for CapsAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() < EXTERNAL_THRESHOLD {
+            return System.alloc(layout);
+        }
+        let ptr = allocate_external(layout);
+        if ptr.is_null() {
+            FALLBACKS.fetch_add(1, Ordering::Relaxed);
+            return System.alloc(layout);
+        }
+        EXTERNAL.fetch_add(1, Ordering::Relaxed);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // free() takes heap_caps blocks as well
+        System.dealloc(ptr, layout);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if new_size < EXTERNAL_THRESHOLD || layout.align() > MIN_ALIGN {
+            return System.realloc(ptr, layout, new_size);
+        }
+        // moved when it sits in internal RAM, so a buffer that grows past
+        // the threshold ends up in PSRAM
+        let new = esp_idf_sys::heap_caps_realloc(ptr.cast(), new_size, EXTERNAL_CAPS);
+        if new.is_null() {
+            FALLBACKS.fetch_add(1, Ordering::Relaxed);
+            return System.realloc(ptr, layout, new_size);
+        }
+        if layout.size() < EXTERNAL_THRESHOLD {
+            EXTERNAL.fetch_add(1, Ordering::Relaxed);
+        }
+        new.cast()
+    }
+}
+
+pub fn free() -> usize {
+    unsafe { esp_idf_sys::esp_get_free_heap_size() as usize }
+}
+
+/// Free internal RAM, which DMA, WiFi buffers and task stacks need.
+pub fn free_internal() -> usize {
+    unsafe { esp_idf_sys::heap_caps_get_free_size(esp_idf_sys::MALLOC_CAP_INTERNAL) }
+}
+
+/// The free heap `check()` compares: internal RAM with PSRAM in the heap,
+/// all of it without.
+pub fn available() -> usize {
+    if CHIP.psram {
+        free_internal()
+    } else {
+        free()
+    }
+}
+
+/// Publishes `LowHeap` each time free heap drops below `threshold`. With
+/// PSRAM in the heap the total stays high while internal RAM runs out, so
+/// there it's internal RAM that counts.
+pub fn check(threshold: usize) {
+    static LOW: AtomicBool = AtomicBool::new(false);
+
+    let free = available();
+    let low = free < threshold;
+    if low && !LOW.swap(low, Ordering::Relaxed) {
+        events::publish(Event::LowHeap { free });
+    } else {
+        LOW.store(low, Ordering::Relaxed);
+    }
+}
+
+/// Logs free bytes, the largest free block and the low water mark of
+/// internal RAM and PSRAM, to compare the allocation policy's placement
+/// around `stage`.
+pub fn report(stage: &str) {
+    let region = |caps| {
+        let mut info = esp_idf_sys::multi_heap_info_t::default();
+        unsafe { esp_idf_sys::heap_caps_get_info(&mut info, caps) };
+        format!(
+            "{} free, {} largest, {} lowest",
+            info.total_free_bytes, info.largest_free_block, info.minimum_free_bytes
+        )
+    };
+    log::info!(
+        "heap {stage}: internal {}",
+        region(esp_idf_sys::MALLOC_CAP_INTERNAL)
+    );
+    if CHIP.psram {
+        log::info!(
+            "heap {stage}: psram {}, {} allocations placed there, {} fallbacks",
+            region(esp_idf_sys::MALLOC_CAP_SPIRAM),
+            EXTERNAL.load(Ordering::Relaxed),
+            FALLBACKS.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// Free blocks of one region by size, from walking every block of it.
+#[derive(Default)]
+struct Walk {
+    /// Count and bytes of the free blocks in each bucket.
+    buckets: [(usize, usize); BUCKETS],
+    used: usize,
+    used_bytes: usize,
+    free_bytes: usize,
+    largest: usize,
+}
+
+impl Walk {
+    /// Walks the regions with `caps`. The heap is locked for the walk, so
+    /// the callback only counts into the walk on this stack.
+    fn of(caps: u32) -> Self {
+        let mut walk = Self::default();
+        unsafe {
+            esp_idf_sys::heap_caps_walk(
+                caps,
+                Some(Self::block),
+                (&mut walk as *mut Self).cast::<c_void>(),
+            )
+        };
+        walk
+    }
+
+    unsafe extern "C" fn block(
+        _: esp_idf_sys::walker_heap_into_t,
+        block: esp_idf_sys::walker_block_info_t,
+        walk: *mut c_void,
+    ) -> bool {
+        let walk = &mut *walk.cast::<Self>();
+        let size = block.size as usize;
+        if block.used {
+            walk.used += 1;
+            walk.used_bytes += size;
+        } else {
+            let power = (usize::BITS - 1).saturating_sub(size.leading_zeros()) as usize;
+            let bucket = &mut walk.buckets[power.saturating_sub(4).min(BUCKETS - 1)];
+            bucket.0 += 1;
+            bucket.1 += size;
+            walk.free_bytes += size;
+            walk.largest = walk.largest.max(size);
+        }
+        // on to the next block
+        true
+    }
+
+    /// How far free memory is from one block: 0 when it all is, towards
+    /// 100 the more it's split into blocks too small to use.
+    fn fragmentation(&self) -> usize {
+        match self.free_bytes {
+            0 => 0,
+            free => 100 - self.largest * 100 / free,
+        }
+    }
+}
+
+impl fmt::Display for Walk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} used blocks of {} bytes, {} bytes free, {} largest, fragmentation {}%",
+            self.used,
+            self.used_bytes,
+            self.free_bytes,
+            self.largest,
+            self.fragmentation()
+        )?;
+        for (bucket, (count, bytes)) in self.buckets.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            let floor = 16usize << bucket;
+            if bucket == BUCKETS - 1 {
+                write!(f, "\n  {floor:>6}+      {count:>5} free, {bytes} bytes")?;
+            } else {
+                write!(
+                    f,
+                    "\n  {floor:>6}-{:<6} {count:>5} free, {bytes} bytes",
+                    floor * 2 - 1
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The histogram of internal RAM, and of PSRAM if there is any.
+fn histogram() -> String {
+    let mut dump = format!("internal: {}", Walk::of(esp_idf_sys::MALLOC_CAP_INTERNAL));
+    if CHIP.psram {
+        dump += &format!("\npsram: {}", Walk::of(esp_idf_sys::MALLOC_CAP_SPIRAM));
+    }
+    dump
+}
+
+/// Registers `heap`, the block histogram now, and `heap tls [host]`, one
+/// before and one after a TLS handshake, to the log.
+pub fn start() {
+    console::register(console::Command {
+        name: COMMAND,
+        usage: "heap [tls [host]]",
+        summary: "free block histogram and fragmentation, or around a tls handshake",
+        run: command,
+    });
+
+    runtime::spawn(async {
+        let mut events = events::subscribe();
+        loop {
+            let Ok(Event::Command(command)) = events.recv().await else {
+                continue;
+            };
+            let mut words = command.split_whitespace();
+            if (words.next(), words.next()) != (Some(COMMAND), Some("tls")) {
+                continue;
+            }
+            let host = words.next().unwrap_or(DEFAULT_HOST);
+            if let Err(err) = around_handshake(host).await {
+                log::warn!("heap: handshake with {host} failed: {err:#}");
+            }
+        }
+    });
+}
+
+fn command(_: &console::Console, args: &[&str]) -> Result<String> {
+    match args {
+        [] => return Ok(histogram()),
+        ["tls"] => events::publish(Event::Command(format!("{COMMAND} tls"))),
+        ["tls", host] => events::publish(Event::Command(format!("{COMMAND} tls {host}"))),
+        _ => bail!("usage: heap [tls [host]]"),
+    }
+    Ok(String::from(
+        "handshake requested, the histograms are logged",
+    ))
+}
+
+async fn around_handshake(host: &str) -> Result<()> {
+    if !events::state().time_synced {
+        bail!("no clock to verify the certificate with");
+    }
+    let connector = tokio_rustls::TlsConnector::from(crate::tls::client_config());
+    log::info!("heap before the handshake with {host}:\n{}", histogram());
+    // held open for the second walk, so the session's buffers are in it
+    let stream = crate::net::socks::connect_tls(host, 443, &connector, "heap").await?;
+    log::info!("heap after the handshake with {host}:\n{}", histogram());
+    drop(stream);
+    Ok(())
+}
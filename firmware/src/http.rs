@@ -0,0 +1,437 @@
+use anyhow::Result;
+use std::future::Future;
+
+#[cfg(not(any(feature = "http-reqwest", feature = "http-lite")))]
+compile_error!("the fetch needs an http client, enable `http-reqwest` or `http-lite`");
+
+#[cfg(feature = "http-reqwest")]
+pub mod cookies;
+#[cfg(feature = "early-data")]
+pub mod early;
+#[cfg(all(feature = "http-lite", not(feature = "http-reqwest")))]
+mod lite;
+#[cfg(all(feature = "http-lite", not(feature = "http-reqwest")))]
+pub use lite::{client, fetch};
+
+/// Lines longer than this are logged in pieces.
+const MAX_LOG_LINE: usize = 256;
+/// The response headers a `Head` keeps: what the body is, how long it may
+/// be cached, and the rate limits a server announces. A trailing `*`
+/// matches any rest of the name.
+const CAPTURED: &[&str] = &[
+    "content-type",
+    "content-length",
+    "content-encoding",
+    "cache-control",
+    "etag",
+    "last-modified",
+    "expires",
+    "age",
+    "retry-after",
+    "x-ratelimit-*",
+    "ratelimit-*",
+];
+
+/// The host of `url`, without user info or port.
+pub fn host(url: &str) -> Option<&str> {
+    let (_, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let authority = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    let host = match authority.strip_prefix('[') {
+        Some(v6) => v6.split_once(']')?.0,
+        None => authority.split(':').next()?,
+    };
+    (!host.is_empty()).then_some(host)
+}
+
+/// A client the fetch can run over: reqwest or the lite client, CoAP, or a
+/// canned one in the simulator.
+pub trait HttpFetcher: Send + Sync {
+    /// Hands the body at `url` to `consumer` as it comes in.
+    fn fetch<C: Consumer>(
+        &self,
+        url: &str,
+        consumer: &mut C,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+#[cfg(feature = "http-reqwest")]
+impl HttpFetcher for reqwest::Client {
+    async fn fetch<C: Consumer>(&self, url: &str, consumer: &mut C) -> Result<()> {
+        fetch(self, url, consumer).await
+    }
+}
+
+/// Takes a body a chunk at a time, borrowed from the client's read buffer,
+/// so a large body is never held whole. A chunk boundary can fall anywhere,
+/// inside a line or a UTF-8 sequence too.
+pub trait Consumer: Send {
+    fn chunk(&mut self, chunk: &[u8]) -> Result<()>;
+
+    /// The status and the captured headers, before the first chunk. An HTTP
+    /// client calls this once per successful response, any other status is
+    /// an error instead; a body served from the cache comes with the 304
+    /// that revalidated it.
+    fn head(&mut self, head: &Head) -> Result<()> {
+        let _ = head;
+        Ok(())
+    }
+
+    /// The body is complete; whoever started the fetch calls this once it
+    /// succeeded, never after a failed one.
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Both, in order.
+impl<A: Consumer, B: Consumer> Consumer for (A, B) {
+    fn chunk(&mut self, chunk: &[u8]) -> Result<()> {
+        self.0.chunk(chunk)?;
+        self.1.chunk(chunk)
+    }
+
+    fn head(&mut self, head: &Head) -> Result<()> {
+        self.0.head(head)?;
+        self.1.head(head)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.0.finish()?;
+        self.1.finish()
+    }
+}
+
+impl<C: Consumer + ?Sized> Consumer for Box<C> {
+    fn chunk(&mut self, chunk: &[u8]) -> Result<()> {
+        (**self).chunk(chunk)
+    }
+
+    fn head(&mut self, head: &Head) -> Result<()> {
+        (**self).head(head)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        (**self).finish()
+    }
+}
+
+/// A response's status and those of its headers `CAPTURED` names, with
+/// the names lowercased.
+#[derive(Clone, Debug, Default)]
+pub struct Head {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Head {
+    pub fn new<'a>(status: u16, headers: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        let headers = headers
+            .into_iter()
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim()))
+            .filter(|(name, _)| {
+                CAPTURED
+                    .iter()
+                    .any(|pattern| match pattern.strip_suffix('*') {
+                        Some(prefix) => name.starts_with(prefix),
+                        None => name == pattern,
+                    })
+            })
+            .map(|(name, value)| (name, value.to_owned()))
+            .collect();
+        Self { status, headers }
+    }
+
+    /// The value of header `name`, in any case.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[cfg(feature = "http-reqwest")]
+impl Head {
+    pub fn of(response: &reqwest::Response) -> Self {
+        Self::new(
+            response.status().as_u16(),
+            response
+                .headers()
+                .iter()
+                .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?))),
+        )
+    }
+}
+
+/// `200, content-type: text/plain, etag: "1"`, the way it's logged.
+impl std::fmt::Display for Head {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.status)?;
+        for (name, value) in &self.headers {
+            write!(f, ", {name}: {value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Logs the body a line at a time.
+#[derive(Default)]
+pub struct LogLines {
+    partial: Vec<u8>,
+}
+
+impl LogLines {
+    fn flush(&mut self) {
+        let line = String::from_utf8_lossy(&self.partial);
+        log::info!("{}", line.trim_end_matches('\r'));
+        self.partial.clear();
+    }
+}
+
+impl Consumer for LogLines {
+    fn chunk(&mut self, mut chunk: &[u8]) -> Result<()> {
+        while let Some(end) = chunk.iter().position(|byte| *byte == b'\n') {
+            self.partial.extend_from_slice(&chunk[..end]);
+            self.flush();
+            chunk = &chunk[end + 1..];
+        }
+        self.partial.extend_from_slice(chunk);
+        if self.partial.len() >= MAX_LOG_LINE {
+            self.flush();
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if !self.partial.is_empty() {
+            self.flush();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "http-reqwest")]
+pub fn client() -> Result<reqwest::Client> {
+    Ok(builder()?.build()?)
+}
+
+/// `client()` before it's built, for one that needs more settings.
+#[cfg(feature = "http-reqwest")]
+pub fn builder() -> Result<reqwest::ClientBuilder> {
+    let mut builder = reqwest::Client::builder()
+        .use_preconfigured_tls((*crate::tls::client_config()).clone())
+        .dns_resolver(std::sync::Arc::new(CachedResolver));
+    if let Some(jar) = cookies::jar() {
+        builder = builder.cookie_provider(jar);
+    }
+    if let Some(proxy) = crate::net::socks::proxy() {
+        builder = builder.proxy(reqwest::Proxy::all(proxy.url())?);
+    }
+    // asked per request, the script may pick another proxy by host
+    #[cfg(feature = "wpad")]
+    if crate::net::socks::proxy().is_none() {
+        builder = builder.proxy(reqwest::Proxy::custom(|url| {
+            crate::net::wpad::route(url.host_str()?).map(|proxy| proxy.url())
+        }));
+    }
+    // the simulator leaves its sockets to the host's defaults
+    #[cfg(target_os = "espidf")]
+    {
+        // reqwest has no say over the buffers, those stay lwIP's
+        let tcp = crate::net::sockopt::options();
+        builder = builder.tcp_nodelay(tcp.nodelay);
+        if let Some(keepalive) = tcp.keepalive {
+            builder = builder
+                .tcp_keepalive(keepalive.idle)
+                .tcp_keepalive_interval(keepalive.interval)
+                .tcp_keepalive_retries(keepalive.count);
+        }
+    }
+    Ok(builder)
+}
+
+#[cfg(feature = "http-reqwest")]
+static SHARED: std::sync::Mutex<Option<reqwest::Client>> = std::sync::Mutex::new(None);
+
+/// The fetch's client, kept so the connections it pools, a warm-up's
+/// among them, are there for the next fetch.
+#[cfg(feature = "http-reqwest")]
+pub fn shared() -> Result<reqwest::Client> {
+    let mut shared = SHARED.lock().unwrap();
+    if let Some(client) = shared.as_ref() {
+        return Ok(client.clone());
+    }
+    // the idle connection stays until the server closes it
+    let client = builder()?
+        .pool_idle_timeout(None)
+        .pool_max_idle_per_host(1)
+        .build()?;
+    *shared = Some(client.clone());
+    Ok(client)
+}
+
+/// Drops the shared client, and with it the connections idle in its pool
+/// once the fetches still holding it are done; the next `shared()` starts
+/// over.
+#[cfg(feature = "http-reqwest")]
+pub fn close_idle() {
+    SHARED.lock().unwrap().take();
+}
+
+/// Routes reqwest's lookups through the shared DNS cache.
+#[cfg(feature = "http-reqwest")]
+struct CachedResolver;
+
+#[cfg(feature = "http-reqwest")]
+impl reqwest::dns::Resolve for CachedResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let addrs = crate::dns::resolve_addrs(name.as_str(), 0).await?;
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// How long a fetched body stays around for revalidation.
+#[cfg(feature = "http-reqwest")]
+const CACHE_TTL: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+
+/// A body kept in the cache with the validators to ask the server whether
+/// it changed.
+#[cfg(feature = "http-reqwest")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Cached {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    body: String,
+}
+
+#[cfg(feature = "http-reqwest")]
+pub async fn fetch(
+    client: &reqwest::Client,
+    url: &str,
+    consumer: &mut impl Consumer,
+) -> Result<()> {
+    use crate::error::{Failure, FirmwareError};
+    use reqwest::{header, StatusCode};
+
+    let key = format!("http:{url}");
+    let cached = crate::cache::get(&key)
+        .await
+        .and_then(|(value, _)| serde_json::from_slice::<Cached>(&value).ok());
+    let mut request = client.get(url);
+    if let Some(cached) = &cached {
+        if let Some(etag) = &cached.etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &cached.last_modified {
+            request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    // the simulator has no identity to sign with
+    #[cfg(target_os = "espidf")]
+    for (name, value) in crate::identity::sign_request("GET", url) {
+        request = request.header(name, value);
+    }
+
+    let request = request.build()?;
+    let host = request.url().host_str().unwrap_or_default().to_owned();
+    crate::ratelimit::acquire_for("http", &host).await?;
+    // reqwest doesn't expose the handshake, so the whole request is boosted
+    let boost = crate::power::boost();
+    let response = client.execute(request).await.map_err(failure);
+    drop(boost);
+    let status = response.as_ref().map(reqwest::Response::status);
+    let status = status.as_ref().map_or("error", StatusCode::as_str);
+    crate::metrics::HTTP_CLIENT_REQUESTS.inc(&[("status", status)]);
+    let mut response = response?;
+    // the handshake is hidden in reqwest, an https response is past it
+    #[cfg(target_os = "espidf")]
+    {
+        use crate::startup::{mark, Phase};
+        if url.starts_with("https://") {
+            mark(Phase::TlsHandshake);
+        }
+        mark(Phase::FirstByte);
+    }
+    // only an https answer came over a verified connection
+    if url.starts_with("https://") {
+        if let Some(date) = response
+            .headers()
+            .get(header::DATE)
+            .and_then(|value| value.to_str().ok())
+        {
+            crate::clock::check_skew(url, date);
+            #[cfg(feature = "tofu")]
+            crate::tofu::record_date(date);
+        }
+    }
+    if let Some(addr) = response.remote_addr() {
+        log::info!("{url} connected over {}", crate::net::family(addr.ip()));
+    }
+    let head = Head::of(&response);
+    log::info!("{url} answered {head}");
+    crate::ratelimit::observe(&host, &head);
+    // an error page is no body, and whoever counts failures has to see it
+    let status = response.status();
+    if !status.is_success() && status != StatusCode::NOT_MODIFIED {
+        let err = anyhow::anyhow!("{url} answered {status}");
+        return Err(FirmwareError::Http(Failure::status(status.as_u16(), err)).into());
+    }
+    consumer.head(&head)?;
+    if let (StatusCode::NOT_MODIFIED, Some(cached)) = (response.status(), cached) {
+        log::info!("{url} not modified");
+        return consumer.chunk(cached.body.as_bytes());
+    }
+
+    let validator = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from)
+    };
+    let (etag, last_modified) = (validator(header::ETAG), validator(header::LAST_MODIFIED));
+    // a copy for the cache only while the body still fits it
+    let mut copy = (etag.is_some() || last_modified.is_some()).then(Vec::new);
+    while let Some(chunk) = response.chunk().await.map_err(failure)? {
+        consumer.chunk(&chunk)?;
+        if let Some(body) = &mut copy {
+            if body.len() + chunk.len() <= crate::cache::MAX_VALUE {
+                body.extend_from_slice(&chunk);
+            } else {
+                copy = None;
+            }
+        }
+    }
+
+    if let Some(body) = copy {
+        let cached = serde_json::to_vec(&Cached {
+            etag,
+            last_modified,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        })?;
+        if cached.len() <= crate::cache::MAX_VALUE {
+            if let Err(err) = crate::cache::put(&key, cached, CACHE_TTL).await {
+                log::debug!("{url} not cached: {err:#}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A reqwest error as a `FirmwareError::Http`. Only a request that couldn't
+/// be built or redirects that loop fail the same way every time; the rest
+/// is the network's or the server's.
+#[cfg(feature = "http-reqwest")]
+fn failure(err: reqwest::Error) -> crate::error::FirmwareError {
+    use crate::error::{Failure, FirmwareError};
+    FirmwareError::Http(if err.is_builder() || err.is_redirect() {
+        Failure::permanent(err)
+    } else {
+        Failure::retryable(err)
+    })
+}
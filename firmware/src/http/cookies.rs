@@ -0,0 +1,297 @@
+//! A cookie jar for reqwest, for APIs that pin a session to one backend
+//! behind a load balancer by a cookie. With `cookie_jar` at 1 every client
+//! `http::builder()` makes shares it; the cookies named in
+//! `cookie_persist` are also written to their own NVS namespace, so the
+//! device comes back to the same backend after a restart. They are stored
+//! as the server sent them, not as secrets.
+
+use crate::config::{Config, Store};
+use anyhow::{bail, Context, Result};
+use reqwest::{header::HeaderValue, Url};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+pub const NAMESPACE: &str = "cookies";
+const KEY: &str = "jar";
+/// Sessions need a handful; a server setting more loses its oldest.
+const MAX_COOKIES: usize = 32;
+/// The longest string NVS stores.
+const MAX_STORED: usize = 4000;
+/// Load balancers that rotate the cookie's value on every response would
+/// otherwise have it written to flash with each poll; in between changes
+/// wait, and a restart may come back with a value a little old.
+const SAVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+static JAR: Mutex<Option<Arc<Jar>>> = Mutex::new(None);
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Cookie {
+    name: String,
+    value: String,
+    /// Lowercase, without a leading dot.
+    domain: String,
+    /// Sent to `domain` only, not its subdomains: no `Domain` attribute.
+    host_only: bool,
+    path: String,
+    secure: bool,
+    /// Unix seconds, `None` for a session cookie.
+    expires: Option<u64>,
+}
+
+impl Cookie {
+    /// A `Set-Cookie` value from `url`, `None` for one to ignore.
+    fn parse(header: &str, url: &Url, now: u64) -> Option<Self> {
+        let host = url.host_str()?.to_ascii_lowercase();
+        let mut attributes = header.split(';');
+        let (name, value) = attributes.next()?.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+        let mut cookie = Cookie {
+            name: name.to_owned(),
+            value: value.trim().trim_matches('"').to_owned(),
+            domain: host.clone(),
+            host_only: true,
+            path: default_path(url),
+            secure: false,
+            expires: None,
+        };
+        let mut max_age = None;
+        for attribute in attributes {
+            let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let value = value.trim();
+            match key.trim().to_ascii_lowercase().as_str() {
+                "domain" if !value.is_empty() => {
+                    let domain = value.trim_start_matches('.').to_ascii_lowercase();
+                    // a server only sets cookies for itself and its parents
+                    if !domain_matches(&host, &domain) {
+                        return None;
+                    }
+                    cookie.domain = domain;
+                    cookie.host_only = false;
+                }
+                "path" if value.starts_with('/') => cookie.path = value.to_owned(),
+                "secure" => cookie.secure = true,
+                "max-age" => max_age = value.parse::<i64>().ok(),
+                "expires" => {
+                    cookie.expires = cookie.expires.or(crate::clock::parse_http_date(value))
+                }
+                _ => {}
+            }
+        }
+        // Max-Age wins over Expires, 0 or less removes the cookie
+        if let Some(max_age) = max_age {
+            cookie.expires = Some(match u64::try_from(max_age) {
+                Ok(secs) if secs > 0 => now.saturating_add(secs),
+                _ => 0,
+            });
+        }
+        Some(cookie)
+    }
+
+    fn expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    fn matches(&self, url: &Url, now: u64) -> bool {
+        let Some(host) = url.host_str().map(str::to_ascii_lowercase) else {
+            return false;
+        };
+        let domain = if self.host_only {
+            host == self.domain
+        } else {
+            domain_matches(&host, &self.domain)
+        };
+        domain
+            && path_matches(url.path(), &self.path)
+            && (!self.secure || url.scheme() == "https")
+            && !self.expired(now)
+    }
+
+    fn same(&self, other: &Cookie) -> bool {
+        self.name == other.name && self.domain == other.domain && self.path == other.path
+    }
+}
+
+fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain
+        || host
+            .strip_suffix(domain)
+            .is_some_and(|rest| rest.ends_with('.'))
+}
+
+fn path_matches(path: &str, cookie: &str) -> bool {
+    path == cookie
+        || path
+            .strip_prefix(cookie)
+            .is_some_and(|rest| cookie.ends_with('/') || rest.starts_with('/'))
+}
+
+/// The directory of `url`'s path, what a cookie without `Path` covers.
+fn default_path(url: &Url) -> String {
+    match url.path().rfind('/') {
+        Some(0) | None => String::from("/"),
+        Some(end) => url.path()[..end].to_owned(),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub struct Jar {
+    state: Mutex<State>,
+    /// Names of the cookies kept in `store`.
+    persist: Vec<String>,
+    store: Mutex<Box<dyn Store + Send>>,
+}
+
+struct State {
+    cookies: Vec<Cookie>,
+    /// A persisted cookie changed since the last save.
+    dirty: bool,
+    saved: Option<Instant>,
+}
+
+impl Jar {
+    fn persisted(&self, cookie: &Cookie) -> bool {
+        self.persist
+            .iter()
+            .any(|name| name == "*" || *name == cookie.name)
+    }
+
+    /// Writes the persisted cookies out, or removes the key once there are
+    /// none left.
+    fn save(&self, cookies: &[Cookie]) -> Result<()> {
+        let kept: Vec<&Cookie> = cookies
+            .iter()
+            .filter(|cookie| self.persisted(cookie))
+            .collect();
+        let mut store = self.store.lock().unwrap();
+        if kept.is_empty() {
+            return store.remove(KEY);
+        }
+        let json = serde_json::to_string(&kept)?;
+        if json.len() > MAX_STORED {
+            bail!("{} bytes of cookies, too many to keep", json.len());
+        }
+        store.set_str(KEY, &json)
+    }
+}
+
+impl reqwest::cookie::CookieStore for Jar {
+    fn set_cookies(&self, headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        let now = now();
+        let mut state = self.state.lock().unwrap();
+        let State {
+            cookies,
+            dirty,
+            saved,
+        } = &mut *state;
+        let mut changed = false;
+        for header in headers {
+            let Some(cookie) = header
+                .to_str()
+                .ok()
+                .and_then(|header| Cookie::parse(header, url, now))
+            else {
+                continue;
+            };
+            let old = cookies.iter().position(|old| old.same(&cookie));
+            if let Some(old) = old {
+                let old = cookies.remove(old);
+                changed |= self.persisted(&old) && old.value != cookie.value;
+            }
+            if cookie.expired(now) {
+                changed |= old.is_some() && self.persisted(&cookie);
+                continue;
+            }
+            changed |= old.is_none() && self.persisted(&cookie);
+            cookies.push(cookie);
+            if cookies.len() > MAX_COOKIES {
+                let dropped = cookies.remove(0);
+                changed |= self.persisted(&dropped);
+            }
+        }
+        // a server resending the same affinity cookie on every response
+        // costs no flash writes
+        *dirty |= changed;
+        if *dirty && saved.map_or(true, |saved| saved.elapsed() >= SAVE_INTERVAL) {
+            if let Err(err) = self.save(cookies) {
+                log::warn!("cookies: couldn't keep them: {err:#}");
+            }
+            *dirty = false;
+            *saved = Some(Instant::now());
+        }
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        let now = now();
+        let state = self.state.lock().unwrap();
+        let mut matching: Vec<&Cookie> = state
+            .cookies
+            .iter()
+            .filter(|cookie| cookie.matches(url, now))
+            .collect();
+        if matching.is_empty() {
+            return None;
+        }
+        // more specific paths first, as browsers send them
+        matching.sort_by_key(|cookie| std::cmp::Reverse(cookie.path.len()));
+        let header = matching
+            .iter()
+            .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+            .collect::<Vec<_>>()
+            .join("; ");
+        HeaderValue::from_str(&header).ok()
+    }
+}
+
+/// Sets up the jar from `config`, with what an earlier boot kept in
+/// `store`; none when `cookie_jar` is off.
+pub fn configure(config: &Config, store: impl Store + Send + 'static) -> Result<()> {
+    if config.cookie_jar != 1 {
+        *JAR.lock().unwrap() = None;
+        return Ok(());
+    }
+    let persist: Vec<String> = config
+        .cookie_persist
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect();
+    let mut cookies: Vec<Cookie> = match store.get_str(KEY)? {
+        Some(json) => serde_json::from_str(&json).context("unreadable kept cookies")?,
+        None => Vec::new(),
+    };
+    // the clock may not be set yet, then nothing looks expired
+    let now = now();
+    cookies.retain(|cookie| !cookie.expired(now));
+    if !cookies.is_empty() {
+        log::info!("cookies: {} kept from the last boot", cookies.len());
+    }
+    let jar = Jar {
+        state: Mutex::new(State {
+            cookies,
+            dirty: false,
+            saved: None,
+        }),
+        persist,
+        store: Mutex::new(Box::new(store)),
+    };
+    *JAR.lock().unwrap() = Some(Arc::new(jar));
+    Ok(())
+}
+
+/// The jar clients share, if it's on.
+pub fn jar() -> Option<Arc<Jar>> {
+    JAR.lock().unwrap().clone()
+}
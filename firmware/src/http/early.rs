@@ -0,0 +1,182 @@
+//! Polls sent as TLS 1.3 early data: with a session ticket from the poll
+//! before, the GET goes out with the ClientHello instead of a round trip
+//! later, which on a satellite or congested cellular link is most of the
+//! poll. Early data can be replayed by anyone who saw it, so only the urls
+//! under an `early_data` prefix, `https://host/path;...`, go this way, the
+//! ones whose server treats a repeated GET as harmless.
+//!
+//! The first poll of a host is a full handshake that brings the ticket; a
+//! server that rejects the early data gets the request again once the
+//! handshake is done. How each went is counted in `tls_early_data_total`.
+//! These polls are plain HTTP/1.0 with nothing cached, reqwest can't send
+//! early data.
+
+use super::{Consumer, Head};
+use crate::{
+    config::Config,
+    error::{Failure, FirmwareError},
+    identity,
+    metrics::{self, Stage},
+    net::{counted::Counted, socks},
+    tls,
+};
+use anyhow::{bail, ensure, Context, Result};
+use rustls::{client::Resumption, pki_types::ServerName, ClientConfig};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex, RwLock},
+    time::Instant,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_rustls::TlsConnector;
+
+/// A response head longer than this is refused.
+const MAX_HEAD: usize = 4096;
+const READ_BUFFER: usize = 1024;
+/// Tickets kept, one or two per polled host.
+const SESSIONS: usize = 8;
+
+static PREFIXES: RwLock<Vec<String>> = RwLock::new(Vec::new());
+/// The early data configs by host, kept for the tickets in them.
+static CONFIGS: Mutex<BTreeMap<String, Arc<ClientConfig>>> = Mutex::new(BTreeMap::new());
+
+/// Reads `early_data`; can be called again to replace it.
+pub fn configure(config: &Config) -> Result<()> {
+    let mut prefixes = Vec::new();
+    for prefix in config.early_data.split(';').map(str::trim) {
+        if prefix.is_empty() {
+            continue;
+        }
+        ensure!(
+            prefix.starts_with("https://"),
+            "early_data prefix {prefix} isn't https, only TLS has early data"
+        );
+        prefixes.push(prefix.to_owned());
+    }
+    *PREFIXES.write().unwrap() = prefixes;
+    Ok(())
+}
+
+/// Whether a poll of `url` may go out as early data.
+pub fn allowed(url: &str) -> bool {
+    PREFIXES
+        .read()
+        .unwrap()
+        .iter()
+        .any(|prefix| url.starts_with(prefix.as_str()))
+}
+
+/// `tls::client_config_for(host)` with early data on, and a session store
+/// of its own: a ticket reqwest got for other ALPN protocols couldn't carry
+/// early data of ours.
+fn client_config(host: &str) -> Arc<ClientConfig> {
+    CONFIGS
+        .lock()
+        .unwrap()
+        .entry(host.to_owned())
+        .or_insert_with(|| {
+            let mut config = (*tls::client_config_for(host)).clone();
+            config.resumption = Resumption::in_memory_sessions(SESSIONS);
+            config.enable_early_data = true;
+            Arc::new(config)
+        })
+        .clone()
+}
+
+/// GETs `url` for `consumer`, in early data when there's a ticket for it.
+pub async fn fetch(url: &str, consumer: &mut impl Consumer) -> Result<()> {
+    let rest = url
+        .strip_prefix("https://")
+        .context("early data is https only")?;
+    let (authority, path) = rest.find('/').map_or((rest, "/"), |at| rest.split_at(at));
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().context("invalid url port")?),
+        None => (authority, 443),
+    };
+    crate::ratelimit::acquire_for("http", host).await?;
+
+    let mut request = format!("GET {path} HTTP/1.0\r\nHost: {host}\r\nConnection: close\r\n");
+    for (name, value) in identity::sign_request("GET", url) {
+        let _ = write!(request, "{name}: {value}\r\n");
+    }
+    request.push_str("\r\n");
+
+    let tcp = Counted::new(socks::connect(host, port).await?, host);
+    let connector = TlsConnector::from(client_config(host)).early_data(true);
+    let start = Instant::now();
+    let mut stream = {
+        let _boost = crate::power::boost();
+        // with a ticket this is back before the server answered, the
+        // request below is written as early data and the flush finishes
+        // the handshake
+        let mut stream = connector
+            .connect(ServerName::try_from(host.to_owned())?, tcp)
+            .await
+            .map_err(|err| {
+                metrics::failed(host, Stage::Tls("early"));
+                tls::failure(err)
+            })?;
+        let early = stream.get_ref().1.is_handshaking();
+        stream.write_all(request.as_bytes()).await?;
+        stream.flush().await.map_err(tls::failure)?;
+        let outcome = match (early, stream.get_ref().1.is_early_data_accepted()) {
+            (false, _) => "full",
+            (true, true) => "accepted",
+            (true, false) => "rejected",
+        };
+        log::debug!("{host}: early data {outcome}");
+        metrics::TLS_EARLY_DATA.inc(&[("host", host), ("outcome", outcome)]);
+        stream
+    };
+    metrics::reached(host, Stage::Tls("early"), start.elapsed());
+    tls::suites::observe("early", host, stream.get_ref().1);
+
+    // the head is collected, the body goes out as it's read past it
+    let mut buffer = [0; READ_BUFFER];
+    let mut head = Vec::new();
+    loop {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            bail!("response has no header terminator");
+        }
+        head.extend_from_slice(&buffer[..read]);
+        if let Some(split) = head.windows(4).position(|window| window == b"\r\n\r\n") {
+            let text = String::from_utf8_lossy(&head[..split]);
+            let status = text.lines().next().unwrap_or_default();
+            let code = status.split_whitespace().nth(1).unwrap_or("malformed");
+            metrics::HTTP_CLIENT_REQUESTS.inc(&[("status", code)]);
+            let fields = || text.lines().skip(1).filter_map(|line| line.split_once(':'));
+            let captured = Head::new(code.parse().unwrap_or(0), fields());
+            crate::ratelimit::observe(host, &captured);
+            if code != "200" {
+                let err = anyhow::anyhow!("unexpected response: {status}");
+                let code = code.parse().unwrap_or(0);
+                return Err(FirmwareError::Http(Failure::status(code, err)).into());
+            }
+            if let Some((_, date)) =
+                fields().find(|(name, _)| name.trim().eq_ignore_ascii_case("date"))
+            {
+                crate::clock::check_skew(url, date.trim());
+            }
+            log::info!("{url} answered {captured}");
+            consumer.head(&captured)?;
+            consumer.chunk(&head[split + 4..])?;
+            break;
+        }
+        if head.len() > MAX_HEAD {
+            bail!("response head over {MAX_HEAD} bytes");
+        }
+    }
+    drop(head);
+
+    loop {
+        match stream.read(&mut buffer).await {
+            Ok(0) => return Ok(()),
+            Ok(read) => consumer.chunk(&buffer[..read])?,
+            // plenty of servers close without a close_notify
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
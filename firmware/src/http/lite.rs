@@ -0,0 +1,220 @@
+use super::{Consumer, Head};
+use crate::{
+    dns,
+    error::{Failure, FirmwareError},
+    identity,
+    metrics::{self, Stage},
+    net,
+    startup::{self, Phase},
+    tls,
+};
+use anyhow::{bail, Context, Result};
+use async_io::Async;
+use futures_lite::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use rustls::pki_types::ServerName;
+use std::{
+    fmt::Write,
+    net::{SocketAddr, TcpStream},
+    time::Instant,
+};
+
+/// A response head longer than this is refused.
+const MAX_HEAD: usize = 4096;
+const READ_BUFFER: usize = 1024;
+
+/// Bare HTTP/1.0 GET client for builds without reqwest, covering just what
+/// `fetch()` needs.
+pub struct Client;
+
+pub fn client() -> Result<Client> {
+    Ok(Client)
+}
+
+pub async fn fetch(client: &Client, url: &str, consumer: &mut impl Consumer) -> Result<()> {
+    client.get(url, consumer).await
+}
+
+impl super::HttpFetcher for Client {
+    async fn fetch<C: Consumer>(&self, url: &str, consumer: &mut C) -> Result<()> {
+        fetch(self, url, consumer).await
+    }
+}
+
+impl Client {
+    pub async fn get(&self, url: &str, consumer: &mut impl Consumer) -> Result<()> {
+        let signature = identity::sign_request("GET", url);
+        let url = Url::parse(url)?;
+        crate::ratelimit::acquire_for("http", url.host).await?;
+        let addrs = dns::resolve_addrs(url.host, url.port)
+            .await
+            .with_context(|| format!("couldn't resolve {}", url.host))?;
+
+        if url.tls {
+            let server_name = ServerName::try_from(url.host.to_owned())?;
+            let connector = futures_rustls::TlsConnector::from(tls::client_config_for(url.host));
+            let _boost = crate::power::boost();
+            let (addr, stream) = net::eyeballs::race(addrs, |addr| {
+                let (server_name, connector) = (server_name.clone(), connector.clone());
+                async move {
+                    let stream = dial(url.host, addr).await?;
+                    let start = Instant::now();
+                    let stream = connector
+                        .connect(server_name, stream)
+                        .await
+                        .map_err(|err| {
+                            metrics::failed(url.host, Stage::Tls("http"));
+                            tls::failure(err)
+                        })?;
+                    metrics::reached(url.host, Stage::Tls("http"), start.elapsed());
+                    tls::suites::observe("http", url.host, stream.get_ref().1);
+                    startup::mark(Phase::TlsHandshake);
+                    anyhow::Ok(stream)
+                }
+            })
+            .await?;
+            log::info!("{} connected over {}", url.host, net::family(addr.ip()));
+            request(stream, &url, &signature, consumer).await
+        } else {
+            let (addr, stream) = net::eyeballs::race(addrs, |addr| dial(url.host, addr)).await?;
+            log::info!("{} connected over {}", url.host, net::family(addr.ip()));
+            request(stream, &url, &signature, consumer).await
+        }
+    }
+}
+
+async fn dial(host: &str, addr: SocketAddr) -> Result<Async<TcpStream>> {
+    let start = Instant::now();
+    let stream = Async::<TcpStream>::connect(addr)
+        .await
+        .inspect_err(|_| metrics::failed(host, Stage::Connect))?;
+    metrics::reached(host, Stage::Connect, start.elapsed());
+    net::sockopt::apply(stream.get_ref());
+    Ok(stream)
+}
+
+async fn request(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    url: &Url<'_>,
+    headers: &[(&str, String)],
+    consumer: &mut impl Consumer,
+) -> Result<()> {
+    let mut request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n",
+        url.path, url.host
+    );
+    for (name, value) in headers {
+        let _ = write!(request, "{name}: {value}\r\n");
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+
+    // the head is collected, the body goes out as it's read past it
+    let mut buffer = [0; READ_BUFFER];
+    let mut head = Vec::new();
+    loop {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            bail!("response has no header terminator");
+        }
+        head.extend_from_slice(&buffer[..read]);
+        if let Some(split) = head.windows(4).position(|window| window == b"\r\n\r\n") {
+            startup::mark(Phase::FirstByte);
+            let text = String::from_utf8_lossy(&head[..split]);
+            let captured = Head::new(status_code(&text), fields(&text));
+            crate::ratelimit::observe(url.host, &captured);
+            check_status(&text)?;
+            if url.tls {
+                check_date(&text, url);
+            }
+            log::info!("{}{} answered {captured}", url.host, url.path);
+            consumer.head(&captured)?;
+            consumer.chunk(&head[split + 4..])?;
+            break;
+        }
+        if head.len() > MAX_HEAD {
+            bail!("response head over {MAX_HEAD} bytes");
+        }
+    }
+    drop(head);
+
+    loop {
+        match stream.read(&mut buffer).await? {
+            0 => return Ok(()),
+            read => consumer.chunk(&buffer[..read])?,
+        }
+    }
+}
+
+/// The status line's code, 0 when there isn't one.
+fn status_code(head: &str) -> u16 {
+    head.lines()
+        .next()
+        .and_then(|status| status.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .unwrap_or(0)
+}
+
+fn check_status(head: &str) -> Result<()> {
+    let status = head.lines().next().unwrap_or_default();
+    let code = status.split_whitespace().nth(1).unwrap_or("malformed");
+    metrics::HTTP_CLIENT_REQUESTS.inc(&[("status", code)]);
+    if code == "200" {
+        return Ok(());
+    }
+    let err = anyhow::anyhow!("unexpected response: {status}");
+    let code = code.parse().unwrap_or(0);
+    Err(FirmwareError::Http(Failure::status(code, err)).into())
+}
+
+/// The head's header fields, past the status line.
+fn fields(head: &str) -> impl Iterator<Item = (&str, &str)> {
+    head.lines().skip(1).filter_map(|line| line.split_once(':'))
+}
+
+/// Checks the clock against the `Date` header, if the head has one.
+fn check_date(head: &str, url: &Url<'_>) {
+    let date = fields(head).find_map(|(name, value)| {
+        name.trim()
+            .eq_ignore_ascii_case("date")
+            .then(|| value.trim())
+    });
+    if let Some(date) = date {
+        crate::clock::check_skew(&format!("https://{}{}", url.host, url.path), date);
+    }
+}
+
+struct Url<'a> {
+    tls: bool,
+    host: &'a str,
+    port: u16,
+    path: &'a str,
+}
+
+impl<'a> Url<'a> {
+    fn parse(url: &'a str) -> Result<Self> {
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            bail!("unsupported url scheme: {url}");
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().context("invalid url port")?),
+            None => (authority, if tls { 443 } else { 80 }),
+        };
+
+        Ok(Self {
+            tls,
+            host,
+            port,
+            path,
+        })
+    }
+}
@@ -0,0 +1,56 @@
+//! The peripheral I2C bus on I2C1, shared by the environment sensors, the
+//! RTC, the secure element and the console's bus scan through `bus`. The
+//! display has I2C0, with a bus of its own.
+
+use crate::bus::{self, Bus, Guard};
+use anyhow::Result;
+use esp_idf_hal::{
+    gpio::AnyIOPin,
+    i2c::{I2cConfig, I2cDriver, I2C1},
+    units::Hertz,
+};
+
+mod scan;
+
+pub use scan::devices;
+
+const BAUDRATE: Hertz = Hertz(100_000);
+
+static BUS: Bus<I2cDriver<'static>> = Bus::new("i2c1");
+
+pub struct Pins {
+    pub sda: AnyIOPin,
+    pub scl: AnyIOPin,
+}
+
+pub fn start(i2c: I2C1, pins: Pins) -> Result<()> {
+    BUS.install(I2cDriver::new(
+        i2c,
+        pins.sda,
+        pins.scl,
+        &I2cConfig::new().baudrate(BAUDRATE),
+    )?)?;
+
+    crate::console::register(crate::console::Command {
+        name: "i2c",
+        usage: "i2c",
+        summary: "scan the peripheral bus",
+        run: |_, _| scan::scan(),
+    });
+    Ok(())
+}
+
+/// Runs `f` with the bus to itself.
+pub fn with<T>(f: impl FnOnce(&mut I2cDriver<'static>) -> Result<T>) -> Result<T> {
+    BUS.with(f)
+}
+
+/// Waits for the bus, for async code.
+pub async fn lock() -> Result<Guard<'static, I2cDriver<'static>>> {
+    BUS.lock().await
+}
+
+/// The bus for a driver crate that keeps its own handle.
+pub fn device() -> bus::Device<I2cDriver<'static>> {
+    BUS.device()
+}
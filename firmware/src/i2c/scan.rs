@@ -0,0 +1,61 @@
+use anyhow::Result;
+use esp_idf_hal::delay::TickType;
+use std::{fmt::Write, time::Duration};
+
+/// Per address; a missing device NACKs right away, this only bounds a
+/// stuck bus.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Usual residents of each 7-bit address on hobbyist boards, a guess since
+/// many parts share addresses.
+const KNOWN: &[(u8, &str)] = &[
+    (0x1e, "HMC5883L"),
+    (0x20, "MCP23017/PCF8574"),
+    (0x23, "BH1750"),
+    (0x27, "PCF8574 LCD backpack"),
+    (0x29, "VL53L0X/TSL2591"),
+    (0x36, "MAX17048"),
+    (0x38, "AHT20"),
+    (0x3c, "SSD1306"),
+    (0x3d, "SSD1306"),
+    (0x40, "INA219/HTU21D/Si7021"),
+    (0x44, "SHT3x"),
+    (0x45, "SHT3x"),
+    (0x48, "ADS1115/TMP102"),
+    (0x50, "AT24C EEPROM"),
+    (0x53, "ADXL345"),
+    (0x57, "AT24C32"),
+    (0x5a, "CCS811/MLX90614"),
+    (0x60, "ATECC608, only while awake"),
+    (0x62, "SCD4x"),
+    (0x68, "MPU-6050/DS3231"),
+    (0x70, "TCA9548A"),
+    (0x76, "BME280/BMP280"),
+    (0x77, "BME280/BMP280"),
+];
+
+/// The non-reserved addresses that acknowledge a probe.
+pub fn devices() -> Result<Vec<u8>> {
+    let timeout = TickType::from(PROBE_TIMEOUT).0;
+    super::with(|i2c| {
+        Ok((0x08..=0x77)
+            .filter(|&address| i2c.write(address, &[], timeout).is_ok())
+            .collect())
+    })
+}
+
+/// Reports the devices that acknowledge, also to the log.
+pub fn scan() -> Result<String> {
+    let found = devices()?;
+
+    let mut report = format!("{} device(s) on the bus", found.len());
+    for address in found {
+        let name = KNOWN
+            .iter()
+            .find(|(known, _)| *known == address)
+            .map_or("unknown", |(_, name)| name);
+        let _ = write!(report, "\n  0x{address:02x}  {name}");
+    }
+    log::info!("i2c scan: {report}");
+    Ok(report)
+}
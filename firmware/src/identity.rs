@@ -0,0 +1,282 @@
+//! Who the device is. The id is the one burned into the user data eFuse
+//! block when there is one, or else the one the factory line wrote, or
+//! else derived from the station MAC. The
+//! per-device secrets come out of an HKDF over a root that, once an HMAC
+//! key is burned, only the chip's HMAC peripheral can compute: the key is
+//! read protected, so it's never in flash or RAM.
+//!
+//! A backend holding the burned key gets to the same secrets with
+//! `root = HMAC-SHA256(key, ROOT_MESSAGE)` and
+//! `secret = HKDF-SHA256(salt = SALT, ikm = root, info = label)`.
+//! Without a key the root is the MAC, good for telling devices apart but
+//! guessable by anyone who has seen them.
+//!
+//! Requests are only signed for the device's own backends, the hosts of
+//! the configured backend urls; a fetch from anywhere else goes out bare.
+
+use crate::{config::Config, console, secret::Secret};
+use anyhow::{bail, ensure, Context, Result};
+use esp_idf_sys::{esp, hmac};
+use ring::{hkdf, hmac as ring_hmac};
+use std::sync::{Mutex, OnceLock};
+
+const ROOT_MESSAGE: &[u8] = b"esp32-rustls identity root";
+const SALT: &[u8] = b"esp32-rustls identity v1";
+/// HKDF label of the request signing key.
+const SIGNING_LABEL: &str = "request-signing";
+
+const USER_DATA: esp_idf_sys::esp_efuse_block_t = esp_idf_sys::esp_efuse_block_t_EFUSE_BLK3;
+const ID_LEN: usize = 32;
+const KEY_LEN: usize = 32;
+
+/// Hosts `sign_request()` signs for, from `configure()`.
+static BACKENDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Where the secrets' root came from.
+#[derive(Clone, Copy)]
+enum Root {
+    /// The HMAC peripheral with the key in this key block.
+    Efuse(u32),
+    Mac,
+}
+
+/// The device id: the eFuse one, or `esp32-<mac>`.
+pub fn id() -> &'static str {
+    static ID: OnceLock<String> = OnceLock::new();
+
+    ID.get_or_init(|| match burned_id() {
+        Ok(Some(id)) => id,
+        Ok(None) => provisioned_id().unwrap_or_else(mac_id),
+        Err(err) => {
+            log::warn!("couldn't read the burned id, using the mac: {err:#}");
+            mac_id()
+        }
+    })
+}
+
+/// A 32 byte secret for `label`, the same on every boot of this device
+/// and different on every other device.
+pub fn secret(label: &str) -> Secret<[u8; 32]> {
+    let (_, root) = root();
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, SALT).extract(root.expose());
+    let mut secret = Secret::new([0; 32]);
+    prk.expand(&[label.as_bytes()], hkdf::HKDF_SHA256)
+        .and_then(|okm| okm.fill(secret.expose_mut()))
+        .expect("32 bytes is a valid HKDF-SHA256 length");
+    secret
+}
+
+/// Headers proving a request came from this device: the id, the time and
+/// `HMAC-SHA256(secret("request-signing"), "<method>\n<url>\n<time>")`.
+/// None unless `url` is on one of the backends.
+pub fn sign_request(method: &str, url: &str) -> Vec<(&'static str, String)> {
+    let backend = crate::http::host(url).is_some_and(|host| {
+        BACKENDS
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|backend| backend.eq_ignore_ascii_case(host))
+    });
+    if !backend {
+        return Vec::new();
+    }
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        .to_string();
+    let key = ring_hmac::Key::new(ring_hmac::HMAC_SHA256, secret(SIGNING_LABEL).expose());
+    let tag = ring_hmac::sign(&key, format!("{method}\n{url}\n{timestamp}").as_bytes());
+    vec![
+        ("x-device-id", id().to_owned()),
+        ("x-timestamp", timestamp),
+        ("x-signature", to_hex(tag.as_ref())),
+    ]
+}
+
+/// Takes the backends `sign_request()` signs for, before the first fetch.
+pub fn configure(config: &Config) {
+    *BACKENDS.lock().unwrap() = [
+        &config.download_url,
+        &config.lan_url,
+        &config.config_url,
+        &config.cloud_url,
+        &config.gateway_url,
+        &config.incident_url,
+    ]
+    .iter()
+    .filter_map(|url| crate::http::host(url))
+    .map(str::to_owned)
+    .collect();
+}
+
+/// Registers the `identity` command and says where the identity comes
+/// from.
+pub fn start() {
+    let (source, _) = root();
+    match source {
+        Root::Efuse(block) => log::info!("identity {} keyed by efuse block {block}", id()),
+        Root::Mac => log::warn!(
+            "identity {}, no hmac key burned so secrets derive from the mac",
+            id()
+        ),
+    }
+    console::register(console::Command {
+        name: "identity",
+        usage: "identity [burn-id <id> yes | burn-key <hex> yes]",
+        summary: "device id and secret root, or burn them into efuse for good",
+        run: command,
+    });
+}
+
+fn command(console: &console::Console, args: &[&str]) -> Result<String> {
+    match args {
+        [] => {
+            let (source, _) = root();
+            Ok(match source {
+                Root::Efuse(block) => format!("id {}, secrets keyed by efuse block {block}", id()),
+                Root::Mac => format!("id {}, secrets derived from the mac", id()),
+            })
+        }
+        ["burn-id", new_id, "yes"] => {
+            burn_id(new_id)?;
+            Ok(format!("id {new_id} burned, it applies after a restart"))
+        }
+        ["burn-key", key, "yes"] => {
+            // the key roots every secret, it isn't for a network console
+            ensure!(console.is_uart(), "burn-key is only on the serial console");
+            let block = burn_key(key)?;
+            Ok(format!(
+                "hmac key burned into efuse block {block}, it applies after a restart"
+            ))
+        }
+        _ => bail!(
+            "usage: identity [burn-id <id> yes | burn-key <hex> yes], burning can't be undone"
+        ),
+    }
+}
+
+fn root() -> &'static (Root, Secret<[u8; 32]>) {
+    static ROOT: OnceLock<(Root, Secret<[u8; 32]>)> = OnceLock::new();
+
+    ROOT.get_or_init(|| {
+        if let Some(block) = hmac_key_block() {
+            let mut root = Secret::new([0; 32]);
+            let key_id = block - esp_idf_sys::esp_efuse_block_t_EFUSE_BLK_KEY0;
+            match esp!(unsafe {
+                hmac::esp_hmac_calculate(
+                    key_id as _,
+                    ROOT_MESSAGE.as_ptr().cast(),
+                    ROOT_MESSAGE.len(),
+                    root.expose_mut().as_mut_ptr(),
+                )
+            }) {
+                Ok(()) => return (Root::Efuse(block), root),
+                Err(err) => log::error!("hmac over efuse block {block} failed: {err}"),
+            }
+        }
+        let digest = ring::digest::digest(&ring::digest::SHA256, mac_id().as_bytes());
+        let mut root = Secret::new([0; 32]);
+        root.expose_mut().copy_from_slice(digest.as_ref());
+        (Root::Mac, root)
+    })
+}
+
+fn hmac_key_block() -> Option<u32> {
+    let mut block = 0;
+    unsafe {
+        esp_idf_sys::esp_efuse_find_purpose(
+            esp_idf_sys::esp_efuse_purpose_t_ESP_EFUSE_KEY_PURPOSE_HMAC_UP,
+            &mut block,
+        )
+    }
+    .then_some(block)
+}
+
+fn provisioned_id() -> Option<String> {
+    #[cfg(feature = "factory")]
+    return crate::factory::get("device_id").filter(|id| !id.is_empty());
+    #[cfg(not(feature = "factory"))]
+    None
+}
+
+fn mac_id() -> String {
+    let mut mac = [0u8; 6];
+    unsafe {
+        esp_idf_sys::esp_read_mac(
+            mac.as_mut_ptr(),
+            esp_idf_sys::esp_mac_type_t_ESP_MAC_WIFI_STA,
+        )
+    };
+    format!("esp32-{}", to_hex(&mac))
+}
+
+/// The id in the user data block, NUL padded; all zeros is none burned.
+fn burned_id() -> Result<Option<String>> {
+    let mut raw = [0u8; ID_LEN];
+    esp!(unsafe {
+        esp_idf_sys::esp_efuse_read_block(USER_DATA, raw.as_mut_ptr().cast(), 0, ID_LEN * 8)
+    })?;
+    let len = raw.iter().position(|&byte| byte == 0).unwrap_or(ID_LEN);
+    if len == 0 {
+        return Ok(None);
+    }
+    Ok(Some(
+        String::from_utf8(raw[..len].to_vec()).context("burned id isn't utf-8")?,
+    ))
+}
+
+fn burn_id(new_id: &str) -> Result<()> {
+    ensure!(
+        !new_id.is_empty() && new_id.len() < ID_LEN,
+        "the id takes 1 to {} bytes",
+        ID_LEN - 1
+    );
+    ensure!(burned_id()?.is_none(), "an id is already burned");
+    let mut raw = [0u8; ID_LEN];
+    raw[..new_id.len()].copy_from_slice(new_id.as_bytes());
+    esp!(unsafe {
+        esp_idf_sys::esp_efuse_write_block(USER_DATA, raw.as_ptr().cast(), 0, ID_LEN * 8)
+    })
+    .context("couldn't burn the id")?;
+    log::warn!("identity: id {new_id} burned");
+    Ok(())
+}
+
+/// Burns `key` into the first unused key block for the HMAC peripheral;
+/// the block is read protected from then on.
+fn burn_key(key: &str) -> Result<u32> {
+    let key = from_hex(key)
+        .filter(|key| key.len() == KEY_LEN)
+        .with_context(|| format!("the key takes {KEY_LEN} bytes of hex"))?;
+    ensure!(hmac_key_block().is_none(), "an hmac key is already burned");
+    let block = unsafe { esp_idf_sys::esp_efuse_find_unused_key_block() };
+    ensure!(
+        block != esp_idf_sys::esp_efuse_block_t_EFUSE_BLK_KEY_MAX,
+        "no unused efuse key block left"
+    );
+    esp!(unsafe {
+        esp_idf_sys::esp_efuse_write_key(
+            block,
+            esp_idf_sys::esp_efuse_purpose_t_ESP_EFUSE_KEY_PURPOSE_HMAC_UP,
+            key.as_ptr().cast(),
+            key.len(),
+        )
+    })
+    .context("couldn't burn the key")?;
+    log::warn!("identity: hmac key burned into efuse block {block}");
+    Ok(block)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim();
+    // an odd length runs past the end on the last pair
+    (0..text.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(text.get(at..at + 2)?, 16).ok())
+        .collect()
+}
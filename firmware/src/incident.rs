@@ -0,0 +1,362 @@
+//! Diagnostic bundles for support: `incident [id]` from the console, the
+//! `cmd` channel or `POST /trigger` gathers what a field issue needs into
+//! one archive and POSTs it to `incident_url`, with `incident_token` as
+//! the bearer token when set. The id names the upload; support can pass
+//! the ticket's, otherwise one is made up and logged.
+//!
+//! The archive is a tar of `<id>/`: `incident.json` with the device, the
+//! build and when it was taken, `log.txt` with the ring of recent lines,
+//! `errors.txt` with the warnings among them and the tasks' last errors,
+//! `heap.json`, `tasks.json` with the FreeRTOS stack watermarks and the
+//! supervised tasks, `wifi.json`, `config.json` as stored with secrets
+//! redacted, and `status.json`, the telemetry snapshot. It goes gzipped
+//! when the heap has room for the encoder's tables, as a plain tar
+//! otherwise, which the content type tells apart.
+
+use crate::{
+    config::Config, console, device, events, heap, logtail, net, runtime, secret::Secret, telemetry,
+};
+use anyhow::{bail, ensure, Context, Result};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_sys as sys;
+use serde_json::{json, Value};
+use std::{
+    ffi::CStr,
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const COMMAND: &str = "incident";
+const MAX_ID: usize = 64;
+/// What the deflate encoder's window and hash chains take, with some left
+/// over for the upload.
+const DEFLATE_HEAP: usize = 300 * 1024;
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+const BLOCK: usize = 512;
+
+struct Target {
+    url: String,
+    token: Secret<String>,
+    nvs: EspDefaultNvsPartition,
+}
+
+static TARGET: OnceLock<Target> = OnceLock::new();
+/// Set while a bundle is being gathered or uploaded, one at a time.
+static BUSY: AtomicBool = AtomicBool::new(false);
+
+/// Registers `incident` and uploads the bundles it asks for.
+pub fn start(config: &Config, nvs: EspDefaultNvsPartition) {
+    let _ = TARGET.set(Target {
+        url: config.incident_url.clone(),
+        token: config.incident_token.clone(),
+        nvs,
+    });
+    console::register(console::Command {
+        name: COMMAND,
+        usage: "incident [id]",
+        summary: "upload a diagnostic bundle to incident_url",
+        run: command,
+    });
+
+    runtime::spawn(async {
+        let mut events = events::subscribe();
+        loop {
+            let Ok(events::Event::Command(command)) = events.recv().await else {
+                continue;
+            };
+            let mut words = command.split_whitespace();
+            if words.next() != Some(COMMAND) {
+                continue;
+            }
+            let id = words.next().map(str::to_owned);
+            // its own task, the bus keeps being read while it uploads
+            runtime::spawn(async move {
+                if let Err(err) = upload(id).await {
+                    log::warn!("incident: {err:#}");
+                }
+            });
+        }
+    });
+}
+
+fn command(_: &console::Console, args: &[&str]) -> Result<String> {
+    let id = match args {
+        [] => new_id(),
+        [id] => check_id(id)?.to_owned(),
+        _ => bail!("usage: incident [id]"),
+    };
+    ensure!(
+        TARGET.get().is_some_and(|target| !target.url.is_empty()),
+        "no incident_url to upload to"
+    );
+    events::publish(events::Event::Command(format!("{COMMAND} {id}")));
+    Ok(format!("incident {id}, uploading"))
+}
+
+fn new_id() -> String {
+    format!("{}-{:08x}", device::id(), device::random())
+}
+
+/// An id goes into a header and the archive's paths, so it's kept to
+/// letters, digits, `-` and `_`.
+fn check_id(id: &str) -> Result<&str> {
+    ensure!(
+        !id.is_empty()
+            && id.len() <= MAX_ID
+            && id
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_'),
+        "an incident id is up to {MAX_ID} letters, digits, - and _"
+    );
+    Ok(id)
+}
+
+/// Gathers a bundle and uploads it under `id`, a new one without, and
+/// returns the id and the size uploaded.
+pub async fn upload(id: Option<String>) -> Result<(String, usize)> {
+    let id = match id {
+        Some(id) => check_id(&id)?.to_owned(),
+        None => new_id(),
+    };
+    let target = TARGET.get().context("incident bundles not started")?;
+    ensure!(!target.url.is_empty(), "no incident_url to upload to");
+    ensure!(
+        !BUSY.swap(true, Ordering::Relaxed),
+        "another incident is uploading"
+    );
+    let result = send(target, &id).await;
+    BUSY.store(false, Ordering::Relaxed);
+    result.map(|size| (id, size))
+}
+
+async fn send(target: &Target, id: &str) -> Result<usize> {
+    let (nvs, owned) = (target.nvs.clone(), id.to_owned());
+    let (archive, content_type) = runtime::run_blocking(move || bundle(nvs, &owned)).await??;
+    let size = archive.len();
+    log::info!(
+        "incident {id}: {size} bytes of {content_type} to {}",
+        target.url
+    );
+
+    let mut request = crate::http::client()?
+        .post(&target.url)
+        .timeout(UPLOAD_TIMEOUT)
+        .header("Content-Type", content_type)
+        .header("X-Incident-Id", id)
+        .header("X-Device-Id", device::id())
+        .body(archive);
+    if !target.token.expose().is_empty() {
+        request = request.bearer_auth(target.token.expose());
+    }
+    request
+        .send()
+        .await
+        .context("couldn't upload the bundle")?
+        .error_for_status()
+        .context("the bundle was refused")?;
+    log::info!("incident {id}: uploaded");
+    Ok(size)
+}
+
+/// The archive and its content type.
+fn bundle(nvs: EspDefaultNvsPartition, id: &str) -> Result<(Vec<u8>, &'static str)> {
+    let lines = logtail::recent();
+    let taken = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .filter(|_| events::state().time_synced);
+    let mtime = taken.map_or(0, |taken| taken.as_secs());
+
+    let mut tar = Tar::default();
+    let add_json = |tar: &mut Tar, name: &str, value: &Value| -> Result<()> {
+        tar.add(id, name, mtime, &serde_json::to_vec_pretty(value)?);
+        Ok(())
+    };
+    add_json(
+        &mut tar,
+        "incident.json",
+        &json!({
+            "incident": id,
+            "device": device::id(),
+            "firmware": device::firmware_info(),
+            "inventory": device::inventory(),
+            "taken": taken.map(|taken| taken.as_secs()),
+            "uptime_s": device::uptime().as_secs(),
+        }),
+    )?;
+    tar.add(id, "log.txt", mtime, lines.join("\n").as_bytes());
+    tar.add(id, "errors.txt", mtime, errors(&lines).as_bytes());
+    add_json(&mut tar, "heap.json", &heap_stats())?;
+    add_json(&mut tar, "tasks.json", &tasks())?;
+    add_json(&mut tar, "wifi.json", &wifi())?;
+    let config = Config::load(nvs).map_or_else(
+        |err| json!({ "error": format!("{err:#}") }),
+        |config| config.redacted(),
+    );
+    add_json(&mut tar, "config.json", &config)?;
+    add_json(&mut tar, "status.json", &telemetry::snapshot())?;
+    let tar = tar.finish();
+
+    if heap::free() < DEFLATE_HEAP {
+        log::info!("incident {id}: too little heap to compress, sending the plain tar");
+        return Ok((tar, "application/x-tar"));
+    }
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(&tar)?;
+    Ok((encoder.finish()?, "application/gzip"))
+}
+
+/// The warnings and errors in the ring, then the supervised tasks' last
+/// errors.
+fn errors(lines: &[String]) -> String {
+    let mut errors: Vec<String> = lines
+        .iter()
+        .filter(|line| {
+            matches!(
+                logtail::level(line),
+                Some(log::Level::Error | log::Level::Warn)
+            )
+        })
+        .cloned()
+        .collect();
+    for task in runtime::tasks() {
+        if let Some(error) = task.last_error {
+            errors.push(format!(
+                "task {}, {} restarts: {error}",
+                task.name, task.restarts
+            ));
+        }
+    }
+    errors.join("\n")
+}
+
+fn heap_stats() -> Value {
+    let region = |caps| {
+        let mut info = sys::multi_heap_info_t::default();
+        unsafe { sys::heap_caps_get_info(&mut info, caps) };
+        json!({
+            "free": info.total_free_bytes,
+            "allocated": info.total_allocated_bytes,
+            "largest_block": info.largest_free_block,
+            "minimum_free": info.minimum_free_bytes,
+        })
+    };
+    json!({
+        "free": heap::free(),
+        "internal": region(sys::MALLOC_CAP_INTERNAL),
+        "psram": region(sys::MALLOC_CAP_SPIRAM),
+    })
+}
+
+fn tasks() -> Value {
+    let count = unsafe { sys::uxTaskGetNumberOfTasks() } as usize;
+    // room for a task or two started in between
+    let mut statuses = vec![sys::TaskStatus_t::default(); count + 4];
+    let filled = unsafe {
+        sys::uxTaskGetSystemState(
+            statuses.as_mut_ptr(),
+            statuses.len() as _,
+            std::ptr::null_mut(),
+        )
+    } as usize;
+    statuses.truncate(filled);
+    statuses.sort_by_key(|status| status.usStackHighWaterMark);
+
+    let threads: Vec<Value> = statuses
+        .iter()
+        .map(|status| {
+            let name = unsafe { CStr::from_ptr(status.pcTaskName) };
+            json!({
+                "name": name.to_string_lossy(),
+                "stack_free": status.usStackHighWaterMark,
+                "priority": status.uxCurrentPriority,
+                "state": match status.eCurrentState {
+                    sys::eTaskState_eRunning => "running",
+                    sys::eTaskState_eReady => "ready",
+                    sys::eTaskState_eBlocked => "blocked",
+                    sys::eTaskState_eSuspended => "suspended",
+                    _ => "deleted",
+                },
+            })
+        })
+        .collect();
+    let supervised: Vec<Value> = runtime::tasks()
+        .iter()
+        .map(|task| {
+            json!({
+                "name": task.name,
+                "state": format!("{:?}", task.state).to_lowercase(),
+                "up_s": task.spawned_at.elapsed().as_secs(),
+                "restarts": task.restarts,
+                "last_error": task.last_error,
+            })
+        })
+        .collect();
+    json!({ "threads": threads, "supervised": supervised })
+}
+
+fn wifi() -> Value {
+    #[cfg(feature = "wifi")]
+    let gateway = net::gateway();
+    #[cfg(not(feature = "wifi"))]
+    let gateway: Option<std::net::Ipv4Addr> = None;
+    json!({
+        "links": ["wifi", "eth", "cellular"]
+            .into_iter()
+            .filter(|link| net::link_up(link))
+            .collect::<Vec<_>>(),
+        "ssid": net::ssid(),
+        "rssi": net::rssi(),
+        "phy": net::phy(),
+        "ipv4": net::ipv4(),
+        "ipv6": net::ipv6(),
+        "gateway": gateway,
+        "dns": net::dns_servers(),
+    })
+}
+
+/// A ustar archive built in memory, files only.
+#[derive(Default)]
+struct Tar(Vec<u8>);
+
+impl Tar {
+    fn add(&mut self, dir: &str, name: &str, mtime: u64, data: &[u8]) {
+        let mut header = [0u8; BLOCK];
+        let path = format!("{dir}/{name}");
+        header[..path.len()].copy_from_slice(path.as_bytes());
+        octal(&mut header[100..108], 0o644);
+        octal(&mut header[108..116], 0);
+        octal(&mut header[116..124], 0);
+        octal(&mut header[124..136], data.len() as u64);
+        octal(&mut header[136..148], mtime);
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        // the checksum is taken with its own field as spaces
+        header[148..156].fill(b' ');
+        let sum: u64 = header.iter().map(|&byte| u64::from(byte)).sum();
+        octal(&mut header[148..155], sum);
+
+        self.0.extend_from_slice(&header);
+        self.0.extend_from_slice(data);
+        let padding = (BLOCK - data.len() % BLOCK) % BLOCK;
+        self.0.resize(self.0.len() + padding, 0);
+    }
+
+    /// The archive with the two zero blocks that end it.
+    fn finish(mut self) -> Vec<u8> {
+        self.0.resize(self.0.len() + 2 * BLOCK, 0);
+        self.0
+    }
+}
+
+/// `value` as zero padded octal filling `field` but for its closing NUL.
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{value:0width$o}", width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
@@ -0,0 +1,172 @@
+use crate::events::{self, Event};
+use anyhow::{Context, Result};
+use std::{
+    sync::atomic::{AtomicU8, Ordering},
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast::error::TryRecvError;
+
+#[cfg(not(feature = "neopixel"))]
+mod led;
+#[cfg(feature = "neopixel")]
+mod rgb;
+#[cfg(not(feature = "neopixel"))]
+pub use led::Led;
+#[cfg(feature = "neopixel")]
+pub use rgb::Rgb;
+
+/// How often a running pattern checks the bus for a state change.
+const POLL: Duration = Duration::from_millis(50);
+const STACK_SIZE: usize = 3072;
+/// Provisioning that hasn't brought the network up by then has failed.
+const PROVISION_TIMEOUT: Duration = Duration::from_secs(300);
+/// Until the config is loaded, the same as the config default.
+static BRIGHTNESS: AtomicU8 = AtomicU8::new(32);
+
+/// What the indicator shows, by priority: the first flag set wins.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Error,
+    Ota,
+    Fetching,
+    Provisioning,
+    Connected,
+    Connecting,
+}
+
+impl State {
+    /// On and off times in milliseconds, repeated.
+    fn pattern(self) -> &'static [(u64, u64)] {
+        match self {
+            Self::Error => &[(100, 100), (100, 100), (100, 1000)],
+            Self::Ota => &[(800, 200)],
+            Self::Fetching => &[(50, 50)],
+            Self::Provisioning => &[(100, 100), (100, 700)],
+            Self::Connected => &[(1000, 0)],
+            Self::Connecting => &[(200, 200)],
+        }
+    }
+}
+
+#[derive(Default)]
+struct Flags {
+    net_up: bool,
+    /// Since when, until the network comes up or `PROVISION_TIMEOUT`.
+    provisioning: Option<Instant>,
+    fetching: bool,
+    ota: bool,
+    error: bool,
+}
+
+impl Flags {
+    fn state(&self) -> State {
+        if self.error {
+            State::Error
+        } else if self.ota {
+            State::Ota
+        } else if self.fetching {
+            State::Fetching
+        } else if self
+            .provisioning
+            .is_some_and(|since| since.elapsed() < PROVISION_TIMEOUT)
+        {
+            State::Provisioning
+        } else if self.net_up {
+            State::Connected
+        } else {
+            State::Connecting
+        }
+    }
+
+    fn apply(&mut self, event: &Event) {
+        match event {
+            Event::NetUp => {
+                self.net_up = true;
+                self.provisioning = None;
+                self.error = false;
+            }
+            Event::NetDown => self.net_up = false,
+            Event::Command(command) if command == "provision" => {
+                self.provisioning = Some(Instant::now());
+            }
+            Event::FetchStarted => self.fetching = true,
+            Event::FetchDone { ok } => {
+                self.fetching = false;
+                self.error = !ok;
+            }
+            Event::OtaPending => self.ota = true,
+            Event::OtaDone { ok } => {
+                self.ota = false;
+                self.error |= !ok;
+            }
+            Event::WifiDead
+            | Event::LowHeap { .. }
+            | Event::LowBattery { .. }
+            | Event::Overheat { .. } => {
+                self.error = true;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Something that can show a state, lit or dark for the blink patterns.
+pub trait Indicator: Send {
+    fn show(&mut self, state: State, lit: bool) -> Result<()>;
+}
+
+pub fn configure(config: &crate::config::Config) {
+    BRIGHTNESS.store(
+        u8::try_from(config.led_brightness).unwrap_or(u8::MAX),
+        Ordering::Relaxed,
+    );
+}
+
+/// Brightness for backends that can dim, 0-255.
+#[cfg(feature = "neopixel")]
+fn brightness() -> u8 {
+    BRIGHTNESS.load(Ordering::Relaxed)
+}
+
+/// Blinks the indicator from the event bus on its own thread.
+pub fn start(mut indicator: impl Indicator + 'static) -> Result<()> {
+    let mut events = events::subscribe();
+
+    std::thread::Builder::new()
+        .name("indicator".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            let mut flags = Flags::default();
+
+            loop {
+                let state = flags.state();
+                'pattern: for &(on, off) in state.pattern().iter().cycle() {
+                    for (lit, millis) in [(true, on), (false, off)] {
+                        if millis == 0 {
+                            continue;
+                        }
+                        if let Err(err) = indicator.show(state, lit) {
+                            log::warn!("indicator: {err:#}");
+                        }
+                        let until = Instant::now() + Duration::from_millis(millis);
+                        while Instant::now() < until {
+                            std::thread::sleep(POLL);
+                            loop {
+                                match events.try_recv() {
+                                    Ok(event) => flags.apply(&event),
+                                    Err(TryRecvError::Lagged(_)) => {}
+                                    Err(_) => break,
+                                }
+                            }
+                            if flags.state() != state {
+                                break 'pattern;
+                            }
+                        }
+                    }
+                }
+                log::debug!("indicator {:?}", flags.state());
+            }
+        })
+        .context("couldn't spawn indicator")?;
+    Ok(())
+}
@@ -0,0 +1,18 @@
+use super::{Indicator, State};
+use anyhow::Result;
+use esp_idf_hal::gpio::{Output, OutputPin, PinDriver};
+
+/// A plain LED, active high; it only has the patterns to tell states apart.
+pub struct Led<P: OutputPin>(PinDriver<'static, P, Output>);
+
+impl<P: OutputPin> Led<P> {
+    pub fn new(pin: P) -> Result<Self> {
+        Ok(Self(PinDriver::output(pin)?))
+    }
+}
+
+impl<P: OutputPin> Indicator for Led<P> {
+    fn show(&mut self, _state: State, lit: bool) -> Result<()> {
+        Ok(self.0.set_level(lit.into())?)
+    }
+}
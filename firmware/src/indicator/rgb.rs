@@ -0,0 +1,78 @@
+use super::{Indicator, State};
+use anyhow::Result;
+use esp_idf_hal::{
+    gpio::OutputPin,
+    peripheral::Peripheral,
+    rmt::{config::TransmitConfig, FixedLengthSignal, PinState, Pulse, RmtChannel, TxRmtDriver},
+};
+use std::time::Duration;
+
+const _: () = assert!(
+    crate::chip::CHIP.rmt_tx_channels > 0,
+    "the `neopixel` feature needs an RMT transmit channel"
+);
+
+/// WS2812 bit timings, high then low, for a 0 and a 1 bit.
+const T0H: Duration = Duration::from_nanos(350);
+const T0L: Duration = Duration::from_nanos(800);
+const T1H: Duration = Duration::from_nanos(700);
+const T1L: Duration = Duration::from_nanos(600);
+
+/// A single WS2812 on an RMT channel, like the DevKitC-1's on GPIO48.
+pub struct Rgb {
+    tx: TxRmtDriver<'static>,
+    bits: [(Pulse, Pulse); 2],
+}
+
+impl Rgb {
+    pub fn new<C: RmtChannel>(
+        channel: impl Peripheral<P = C> + 'static,
+        pin: impl Peripheral<P = impl OutputPin> + 'static,
+    ) -> Result<Self> {
+        // keeps the bit timings right while DFS moves the APB clock
+        let config = TransmitConfig::new().clock_divider(1).aware_dfs(true);
+        let tx = TxRmtDriver::new(channel, pin, &config)?;
+        let ticks = tx.counter_clock()?;
+        let bits = [
+            (
+                Pulse::new_with_duration(ticks, PinState::High, &T0H)?,
+                Pulse::new_with_duration(ticks, PinState::Low, &T0L)?,
+            ),
+            (
+                Pulse::new_with_duration(ticks, PinState::High, &T1H)?,
+                Pulse::new_with_duration(ticks, PinState::Low, &T1L)?,
+            ),
+        ];
+        Ok(Self { tx, bits })
+    }
+
+    fn write(&mut self, (red, green, blue): (u8, u8, u8)) -> Result<()> {
+        let scale = |channel: u8| (u16::from(channel) * u16::from(super::brightness()) / 255) as u8;
+        // the strip wants green first
+        let grb = u32::from_be_bytes([0, scale(green), scale(red), scale(blue)]);
+
+        let mut signal = FixedLengthSignal::<24>::new();
+        for bit in 0..24 {
+            let one = grb & (1 << (23 - bit)) != 0;
+            signal.set(bit, &self.bits[usize::from(one)])?;
+        }
+        Ok(self.tx.start_blocking(&signal)?)
+    }
+}
+
+fn color(state: State) -> (u8, u8, u8) {
+    match state {
+        State::Error => (255, 0, 0),
+        State::Ota => (255, 0, 255),
+        State::Fetching => (0, 255, 255),
+        State::Provisioning => (0, 0, 255),
+        State::Connected => (0, 255, 0),
+        State::Connecting => (255, 160, 0),
+    }
+}
+
+impl Indicator for Rgb {
+    fn show(&mut self, state: State, lit: bool) -> Result<()> {
+        self.write(if lit { color(state) } else { (0, 0, 0) })
+    }
+}
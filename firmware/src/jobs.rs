@@ -0,0 +1,266 @@
+use crate::{
+    device,
+    events::{self, Event},
+    runtime,
+};
+use anyhow::Result;
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Job {
+    name: &'static str,
+    interval: Duration,
+    jitter: Duration,
+    radio: bool,
+    phased: bool,
+    trigger: Option<fn(&Event) -> bool>,
+}
+
+impl Job {
+    pub fn new(name: &'static str, interval: Duration) -> Self {
+        Self {
+            name,
+            interval,
+            jitter: Duration::ZERO,
+            radio: false,
+            phased: false,
+            trigger: None,
+        }
+    }
+
+    /// Adds up to `jitter` of random delay to every run, so jobs sharing an
+    /// interval don't all fire on the same tick.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// The job goes out over the radio: quiet hours hold it, and it runs
+    /// as soon as they're over, to catch up.
+    pub fn radio(mut self) -> Self {
+        self.radio = true;
+        self
+    }
+
+    /// Runs the job at this device's own point in the interval, taken from
+    /// a hash of its id, so a fleet powered up at once doesn't poll at
+    /// once. With the clock set the points are on wall-clock time and stay
+    /// spread however the devices booted; without it the first run is held
+    /// back by as much.
+    pub fn phased(mut self) -> Self {
+        self.phased = true;
+        self
+    }
+
+    /// Also runs the job as soon as an event `trigger` picks is published,
+    /// rather than at the next tick; the interval starts over from there.
+    /// A run still going when the event comes is left to finish, the event
+    /// doesn't queue another.
+    // only the ambient sensor's events trigger jobs so far
+    #[cfg_attr(not(feature = "ambient"), allow(dead_code))]
+    pub fn on(mut self, trigger: fn(&Event) -> bool) -> Self {
+        self.trigger = Some(trigger);
+        self
+    }
+
+    /// This device's offset into the interval.
+    fn phase(&self) -> Duration {
+        // FNV-1a, the same on every build, unlike std's hasher
+        let hash = device::id()
+            .bytes()
+            .chain(self.name.bytes())
+            .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+            });
+        let interval = self.interval.as_millis().max(1) as u64;
+        Duration::from_millis(hash % interval)
+    }
+
+    /// How long to wait for the next run.
+    fn delay(&self, first: bool) -> Duration {
+        let jitter = random_delay(self.jitter);
+        if !self.phased {
+            return self.interval + jitter;
+        }
+        if !events::state().time_synced {
+            return if first { self.phase() } else { self.interval } + jitter;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let interval = self.interval.as_millis().max(1) as u64;
+        let phase = self.phase().as_millis() as u64;
+        let mut delay = (phase + interval - now % interval) % interval;
+        // a timer firing a little early would otherwise run the same slot
+        // twice
+        if !first && delay < interval / 4 {
+            delay += interval;
+        }
+        Duration::from_millis(delay) + jitter
+    }
+}
+
+/// One timer per job, rearmed after every run.
+pub trait Timer: Send + 'static {
+    fn after(&mut self, duration: Duration) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Makes the job timers: `EspTaskTimerService` on the device, tokio's
+/// clock in the simulator.
+pub trait Timers: Clone + Send + 'static {
+    type Timer: Timer;
+
+    fn timer(&self) -> Result<Self::Timer>;
+}
+
+#[cfg(target_os = "espidf")]
+impl Timer for esp_idf_svc::timer::EspAsyncTimer {
+    async fn after(&mut self, duration: Duration) -> Result<()> {
+        Ok(esp_idf_svc::timer::EspAsyncTimer::after(self, duration).await?)
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl Timers for esp_idf_svc::timer::EspTaskTimerService {
+    type Timer = esp_idf_svc::timer::EspAsyncTimer;
+
+    fn timer(&self) -> Result<Self::Timer> {
+        Ok(self.timer_async()?)
+    }
+}
+
+/// Runs periodic async jobs off `Timers`. A job whose previous run is
+/// still in progress when its timer fires is skipped rather than run twice.
+#[derive(Clone)]
+pub struct Scheduler<T> {
+    timers: T,
+}
+
+impl<T: Timers> Scheduler<T> {
+    pub fn new(timers: T) -> Self {
+        Self { timers }
+    }
+
+    pub fn register<F, Fut>(&self, job: Job, run: F) -> Handle
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        log::info!("registered job {} every {:?}", job.name, job.interval);
+
+        let timers = self.timers.clone();
+        let run = Arc::new(Mutex::new(run));
+        let running = Arc::new(AtomicBool::new(false));
+        let handle = Handle::default();
+
+        let cancelled = handle.cancelled.clone();
+        runtime::spawn_named(job.name, move || {
+            drive(
+                job,
+                timers.clone(),
+                run.clone(),
+                running.clone(),
+                cancelled.clone(),
+            )
+        });
+        handle
+    }
+}
+
+/// Stops a registered job: one that is running finishes, the timer isn't
+/// armed again.
+#[derive(Clone, Default)]
+pub struct Handle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Handle {
+    // only jobs that can be reconfigured are ever cancelled
+    #[allow(dead_code)]
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+}
+
+async fn drive<T, F, Fut>(
+    job: Job,
+    timers: T,
+    run: Arc<Mutex<F>>,
+    running: Arc<AtomicBool>,
+    cancelled: Arc<AtomicBool>,
+) -> Result<()>
+where
+    T: Timers,
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let mut timer = timers.timer()?;
+    // subscribed before the first wait, so no event is missed between runs
+    let mut events = job.trigger.map(|_| events::subscribe());
+
+    let mut first = true;
+    loop {
+        match (job.trigger, events.as_mut()) {
+            (Some(trigger), Some(events)) => tokio::select! {
+                elapsed = timer.after(job.delay(first)) => elapsed?,
+                event = triggered(events, trigger) => {
+                    log::info!("job {} triggered by {event:?}", job.name);
+                }
+            },
+            _ => timer.after(job.delay(first)).await?,
+        }
+        first = false;
+
+        if job.radio && events::state().quiet {
+            log::info!("job {} held for quiet hours", job.name);
+            events::wait_until(|state| !state.quiet).await;
+        }
+        if cancelled.load(Ordering::Acquire) {
+            log::info!("job {} cancelled", job.name);
+            return Ok(());
+        }
+        if running.swap(true, Ordering::AcqRel) {
+            log::warn!("job {} still running, skipping this run", job.name);
+            continue;
+        }
+
+        let name = job.name;
+        let running = running.clone();
+        let future = (run.lock().unwrap())();
+        runtime::spawn(async move {
+            if let Err(err) = future.await {
+                log::warn!("job {name} failed: {err:#}");
+            }
+            running.store(false, Ordering::Release);
+        });
+    }
+}
+
+/// The next event `trigger` picks; never, once nothing publishes any more.
+async fn triggered(events: &mut broadcast::Receiver<Event>, trigger: fn(&Event) -> bool) -> Event {
+    loop {
+        match events.recv().await {
+            Ok(event) if trigger(&event) => return event,
+            Ok(_) | Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => return std::future::pending().await,
+        }
+    }
+}
+
+fn random_delay(max: Duration) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+
+    let random = device::random() as u64;
+    Duration::from_millis(random % (max.as_millis() as u64 + 1))
+}
@@ -0,0 +1,167 @@
+//! The words and dates the device shows people, in the language the build
+//! was made for: `lang-de`, `lang-fr` or `lang-es`, English without any.
+//! A pack is a `Pack` constant, so a build carries just its own. Dates
+//! are local time by `utc_offset`, in the order and with the separators
+//! the language writes them in.
+//!
+//! What's for people is the screen and the instructions the log gives
+//! them, like where to log in. The rest of the log stays English: it's
+//! read by whoever debugs the device, and one language keeps it
+//! searchable.
+
+use crate::config::Config;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+#[cfg(any(
+    all(feature = "lang-de", feature = "lang-fr"),
+    all(feature = "lang-de", feature = "lang-es"),
+    all(feature = "lang-fr", feature = "lang-es"),
+))]
+compile_error!("pick one of the lang-* features");
+
+/// How a day, month and year are written.
+// a build has one pack, and the pack one style
+#[allow(dead_code)]
+enum DateStyle {
+    /// `14-Oct-2026`, the month by name.
+    Named(char, [&'static str; 12]),
+    /// `14.10.2026`.
+    Numeric(char),
+}
+
+pub struct Pack {
+    /// ISO 639-1, for telemetry.
+    pub code: &'static str,
+    date: DateStyle,
+    pub no_wifi: &'static str,
+    /// A replayed body of unknown age.
+    pub old: &'static str,
+    /// Ages of a replayed body, `{}` the hours or minutes.
+    hours_old: &'static str,
+    minutes_old: &'static str,
+    /// Where to log in and the code to give there, `{}` each.
+    login: &'static str,
+    /// The same with the code in the address.
+    login_at: &'static str,
+}
+
+#[cfg(not(any(feature = "lang-de", feature = "lang-fr", feature = "lang-es")))]
+pub const PACK: Pack = Pack {
+    code: "en",
+    date: DateStyle::Named(
+        '-',
+        [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ],
+    ),
+    no_wifi: "no wifi",
+    old: "old",
+    hours_old: "{}h old",
+    minutes_old: "{}m old",
+    login: "open {} and enter {}",
+    login_at: "open {} to log in",
+};
+
+#[cfg(feature = "lang-de")]
+pub const PACK: Pack = Pack {
+    code: "de",
+    date: DateStyle::Numeric('.'),
+    no_wifi: "kein WLAN",
+    old: "alt",
+    hours_old: "vor {} Std.",
+    minutes_old: "vor {} Min.",
+    login: "{} öffnen und {} eingeben",
+    login_at: "{} öffnen, um sich anzumelden",
+};
+
+#[cfg(feature = "lang-fr")]
+pub const PACK: Pack = Pack {
+    code: "fr",
+    date: DateStyle::Numeric('/'),
+    no_wifi: "pas de wifi",
+    old: "ancien",
+    hours_old: "il y a {} h",
+    minutes_old: "il y a {} min",
+    login: "ouvrez {} et saisissez {}",
+    login_at: "ouvrez {} pour vous connecter",
+};
+
+#[cfg(feature = "lang-es")]
+pub const PACK: Pack = Pack {
+    code: "es",
+    date: DateStyle::Numeric('/'),
+    no_wifi: "sin wifi",
+    old: "antiguo",
+    hours_old: "hace {} h",
+    minutes_old: "hace {} min",
+    login: "abra {} e introduzca {}",
+    login_at: "abra {} para iniciar sesión",
+};
+
+/// `utc_offset` in seconds.
+static OFFSET: AtomicI64 = AtomicI64::new(0);
+
+/// Takes the offset local dates are shown at, UTC when it doesn't parse;
+/// `quiet` is the one to complain about it.
+pub fn configure(config: &Config) {
+    let minutes = crate::quiet::offset(&config.utc_offset).unwrap_or_default();
+    OFFSET.store(minutes * 60, Ordering::Relaxed);
+    crate::telemetry::set("language", PACK.code);
+}
+
+/// `template` with each `{}` replaced by the next of `values`.
+fn fill(template: &str, values: &[&dyn std::fmt::Display]) -> String {
+    let mut parts = template.split("{}");
+    let mut filled = String::from(parts.next().unwrap_or_default());
+    for (value, part) in values.iter().zip(parts) {
+        filled.push_str(&value.to_string());
+        filled.push_str(part);
+    }
+    filled
+}
+
+impl Pack {
+    /// How old a replayed body is, for `secs` of age.
+    pub fn age(&self, secs: u64) -> String {
+        if secs >= 3600 {
+            fill(self.hours_old, &[&(secs / 3600)])
+        } else {
+            fill(self.minutes_old, &[&(secs / 60)])
+        }
+    }
+
+    /// The login instruction: open `uri`, then enter `code` unless the uri
+    /// has it already.
+    // only the oauth login gives one
+    #[cfg_attr(not(feature = "oauth"), allow(dead_code))]
+    pub fn login(&self, uri: &str, code: Option<&str>) -> String {
+        match code {
+            Some(code) => fill(self.login, &[&uri, &code]),
+            None => fill(self.login_at, &[&uri]),
+        }
+    }
+}
+
+/// The local date and time now, as the language writes it.
+pub fn now() -> String {
+    let offset = time::Duration::seconds(OFFSET.load(Ordering::Relaxed));
+    let Some(now) = time::UtcDateTime::now().checked_add(offset) else {
+        return String::from("<invalid>");
+    };
+    let (day, year) = (now.day(), now.year());
+    let date = match &PACK.date {
+        DateStyle::Named(sep, months) => {
+            format!(
+                "{day:02}{sep}{}{sep}{year}",
+                months[now.month() as usize - 1]
+            )
+        }
+        DateStyle::Numeric(sep) => format!("{day:02}{sep}{:02}{sep}{year}", now.month() as u8),
+    };
+    format!(
+        "{date} {:02}:{:02}:{:02}",
+        now.hour(),
+        now.minute(),
+        now.second()
+    )
+}
@@ -0,0 +1,255 @@
+//! The latest log lines in a ring buffer, and `logtail on` to stream them
+//! to support without a serial cable: to the `logs` channel when MQTT is
+//! up and as text frames on the backend WebSocket. Streaming is rate
+//! limited and stops by itself, so a forgotten tail can't keep the radio
+//! busy. The status server's `/logs/stream` reads the ring on its own,
+//! see `read_from()`.
+
+use crate::{
+    console, device,
+    events::{self, Event},
+    runtime,
+};
+use anyhow::{bail, Result};
+use esp_idf_svc::log::EspLogger;
+use log::{Log, Metadata, Record};
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast;
+
+const COMMAND: &str = "logtail";
+/// Lines the ring buffer holds, what `logtail` shows.
+const CAPACITY: usize = 64;
+/// Longer lines are cut, so a hex dump can't take the buffer.
+const MAX_LINE: usize = 200;
+/// Lines go out in one batch this often...
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// ...of at most this many, the rest counted as dropped.
+const MAX_BATCH: usize = 20;
+/// How long `logtail on` streams without a duration.
+const DEFAULT_DURATION: Duration = Duration::from_secs(10 * 60);
+/// The longest tail one `on` asks for; a larger one gets this.
+const MAX_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+/// Targets logging on the way out, which would tail themselves.
+const QUIET_TARGETS: &[&str] = &["rumqttc", "tungstenite", "tokio_tungstenite"];
+
+static LOGGER: TailLogger = TailLogger {
+    esp: EspLogger::new(),
+    ring: Mutex::new(Ring {
+        lines: VecDeque::new(),
+        next: 0,
+    }),
+};
+
+struct TailLogger {
+    esp: EspLogger,
+    ring: Mutex<Ring>,
+}
+
+struct Ring {
+    lines: VecDeque<String>,
+    /// Sequence number of the next line, so a tail knows what it missed.
+    next: u64,
+}
+
+/// Installs the logger, in place of `EspLogger::initialize_default()`.
+pub fn init() {
+    log::set_logger(&LOGGER)
+        .map(|()| LOGGER.esp.initialize())
+        .unwrap();
+}
+
+/// The buffered lines, oldest first.
+pub fn recent() -> Vec<String> {
+    LOGGER.ring.lock().unwrap().lines.iter().cloned().collect()
+}
+
+/// The buffered lines numbered `from` on, and the number of the first of
+/// them: later than `from` when the ring moved past some.
+pub fn read_from(from: u64) -> (u64, Vec<String>) {
+    let ring = LOGGER.ring.lock().unwrap();
+    let first = ring.next - ring.lines.len() as u64;
+    let skip = from.saturating_sub(first) as usize;
+    (
+        first.max(from).min(ring.next),
+        ring.lines.iter().skip(skip).cloned().collect(),
+    )
+}
+
+/// The level of a buffered line, from the letter it starts with.
+pub fn level(line: &str) -> Option<log::Level> {
+    match line.as_bytes().first()? {
+        b'E' => Some(log::Level::Error),
+        b'W' => Some(log::Level::Warn),
+        b'I' => Some(log::Level::Info),
+        b'D' => Some(log::Level::Debug),
+        b'T' => Some(log::Level::Trace),
+        _ => None,
+    }
+}
+
+/// Batches of tailed lines, for the transports that carry them.
+pub fn subscribe() -> broadcast::Receiver<Arc<str>> {
+    batches().subscribe()
+}
+
+fn batches() -> &'static broadcast::Sender<Arc<str>> {
+    static BATCHES: OnceLock<broadcast::Sender<Arc<str>>> = OnceLock::new();
+    BATCHES.get_or_init(|| broadcast::channel(4).0)
+}
+
+impl Log for TailLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.esp.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.esp.log(record);
+        if !self.enabled(record.metadata())
+            || QUIET_TARGETS
+                .iter()
+                .any(|target| record.target().starts_with(target))
+        {
+            return;
+        }
+
+        let mut line = format!(
+            "{} ({}) {}: {}",
+            &record.level().as_str()[..1],
+            device::uptime().as_millis(),
+            record.target(),
+            record.args()
+        );
+        if line.len() > MAX_LINE {
+            let mut end = MAX_LINE;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            line.truncate(end);
+        }
+        let mut ring = self.ring.lock().unwrap();
+        if ring.lines.len() == CAPACITY {
+            ring.lines.pop_front();
+        }
+        ring.lines.push_back(line);
+        ring.next += 1;
+    }
+
+    fn flush(&self) {}
+}
+
+/// Registers the `logtail` command and the task that streams on it.
+pub fn start() {
+    console::register(console::Command {
+        name: COMMAND,
+        usage: "logtail [on [secs] | off]",
+        summary: "the recent log lines, or stream them over mqtt and the websocket",
+        run: command,
+    });
+
+    #[cfg(feature = "mqtt")]
+    runtime::spawn(async {
+        let topic = crate::mqtt::topic("logs");
+        let mut batches = subscribe();
+        loop {
+            let Ok(batch) = batches.recv().await else {
+                continue;
+            };
+            // without a session there's no one to tail to over mqtt
+            if let Ok(session) = crate::mqtt::session() {
+                let _ = session.publish(&topic, batch.as_bytes().to_vec());
+            }
+        }
+    });
+
+    runtime::spawn(async {
+        let mut events = events::subscribe();
+        let mut flush_timer = tokio::time::interval(FLUSH_INTERVAL);
+        let mut until: Option<Instant> = None;
+        let mut sent = 0;
+        loop {
+            tokio::select! {
+                event = events.recv() => {
+                    let Ok(Event::Command(command)) = event else {
+                        continue;
+                    };
+                    let mut words = command.split_whitespace();
+                    if words.next() != Some(COMMAND) {
+                        continue;
+                    }
+                    match (words.next(), words.next().and_then(|secs| secs.parse().ok())) {
+                        (Some("on"), secs) => {
+                            let duration = secs
+                                .map_or(DEFAULT_DURATION, Duration::from_secs)
+                                .min(MAX_DURATION);
+                            // the tail starts with the last batch's worth of the ring
+                            let next = LOGGER.ring.lock().unwrap().next;
+                            sent = next.saturating_sub(MAX_BATCH as u64);
+                            until = Some(Instant::now() + duration);
+                            log::info!("logtail on for {duration:?}");
+                        }
+                        (Some("off"), _) => {
+                            until = None;
+                            log::info!("logtail off");
+                        }
+                        _ => {}
+                    }
+                }
+                _ = flush_timer.tick() => {
+                    if until.is_some_and(|until| Instant::now() >= until) {
+                        until = None;
+                        log::info!("logtail stopped, its time ran out");
+                    }
+                    if until.is_some() {
+                        sent = flush(sent);
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Sends the lines after `sent` as one batch and returns where it got to.
+fn flush(sent: u64) -> u64 {
+    let mut batch = String::new();
+    let next = {
+        let ring = LOGGER.ring.lock().unwrap();
+        let first = ring.next - ring.lines.len() as u64;
+        let missed = first.saturating_sub(sent);
+        let skip = sent.saturating_sub(first) as usize;
+        let lines = ring.lines.len().saturating_sub(skip);
+        let dropped = missed + lines.saturating_sub(MAX_BATCH) as u64;
+        if dropped > 0 {
+            let _ = writeln!(batch, "... {dropped} lines dropped");
+        }
+        for line in ring
+            .lines
+            .iter()
+            .skip(skip)
+            .skip(lines.saturating_sub(MAX_BATCH))
+        {
+            let _ = writeln!(batch, "{line}");
+        }
+        ring.next
+    };
+    if !batch.is_empty() {
+        // no transport listening is fine, the lines stay in the ring
+        let _ = batches().send(Arc::from(batch));
+    }
+    next
+}
+
+fn command(_: &console::Console, args: &[&str]) -> Result<String> {
+    match args {
+        [] => Ok(recent().join("\n")),
+        ["on"] | ["on", _] | ["off"] => {
+            events::publish(Event::Command(format!("{COMMAND} {}", args.join(" "))));
+            Ok(format!("logtail {}", args[0]))
+        }
+        _ => bail!("usage: logtail [on [secs] | off]"),
+    }
+}
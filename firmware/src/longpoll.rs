@@ -0,0 +1,101 @@
+//! Commands over plain HTTP long polling, lighter than a WebSocket and
+//! through any proxy: `GET longpoll_url?wait=<secs>[&cursor=..]` is held by
+//! the server until it has commands, one per line of the body, or answers
+//! 204 once `wait` is up. It's re-issued straight away on the same pooled
+//! connection, and after an error with a backoff.
+//!
+//! A held request is an idle TCP connection as far as a NAT is concerned,
+//! and home routers and carriers drop those in a minute or two, so the
+//! client probes well inside that.
+
+use crate::{
+    config::Config,
+    error::{Failure, FirmwareError},
+    events::{self, Event},
+    http, runtime,
+};
+use anyhow::{anyhow, Result};
+use std::time::Duration;
+
+/// How long the server is asked to hold a request.
+const HOLD: Duration = Duration::from_secs(50);
+/// Past the hold, before a request counts as lost.
+const HOLD_SLACK: Duration = Duration::from_secs(15);
+const KEEPALIVE_IDLE: Duration = Duration::from_secs(25);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+const KEEPALIVE_RETRIES: u32 = 3;
+const RETRY_MIN_DELAY: Duration = Duration::from_secs(1);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+/// A server can hand back where it got to in this header, sent back as
+/// `cursor`, so no command is lost between two polls.
+const CURSOR_HEADER: &str = "X-Poll-Cursor";
+
+pub fn start(config: &Config) -> Result<()> {
+    let client = http::builder()?
+        .timeout(HOLD + HOLD_SLACK)
+        .tcp_keepalive(KEEPALIVE_IDLE)
+        .tcp_keepalive_interval(KEEPALIVE_INTERVAL)
+        .tcp_keepalive_retries(KEEPALIVE_RETRIES)
+        .build()?;
+    let url = config.longpoll_url.clone();
+
+    runtime::spawn(async move {
+        let mut cursor = None;
+        let mut delay = RETRY_MIN_DELAY;
+        loop {
+            events::wait_until(|state| state.net_up).await;
+            if url.starts_with("https://") {
+                events::wait_until(|state| state.time_synced).await;
+            }
+            match poll(&client, &url, &mut cursor).await {
+                Ok(()) => delay = RETRY_MIN_DELAY,
+                Err(err) => {
+                    if !FirmwareError::is_retryable(&err) {
+                        delay = RETRY_MAX_DELAY;
+                    }
+                    log::warn!("long poll of {url} failed, retrying in {delay:?}: {err:#}");
+                    runtime::sleep(delay).await;
+                    delay = (delay * 2).min(RETRY_MAX_DELAY);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn poll(client: &reqwest::Client, url: &str, cursor: &mut Option<String>) -> Result<()> {
+    let wait = HOLD.as_secs().to_string();
+    let mut request = client.get(url).query(&[("wait", wait.as_str())]);
+    if let Some(cursor) = cursor.as_deref() {
+        request = request.query(&[("cursor", cursor)]);
+    }
+
+    let request = request.build()?;
+    let host = request.url().host_str().unwrap_or_default().to_owned();
+    crate::ratelimit::acquire_for("longpoll", &host).await?;
+    let response = client.execute(request).await?;
+    crate::ratelimit::observe(&host, &http::Head::of(&response));
+    match response.status() {
+        reqwest::StatusCode::NO_CONTENT => return Ok(()),
+        status if !status.is_success() => {
+            let err = anyhow!("server answered {status}");
+            return Err(FirmwareError::Http(Failure::status(status.as_u16(), err)).into());
+        }
+        _ => {}
+    }
+    if let Some(next) = response
+        .headers()
+        .get(CURSOR_HEADER)
+        .and_then(|value| value.to_str().ok())
+    {
+        *cursor = Some(next.to_owned());
+    }
+
+    for command in response.text().await?.lines().map(str::trim) {
+        if !command.is_empty() {
+            log::info!("long poll command: {command}");
+            events::publish(Event::Command(command.to_owned()));
+        }
+    }
+    Ok(())
+}
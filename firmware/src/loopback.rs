@@ -0,0 +1,163 @@
+//! A TLS server on the device and the firmware's own client connecting to
+//! it over 127.0.0.1, so a handshake can be reproduced without WiFi, DNS
+//! or a remote server in the picture. Only built into debug firmware.
+//!
+//! The client is `tls::client_config()` with the loopback CA as its only
+//! root; the certificates are valid from 1970 so a run before the clock is
+//! set still verifies.
+
+use crate::{
+    console,
+    events::{self, Event},
+    metrics, telemetry, tls,
+};
+use anyhow::{bail, Context, Result};
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName},
+    version::{TLS12, TLS13},
+    SupportedProtocolVersion,
+};
+use std::{
+    net::Ipv4Addr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+const CA: &[u8] = include_bytes!("loopback/ca.der");
+const CERT: &[u8] = include_bytes!("loopback/cert.der");
+const KEY: &[u8] = include_bytes!("loopback/key.der");
+
+const COMMAND: &str = "tls-loopback";
+const PING: &[u8] = b"ping\n";
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Registers the `tls-loopback` command and runs the handshake once.
+pub fn start() {
+    console::register(console::Command {
+        name: COMMAND,
+        usage: "tls-loopback [tls12 | tls13]",
+        summary: "tls handshake against a server on the device, optionally pinned to one version",
+        run: command,
+    });
+
+    crate::runtime::spawn(async {
+        report(run(None).await);
+        let mut events = events::subscribe();
+        loop {
+            let Ok(Event::Command(command)) = events.recv().await else {
+                continue;
+            };
+            let mut words = command.split_whitespace();
+            if words.next() != Some(COMMAND) {
+                continue;
+            }
+            let version = match words.next() {
+                Some("tls12") => Some(&TLS12),
+                Some(_) => Some(&TLS13),
+                None => None,
+            };
+            report(run(version).await);
+        }
+    });
+}
+
+fn command(_: &console::Console, args: &[&str]) -> Result<String> {
+    match args {
+        [] => events::publish(Event::Command(String::from(COMMAND))),
+        [version @ ("tls12" | "tls13")] => {
+            events::publish(Event::Command(format!("{COMMAND} {version}")))
+        }
+        _ => bail!("usage: tls-loopback [tls12 | tls13]"),
+    }
+    Ok(String::from(
+        "loopback handshake requested, the result is logged",
+    ))
+}
+
+fn report(result: Result<String>) {
+    match result {
+        Ok(summary) => {
+            log::info!("loopback tls: {summary}");
+            telemetry::set("loopback_tls", summary);
+        }
+        Err(err) => {
+            log::warn!("loopback tls failed: {err:#}");
+            telemetry::set("loopback_tls", format!("{err:#}"));
+        }
+    }
+}
+
+/// One handshake and ping against a fresh server that only offers
+/// `version`, or every version rustls defaults to.
+async fn run(version: Option<&'static SupportedProtocolVersion>) -> Result<String> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let addr = listener.local_addr()?;
+    let acceptor = TlsAcceptor::from(Arc::new(server_config(version)?));
+    let server = async {
+        let (stream, _) = listener.accept().await?;
+        let mut stream = acceptor.accept(stream).await.context("server handshake")?;
+        let mut line = [0; PING.len()];
+        stream.read_exact(&mut line).await?;
+        stream.write_all(&line).await?;
+        stream.shutdown().await?;
+        anyhow::Ok(())
+    };
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(CertificateDer::from(CA))?;
+    let connector = TlsConnector::from(Arc::new(tls::client_config_with(roots)));
+    let client = async {
+        let stream = TcpStream::connect(addr).await?;
+        let start = Instant::now();
+        let mut stream = connector
+            .connect(ServerName::from(addr.ip()), stream)
+            .await
+            .context("client handshake")?;
+        let handshake = start.elapsed();
+        metrics::TLS_HANDSHAKE.observe(&[("client", "loopback"), ("host", "loopback")], handshake);
+
+        stream.write_all(PING).await?;
+        let mut echo = [0; PING.len()];
+        stream.read_exact(&mut echo).await?;
+        if echo != PING {
+            bail!("echo came back as {echo:?}");
+        }
+
+        let (_, session) = stream.get_ref();
+        Ok(format!(
+            "{:?} {:?} handshake {handshake:?}, heap free {}",
+            session.protocol_version().context("no protocol version")?,
+            session
+                .negotiated_cipher_suite()
+                .context("no cipher suite")?
+                .suite(),
+            crate::heap::free(),
+        ))
+    };
+
+    let (_, summary) = tokio::time::timeout(TIMEOUT, async { tokio::try_join!(server, client) })
+        .await
+        .context("loopback handshake timed out")??;
+    Ok(summary)
+}
+
+fn server_config(
+    version: Option<&'static SupportedProtocolVersion>,
+) -> Result<rustls::ServerConfig> {
+    let builder = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ));
+    let builder = match version {
+        Some(version) => builder.with_protocol_versions(&[version])?,
+        None => builder.with_safe_default_protocol_versions()?,
+    };
+    Ok(builder.with_no_client_auth().with_single_cert(
+        vec![CertificateDer::from(CERT)],
+        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(KEY)),
+    )?)
+}
@@ -0,0 +1,402 @@
+use crate::{
+    coap,
+    config::Config,
+    dns,
+    events::{self, Event},
+    runtime,
+};
+use anyhow::{bail, Context, Result};
+use coap_lite::{
+    CoapOption, ContentFormat, MessageClass, MessageType, Packet, RequestType, ResponseType,
+};
+use objects::{Action, Error, Objects};
+use std::time::Duration;
+use tokio::{net::UdpSocket, time::timeout};
+
+mod objects;
+mod tlv;
+
+/// How long a bootstrap server gets to write the config and send
+/// Bootstrap-Finish.
+const BOOTSTRAP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// OMA LwM2M 1.0 client over plain CoAP, NoSec only since there is no
+/// DTLS in the build. Registers the Server, Device and Firmware Update
+/// objects, optionally after a bootstrap, and serves Read, Write and
+/// Execute from the management server on the same socket. Reboot and
+/// Firmware Update need DTLS, so they're refused for now.
+pub fn start(config: &Config) {
+    let settings = (
+        config.lwm2m_server.clone(),
+        config.lwm2m_bootstrap.clone(),
+        config.device_name().to_owned(),
+    );
+    runtime::spawn_named("lwm2m", move || {
+        let (server, bootstrap, endpoint) = settings.clone();
+        async move { session(server, bootstrap, endpoint).await }
+    });
+}
+
+async fn session(server: String, bootstrap: String, endpoint: String) -> Result<()> {
+    events::wait_until(|state| state.net_up).await;
+    // plain CoAP, so the Reboot and Firmware Update executes are refused
+    let mut objects = Objects::new(false);
+
+    let server = if bootstrap.is_empty() {
+        server
+    } else {
+        let mut link = Link::connect(&bootstrap).await?;
+        link.bootstrap(&mut objects, &endpoint).await?;
+        let server = objects
+            .bootstrapped_server()
+            .context("bootstrap handed out no management server")?
+            .to_owned();
+        log::info!("lwm2m bootstrapped, management server {server}");
+        server
+    };
+
+    let mut link = Link::connect(&server).await?;
+    let location = link.register(&mut objects, &endpoint).await?;
+    log::info!("lwm2m registered with {server} at /{}", location.join("/"));
+
+    loop {
+        // update well inside the lifetime so one lost update doesn't expire us
+        let interval = Duration::from_secs(u64::from(objects.lifetime / 2).max(1));
+        let packet = tokio::select! {
+            packet = link.recv() => Some(packet?),
+            _ = runtime::sleep(interval) => None,
+        };
+        let action = match packet {
+            Some(packet) => link.serve(&mut objects, &packet).await?,
+            None => Action::RegistrationUpdate,
+        };
+
+        match action {
+            Action::None => {}
+            Action::RegistrationUpdate => {
+                let mut request = link.request(RequestType::Post, &location);
+                request.add_option(
+                    CoapOption::UriQuery,
+                    format!("lt={}", objects.lifetime).into(),
+                );
+                coap::check(&link.exchange(&mut objects, request).await?)
+                    .context("lwm2m registration update rejected")?;
+            }
+            Action::Reboot => {
+                log::warn!("lwm2m server requested a reboot");
+                esp_idf_hal::reset::restart();
+            }
+            Action::FirmwareUpdate => {
+                log::info!("lwm2m firmware update from {}", objects.package_uri);
+                events::publish(Event::OtaPending);
+            }
+        }
+    }
+}
+
+/// One UDP association with an LwM2M server, acting as CoAP client for
+/// our own requests and as CoAP server for the server's.
+struct Link {
+    socket: UdpSocket,
+    message_id: u16,
+    token: u32,
+}
+
+impl Link {
+    async fn connect(url: &str) -> Result<Self> {
+        let (host, port, _) = coap::parse_url(url)?;
+        let addr = dns::resolve_addrs(host, port)
+            .await?
+            .into_iter()
+            .find(|addr| addr.is_ipv4())
+            .with_context(|| format!("{host} has no ipv4 address"))?;
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(addr).await?;
+
+        let seed = crate::device::random();
+        Ok(Self {
+            socket,
+            message_id: seed as u16,
+            token: seed.rotate_left(16),
+        })
+    }
+
+    /// Bootstrap-Request, then serves the bootstrap server's writes until
+    /// it sends Bootstrap-Finish.
+    async fn bootstrap(&mut self, objects: &mut Objects, endpoint: &str) -> Result<()> {
+        let mut request = self.request(RequestType::Post, &["bs".into()]);
+        request.add_option(CoapOption::UriQuery, format!("ep={endpoint}").into());
+        coap::check(&self.exchange(objects, request).await?)
+            .context("lwm2m bootstrap request rejected")?;
+
+        timeout(BOOTSTRAP_TIMEOUT, async {
+            loop {
+                let packet = self.recv().await?;
+                let finished = is_bootstrap_finish(&packet);
+                self.serve(objects, &packet).await?;
+                if finished {
+                    return anyhow::Ok(());
+                }
+            }
+        })
+        .await
+        .context("lwm2m bootstrap didn't finish")?
+    }
+
+    /// Registers and returns the registration's location path.
+    async fn register(&mut self, objects: &mut Objects, endpoint: &str) -> Result<Vec<String>> {
+        let mut request = self.request(RequestType::Post, &["rd".into()]);
+        for query in [
+            format!("ep={endpoint}"),
+            format!("lt={}", objects.lifetime),
+            String::from("lwm2m=1.0"),
+            String::from("b=U"),
+        ] {
+            request.add_option(CoapOption::UriQuery, query.into());
+        }
+        request.set_content_format(ContentFormat::ApplicationLinkFormat);
+        request.payload = objects::REGISTERED
+            .iter()
+            .map(|(object, instance)| format!("</{object}/{instance}>"))
+            .collect::<Vec<_>>()
+            .join(",")
+            .into_bytes();
+
+        let response = self.exchange(objects, request).await?;
+        coap::check(&response).context("lwm2m registration rejected")?;
+        let location: Vec<String> = response
+            .get_option(CoapOption::LocationPath)
+            .into_iter()
+            .flatten()
+            .map(|segment| String::from_utf8_lossy(segment).into_owned())
+            .collect();
+        if location.is_empty() {
+            bail!("lwm2m registration without a location");
+        }
+        Ok(location)
+    }
+
+    fn request(&mut self, method: RequestType, path: &[String]) -> Packet {
+        self.message_id = self.message_id.wrapping_add(1);
+        self.token = self.token.wrapping_add(1);
+
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        packet.header.code = MessageClass::Request(method);
+        packet.header.message_id = self.message_id;
+        packet.set_token(self.token.to_be_bytes().to_vec());
+        for segment in path {
+            packet.add_option(CoapOption::UriPath, segment.clone().into_bytes());
+        }
+        packet
+    }
+
+    async fn recv(&self) -> Result<Packet> {
+        let mut buf = vec![0; coap::MAX_DATAGRAM];
+        loop {
+            let len = self.socket.recv(&mut buf).await?;
+            if let Ok(packet) = Packet::from_bytes(&buf[..len]) {
+                return Ok(packet);
+            }
+        }
+    }
+
+    /// Sends a confirmable request and waits for its response like
+    /// `coap::Client`, answering whatever the server asks in the meantime.
+    async fn exchange(&mut self, objects: &mut Objects, request: Packet) -> Result<Packet> {
+        let bytes = request.to_bytes()?;
+        let mut wait = coap::ACK_TIMEOUT;
+        let mut acked = false;
+
+        for _ in 0..=coap::MAX_RETRANSMIT {
+            if !acked {
+                self.socket.send(&bytes).await?;
+            }
+
+            let deadline = tokio::time::Instant::now() + wait;
+            while let Ok(packet) = timeout(
+                deadline.saturating_duration_since(tokio::time::Instant::now()),
+                self.recv(),
+            )
+            .await
+            {
+                let packet = packet?;
+                match packet.header.code {
+                    MessageClass::Request(_) => {
+                        // actions wait until this exchange is done, it's
+                        // only ever a registration
+                        self.serve(objects, &packet).await?;
+                    }
+                    MessageClass::Empty
+                        if packet.header.message_id == request.header.message_id =>
+                    {
+                        match packet.header.get_type() {
+                            MessageType::Reset => bail!("lwm2m request reset by server"),
+                            _ => acked = true,
+                        }
+                    }
+                    MessageClass::Response(_) if packet.get_token() == request.get_token() => {
+                        if packet.header.get_type() == MessageType::Confirmable {
+                            self.socket.send(&coap::ack(&packet).to_bytes()?).await?;
+                        }
+                        return Ok(packet);
+                    }
+                    _ => {}
+                }
+            }
+
+            wait *= 2;
+        }
+
+        bail!("lwm2m request timed out")
+    }
+
+    /// Answers one request from the server with a piggybacked response.
+    async fn serve(&mut self, objects: &mut Objects, request: &Packet) -> Result<Action> {
+        let MessageClass::Request(method) = request.header.code else {
+            return Ok(Action::None);
+        };
+        let path: Vec<String> = request
+            .get_option(CoapOption::UriPath)
+            .into_iter()
+            .flatten()
+            .map(|segment| String::from_utf8_lossy(segment).into_owned())
+            .collect();
+
+        let mut response = Packet::new();
+        response.header.set_type(match request.header.get_type() {
+            MessageType::Confirmable => MessageType::Acknowledgement,
+            _ => MessageType::NonConfirmable,
+        });
+        response.header.message_id = request.header.message_id;
+        response.set_token(request.get_token().to_vec());
+
+        let (code, action) = match handle(objects, method, &path, request, &mut response) {
+            Ok((code, action)) => (code, action),
+            Err(Error::NotFound) => (ResponseType::NotFound, Action::None),
+            Err(Error::MethodNotAllowed) => (ResponseType::MethodNotAllowed, Action::None),
+            Err(Error::BadRequest) => (ResponseType::BadRequest, Action::None),
+            Err(Error::Unauthorized) => (ResponseType::Unauthorized, Action::None),
+        };
+        log::debug!("lwm2m {method:?} /{} -> {code:?}", path.join("/"));
+        response.header.code = MessageClass::Response(code);
+        self.socket.send(&response.to_bytes()?).await?;
+        Ok(action)
+    }
+}
+
+fn handle(
+    objects: &mut Objects,
+    method: RequestType,
+    path: &[String],
+    request: &Packet,
+    response: &mut Packet,
+) -> Result<(ResponseType, Action), Error> {
+    if path == ["bs"] && method == RequestType::Post {
+        return Ok((ResponseType::Changed, Action::None));
+    }
+    let ids = path
+        .iter()
+        .map(|id| id.parse::<u16>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| Error::NotFound)?;
+    let tlv = request.get_content_format() == Some(ContentFormat::ApplicationVndOmaLwm2mTlv);
+
+    match (method, ids.as_slice()) {
+        (RequestType::Get, [object, instance, resource]) => {
+            let value = objects.read(*object, *instance, *resource)?;
+            response.set_content_format(ContentFormat::TextPlain);
+            response.payload = match value {
+                objects::Value::Str(text) => text.into_bytes(),
+                objects::Value::Int(int) => int.to_string().into_bytes(),
+                objects::Value::Bool(flag) => u8::from(flag).to_string().into_bytes(),
+            };
+            Ok((ResponseType::Content, Action::None))
+        }
+        (RequestType::Get, [object, instance]) => {
+            response.set_content_format(ContentFormat::ApplicationVndOmaLwm2mTlv);
+            response.payload = read_instance(objects, *object, *instance)?;
+            Ok((ResponseType::Content, Action::None))
+        }
+        (RequestType::Get, [object]) => {
+            let instance = read_instance(objects, *object, 0)?;
+            let mut payload = Vec::new();
+            tlv::encode(tlv::Kind::ObjectInstance, 0, &instance, &mut payload);
+            response.set_content_format(ContentFormat::ApplicationVndOmaLwm2mTlv);
+            response.payload = payload;
+            Ok((ResponseType::Content, Action::None))
+        }
+        (RequestType::Put | RequestType::Post, [object, instance, resource]) if !tlv => {
+            if method == RequestType::Post {
+                return Ok((
+                    ResponseType::Changed,
+                    objects.execute(*object, *instance, *resource)?,
+                ));
+            }
+            objects.write(*object, *instance, *resource, &request.payload, false)?;
+            Ok((ResponseType::Changed, Action::None))
+        }
+        (RequestType::Put | RequestType::Post, [object, rest @ ..]) if tlv => {
+            write_tlv(objects, *object, rest, &request.payload)?;
+            Ok((ResponseType::Changed, Action::None))
+        }
+        (RequestType::Delete, ids) => {
+            objects.delete(ids.first().copied(), ids.get(1).copied());
+            Ok((ResponseType::Deleted, Action::None))
+        }
+        _ => Err(Error::MethodNotAllowed),
+    }
+}
+
+fn read_instance(objects: &Objects, object: u16, instance: u16) -> Result<Vec<u8>, Error> {
+    let resources = Objects::resources(object);
+    if resources.is_empty() {
+        return Err(Error::NotFound);
+    }
+    let mut payload = Vec::new();
+    for resource in resources {
+        let value = objects.read(object, instance, *resource)?;
+        tlv::encode(
+            tlv::Kind::Resource,
+            *resource,
+            &tlv::value(&value),
+            &mut payload,
+        );
+    }
+    Ok(payload)
+}
+
+/// TLV writes address an object (with instances inside), an instance or a
+/// single resource.
+fn write_tlv(
+    objects: &mut Objects,
+    object: u16,
+    path: &[u16],
+    payload: &[u8],
+) -> Result<(), Error> {
+    let entries = tlv::decode(payload).ok_or(Error::BadRequest)?;
+    for entry in entries {
+        match (path, entry.kind) {
+            ([], tlv::Kind::ObjectInstance) => {
+                write_tlv(objects, object, &[entry.id], entry.value)?
+            }
+            ([instance], tlv::Kind::Resource) => {
+                objects.write(object, *instance, entry.id, entry.value, true)?
+            }
+            ([instance, resource], tlv::Kind::Resource) if entry.id == *resource => {
+                objects.write(object, *instance, *resource, entry.value, true)?
+            }
+            // multiple-instance resources aren't used by these objects
+            (_, tlv::Kind::MultipleResource | tlv::Kind::ResourceInstance) => {}
+            _ => return Err(Error::BadRequest),
+        }
+    }
+    Ok(())
+}
+
+fn is_bootstrap_finish(packet: &Packet) -> bool {
+    packet.header.code == MessageClass::Request(RequestType::Post)
+        && packet.get_option(CoapOption::UriPath).is_some_and(|path| {
+            path.len() == 1 && path.front().is_some_and(|segment| segment == b"bs")
+        })
+}
@@ -0,0 +1,201 @@
+use crate::{chip, device, heap};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const SECURITY: u16 = 0;
+pub const SERVER: u16 = 1;
+pub const DEVICE: u16 = 3;
+pub const FIRMWARE: u16 = 5;
+
+/// Objects announced on registration; Security is never reported.
+pub const REGISTERED: &[(u16, u16)] = &[(SERVER, 0), (DEVICE, 0), (FIRMWARE, 0)];
+
+const DEFAULT_LIFETIME: u32 = 300;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+}
+
+/// What the session has to do after answering an Execute.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    None,
+    Reboot,
+    RegistrationUpdate,
+    FirmwareUpdate,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    NotFound,
+    MethodNotAllowed,
+    BadRequest,
+    /// Needs a server that authenticated itself, over DTLS.
+    Unauthorized,
+}
+
+/// Firmware Update object states, LwM2M 1.0 §E.6.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FirmwareState {
+    Idle = 0,
+    Downloading = 1,
+}
+
+/// The device's LwM2M object tree: Server, Device and Firmware Update,
+/// plus the Security entries a bootstrap server writes.
+pub struct Objects {
+    pub short_server_id: i64,
+    pub lifetime: u32,
+    pub package_uri: String,
+    firmware_state: FirmwareState,
+    /// Server URIs by Security instance, with their bootstrap flag.
+    pub security: Vec<(u16, String, bool)>,
+    /// Whether the link is DTLS; a NoSec server can't reboot or reflash.
+    secure: bool,
+}
+
+impl Objects {
+    pub fn new(secure: bool) -> Self {
+        Self {
+            short_server_id: 1,
+            lifetime: DEFAULT_LIFETIME,
+            package_uri: String::new(),
+            firmware_state: FirmwareState::Idle,
+            security: Vec::new(),
+            secure,
+        }
+    }
+
+    /// Resource ids an instance exposes for reads, in order.
+    pub fn resources(object: u16) -> &'static [u16] {
+        match object {
+            SERVER => &[0, 1, 6, 7],
+            DEVICE => &[0, 1, 2, 3, 10, 11, 13, 16],
+            FIRMWARE => &[1, 3, 5, 9],
+            _ => &[],
+        }
+    }
+
+    pub fn read(&self, object: u16, instance: u16, resource: u16) -> Result<Value, Error> {
+        if instance != 0 {
+            return Err(Error::NotFound);
+        }
+        Ok(match (object, resource) {
+            (SERVER, 0) => Value::Int(self.short_server_id),
+            (SERVER, 1) => Value::Int(self.lifetime.into()),
+            (SERVER, 6) => Value::Bool(false),
+            (SERVER, 7) => Value::Str("U".into()),
+            (DEVICE, 0) => Value::Str("Espressif".into()),
+            (DEVICE, 1) => Value::Str(chip::CHIP.name.into()),
+            (DEVICE, 2) => Value::Str(device::id().into()),
+            (DEVICE, 3) => Value::Str(device::firmware_version().into()),
+            (DEVICE, 10) => Value::Int((heap::free() / 1024) as i64),
+            (DEVICE, 11) => Value::Int(0),
+            (DEVICE, 13) => Value::Int(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |now| now.as_secs() as i64),
+            ),
+            (DEVICE, 16) => Value::Str("U".into()),
+            (FIRMWARE, 1) => Value::Str(self.package_uri.clone()),
+            (FIRMWARE, 3) => Value::Int(self.firmware_state as i64),
+            (FIRMWARE, 5) => Value::Int(0),
+            // pull only, the device downloads the package itself
+            (FIRMWARE, 9) => Value::Int(0),
+            _ => return Err(Error::NotFound),
+        })
+    }
+
+    /// Writes one resource; `payload` is the raw value in whichever format
+    /// it arrived, both plain text and TLV values are byte strings here.
+    pub fn write(
+        &mut self,
+        object: u16,
+        instance: u16,
+        resource: u16,
+        payload: &[u8],
+        tlv: bool,
+    ) -> Result<(), Error> {
+        let text = || String::from_utf8(payload.to_vec()).map_err(|_| Error::BadRequest);
+        let int = || -> Result<i64, Error> {
+            if tlv {
+                super::tlv::int(payload).ok_or(Error::BadRequest)
+            } else {
+                text()?.trim().parse().map_err(|_| Error::BadRequest)
+            }
+        };
+        let bool = || -> Result<bool, Error> {
+            match payload {
+                [0] | b"0" => Ok(false),
+                [1] | b"1" => Ok(true),
+                _ => Err(Error::BadRequest),
+            }
+        };
+
+        match (object, resource) {
+            (SECURITY, 0) => self.security_entry(instance).1 = text()?,
+            (SECURITY, 1) => self.security_entry(instance).2 = bool()?,
+            (SECURITY, _) => {}
+            (SERVER, 0) => self.short_server_id = int()?,
+            (SERVER, 1) => {
+                self.lifetime = u32::try_from(int()?).map_err(|_| Error::BadRequest)?;
+            }
+            (SERVER, 6 | 7) => {}
+            (FIRMWARE, 1) if instance == 0 => self.package_uri = text()?,
+            (SERVER | DEVICE | FIRMWARE, _) => return Err(Error::MethodNotAllowed),
+            _ => return Err(Error::NotFound),
+        }
+        Ok(())
+    }
+
+    pub fn execute(&mut self, object: u16, instance: u16, resource: u16) -> Result<Action, Error> {
+        if instance != 0 {
+            return Err(Error::NotFound);
+        }
+        // anyone on the path can send NoSec requests
+        if matches!((object, resource), (DEVICE, 4) | (FIRMWARE, 2)) && !self.secure {
+            return Err(Error::Unauthorized);
+        }
+        match (object, resource) {
+            (DEVICE, 4) => Ok(Action::Reboot),
+            (SERVER, 8) => Ok(Action::RegistrationUpdate),
+            (FIRMWARE, 2) if self.package_uri.is_empty() => Err(Error::BadRequest),
+            (FIRMWARE, 2) => {
+                self.firmware_state = FirmwareState::Downloading;
+                Ok(Action::FirmwareUpdate)
+            }
+            (SERVER | DEVICE | FIRMWARE, _) => Err(Error::MethodNotAllowed),
+            _ => Err(Error::NotFound),
+        }
+    }
+
+    /// Drops whatever a bootstrap server deletes; only Security entries are
+    /// ever created at runtime.
+    pub fn delete(&mut self, object: Option<u16>, instance: Option<u16>) {
+        if matches!(object, None | Some(SECURITY)) {
+            self.security
+                .retain(|(id, ..)| instance.is_some_and(|instance| instance != *id));
+        }
+    }
+
+    /// The management server a bootstrap handed out, if any.
+    pub fn bootstrapped_server(&self) -> Option<&str> {
+        self.security
+            .iter()
+            .find(|(_, uri, bootstrap)| !bootstrap && !uri.is_empty())
+            .map(|(_, uri, _)| uri.as_str())
+    }
+
+    fn security_entry(&mut self, instance: u16) -> &mut (u16, String, bool) {
+        let index = match self.security.iter().position(|(id, ..)| *id == instance) {
+            Some(index) => index,
+            None => {
+                self.security.push((instance, String::new(), false));
+                self.security.len() - 1
+            }
+        };
+        &mut self.security[index]
+    }
+}
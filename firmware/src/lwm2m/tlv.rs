@@ -0,0 +1,116 @@
+//! OMA LwM2M TLV, content format 11542.
+
+use super::objects::Value;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    ObjectInstance = 0b00,
+    ResourceInstance = 0b01,
+    MultipleResource = 0b10,
+    Resource = 0b11,
+}
+
+pub struct Entry<'a> {
+    pub kind: Kind,
+    pub id: u16,
+    pub value: &'a [u8],
+}
+
+/// Splits one level of TLV entries, `None` if the payload is malformed.
+pub fn decode(mut bytes: &[u8]) -> Option<Vec<Entry<'_>>> {
+    let mut entries = Vec::new();
+    while let Some((&kind, rest)) = bytes.split_first() {
+        let (id, rest) = if kind & 0x20 != 0 {
+            (
+                u16::from_be_bytes([*rest.first()?, *rest.get(1)?]),
+                rest.get(2..)?,
+            )
+        } else {
+            (u16::from(*rest.first()?), rest.get(1..)?)
+        };
+        let (len, rest) = match (kind >> 3) & 0b11 {
+            0 => (usize::from(kind & 0b111), rest),
+            width => {
+                let width = usize::from(width);
+                let len = rest
+                    .get(..width)?
+                    .iter()
+                    .fold(0, |len, byte| len << 8 | usize::from(*byte));
+                (len, &rest[width..])
+            }
+        };
+
+        entries.push(Entry {
+            kind: match kind >> 6 {
+                0b00 => Kind::ObjectInstance,
+                0b01 => Kind::ResourceInstance,
+                0b10 => Kind::MultipleResource,
+                _ => Kind::Resource,
+            },
+            id,
+            value: rest.get(..len)?,
+        });
+        bytes = &rest[len..];
+    }
+    Some(entries)
+}
+
+/// Big-endian two's complement integer of 1, 2, 4 or 8 bytes.
+pub fn int(bytes: &[u8]) -> Option<i64> {
+    Some(match bytes.len() {
+        1 => i64::from(bytes[0] as i8),
+        2 => i64::from(i16::from_be_bytes(bytes.try_into().ok()?)),
+        4 => i64::from(i32::from_be_bytes(bytes.try_into().ok()?)),
+        8 => i64::from_be_bytes(bytes.try_into().ok()?),
+        _ => return None,
+    })
+}
+
+pub fn encode(kind: Kind, id: u16, value: &[u8], out: &mut Vec<u8>) {
+    let mut header = (kind as u8) << 6;
+    if id > 0xff {
+        header |= 0x20;
+    }
+    let len = value.len();
+    let length_bytes: &[u8] = match len {
+        0..=7 => {
+            header |= len as u8;
+            &[]
+        }
+        8..=0xff => {
+            header |= 0b01 << 3;
+            &(len as u32).to_be_bytes()[3..]
+        }
+        0x100..=0xffff => {
+            header |= 0b10 << 3;
+            &(len as u32).to_be_bytes()[2..]
+        }
+        _ => {
+            header |= 0b11 << 3;
+            &(len as u32).to_be_bytes()[1..]
+        }
+    };
+
+    out.push(header);
+    if id > 0xff {
+        out.extend_from_slice(&id.to_be_bytes());
+    } else {
+        out.push(id as u8);
+    }
+    out.extend_from_slice(length_bytes);
+    out.extend_from_slice(value);
+}
+
+/// The TLV value bytes of a resource.
+pub fn value(value: &Value) -> Vec<u8> {
+    match value {
+        Value::Str(text) => text.as_bytes().to_vec(),
+        Value::Bool(flag) => vec![u8::from(*flag)],
+        Value::Int(int) => match *int {
+            int if i8::try_from(int).is_ok() => (int as i8).to_be_bytes().to_vec(),
+            int if i16::try_from(int).is_ok() => (int as i16).to_be_bytes().to_vec(),
+            int if i32::try_from(int).is_ok() => (int as i32).to_be_bytes().to_vec(),
+            int => int.to_be_bytes().to_vec(),
+        },
+    }
+}
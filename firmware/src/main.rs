@@ -1,107 +1,990 @@
 use anyhow::{Context, Result};
+#[cfg(feature = "wifi")]
+use esp_idf_svc::wifi::{AsyncWifi, EspWifi};
 use esp_idf_svc::{
-    eventloop::EspSystemEventLoop, nvs::EspDefaultNvsPartition, timer::EspTimerService,
-    wifi::EspWifi,
+    eventloop::EspSystemEventLoop,
+    nvs::EspDefaultNvsPartition,
+    timer::{EspTaskTimerService, EspTimerService},
 };
-use std::default::Default;
+use events::Event;
+use jobs::{Job, Scheduler};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
 
-const WIFI_SSID: &str = include_str!("../config_ssid.txt");
-const WIFI_PASSWORD: &str = include_str!("../config_password.txt");
-const NTP_SERVER: &str = "pool.ntp.org";
-const DOWNLOAD_URL: &str = "http://example.com";
+mod abtest;
+#[cfg(feature = "ambient")]
+mod ambient;
+#[cfg(feature = "atecc608")]
+mod atecc608;
+#[cfg(feature = "backup")]
+mod backup;
+#[cfg(feature = "battery")]
+mod battery;
+#[cfg(feature = "beacon")]
+mod beacon;
+#[cfg(feature = "bench")]
+mod bench;
+#[cfg(feature = "ble")]
+mod ble;
+mod board;
+mod budget;
+mod buildinfo;
+#[cfg(any(
+    feature = "sensors",
+    feature = "rtc",
+    feature = "atecc608",
+    feature = "display"
+))]
+mod bus;
+#[cfg(feature = "button")]
+mod button;
+#[cfg(feature = "buzzer")]
+mod buzzer;
+mod cache;
+#[cfg(feature = "cellular")]
+mod cellular;
+mod chip;
+mod clock;
+#[cfg(any(
+    feature = "cloud",
+    feature = "oauth",
+    feature = "aws",
+    feature = "azure",
+    feature = "cloud-https"
+))]
+mod cloud;
+#[cfg(feature = "coap")]
+mod coap;
+#[cfg(any(feature = "mqtt", feature = "cloud-https"))]
+mod codec;
+mod config;
+mod console;
+#[cfg(feature = "datalog")]
+mod datalog;
+mod device;
+#[cfg(debug_assertions)]
+mod diag;
+#[cfg(feature = "display")]
+mod display;
+mod dns;
+mod error;
+#[cfg(feature = "wifi")]
+mod espnow;
+#[cfg(any(feature = "eth", feature = "qemu"))]
+mod eth;
+mod events;
+#[cfg(feature = "factory")]
+mod factory;
+mod failover;
+#[cfg(feature = "faults")]
+mod faults;
+mod flags;
+mod fs;
+#[cfg(feature = "geolocation")]
+mod geolocation;
+#[cfg(feature = "gps")]
+mod gps;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod heap;
+mod http;
+#[cfg(any(feature = "sensors", feature = "rtc", feature = "atecc608"))]
+mod i2c;
+mod identity;
+#[cfg(feature = "incident")]
+mod incident;
+#[cfg(feature = "indicator")]
+mod indicator;
+mod jobs;
+// the status screen shows most of it
+#[cfg_attr(not(feature = "display"), allow(dead_code))]
+mod locale;
+mod logtail;
+#[cfg(feature = "longpoll")]
+mod longpoll;
+#[cfg(all(debug_assertions, feature = "tokio-rt"))]
+mod loopback;
+#[cfg(feature = "lwm2m")]
+mod lwm2m;
+mod mdns;
+mod metrics;
+#[cfg(feature = "modbus")]
+mod modbus;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod net;
+// nothing downloads an image for it yet, `OtaPending` only announces one
+#[allow(dead_code)]
+mod ota;
+#[cfg(feature = "pcap")]
+mod pcap;
+mod pipeline;
+mod poller;
+mod power;
+mod preflight;
+#[cfg(feature = "quic")]
+mod quic;
+mod quiet;
+mod ratelimit;
+mod reboot;
+mod reload;
+#[cfg(feature = "remote-config")]
+mod remote_config;
+mod replay;
+#[cfg(feature = "rtc")]
+mod rtc;
+mod runtime;
+mod secret;
+mod security;
+mod selftest;
+#[cfg(feature = "sensors")]
+mod sensors;
+mod server;
+mod shed;
+#[cfg(feature = "sse")]
+mod sse;
+mod startup;
+mod telemetry;
+#[cfg(esp_idf_soc_temp_sensor_supported)]
+mod thermal;
+mod tls;
+#[cfg(feature = "tofu")]
+mod tofu;
+mod warmup;
+#[cfg(feature = "wireguard")]
+mod wireguard;
+#[cfg(feature = "ws")]
+mod ws;
+
+/// Without one the clock only survives deep sleep, never set at power on.
+const HAS_TIME_SOURCE: bool = cfg!(any(feature = "sntp", feature = "rtc", feature = "gps"));
+const LOW_HEAP_THRESHOLD: usize = 32 * 1024;
+const HEAP_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+#[cfg(feature = "wifi")]
+const NET_WATCHDOG_INTERVAL: Duration = Duration::from_secs(30);
+#[cfg(all(feature = "light-sleep", debug_assertions))]
+const SLEEP_STATS_INTERVAL: Duration = Duration::from_secs(60);
+#[cfg(feature = "wifi")]
+const PRESENCE_INTERVAL: Duration = Duration::from_secs(30);
+const UDP_SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+#[cfg(feature = "http-reqwest")]
+const INFLUX_SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+#[cfg(feature = "geolocation")]
+const GEOLOCATION_INTERVAL: Duration = Duration::from_secs(10 * 60);
+#[cfg(feature = "modbus")]
+const MODBUS_POLL_INTERVAL: Duration = Duration::from_secs(10);
+#[cfg(feature = "remote-config")]
+const REMOTE_CONFIG_INTERVAL: Duration = Duration::from_secs(15 * 60);
+#[cfg(any(feature = "mqtt", feature = "grpc"))]
+const TELEMETRY_INTERVAL: Duration = Duration::from_secs(60);
 
 fn main() -> Result<()> {
     esp_idf_svc::sys::link_patches();
-    esp_idf_svc::log::EspLogger::initialize_default();
+    startup::mark(startup::Phase::LinkPatches);
+    logtail::init();
     log::set_max_level(log::LevelFilter::Debug);
+    buildinfo::announce();
 
-    let eventfd_config = esp_idf_sys::esp_vfs_eventfd_config_t {
-        max_fds: 1,
-        ..Default::default()
-    };
-    esp_idf_sys::esp_nofail! { unsafe { esp_idf_sys::esp_vfs_eventfd_register(&eventfd_config) } }
+    let runtime_config = runtime::RuntimeConfig::default();
+    let runtime = runtime::init(runtime_config)?;
+    preflight::run(&runtime_config);
 
     let peripherals = esp_idf_hal::peripherals::Peripherals::take()?;
+    #[cfg_attr(not(any(feature = "wifi", feature = "eth")), allow(unused_variables))]
     let sys_loop = EspSystemEventLoop::take()?;
     let nvs = EspDefaultNvsPartition::take()?;
+    #[cfg(feature = "factory")]
+    factory::init(nvs.clone());
     let timer_service = EspTimerService::new()?;
+    let jobs = Scheduler::new(timer_service.clone());
+    // storage is optional, everything that uses it falls back without
+    if let Err(err) = fs::mount() {
+        log::error!("no filesystem: {err:#}");
+    }
+    #[cfg(feature = "datalog")]
+    if let Err(err) = datalog::open() {
+        log::error!("no datalog: {err:#}");
+    }
 
-    let esp_wifi = EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs))
-        .context("failed to get esp_wifi")?;
-    let mut wifi = esp_idf_svc::wifi::AsyncWifi::wrap(esp_wifi, sys_loop, timer_service)
-        .context("failed to wrap wifi")?;
+    // read by the pins of whichever peripheral features are on
+    #[allow(unused_variables)]
+    let board = board::current(nvs.clone());
 
-    log::info!("Starting async run loop");
-    tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()?
-        .block_on(async move {
-            start_wifi(&mut wifi).await.expect("couldn't start wifi");
-            update_time().await.expect("couldn't update time");
-            display_url().await.expect("couldn't download file");
-        });
+    #[cfg(feature = "neopixel")]
+    match board.rgb {
+        Some(pin) => indicator::start(indicator::Rgb::new(
+            peripherals.rmt.channel0,
+            board::pin(pin),
+        )?)?,
+        None => log::warn!("{} has no rgb led", board.name),
+    }
+    #[cfg(all(feature = "indicator", not(feature = "neopixel")))]
+    match board.led {
+        Some(pin) => indicator::start(indicator::Led::new(board::pin(pin))?)?,
+        None => log::warn!("{} has no led", board.name),
+    }
+    #[cfg(feature = "buzzer")]
+    match board.buzzer {
+        Some(pin) => buzzer::start(buzzer::Buzzer::new(
+            peripherals.ledc.timer0,
+            peripherals.ledc.channel0,
+            board::pin(pin),
+        )?)?,
+        None => log::warn!("{} has no buzzer", board.name),
+    }
+    #[cfg(feature = "button")]
+    match (board.button, board.touch) {
+        (Some(pin), _) => button::start(board::pin(pin))?,
+        #[cfg(esp32s3)]
+        (None, Some(pad)) => button::start_touch(pad)?,
+        _ => log::warn!("{} has no button", board.name),
+    }
+    #[cfg(feature = "serial-console")]
+    console::uart::start(
+        peripherals.uart0,
+        console::uart::Pins {
+            tx: board::pin(board.console.tx),
+            rx: board::pin(board.console.rx),
+        },
+    )?;
+    #[cfg(feature = "battery")]
+    battery::start(
+        peripherals.adc1,
+        battery::Pins {
+            sense: peripherals.pins.gpio1,
+        },
+    )?;
+    #[cfg(feature = "ambient")]
+    {
+        #[cfg(feature = "battery")]
+        let adc = None;
+        #[cfg(not(feature = "battery"))]
+        let adc = Some(peripherals.adc1);
+        ambient::start(
+            adc,
+            ambient::Pins {
+                sense: peripherals.pins.gpio3,
+            },
+        )?;
+    }
+
+    #[cfg(feature = "epaper")]
+    match board.epaper {
+        Some(pins) => display::start(display::Epaper::new(
+            peripherals.spi3,
+            display::Pins {
+                sclk: board::pin(pins.sclk),
+                mosi: board::pin(pins.mosi),
+                cs: board::pin(pins.cs),
+                dc: board::pin(pins.dc),
+                rst: board::pin(pins.rst),
+                busy: board::pin(pins.busy),
+            },
+        )?)?,
+        None => log::warn!("{} has no e-paper", board.name),
+    }
+    #[cfg(all(feature = "display", not(feature = "epaper")))]
+    match board.oled {
+        Some(pins) => display::start(display::Oled::new(
+            peripherals.i2c0,
+            display::Pins {
+                sda: board::pin(pins.sda),
+                scl: board::pin(pins.scl),
+            },
+        )?)?,
+        None => log::warn!("{} has no display", board.name),
+    }
+    #[cfg(esp_idf_soc_temp_sensor_supported)]
+    thermal::start(peripherals.temp_sensor)?;
+    #[cfg(any(feature = "sensors", feature = "rtc", feature = "atecc608"))]
+    match board.i2c {
+        Some(pins) => i2c::start(
+            peripherals.i2c1,
+            i2c::Pins {
+                sda: board::pin(pins.sda),
+                scl: board::pin(pins.scl),
+            },
+        )?,
+        None => log::warn!("{} has no peripheral bus", board.name),
+    }
+    #[cfg(feature = "sensors")]
+    sensors::start()?;
+    #[cfg(feature = "gps")]
+    match board.gps {
+        Some(pins) => gps::start(
+            peripherals.uart2,
+            gps::Pins {
+                tx: board::pin(pins.tx),
+                rx: board::pin(pins.rx),
+            },
+        )?,
+        None => log::warn!("{} has no gps", board.name),
+    }
 
-    log::info!("done, exiting main");
+    let (_wifi_modem, _bt_modem) = peripherals.modem.split();
+    #[cfg(feature = "ble")]
+    ble::start(
+        _bt_modem,
+        nvs.clone(),
+        config::ble_passkey(nvs.clone()).unwrap_or_else(|err| {
+            log::warn!("{err:#}");
+            None
+        }),
+    )?;
+
+    #[cfg(feature = "wifi")]
+    let _link = net::watch_link(&sys_loop)?;
+    #[cfg(feature = "wifi")]
+    let wifi = {
+        let esp_wifi = EspWifi::new(_wifi_modem, sys_loop.clone(), Some(nvs.clone()))
+            .context("failed to get esp_wifi")?;
+        AsyncWifi::wrap(esp_wifi, sys_loop.clone(), timer_service.clone())
+            .context("failed to wrap wifi")?
+    };
+    #[cfg(feature = "light-sleep")]
+    power::enable_light_sleep()?;
+
+    let links = net::Links {
+        #[cfg(feature = "wifi")]
+        wifi,
+        #[cfg(feature = "eth")]
+        eth: match board.eth {
+            Some(pins) => Some(eth::new(
+                peripherals.spi2,
+                eth::Pins {
+                    sclk: board::pin(pins.spi.sclk),
+                    mosi: board::pin(pins.spi.mosi),
+                    miso: board::pin(pins.spi.miso),
+                    cs: board::pin(pins.spi.cs),
+                    int: board::pin(pins.int),
+                    rst: board::pin(pins.rst),
+                },
+                sys_loop,
+                timer_service,
+            )?),
+            None => {
+                log::warn!("{} has no ethernet, running without", board.name);
+                None
+            }
+        },
+        #[cfg(feature = "qemu")]
+        open_eth: eth::open_eth(peripherals.mac, sys_loop, timer_service)?,
+        #[cfg(feature = "cellular")]
+        cellular: match board.cellular {
+            Some(pins) => Some(cellular::new(
+                peripherals.uart1,
+                cellular::Pins {
+                    tx: board::pin(pins.tx),
+                    rx: board::pin(pins.rx),
+                },
+            )?),
+            None => {
+                log::warn!("{} has no modem, running without", board.name);
+                None
+            }
+        },
+    };
+
+    log::info!("Starting async run loop");
+    runtime
+        .block_on(move || boot(links, nvs, jobs))?
+        .expect("boot failed");
 
     Ok(())
 }
 
-async fn display_url() -> Result<()> {
-    let body = reqwest::get(DOWNLOAD_URL).await?.text().await?;
+/// Runs the boot stages concurrently; each stage only waits on the system
+/// state it actually depends on.
+async fn boot(
+    links: net::Links,
+    nvs: EspDefaultNvsPartition,
+    jobs: Scheduler<EspTaskTimerService>,
+) -> Result<()> {
+    shed::start(LOW_HEAP_THRESHOLD);
+    jobs.register(
+        Job::new("heap-monitor", HEAP_CHECK_INTERVAL).jitter(Duration::from_secs(1)),
+        || async {
+            heap::check(LOW_HEAP_THRESHOLD);
+            Ok(())
+        },
+    );
 
-    log::info!("{}", body);
+    #[cfg(feature = "wifi")]
+    jobs.register(
+        Job::new("net-watchdog", NET_WATCHDOG_INTERVAL).radio(),
+        net::watchdog::check,
+    );
+    #[cfg(all(feature = "light-sleep", debug_assertions))]
+    jobs.register(Job::new("sleep-stats", SLEEP_STATS_INTERVAL), || async {
+        power::log_sleep_stats()
+    });
 
-    Ok(())
+    let config = OnceCell::new();
+    let load_config = || {
+        let nvs = nvs.clone();
+        async move {
+            let partition = nvs.clone();
+            let config = runtime::run_blocking(move || {
+                if let Err(err) = config::Config::recover(partition.clone()) {
+                    log::error!("config journal: {err:#}");
+                }
+                config::Config::load(partition)
+            })
+            .await??;
+            if let Err(err) = reload::set_log_level(&config) {
+                log::warn!("keeping log level {}: {err:#}", log::max_level());
+            }
+            // before what the flags gate
+            if let Err(err) = flags::configure(&config) {
+                log::warn!("keeping the flag defaults: {err:#}");
+            }
+            dns::configure(&config)?;
+            ratelimit::configure(&config)?;
+            tls::allowlist::configure(&config)?;
+            tls::chain::configure(&config)?;
+            tls::ocsp::configure(&config)?;
+            #[cfg(feature = "early-data")]
+            http::early::configure(&config)?;
+            #[cfg(feature = "mqtt")]
+            mqtt::configure(&config)?;
+            if let Err(err) = tls::suites::configure(&config) {
+                log::warn!("keeping the default cipher suites: {err:#}");
+            }
+            #[cfg(feature = "tls-profiles")]
+            tls::profile::configure(&config)?;
+            net::sockopt::configure(&config)?;
+            config::flush_on_restart()?;
+            // before the first client is built, so they all share the jar
+            #[cfg(feature = "http-reqwest")]
+            http::cookies::configure(
+                &config,
+                config::batch::Batched::new(
+                    esp_idf_svc::nvs::EspNvs::new(nvs, http::cookies::NAMESPACE, true)
+                        .context("couldn't open cookie nvs")?,
+                ),
+            )?;
+            net::portal::configure(&config);
+            identity::configure(&config);
+            #[cfg(feature = "tokio-rt")]
+            net::socks::configure(&config)?;
+            // before the first TLS client builds its config
+            #[cfg(feature = "tofu")]
+            tofu::load().await;
+            #[cfg(feature = "atecc608")]
+            atecc608::configure(&config);
+            #[cfg(any(feature = "aws", feature = "azure"))]
+            cloud::configure(&config)?;
+            #[cfg(feature = "cloud")]
+            if let Err(err) = cloud::auth::configure(&config) {
+                log::warn!("cloud auth disabled: {err:#}");
+            }
+            #[cfg(feature = "cellular")]
+            cellular::configure(&config);
+            locale::configure(&config);
+            #[cfg(feature = "battery")]
+            battery::configure(&config)?;
+            #[cfg(feature = "ambient")]
+            ambient::configure(&config)?;
+            #[cfg(feature = "indicator")]
+            indicator::configure(&config);
+            #[cfg(feature = "buzzer")]
+            buzzer::configure(&config);
+            #[cfg(esp_idf_soc_temp_sensor_supported)]
+            thermal::configure(&config);
+            anyhow::Ok(config)
+        }
+    };
+
+    let network = async {
+        // the radio's modes are set before it starts
+        #[cfg(feature = "wifi")]
+        net::configure(config.get_or_try_init(load_config).await?)?;
+        net::start(links);
+        events::wait_until(|state| state.net_up).await;
+        anyhow::Ok(())
+    };
+
+    let time = async {
+        #[cfg(feature = "rtc")]
+        if rtc::seed().await {
+            events::publish(Event::TimeSynced);
+        }
+        if power::clock_retained() {
+            log::info!("clock kept through deep sleep: {}", clock::format_time());
+            events::publish(Event::TimeSynced);
+            return Ok(());
+        }
+        #[cfg(feature = "sntp")]
+        {
+            let ntp = clock::Sntp::new(config.get_or_try_init(load_config).await?)?;
+            if !cfg!(any(feature = "rtc", feature = "gps")) {
+                return ntp_sync(ntp).await;
+            }
+            // with another time source NTP may well be blocked for good, so
+            // it keeps trying in the background and boot takes whichever is
+            // first
+            runtime::spawn(async move {
+                if let Err(err) = ntp_sync(ntp).await {
+                    log::warn!("{err:#}");
+                }
+            });
+        }
+        if !HAS_TIME_SOURCE {
+            log::warn!("no time source, certificates can't be verified");
+            return Ok(());
+        }
+        events::wait_until(|state| state.time_synced).await;
+        anyhow::Ok(())
+    };
+
+    let fetch = async {
+        let config = config.get_or_try_init(load_config).await?;
+        events::wait_until(|state| state.net_up).await;
+        // plain http doesn't need a valid clock to verify certificates, nor
+        // does https with what trust on first use kept from an earlier boot
+        #[cfg(feature = "tofu")]
+        let needs_clock = HAS_TIME_SOURCE && !tofu::bootstrapped();
+        #[cfg(not(feature = "tofu"))]
+        let needs_clock = HAS_TIME_SOURCE;
+        let https = [&config.download_url, &config.lan_url].iter().any(|url| {
+            ["https://", "https+srv://", "mdns://_https."]
+                .iter()
+                .any(|https| url.starts_with(https))
+        });
+        if https && needs_clock {
+            events::wait_until(|state| state.time_synced).await;
+        }
+        heap::report("before the first fetch");
+        let result = failover::download(
+            &config.download_url,
+            |url| async move { download(&url).await },
+        )
+        .await;
+        heap::report("after the first fetch");
+        #[cfg(feature = "quic")]
+        if config.download_url.starts_with("https://") && flags::on(flags::Flag::Quic) {
+            // comparison only, the TCP result above is what counts
+            if let Err(err) = quic::display_url(&config.download_url).await {
+                log::warn!("http/3 fetch failed: {err:#}");
+            }
+        }
+        // a backend outage mustn't take the rest of the device down with
+        // it; a `fetch` command, on the console or at `/trigger`, retries
+        if let Err(err) = result {
+            log::error!("couldn't download file: {err:#}");
+        }
+        anyhow::Ok(())
+    };
+
+    let services = async {
+        let config = config.get_or_try_init(load_config).await?;
+        start_services(config, &nvs, &jobs)?;
+        power::report_wake();
+
+        // ESP-NOW rides on the station interface, so the radio has to be up
+        #[cfg(feature = "wifi")]
+        {
+            events::wait_until(|state| state.net_up).await;
+            espnow::start()?;
+            #[cfg(feature = "espnow-gateway")]
+            espnow::gateway::start(config)?;
+            jobs.register(
+                Job::new("espnow-presence", PRESENCE_INTERVAL).radio(),
+                || async { espnow::broadcast(device::id().as_bytes()) },
+            );
+        }
+        anyhow::Ok(())
+    };
+
+    if let Err(err) = tokio::try_join!(network, time, fetch, services) {
+        // a duty-cycled device sleeps off a failed cycle, the next wake retries
+        let Some(config) = config.get().filter(|config| config.sleep_secs > 0) else {
+            return Err(err);
+        };
+        log::error!("boot failed, sleeping anyway: {err:#}");
+        let interval = Duration::from_secs(config.sleep_secs.into());
+        power::sleep_after_report(config, interval).await;
+    }
+
+    log::info!("boot completed");
+    startup::report();
+    runtime::log_tasks();
+
+    let config = config.get().expect("loaded by the boot stages");
+    if config.sleep_secs > 0 {
+        let interval = Duration::from_secs(config.sleep_secs.into());
+        power::sleep_after_report(config, interval).await;
+    }
+
+    // the subsystems keep running on their own tasks from here on
+    std::future::pending().await
 }
 
-async fn update_time() -> Result<()> {
-    let client = esp_idf_svc::sntp::EspSntp::new(&esp_idf_svc::sntp::SntpConf {
-        servers: [NTP_SERVER],
-        operating_mode: esp_idf_svc::sntp::OperatingMode::Poll,
-        sync_mode: esp_idf_svc::sntp::SyncMode::Immediate,
-    })?;
+/// Sets the clock from a network time source once the network is up.
+#[cfg(feature = "sntp")]
+async fn ntp_sync(mut source: impl clock::TimeSource) -> Result<()> {
+    events::wait_until(|state| state.net_up).await;
+    source
+        .sync()
+        .await
+        .with_context(|| format!("couldn't update time over {}", source.name()))?;
+    startup::mark(startup::Phase::Sntp);
+    power::clock_synced();
+    #[cfg(feature = "rtc")]
+    rtc::store();
+    Ok(())
+}
 
-    while client.get_sync_status() != esp_idf_svc::sntp::SyncStatus::Completed {
-        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+/// Fetches `url` over the fetcher for its scheme.
+async fn download(url: &str) -> Result<()> {
+    let url = &if url.starts_with("mdns://") {
+        let url = url.to_owned();
+        runtime::run_blocking(move || mdns::resolve_url(&url)).await??
+    } else {
+        dns::resolve_srv_url(url).await?
+    };
+    #[cfg(feature = "coap")]
+    if url.starts_with("coap://") {
+        return fetch_with(&coap::Fetcher, url).await;
     }
+    #[cfg(feature = "http-reqwest")]
+    let client = http::shared()?;
+    #[cfg(not(feature = "http-reqwest"))]
+    let client = http::client()?;
+    fetch_with(&client, url).await
+}
 
-    log::info!(
-        "ntp syncing completed, current time: {}",
-        time::UtcDateTime::now()
-            .format(time::macros::format_description!(
-                "[day]-[month repr:short]-[year] [hour]:[minute]:[second]"
-            ))
-            .unwrap_or(String::from("<invalid>"))
+/// Fetches over `fetcher` and reports the outcome on the bus, in telemetry
+/// and on the display.
+async fn fetch_with(fetcher: &impl http::HttpFetcher, url: &str) -> Result<()> {
+    let start = Instant::now();
+    events::publish(Event::FetchStarted);
+    #[cfg(feature = "display")]
+    let mut consumer = (http::LogLines::default(), display::Fetched::default());
+    #[cfg(not(feature = "display"))]
+    let mut consumer = http::LogLines::default();
+    let result = async {
+        budget::HTTP
+            .track(fetcher.fetch(url, &mut consumer))
+            .await?;
+        http::Consumer::finish(&mut consumer)
+    }
+    .await;
+    telemetry::set("fetch_ms", start.elapsed().as_millis() as u64);
+    events::publish(Event::FetchDone { ok: result.is_ok() });
+    telemetry::set(
+        "last_fetch",
+        match &result {
+            Ok(_) => String::from("ok"),
+            Err(err) => format!("{err:#}"),
+        },
     );
+    result
+}
 
-    Ok(())
+/// Syncs the clock again for every `timesync` command.
+#[cfg(feature = "sntp")]
+fn timesync_on_command(config: &config::Config) {
+    let ntp = match clock::Sntp::new(config) {
+        Ok(ntp) => ntp,
+        Err(err) => {
+            log::warn!("no timesync command: {err:#}");
+            return;
+        }
+    };
+    runtime::spawn(async move {
+        let mut events = events::subscribe();
+        loop {
+            let Ok(Event::Command(command)) = events.recv().await else {
+                continue;
+            };
+            if command.trim() != "timesync" {
+                continue;
+            }
+            if let Err(err) = ntp_sync(ntp.clone()).await {
+                log::warn!("{err:#}");
+            }
+        }
+    });
 }
 
-async fn start_wifi(wifi: &mut esp_idf_svc::wifi::AsyncWifi<EspWifi<'static>>) -> Result<()> {
-    let ssid: heapless::String<32> =
-        heapless::String::try_from(WIFI_SSID).context("couldn't convert wifi ssid text")?;
-    let password: heapless::String<64> =
-        heapless::String::try_from(WIFI_PASSWORD).context("couldn't convert wifi password text")?;
-
-    wifi.set_configuration(&esp_idf_svc::wifi::Configuration::Client(
-        esp_idf_svc::wifi::ClientConfiguration {
-            ssid: ssid.parse().unwrap(),
-            auth_method: esp_idf_svc::wifi::AuthMethod::WPA2Personal,
-            password: password.parse().unwrap(),
-            ..Default::default()
+/// Runs `download()` again for every `fetch [url]` command, whichever
+/// channel it came in on; the configured backends without one. A url on
+/// another host is only fetched from the console, where someone with the
+/// device at hand asked for it.
+fn download_on_command(config: &config::Config) {
+    let default_url = config.download_url.clone();
+    let hosts: Vec<String> = [&config.download_url, &config.lan_url]
+        .into_iter()
+        .filter_map(|url| http::host(url))
+        .map(String::from)
+        .collect();
+    console::register(console::Command {
+        name: "fetch",
+        usage: "fetch [url]",
+        summary: "run the download again, or fetch another url",
+        run: |_, args| match args {
+            [] => {
+                events::publish(Event::Command(String::from("fetch")));
+                Ok(String::from("fetch requested"))
+            }
+            [url] => {
+                let url = url.to_string();
+                runtime::spawn(async move {
+                    if let Err(err) = download(&url).await {
+                        log::warn!("fetch failed: {err:#}");
+                    }
+                });
+                Ok(String::from("fetch requested"))
+            }
+            _ => anyhow::bail!("usage: fetch [url]"),
         },
-    ))?;
+    });
+    runtime::spawn(async move {
+        let mut events = events::subscribe();
+        loop {
+            let Ok(Event::Command(command)) = events.recv().await else {
+                continue;
+            };
+            let mut words = command.split_whitespace();
+            if words.next() != Some("fetch") {
+                continue;
+            }
+            let configured = |url: &str| {
+                http::host(url).is_some_and(|host| hosts.iter().any(|known| known == host))
+            };
+            let result = match words.next() {
+                Some(url) if !configured(url) => {
+                    log::warn!("fetch of {url} refused, not a configured host");
+                    continue;
+                }
+                Some(url) => download(url).await,
+                None => {
+                    failover::download(&default_url, |url| async move { download(&url).await })
+                        .await
+                }
+            };
+            if let Err(err) = result {
+                log::warn!("fetch failed: {err:#}");
+            }
+        }
+    });
+}
+
+/// Snapshots the config whenever an OTA update is on its way, for the
+/// case it's rolled back.
+fn snapshot_on_ota(nvs: &EspDefaultNvsPartition) {
+    let nvs = nvs.clone();
+    runtime::spawn(async move {
+        let mut events = events::subscribe();
+        loop {
+            let Ok(Event::OtaPending) = events.recv().await else {
+                continue;
+            };
+            let nvs = nvs.clone();
+            let result = runtime::run_blocking(move || config::Config::snapshot(nvs)).await;
+            if let Err(err) = result.and_then(|snapshot| snapshot) {
+                log::error!("{err:#}");
+            }
+        }
+    });
+}
 
-    wifi.start().await.context("wifi couldn't start")?;
-    wifi.connect().await.context("wifi couldn't connect")?;
-    wifi.wait_netif_up().await.context("wifi netif_up failed")?;
+/// A job reporting telemetry, which the ambient sensor's events also run
+/// with `telemetry` in `ambient_trigger`.
+fn telemetry_job(name: &'static str, interval: Duration) -> Job {
+    let job = Job::new(name, interval).radio();
+    #[cfg(feature = "ambient")]
+    let job = job.on(ambient::telemetry_trigger);
+    job
+}
+
+fn start_services(
+    config: &config::Config,
+    nvs: &EspDefaultNvsPartition,
+    jobs: &Scheduler<EspTaskTimerService>,
+) -> Result<()> {
+    identity::start();
+    security::start(config);
+    #[cfg(feature = "backup")]
+    backup::start(config, nvs.clone());
+    #[cfg(feature = "incident")]
+    incident::start(config, nvs.clone());
+    server::start(config)?;
+    telemetry::endpoints::configure(config);
+    failover::start(config);
+    download_on_command(config);
+    snapshot_on_ota(nvs);
+    #[cfg(feature = "sntp")]
+    timesync_on_command(config);
+    warmup::start(config);
+    #[cfg(feature = "wpad")]
+    net::wpad::start(config);
+    quiet::start(config)?;
+    reboot::start(config)?;
+    poller::start(config, jobs)?;
+    reload::start(config, nvs.clone(), jobs.clone());
+    logtail::start();
+    mdns::start(config)?;
+    net::stun::start(config);
+    #[cfg(feature = "beacon")]
+    beacon::start(config);
+    #[cfg(debug_assertions)]
+    diag::start()?;
+    #[cfg(all(debug_assertions, feature = "tokio-rt"))]
+    loopback::start();
+    #[cfg(feature = "faults")]
+    faults::start()?;
+    #[cfg(feature = "pcap")]
+    pcap::start()?;
+    #[cfg(feature = "bench")]
+    bench::start(config);
+    selftest::start(nvs);
+    heap::start();
+    budget::start();
+    abtest::start();
+    if !config.console_password.expose().is_empty() {
+        console::tcp::start(
+            console::Console::new(config, nvs.clone()),
+            config.console_password.clone(),
+        )?;
+    }
+    #[cfg(feature = "serial-console")]
+    console::uart::serve(console::Console::new(config, nvs.clone()));
 
-    let net_if = wifi.wifi().sta_netif();
-    log::info!(
-        "nameservers {}, {}",
-        net_if.get_dns(),
-        net_if.get_secondary_dns()
+    #[cfg(feature = "battery")]
+    if !config.battery_divider.is_empty() {
+        power::sleep_on_low_battery(config);
+    }
+
+    #[cfg(feature = "wireguard")]
+    if !config.wg_endpoint.is_empty() {
+        wireguard::start(config)?;
+    }
+
+    // registered either way, a collector or endpoint can be set later
+    telemetry::udp::start(config)?;
+    jobs.register(
+        telemetry_job("udp-telemetry", UDP_SAMPLE_INTERVAL),
+        telemetry::udp::sample,
     );
 
+    #[cfg(feature = "http-reqwest")]
+    {
+        telemetry::influx::start(config)?;
+        jobs.register(
+            telemetry_job("influx-telemetry", INFLUX_SAMPLE_INTERVAL),
+            telemetry::influx::sample,
+        );
+    }
+
+    #[cfg(feature = "remote-config")]
+    if !config.config_url.is_empty() {
+        let poller = std::sync::Arc::new(remote_config::Poller::new(config, nvs.clone())?);
+        jobs.register(
+            Job::new("remote-config", REMOTE_CONFIG_INTERVAL).radio(),
+            move || {
+                let poller = poller.clone();
+                async move { poller.poll().await }
+            },
+        );
+    }
+
+    #[cfg(feature = "mqtt")]
+    if !config.mqtt_broker.is_empty() {
+        if config.mqtt_broker == mdns::LOCAL {
+            let (config, nvs) = (config.clone(), nvs.clone());
+            runtime::spawn(async move {
+                if let Err(err) = mqtt::start_local(config, nvs).await {
+                    log::error!("mqtt not started: {err:#}");
+                }
+            });
+        } else {
+            mqtt::start(config, nvs.clone())?;
+        }
+        jobs.register(
+            telemetry_job("mqtt-telemetry", TELEMETRY_INTERVAL),
+            || async { mqtt::publish_telemetry() },
+        );
+    }
+
+    #[cfg(any(feature = "aws", feature = "azure", feature = "cloud-https"))]
+    let connector = cloud::start(config, nvs.clone()).unwrap_or_else(|err| {
+        log::warn!("running without a cloud connector: {err:#}");
+        None
+    });
+    #[cfg(any(feature = "aws", feature = "azure", feature = "cloud-https"))]
+    if let Some(connector) = connector {
+        jobs.register(
+            telemetry_job("cloud-telemetry", TELEMETRY_INTERVAL),
+            move || {
+                let connector = connector.clone();
+                async move { connector.publish_telemetry() }
+            },
+        );
+    }
+
+    #[cfg(feature = "ws")]
+    if !config.ws_url.is_empty() {
+        ws::start(config);
+    }
+
+    #[cfg(feature = "grpc")]
+    if !config.grpc_url.is_empty() {
+        let url = config.grpc_url.clone();
+        jobs.register(
+            telemetry_job("grpc-status", TELEMETRY_INTERVAL),
+            move || {
+                let url = url.clone();
+                async move { grpc::device::report(&url).await }
+            },
+        );
+    }
+
+    #[cfg(feature = "modbus")]
+    if !config.modbus_server.is_empty() {
+        let poller = std::sync::Arc::new(modbus::Poller::new(config)?);
+        jobs.register(
+            Job::new("modbus-poll", MODBUS_POLL_INTERVAL).radio(),
+            move || {
+                let poller = poller.clone();
+                async move { poller.poll().await }
+            },
+        );
+    }
+
+    #[cfg(feature = "geolocation")]
+    if !config.geo_api_url.is_empty() {
+        let locator = std::sync::Arc::new(geolocation::Locator::new(config)?);
+        jobs.register(
+            Job::new("geolocation", GEOLOCATION_INTERVAL).radio(),
+            move || {
+                let locator = locator.clone();
+                async move { locator.locate().await }
+            },
+        );
+    }
+
+    #[cfg(feature = "lwm2m")]
+    if !config.lwm2m_server.is_empty() || !config.lwm2m_bootstrap.is_empty() {
+        lwm2m::start(config);
+    }
+
+    #[cfg(feature = "sse")]
+    if !config.sse_url.is_empty() {
+        sse::start(config);
+    }
+    #[cfg(feature = "longpoll")]
+    if !config.longpoll_url.is_empty() {
+        longpoll::start(config)?;
+    }
+
+    #[cfg(feature = "oauth")]
+    if !config.oauth_device_url.is_empty() {
+        if let Err(err) = cloud::oauth::start(config, nvs.clone()) {
+            log::warn!("oauth disabled: {err:#}");
+        }
+    }
+
     Ok(())
 }
@@ -0,0 +1,163 @@
+//! mDNS both ways: the device advertises itself, and finds services other
+//! hosts advertise. In local mode, `mqtt_broker` set to `mdns` or a
+//! download url of `mdns://_http._tcp/<path>`, the broker or backend is
+//! whichever host on the LAN answers for the service, by its `.local`
+//! name so TLS still checks the certificate against a name.
+
+use crate::{config::Config, console, device, server};
+use anyhow::{anyhow, bail, Context, Result};
+use esp_idf_svc::mdns::{EspMdns, Interface, Protocol, QueryResult};
+use std::{
+    net::IpAddr,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+/// The `mqtt_broker` that asks for the broker to be found.
+pub const LOCAL: &str = "mdns";
+const SCHEME: &str = "mdns://";
+/// How long a browse listens for answers.
+const BROWSE_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_RESULTS: usize = 8;
+
+static MDNS: OnceLock<Mutex<EspMdns>> = OnceLock::new();
+/// Our own hostname, to skip when looking for others.
+static HOSTNAME: OnceLock<String> = OnceLock::new();
+
+/// A service instance some host on the LAN advertises.
+#[derive(Debug)]
+pub struct Service {
+    pub instance: String,
+    /// Its `.local` name, the address when it didn't say.
+    pub host: String,
+    pub port: u16,
+    pub txt: Vec<(String, String)>,
+}
+
+/// Advertises the device as `<name>.local` with an `_http._tcp` service
+/// pointing at the status server.
+pub fn start(config: &Config) -> Result<()> {
+    let name = config.device_name();
+    let mut mdns = EspMdns::take()?;
+
+    mdns.set_hostname(name)?;
+    mdns.set_instance_name(name)?;
+    mdns.add_service(
+        Some(name),
+        "_http",
+        "_tcp",
+        server::PORT,
+        &[
+            ("id", device::id()),
+            ("version", device::firmware_version()),
+        ],
+    )?;
+
+    MDNS.set(Mutex::new(mdns))
+        .map_err(|_| anyhow!("mdns already started"))?;
+    let _ = HOSTNAME.set(format!("{name}.local"));
+
+    log::info!("mdns advertising {name}.local");
+
+    console::register(console::Command {
+        name: "mdns",
+        usage: "mdns <_service._proto>",
+        summary: "browse the lan for a service",
+        run: |_, args| {
+            let [service] = args else {
+                bail!("usage: mdns <_service._proto>");
+            };
+            let found = browse(service)?;
+            if found.is_empty() {
+                return Ok(format!("no {service} on the lan"));
+            }
+            Ok(found
+                .iter()
+                .map(|service| format!("{} at {}:{}", service.instance, service.host, service.port))
+                .collect::<Vec<_>>()
+                .join("\n"))
+        },
+    });
+
+    Ok(())
+}
+
+/// The instances of `service`, `_mqtt._tcp` say, that answer within
+/// `BROWSE_TIMEOUT`. Blocks until then.
+pub fn browse(service: &str) -> Result<Vec<Service>> {
+    let (kind, proto) = service
+        .split_once('.')
+        .filter(|(kind, proto)| kind.starts_with('_') && proto.starts_with('_'))
+        .with_context(|| format!("{service} isn't _<service>._<proto>"))?;
+    let mdns = MDNS.get().context("mdns isn't started")?.lock().unwrap();
+    let mut results = vec![empty_result(); MAX_RESULTS];
+    let count = mdns
+        .query_ptr(kind, proto, BROWSE_TIMEOUT, MAX_RESULTS, &mut results)
+        .with_context(|| format!("couldn't browse for {service}"))?;
+    Ok(results
+        .into_iter()
+        .take(count)
+        .filter_map(|result| {
+            let host = match (&result.hostname, result.addr.first()) {
+                (Some(hostname), _) => format!("{hostname}.local"),
+                (None, Some(addr)) => addr.to_string(),
+                (None, None) => return None,
+            };
+            Some(Service {
+                instance: result.instance_name.unwrap_or_default(),
+                host,
+                port: result.port,
+                txt: result.txt,
+            })
+        })
+        .collect())
+}
+
+/// The first instance of `service` that isn't this device.
+pub fn locate(service: &str) -> Result<Service> {
+    let own = HOSTNAME.get().map_or("", String::as_str);
+    let found = browse(service)?
+        .into_iter()
+        .find(|found| !found.host.eq_ignore_ascii_case(own))
+        .with_context(|| format!("no {service} found on the lan"))?;
+    log::info!(
+        "mdns found {service} {} at {}:{}",
+        found.instance,
+        found.host,
+        found.port
+    );
+    Ok(found)
+}
+
+/// `url` with an `mdns://_<service>._<proto>` host replaced by where the
+/// service is, others as they are. `_https` services are fetched over TLS.
+pub fn resolve_url(url: &str) -> Result<String> {
+    let Some(rest) = url.strip_prefix(SCHEME) else {
+        return Ok(url.to_owned());
+    };
+    let (service, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let scheme = if service.starts_with("_https.") {
+        "https"
+    } else {
+        "http"
+    };
+    let found = locate(service)?;
+    let host = match found.host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(addr)) => format!("[{addr}]"),
+        _ => found.host,
+    };
+    Ok(format!("{scheme}://{host}:{}{path}", found.port))
+}
+
+/// What `query_ptr()` overwrites, it takes a filled slice.
+fn empty_result() -> QueryResult {
+    QueryResult {
+        instance_name: None,
+        hostname: None,
+        port: 0,
+        txt: Vec::new(),
+        addr: Vec::new(),
+        interface: Interface::STA,
+        ip_protocol: Protocol::V4,
+    }
+}
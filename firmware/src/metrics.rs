@@ -0,0 +1,321 @@
+//! The registry `/metrics` renders in the Prometheus text format. Every
+//! family the device reports is a static here: counters and histograms are
+//! recorded into as things happen, gauges are sampled by the handler when
+//! it's scraped.
+
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex, time::Duration};
+
+pub static FREE_HEAP: Gauge = Gauge::new("heap_free_bytes", "Free heap, by region");
+pub static WIFI_RSSI: Gauge = Gauge::new("wifi_rssi_dbm", "Signal of the joined access point");
+pub static UPTIME: Gauge = Gauge::new("uptime_seconds", "Time since boot");
+pub static TASK_RUNNING: Gauge = Gauge::new("task_running", "1 while the named task runs");
+pub static TASK_RESTARTS: Gauge = Gauge::new("task_restarts", "Times the named task failed");
+pub static HTTP_SERVER_REQUESTS: Counter = Counter::new(
+    "http_server_requests_total",
+    "Requests the status server answered, by path",
+);
+pub static HTTP_CLIENT_REQUESTS: Counter = Counter::new(
+    "http_client_requests_total",
+    "Outgoing HTTP requests, by status or error",
+);
+pub static OUTBOUND_LIMITED: Counter = Counter::new(
+    "outbound_limited_total",
+    "Outgoing requests the rate limit deferred or dropped, by client",
+);
+pub static TLS_REFUSED: Counter = Counter::new(
+    "tls_refused_total",
+    "TLS connections refused by tls_allowlist, by host",
+);
+pub static TLS_REJECTED: Counter = Counter::new(
+    "tls_rejected_total",
+    "TLS servers whose certificate didn't verify, by host",
+);
+pub static TLS_NEGOTIATED: Counter = Counter::new(
+    "tls_negotiated_total",
+    "TLS client connections, by client, cipher suite and key exchange group",
+);
+pub static TLS_OCSP: Counter = Counter::new(
+    "tls_ocsp_total",
+    "Stapled OCSP checks, by host and what the staple said",
+);
+pub static TLS_EARLY_DATA: Counter = Counter::new(
+    "tls_early_data_total",
+    "Polls that could go as early data, by host and whether the server took it",
+);
+pub static ESPNOW_FORWARDED: Counter = Counter::new(
+    "espnow_forwarded_total",
+    "ESP-NOW frames the gateway took from its nodes, by uplink and outcome",
+);
+pub static TLS_BYTES: Counter = Counter::new(
+    "tls_bytes_total",
+    "Bytes on the wire of the TLS connections the device dials itself, by host and direction",
+);
+pub static NET_ERRORS: Counter = Counter::new(
+    "net_errors_total",
+    "Lookups, connects and handshakes that failed, by host and stage",
+);
+pub static HEAP_SHED: Counter = Counter::new(
+    "heap_shed_total",
+    "Load shed while the heap ran low, by action",
+);
+pub static HEAP_BUDGET_EXCEEDED: Counter = Counter::new(
+    "heap_budget_exceeded_total",
+    "Times a subsystem went over its RAM budget, by subsystem",
+);
+pub static TLS_HANDSHAKE: Histogram = Histogram::new(
+    "tls_handshake_seconds",
+    "TLS client handshakes, by the connection making them and host",
+);
+pub static DNS_RESOLVE: Histogram = Histogram::new(
+    "dns_resolve_seconds",
+    "Lookups that went to the network rather than a cache, by host",
+);
+pub static TCP_CONNECT: Histogram = Histogram::new(
+    "tcp_connect_seconds",
+    "TCP connections the device dials itself, by host",
+);
+
+/// Upper bounds in seconds, from a LAN round trip to a slow RSA chain.
+const BUCKETS: [f64; 8] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    values: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(&self, labels: &[(&str, &str)]) {
+        self.add(labels, 1);
+    }
+
+    pub fn add(&self, labels: &[(&str, &str)], count: u64) {
+        *self
+            .values
+            .lock()
+            .unwrap()
+            .entry(label_set(labels))
+            .or_default() += count;
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "counter");
+        for (labels, value) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{} {value}", self.name, braces(labels, ""));
+        }
+    }
+}
+
+pub struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    values: Mutex<BTreeMap<String, f64>>,
+}
+
+impl Gauge {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn set(&self, labels: &[(&str, &str)], value: f64) {
+        self.values.lock().unwrap().insert(label_set(labels), value);
+    }
+
+    /// Drops every value, for a gauge that has none to report right now.
+    pub fn clear(&self) {
+        self.values.lock().unwrap().clear();
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "gauge");
+        for (labels, value) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{} {value}", self.name, braces(labels, ""));
+        }
+    }
+}
+
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    values: Mutex<BTreeMap<String, Buckets>>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    counts: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn observe(&self, labels: &[(&str, &str)], duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut values = self.values.lock().unwrap();
+        let buckets = values.entry(label_set(labels)).or_default();
+        for (count, _) in buckets
+            .counts
+            .iter_mut()
+            .zip(BUCKETS)
+            .filter(|(_, bound)| seconds <= *bound)
+        {
+            *count += 1;
+        }
+        buckets.sum += seconds;
+        buckets.count += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "histogram");
+        for (labels, buckets) in self.values.lock().unwrap().iter() {
+            for (count, bound) in buckets.counts.iter().zip(BUCKETS) {
+                let le = format!("le=\"{bound}\"");
+                let _ = writeln!(out, "{}_bucket{} {count}", self.name, braces(labels, &le));
+            }
+            let inf = braces(labels, "le=\"+Inf\"");
+            let _ = writeln!(out, "{}_bucket{inf} {}", self.name, buckets.count);
+            let _ = writeln!(
+                out,
+                "{}_sum{} {}",
+                self.name,
+                braces(labels, ""),
+                buckets.sum
+            );
+            let _ = writeln!(
+                out,
+                "{}_count{} {}",
+                self.name,
+                braces(labels, ""),
+                buckets.count
+            );
+        }
+    }
+}
+
+/// Every family, as `/metrics` answers.
+pub fn render() -> String {
+    let mut out = String::new();
+    for gauge in [
+        &FREE_HEAP,
+        &WIFI_RSSI,
+        &UPTIME,
+        &TASK_RUNNING,
+        &TASK_RESTARTS,
+    ] {
+        gauge.render(&mut out);
+    }
+    for counter in [
+        &HTTP_SERVER_REQUESTS,
+        &HTTP_CLIENT_REQUESTS,
+        &OUTBOUND_LIMITED,
+        &TLS_REFUSED,
+        &TLS_REJECTED,
+        &TLS_NEGOTIATED,
+        &TLS_OCSP,
+        &TLS_EARLY_DATA,
+        &ESPNOW_FORWARDED,
+        &TLS_BYTES,
+        &NET_ERRORS,
+        &HEAP_SHED,
+        &HEAP_BUDGET_EXCEEDED,
+    ] {
+        counter.render(&mut out);
+    }
+    for histogram in [&TLS_HANDSHAKE, &DNS_RESOLVE, &TCP_CONNECT] {
+        histogram.render(&mut out);
+    }
+    out
+}
+
+/// A step on the way to a host, for where the time goes on a bad network.
+#[derive(Clone, Copy, Debug)]
+pub enum Stage {
+    Dns,
+    Connect,
+    /// The handshake, by the client making it.
+    Tls(&'static str),
+}
+
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Dns => "dns",
+            Self::Connect => "connect",
+            Self::Tls(_) => "tls",
+        }
+    }
+}
+
+/// The last time each stage took by host, in milliseconds.
+static LAST_MS: Mutex<BTreeMap<String, BTreeMap<&'static str, u64>>> = Mutex::new(BTreeMap::new());
+
+/// `stage` of reaching `host` took `took`: observed in its histogram, and
+/// kept as the latest for the `net_ms` telemetry field.
+pub fn reached(host: &str, stage: Stage, took: Duration) {
+    match stage {
+        Stage::Dns => DNS_RESOLVE.observe(&[("host", host)], took),
+        Stage::Connect => TCP_CONNECT.observe(&[("host", host)], took),
+        Stage::Tls(client) => TLS_HANDSHAKE.observe(&[("client", client), ("host", host)], took),
+    }
+    let mut last = LAST_MS.lock().unwrap();
+    last.entry(host.to_owned())
+        .or_default()
+        .insert(stage.name(), took.as_millis() as u64);
+    let value = serde_json::to_value(&*last).unwrap_or_default();
+    drop(last);
+    crate::telemetry::set("net_ms", value);
+}
+
+/// `stage` of reaching `host` failed.
+pub fn failed(host: &str, stage: Stage) {
+    NET_ERRORS.inc(&[("host", host), ("stage", stage.name())]);
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// The labels as they go between the braces, which is also the key their
+/// value is kept under.
+fn label_set(labels: &[(&str, &str)]) -> String {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{name}=\"{value}\"")
+        })
+        .collect();
+    labels.join(",")
+}
+
+fn braces(labels: &str, extra: &str) -> String {
+    match (labels.is_empty(), extra.is_empty()) {
+        (true, true) => String::new(),
+        (false, true) => format!("{{{labels}}}"),
+        (true, false) => format!("{{{extra}}}"),
+        (false, false) => format!("{{{labels},{extra}}}"),
+    }
+}
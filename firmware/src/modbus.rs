@@ -0,0 +1,269 @@
+use crate::{config::Config, net::socks, telemetry};
+use anyhow::{bail, Context, Result};
+use tokio::sync::Mutex;
+use tokio_modbus::{
+    client::{Context as Connection, Reader, Writer},
+    Slave,
+};
+
+const DEFAULT_PORT: u16 = 502;
+
+/// A value stored in one or more consecutive 16-bit registers. Wider values
+/// use big-endian word order, high word first, like most PLCs.
+pub trait RegisterValue: Sized {
+    const REGISTERS: u16;
+
+    fn decode(words: &[u16]) -> Self;
+    fn encode(self) -> Vec<u16>;
+}
+
+impl RegisterValue for u16 {
+    const REGISTERS: u16 = 1;
+
+    fn decode(words: &[u16]) -> Self {
+        words[0]
+    }
+
+    fn encode(self) -> Vec<u16> {
+        vec![self]
+    }
+}
+
+impl RegisterValue for i16 {
+    const REGISTERS: u16 = 1;
+
+    fn decode(words: &[u16]) -> Self {
+        words[0] as i16
+    }
+
+    fn encode(self) -> Vec<u16> {
+        vec![self as u16]
+    }
+}
+
+impl RegisterValue for u32 {
+    const REGISTERS: u16 = 2;
+
+    fn decode(words: &[u16]) -> Self {
+        (words[0] as u32) << 16 | words[1] as u32
+    }
+
+    fn encode(self) -> Vec<u16> {
+        vec![(self >> 16) as u16, self as u16]
+    }
+}
+
+impl RegisterValue for i32 {
+    const REGISTERS: u16 = 2;
+
+    fn decode(words: &[u16]) -> Self {
+        u32::decode(words) as i32
+    }
+
+    fn encode(self) -> Vec<u16> {
+        (self as u32).encode()
+    }
+}
+
+impl RegisterValue for f32 {
+    const REGISTERS: u16 = 2;
+
+    fn decode(words: &[u16]) -> Self {
+        f32::from_bits(u32::decode(words))
+    }
+
+    fn encode(self) -> Vec<u16> {
+        self.to_bits().encode()
+    }
+}
+
+/// Which register table a value lives in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Table {
+    Holding,
+    Input,
+}
+
+/// Modbus TCP session with one unit on a PLC or gateway.
+pub struct Client {
+    connection: Connection,
+}
+
+impl Client {
+    /// Connects to `host[:port]`, through the SOCKS proxy when one is set.
+    pub async fn connect(server: &str, unit: u8) -> Result<Self> {
+        let (host, port) = match server.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().context("invalid modbus port")?),
+            None => (server, DEFAULT_PORT),
+        };
+        let stream = socks::connect(host, port).await?;
+        log::info!("modbus connected to {host}:{port} unit {unit}");
+        Ok(Self {
+            connection: tokio_modbus::client::tcp::attach_slave(stream, Slave(unit)),
+        })
+    }
+
+    pub async fn read<T: RegisterValue>(&mut self, table: Table, addr: u16) -> Result<T> {
+        let words = match table {
+            Table::Holding => {
+                self.connection
+                    .read_holding_registers(addr, T::REGISTERS)
+                    .await??
+            }
+            Table::Input => {
+                self.connection
+                    .read_input_registers(addr, T::REGISTERS)
+                    .await??
+            }
+        };
+        if words.len() < T::REGISTERS as usize {
+            bail!("short read at register {addr}");
+        }
+        Ok(T::decode(&words))
+    }
+
+    /// Writes a holding register, or several for the wider types.
+    #[allow(dead_code)]
+    pub async fn write<T: RegisterValue>(&mut self, addr: u16, value: T) -> Result<()> {
+        let words = value.encode();
+        match words.as_slice() {
+            [word] => self.connection.write_single_register(addr, *word).await??,
+            words => {
+                self.connection
+                    .write_multiple_registers(addr, words)
+                    .await??
+            }
+        }
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub async fn read_coils(&mut self, addr: u16, count: u16) -> Result<Vec<bool>> {
+        Ok(self.connection.read_coils(addr, count).await??)
+    }
+
+    #[allow(dead_code)]
+    pub async fn write_coil(&mut self, addr: u16, value: bool) -> Result<()> {
+        Ok(self.connection.write_single_coil(addr, value).await??)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Kind {
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+}
+
+/// One telemetry field backed by a register.
+#[derive(Clone, Debug)]
+struct Mapping {
+    name: String,
+    table: Table,
+    addr: u16,
+    kind: Kind,
+}
+
+impl Mapping {
+    /// `name=addr[:kind][:input]`, kind one of u16, i16, u32, i32, f32.
+    fn parse(entry: &str) -> Result<Self> {
+        let (name, spec) = entry
+            .split_once('=')
+            .with_context(|| format!("modbus mapping {entry} has no '='"))?;
+        let mut parts = spec.split(':');
+        let addr = parts
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid register in {entry}"))?;
+
+        let (mut kind, mut table) = (Kind::U16, Table::Holding);
+        for part in parts {
+            match part.trim() {
+                "u16" => kind = Kind::U16,
+                "i16" => kind = Kind::I16,
+                "u32" => kind = Kind::U32,
+                "i32" => kind = Kind::I32,
+                "f32" => kind = Kind::F32,
+                "input" => table = Table::Input,
+                "holding" => table = Table::Holding,
+                other => bail!("unknown modbus option {other} in {entry}"),
+            }
+        }
+
+        Ok(Self {
+            name: name.trim().to_owned(),
+            table,
+            addr,
+            kind,
+        })
+    }
+
+    async fn read(&self, client: &mut Client) -> Result<serde_json::Value> {
+        let (table, addr) = (self.table, self.addr);
+        Ok(match self.kind {
+            Kind::U16 => client.read::<u16>(table, addr).await?.into(),
+            Kind::I16 => client.read::<i16>(table, addr).await?.into(),
+            Kind::U32 => client.read::<u32>(table, addr).await?.into(),
+            Kind::I32 => client.read::<i32>(table, addr).await?.into(),
+            Kind::F32 => client.read::<f32>(table, addr).await?.into(),
+        })
+    }
+}
+
+/// Register polling for the `modbus-poll` job.
+pub struct Poller {
+    server: String,
+    unit: u8,
+    mappings: Vec<Mapping>,
+    // kept across rounds and dropped on the first error, so the next round
+    // reconnects
+    client: Mutex<Option<Client>>,
+}
+
+impl Poller {
+    /// `modbus_server` is `host[:port][/unit]`, `modbus_map` a `;` separated
+    /// list of register mappings.
+    pub fn new(config: &Config) -> Result<Self> {
+        let (server, unit) = match config.modbus_server.split_once('/') {
+            Some((server, unit)) => (server, unit.parse().context("invalid modbus unit")?),
+            None => (config.modbus_server.as_str(), 1),
+        };
+        let mappings = config
+            .modbus_map
+            .split(';')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(Mapping::parse)
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            server: server.to_owned(),
+            unit,
+            mappings,
+            client: Mutex::new(None),
+        })
+    }
+
+    /// Reads every mapped register into its telemetry field.
+    pub async fn poll(&self) -> Result<()> {
+        let mut client = self.client.lock().await;
+        if client.is_none() {
+            *client = Some(Client::connect(&self.server, self.unit).await?);
+        }
+        let connection = client.as_mut().unwrap();
+
+        for mapping in &self.mappings {
+            match mapping.read(connection).await {
+                Ok(value) => telemetry::set(&mapping.name, value),
+                Err(err) => {
+                    *client = None;
+                    return Err(err.context(format!("couldn't read {}", mapping.name)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,375 @@
+#[cfg(feature = "cloud")]
+use crate::cloud::auth;
+use crate::{
+    budget,
+    codec::Codec,
+    config::Config,
+    device, events,
+    net::{self, socks},
+    runtime, telemetry, tls,
+};
+use anyhow::{anyhow, bail, ensure, Result};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use rumqttc::{AsyncClient, EventLoop, MqttOptions, Packet, QoS, TlsConfiguration, Transport};
+use std::{
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+#[cfg(feature = "datalog")]
+mod acks;
+mod command;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// What `mqtt_broker = mdns` browses the LAN for.
+const BROKER_SERVICE: &str = "_mqtt._tcp";
+/// Between browses while no broker answers.
+const LOCATE_RETRY: Duration = Duration::from_secs(30);
+const REQUEST_CAPACITY: usize = 10;
+/// Path most brokers serve MQTT-over-WebSocket on.
+const DEFAULT_WS_PATH: &str = "/mqtt";
+/// Longest topic `configure()` lets through, with the longest channel in;
+/// brokers take more, the bytes are in every message.
+const MAX_TOPIC: usize = 128;
+
+/// Broker session as seen by the rest of the firmware, so the rumqttc client
+/// could be swapped for esp-idf's MQTT client without touching callers.
+pub trait Session: Send + Sync {
+    fn publish(&self, topic: &str, payload: Vec<u8>) -> Result<()>;
+    /// A datalog record, marked sent once the broker acknowledges it.
+    #[cfg(feature = "datalog")]
+    fn publish_record(
+        &self,
+        topic: &str,
+        payload: Vec<u8>,
+        receipt: crate::datalog::Receipt,
+    ) -> Result<()>;
+    fn subscribe(&self, topic: &str) -> Result<()>;
+}
+
+impl Session for AsyncClient {
+    fn publish(&self, topic: &str, payload: Vec<u8>) -> Result<()> {
+        // rumqttc queues, it can't be held back, so an over the limit
+        // message is dropped
+        crate::ratelimit::try_acquire("mqtt")?;
+        #[cfg(feature = "datalog")]
+        let mut queued = acks::queue();
+        self.try_publish(topic, QoS::AtLeastOnce, false, payload)?;
+        #[cfg(feature = "datalog")]
+        queued.push_back(None);
+        Ok(())
+    }
+
+    #[cfg(feature = "datalog")]
+    fn publish_record(
+        &self,
+        topic: &str,
+        payload: Vec<u8>,
+        receipt: crate::datalog::Receipt,
+    ) -> Result<()> {
+        crate::ratelimit::try_acquire("mqtt")?;
+        let mut queued = acks::queue();
+        self.try_publish(topic, QoS::AtLeastOnce, false, payload)?;
+        queued.push_back(Some(receipt));
+        Ok(())
+    }
+
+    fn subscribe(&self, topic: &str) -> Result<()> {
+        Ok(self.try_subscribe(topic, QoS::AtLeastOnce)?)
+    }
+}
+
+static SESSION: OnceLock<Box<dyn Session>> = OnceLock::new();
+/// `mqtt_topic` with all but the channel filled in.
+static TOPIC: OnceLock<String> = OnceLock::new();
+static CODEC: OnceLock<Codec> = OnceLock::new();
+
+/// Gets the payload of every message on the topic it was registered for.
+pub type Handler = fn(&[u8]);
+
+static HANDLERS: Mutex<Vec<(String, Handler)>> = Mutex::new(Vec::new());
+static ON_CONNECT: Mutex<Vec<fn()>> = Mutex::new(Vec::new());
+
+/// Subscribes to `topic` on every connect and hands its messages to
+/// `handler`, on the MQTT task so it has to be quick.
+// only the aws module listens beyond the command topic
+#[cfg_attr(not(feature = "aws"), allow(dead_code))]
+pub fn on_message(topic: String, handler: Handler) {
+    HANDLERS.lock().unwrap().push((topic, handler));
+}
+
+/// Runs `hook` after every connect, once the `on_message()` topics are
+/// subscribed.
+#[cfg_attr(not(feature = "aws"), allow(dead_code))]
+pub fn on_connect(hook: fn()) {
+    ON_CONNECT.lock().unwrap().push(hook);
+}
+
+/// How payloads are encoded, from `mqtt_format`.
+fn codec() -> Codec {
+    CODEC.get().copied().unwrap_or_default()
+}
+
+/// Checks `mqtt_topic` and fills in `mqtt_prefix` and the device id, for
+/// `topic()`: it has to have `{channel}` and nothing else in braces, and
+/// whatever the channel, make a topic to publish on, without wildcards,
+/// empty levels or a `$` in front. The first config it's called with
+/// stays.
+pub fn configure(config: &Config) -> Result<()> {
+    let template = &config.mqtt_topic;
+    ensure!(
+        template.contains("{channel}"),
+        "mqtt_topic {template} has no {{channel}}, every channel would share a topic"
+    );
+    let topic = template
+        .replace("{prefix}", &config.mqtt_prefix)
+        .replace("{device_id}", device::id());
+    ensure!(
+        !topic.replace("{channel}", "").contains(['{', '}']),
+        "mqtt_topic {template} has braces other than {{prefix}}, {{device_id}}, {{channel}}"
+    );
+    // an espnow gateway's nodes/<mac> is the longest channel there is
+    let longest = topic.replace("{channel}", "nodes/000000000000");
+    ensure!(
+        !longest.contains(['+', '#']),
+        "mqtt topic {longest} has a wildcard"
+    );
+    ensure!(
+        !longest.starts_with('$'),
+        "mqtt topic {longest} starts with the broker's $"
+    );
+    ensure!(
+        !longest.split('/').any(str::is_empty),
+        "mqtt topic {longest} has an empty level, is mqtt_prefix empty?"
+    );
+    ensure!(
+        longest.len() <= MAX_TOPIC,
+        "mqtt topic {longest} is over {MAX_TOPIC} bytes"
+    );
+    let _ = TOPIC.set(topic);
+    Ok(())
+}
+
+/// The topic of `channel`, `devices/<id>/<channel>` unless `mqtt_topic`
+/// says otherwise.
+pub fn topic(channel: &str) -> String {
+    match TOPIC.get() {
+        Some(topic) => topic.replace("{channel}", channel),
+        None => format!("devices/{}/{}", device::id(), channel),
+    }
+}
+
+/// `start()` with the broker found on the LAN, for `mqtt_broker` set to
+/// `mdns`. Keeps browsing until one answers; the session then stays with
+/// it, another is looked for on the next boot.
+pub async fn start_local(mut config: Config, nvs: EspDefaultNvsPartition) -> Result<()> {
+    let found = loop {
+        events::wait_until(|state| state.net_up).await;
+        match runtime::run_blocking(|| crate::mdns::locate(BROKER_SERVICE)).await? {
+            Ok(found) => break found,
+            Err(err) => log::warn!("mqtt: {err:#}, looking again in {LOCATE_RETRY:?}"),
+        }
+        runtime::sleep(LOCATE_RETRY).await;
+    };
+    config.mqtt_broker = found.host;
+    config.mqtt_port = found.port;
+    start(&config, nvs)
+}
+
+/// Connects to the configured broker and keeps the session alive in the
+/// background, reconnecting whenever the network comes back. `nvs` is
+/// where `set_config` commands store the config.
+pub fn start(config: &Config, nvs: EspDefaultNvsPartition) -> Result<()> {
+    let _ = CODEC.set(Codec::parse(&config.mqtt_format)?);
+    let mut options = match config.mqtt_transport.as_str() {
+        "" | "tcp" => tcp_options(config)?,
+        "wss" => wss_options(config)?,
+        other => bail!("unknown mqtt transport {other}"),
+    };
+    options.set_keep_alive(KEEP_ALIVE);
+    if !config.mqtt_username.is_empty() {
+        options.set_credentials(&config.mqtt_username, config.mqtt_password.expose());
+    }
+
+    #[cfg(feature = "cloud")]
+    let jwt_username = match config.mqtt_username.as_str() {
+        // GCP-style brokers ignore it, but MQTT needs one with a password
+        "" => String::from("unused"),
+        username => username.to_owned(),
+    };
+
+    command::init(nvs);
+    let (client, mut eventloop) = AsyncClient::new(options, REQUEST_CAPACITY);
+    tune(&mut eventloop);
+    SESSION
+        .set(Box::new(client))
+        .map_err(|_| anyhow!("mqtt already started"))?;
+
+    log::info!(
+        "mqtt connecting to {}:{}",
+        config.mqtt_broker,
+        config.mqtt_port
+    );
+
+    runtime::spawn(async move {
+        let command_topic = topic("cmd");
+        let mut bus = events::subscribe();
+
+        loop {
+            events::wait_until(|state| state.net_up).await;
+            // a fresh token for every connect, the broker drops the session
+            // once the one it was opened with expires
+            #[cfg(feature = "cloud")]
+            if eventloop.network.is_none() && auth::configured() {
+                match auth::token() {
+                    Ok(token) => {
+                        eventloop
+                            .mqtt_options
+                            .set_credentials(jwt_username.clone(), token);
+                    }
+                    Err(err) => log::warn!("mqtt connecting without a jwt: {err:#}"),
+                }
+            }
+
+            // the connection may not have come along to the new access
+            // point, and the keep alive would take a while to tell
+            let polled = tokio::select! {
+                polled = budget::MQTT.track(eventloop.poll()) => Some(polled),
+                () = net::roamed(&mut bus) => None,
+            };
+            let Some(polled) = polled else {
+                log::info!("mqtt reconnecting after a roam");
+                eventloop.clean();
+                eventloop.network = None;
+                continue;
+            };
+            match polled {
+                Ok(rumqttc::Event::Incoming(Packet::ConnAck(_))) => {
+                    log::info!("mqtt connected");
+                    let handlers = HANDLERS.lock().unwrap().clone();
+                    for topic in std::iter::once(&command_topic)
+                        .chain(handlers.iter().map(|(topic, _)| topic))
+                    {
+                        if let Err(err) = session().and_then(|session| session.subscribe(topic)) {
+                            log::warn!("mqtt couldn't subscribe to {topic}: {err:#}");
+                        }
+                    }
+                    for hook in ON_CONNECT.lock().unwrap().iter() {
+                        hook();
+                    }
+                }
+                Ok(rumqttc::Event::Incoming(Packet::Publish(publish))) => {
+                    if publish.topic == command_topic {
+                        command::dispatch(&publish.payload);
+                    } else if let Some((_, handler)) = HANDLERS
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .find(|(topic, _)| *topic == publish.topic)
+                    {
+                        handler(&publish.payload);
+                    }
+                }
+                #[cfg(feature = "datalog")]
+                Ok(rumqttc::Event::Outgoing(rumqttc::Outgoing::Publish(pkid))) => acks::sent(pkid),
+                #[cfg(feature = "datalog")]
+                Ok(rumqttc::Event::Incoming(Packet::PubAck(ack))) => acks::acked(ack.pkid),
+                Ok(_) => {}
+                Err(err) => {
+                    log::warn!("mqtt connection error: {err}");
+                    runtime::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// MQTT over TLS on its own port, the default.
+fn tcp_options(config: &Config) -> Result<MqttOptions> {
+    tls_options(device::id(), &config.mqtt_broker, config.mqtt_port)
+}
+
+/// Options for MQTT over TLS to `host:port`, through the SOCKS proxy when
+/// there is one. Calls for the same host share its relay, so a session
+/// that rebuilds its options on every reconnect doesn't pile them up.
+pub fn tls_options(client_id: &str, host: &str, port: u16) -> Result<MqttOptions> {
+    Ok(match socks::route(host) {
+        // rumqttc dials its own socket, so it talks plain MQTT to a loopback
+        // relay that does the proxying and TLS
+        Some(_) => {
+            let relay = socks::relay(host, port, true)?;
+            let mut options = MqttOptions::new(client_id, relay.ip().to_string(), relay.port());
+            options.set_transport(Transport::Tcp);
+            options
+        }
+        None => {
+            let mut options = MqttOptions::new(client_id, host, port);
+            options.set_transport(Transport::tls_with_config(TlsConfiguration::Rustls(
+                tls::client_config_for(host),
+            )));
+            options
+        }
+    })
+}
+
+/// Applies the `tcp_options` rumqttc takes to the socket it dials; its
+/// own MQTT keep alive stands in for TCP's.
+pub fn tune(eventloop: &mut EventLoop) {
+    let tcp = crate::net::sockopt::options();
+    let mut network = eventloop.network_options();
+    network.set_tcp_nodelay(tcp.nodelay);
+    if let Some(bytes) = tcp.recv_buffer {
+        network.set_tcp_recv_buffer_size(bytes);
+    }
+    if let Some(bytes) = tcp.send_buffer {
+        network.set_tcp_send_buffer_size(bytes);
+    }
+    eventloop.set_network_options(network);
+}
+
+/// MQTT tunneled through a TLS WebSocket, for networks that only let 443
+/// out. The broker may carry the path, `host/path`, `/mqtt` otherwise.
+fn wss_options(config: &Config) -> Result<MqttOptions> {
+    let (host, path) = match config.mqtt_broker.split_once('/') {
+        Some((host, path)) => (host, format!("/{path}")),
+        None => (config.mqtt_broker.as_str(), String::from(DEFAULT_WS_PATH)),
+    };
+    if socks::route(host).is_some() {
+        // the loopback relay would put 127.0.0.1 in the Host header
+        bail!("mqtt over websocket can't go through the socks proxy");
+    }
+    let url = format!("wss://{host}:{}{path}", config.mqtt_port);
+
+    let mut options = MqttOptions::new(device::id(), url, config.mqtt_port);
+    options.set_transport(Transport::wss_with_config(TlsConfiguration::Rustls(
+        tls::client_config_for(host),
+    )));
+    Ok(options)
+}
+
+pub fn session() -> Result<&'static dyn Session> {
+    SESSION
+        .get()
+        .map(|session| session.as_ref())
+        .ok_or_else(|| anyhow!("mqtt not started"))
+}
+
+pub fn publish_telemetry() -> Result<()> {
+    #[cfg(feature = "datalog")]
+    if !events::state().net_up {
+        // stamped, the sample reaches the broker long after it was taken
+        let mut sample = telemetry::snapshot();
+        sample["time"] = time::UtcDateTime::now().unix_timestamp().into();
+        return crate::datalog::append(&codec().encode(&sample)?);
+    }
+    #[cfg(feature = "datalog")]
+    crate::datalog::drain(|record, receipt| {
+        session()?.publish_record(&topic("telemetry"), record.to_vec(), receipt)
+    })?;
+
+    let payload = codec().encode(&telemetry::snapshot())?;
+    session()?.publish(&topic("telemetry"), payload)
+}
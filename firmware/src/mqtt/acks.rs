@@ -0,0 +1,44 @@
+//! Which PUBACK settles which datalog record. rumqttc numbers publishes as
+//! they go out, in the order they were queued, so each one queued is noted
+//! here and takes its packet id when the event loop sees it leave. A
+//! retransmit after a reconnect goes out under the id it already has.
+
+use crate::datalog::{self, Receipt};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Mutex, MutexGuard},
+};
+
+/// The publishes queued with rumqttc and not out yet, a datalog record's
+/// receipt or `None`.
+static QUEUED: Mutex<VecDeque<Option<Receipt>>> = Mutex::new(VecDeque::new());
+/// The publishes out and not acknowledged, by packet id.
+static IN_FLIGHT: Mutex<Option<HashMap<u16, Option<Receipt>>>> = Mutex::new(None);
+
+/// Held across queueing a publish, so the order here is rumqttc's.
+pub fn queue() -> MutexGuard<'static, VecDeque<Option<Receipt>>> {
+    QUEUED.lock().unwrap()
+}
+
+pub fn sent(pkid: u16) {
+    let mut in_flight = IN_FLIGHT.lock().unwrap();
+    let in_flight = in_flight.get_or_insert_with(HashMap::new);
+    if !in_flight.contains_key(&pkid) {
+        let receipt = QUEUED.lock().unwrap().pop_front().flatten();
+        in_flight.insert(pkid, receipt);
+    }
+}
+
+pub fn acked(pkid: u16) {
+    let receipt = IN_FLIGHT
+        .lock()
+        .unwrap()
+        .as_mut()
+        .and_then(|in_flight| in_flight.remove(&pkid))
+        .flatten();
+    if let Some(receipt) = receipt {
+        if let Err(err) = datalog::acked(receipt) {
+            log::warn!("datalog: couldn't mark a record sent: {err:#}");
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! JSON commands on the `cmd` channel, `devices/<id>/cmd` unless
+//! `mqtt_topic` has it elsewhere, such as
+//! `{"id": "42", "command": "set_config", "config": {"ntp_server": ".."}}`,
+//! answered on `cmd/response` with the same `id` and either
+//! `"ok": true` and a `result` or `"ok": false` and an `error`. With CBOR or
+//! MessagePack as the `mqtt_format` both are maps in that encoding instead.
+//! A payload that isn't such a document is a plain text command, as the
+//! console takes them.
+
+use super::{codec, session, topic};
+use crate::{
+    codec::Codec,
+    config::Config,
+    events::{self, Event},
+    runtime,
+};
+use anyhow::{bail, Context, Result};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::{sync::OnceLock, time::Duration};
+
+/// Lets the response out before the restart.
+const REBOOT_GRACE: Duration = Duration::from_secs(2);
+
+static NVS: OnceLock<EspDefaultNvsPartition> = OnceLock::new();
+
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Command {
+    /// Downloads `url`, the configured one without it.
+    Fetch {
+        url: Option<String>,
+    },
+    /// Uploads a diagnostic bundle under `incident`, a new id without it,
+    /// and answers with the id once it's uploaded.
+    #[cfg(feature = "incident")]
+    Incident {
+        incident: Option<String>,
+    },
+    Reboot,
+    /// Runs the end-of-line self-test and answers with its report.
+    Selftest,
+    /// Stores the fields like a remote config document.
+    SetConfig {
+        config: Map<String, Value>,
+    },
+    StartOta {
+        url: String,
+    },
+}
+
+impl Command {
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Fetch { .. } => "fetch",
+            #[cfg(feature = "incident")]
+            Command::Incident { .. } => "incident",
+            Command::Reboot => "reboot",
+            Command::Selftest => "selftest",
+            Command::SetConfig { .. } => "set_config",
+            Command::StartOta { .. } => "start_ota",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Request {
+    /// Echoed in the response for the backend to match it up.
+    #[serde(default)]
+    id: Value,
+    #[serde(flatten)]
+    command: Command,
+}
+
+pub(super) fn init(nvs: EspDefaultNvsPartition) {
+    let _ = NVS.set(nvs);
+}
+
+/// Routes a message on the command topic, off the MQTT task.
+pub(super) fn dispatch(payload: &[u8]) {
+    let codec = codec();
+    let document = match codec {
+        Codec::Json => payload.iter().find(|byte| !byte.is_ascii_whitespace()) == Some(&b'{'),
+        // no text command starts with a map's lead byte, those aren't ASCII
+        _ => codec
+            .decode::<Value>(payload)
+            .is_ok_and(|value| value.is_object()),
+    };
+    if !document {
+        let command = String::from_utf8_lossy(payload).into_owned();
+        log::info!("mqtt command: {command}");
+        events::publish(Event::Command(command));
+        return;
+    }
+
+    let request = match codec.decode::<Request>(payload) {
+        Ok(request) => request,
+        Err(err) => {
+            // an id may still be in there for the backend to match up
+            let id = codec
+                .decode::<Value>(payload)
+                .ok()
+                .and_then(|value| value.get("id").cloned())
+                .unwrap_or_default();
+            respond(&id, Err(err.context("malformed command")));
+            return;
+        }
+    };
+    log::info!("mqtt command: {}", request.command.name());
+    runtime::spawn(async move {
+        let result = run(request.command).await;
+        respond(&request.id, result);
+    });
+}
+
+async fn run(command: Command) -> Result<Value> {
+    match command {
+        Command::Fetch { url } => {
+            events::publish(Event::Command(match &url {
+                Some(url) => format!("fetch {url}"),
+                None => String::from("fetch"),
+            }));
+            Ok(json!("fetch requested"))
+        }
+        #[cfg(feature = "incident")]
+        Command::Incident { incident } => {
+            let (incident, bytes) = crate::incident::upload(incident).await?;
+            Ok(json!({ "incident": incident, "bytes": bytes }))
+        }
+        Command::Reboot => {
+            runtime::spawn(async {
+                runtime::sleep(REBOOT_GRACE).await;
+                esp_idf_hal::reset::restart();
+            });
+            Ok(json!("rebooting"))
+        }
+        Command::Selftest => Ok(crate::selftest::run().await),
+        Command::SetConfig { config } => {
+            let nvs = NVS.get().cloned().context("mqtt commands not started")?;
+            let changed =
+                runtime::run_blocking(move || Config::apply_remote(nvs, &config)).await??;
+            if !changed.is_empty() {
+                log::info!("mqtt command changed {}", changed.join(", "));
+                events::publish(Event::ConfigChanged);
+            }
+            Ok(json!({ "changed": changed }))
+        }
+        // an OtaPending nothing acts on would leave the backend waiting
+        Command::StartOta { url } => {
+            bail!("no firmware updater in this build, {url} not fetched")
+        }
+    }
+}
+
+fn respond(id: &Value, result: Result<Value>) {
+    let response = match result {
+        Ok(result) => json!({ "id": id, "ok": true, "result": result }),
+        Err(err) => {
+            log::warn!("mqtt command failed: {err:#}");
+            json!({ "id": id, "ok": false, "error": format!("{err:#}") })
+        }
+    };
+    let sent = codec()
+        .encode(&response)
+        .and_then(|payload| session()?.publish(&topic("cmd/response"), payload));
+    if let Err(err) = sent {
+        log::warn!("mqtt couldn't send the command response: {err:#}");
+    }
+}
@@ -0,0 +1,275 @@
+use crate::{
+    events::{self, Event},
+    runtime, startup, telemetry,
+};
+use anyhow::Result;
+use esp_idf_svc::ipv4::Ipv4Addr;
+#[cfg(feature = "wifi")]
+use esp_idf_svc::wifi::{AsyncWifi, EspWifi};
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "tokio-rt")]
+pub mod counted;
+#[cfg(any(feature = "tokio-rt", feature = "http-lite"))]
+pub mod eyeballs;
+mod ipv6;
+mod ping;
+pub mod portal;
+mod resolve;
+pub mod sockopt;
+#[cfg(feature = "tokio-rt")]
+pub mod socks;
+pub mod stun;
+mod supervisor;
+mod transport;
+#[cfg(feature = "wifi")]
+pub mod watchdog;
+#[cfg(feature = "wifi")]
+mod wifi;
+#[cfg(feature = "wpad")]
+pub mod wpad;
+
+pub use ping::{ping, PingStats};
+use supervisor::Supervisor;
+pub use transport::{NetTransport, NetworkManager};
+#[cfg(feature = "wifi")]
+pub use wifi::{configure, scan, watch_link};
+
+/// How long to look for a SLAAC address once a link is up on IPv4.
+const IPV6_WAIT: Duration = Duration::from_secs(10);
+const IPV6_POLL: Duration = Duration::from_millis(500);
+
+static IPV4: Mutex<Option<Ipv4Addr>> = Mutex::new(None);
+static GATEWAY: Mutex<Option<Ipv4Addr>> = Mutex::new(None);
+/// Primary and secondary.
+static DNS_SERVERS: Mutex<[Option<Ipv4Addr>; 2]> = Mutex::new([None; 2]);
+static IPV6: Mutex<Option<Ipv6Addr>> = Mutex::new(None);
+/// Names of the links currently up.
+static UP: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+/// Every link the firmware brings up, each kept alive by its own supervisor.
+pub struct Links {
+    #[cfg(feature = "wifi")]
+    pub wifi: AsyncWifi<EspWifi<'static>>,
+    /// `None` on a board without the pins.
+    #[cfg(feature = "eth")]
+    pub eth: Option<crate::eth::Eth>,
+    #[cfg(feature = "qemu")]
+    pub open_eth: crate::eth::OpenEth,
+    #[cfg(feature = "cellular")]
+    pub cellular: Option<crate::cellular::Cellular>,
+}
+
+pub fn start(links: Links) {
+    resolve::start();
+    #[cfg(feature = "wifi")]
+    wifi::start(links.wifi);
+    #[cfg(feature = "eth")]
+    if let Some(eth) = links.eth {
+        runtime::spawn(run(eth));
+    }
+    #[cfg(feature = "qemu")]
+    runtime::spawn(run(links.open_eth));
+    #[cfg(feature = "cellular")]
+    if let Some(cellular) = links.cellular {
+        runtime::spawn(run(cellular));
+    }
+}
+
+/// Brings the link up and keeps it connected for the lifetime of the
+/// firmware, reconnecting with backoff after every disconnect. The network
+/// counts as up once a link is past any captive portal, and as down only
+/// once every link is.
+async fn run(mut link: impl NetTransport) {
+    let mut supervisor = Supervisor::default();
+
+    loop {
+        supervisor.connecting();
+        match connect(&mut link).await {
+            Ok(()) => {
+                supervisor.connected();
+                UP.lock().unwrap().push(link.name());
+                runtime::spawn(portal::admit(link.name()));
+
+                let radio = link.radio();
+                let quiet = async {
+                    if radio {
+                        crate::quiet::radio_off().await
+                    } else {
+                        std::future::pending().await
+                    }
+                };
+                let suspend = tokio::select! {
+                    result = link.wait_disconnected() => {
+                        if let Err(err) = result {
+                            log::warn!("{} wait failed: {err}", link.name());
+                        }
+                        false
+                    }
+                    () = quiet => true,
+                };
+                if suspend {
+                    log::info!("{} off for quiet hours", link.name());
+                    if let Err(err) = link.suspend().await {
+                        log::warn!("{} couldn't go down: {err:#}", link.name());
+                    }
+                } else {
+                    log::warn!("{} link down", link.name());
+                }
+                supervisor.disconnected();
+                let last = {
+                    let mut up = UP.lock().unwrap();
+                    up.retain(|name| *name != link.name());
+                    up.is_empty()
+                };
+                if last {
+                    events::publish(Event::NetDown);
+                }
+                if suspend {
+                    events::wait_until(|state| !state.quiet).await;
+                }
+            }
+            Err(err) => {
+                let delay = supervisor.failed(&err);
+                log::warn!(
+                    "{} connect failed, retrying in {delay:?}: {err:#}",
+                    link.name()
+                );
+                runtime::sleep(delay).await;
+            }
+        }
+    }
+}
+
+async fn connect(link: &mut impl NetTransport) -> Result<()> {
+    link.connect().await?;
+    startup::mark(startup::Phase::Dhcp);
+
+    // not every link negotiates IPv6 (PPP usually doesn't), IPv4 still works
+    if let Err(err) = ipv6::enable(link.netif()) {
+        log::warn!("{} couldn't enable ipv6: {err}", link.name());
+    }
+    *IPV6.lock().unwrap() = None;
+    telemetry::set("ipv6", None::<String>);
+    runtime::spawn(wait_ipv6(link.name(), ipv6::Netif::new(link.netif())));
+
+    let net_if = link.netif();
+    log::info!(
+        "{} up, nameservers {}, {}",
+        link.name(),
+        net_if.get_dns(),
+        net_if.get_secondary_dns()
+    );
+    let ip_info = net_if.get_ip_info()?;
+    *IPV4.lock().unwrap() = Some(ip_info.ip);
+    *GATEWAY.lock().unwrap() = Some(ip_info.subnet.gateway);
+    *DNS_SERVERS.lock().unwrap() = [net_if.get_dns(), net_if.get_secondary_dns()]
+        .map(|dns| Some(dns).filter(|dns| !dns.is_unspecified()));
+
+    Ok(())
+}
+
+/// Looks for a SLAAC address while the link carries on over IPv4; whatever
+/// connects after it turns up can use it.
+async fn wait_ipv6(name: &'static str, netif: ipv6::Netif) {
+    let deadline = Instant::now() + IPV6_WAIT;
+    loop {
+        if let Some(addr) = ipv6::routable(&netif) {
+            log::info!("{name} ipv6 address {addr}");
+            *IPV6.lock().unwrap() = Some(addr);
+            telemetry::set("ipv6", addr.to_string());
+            return;
+        }
+        if Instant::now() >= deadline {
+            log::info!("no routable ipv6 address on {name}, ipv4 only");
+            return;
+        }
+        runtime::sleep(IPV6_POLL).await;
+    }
+}
+
+/// Waits for the station to roam, `Event::Roamed` on `bus`; a connection
+/// of its own that may not survive that reconnects when this returns.
+pub async fn roamed(bus: &mut tokio::sync::broadcast::Receiver<Event>) {
+    // the sender lives in a static, so only lagging is an error
+    while !matches!(bus.recv().await, Ok(Event::Roamed { .. })) {}
+}
+
+pub fn link_up(name: &str) -> bool {
+    UP.lock().unwrap().contains(&name)
+}
+
+/// Address from the last connect.
+#[cfg(any(feature = "display", feature = "beacon", feature = "incident"))]
+pub fn ipv4() -> Option<Ipv4Addr> {
+    *IPV4.lock().unwrap()
+}
+
+#[cfg(feature = "wifi")]
+pub fn gateway() -> Option<Ipv4Addr> {
+    *GATEWAY.lock().unwrap()
+}
+
+/// Global or unique-local address from the last connect, if the network
+/// offers IPv6 at all.
+pub fn ipv6() -> Option<Ipv6Addr> {
+    *IPV6.lock().unwrap()
+}
+
+/// Address family name for connection logs.
+pub fn family(ip: IpAddr) -> &'static str {
+    if ip.is_ipv6() {
+        "ipv6"
+    } else {
+        "ipv4"
+    }
+}
+
+/// Primary DNS server handed out by DHCP on the last connect.
+pub fn dns_server() -> Option<Ipv4Addr> {
+    DNS_SERVERS.lock().unwrap()[0]
+}
+
+/// The primary and secondary DNS servers, either missing when DHCP didn't
+/// hand one out.
+pub fn dns_servers() -> [Option<Ipv4Addr>; 2] {
+    *DNS_SERVERS.lock().unwrap()
+}
+
+pub fn rssi() -> Option<i8> {
+    ap_info().map(|info| info.rssi)
+}
+
+/// The best 802.11 mode the station's AP offers, `lr` for long range.
+pub fn phy() -> Option<&'static str> {
+    let info = ap_info()?;
+    Some(if info.phy_lr() != 0 {
+        "lr"
+    } else if info.phy_11n() != 0 {
+        "11n"
+    } else if info.phy_11g() != 0 {
+        "11g"
+    } else {
+        "11b"
+    })
+}
+
+/// Network the station is associated with, which isn't necessarily the
+/// link carrying traffic.
+#[cfg(any(feature = "display", feature = "incident"))]
+pub fn ssid() -> Option<String> {
+    let info = ap_info()?;
+    let ssid = std::ffi::CStr::from_bytes_until_nul(&info.ssid).ok()?;
+    Some(ssid.to_string_lossy().into_owned())
+}
+
+fn ap_info() -> Option<esp_idf_sys::wifi_ap_record_t> {
+    let mut info = esp_idf_sys::wifi_ap_record_t::default();
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_wifi_sta_get_ap_info(&mut info) })
+        .ok()
+        .map(|_| info)
+}
@@ -0,0 +1,92 @@
+//! A socket that counts what goes through it, under TLS the records on the
+//! wire, into `tls_bytes_total` by host. The counts are added up here and
+//! handed to the registry every `FLUSH` bytes and when the socket goes, so
+//! a download doesn't take the registry's lock for every read.
+
+use crate::metrics::TLS_BYTES;
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const FLUSH: u64 = 16 * 1024;
+
+pub struct Counted<S> {
+    inner: S,
+    host: String,
+    received: u64,
+    sent: u64,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S, host: &str) -> Self {
+        Self {
+            inner,
+            host: host.to_owned(),
+            received: 0,
+            sent: 0,
+        }
+    }
+
+    fn flush_counts(&mut self) {
+        for (direction, count) in [("in", &mut self.received), ("out", &mut self.sent)] {
+            if *count > 0 {
+                TLS_BYTES.add(&[("host", &self.host), ("direction", direction)], *count);
+                *count = 0;
+            }
+        }
+    }
+}
+
+impl<S> Drop for Counted<S> {
+    fn drop(&mut self) {
+        self.flush_counts();
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            this.received += (buf.filled().len() - before) as u64;
+            if this.received >= FLUSH {
+                this.flush_counts();
+            }
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            this.sent += written as u64;
+            if this.sent >= FLUSH {
+                this.flush_counts();
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,89 @@
+//! Happy Eyeballs (RFC 8305) for hosts with more than one address: the
+//! attempts start [`ATTEMPT_DELAY`] apart, alternating families, and the
+//! first to finish, TLS handshake included where the caller does one, wins
+//! while the rest are dropped. A dead address then costs a quarter second
+//! rather than a whole connect timeout.
+
+use crate::runtime;
+use anyhow::{anyhow, Error, Result};
+use std::{
+    collections::VecDeque,
+    future::{poll_fn, Future},
+    net::SocketAddr,
+    pin::Pin,
+    task::Poll,
+    time::Duration,
+};
+
+/// What RFC 8305 recommends between connection attempts.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Runs `attempt` for each of `addrs`, staggered, and returns the first
+/// success along with the address it was for. A failed attempt starts the
+/// next one right away; the error out is the last attempt's.
+pub async fn race<T, F, Fut>(addrs: Vec<SocketAddr>, mut attempt: F) -> Result<(SocketAddr, T)>
+where
+    F: FnMut(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut pending = interleave(addrs);
+    let mut running: Vec<(SocketAddr, Pin<Box<Fut>>)> = Vec::new();
+    let mut next_start = Box::pin(runtime::sleep(Duration::ZERO));
+    let mut last_error: Option<Error> = None;
+
+    loop {
+        if running.is_empty() && pending.is_empty() {
+            return Err(last_error.unwrap_or_else(|| anyhow!("no address to connect to")));
+        }
+
+        let finished = poll_fn(|cx| {
+            for (index, (_, future)) in running.iter_mut().enumerate() {
+                if let Poll::Ready(result) = future.as_mut().poll(cx) {
+                    return Poll::Ready(Some((index, result)));
+                }
+            }
+            if !pending.is_empty() && next_start.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(None);
+            }
+            Poll::Pending
+        })
+        .await;
+
+        match finished {
+            Some((index, Ok(value))) => {
+                let (addr, _) = running.swap_remove(index);
+                if !running.is_empty() {
+                    log::debug!("{addr} won, dropping {} other attempts", running.len());
+                }
+                return Ok((addr, value));
+            }
+            Some((index, Err(err))) => {
+                let (addr, _) = running.swap_remove(index);
+                log::debug!("connecting to {addr} failed: {err:#}");
+                last_error = Some(err.context(format!("couldn't connect to {addr}")));
+                next_start = Box::pin(runtime::sleep(Duration::ZERO));
+            }
+            None => {
+                let addr = pending.pop_front().expect("checked above");
+                running.push((addr, Box::pin(attempt(addr))));
+                next_start = Box::pin(runtime::sleep(ATTEMPT_DELAY));
+            }
+        }
+    }
+}
+
+/// The addresses alternating between families, starting with the first
+/// one's, which `dns::resolve` puts IPv6 in front of.
+fn interleave(addrs: Vec<SocketAddr>) -> VecDeque<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (mut first, mut second): (VecDeque<_>, VecDeque<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+    let mut ordered = VecDeque::with_capacity(first.len() + second.len());
+    loop {
+        match (first.pop_front(), second.pop_front()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
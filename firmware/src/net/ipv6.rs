@@ -0,0 +1,51 @@
+use anyhow::Result;
+use esp_idf_svc::{handle::RawHandle, netif::EspNetif, sys};
+use std::net::Ipv6Addr;
+
+/// More slots than lwIP keeps per interface (3 by default).
+const MAX_ADDRESSES: usize = 8;
+
+/// Starts IPv6 on the interface; the link-local address is what kicks off
+/// router solicitation and SLAAC for the global ones.
+pub fn enable(netif: &EspNetif) -> Result<()> {
+    sys::esp!(unsafe { sys::esp_netif_create_ip6_linklocal(netif.handle()) })?;
+    Ok(())
+}
+
+/// A link's interface, for the task that waits for SLAAC while the link's
+/// own task holds the link.
+pub struct Netif(*mut sys::esp_netif_t);
+
+// links live as long as the firmware, and lwIP locks the interface itself
+unsafe impl Send for Netif {}
+
+impl Netif {
+    pub fn new(netif: &EspNetif) -> Self {
+        Self(netif.handle())
+    }
+}
+
+/// First global or unique-local address on the interface, the ones that
+/// can actually reach a backend.
+pub fn routable(netif: &Netif) -> Option<Ipv6Addr> {
+    let mut addrs = [sys::esp_ip6_addr_t::default(); MAX_ADDRESSES];
+    let count = unsafe { sys::esp_netif_get_all_ip6(netif.0, addrs.as_mut_ptr()) };
+
+    addrs
+        .iter()
+        .take(count.max(0) as usize)
+        .filter(|addr| {
+            let kind = unsafe { sys::esp_netif_ip6_get_addr_type(*addr as *const _ as *mut _) };
+            kind == sys::esp_ip6_addr_type_t_ESP_IP6_ADDR_IS_GLOBAL
+                || kind == sys::esp_ip6_addr_type_t_ESP_IP6_ADDR_IS_UNIQUE_LOCAL
+        })
+        .map(|addr| {
+            let mut octets = [0; 16];
+            for (chunk, word) in octets.chunks_exact_mut(4).zip(addr.addr) {
+                // lwIP keeps each word in network order
+                chunk.copy_from_slice(&word.to_ne_bytes());
+            }
+            Ipv6Addr::from(octets)
+        })
+        .next()
+}
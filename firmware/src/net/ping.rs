@@ -0,0 +1,79 @@
+use crate::{dns, runtime};
+use anyhow::{Context, Result};
+use esp_idf_svc::ping::{Configuration, EspPing, Reply};
+use std::{fmt, net::IpAddr, time::Duration};
+
+#[derive(Clone, Debug, Default)]
+pub struct PingStats {
+    pub transmitted: u32,
+    pub received: u32,
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+}
+
+impl PingStats {
+    pub fn loss_percent(&self) -> u32 {
+        if self.transmitted == 0 {
+            return 100;
+        }
+        100 - self.received * 100 / self.transmitted
+    }
+}
+
+impl fmt::Display for PingStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} received ({}% loss), rtt min/avg/max {}/{}/{} ms",
+            self.received,
+            self.transmitted,
+            self.loss_percent(),
+            self.min.as_millis(),
+            self.avg.as_millis(),
+            self.max.as_millis()
+        )
+    }
+}
+
+/// Sends `count` ICMP echo requests to `host` through lwIP and reports the
+/// round trip times.
+pub async fn ping(host: &str, count: u32) -> Result<PingStats> {
+    let ip = match dns::resolve(host).await?.into_iter().find(IpAddr::is_ipv4) {
+        Some(IpAddr::V4(ip)) => ip,
+        _ => anyhow::bail!("{host} has no ipv4 address"),
+    };
+
+    runtime::run_blocking(move || {
+        let mut stats = PingStats::default();
+        let mut total = Duration::ZERO;
+        let mut min: Option<Duration> = None;
+        let summary = EspPing::default()
+            .ping_details(
+                ip,
+                &Configuration {
+                    count,
+                    ..Default::default()
+                },
+                |_, reply| {
+                    if let Reply::Success(info) = reply {
+                        let rtt = info.elapsed_time;
+                        min = Some(min.map_or(rtt, |min| min.min(rtt)));
+                        stats.max = stats.max.max(rtt);
+                        total += rtt;
+                    }
+                },
+            )
+            .with_context(|| format!("couldn't ping {ip}"))?;
+
+        stats.min = min.unwrap_or_default();
+        stats.transmitted = summary.transmitted;
+        stats.received = summary.received;
+        if summary.received > 0 {
+            stats.avg = total / summary.received;
+        }
+
+        Ok(stats)
+    })
+    .await?
+}
@@ -0,0 +1,111 @@
+//! Captive portal detection. Hotel and café networks answer every request
+//! with their login page until it's been clicked through, so a TLS
+//! handshake there fails in ways that look like a broken server. When a
+//! link comes up, `portal_url`, a plain http url that answers an empty
+//! 204, is fetched first: a redirect or any other answer means a portal,
+//! published as `Event::CaptivePortal` in place of `Event::NetUp`, and the
+//! check is repeated until the portal lets traffic through.
+
+use super::link_up;
+use crate::{
+    config::Config,
+    events::{self, Event},
+    runtime, telemetry,
+};
+use anyhow::Result;
+use esp_idf_svc::{
+    http::{
+        client::{Configuration, EspHttpConnection, FollowRedirectsPolicy},
+        Method,
+    },
+    io::Read,
+};
+use std::{sync::Mutex, time::Duration};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often a portal is checked again, for someone to log in meanwhile.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// `None` until `configure()`, which boot calls long before DHCP is done;
+/// links up before that aren't checked.
+static URL: Mutex<Option<String>> = Mutex::new(None);
+
+pub fn configure(config: &Config) {
+    *URL.lock().unwrap() = Some(config.portal_url.clone());
+}
+
+enum Probe {
+    Online,
+    Portal {
+        status: u16,
+        location: Option<String>,
+    },
+}
+
+/// Publishes `Event::NetUp` for the link `name` once the probe goes
+/// through. A probe that fails outright doesn't hold the link back: the
+/// check's own server may be what's down.
+pub async fn admit(name: &'static str) {
+    let url = URL.lock().unwrap().clone().unwrap_or_default();
+    if url.is_empty() {
+        events::publish(Event::NetUp);
+        return;
+    }
+
+    let mut reported = false;
+    while link_up(name) {
+        let probe = {
+            let url = url.clone();
+            runtime::run_blocking(move || probe(&url)).await
+        };
+        match probe.and_then(|probe| probe) {
+            Ok(Probe::Portal { status, location }) => {
+                if !reported {
+                    log::warn!(
+                        "{name} is behind a captive portal, {url} answered {status}{}",
+                        location.map_or_else(String::new, |to| format!(" to {to}"))
+                    );
+                    telemetry::set("captive_portal", true);
+                    events::publish(Event::CaptivePortal);
+                    reported = true;
+                }
+                runtime::sleep(RECHECK_INTERVAL).await;
+            }
+            Ok(Probe::Online) => {
+                if reported {
+                    log::info!("{name} is past the captive portal");
+                }
+                break;
+            }
+            Err(err) => {
+                log::warn!("{name} portal check failed, assuming none: {err:#}");
+                break;
+            }
+        }
+    }
+    if link_up(name) {
+        telemetry::set("captive_portal", false);
+        events::publish(Event::NetUp);
+    }
+}
+
+fn probe(url: &str) -> Result<Probe> {
+    let mut connection = EspHttpConnection::new(&Configuration {
+        timeout: Some(PROBE_TIMEOUT),
+        follow_redirects_policy: FollowRedirectsPolicy::FollowNone,
+        ..Default::default()
+    })?;
+    connection.initiate_request(Method::Get, url, &[])?;
+    connection.initiate_response()?;
+    let status = connection.status();
+    let location = connection.header("Location").map(String::from);
+    // a portal's login page has a body, the real answer doesn't
+    let mut body = [0; 64];
+    let read = connection.read(&mut body)?;
+
+    if status == 204 || ((200..300).contains(&status) && read == 0) {
+        Ok(Probe::Online)
+    } else {
+        Ok(Probe::Portal { status, location })
+    }
+}
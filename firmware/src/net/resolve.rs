@@ -0,0 +1,80 @@
+//! `resolve <host>` on the console: the same question put to the primary
+//! and the secondary DNS server straight, with each answer's TTL and how
+//! long it took, then what the system resolver makes of it. A secondary
+//! that has gone stale or times out only shows when lwIP falls back to it,
+//! which makes it look like flaky fetches rather than DNS.
+//!
+//! The firmware has no DNS over HTTPS client to put next to them.
+
+use crate::{console, dns};
+use anyhow::{bail, Result};
+use std::{
+    fmt::Write,
+    net::{IpAddr, ToSocketAddrs},
+    time::Instant,
+};
+
+pub(super) fn start() {
+    console::register(console::Command {
+        name: "resolve",
+        usage: "resolve <host>",
+        summary: "ask each dns server for a host, with latencies",
+        run: command,
+    });
+}
+
+fn command(_: &console::Console, args: &[&str]) -> Result<String> {
+    let [host] = args else {
+        bail!("usage: resolve <host>");
+    };
+    let mut kinds = vec![("A", dns::TYPE_A)];
+    if super::ipv6().is_some() {
+        kinds.push(("AAAA", dns::TYPE_AAAA));
+    }
+
+    let mut report = String::new();
+    let [primary, secondary] = super::dns_servers();
+    for (name, server) in [("primary", primary), ("secondary", secondary)] {
+        let Some(server) = server else {
+            let _ = writeln!(report, "{name:<9}  none from dhcp");
+            continue;
+        };
+        for (kind, code) in &kinds {
+            let started = Instant::now();
+            let result = dns::query(host, server, *code);
+            let millis = started.elapsed().as_millis();
+            let answer = match result {
+                Ok((addrs, _)) if addrs.is_empty() => String::from("no records"),
+                Ok((addrs, ttl)) => format!("{} ttl {}s", join(&addrs), ttl.as_secs()),
+                Err(err) => format!("{err:#}"),
+            };
+            let _ = writeln!(
+                report,
+                "{name:<9}  {server:<15} {kind:<4} {millis:>5} ms  {answer}"
+            );
+        }
+    }
+
+    // getaddrinfo, with lwIP's own fallback between the two
+    let started = Instant::now();
+    let result = (*host, 0).to_socket_addrs();
+    let millis = started.elapsed().as_millis();
+    let answer = match result {
+        Ok(addrs) => join(&addrs.map(|addr| addr.ip()).collect::<Vec<_>>()),
+        Err(err) => err.to_string(),
+    };
+    let _ = write!(
+        report,
+        "{:<9}  {:<20} {millis:>5} ms  {answer}",
+        "system", ""
+    );
+    Ok(report)
+}
+
+fn join(addrs: &[IpAddr]) -> String {
+    addrs
+        .iter()
+        .map(IpAddr::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
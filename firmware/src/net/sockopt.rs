@@ -0,0 +1,145 @@
+//! TCP options for the connections the firmware dials, from `tcp_options`:
+//! comma separated `nodelay` or `delay`, `rcvbuf=<bytes>`, `sndbuf=<bytes>`
+//! and `keepalive=<idle>/<interval>/<count>` in seconds or `keepalive=off`.
+//! Nagle holds back the tail of every TLS record and lwIP never probes an
+//! idle connection, so a half-open one behind a dropped AP lingers for
+//! hours; without the key it's `nodelay,keepalive=60/10/3`.
+//!
+//! lwIP sizes send buffers at build time and only takes `rcvbuf` with
+//! `CONFIG_LWIP_SO_RCVBUF`, so either may be refused; that's logged and the
+//! connection goes ahead with the stack's.
+
+use crate::config::Config;
+use anyhow::{bail, Context, Result};
+use std::{ffi::c_void, io, os::fd::AsRawFd, sync::OnceLock, time::Duration};
+
+#[derive(Clone, Copy, Debug)]
+pub struct TcpOptions {
+    pub nodelay: bool,
+    pub recv_buffer: Option<u32>,
+    pub send_buffer: Option<u32>,
+    pub keepalive: Option<Keepalive>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Keepalive {
+    pub idle: Duration,
+    pub interval: Duration,
+    pub count: u32,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            recv_buffer: None,
+            send_buffer: None,
+            keepalive: Some(Keepalive {
+                idle: Duration::from_secs(60),
+                interval: Duration::from_secs(10),
+                count: 3,
+            }),
+        }
+    }
+}
+
+impl TcpOptions {
+    fn parse(value: &str) -> Result<Self> {
+        let mut options = Self::default();
+        for option in value
+            .split(',')
+            .map(str::trim)
+            .filter(|option| !option.is_empty())
+        {
+            match option.split_once('=') {
+                None if option == "nodelay" => options.nodelay = true,
+                None if option == "delay" => options.nodelay = false,
+                Some(("rcvbuf", bytes)) => {
+                    options.recv_buffer = Some(bytes.parse().context("rcvbuf takes bytes")?);
+                }
+                Some(("sndbuf", bytes)) => {
+                    options.send_buffer = Some(bytes.parse().context("sndbuf takes bytes")?);
+                }
+                Some(("keepalive", "off")) => options.keepalive = None,
+                Some(("keepalive", probes)) => {
+                    let mut probes = probes.split('/').map(str::parse::<u32>);
+                    let (Some(Ok(idle)), Some(Ok(interval)), Some(Ok(count)), None) =
+                        (probes.next(), probes.next(), probes.next(), probes.next())
+                    else {
+                        bail!("keepalive takes <idle>/<interval>/<count> or off");
+                    };
+                    options.keepalive = Some(Keepalive {
+                        idle: Duration::from_secs(idle.into()),
+                        interval: Duration::from_secs(interval.into()),
+                        count,
+                    });
+                }
+                _ => bail!("unknown tcp option {option}"),
+            }
+        }
+        Ok(options)
+    }
+}
+
+static OPTIONS: OnceLock<TcpOptions> = OnceLock::new();
+
+pub fn configure(config: &Config) -> Result<()> {
+    let options = TcpOptions::parse(&config.tcp_options).context("invalid tcp_options")?;
+    // a later call with the same config store is a no-op
+    let _ = OPTIONS.set(options);
+    Ok(())
+}
+
+pub fn options() -> TcpOptions {
+    OPTIONS.get().copied().unwrap_or_default()
+}
+
+/// Sets the options on a socket the firmware dialed itself.
+pub fn apply(socket: &impl AsRawFd) {
+    use esp_idf_sys::{
+        IPPROTO_TCP, SOL_SOCKET, SO_KEEPALIVE, SO_RCVBUF, SO_SNDBUF, TCP_KEEPCNT, TCP_KEEPIDLE,
+        TCP_KEEPINTVL, TCP_NODELAY,
+    };
+
+    let options = options();
+    let fd = socket.as_raw_fd();
+    let set = |name: &str, level: u32, option: u32, value: i32| {
+        if let Err(err) = setsockopt(fd, level, option, value) {
+            log::debug!("tcp {name} not set: {err}");
+        }
+    };
+    set("nodelay", IPPROTO_TCP, TCP_NODELAY, options.nodelay.into());
+    if let Some(bytes) = options.recv_buffer {
+        set("rcvbuf", SOL_SOCKET, SO_RCVBUF, bytes as i32);
+    }
+    if let Some(bytes) = options.send_buffer {
+        set("sndbuf", SOL_SOCKET, SO_SNDBUF, bytes as i32);
+    }
+    match options.keepalive {
+        Some(keepalive) => {
+            set("keepalive", SOL_SOCKET, SO_KEEPALIVE, 1);
+            let (idle, interval) = (keepalive.idle.as_secs(), keepalive.interval.as_secs());
+            set("keepidle", IPPROTO_TCP, TCP_KEEPIDLE, idle as i32);
+            set("keepintvl", IPPROTO_TCP, TCP_KEEPINTVL, interval as i32);
+            set("keepcnt", IPPROTO_TCP, TCP_KEEPCNT, keepalive.count as i32);
+        }
+        None => set("keepalive", SOL_SOCKET, SO_KEEPALIVE, 0),
+    }
+}
+
+fn setsockopt(fd: i32, level: u32, option: u32, value: i32) -> io::Result<()> {
+    let result = unsafe {
+        esp_idf_sys::lwip_setsockopt(
+            fd,
+            level as i32,
+            option as i32,
+            &value as *const i32 as *const c_void,
+            std::mem::size_of::<i32>() as u32,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
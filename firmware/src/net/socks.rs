@@ -0,0 +1,408 @@
+use crate::{
+    budget,
+    config::Config,
+    dns,
+    metrics::{self, Stage},
+    net::{self, counted::Counted, eyeballs, sockopt},
+    runtime,
+    secret::Secret,
+    startup, tls,
+};
+use anyhow::{ensure, Context, Result};
+use rustls::pki_types::ServerName;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use tokio_socks::tcp::Socks5Stream;
+
+/// SOCKS5 bastion every outbound connection is routed through when set,
+/// from the `socks_proxy` config key as `[user:password@]host:port`. With
+/// `wpad` the proxy can also be an HTTP one, picked per host.
+#[derive(Clone)]
+pub struct Proxy {
+    kind: Kind,
+    host: String,
+    port: u16,
+    auth: Option<(String, Secret<String>)>,
+}
+
+// only proxy auto-config names HTTP proxies
+#[cfg_attr(not(feature = "wpad"), allow(dead_code))]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Socks5,
+    /// Tunnels with `CONNECT`.
+    Http,
+}
+
+/// Longest answer to a `CONNECT` taken from an HTTP proxy.
+const MAX_CONNECT_HEAD: usize = 1024;
+
+static PROXY: OnceLock<Option<Proxy>> = OnceLock::new();
+
+impl Proxy {
+    /// An unauthenticated proxy on this device.
+    #[cfg(any(feature = "faults", feature = "pcap"))]
+    pub fn loopback(port: u16) -> Self {
+        Self {
+            kind: Kind::Socks5,
+            host: String::from("127.0.0.1"),
+            port,
+            auth: None,
+        }
+    }
+
+    /// An unauthenticated proxy, as a proxy auto-config script names them.
+    #[cfg(feature = "wpad")]
+    pub fn new(kind: Kind, host: &str, port: u16) -> Self {
+        Self {
+            kind,
+            host: host.to_owned(),
+            port,
+            auth: None,
+        }
+    }
+
+    fn parse(value: &str) -> Result<Self> {
+        let (auth, addr) = match value.rsplit_once('@') {
+            Some((auth, addr)) => {
+                let (user, password) = auth.split_once(':').unwrap_or((auth, ""));
+                (
+                    Some((user.to_owned(), Secret::new(password.to_owned()))),
+                    addr,
+                )
+            }
+            None => (None, value),
+        };
+        let (host, port) = addr
+            .rsplit_once(':')
+            .context("socks proxy needs host:port")?;
+
+        Ok(Self {
+            kind: Kind::Socks5,
+            host: host.to_owned(),
+            port: port.parse().context("invalid socks proxy port")?,
+            auth,
+        })
+    }
+
+    /// The proxy as a `socks5h://` url, for clients with built-in support.
+    /// Names are resolved by the proxy, since the bastion is usually the
+    /// only thing that can see the backend's DNS.
+    pub fn url(&self) -> String {
+        if self.kind == Kind::Http {
+            return format!("http://{}:{}", self.host, self.port);
+        }
+        match &self.auth {
+            Some((user, password)) => {
+                let password = password.expose();
+                format!("socks5h://{user}:{password}@{}:{}", self.host, self.port)
+            }
+            None => format!("socks5h://{}:{}", self.host, self.port),
+        }
+    }
+
+    async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        if self.kind == Kind::Http {
+            return self.tunnel(host, port).await;
+        }
+        let proxy = (self.host.as_str(), self.port);
+        let stream = match &self.auth {
+            Some((user, password)) => {
+                Socks5Stream::connect_with_password(proxy, (host, port), user, password.expose())
+                    .await
+            }
+            None => Socks5Stream::connect(proxy, (host, port)).await,
+        }
+        .with_context(|| format!("socks proxy couldn't reach {host}:{port}"))?;
+
+        let stream = stream.into_inner();
+        sockopt::apply(&stream);
+        Ok(stream)
+    }
+
+    async fn tunnel(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .with_context(|| format!("http proxy {}:{} unreachable", self.host, self.port))?;
+        sockopt::apply(&stream);
+        let target = format!("{host}:{port}");
+        let request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // a byte at a time, so nothing of the tunnel past the head is read
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            ensure!(head.len() < MAX_CONNECT_HEAD, "http proxy answer too long");
+            head.push(stream.read_u8().await?);
+        }
+        let head = String::from_utf8_lossy(&head);
+        let status = head.lines().next().unwrap_or_default();
+        ensure!(
+            status.split_whitespace().nth(1) == Some("200"),
+            "http proxy refused {target}: {status}"
+        );
+        Ok(stream)
+    }
+}
+
+pub fn configure(config: &Config) -> Result<()> {
+    let proxy = if config.socks_proxy.expose().is_empty() {
+        None
+    } else {
+        Some(Proxy::parse(config.socks_proxy.expose())?)
+    };
+    // a later call with the same config store is a no-op
+    let _ = PROXY.set(proxy);
+    Ok(())
+}
+
+pub fn proxy() -> Option<&'static Proxy> {
+    #[cfg(feature = "faults")]
+    if let Some(proxy) = crate::faults::proxy() {
+        return Some(proxy);
+    }
+    #[cfg(feature = "pcap")]
+    if let Some(proxy) = crate::pcap::proxy() {
+        return Some(proxy);
+    }
+    PROXY.get().and_then(Option::as_ref)
+}
+
+/// The proxy a connection to `host` goes through: the configured one, or
+/// what the WPAD script says, `None` for a direct connection.
+#[cfg_attr(not(feature = "wpad"), allow(unused_variables))]
+pub fn route(host: &str) -> Option<Proxy> {
+    if let Some(proxy) = proxy() {
+        return Some(proxy.clone());
+    }
+    #[cfg(feature = "wpad")]
+    return super::wpad::route(host);
+    #[cfg(not(feature = "wpad"))]
+    None
+}
+
+/// Opens a TCP connection to `host:port`, through the proxy when one is
+/// configured or auto-detected, with the `tcp_options` set. Without a proxy every address
+/// the name resolves to is raced.
+// the lean builds have no client dialing its own sockets
+#[cfg_attr(
+    not(any(
+        feature = "grpc",
+        feature = "early-data",
+        feature = "ws",
+        feature = "modbus",
+        feature = "bench"
+    )),
+    allow(dead_code)
+)]
+pub async fn connect(host: &str, port: u16) -> Result<TcpStream> {
+    match route(host) {
+        Some(proxy) => through(&proxy, host, port).await,
+        None => {
+            let addrs = dns::resolve_addrs(host, port).await?;
+            let (addr, stream) = eyeballs::race(addrs, |addr| dial(host, addr)).await?;
+            log::info!("{host}:{port} connected over {}", net::family(addr.ip()));
+            Ok(stream)
+        }
+    }
+}
+
+/// [`connect`] with TLS on top, for `client` in the handshake metrics.
+/// Without a proxy the race is to a finished handshake, so an address that
+/// takes the connection but never answers the hello loses too. The bytes
+/// the session moves are counted against `host`.
+pub async fn connect_tls(
+    host: &str,
+    port: u16,
+    connector: &TlsConnector,
+    client: &'static str,
+) -> Result<TlsStream<Counted<TcpStream>>> {
+    let name = ServerName::try_from(host.to_owned())?;
+    let handshake = |stream: TcpStream| {
+        let name = name.clone();
+        async move {
+            let start = Instant::now();
+            // the session's buffers are allocated here and stay charged to it
+            let stream = budget::TLS
+                .track(connector.connect(name, Counted::new(stream, host)))
+                .await
+                .inspect_err(|_| metrics::failed(host, Stage::Tls(client)))?;
+            metrics::reached(host, Stage::Tls(client), start.elapsed());
+            tls::suites::observe(client, host, stream.get_ref().1);
+            startup::mark(startup::Phase::TlsHandshake);
+            anyhow::Ok(stream)
+        }
+    };
+
+    let handshake = &handshake;
+    let _boost = crate::power::boost();
+    match route(host) {
+        Some(proxy) => handshake(through(&proxy, host, port).await?).await,
+        None => {
+            let addrs = dns::resolve_addrs(host, port).await?;
+            let (addr, stream) = eyeballs::race(addrs, |addr| async move {
+                handshake(dial(host, addr).await?).await
+            })
+            .await?;
+            log::info!("{host}:{port} connected over {}", net::family(addr.ip()));
+            Ok(stream)
+        }
+    }
+}
+
+/// A connection to one of `host`'s addresses, timed against it.
+async fn dial(host: &str, addr: SocketAddr) -> Result<TcpStream> {
+    let start = Instant::now();
+    let stream = TcpStream::connect(addr)
+        .await
+        .inspect_err(|_| metrics::failed(host, Stage::Connect))?;
+    metrics::reached(host, Stage::Connect, start.elapsed());
+    sockopt::apply(&stream);
+    Ok(stream)
+}
+
+/// `host:port` through `proxy`, timed as the connect to `host` since the
+/// proxy's own connect is part of it.
+async fn through(proxy: &Proxy, host: &str, port: u16) -> Result<TcpStream> {
+    let start = Instant::now();
+    let stream = proxy
+        .connect(host, port)
+        .await
+        .inspect_err(|_| metrics::failed(host, Stage::Connect))?;
+    metrics::reached(host, Stage::Connect, start.elapsed());
+    Ok(stream)
+}
+
+/// How long the relay waits after a failed accept.
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The relays running, by host, port and `tls`.
+type Relays = HashMap<(String, u16, bool), SocketAddr>;
+static RELAYS: Mutex<Option<Relays>> = Mutex::new(None);
+
+/// Loopback relay for clients that insist on dialing their own socket:
+/// they connect to the returned address in plain TCP and the relay carries
+/// the bytes to `host:port` through the proxy, adding TLS on the proxied
+/// leg when `tls` is set so the server name is still verified. A
+/// destination gets one relay, a later call returns the same address.
+// only rumqttc dials its own sockets
+#[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+pub fn relay(host: &str, port: u16, tls: bool) -> Result<SocketAddr> {
+    let mut relays = RELAYS.lock().unwrap();
+    let relays = relays.get_or_insert_with(HashMap::new);
+    let key = (host.to_owned(), port, tls);
+    if let Some(addr) = relays.get(&key) {
+        return Ok(*addr);
+    }
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+    let listener = TcpListener::from_std(listener)?;
+
+    log::info!("socks relay for {host}:{port} on {addr}");
+
+    let host = host.to_owned();
+    runtime::spawn(async move {
+        loop {
+            let client = match listener.accept().await {
+                Ok((client, _)) => client,
+                Err(err) => {
+                    log::warn!("socks relay accept failed: {err}");
+                    // out of sockets, most likely; spinning won't free one
+                    runtime::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            let host = host.clone();
+            runtime::spawn(async move {
+                if let Err(err) = forward(client, &host, port, tls).await {
+                    log::warn!("socks relay to {host}:{port} failed: {err:#}");
+                }
+            });
+        }
+    });
+
+    relays.insert(key, addr);
+    Ok(addr)
+}
+
+async fn forward(mut client: TcpStream, host: &str, port: u16, tls: bool) -> Result<()> {
+    if !tls {
+        let mut upstream = connect(host, port).await?;
+        tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+        return Ok(());
+    }
+
+    let connector = TlsConnector::from(tls::client_config_for(host));
+    let mut upstream = connect_tls(host, port, &connector, "socks").await?;
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+#[cfg(any(feature = "faults", feature = "pcap"))]
+const SOCKS_VERSION: u8 = 5;
+#[cfg(any(feature = "faults", feature = "pcap"))]
+const SOCKS_CONNECT: u8 = 1;
+#[cfg(any(feature = "faults", feature = "pcap"))]
+const ATYP_IPV4: u8 = 1;
+#[cfg(any(feature = "faults", feature = "pcap"))]
+const ATYP_DOMAIN: u8 = 3;
+#[cfg(any(feature = "faults", feature = "pcap"))]
+const ATYP_IPV6: u8 = 4;
+
+/// The server side of a loopback proxy: reads the SOCKS5 greeting and
+/// CONNECT request, no authentication, and returns the target asked for.
+#[cfg(any(feature = "faults", feature = "pcap"))]
+pub async fn accept(client: &mut TcpStream) -> Result<(String, u16)> {
+    let mut header = [0; 2];
+    client.read_exact(&mut header).await?;
+    ensure!(header[0] == SOCKS_VERSION, "not a socks5 client");
+    let mut methods = vec![0; header[1].into()];
+    client.read_exact(&mut methods).await?;
+    client.write_all(&[SOCKS_VERSION, 0]).await?;
+
+    let mut request = [0; 4];
+    client.read_exact(&mut request).await?;
+    ensure!(request[1] == SOCKS_CONNECT, "only CONNECT is proxied");
+    let host = match request[3] {
+        ATYP_IPV4 => {
+            let mut ip = [0; 4];
+            client.read_exact(&mut ip).await?;
+            std::net::Ipv4Addr::from(ip).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0; 16];
+            client.read_exact(&mut ip).await?;
+            std::net::Ipv6Addr::from(ip).to_string()
+        }
+        ATYP_DOMAIN => {
+            let len = client.read_u8().await?;
+            let mut name = vec![0; len.into()];
+            client.read_exact(&mut name).await?;
+            String::from_utf8(name).context("bad socks host name")?
+        }
+        other => anyhow::bail!("unknown socks address type {other}"),
+    };
+    let port = client.read_u16().await?;
+    Ok((host, port))
+}
+
+/// Tells a client `accept()` took that its target is connected.
+#[cfg(any(feature = "faults", feature = "pcap"))]
+pub async fn granted(client: &mut TcpStream) -> Result<()> {
+    // success, with an address the client doesn't look at
+    client
+        .write_all(&[SOCKS_VERSION, 0, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
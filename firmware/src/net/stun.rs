@@ -0,0 +1,216 @@
+use crate::{config::Config, dns, events, runtime, telemetry};
+use anyhow::{bail, ensure, Context, Result};
+use serde::Serialize;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+const DEFAULT_SERVERS: &str = "stun.l.google.com:19302,stun.cloudflare.com:3478";
+const DEFAULT_PORT: u16 = 3478;
+/// Doubled on every retransmit, like RFC 5389's RTO.
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(500);
+const ATTEMPTS: usize = 3;
+
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_RESPONSE: u16 = 0x0101;
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const HEADER_LEN: usize = 20;
+
+/// How the NAT in front of the device maps its UDP socket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NatType {
+    /// The public endpoint is the local one, no NAT at all.
+    Open,
+    /// Every server saw the same endpoint, so peers can reuse it.
+    EndpointIndependent,
+    /// Each server saw a different port, hole punching won't work.
+    Symmetric,
+    /// Only one server answered, mapping behaviour unknown.
+    Unknown,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Mapping {
+    pub local: SocketAddr,
+    pub public: SocketAddr,
+    pub nat: NatType,
+}
+
+/// Rediscovers the public endpoint every time the network comes up and
+/// reports it through telemetry.
+pub fn start(config: &Config) {
+    let servers: Vec<String> = if config.stun_servers.is_empty() {
+        DEFAULT_SERVERS
+    } else {
+        config.stun_servers.as_str()
+    }
+    .split(',')
+    .map(|server| server.trim().to_owned())
+    .filter(|server| !server.is_empty())
+    .collect();
+
+    runtime::spawn_named("stun", move || watch(servers.clone()));
+}
+
+async fn watch(servers: Vec<String>) -> Result<()> {
+    loop {
+        events::wait_until(|state| state.net_up).await;
+        match discover(&servers).await {
+            Ok(mapping) => {
+                log::info!(
+                    "public endpoint {} (local {}), nat {:?}",
+                    mapping.public,
+                    mapping.local,
+                    mapping.nat
+                );
+                telemetry::set("public_endpoint", mapping.public.to_string());
+                telemetry::set("nat_type", serde_json::to_value(mapping.nat)?);
+            }
+            Err(err) => log::warn!("stun discovery failed: {err:#}"),
+        }
+        events::wait_until(|state| !state.net_up).await;
+    }
+}
+
+/// Asks up to two servers for our mapping from the same socket and compares
+/// the answers to classify the NAT.
+pub async fn discover(servers: &[String]) -> Result<Mapping> {
+    let mut addrs = Vec::new();
+    for server in servers.iter().take(2) {
+        let (host, port) = match server.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().context("invalid stun port")?),
+            None => (server.as_str(), DEFAULT_PORT),
+        };
+        match dns::resolve_addrs(host, port).await {
+            // the socket is IPv4, so is the mapping we care about
+            Ok(resolved) => addrs.extend(resolved.into_iter().find(SocketAddr::is_ipv4)),
+            Err(err) => log::debug!("stun server {server}: {err:#}"),
+        }
+    }
+    ensure!(!addrs.is_empty(), "no stun server resolved");
+
+    runtime::run_blocking(move || {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        let mut local = None;
+        let mut public = Vec::new();
+        for server in addrs {
+            // connecting picks the source address, which the unbound local
+            // address can't tell us
+            socket.connect(server)?;
+            local.get_or_insert(socket.local_addr()?);
+            match binding(&socket) {
+                Ok(mapped) => public.push(mapped),
+                Err(err) => log::debug!("stun {server}: {err:#}"),
+            }
+        }
+
+        let local = local.context("stun socket never connected")?;
+        let nat = match public.as_slice() {
+            [] => bail!("no stun server answered"),
+            [first, ..] if *first == local => NatType::Open,
+            [_] => NatType::Unknown,
+            [first, second, ..] if first == second => NatType::EndpointIndependent,
+            _ => NatType::Symmetric,
+        };
+        Ok(Mapping {
+            local,
+            public: public[0],
+            nat,
+        })
+    })
+    .await?
+}
+
+/// One Binding transaction against the server the socket is connected to.
+fn binding(socket: &UdpSocket) -> Result<SocketAddr> {
+    let mut transaction = [0; 12];
+    for chunk in transaction.chunks_mut(4) {
+        chunk.copy_from_slice(&crate::device::random().to_be_bytes());
+    }
+
+    let mut request = Vec::with_capacity(HEADER_LEN);
+    request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(&transaction);
+
+    let mut timeout = INITIAL_TIMEOUT;
+    let mut response = [0; 512];
+    for _ in 0..ATTEMPTS {
+        socket.set_read_timeout(Some(timeout))?;
+        socket.send(&request)?;
+        // a timeout ends the wait for this attempt
+        while let Ok(len) = socket.recv(&mut response) {
+            // ignore answers to earlier transactions
+            if len >= HEADER_LEN && response[8..HEADER_LEN] == transaction {
+                return parse(&response[..len], &transaction);
+            }
+        }
+        timeout *= 2;
+    }
+    bail!("no binding response")
+}
+
+fn parse(message: &[u8], transaction: &[u8; 12]) -> Result<SocketAddr> {
+    let u16_at = |at: usize| u16::from_be_bytes([message[at], message[at + 1]]);
+    ensure!(
+        u16_at(0) == BINDING_RESPONSE,
+        "unexpected stun message {:#06x}",
+        u16_at(0)
+    );
+    let end = (HEADER_LEN + u16_at(2) as usize).min(message.len());
+
+    let mut mapped = None;
+    let mut at = HEADER_LEN;
+    while at + 4 <= end {
+        let kind = u16_at(at);
+        let len = u16_at(at + 2) as usize;
+        let value = message
+            .get(at + 4..at + 4 + len)
+            .context("truncated attribute")?;
+        match kind {
+            ATTR_XOR_MAPPED_ADDRESS => return address(value, Some(transaction)),
+            ATTR_MAPPED_ADDRESS => mapped = Some(address(value, None)?),
+            _ => {}
+        }
+        // attributes are padded to 32 bits
+        at += 4 + len.div_ceil(4) * 4;
+    }
+    mapped.context("binding response without a mapped address")
+}
+
+/// Decodes a (XOR-)MAPPED-ADDRESS value; `transaction` is set for the XOR
+/// variant, whose fields are masked with the cookie and transaction id.
+fn address(value: &[u8], transaction: Option<&[u8; 12]>) -> Result<SocketAddr> {
+    ensure!(value.len() >= 8, "short address attribute");
+    let mut mask = [0; 16];
+    if let Some(transaction) = transaction {
+        mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        mask[4..].copy_from_slice(transaction);
+    }
+
+    let port = u16::from_be_bytes([value[2] ^ mask[0], value[3] ^ mask[1]]);
+    let ip = match value[1] {
+        0x01 => {
+            let mut octets = [0; 4];
+            for (i, octet) in octets.iter_mut().enumerate() {
+                *octet = value[4 + i] ^ mask[i];
+            }
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        0x02 => {
+            ensure!(value.len() >= 20, "short ipv6 address attribute");
+            let mut octets = [0; 16];
+            for (i, octet) in octets.iter_mut().enumerate() {
+                *octet = value[4 + i] ^ mask[i];
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        family => bail!("unknown address family {family}"),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
@@ -0,0 +1,69 @@
+//! The reconnect state of one link, apart from the loop that acts on it so
+//! the simulator runs and tests the same backoff.
+
+use crate::error::FirmwareError;
+use std::time::Duration;
+
+pub const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+pub const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkState {
+    Connecting,
+    Up,
+    /// Lost after being up; reconnects straight away.
+    Down,
+    /// Failed to connect, trying again after this long.
+    Backoff(Duration),
+}
+
+/// Walks a link through `LinkState`: connecting, then up or backing off,
+/// and down again once it drops. The delay doubles from
+/// `RECONNECT_MIN_DELAY` with each failed attempt, up to
+/// `RECONNECT_MAX_DELAY`, and starts over once the link comes up.
+pub struct Supervisor {
+    state: LinkState,
+    delay: Duration,
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self {
+            state: LinkState::Connecting,
+            delay: RECONNECT_MIN_DELAY,
+        }
+    }
+}
+
+impl Supervisor {
+    // only the simulator's tests look, the device logs each change instead
+    #[cfg_attr(target_os = "espidf", allow(dead_code))]
+    pub fn state(&self) -> LinkState {
+        self.state
+    }
+
+    pub fn connecting(&mut self) {
+        self.state = LinkState::Connecting;
+    }
+
+    pub fn connected(&mut self) {
+        self.state = LinkState::Up;
+        self.delay = RECONNECT_MIN_DELAY;
+    }
+
+    pub fn disconnected(&mut self) {
+        self.state = LinkState::Down;
+    }
+
+    /// How long to wait before the next attempt after `err`.
+    pub fn failed(&mut self, err: &anyhow::Error) -> Duration {
+        // retrying straight away won't help, only a config change will
+        if !FirmwareError::is_retryable(err) {
+            self.delay = RECONNECT_MAX_DELAY;
+        }
+        let delay = self.delay;
+        self.state = LinkState::Backoff(delay);
+        self.delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+        delay
+    }
+}
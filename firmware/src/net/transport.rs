@@ -0,0 +1,36 @@
+use anyhow::Result;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::netif::EspNetif;
+use std::future::Future;
+
+/// What the reconnect loop drives: a link that comes up and later goes
+/// down again. Nothing of ESP-IDF, so the simulator's links and its tests'
+/// mocks are one too.
+pub trait NetworkManager: Send + 'static {
+    fn name(&self) -> &'static str;
+
+    /// Brings the link up until the interface has an IPv4 address.
+    fn connect(&mut self) -> impl Future<Output = Result<()>> + Send;
+
+    /// Resolves once the link has gone down again.
+    fn wait_disconnected(&mut self) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// A link lwIP can route over. The supervisor in `net::run()` only sees
+/// this, so TLS, HTTP and everything above stay the same whichever link
+/// carries the traffic.
+pub trait NetTransport: NetworkManager {
+    /// Only on the device, the simulator's link has none.
+    #[cfg(target_os = "espidf")]
+    fn netif(&self) -> &EspNetif;
+
+    /// Whether the link is a radio that quiet hours may take down.
+    fn radio(&self) -> bool {
+        false
+    }
+
+    /// Takes a `radio()` link down; `connect()` brings it back.
+    fn suspend(&mut self) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+}
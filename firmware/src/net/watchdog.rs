@@ -0,0 +1,37 @@
+use super::{gateway, ping};
+use crate::events::{self, Event};
+use anyhow::Result;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+const PING_COUNT: u32 = 3;
+const MAX_FAILURES: u32 = 3;
+
+static FAILURES: AtomicU32 = AtomicU32::new(0);
+
+/// One watchdog round: pings the gateway and, after `MAX_FAILURES` rounds in
+/// a row without a single reply, declares the link dead and forces the
+/// station to reconnect.
+pub async fn check() -> Result<()> {
+    let Some(gateway) = gateway() else {
+        return Ok(());
+    };
+
+    let stats = ping(&gateway.to_string(), PING_COUNT).await?;
+    log::debug!("watchdog ping {gateway}: {stats}");
+
+    if stats.received > 0 {
+        FAILURES.store(0, Ordering::Relaxed);
+        return Ok(());
+    }
+
+    let failures = FAILURES.fetch_add(1, Ordering::Relaxed) + 1;
+    if failures >= MAX_FAILURES {
+        log::error!("watchdog: gateway {gateway} unreachable {failures} times, reconnecting wifi");
+        FAILURES.store(0, Ordering::Relaxed);
+        // the supervisor in net::run() reconnects once the disconnect lands
+        esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_wifi_disconnect() })?;
+        events::publish(Event::WifiDead);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,233 @@
+//! The station link and the radio features that come with it. `wifi_phy`
+//! picks the 802.11 modes, with Espressif's long range (LR) mode for links
+//! between two of its chips too far apart for b/g/n: set on the station,
+//! and on the soft AP whenever one is up, before the radio starts.
+
+use super::{run, NetTransport, NetworkManager};
+use crate::{
+    console,
+    error::{Failure, FirmwareError},
+    events::{self, Event},
+    runtime, startup,
+};
+use anyhow::{bail, Context, Result};
+use esp_idf_svc::{
+    eventloop::{EspSubscription, EspSystemEventLoop, System},
+    netif::EspNetif,
+    wifi::{AsyncWifi, EspWifi, WifiEvent},
+};
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Mutex,
+    },
+};
+
+const WIFI_SSID: &str = include_str!("../../config_ssid.txt");
+const WIFI_PASSWORD: &str = include_str!("../../config_password.txt");
+
+/// Records the console's `wifi scan` lists.
+const SCAN_RECORDS: usize = 20;
+
+const BGN: u8 = (esp_idf_sys::WIFI_PROTOCOL_11B
+    | esp_idf_sys::WIFI_PROTOCOL_11G
+    | esp_idf_sys::WIFI_PROTOCOL_11N) as u8;
+const LR: u8 = esp_idf_sys::WIFI_PROTOCOL_LR as u8;
+
+/// The protocol bitmap `wifi_phy` asks for.
+static PROTOCOL: AtomicU8 = AtomicU8::new(BGN);
+/// The access point the station last associated with.
+static BSSID: Mutex<Option<[u8; 6]>> = Mutex::new(None);
+
+/// Takes `wifi_phy`, for the next time the radio starts.
+pub fn configure(config: &crate::config::Config) -> Result<()> {
+    let protocol = match config.wifi_phy.as_str() {
+        "" | "bgn" => BGN,
+        "lr" => LR,
+        "bgn+lr" => BGN | LR,
+        other => bail!("unknown wifi_phy {other}, takes bgn, lr or bgn+lr"),
+    };
+    PROTOCOL.store(protocol, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Sets the modes on the interfaces the radio's mode has.
+fn set_protocol() -> Result<()> {
+    let protocol = PROTOCOL.load(Ordering::Relaxed);
+    let mut mode = esp_idf_sys::wifi_mode_t_WIFI_MODE_NULL;
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_wifi_get_mode(&mut mode) })?;
+    esp_idf_sys::esp!(unsafe {
+        esp_idf_sys::esp_wifi_set_protocol(esp_idf_sys::wifi_interface_t_WIFI_IF_STA, protocol)
+    })?;
+    if mode == esp_idf_sys::wifi_mode_t_WIFI_MODE_APSTA {
+        esp_idf_sys::esp!(unsafe {
+            esp_idf_sys::esp_wifi_set_protocol(esp_idf_sys::wifi_interface_t_WIFI_IF_AP, protocol)
+        })?;
+    }
+    if protocol & LR != 0 {
+        log::info!(
+            "wifi long range mode on{}",
+            if protocol == LR { ", only" } else { "" }
+        );
+    }
+    Ok(())
+}
+
+pub(super) fn start(wifi: AsyncWifi<EspWifi<'static>>) {
+    console::register(console::Command {
+        name: "wifi",
+        usage: "wifi scan",
+        summary: "list the access points in range",
+        run: scan_command,
+    });
+    runtime::spawn(run(wifi));
+    runtime::spawn(migrate());
+}
+
+impl NetworkManager for AsyncWifi<EspWifi<'static>> {
+    fn name(&self) -> &'static str {
+        "wifi"
+    }
+
+    async fn connect(&mut self) -> Result<()> {
+        if !self.is_started()? {
+            let (ssid, password) = credentials();
+            // credentials that don't fit won't on the next attempt either
+            let ssid: heapless::String<32> = heapless::String::try_from(ssid.as_str())
+                .context("couldn't convert wifi ssid text")
+                .map_err(|err| FirmwareError::Wifi(Failure::permanent(err)))?;
+            let password: heapless::String<64> = heapless::String::try_from(password.as_str())
+                .context("couldn't convert wifi password text")
+                .map_err(|err| FirmwareError::Wifi(Failure::permanent(err)))?;
+
+            self.set_configuration(&esp_idf_svc::wifi::Configuration::Client(
+                esp_idf_svc::wifi::ClientConfiguration {
+                    ssid: ssid.parse().unwrap(),
+                    auth_method: esp_idf_svc::wifi::AuthMethod::WPA2Personal,
+                    password: password.parse().unwrap(),
+                    ..Default::default()
+                },
+            ))?;
+            set_protocol().context("couldn't set the wifi modes")?;
+
+            self.start().await.context("wifi couldn't start")?;
+            startup::mark(startup::Phase::WifiStart);
+        }
+
+        AsyncWifi::connect(self)
+            .await
+            .context("wifi couldn't connect")?;
+        self.wait_netif_up().await.context("wifi netif_up failed")?;
+        let phy = super::phy();
+        log::info!("wifi associated over {}", phy.unwrap_or("unknown phy"));
+        crate::telemetry::set("wifi_phy", phy);
+        Ok(())
+    }
+
+    async fn wait_disconnected(&mut self) -> Result<()> {
+        Ok(self.wifi_wait(|wifi| wifi.is_connected(), None).await?)
+    }
+}
+
+impl NetTransport for AsyncWifi<EspWifi<'static>> {
+    fn netif(&self) -> &EspNetif {
+        self.wifi().sta_netif()
+    }
+
+    fn radio(&self) -> bool {
+        true
+    }
+
+    /// Stopped, not just disconnected, so the radio is off.
+    async fn suspend(&mut self) -> Result<()> {
+        AsyncWifi::disconnect(self).await?;
+        self.stop().await.context("wifi couldn't stop")
+    }
+}
+
+/// The network the factory line set, else the one built in.
+fn credentials() -> (String, String) {
+    #[cfg(feature = "factory")]
+    if let Some(ssid) = crate::factory::get("wifi_ssid").filter(|ssid| !ssid.is_empty()) {
+        let password = crate::factory::get("wifi_pass").unwrap_or_default();
+        return (ssid, password);
+    }
+    (WIFI_SSID.to_owned(), WIFI_PASSWORD.to_owned())
+}
+
+/// Logs why the station lost its association, the supervisor handles the
+/// reconnect itself, and publishes `Event::Roamed` when it associates with
+/// another BSSID than the last.
+pub fn watch_link(sys_loop: &EspSystemEventLoop) -> Result<EspSubscription<'static, System>> {
+    Ok(sys_loop.subscribe::<WifiEvent, _>(|event| match event {
+        WifiEvent::StaDisconnected(info) => {
+            log::warn!("wifi disconnected, reason {}", info.reason());
+        }
+        WifiEvent::StaConnected(info) => {
+            let bssid = info.bssid();
+            let last = BSSID.lock().unwrap().replace(bssid);
+            if let Some(last) = last.filter(|last| *last != bssid) {
+                let (from, to) = (bssid_text(last), bssid_text(bssid));
+                log::info!("wifi roamed from {from} to {to}");
+                events::publish(Event::Roamed { from, to });
+            }
+        }
+        _ => {}
+    })?)
+}
+
+fn bssid_text(bssid: [u8; 6]) -> String {
+    let octets: Vec<String> = bssid.iter().map(|octet| format!("{octet:02x}")).collect();
+    octets.join(":")
+}
+
+/// Moves the connections kept open onto the new access point after a roam.
+/// One on another subnet, or behind a mesh node doing its own NAT, never
+/// saw them, and they'd hang until TCP gave up: the idle HTTP connections
+/// are dropped, the DNS answers asked for again once the link is up, and
+/// MQTT reconnects on its own, see `net::roamed()`.
+async fn migrate() {
+    let mut bus = events::subscribe();
+    loop {
+        super::roamed(&mut bus).await;
+        #[cfg(feature = "http-reqwest")]
+        crate::http::close_idle();
+        events::wait_until(|state| state.net_up).await;
+        crate::dns::refresh().await;
+    }
+}
+
+/// Blocking active scan on the station interface, up to `max` records,
+/// strongest first. The connection stays up, the radio just hops channels
+/// for a couple of seconds.
+pub fn scan(max: usize) -> Result<Vec<esp_idf_sys::wifi_ap_record_t>> {
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_wifi_scan_start(std::ptr::null(), true) })?;
+
+    let mut count = max as u16;
+    let mut records = vec![esp_idf_sys::wifi_ap_record_t::default(); max];
+    esp_idf_sys::esp!(unsafe {
+        esp_idf_sys::esp_wifi_scan_get_ap_records(&mut count, records.as_mut_ptr())
+    })?;
+    records.truncate(count.into());
+    Ok(records)
+}
+
+fn scan_command(_: &console::Console, args: &[&str]) -> Result<String> {
+    if args != ["scan"] {
+        bail!("usage: wifi scan");
+    }
+    let records = scan(SCAN_RECORDS)?;
+    let mut reply = format!("{} access point(s)", records.len());
+    for record in records {
+        let ssid = std::ffi::CStr::from_bytes_until_nul(&record.ssid)
+            .map(|ssid| ssid.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let _ = write!(
+            reply,
+            "\n  {:<32} {:>4} dBm  ch {}",
+            ssid, record.rssi, record.primary
+        );
+    }
+    Ok(reply)
+}
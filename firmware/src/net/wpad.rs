@@ -0,0 +1,377 @@
+//! Web proxy auto-discovery, for enterprise networks whose only way out is
+//! a proxy. With `wpad` set, every time the network comes up a proxy
+//! auto-config script is looked for, and `socks::route` asks it which
+//! proxy, if any, a host is reached through.
+//!
+//! The script comes from the configured url, or from
+//! `http://wpad.<domain>/wpad.dat` for the configured domain and each of
+//! its parents down to two labels, or from the bare `wpad` name with `on`.
+//! DHCP option 252 would name it too, but the lwIP DHCP client in ESP-IDF
+//! drops the options it doesn't use itself, so it's never seen.
+//!
+//! PAC is JavaScript, and only a subset is understood: `FindProxyForURL`
+//! as a run of `if`s that each return a string, on `isPlainHostName(host)`,
+//! `dnsDomainIs(host, ..)`, `localHostOrDomainIs(host, ..)` and
+//! `shExpMatch(host, ..)` joined by `||`, then a last `return`. Any other
+//! condition never matches, and of an answer the first `DIRECT`, `PROXY`
+//! or `SOCKS5` entry is taken.
+
+use super::socks::{Kind, Proxy};
+use crate::{config::Config, dns, events, runtime};
+use anyhow::{bail, ensure, Context, Result};
+use std::{sync::Mutex, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_SCRIPT: usize = 32 * 1024;
+
+/// The script found for the network that's up, if any.
+static SCRIPT: Mutex<Option<Script>> = Mutex::new(None);
+
+pub fn start(config: &Config) {
+    let urls = script_urls(&config.wpad);
+    // a configured proxy always wins, there's nothing to discover
+    if urls.is_empty() || !config.socks_proxy.expose().is_empty() {
+        return;
+    }
+
+    runtime::spawn(async move {
+        loop {
+            events::wait_until(|state| state.net_up).await;
+            match discover(&urls).await {
+                Some((url, script)) => {
+                    log::info!("wpad: {} rules from {url}", script.rules.len());
+                    *SCRIPT.lock().unwrap() = Some(script);
+                }
+                None => log::info!("wpad: no proxy script found, connecting directly"),
+            }
+            events::wait_until(|state| !state.net_up).await;
+            *SCRIPT.lock().unwrap() = None;
+        }
+    });
+}
+
+/// The proxy the script picks for `host`, `None` for a direct connection
+/// or without a script.
+pub fn route(host: &str) -> Option<Proxy> {
+    SCRIPT.lock().unwrap().as_ref()?.find(host)
+}
+
+fn script_urls(value: &str) -> Vec<String> {
+    match value.trim() {
+        "" => Vec::new(),
+        "on" => vec![String::from("http://wpad/wpad.dat")],
+        url if url.contains("://") => vec![url.to_owned()],
+        domain => {
+            let labels: Vec<&str> = domain.trim_matches('.').split('.').collect();
+            (0..labels.len().saturating_sub(1).max(1))
+                .map(|skip| format!("http://wpad.{}/wpad.dat", labels[skip..].join(".")))
+                .collect()
+        }
+    }
+}
+
+async fn discover(urls: &[String]) -> Option<(&str, Script)> {
+    for url in urls {
+        let script = tokio::time::timeout(FETCH_TIMEOUT, download(url))
+            .await
+            .context("timed out")
+            .and_then(|result| result)
+            .and_then(|source| Script::parse(&source));
+        match script {
+            Ok(script) => return Some((url, script)),
+            Err(err) => log::debug!("wpad: {url}: {err:#}"),
+        }
+    }
+    None
+}
+
+/// A plain GET, always direct: the script is what says whether to proxy.
+async fn download(url: &str) -> Result<String> {
+    let rest = url
+        .strip_prefix("http://")
+        .context("the script url has to be http")?;
+    let (authority, path) = rest.find('/').map_or((rest, "/"), |at| rest.split_at(at));
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().context("invalid port")?),
+        None => (authority, 80),
+    };
+    let addrs = dns::resolve_addrs(host, port).await?;
+    let mut stream = TcpStream::connect(&addrs[..]).await?;
+    let request = format!("GET {path} HTTP/1.0\r\nHost: {authority}\r\nConnection: close\r\n\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    stream
+        .take(MAX_SCRIPT as u64 + 1024)
+        .read_to_end(&mut response)
+        .await?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .context("response without a body")?;
+    let status = head.lines().next().unwrap_or_default();
+    ensure!(
+        status.split_whitespace().nth(1) == Some("200"),
+        "server answered {status}"
+    );
+    ensure!(body.len() <= MAX_SCRIPT, "script too large");
+    Ok(body.to_owned())
+}
+
+struct Script {
+    rules: Vec<Rule>,
+    /// The last `return`'s answer.
+    fallback: Option<Proxy>,
+}
+
+/// An `if` returning `proxy` when any of `conditions` holds.
+struct Rule {
+    conditions: Vec<Condition>,
+    proxy: Option<Proxy>,
+}
+
+enum Condition {
+    PlainHostName,
+    DomainIs(String),
+    LocalHostOrDomainIs(String),
+    ShExpMatch(String),
+    /// Outside the subset, never true.
+    Unknown,
+}
+
+impl Script {
+    fn parse(source: &str) -> Result<Self> {
+        let source = strip_comments(source);
+        let start = source
+            .find("FindProxyForURL")
+            .context("no FindProxyForURL in the script")?;
+        let open = source[start..]
+            .find('{')
+            .context("FindProxyForURL has no body")?;
+        let (body, _) = enclosed(&source[start + open..], '{', '}')?;
+
+        let mut rules = Vec::new();
+        let mut rest = body;
+        loop {
+            rest = rest.trim_start_matches(|c: char| c.is_whitespace() || matches!(c, ';' | '}'));
+            if rest.is_empty() {
+                bail!("FindProxyForURL doesn't end in a return");
+            }
+            if let Some(after) = keyword(rest, "if") {
+                let (condition, after) = enclosed(after, '(', ')')?;
+                let after = after.trim_start();
+                let (then, after) = if after.starts_with('{') {
+                    enclosed(after, '{', '}')?
+                } else {
+                    let end = after.find(';').map_or(after.len(), |at| at + 1);
+                    after.split_at(end)
+                };
+                // an if that does more than return is beyond the subset
+                if let Some(answer) = simple_return(then) {
+                    rules.push(Rule {
+                        conditions: condition.split("||").map(Condition::parse).collect(),
+                        proxy: proxy_of(answer),
+                    });
+                }
+                rest = after;
+            } else if let Some(after) = keyword(rest, "else") {
+                rest = after.trim_start().trim_start_matches('{');
+            } else if let Some(after) = keyword(rest, "return") {
+                let (answer, _) = string(after)?;
+                return Ok(Self {
+                    rules,
+                    fallback: proxy_of(answer),
+                });
+            } else {
+                let end = rest.find(';').map_or(rest.len(), |at| at + 1);
+                rest = &rest[end..];
+            }
+        }
+    }
+
+    fn find(&self, host: &str) -> Option<Proxy> {
+        let host = host.to_ascii_lowercase();
+        self.rules
+            .iter()
+            .find(|rule| {
+                rule.conditions
+                    .iter()
+                    .any(|condition| condition.matches(&host))
+            })
+            .map_or(&self.fallback, |rule| &rule.proxy)
+            .clone()
+    }
+}
+
+impl Condition {
+    fn parse(term: &str) -> Self {
+        let mut term = term.trim();
+        while let Ok((inner, "")) = enclosed(term, '(', ')') {
+            term = inner.trim();
+        }
+        let Some((name, args)) = term.split_once('(') else {
+            return Self::Unknown;
+        };
+        let Some(args) = args.strip_suffix(')') else {
+            return Self::Unknown;
+        };
+        let args: Vec<&str> = args.split(',').map(str::trim).collect();
+        let argument = || match args[..] {
+            ["host", value] => match string(value) {
+                Ok((value, rest)) if rest.trim().is_empty() => Some(value.to_ascii_lowercase()),
+                _ => None,
+            },
+            _ => None,
+        };
+        match name.trim() {
+            "isPlainHostName" if args == ["host"] => Self::PlainHostName,
+            "dnsDomainIs" => argument().map_or(Self::Unknown, Self::DomainIs),
+            "localHostOrDomainIs" => argument().map_or(Self::Unknown, Self::LocalHostOrDomainIs),
+            "shExpMatch" => argument().map_or(Self::Unknown, Self::ShExpMatch),
+            _ => Self::Unknown,
+        }
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            Self::PlainHostName => !host.contains('.'),
+            Self::DomainIs(domain) => host.ends_with(domain.as_str()),
+            Self::LocalHostOrDomainIs(name) => {
+                host == name || (!host.contains('.') && name.starts_with(&format!("{host}.")))
+            }
+            Self::ShExpMatch(pattern) => glob(pattern.as_bytes(), host.as_bytes()),
+            Self::Unknown => false,
+        }
+    }
+}
+
+/// The first entry of a PAC answer this device can use, `None` for
+/// `DIRECT` or when there's none.
+fn proxy_of(answer: &str) -> Option<Proxy> {
+    for entry in answer.split(';') {
+        let mut words = entry.split_whitespace();
+        let kind = match words.next().map(str::to_ascii_uppercase).as_deref() {
+            Some("DIRECT") => return None,
+            Some("PROXY" | "HTTP") => Kind::Http,
+            Some("SOCKS5") => Kind::Socks5,
+            _ => continue,
+        };
+        let Some((host, port)) = words.next().and_then(|addr| addr.rsplit_once(':')) else {
+            continue;
+        };
+        if let Ok(port) = port.parse() {
+            return Some(Proxy::new(kind, host, port));
+        }
+    }
+    None
+}
+
+/// `return "<answer>";` and nothing else, as the body of an `if`.
+fn simple_return(statement: &str) -> Option<&str> {
+    let after = keyword(statement.trim_start(), "return")?;
+    let (answer, rest) = string(after).ok()?;
+    matches!(rest.trim(), "" | ";").then_some(answer)
+}
+
+/// `text` past `word`, if it starts with it as a whole word.
+fn keyword<'a>(text: &'a str, word: &str) -> Option<&'a str> {
+    let rest = text.strip_prefix(word)?;
+    let whole = !rest.starts_with(|c: char| c.is_alphanumeric() || c == '_');
+    whole.then_some(rest)
+}
+
+/// The string literal `text` starts with, and what follows it.
+fn string(text: &str) -> Result<(&str, &str)> {
+    let text = text.trim_start();
+    let quote = text
+        .chars()
+        .next()
+        .filter(|c| matches!(c, '"' | '\''))
+        .context("expected a string")?;
+    let end = text[1..].find(quote).context("unterminated string")?;
+    Ok((&text[1..end + 1], &text[end + 2..]))
+}
+
+/// What's between the `open` that `text` starts with and the `close` it
+/// pairs with, strings skipped, and what follows.
+fn enclosed(text: &str, open: char, close: char) -> Result<(&str, &str)> {
+    let text = text.trim_start();
+    ensure!(text.starts_with(open), "expected {open}");
+    let (mut depth, mut quote) = (0, None);
+    for (at, c) in text.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, c) if c == open => depth += 1,
+            (None, c) if c == close => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok((&text[1..at], &text[at + 1..]));
+                }
+            }
+            _ => {}
+        }
+    }
+    bail!("unbalanced {open}")
+}
+
+/// `source` without its `//` and `/* */` comments, leaving strings be:
+/// urls in them have slashes.
+fn strip_comments(source: &str) -> String {
+    let mut out = String::with_capacity(source.len());
+    let mut chars = source.chars().peekable();
+    let mut quote = None;
+    while let Some(c) = chars.next() {
+        match (quote, c, chars.peek()) {
+            (Some(q), c, _) => {
+                if c == q {
+                    quote = None;
+                }
+                out.push(c);
+            }
+            (None, '/', Some('/')) => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        out.push('\n');
+                        break;
+                    }
+                }
+            }
+            (None, '/', Some('*')) => {
+                chars.next();
+                let mut last = ' ';
+                for c in chars.by_ref() {
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+                out.push(' ');
+            }
+            (None, '"' | '\'', _) => {
+                quote = Some(c);
+                out.push(c);
+            }
+            (None, c, _) => out.push(c),
+        }
+    }
+    out
+}
+
+/// `shExpMatch`: `*` any run of characters, `?` any one.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob(&pattern[1..], text) || (!text.is_empty() && glob(pattern, &text[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob(&pattern[1..], &text[1..]),
+        (Some(p), Some(t)) if p.eq_ignore_ascii_case(t) => glob(&pattern[1..], &text[1..]),
+        _ => false,
+    }
+}
@@ -0,0 +1,253 @@
+//! Checks an app image while it streams in, for the OTA updater to feed
+//! each chunk through before it goes to flash. The image header, chip and
+//! app descriptor are in the first few hundred bytes, so an image for
+//! another chip, a chip revision it doesn't run on, or another project is
+//! refused before the rest is downloaded or anything is written. The
+//! SHA-256 the image carries at its end is computed over the bytes as they
+//! pass, rather than by reading the partition back afterwards.
+//!
+//! The layout is ESP-IDF's `esp_image_format.h`: a 24 byte header, the
+//! segments, each an 8 byte header and its data, padding to 16 bytes with
+//! the checksum as the last byte, then the digest when the header says it
+//! was appended. A secure boot signature block may follow, which is left
+//! to the bootloader.
+
+use crate::chip::{self, CHIP};
+use anyhow::{bail, ensure, Result};
+use ring::digest;
+use std::ffi::CStr;
+
+const IMAGE_MAGIC: u8 = 0xe9;
+const APP_DESC_MAGIC: u32 = 0xabcd_5432;
+const HEADER: usize = 24;
+const SEGMENT_HEADER: usize = 8;
+/// Where the app descriptor starts, at the front of the first segment.
+const APP_DESC: usize = HEADER + SEGMENT_HEADER;
+/// The header through the descriptor's project name, what `check_head()`
+/// needs.
+const HEAD: usize = APP_DESC + 80;
+/// `ESP_IMAGE_MAX_SEGMENTS`.
+const MAX_SEGMENTS: u8 = 16;
+/// No segment is bigger than the flash there is.
+const MAX_SEGMENT: usize = 16 << 20;
+const CHECKSUM_SEED: u8 = 0xef;
+const DIGEST: usize = 32;
+
+/// The part of the image the next bytes belong to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Part {
+    Header,
+    SegmentHeader,
+    Data(usize),
+    Padding(usize),
+    Checksum,
+    Digest,
+    /// Past the digest, or the checksum when there's none.
+    Done,
+}
+
+pub struct ImageCheck {
+    part: Part,
+    /// The bytes of a header or digest still being put together.
+    pending: Vec<u8>,
+    /// The first `HEAD` bytes, until they're checked.
+    head: Vec<u8>,
+    segments_left: u8,
+    hash_appended: bool,
+    checksum: u8,
+    sha256: digest::Context,
+    len: usize,
+}
+
+impl ImageCheck {
+    pub fn new() -> Self {
+        Self {
+            part: Part::Header,
+            pending: Vec::with_capacity(DIGEST),
+            head: Vec::with_capacity(HEAD),
+            segments_left: 0,
+            hash_appended: false,
+            checksum: CHECKSUM_SEED,
+            sha256: digest::Context::new(&digest::SHA256),
+            len: 0,
+        }
+    }
+
+    /// Takes the next bytes of the image, an error as soon as they show it
+    /// isn't one this device can boot; the download should stop there.
+    pub fn update(&mut self, mut chunk: &[u8]) -> Result<()> {
+        if self.head.len() < HEAD {
+            let take = chunk.len().min(HEAD - self.head.len());
+            self.head.extend_from_slice(&chunk[..take]);
+            if self.head.len() == HEAD {
+                check_head(&self.head)?;
+            }
+        }
+
+        while !chunk.is_empty() && self.part != Part::Done {
+            let take = match self.part {
+                Part::Data(left) | Part::Padding(left) => left.min(chunk.len()),
+                part => (fixed_len(part) - self.pending.len()).min(chunk.len()),
+            };
+            let (bytes, rest) = chunk.split_at(take);
+            chunk = rest;
+            self.len += take;
+            if self.part != Part::Digest {
+                self.sha256.update(bytes);
+            }
+
+            self.part = match self.part {
+                Part::Data(left) => {
+                    self.checksum = bytes.iter().fold(self.checksum, |sum, byte| sum ^ byte);
+                    match left - take {
+                        0 => self.next_segment(),
+                        left => Part::Data(left),
+                    }
+                }
+                Part::Padding(left) => match left - take {
+                    0 => Part::Checksum,
+                    left => Part::Padding(left),
+                },
+                part => {
+                    self.pending.extend_from_slice(bytes);
+                    if self.pending.len() < fixed_len(part) {
+                        continue;
+                    }
+                    let pending = std::mem::take(&mut self.pending);
+                    self.complete(part, &pending)?
+                }
+            };
+        }
+        Ok(())
+    }
+
+    /// A header, checksum or digest all there, and what comes after it.
+    fn complete(&mut self, part: Part, bytes: &[u8]) -> Result<Part> {
+        Ok(match part {
+            Part::Header => {
+                ensure!(bytes[0] == IMAGE_MAGIC, "not an app image");
+                self.segments_left = bytes[1];
+                ensure!(
+                    (1..=MAX_SEGMENTS).contains(&self.segments_left),
+                    "image has {} segments",
+                    self.segments_left
+                );
+                self.hash_appended = bytes[23] == 1;
+                Part::SegmentHeader
+            }
+            Part::SegmentHeader => {
+                let len = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+                ensure!(len <= MAX_SEGMENT, "image segment of {len} bytes");
+                self.segments_left -= 1;
+                match len {
+                    0 => self.next_segment(),
+                    len => Part::Data(len),
+                }
+            }
+            Part::Checksum => {
+                ensure!(
+                    bytes[0] == self.checksum,
+                    "image checksum {:02x}, its segments add up to {:02x}",
+                    bytes[0],
+                    self.checksum
+                );
+                if self.hash_appended {
+                    Part::Digest
+                } else {
+                    Part::Done
+                }
+            }
+            Part::Digest => {
+                let computed =
+                    std::mem::replace(&mut self.sha256, digest::Context::new(&digest::SHA256))
+                        .finish();
+                ensure!(
+                    computed.as_ref() == bytes,
+                    "image sha-256 doesn't match its contents"
+                );
+                Part::Done
+            }
+            part => unreachable!("{part:?} isn't taken whole"),
+        })
+    }
+
+    /// After a segment's data: the next segment, or the padding to the
+    /// checksum, which ends a 16 byte block.
+    fn next_segment(&self) -> Part {
+        if self.segments_left > 0 {
+            return Part::SegmentHeader;
+        }
+        match 15 - self.len % 16 {
+            0 => Part::Checksum,
+            padding => Part::Padding(padding),
+        }
+    }
+
+    /// After the last chunk: an error unless the whole image came, up to
+    /// its checksum or digest.
+    pub fn finish(self) -> Result<()> {
+        if self.head.len() < HEAD || self.part != Part::Done {
+            bail!("image cut short after {} bytes", self.len);
+        }
+        log::info!("image of {} bytes checked", self.len);
+        Ok(())
+    }
+}
+
+fn fixed_len(part: Part) -> usize {
+    match part {
+        Part::Header => HEADER,
+        Part::SegmentHeader => SEGMENT_HEADER,
+        Part::Checksum => 1,
+        Part::Digest => DIGEST,
+        _ => 0,
+    }
+}
+
+/// The checks the first `HEAD` bytes are enough for: the chip, its
+/// revision and the project the image was built from.
+fn check_head(head: &[u8]) -> Result<()> {
+    ensure!(head[0] == IMAGE_MAGIC, "not an app image");
+    let chip_id = u16::from_le_bytes([head[12], head[13]]);
+    ensure!(
+        chip_id == CHIP.image_id,
+        "image is for chip id {chip_id}, this is an {} ({})",
+        CHIP.name,
+        CHIP.image_id
+    );
+    let min_revision = u16::from_le_bytes([head[15], head[16]]);
+    let max_revision = u16::from_le_bytes([head[17], head[18]]);
+    let revision = chip::revision();
+    ensure!(
+        revision >= min_revision && (max_revision == 0 || revision <= max_revision),
+        "image runs on chip revisions {} to {}, this is {}",
+        version(min_revision),
+        version(max_revision),
+        version(revision)
+    );
+
+    let desc = &head[APP_DESC..];
+    let magic = u32::from_le_bytes(desc[..4].try_into().unwrap());
+    ensure!(magic == APP_DESC_MAGIC, "image has no app descriptor");
+    let text = |field: &[u8]| {
+        CStr::from_bytes_until_nul(field)
+            .map(|text| text.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
+    let project = text(&desc[48..80]);
+    ensure!(
+        project == env!("CARGO_PKG_NAME"),
+        "image is of {project}, not {}",
+        env!("CARGO_PKG_NAME")
+    );
+    log::info!(
+        "image {} for {}, checking the rest as it comes",
+        text(&desc[16..48]),
+        CHIP.name
+    );
+    Ok(())
+}
+
+fn version(revision: u16) -> String {
+    format!("v{}.{}", revision / 100, revision % 100)
+}
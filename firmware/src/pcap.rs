@@ -0,0 +1,394 @@
+//! `pcap start` to capture the device's own TCP connections, for looking
+//! at a failing TLS handshake in Wireshark without a mirror port on the
+//! AP. While a capture runs, outbound connections go through a SOCKS5
+//! proxy on loopback, the way `faults` routes them, and the bytes each
+//! one carries are written out as TCP segments of a PCAP file: to flash,
+//! or as a stream to a laptop, `nc -l 9000 > capture.pcap` for
+//! `pcap start tcp <laptop>:9000`. It stops at its size bound or on
+//! `pcap stop`. Only a console starts one, a remote channel asking for it
+//! isn't heard: a capture to a host of its choosing would hand it every
+//! connection's bytes.
+//!
+//! The segments are made up from the byte streams, with the real
+//! addresses and ports but sequence numbers of their own and none of the
+//! retransmissions; the TLS records in them are what went over the wire.
+//! WiFi frames off the air would carry the same records WPA-encrypted,
+//! and not the ones the device sends. UDP, DNS and NTP among it, isn't
+//! captured, and a configured `socks_proxy` is bypassed while capturing.
+
+use crate::{
+    console, dns, fs,
+    net::socks::{self, Proxy},
+    runtime,
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+const COMMAND: &str = "pcap";
+const USAGE: &str = "pcap [start [file <name> | tcp <host:port>] [<kb>] | stop]";
+const PROXY_PORT: u16 = 1082;
+const DEFAULT_FILE: &str = "capture.pcap";
+/// Size bounds without one given, flash has less room than a laptop.
+const DEFAULT_FILE_KB: usize = 64;
+const DEFAULT_TCP_KB: usize = 1024;
+/// Packets waiting for the writer, more are dropped and counted.
+const QUEUE: usize = 32;
+/// Largest payload of a made-up segment.
+const SEGMENT: usize = 1460;
+/// Packets start with their IP header.
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+const FILE_HEADER: usize = 24;
+/// Between failed accepts, out of sockets most likely.
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+/// Where the packets go while a capture runs.
+static CAPTURE: Mutex<Option<mpsc::Sender<Vec<u8>>>> = Mutex::new(None);
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+static PROXY: OnceLock<Proxy> = OnceLock::new();
+
+#[derive(Debug)]
+enum Output {
+    File(String),
+    Tcp(String),
+}
+
+/// Starts the proxy and registers the `pcap` command.
+pub fn start() -> Result<()> {
+    let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, PROXY_PORT))
+        .context("couldn't bind the capture proxy")?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    let _ = PROXY.set(Proxy::loopback(PROXY_PORT));
+
+    runtime::spawn(async move {
+        loop {
+            let client = match listener.accept().await {
+                Ok((client, _)) => client,
+                Err(err) => {
+                    log::warn!("capture proxy accept failed: {err}");
+                    runtime::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            runtime::spawn(async move {
+                if let Err(err) = relay(client).await {
+                    log::info!("capture proxy: {err:#}");
+                }
+            });
+        }
+    });
+
+    console::register(console::Command {
+        name: COMMAND,
+        usage: USAGE,
+        summary: "capture the device's own tcp connections to a pcap file",
+        run: command,
+    });
+    Ok(())
+}
+
+/// The loopback proxy while a capture runs.
+pub fn proxy() -> Option<&'static Proxy> {
+    CAPTURE
+        .lock()
+        .unwrap()
+        .is_some()
+        .then(|| PROXY.get())
+        .flatten()
+}
+
+fn command(_: &console::Console, args: &[&str]) -> Result<String> {
+    match args {
+        [] => Ok(format!(
+            "{}, {} packets dropped",
+            if proxy().is_some() {
+                "capturing"
+            } else {
+                "not capturing"
+            },
+            DROPPED.load(Ordering::Relaxed)
+        )),
+        ["stop"] => {
+            stop();
+            Ok(String::from("capture stopped"))
+        }
+        ["start", rest @ ..] => {
+            begin(rest)?;
+            Ok(String::from("capture started, see the log"))
+        }
+        _ => bail!("usage: {USAGE}"),
+    }
+}
+
+fn parse(args: &[&str]) -> Result<(Output, usize)> {
+    let (output, rest) = match args {
+        ["file", name, rest @ ..] => (Output::File((*name).to_owned()), rest),
+        ["tcp", addr, rest @ ..] => (Output::Tcp((*addr).to_owned()), rest),
+        rest => (Output::File(String::from(DEFAULT_FILE)), rest),
+    };
+    let kb = match (rest, &output) {
+        ([], Output::File(_)) => DEFAULT_FILE_KB,
+        ([], Output::Tcp(_)) => DEFAULT_TCP_KB,
+        ([kb], _) => kb.parse().context("the size is in kb")?,
+        _ => bail!("usage: {USAGE}"),
+    };
+    Ok((output, kb * 1024))
+}
+
+fn begin(args: &[&str]) -> Result<()> {
+    let (output, max) = parse(args)?;
+    let (sender, packets) = mpsc::channel(QUEUE);
+    {
+        let mut capture = CAPTURE.lock().unwrap();
+        if capture.is_some() {
+            bail!("already capturing");
+        }
+        *capture = Some(sender);
+    }
+    DROPPED.store(0, Ordering::Relaxed);
+    // pooled connections were dialed around the proxy
+    #[cfg(feature = "http-reqwest")]
+    crate::http::close_idle();
+    log::info!("pcap: capturing to {output:?}, up to {max} bytes");
+    runtime::spawn(async move {
+        match write(&output, max, packets).await {
+            Ok(written) => log::info!("pcap: {written} bytes captured to {output:?}"),
+            Err(err) => log::warn!("pcap: capture to {output:?} failed: {err:#}"),
+        }
+        stop();
+    });
+    Ok(())
+}
+
+fn stop() {
+    if CAPTURE.lock().unwrap().take().is_some() {
+        #[cfg(feature = "http-reqwest")]
+        crate::http::close_idle();
+    }
+}
+
+async fn write(output: &Output, max: usize, mut packets: mpsc::Receiver<Vec<u8>>) -> Result<usize> {
+    let mut stream = match output {
+        Output::File(_) => None,
+        Output::Tcp(addr) => {
+            let (host, port) = addr.rsplit_once(':').context("tcp takes host:port")?;
+            let port = port.parse().context("invalid port")?;
+            let addrs = dns::resolve_addrs(host, port).await?;
+            Some(
+                TcpStream::connect(addrs.as_slice())
+                    .await
+                    .with_context(|| format!("couldn't reach {addr}"))?,
+            )
+        }
+    };
+    let mut data = file_header();
+    let mut written = 0;
+    loop {
+        if let Some(stream) = &mut stream {
+            stream.write_all(&data).await?;
+            written += data.len();
+            data.clear();
+        }
+        let Some(packet) = packets.recv().await else {
+            break;
+        };
+        if written + data.len() + packet.len() > max {
+            log::info!("pcap: {max} bytes reached");
+            break;
+        }
+        data.extend_from_slice(&packet);
+    }
+    if let Output::File(name) = output {
+        written = data.len();
+        fs::write(name, data).await?;
+    }
+    Ok(written)
+}
+
+fn file_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(FILE_HEADER);
+    header.extend_from_slice(&0xa1b2_c3d4_u32.to_le_bytes());
+    header.extend_from_slice(&2_u16.to_le_bytes());
+    header.extend_from_slice(&4_u16.to_le_bytes());
+    // no time zone offset, no accuracy
+    header.extend_from_slice(&[0; 8]);
+    header.extend_from_slice(&SNAPLEN.to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    header
+}
+
+fn record(packet: Vec<u8>) {
+    let capture = CAPTURE.lock().unwrap();
+    let Some(sender) = capture.as_ref() else {
+        return;
+    };
+    if sender.try_send(packet).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+async fn relay(mut client: TcpStream) -> Result<()> {
+    let (host, port) = socks::accept(&mut client).await?;
+    let upstream = TcpStream::connect(dns::resolve_addrs(&host, port).await?.as_slice())
+        .await
+        .with_context(|| format!("couldn't reach {host}:{port}"))?;
+    socks::granted(&mut client).await?;
+
+    let flow = Arc::new(Mutex::new(Flow {
+        device: upstream.local_addr()?,
+        server: upstream.peer_addr()?,
+        seq: [0, 0],
+    }));
+    flow.lock().unwrap().open();
+    let (mut client_read, mut client_write) = client.into_split();
+    let (mut upstream_read, mut upstream_write) = upstream.into_split();
+    tokio::try_join!(
+        copy(&mut client_read, &mut upstream_write, &flow, Side::Device),
+        copy(&mut upstream_read, &mut client_write, &flow, Side::Server),
+    )?;
+    Ok(())
+}
+
+async fn copy(
+    from: &mut (impl AsyncRead + Unpin),
+    to: &mut (impl AsyncWrite + Unpin),
+    flow: &Mutex<Flow>,
+    side: Side,
+) -> Result<()> {
+    let mut buf = vec![0; SEGMENT];
+    loop {
+        let len = from.read(&mut buf).await?;
+        flow.lock().unwrap().data(side, &buf[..len]);
+        if len == 0 {
+            to.shutdown().await?;
+            return Ok(());
+        }
+        to.write_all(&buf[..len]).await?;
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Side {
+    Device,
+    Server,
+}
+
+/// One connection as the capture tells it.
+struct Flow {
+    device: SocketAddr,
+    server: SocketAddr,
+    /// The next sequence number of the device and of the server.
+    seq: [u32; 2],
+}
+
+impl Flow {
+    fn open(&mut self) {
+        self.send(Side::Device, SYN, &[]);
+        self.seq[0] = 1;
+        self.send(Side::Server, SYN | ACK, &[]);
+        self.seq[1] = 1;
+        self.send(Side::Device, ACK, &[]);
+    }
+
+    /// `data` from `side`, the end of its stream when empty.
+    fn data(&mut self, side: Side, data: &[u8]) {
+        let at = side as usize;
+        if data.is_empty() {
+            self.send(side, FIN | ACK, &[]);
+            self.seq[at] = self.seq[at].wrapping_add(1);
+            return;
+        }
+        self.send(side, PSH | ACK, data);
+        self.seq[at] = self.seq[at].wrapping_add(data.len() as u32);
+    }
+
+    fn send(&self, side: Side, flags: u8, payload: &[u8]) {
+        let (from, to) = match side {
+            Side::Device => (self.device, self.server),
+            Side::Server => (self.server, self.device),
+        };
+        let seq = self.seq[side as usize];
+        let ack = if flags & ACK == 0 {
+            0
+        } else {
+            self.seq[1 - side as usize]
+        };
+
+        let mut tcp = Vec::with_capacity(20 + payload.len());
+        tcp.extend_from_slice(&from.port().to_be_bytes());
+        tcp.extend_from_slice(&to.port().to_be_bytes());
+        tcp.extend_from_slice(&seq.to_be_bytes());
+        tcp.extend_from_slice(&ack.to_be_bytes());
+        // a 20 byte header, the checksum left at 0 for Wireshark to skip
+        tcp.extend_from_slice(&[5 << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        tcp.extend_from_slice(payload);
+
+        let mut packet = ip_header(from.ip(), to.ip(), tcp.len());
+        packet.extend_from_slice(&tcp);
+
+        let since = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut data = Vec::with_capacity(16 + packet.len());
+        data.extend_from_slice(&(since.as_secs() as u32).to_le_bytes());
+        data.extend_from_slice(&since.subsec_micros().to_le_bytes());
+        data.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        data.extend_from_slice(&packet);
+        record(data);
+    }
+}
+
+/// IPv4 or IPv6 header for a TCP segment of `len` bytes, both ends of a
+/// connection being of one family.
+fn ip_header(from: IpAddr, to: IpAddr, len: usize) -> Vec<u8> {
+    const TCP: u8 = 6;
+    match (from, to) {
+        (IpAddr::V4(from), IpAddr::V4(to)) => {
+            let total = (20 + len) as u16;
+            let mut header = vec![0x45, 0];
+            header.extend_from_slice(&total.to_be_bytes());
+            // no id, don't fragment, ttl 64
+            header.extend_from_slice(&[0, 0, 0x40, 0, 64, TCP, 0, 0]);
+            header.extend_from_slice(&from.octets());
+            header.extend_from_slice(&to.octets());
+            let sum = header.chunks(2).fold(0_u32, |sum, pair| {
+                sum + u32::from(u16::from_be_bytes([pair[0], pair[1]]))
+            });
+            let sum = (sum & 0xffff) + (sum >> 16);
+            let checksum = !((sum & 0xffff) + (sum >> 16)) as u16;
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            header
+        }
+        (from, to) => {
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            let mut header = vec![0x60, 0, 0, 0];
+            header.extend_from_slice(&(len as u16).to_be_bytes());
+            header.extend_from_slice(&[TCP, 64]);
+            header.extend_from_slice(&v6(from).octets());
+            header.extend_from_slice(&v6(to).octets());
+            header
+        }
+    }
+}
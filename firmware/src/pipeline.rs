@@ -0,0 +1,508 @@
+//! What a fetched body goes through on its way to a handler, as `|`
+//! separated stages after the url of a `poll_urls` entry:
+//!
+//! ```text
+//! 300=https://example.com/rates.json.gz|gunzip|sha256|json|telemetry=rates
+//! ```
+//!
+//! The byte stages, `gunzip` and `sha256[=<hex>]`, pass the body on as it
+//! streams; `sha256` logs the digest, or checks it against the one given.
+//! `lines` logs what gets to it, `display` shows its first line on the
+//! screen, `file=<name>` keeps it in a file on flash, replaced once a whole
+//! new body is in, and `mqtt=<channel>` publishes it under the device's
+//! topic; each of those ends the pipeline, taking the body as it is.
+//! `json` and `cbor` collect the body and decode it for the handler after
+//! them, `telemetry=<field>` or `log`. `items[=<key>]` is for arrays too
+//! large to collect: it decodes the elements of the body's top-level
+//! array, or of the one under a top-level `key`, one at a time as they
+//! stream past, and hands each to the handler. A new data source or sink
+//! is a new stage here, not another hook in the clients.
+
+use crate::{
+    fs,
+    http::{Consumer, LogLines},
+    runtime, telemetry,
+};
+use anyhow::{bail, ensure, Context, Result};
+use serde_json::Value;
+use std::{fmt::Write, iter::Peekable};
+
+/// Largest body `json` or `cbor` will collect.
+const MAX_DOCUMENT: usize = 16 * 1024;
+/// Largest array element `items` will collect.
+const MAX_ITEM: usize = 4 * 1024;
+/// Longer keys can't be the one `items` looks for.
+const MAX_KEY: usize = 64;
+/// Largest body `file` keeps, what it holds until the body is complete.
+const MAX_FILE: usize = 64 * 1024;
+
+/// The consumer for `spec`, the stages without the url. Empty takes the
+/// body and does nothing with it.
+pub fn build(spec: &str) -> Result<Box<dyn Consumer>> {
+    let mut stages = spec
+        .split('|')
+        .map(str::trim)
+        .filter(|stage| !stage.is_empty())
+        .rev()
+        .peekable();
+
+    let mut consumer = sink(&mut stages)?;
+    for stage in stages {
+        consumer = match stage.split_once('=').unwrap_or((stage, "")) {
+            ("gunzip", "") => gunzip(consumer)?,
+            ("sha256", expected) => Box::new(Checksum::new(expected, consumer)?),
+            (
+                "json" | "cbor" | "items" | "lines" | "display" | "file" | "mqtt" | "telemetry"
+                | "log",
+                _,
+            ) => {
+                bail!("{stage} can only end the pipeline")
+            }
+            _ => bail!("unknown pipeline stage {stage}"),
+        };
+    }
+    Ok(consumer)
+}
+
+/// The end of the pipeline, taken off the back of `stages`.
+fn sink<'a>(stages: &mut Peekable<impl Iterator<Item = &'a str>>) -> Result<Box<dyn Consumer>> {
+    let last = stages.peek().copied();
+    let handler = match last.map(|stage| stage.split_once('=').unwrap_or((stage, ""))) {
+        Some(("lines", "")) => {
+            stages.next();
+            return Ok(Box::new(LogLines::default()));
+        }
+        Some(("display", "")) => {
+            stages.next();
+            return display();
+        }
+        Some(("file", name)) => {
+            stages.next();
+            return Ok(Box::new(File::new(name)?));
+        }
+        Some(("mqtt", channel)) => {
+            stages.next();
+            return mqtt(channel);
+        }
+        Some(("telemetry", field)) if !field.is_empty() => Handler::Telemetry(field.to_owned()),
+        Some(("log", "")) => Handler::Log,
+        _ => return Ok(Box::new(Discard)),
+    };
+    stages.next();
+    let stage = stages.next().unwrap_or_default();
+    let format = match stage.split_once('=').unwrap_or((stage, "")) {
+        ("json", "") => Format::Json,
+        ("cbor", "") => Format::Cbor,
+        ("items", key) => return Ok(Box::new(Items::new(key, handler))),
+        _ => bail!("a handler needs json, cbor or items right before it"),
+    };
+    Ok(Box::new(Decode {
+        format,
+        handler,
+        body: Vec::new(),
+    }))
+}
+
+struct Discard;
+
+impl Consumer for Discard {
+    fn chunk(&mut self, _: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "display")]
+fn display() -> Result<Box<dyn Consumer>> {
+    Ok(Box::new(crate::display::Fetched::default()))
+}
+
+#[cfg(not(feature = "display"))]
+fn display() -> Result<Box<dyn Consumer>> {
+    bail!("display needs a build with the display feature")
+}
+
+/// The body, written over `name` once it's all in; a failed fetch leaves
+/// the last one.
+struct File {
+    name: String,
+    body: Vec<u8>,
+}
+
+impl File {
+    fn new(name: &str) -> Result<Self> {
+        ensure!(
+            !name.is_empty() && !name.contains('/') && name != "." && name != "..",
+            "file takes a name without a directory"
+        );
+        Ok(Self {
+            name: name.to_owned(),
+            body: Vec::new(),
+        })
+    }
+}
+
+impl Consumer for File {
+    fn chunk(&mut self, chunk: &[u8]) -> Result<()> {
+        ensure!(
+            self.body.len() + chunk.len() <= MAX_FILE,
+            "body over {MAX_FILE} bytes, too large to keep"
+        );
+        self.body.extend_from_slice(chunk);
+        Ok(())
+    }
+
+    /// The write goes to the blocking pool, a failed one is only logged.
+    fn finish(&mut self) -> Result<()> {
+        let (name, body) = (self.name.clone(), std::mem::take(&mut self.body));
+        runtime::spawn(async move {
+            match fs::write(&name, body).await {
+                Ok(()) => log::debug!("body kept in {name}"),
+                Err(err) => log::warn!("couldn't keep the body in {name}: {err:#}"),
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(feature = "mqtt")]
+fn mqtt(channel: &str) -> Result<Box<dyn Consumer>> {
+    ensure!(
+        !channel.is_empty() && !channel.contains(['#', '+']),
+        "mqtt takes a channel without wildcards"
+    );
+    Ok(Box::new(Republish {
+        channel: channel.to_owned(),
+        body: Vec::new(),
+    }))
+}
+
+#[cfg(not(feature = "mqtt"))]
+fn mqtt(_: &str) -> Result<Box<dyn Consumer>> {
+    bail!("mqtt needs a build with the mqtt feature")
+}
+
+/// The body, published whole on `channel` under the device's topic.
+#[cfg(feature = "mqtt")]
+struct Republish {
+    channel: String,
+    body: Vec<u8>,
+}
+
+#[cfg(feature = "mqtt")]
+impl Consumer for Republish {
+    fn chunk(&mut self, chunk: &[u8]) -> Result<()> {
+        ensure!(
+            self.body.len() + chunk.len() <= MAX_DOCUMENT,
+            "body over {MAX_DOCUMENT} bytes, too large to publish"
+        );
+        self.body.extend_from_slice(chunk);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let topic = crate::mqtt::topic(&self.channel);
+        crate::mqtt::session()?.publish(&topic, std::mem::take(&mut self.body))
+    }
+}
+
+#[cfg(feature = "gzip")]
+fn gunzip(next: Box<dyn Consumer>) -> Result<Box<dyn Consumer>> {
+    Ok(Box::new(Gunzip(flate2::write::GzDecoder::new(Forward(
+        next,
+    )))))
+}
+
+#[cfg(not(feature = "gzip"))]
+fn gunzip(_: Box<dyn Consumer>) -> Result<Box<dyn Consumer>> {
+    bail!("gunzip needs a build with the gzip feature")
+}
+
+/// The decompressed body, as flate2 writes it out.
+#[cfg(feature = "gzip")]
+struct Forward(Box<dyn Consumer>);
+
+#[cfg(feature = "gzip")]
+impl std::io::Write for Forward {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.0.chunk(data).map_err(std::io::Error::other)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "gzip")]
+struct Gunzip(flate2::write::GzDecoder<Forward>);
+
+#[cfg(feature = "gzip")]
+impl Consumer for Gunzip {
+    fn chunk(&mut self, chunk: &[u8]) -> Result<()> {
+        use std::io::Write;
+        self.0.write_all(chunk).context("couldn't gunzip the body")
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.0.try_finish().context("truncated gzip body")?;
+        self.0.get_mut().0.finish()
+    }
+}
+
+struct Checksum {
+    context: ring::digest::Context,
+    expected: Option<String>,
+    next: Box<dyn Consumer>,
+}
+
+impl Checksum {
+    fn new(expected: &str, next: Box<dyn Consumer>) -> Result<Self> {
+        let expected = (!expected.is_empty()).then(|| expected.to_ascii_lowercase());
+        if let Some(hex) = &expected {
+            ensure!(
+                hex.len() == 64 && hex.bytes().all(|byte| byte.is_ascii_hexdigit()),
+                "sha256 takes 64 hex digits"
+            );
+        }
+        Ok(Self {
+            context: ring::digest::Context::new(&ring::digest::SHA256),
+            expected,
+            next,
+        })
+    }
+}
+
+impl Consumer for Checksum {
+    fn chunk(&mut self, chunk: &[u8]) -> Result<()> {
+        self.context.update(chunk);
+        self.next.chunk(chunk)
+    }
+
+    /// Checked before the stages after it finish, so a handler never acts
+    /// on a body that doesn't match.
+    fn finish(&mut self) -> Result<()> {
+        let digest = self.context.clone().finish();
+        let hex = digest
+            .as_ref()
+            .iter()
+            .fold(String::with_capacity(64), |mut hex, byte| {
+                let _ = write!(hex, "{byte:02x}");
+                hex
+            });
+        match &self.expected {
+            Some(expected) => ensure!(*expected == hex, "sha256 {hex} isn't the expected one"),
+            None => log::info!("body sha256 {hex}"),
+        }
+        self.next.finish()
+    }
+}
+
+enum Format {
+    Json,
+    Cbor,
+}
+
+enum Handler {
+    Telemetry(String),
+    Log,
+}
+
+impl Handler {
+    fn handle(&self, value: Value) {
+        match self {
+            Self::Telemetry(field) => telemetry::set(field, value),
+            Self::Log => log::info!("{value}"),
+        }
+    }
+}
+
+/// The whole body, decoded once it's in.
+struct Decode {
+    format: Format,
+    handler: Handler,
+    body: Vec<u8>,
+}
+
+impl Consumer for Decode {
+    fn chunk(&mut self, chunk: &[u8]) -> Result<()> {
+        ensure!(
+            self.body.len() + chunk.len() <= MAX_DOCUMENT,
+            "document over {MAX_DOCUMENT} bytes"
+        );
+        self.body.extend_from_slice(chunk);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let body = std::mem::take(&mut self.body);
+        let value: Value = match self.format {
+            Format::Json => serde_json::from_slice(&body).context("invalid json")?,
+            Format::Cbor => ciborium::from_reader(body.as_slice()).context("invalid cbor")?,
+        };
+        self.handler.handle(value);
+        Ok(())
+    }
+}
+
+enum Position {
+    /// Looking for the array.
+    Before,
+    Inside,
+    /// Past its closing bracket, the rest is skipped.
+    After,
+}
+
+/// Follows just enough of the JSON syntax, nesting and strings, to find the
+/// array and cut it at its top-level commas. Each element is decoded on
+/// its own, so the body is never held whole, only the element being read.
+struct Items {
+    /// The top-level key the array is under, `None` for a top-level array.
+    key: Option<String>,
+    handler: Handler,
+    position: Position,
+    depth: usize,
+    /// The depth inside the array, where its elements are.
+    elements: usize,
+    string: bool,
+    escape: bool,
+    /// The string last read at depth 1, the key of the value after it.
+    last: Vec<u8>,
+    item: Vec<u8>,
+    items: usize,
+}
+
+impl Items {
+    fn new(key: &str, handler: Handler) -> Self {
+        Self {
+            key: (!key.is_empty()).then(|| key.to_owned()),
+            handler,
+            position: Position::Before,
+            depth: 0,
+            elements: 0,
+            string: false,
+            escape: false,
+            last: Vec::new(),
+            item: Vec::new(),
+            items: 0,
+        }
+    }
+
+    fn byte(&mut self, byte: u8) -> Result<()> {
+        if self.string {
+            let closing = !self.escape && byte == b'"';
+            self.escape = !self.escape && byte == b'\\';
+            self.string = !closing;
+            match self.position {
+                Position::Inside => self.push(byte)?,
+                Position::Before if self.depth == 1 && !closing && self.last.len() < MAX_KEY => {
+                    self.last.push(byte)
+                }
+                _ => {}
+            }
+            return Ok(());
+        }
+        match self.position {
+            Position::Before => self.before(byte),
+            Position::Inside => self.inside(byte),
+            Position::After => Ok(()),
+        }
+    }
+
+    fn before(&mut self, byte: u8) -> Result<()> {
+        let Some(key) = &self.key else {
+            return match byte {
+                b'[' => self.enter(),
+                byte if byte.is_ascii_whitespace() => Ok(()),
+                _ => bail!("the body isn't a json array"),
+            };
+        };
+        match byte {
+            b'[' if self.depth == 1 && self.last == key.as_bytes() => return self.enter(),
+            b'"' => {
+                self.string = true;
+                if self.depth == 1 {
+                    self.last.clear();
+                }
+            }
+            b'{' | b'[' => self.depth += 1,
+            b'}' | b']' => {
+                self.depth = self.depth.saturating_sub(1);
+                ensure!(self.depth > 0, "no {key} array in the body");
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn enter(&mut self) -> Result<()> {
+        self.depth += 1;
+        self.elements = self.depth;
+        self.position = Position::Inside;
+        Ok(())
+    }
+
+    fn inside(&mut self, byte: u8) -> Result<()> {
+        let top = self.depth == self.elements;
+        match byte {
+            b',' if top => self.element(),
+            b']' if top => {
+                self.element()?;
+                self.depth -= 1;
+                self.position = Position::After;
+                Ok(())
+            }
+            byte if top && byte.is_ascii_whitespace() => Ok(()),
+            b'"' => {
+                self.string = true;
+                self.push(byte)
+            }
+            b'{' | b'[' => {
+                self.depth += 1;
+                self.push(byte)
+            }
+            b'}' | b']' => {
+                ensure!(!top, "unbalanced json array");
+                self.depth -= 1;
+                self.push(byte)
+            }
+            _ => self.push(byte),
+        }
+    }
+
+    fn push(&mut self, byte: u8) -> Result<()> {
+        ensure!(
+            self.item.len() < MAX_ITEM,
+            "array element over {MAX_ITEM} bytes"
+        );
+        self.item.push(byte);
+        Ok(())
+    }
+
+    /// Decodes the element read so far, none before `]` of an empty array.
+    fn element(&mut self) -> Result<()> {
+        if self.item.is_empty() {
+            return Ok(());
+        }
+        let value = serde_json::from_slice(&self.item)
+            .with_context(|| format!("invalid json in array element {}", self.items))?;
+        self.item.clear();
+        self.items += 1;
+        self.handler.handle(value);
+        Ok(())
+    }
+}
+
+impl Consumer for Items {
+    fn chunk(&mut self, chunk: &[u8]) -> Result<()> {
+        chunk.iter().try_for_each(|byte| self.byte(*byte))
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        ensure!(
+            matches!(self.position, Position::After),
+            "the body ended inside the json array"
+        );
+        log::debug!("{} json array elements", self.items);
+        Ok(())
+    }
+}
@@ -0,0 +1,246 @@
+//! The fetch for a list of urls, each on its own interval: `poll_urls` as
+//! `<secs>=<url>;...`, and every device at its own phase of the interval,
+//! see `Job::phased()`. At most `poll_limit` polls run at once, which
+//! bounds how many TLS sessions, each with its own record buffers, are open
+//! at the same time. Every poll publishes `Event::Polled`, saying whether
+//! the body differs from the one before. An entry's url can be followed by
+//! the `pipeline` stages its body goes through. `start()` again replaces
+//! the polls with the config's, which is how a changed `poll_urls` applies.
+//! A poll with no network replays the body `replay` kept instead, once
+//! each time it goes offline. A second url after the first is its backup,
+//! polled while the first is failing, see `telemetry::endpoints`; the
+//! poll still goes by the first url everywhere else. Polls under an
+//! `early_data` prefix skip a round trip, see `http::early`.
+
+use crate::{
+    abtest, budget,
+    config::Config,
+    dns,
+    events::{self, Event},
+    http::{self, Consumer, HttpFetcher},
+    jobs::{Handle, Job, Scheduler, Timers},
+    pipeline, replay,
+    telemetry::endpoints,
+};
+use anyhow::{Context, Result};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::Hasher,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Semaphore;
+
+/// The jobs of the polls `start()` registered last.
+static JOBS: Mutex<Vec<Handle>> = Mutex::new(Vec::new());
+/// Polls skip their runs while set, see `pause()`.
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+struct Target {
+    url: String,
+    /// Polled instead while `url` is failing, empty for none.
+    backup: String,
+    interval: Duration,
+    /// The `pipeline` stages, empty for none.
+    stages: String,
+}
+
+fn parse(value: &str) -> Result<Vec<Target>> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (secs, url) = entry
+                .split_once('=')
+                .with_context(|| format!("poll entry {entry} isn't <secs>=<url>"))?;
+            let secs: u64 = secs.trim().parse().context("poll interval takes seconds")?;
+            anyhow::ensure!(secs > 0, "poll interval can't be 0");
+            let (urls, stages) = url.split_once('|').unwrap_or((url, ""));
+            let mut urls = urls.split_whitespace();
+            let url = urls
+                .next()
+                .with_context(|| format!("poll entry {entry} has no url"))?;
+            let backup = urls.next().unwrap_or_default();
+            anyhow::ensure!(
+                urls.next().is_none(),
+                "poll entry {entry} has more than a url and its backup"
+            );
+            // built now to turn a mistake up at boot rather than at the poll
+            pipeline::build(stages).with_context(|| format!("poll entry {entry}"))?;
+            Ok(Target {
+                url: url.to_owned(),
+                backup: backup.to_owned(),
+                interval: Duration::from_secs(secs),
+                stages: stages.to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// What a poll keeps of the body: its size, a hash to compare, and the
+/// content type it was served as.
+#[derive(Default)]
+struct Digest {
+    bytes: usize,
+    hasher: DefaultHasher,
+    content_type: Option<String>,
+}
+
+impl Consumer for Digest {
+    fn head(&mut self, head: &http::Head) -> Result<()> {
+        self.content_type = head.get("content-type").map(String::from);
+        Ok(())
+    }
+
+    fn chunk(&mut self, chunk: &[u8]) -> Result<()> {
+        self.bytes += chunk.len();
+        self.hasher.write(chunk);
+        Ok(())
+    }
+}
+
+pub fn start(config: &Config, jobs: &Scheduler<impl Timers>) -> Result<()> {
+    let targets = parse(&config.poll_urls).context("invalid poll_urls")?;
+    let mut handles = JOBS.lock().unwrap();
+    for handle in handles.drain(..) {
+        handle.cancel();
+    }
+    let names: Vec<_> = targets.iter().map(|target| endpoint(&target.url)).collect();
+    for (target, name) in targets.iter().zip(&names) {
+        endpoints::register(name, &target.url, &target.backup);
+    }
+    endpoints::retain("poll ", &names);
+    if targets.is_empty() {
+        return Ok(());
+    }
+    let limit = Arc::new(Semaphore::new(config.poll_limit.max(1).into()));
+
+    for Target {
+        url,
+        interval,
+        stages,
+        ..
+    } in targets
+    {
+        let limit = limit.clone();
+        let last = Arc::new(Mutex::new(None));
+        let replayed = Arc::new(AtomicBool::new(false));
+        let job = Job::new("poll", interval)
+            .phased()
+            .jitter(Duration::from_secs(1))
+            .radio();
+        #[cfg(feature = "ambient")]
+        let job = job.on(crate::ambient::fetch_trigger);
+        let handle = jobs.register(job, move || {
+            let (url, limit, last) = (url.clone(), limit.clone(), last.clone());
+            let (stages, replayed) = (stages.clone(), replayed.clone());
+            async move {
+                let state = events::state();
+                if PAUSED.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+                if !state.net_up {
+                    if !replayed.swap(true, Ordering::Relaxed)
+                        && replay::replay(&url, &mut pipeline::build(&stages)?).await?
+                    {
+                        log::info!("offline, replayed what was kept of {url}");
+                    }
+                    return Ok(());
+                }
+                if url.starts_with("https") && !state.time_synced {
+                    return Ok(());
+                }
+                let permit = limit.acquire().await?;
+                let mut consumer = (
+                    (Digest::default(), replay::Collect::default()),
+                    pipeline::build(&stages)?,
+                );
+                let name = endpoint(&url);
+                let polled = endpoints::call(&name, &url, |active| {
+                    let consumer = &mut consumer;
+                    async move { fetch(&active, consumer).await }
+                });
+                let result = match polled.await {
+                    Ok(()) => consumer.finish(),
+                    Err(err) => Err(err),
+                };
+                drop(permit);
+                let ((digest, kept), _) = consumer;
+
+                let ok = result.is_ok();
+                let hash = digest.hasher.finish();
+                let changed = ok && last.lock().unwrap().replace(hash) != Some(hash);
+                if ok {
+                    replayed.store(false, Ordering::Relaxed);
+                    #[cfg(feature = "display")]
+                    crate::display::set_stale(false, None);
+                }
+                if let (true, Some(body)) = (changed, kept.into_body()) {
+                    let content_type = digest.content_type.clone();
+                    if let Err(err) = replay::keep(&url, content_type, body).await {
+                        log::warn!("couldn't keep the body of {url}: {err:#}");
+                    }
+                }
+                events::publish(Event::Polled {
+                    url: url.clone(),
+                    ok,
+                    changed,
+                });
+                result.with_context(|| format!("couldn't poll {url}"))?;
+                log::info!(
+                    "polled {url}: {} bytes of {}, {}",
+                    digest.bytes,
+                    digest.content_type.as_deref().unwrap_or("unknown type"),
+                    if changed { "changed" } else { "unchanged" }
+                );
+                Ok(())
+            }
+        });
+        handles.push(handle);
+    }
+    Ok(())
+}
+
+/// Holds every poll back while `paused`, the jobs stay registered.
+pub fn pause(paused: bool) {
+    PAUSED.store(paused, Ordering::Relaxed);
+}
+
+/// The `telemetry::endpoints` name of the poll of `url`.
+fn endpoint(url: &str) -> String {
+    format!("poll {url}")
+}
+
+/// Fetches `url`, with an SRV name looked up, or its other scheme's
+/// variant while `ab` says so.
+async fn fetch(url: &str, consumer: &mut impl Consumer) -> Result<()> {
+    let url = dns::resolve_srv_url(url).await?;
+    let (url, scheme) = abtest::target(&url);
+    let start = Instant::now();
+    let result = budget::HTTP.track(fetch_from(&url, consumer)).await;
+    if let Some(scheme) = scheme {
+        abtest::record(scheme, &result, start.elapsed());
+    }
+    result
+}
+
+/// The fetch's client for the url's scheme.
+async fn fetch_from(url: &str, consumer: &mut impl Consumer) -> Result<()> {
+    #[cfg(feature = "coap")]
+    if url.starts_with("coap://") {
+        return crate::coap::Fetcher.fetch(url, consumer).await;
+    }
+    #[cfg(feature = "early-data")]
+    if http::early::allowed(url) && crate::flags::on(crate::flags::Flag::EarlyData) {
+        return http::early::fetch(url, consumer).await;
+    }
+    #[cfg(feature = "http-reqwest")]
+    let client = http::shared()?;
+    #[cfg(not(feature = "http-reqwest"))]
+    let client = http::client()?;
+    client.fetch(url, consumer).await
+}
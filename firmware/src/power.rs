@@ -0,0 +1,137 @@
+//! Battery duty cycle: boot, fetch, report, deep sleep, repeat. The RTC
+//! keeps the system clock running through deep sleep, so a wake shortly
+//! after an NTP sync can skip straight to the fetch; the config itself is
+//! in NVS and survives anyway. With `light-sleep` the chip also light
+//! sleeps whenever it's idle in between.
+
+use crate::{
+    config::Config,
+    events::{self, Event},
+    runtime, telemetry,
+};
+use esp_idf_hal::reset::ResetReason;
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(feature = "light-sleep")]
+mod light;
+mod pm;
+#[cfg(feature = "ulp")]
+pub mod ulp;
+mod wake;
+#[cfg(feature = "light-sleep")]
+pub use light::enable_light_sleep;
+#[cfg(all(feature = "light-sleep", debug_assertions))]
+pub use light::log_sleep_stats;
+pub use pm::{boost, configuration, set_profile};
+pub use wake::{sleep, wake_cause, Ext1Mode, SleepConfig, WakeCause};
+
+/// The RTC slow clock drifts by a few percent, so NTP runs again after this.
+const RESYNC_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+/// Sleep forced by a low battery when no duty cycle is configured.
+#[cfg(feature = "battery")]
+const LOW_BATTERY_SLEEP: Duration = Duration::from_secs(60 * 60);
+/// Time for the MQTT and UDP tasks to get the last report out.
+const REPORT_GRACE: Duration = Duration::from_secs(5);
+
+// RTC slow memory is initialised on power-on and kept through deep sleep; only
+// plain loads and stores, the S3 can't do atomic read-modify-write there
+#[link_section = ".rtc.data"]
+static CYCLES: AtomicU32 = AtomicU32::new(0);
+/// Unix time of the last NTP sync, 0 before the first one.
+#[link_section = ".rtc.data"]
+static SYNCED_AT: AtomicU32 = AtomicU32::new(0);
+
+/// Whether the clock carried over from before deep sleep is still good
+/// enough to verify certificates with.
+pub fn clock_retained() -> bool {
+    if ResetReason::get() != ResetReason::DeepSleep {
+        return false;
+    }
+    let synced_at = SYNCED_AT.load(Ordering::Relaxed);
+    synced_at != 0 && now().saturating_sub(synced_at) < RESYNC_INTERVAL.as_secs() as u32
+}
+
+#[cfg(any(feature = "sntp", feature = "gps"))]
+pub fn clock_synced() {
+    SYNCED_AT.store(now(), Ordering::Relaxed);
+}
+
+/// Sends one last telemetry report and deep sleeps for `interval`; the
+/// wake is a fresh boot.
+pub async fn sleep_after_report(config: &Config, interval: Duration) -> ! {
+    let cycles = CYCLES.load(Ordering::Relaxed) + 1;
+    CYCLES.store(cycles, Ordering::Relaxed);
+    telemetry::set("sleep_cycles", cycles);
+    last_report(config).await;
+
+    let config = SleepConfig {
+        timer: Some(interval),
+        #[cfg(feature = "ulp")]
+        ulp: ulp::watch(config).unwrap_or_else(|err| {
+            log::warn!("sleeping without the ulp watch: {err:#}");
+            None
+        }),
+        ..Default::default()
+    };
+    if let Err(err) = sleep(&config) {
+        log::error!("couldn't deep sleep, restarting instead: {err:#}");
+    }
+    esp_idf_hal::reset::restart()
+}
+
+/// Sends a telemetry report over MQTT and UDP, whichever are configured,
+/// and gives them the time to get it out before the chip goes down.
+pub async fn last_report(config: &Config) {
+    #[cfg(feature = "mqtt")]
+    if !config.mqtt_broker.is_empty() {
+        if let Err(err) = crate::mqtt::publish_telemetry() {
+            log::warn!("couldn't queue the last telemetry report: {err:#}");
+        }
+    }
+    if !config.udp_collector.is_empty() {
+        if let Err(err) = telemetry::udp::sample().await {
+            log::warn!("couldn't send the last udp sample: {err:#}");
+        }
+    }
+    runtime::sleep(REPORT_GRACE).await;
+}
+
+/// Cuts the current cycle short on `LowBattery`: reports and sleeps for
+/// the duty cycle interval, or an hour if the device normally stays awake.
+#[cfg(feature = "battery")]
+pub fn sleep_on_low_battery(config: &Config) {
+    let config = config.clone();
+    runtime::spawn(async move {
+        // the battery monitor may have found it low before this was called
+        events::wait_until(|state| state.battery_low).await;
+        let interval = match config.sleep_secs {
+            0 => LOW_BATTERY_SLEEP,
+            secs => Duration::from_secs(secs.into()),
+        };
+        log::warn!("battery low, sleeping early for {interval:?}");
+        sleep_after_report(&config, interval).await
+    });
+}
+
+/// Puts the wake cause in telemetry and on the bus, once the services that
+/// might care are up.
+pub fn report_wake() {
+    if let Some(cause) = wake_cause() {
+        log::info!("woke from deep sleep: {cause:?}");
+        telemetry::set("wake_cause", format!("{cause:?}"));
+        #[cfg(feature = "ulp")]
+        if let WakeCause::Ulp(data) = cause {
+            telemetry::set("ulp_checks", data.checks);
+        }
+        events::publish(Event::Wake(cause));
+    }
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as u32)
+}
@@ -0,0 +1,85 @@
+//! Automatic light sleep while idle, the `light-sleep` feature.
+
+use super::pm::{self, Profile};
+use anyhow::Result;
+use esp_idf_sys::esp;
+
+#[cfg(not(esp_idf_pm_enable))]
+compile_error!("`light-sleep` needs sdkconfig.defaults.light-sleep in ESP_IDF_SDKCONFIG_DEFAULTS");
+
+/// Lets the chip light sleep whenever every task is blocked. Tokio's driver
+/// parks until the next deadline in its timer wheel, so tickless idle sleeps
+/// exactly until that, an esp_timer, or the next DTIM beacon with WiFi in
+/// modem power save.
+pub fn enable_light_sleep() -> Result<()> {
+    // the economy profile light sleeps with this feature on
+    pm::set_profile(Profile::Economy)?;
+    // the default already, but light sleep doesn't happen without it
+    esp!(unsafe { esp_idf_sys::esp_wifi_set_ps(esp_idf_sys::wifi_ps_type_t_WIFI_PS_MIN_MODEM) })?;
+    log::info!("automatic light sleep enabled");
+    Ok(())
+}
+
+#[cfg(debug_assertions)]
+pub use stats::log_sleep_stats;
+
+/// Light sleep residency from the PM profiler (`CONFIG_PM_PROFILING`, on in
+/// debug builds through `sdkconfig.defaults.debug`) turned into an idle
+/// current estimate from datasheet figures; an ammeter is still the only
+/// real measurement.
+#[cfg(debug_assertions)]
+mod stats {
+    use anyhow::{Context, Result};
+    use std::{sync::Mutex, time::Instant};
+
+    /// ESP32-S3 light sleep with RTC timer wake.
+    const LIGHT_SLEEP_MA: f64 = 0.24;
+    /// Awake between beacons, CPU at the minimum frequency and WiFi in modem
+    /// sleep.
+    const AWAKE_MA: f64 = 25.0;
+    const DUMP_SIZE: usize = 1024;
+
+    /// Time in light sleep and wall time at the previous call.
+    static LAST: Mutex<Option<(u64, Instant)>> = Mutex::new(None);
+
+    pub fn log_sleep_stats() -> Result<()> {
+        let slept_us = sleep_time_us()?;
+        let now = Instant::now();
+        let Some((last_slept, last_now)) = LAST.lock().unwrap().replace((slept_us, now)) else {
+            return Ok(());
+        };
+
+        let elapsed = now.duration_since(last_now).as_micros() as f64;
+        let residency = (slept_us.saturating_sub(last_slept) as f64 / elapsed).clamp(0.0, 1.0);
+        let current = residency * LIGHT_SLEEP_MA + (1.0 - residency) * AWAKE_MA;
+        log::info!(
+            "light sleep {:.1}% of the last {:.0}s, estimated idle current {current:.1} mA",
+            residency * 100.0,
+            elapsed / 1e6
+        );
+        crate::telemetry::set("idle_ma", (current * 10.0).round() / 10.0);
+        Ok(())
+    }
+
+    /// Total light sleep time since boot, the `SLEEP` row of the mode stats
+    /// `esp_pm_dump_locks()` prints.
+    fn sleep_time_us() -> Result<u64> {
+        let mut dump = vec![0u8; DUMP_SIZE];
+        unsafe {
+            let stream = esp_idf_sys::fmemopen(dump.as_mut_ptr().cast(), dump.len(), c"w".as_ptr());
+            anyhow::ensure!(!stream.is_null(), "couldn't open the pm dump buffer");
+            esp_idf_sys::esp_pm_dump_locks(stream.cast());
+            esp_idf_sys::fclose(stream);
+        }
+        let dump = String::from_utf8_lossy(&dump);
+
+        // SLEEP     40M        123456789   97%
+        dump.lines()
+            .find(|line| line.starts_with("SLEEP"))
+            .and_then(|line| {
+                let columns: Vec<_> = line.split_whitespace().collect();
+                columns.get(columns.len().checked_sub(2)?)?.parse().ok()
+            })
+            .context("no light sleep stats, is CONFIG_PM_PROFILING set?")
+    }
+}
@@ -0,0 +1,143 @@
+//! CPU frequency scaling through `esp_pm`. A profile sets the range the
+//! CPU moves in; within it the chip idles at the minimum and `boost()`
+//! holds it at the maximum for CPU bound bursts like TLS handshakes.
+
+use crate::chip::CHIP;
+use anyhow::{bail, Result};
+use esp_idf_sys::{self as sys, esp};
+use std::{ffi::CStr, fmt, str::FromStr, sync::OnceLock};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PmConfig {
+    pub max_freq_mhz: i32,
+    pub min_freq_mhz: i32,
+    pub light_sleep: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Profile {
+    /// Always at full speed, for benchmarks and bulk transfers.
+    Performance,
+    /// Down to the XTAL frequency when idle, light sleeping in between with
+    /// the `light-sleep` feature.
+    Economy,
+}
+
+impl Profile {
+    pub fn config(self) -> PmConfig {
+        match self {
+            Self::Performance => PmConfig {
+                max_freq_mhz: CHIP.max_cpu_mhz,
+                min_freq_mhz: CHIP.max_cpu_mhz,
+                light_sleep: false,
+            },
+            Self::Economy => PmConfig {
+                max_freq_mhz: CHIP.max_cpu_mhz,
+                min_freq_mhz: 40,
+                light_sleep: cfg!(feature = "light-sleep"),
+            },
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        Ok(match name {
+            "performance" => Self::Performance,
+            "economy" => Self::Economy,
+            other => bail!("unknown power profile {other}"),
+        })
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Performance => "performance",
+            Self::Economy => "economy",
+        })
+    }
+}
+
+pub fn configure(config: PmConfig) -> Result<()> {
+    let raw = sys::esp_pm_config_t {
+        max_freq_mhz: config.max_freq_mhz,
+        min_freq_mhz: config.min_freq_mhz,
+        light_sleep_enable: config.light_sleep,
+    };
+    esp!(unsafe { sys::esp_pm_configure(&raw as *const _ as *const _) })?;
+    log::info!("power management: {config:?}");
+    Ok(())
+}
+
+pub fn configuration() -> Result<PmConfig> {
+    let mut raw = sys::esp_pm_config_t::default();
+    esp!(unsafe { sys::esp_pm_get_configuration(&mut raw as *mut _ as *mut _) })?;
+    Ok(PmConfig {
+        max_freq_mhz: raw.max_freq_mhz,
+        min_freq_mhz: raw.min_freq_mhz,
+        light_sleep: raw.light_sleep_enable,
+    })
+}
+
+pub fn set_profile(profile: Profile) -> Result<()> {
+    log::info!("power profile {profile}");
+    configure(profile.config())
+}
+
+/// Keeps the CPU at the profile's maximum frequency until dropped. Boosts
+/// nest, the frequency drops once the last one is gone.
+pub struct Boost(());
+
+struct Lock(sys::esp_pm_lock_handle_t);
+
+// esp_pm locks are meant to be taken and released from any task
+unsafe impl Send for Lock {}
+unsafe impl Sync for Lock {}
+
+pub fn boost() -> Boost {
+    if let Some(lock) = lock() {
+        unsafe { sys::esp_pm_lock_acquire(lock.0) };
+    }
+    Boost(())
+}
+
+impl Drop for Boost {
+    fn drop(&mut self) {
+        if let Some(lock) = lock() {
+            unsafe { sys::esp_pm_lock_release(lock.0) };
+        }
+    }
+}
+
+fn lock() -> Option<&'static Lock> {
+    static LOCK: OnceLock<Option<Lock>> = OnceLock::new();
+
+    // without power management the CPU stays at full speed anyway
+    if cfg!(not(esp_idf_pm_enable)) {
+        return None;
+    }
+
+    LOCK.get_or_init(|| {
+        const NAME: &CStr = c"boost";
+        let mut handle = std::ptr::null_mut();
+        let created = esp!(unsafe {
+            sys::esp_pm_lock_create(
+                sys::esp_pm_lock_type_t_ESP_PM_CPU_FREQ_MAX,
+                0,
+                NAME.as_ptr(),
+                &mut handle,
+            )
+        });
+        match created {
+            Ok(()) => Some(Lock(handle)),
+            Err(err) => {
+                log::warn!("no cpu boost lock: {err}");
+                None
+            }
+        }
+    })
+    .as_ref()
+}
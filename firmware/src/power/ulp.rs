@@ -0,0 +1,208 @@
+//! A GPIO watched by the ULP coprocessor through deep sleep, so a door
+//! contact or a float switch wakes the device only once it reads the level
+//! it's waiting for instead of on every timer wake. `ulp_wake` names the
+//! pin and the level, `<gpio>=high` or `<gpio>=low`, and `ulp_period` how
+//! many milliseconds apart the ULP looks.
+//!
+//! The program is a handful of ULP-FSM instructions assembled here: read
+//! the pin, count the check, halt until the next period unless the level
+//! matched, else wake. It leaves the level and the number of checks in RTC
+//! slow memory, which `wake_data()` reads back after the wake.
+
+use anyhow::{bail, Context, Result};
+use esp_idf_sys::{self as sys, esp};
+use std::time::Duration;
+
+#[cfg(not(esp32s3))]
+compile_error!("the ulp feature assembles ULP-FSM code for the ESP32-S3");
+
+/// The start of RTC slow memory, where the ULP runs from, `SOC_RTC_DATA_LOW`.
+const RTC_SLOW_MEM: *mut u32 = 0x5000_0000 as *mut u32;
+/// Words past the program where it leaves what it saw, within the 512
+/// bytes `CONFIG_ULP_COPROC_RESERVE_MEM` keeps for it.
+const DATA_WORD: u16 = 32;
+const LEVEL: u16 = 0;
+const CHECKS: u16 = 1;
+
+/// `RTC_GPIO_IN_REG` and where its `RTC_GPIO_IN_NEXT` field starts.
+const RTC_GPIO_IN_REG: u32 = 0x6000_8424;
+const RTC_GPIO_IN_NEXT_S: u32 = 10;
+
+/// What the ULP waits for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watch {
+    pub gpio: i32,
+    pub high: bool,
+    pub period: Duration,
+}
+
+/// `ulp_wake` and `ulp_period`, `None` when `ulp_wake` is empty.
+pub fn watch(config: &crate::config::Config) -> Result<Option<Watch>> {
+    if config.ulp_wake.is_empty() {
+        return Ok(None);
+    }
+    let (gpio, level) = config
+        .ulp_wake
+        .split_once('=')
+        .context("ulp_wake isn't <gpio>=<high|low>")?;
+    let high = match level.trim() {
+        "high" => true,
+        "low" => false,
+        other => bail!("ulp_wake level {other} isn't high or low"),
+    };
+    Ok(Some(Watch {
+        gpio: gpio
+            .trim()
+            .parse()
+            .context("ulp_wake takes a gpio number")?,
+        high,
+        period: Duration::from_millis(config.ulp_period.max(10).into()),
+    }))
+}
+
+/// What the ULP saw by the time it woke the chip.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WakeData {
+    pub high: bool,
+    /// Times the pin was read since sleep began, the match included.
+    pub checks: u16,
+}
+
+/// Loads the program for `watch`, starts it and arms the ULP wake.
+pub fn arm(watch: &Watch) -> Result<()> {
+    let pin = unsafe { sys::rtc_io_number_get(watch.gpio) };
+    if pin < 0 {
+        bail!("gpio {} isn't an RTC pin the ULP can read", watch.gpio);
+    }
+    unsafe {
+        esp!(sys::rtc_gpio_init(watch.gpio))?;
+        esp!(sys::rtc_gpio_set_direction(
+            watch.gpio,
+            sys::rtc_gpio_mode_t_RTC_GPIO_MODE_INPUT_ONLY
+        ))?;
+        // pulled towards the level that doesn't wake, as ext0 does
+        if watch.high {
+            esp!(sys::rtc_gpio_pullup_dis(watch.gpio))?;
+            esp!(sys::rtc_gpio_pulldown_en(watch.gpio))?;
+        } else {
+            esp!(sys::rtc_gpio_pulldown_dis(watch.gpio))?;
+            esp!(sys::rtc_gpio_pullup_en(watch.gpio))?;
+        }
+        esp!(sys::rtc_gpio_hold_en(watch.gpio))?;
+        RTC_SLOW_MEM
+            .add((DATA_WORD + LEVEL).into())
+            .write_volatile(0);
+        RTC_SLOW_MEM
+            .add((DATA_WORD + CHECKS).into())
+            .write_volatile(0);
+    }
+
+    let program = program(pin as u32 + RTC_GPIO_IN_NEXT_S, watch.high);
+    let mut size = program.len();
+    unsafe {
+        esp!(sys::ulp::ulp_process_macros_and_load(
+            0,
+            program.as_ptr().cast(),
+            &mut size
+        ))?;
+        esp!(sys::ulp::ulp_set_wakeup_period(
+            0,
+            watch.period.as_micros().try_into().unwrap_or(u32::MAX)
+        ))?;
+        esp!(sys::ulp::ulp_run(0))?;
+        esp!(sys::esp_sleep_enable_ulp_wakeup())?;
+    }
+    Ok(())
+}
+
+/// What the program left behind, for a wake the ULP caused.
+pub fn wake_data() -> WakeData {
+    let read = |word: u16| unsafe {
+        // the ULP only writes the lower half of a word
+        (RTC_SLOW_MEM.add((DATA_WORD + word).into()).read_volatile() & 0xffff) as u16
+    };
+    WakeData {
+        high: read(LEVEL) != 0,
+        checks: read(CHECKS),
+    }
+}
+
+/// The watch, `bit` being the pin's bit in `RTC_GPIO_IN_REG`.
+fn program(bit: u32, high: bool) -> [u32; 10] {
+    let data = u32::from(DATA_WORD);
+    [
+        // r1 = data, r2 = checks + 1, stored back
+        movi(1, data),
+        ld(2, 1, CHECKS.into()),
+        addi(2, 2, 1),
+        st(2, 1, CHECKS.into()),
+        // r0 = the pin's level
+        rd_reg(RTC_GPIO_IN_REG, bit, bit),
+        // the wake below when it's the one waited for
+        jumpr_eq(2, high.into()),
+        halt(),
+        st(0, 1, LEVEL.into()),
+        wake(),
+        halt(),
+    ]
+}
+
+// ULP-FSM encodings of the ESP32-S3, as the `I_*` macros of its `ulp.h`
+// lay the fields out
+
+const OPCODE_RD_REG: u32 = 2;
+const OPCODE_ST: u32 = 6;
+const OPCODE_ALU: u32 = 7;
+const OPCODE_BRANCH: u32 = 8;
+const OPCODE_END: u32 = 9;
+const OPCODE_HALT: u32 = 11;
+const OPCODE_LD: u32 = 13;
+const SUB_OPCODE_ALU_IMM: u32 = 1;
+const SUB_OPCODE_ST: u32 = 4;
+const SUB_OPCODE_B: u32 = 1;
+const ALU_SEL_ADD: u32 = 0;
+const ALU_SEL_MOV: u32 = 4;
+const B_CMP_E: u32 = 2;
+/// `st` to the lower half of the word.
+const WR_WAY_LOW: u32 = 3;
+
+fn alu_imm(sel: u32, dreg: u32, sreg: u32, imm: u32) -> u32 {
+    dreg | sreg << 2 | (imm & 0xffff) << 4 | sel << 21 | SUB_OPCODE_ALU_IMM << 25 | OPCODE_ALU << 28
+}
+
+fn movi(dreg: u32, imm: u32) -> u32 {
+    alu_imm(ALU_SEL_MOV, dreg, 0, imm)
+}
+
+fn addi(dreg: u32, sreg: u32, imm: u32) -> u32 {
+    alu_imm(ALU_SEL_ADD, dreg, sreg, imm)
+}
+
+/// `dreg` = the word at `sreg` + `offset`.
+fn ld(dreg: u32, sreg: u32, offset: u32) -> u32 {
+    dreg | sreg << 2 | offset << 10 | OPCODE_LD << 28
+}
+
+/// The word at `sreg` + `offset` = `dreg`.
+fn st(dreg: u32, sreg: u32, offset: u32) -> u32 {
+    dreg | sreg << 2 | WR_WAY_LOW << 7 | offset << 10 | SUB_OPCODE_ST << 25 | OPCODE_ST << 28
+}
+
+/// r0 = bits `low` to `high` of the RTC register at `reg`.
+fn rd_reg(reg: u32, low: u32, high: u32) -> u32 {
+    let periph_sel = (reg - 0x6000_8000) / 0x400;
+    (reg & 0xff) >> 2 | periph_sel << 8 | low << 18 | high << 23 | OPCODE_RD_REG << 28
+}
+
+/// Skips `forward` instructions ahead when r0 = `imm`.
+fn jumpr_eq(forward: u32, imm: u32) -> u32 {
+    imm | B_CMP_E << 16 | forward << 18 | SUB_OPCODE_B << 26 | OPCODE_BRANCH << 28
+}
+
+fn wake() -> u32 {
+    1 | OPCODE_END << 28
+}
+
+fn halt() -> u32 {
+    OPCODE_HALT << 28
+}
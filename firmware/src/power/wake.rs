@@ -0,0 +1,177 @@
+use anyhow::{bail, Result};
+use esp_idf_sys::{self as sys, esp};
+use std::time::Duration;
+
+/// How the EXT1 pins wake the chip, the two modes the S3 supports.
+// the firmware itself only sleeps on the timer, pin wakes are board specific
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ext1Mode {
+    AnyHigh,
+    AllLow,
+}
+
+/// What may end a deep sleep; every source set is armed and the first one
+/// to fire wins. Pins are RTC GPIOs, 0-21 on the S3.
+#[derive(Clone, Debug, Default)]
+pub struct SleepConfig {
+    pub timer: Option<Duration>,
+    /// One pin and the level that wakes, `true` for high. The internal
+    /// pull towards the other level is enabled.
+    pub ext0: Option<(i32, bool)>,
+    /// Several pins together.
+    pub ext1: Option<(Vec<i32>, Ext1Mode)>,
+    /// Touch pad 1-14; the S3 can only watch one while asleep.
+    pub touch: Option<u32>,
+    /// A pin the ULP reads every so often, see `power::ulp`.
+    #[cfg(feature = "ulp")]
+    pub ulp: Option<super::ulp::Watch>,
+}
+
+/// Why this boot is a wake from deep sleep.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WakeCause {
+    Timer,
+    Ext0,
+    /// Bit mask of the EXT1 pins that triggered.
+    Ext1(u64),
+    /// The touch pad that was touched.
+    Touch(u32),
+    /// What the ULP program saw.
+    #[cfg(feature = "ulp")]
+    Ulp(super::ulp::WakeData),
+    Other(u32),
+}
+
+/// Wake threshold as a share of the pad's idle reading, as in the IDF
+/// touch wakeup example.
+const TOUCH_THRESHOLD_RATIO: f64 = 0.1;
+/// Time for the touch FSM to take a first reading to base the threshold on.
+const TOUCH_SETTLE: Duration = Duration::from_millis(100);
+
+/// Arms the wake sources in `config` and enters deep sleep. Only returns if
+/// a source couldn't be set up, nothing is left armed then.
+pub fn sleep(config: &SleepConfig) -> Result<()> {
+    let result = arm(config);
+    if result.is_err() {
+        unsafe {
+            sys::esp_sleep_disable_wakeup_source(sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_ALL)
+        };
+        return result;
+    }
+    log::info!("deep sleep, wake on {config:?}");
+    unsafe { sys::esp_deep_sleep_start() }
+}
+
+fn arm(config: &SleepConfig) -> Result<()> {
+    #[cfg(feature = "ulp")]
+    let ulp = config.ulp.is_some();
+    #[cfg(not(feature = "ulp"))]
+    let ulp = false;
+    if config.timer.is_none()
+        && config.ext0.is_none()
+        && config.ext1.is_none()
+        && config.touch.is_none()
+        && !ulp
+    {
+        bail!("no wake source, the device would never wake");
+    }
+    // a previous sleep() may have failed halfway
+    esp!(unsafe {
+        sys::esp_sleep_disable_wakeup_source(sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_ALL)
+    })?;
+
+    if let Some(duration) = config.timer {
+        esp!(unsafe { sys::esp_sleep_enable_timer_wakeup(duration.as_micros() as u64) })?;
+    }
+
+    if let Some((pin, high)) = config.ext0 {
+        rtc_pin(pin)?;
+        unsafe {
+            if high {
+                esp!(sys::rtc_gpio_pullup_dis(pin))?;
+                esp!(sys::rtc_gpio_pulldown_en(pin))?;
+            } else {
+                esp!(sys::rtc_gpio_pulldown_dis(pin))?;
+                esp!(sys::rtc_gpio_pullup_en(pin))?;
+            }
+            esp!(sys::esp_sleep_enable_ext0_wakeup(pin, high.into()))?;
+        }
+    }
+
+    if let Some((pins, mode)) = &config.ext1 {
+        let mut mask = 0u64;
+        for &pin in pins {
+            rtc_pin(pin)?;
+            mask |= 1 << pin;
+        }
+        let mode = match mode {
+            Ext1Mode::AnyHigh => sys::esp_sleep_ext1_wakeup_mode_t_ESP_EXT1_WAKEUP_ANY_HIGH,
+            Ext1Mode::AllLow => sys::esp_sleep_ext1_wakeup_mode_t_ESP_EXT1_WAKEUP_ALL_LOW,
+        };
+        esp!(unsafe { sys::esp_sleep_enable_ext1_wakeup(mask, mode) })?;
+    }
+
+    if let Some(pad) = config.touch {
+        arm_touch(pad)?;
+    }
+
+    #[cfg(feature = "ulp")]
+    if let Some(watch) = &config.ulp {
+        super::ulp::arm(watch)?;
+    }
+
+    Ok(())
+}
+
+fn arm_touch(pad: u32) -> Result<()> {
+    if !(1..sys::touch_pad_t_TOUCH_PAD_MAX).contains(&pad) {
+        bail!("touch pad {pad} doesn't exist");
+    }
+    unsafe {
+        esp!(sys::touch_pad_init())?;
+        esp!(sys::touch_pad_config(pad))?;
+        esp!(sys::touch_pad_sleep_channel_enable(pad, true))?;
+        esp!(sys::touch_pad_set_fsm_mode(
+            sys::touch_fsm_mode_t_TOUCH_FSM_MODE_TIMER
+        ))?;
+        esp!(sys::touch_pad_fsm_start())?;
+    }
+    std::thread::sleep(TOUCH_SETTLE);
+
+    let mut idle = 0;
+    unsafe {
+        esp!(sys::touch_pad_sleep_channel_read_smooth(pad, &mut idle))?;
+        esp!(sys::touch_pad_sleep_set_threshold(
+            pad,
+            (f64::from(idle) * TOUCH_THRESHOLD_RATIO) as u32
+        ))?;
+        esp!(sys::esp_sleep_enable_touchpad_wakeup())?;
+    }
+    Ok(())
+}
+
+fn rtc_pin(pin: i32) -> Result<()> {
+    if !unsafe { sys::rtc_gpio_is_valid_gpio(pin) } {
+        bail!("gpio {pin} can't wake from deep sleep, it isn't an RTC pin");
+    }
+    Ok(())
+}
+
+/// The wake source behind this boot, `None` after a reset or power-on.
+pub fn wake_cause() -> Option<WakeCause> {
+    Some(match unsafe { sys::esp_sleep_get_wakeup_cause() } {
+        sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_UNDEFINED => return None,
+        sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER => WakeCause::Timer,
+        sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT0 => WakeCause::Ext0,
+        sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT1 => {
+            WakeCause::Ext1(unsafe { sys::esp_sleep_get_ext1_wakeup_status() })
+        }
+        sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_TOUCHPAD => {
+            WakeCause::Touch(unsafe { sys::esp_sleep_get_touchpad_wakeup_status() })
+        }
+        #[cfg(feature = "ulp")]
+        sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_ULP => WakeCause::Ulp(super::ulp::wake_data()),
+        other => WakeCause::Other(other),
+    })
+}
@@ -0,0 +1,110 @@
+//! Startup checks of the sdkconfig the firmware was built against, as the
+//! running image sees it, next to what the enabled features need. A
+//! mismatch builds fine and fails later and far from its cause, a stack
+//! overflow in the middle of a handshake or a socket that can't be opened,
+//! so each one is logged at boot with the setting to change.
+
+use crate::{chip::CHIP, runtime::RuntimeConfig};
+use esp_idf_hal::task::thread::ThreadSpawnConfiguration;
+
+/// Below this the rustls handshake overflows the stack it runs on.
+const HANDSHAKE_STACK: usize = 32 * 1024;
+/// What a thread that may build a TLS session or resolve a name needs.
+const PTHREAD_STACK: usize = 12 * 1024;
+/// `main()` only sets up and joins the async main thread, but logging and
+/// the chip bring up on the way still need this much.
+const MAIN_TASK_STACK: u32 = 8 * 1024;
+/// Sockets the runtime's eventfds, the console, the servers and a few
+/// concurrent fetches hold at once.
+const SOCKETS: u32 = 10;
+/// Segments lwIP queues per TCP connection before the reader drains them;
+/// fewer stalls a TLS record arriving in several.
+const TCP_RECVMBOX: u32 = 6;
+
+/// Logs a warning for every conflict found.
+pub fn run(runtime: &RuntimeConfig) {
+    let mut conflicts = 0;
+    let mut conflict = |message: String| {
+        log::warn!("preflight: {message}");
+        conflicts += 1;
+    };
+
+    if esp_idf_sys::CONFIG_ESP_MAIN_TASK_STACK_SIZE < MAIN_TASK_STACK {
+        conflict(format!(
+            "main task stack is {} bytes, raise CONFIG_ESP_MAIN_TASK_STACK_SIZE to {MAIN_TASK_STACK}",
+            esp_idf_sys::CONFIG_ESP_MAIN_TASK_STACK_SIZE
+        ));
+    }
+    if runtime.main_stack_size < HANDSHAKE_STACK {
+        conflict(format!(
+            "async main stack is {} bytes and rustls handshakes run on it, {HANDSHAKE_STACK} is the least",
+            runtime.main_stack_size
+        ));
+    }
+    // what threads actually get, set_pthread_stack_size() may not have taken
+    let pthread = ThreadSpawnConfiguration::get().map_or(
+        esp_idf_sys::CONFIG_PTHREAD_TASK_STACK_SIZE_DEFAULT as usize,
+        |config| config.stack_size,
+    );
+    if pthread < PTHREAD_STACK {
+        conflict(format!(
+            "pthread stack is {pthread} bytes, too small for a rustls session, {PTHREAD_STACK} is the least"
+        ));
+    }
+
+    if esp_idf_sys::CONFIG_LWIP_MAX_SOCKETS < SOCKETS {
+        conflict(format!(
+            "lwIP allows {} sockets, raise CONFIG_LWIP_MAX_SOCKETS to {SOCKETS}",
+            esp_idf_sys::CONFIG_LWIP_MAX_SOCKETS
+        ));
+    }
+    if esp_idf_sys::CONFIG_LWIP_TCP_RECVMBOX_SIZE < TCP_RECVMBOX {
+        conflict(format!(
+            "lwIP queues {} segments per connection, raise CONFIG_LWIP_TCP_RECVMBOX_SIZE to {TCP_RECVMBOX}",
+            esp_idf_sys::CONFIG_LWIP_TCP_RECVMBOX_SIZE
+        ));
+    }
+    if esp_idf_sys::CONFIG_LWIP_TCPIP_RECVMBOX_SIZE < esp_idf_sys::CONFIG_LWIP_TCP_RECVMBOX_SIZE {
+        conflict(format!(
+            "the lwIP task's mailbox ({}) is smaller than a connection's ({}), \
+             CONFIG_LWIP_TCPIP_RECVMBOX_SIZE drops what the connections would queue",
+            esp_idf_sys::CONFIG_LWIP_TCPIP_RECVMBOX_SIZE,
+            esp_idf_sys::CONFIG_LWIP_TCP_RECVMBOX_SIZE
+        ));
+    }
+
+    // rustls verifies against webpki roots, the bundle only costs flash
+    #[cfg(esp_idf_mbedtls_certificate_bundle)]
+    conflict(String::from(
+        "mbedTLS's certificate bundle is linked but rustls doesn't read it, \
+         set CONFIG_MBEDTLS_CERTIFICATE_BUNDLE=n",
+    ));
+
+    let psram = unsafe { esp_idf_sys::heap_caps_get_total_size(esp_idf_sys::MALLOC_CAP_SPIRAM) };
+    if CHIP.psram && psram == 0 {
+        conflict(String::from(
+            "no PSRAM in the heap, large allocations all fall back to internal RAM; \
+             check CONFIG_SPIRAM and the module",
+        ));
+    } else if !CHIP.psram && psram > 0 {
+        conflict(format!(
+            "{psram} bytes of PSRAM in the heap on a chip not expected to have any"
+        ));
+    }
+
+    if esp_idf_sys::CONFIG_FREERTOS_HZ < 1000 {
+        conflict(format!(
+            "FreeRTOS ticks at {} Hz, sleeps and timeouts round up to {} ms; set CONFIG_FREERTOS_HZ=1000",
+            esp_idf_sys::CONFIG_FREERTOS_HZ,
+            1000 / esp_idf_sys::CONFIG_FREERTOS_HZ
+        ));
+    }
+    #[cfg(all(feature = "bench", not(esp_idf_freertos_generate_run_time_stats)))]
+    conflict(String::from(
+        "bench can't report cpu usage without CONFIG_FREERTOS_GENERATE_RUN_TIME_STATS=y",
+    ));
+
+    if conflicts == 0 {
+        log::info!("preflight: sdkconfig fits the enabled features");
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{dns, telemetry, tls};
+use anyhow::{anyhow, Context, Result};
+use bytes::Buf;
+use quinn::{crypto::rustls::QuicClientConfig, ClientConfig, Endpoint, TransportConfig, VarInt};
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+const ALPN: &[u8] = b"h3";
+const DEFAULT_PORT: u16 = 443;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// quinn defaults to multi-megabyte windows, far more than the heap can
+/// back.
+const STREAM_WINDOW: u32 = 32 * 1024;
+const CONNECTION_WINDOW: u32 = 64 * 1024;
+
+/// Fetches `url` over HTTP/3 and logs the body along with how long the
+/// QUIC handshake and the whole request took, to compare against the
+/// TCP+TLS path in `http::fetch()`.
+pub async fn display_url(url: &str) -> Result<()> {
+    let uri: http::Uri = url.parse().context("invalid url")?;
+    if uri.scheme_str() != Some("https") {
+        return Err(anyhow!("http/3 needs an https url, got {url}"));
+    }
+    let host = uri.host().context("url has no host")?;
+    let port = uri.port_u16().unwrap_or(DEFAULT_PORT);
+
+    let addr = *dns::resolve_addrs(host, port)
+        .await?
+        .first()
+        .with_context(|| format!("{host} has no addresses"))?;
+
+    let start = Instant::now();
+    let endpoint = endpoint(addr)?;
+    let connection = endpoint
+        .connect(addr, host)?
+        .await
+        .context("quic handshake failed")?;
+    let handshake = start.elapsed();
+    log::info!(
+        "{url} quic handshake over {} took {handshake:?}",
+        crate::net::family(addr.ip())
+    );
+
+    let (mut driver, mut sender) = h3::client::new(h3_quinn::Connection::new(connection)).await?;
+    let drive = tokio::spawn(async move { driver.wait_idle().await });
+
+    let mut stream = sender
+        .send_request(http::Request::get(uri).body(())?)
+        .await?;
+    stream.finish().await?;
+
+    let response = stream.recv_response().await?;
+    let mut body = Vec::new();
+    while let Some(mut chunk) = stream.recv_data().await? {
+        while chunk.has_remaining() {
+            let bytes = chunk.chunk();
+            body.extend_from_slice(bytes);
+            let len = bytes.len();
+            chunk.advance(len);
+        }
+    }
+    let total = start.elapsed();
+
+    drop(sender);
+    endpoint.close(VarInt::from_u32(0), b"done");
+    let _ = drive.await;
+
+    log::info!(
+        "{url} over http/3: {}, {} bytes in {total:?}",
+        response.status(),
+        body.len()
+    );
+    log::info!("{}", String::from_utf8_lossy(&body));
+    telemetry::set("quic_handshake_ms", handshake.as_millis() as u64);
+    telemetry::set("quic_fetch_ms", total.as_millis() as u64);
+
+    Ok(())
+}
+
+fn endpoint(peer: SocketAddr) -> Result<Endpoint> {
+    let bind: SocketAddr = if peer.is_ipv6() {
+        "[::]:0".parse()?
+    } else {
+        "0.0.0.0:0".parse()?
+    };
+    let mut endpoint = Endpoint::client(bind)?;
+    endpoint.set_default_client_config(client_config()?);
+    Ok(endpoint)
+}
+
+/// The shared rustls config with the HTTP/3 ALPN, so QUIC trusts exactly
+/// the roots the TCP clients do.
+fn client_config() -> Result<ClientConfig> {
+    let mut tls = (*tls::client_config()).clone();
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+
+    let mut transport = TransportConfig::default();
+    transport
+        .max_idle_timeout(Some(IDLE_TIMEOUT.try_into()?))
+        .stream_receive_window(VarInt::from_u32(STREAM_WINDOW))
+        .receive_window(VarInt::from_u32(CONNECTION_WINDOW));
+
+    let mut config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?));
+    config.transport_config(Arc::new(transport));
+    Ok(config)
+}
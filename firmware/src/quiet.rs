@@ -0,0 +1,129 @@
+//! Quiet hours, for sites that restrict RF at night: inside a
+//! `quiet_hours` window, local time by `utc_offset`, polls and telemetry
+//! are held (the jobs marked `radio()`), and with `quiet_wifi` at 1 WiFi
+//! goes down too. When the window ends the link comes back and every held
+//! job runs once straight away, flushing what piled up.
+//!
+//! Without a synced clock there is no telling the hour, so nothing is
+//! held until the clock is set.
+
+use crate::{
+    config::Config,
+    events::{self, Event},
+    runtime,
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const DAY_SECS: i64 = 24 * 60 * 60;
+
+/// Whether WiFi goes down with the quiet hours.
+static WIFI_OFF: AtomicBool = AtomicBool::new(false);
+
+/// Start and end, minutes into the local day; the end is before the start
+/// for a window over midnight.
+#[derive(Clone, Copy)]
+struct Window {
+    start: u32,
+    end: u32,
+}
+
+impl Window {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start < self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+fn parse(value: &str) -> Result<Vec<Window>> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|window| !window.is_empty())
+        .map(|window| {
+            let (start, end) = window
+                .split_once('-')
+                .with_context(|| format!("quiet window {window} isn't <HH:MM>-<HH:MM>"))?;
+            let (start, end) = (minute(start)?, minute(end)?);
+            if start == end {
+                bail!("quiet window {window} is empty");
+            }
+            Ok(Window { start, end })
+        })
+        .collect()
+}
+
+/// `HH:MM` as minutes.
+pub fn minute(value: &str) -> Result<u32> {
+    let (hours, minutes) = value
+        .trim()
+        .split_once(':')
+        .with_context(|| format!("{value} isn't HH:MM"))?;
+    let (hours, minutes): (u32, u32) = (hours.parse()?, minutes.parse()?);
+    if hours > 23 || minutes > 59 {
+        bail!("{value} isn't a time of day");
+    }
+    Ok(hours * 60 + minutes)
+}
+
+/// `+HH:MM` or `-HH:MM` as signed minutes, 0 when empty.
+pub fn offset(value: &str) -> Result<i64> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(0);
+    }
+    let (sign, rest) = match value.split_at(1) {
+        ("+", rest) => (1, rest),
+        ("-", rest) => (-1, rest),
+        _ => bail!("utc_offset {value} doesn't start with + or -"),
+    };
+    Ok(sign * i64::from(minute(rest).context("invalid utc_offset")?))
+}
+
+pub fn start(config: &Config) -> Result<()> {
+    let windows = parse(&config.quiet_hours).context("invalid quiet_hours")?;
+    let offset = offset(&config.utc_offset)?;
+    WIFI_OFF.store(config.quiet_wifi == 1, Ordering::Relaxed);
+    if windows.is_empty() {
+        return Ok(());
+    }
+
+    runtime::spawn(async move {
+        events::wait_until(|state| state.time_synced).await;
+        loop {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let local = now as i64 + offset * 60;
+            let minute = (local.rem_euclid(DAY_SECS) / 60) as u32;
+            let quiet = windows.iter().any(|window| window.contains(minute));
+            if quiet != events::state().quiet {
+                log::info!("quiet hours {}", if quiet { "begin" } else { "over" });
+                events::publish(if quiet {
+                    Event::QuietStarted
+                } else {
+                    Event::QuietEnded
+                });
+            }
+            // the windows are whole minutes, checked on each one
+            runtime::sleep(Duration::from_secs(60 - now % 60)).await;
+        }
+    });
+    Ok(())
+}
+
+/// Resolves once a radio link should go down for quiet hours, never when
+/// `quiet_wifi` leaves it up.
+pub async fn radio_off() {
+    if !WIFI_OFF.load(Ordering::Relaxed) {
+        return std::future::pending().await;
+    }
+    events::wait_until(|state| state.quiet).await;
+}
@@ -0,0 +1,210 @@
+//! One token bucket for every request the device makes of its backends, so
+//! a poll interval set too short or a storm of commands can't flood them
+//! or trip a WAF. `rate_limit` is `<requests>/<secs>`: bursts of up to
+//! `requests`, refilled at that rate. A request with no token left is held
+//! until there is one, or dropped if that's more than `MAX_WAIT` away; both
+//! are counted in `outbound_limited_total`.
+//!
+//! A server can also ask for fewer requests itself: a 429 or 503 with
+//! `Retry-After`, or rate limit headers down to no requests remaining until
+//! a reset. Its host is then throttled for that long, published as
+//! `Throttled`, and `acquire_for()` holds or drops requests to it the same
+//! way until the time is up.
+
+use crate::{
+    config::Config,
+    error::{Failure, FirmwareError},
+    events::{self, Event},
+    http::Head,
+    metrics::OUTBOUND_LIMITED,
+    runtime,
+};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Longest a request is held for a token.
+const MAX_WAIT: Duration = Duration::from_secs(30);
+/// A 429 without a `Retry-After` still gets this long.
+const DEFAULT_THROTTLE: Duration = Duration::from_secs(60);
+/// The most a server can hold requests off for, so a wrong header can't
+/// keep the device from its backend for days.
+const MAX_THROTTLE: Duration = Duration::from_secs(60 * 60);
+/// Reset values past this are unix times rather than seconds from now.
+const EPOCH_RESET: u64 = 1_000_000_000;
+
+struct Bucket {
+    capacity: f64,
+    per_sec: f64,
+    /// Below zero while requests are held for tokens not yet refilled.
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// `None` when unlimited.
+static BUCKET: Mutex<Option<Bucket>> = Mutex::new(None);
+/// Until when each host asked to be left alone.
+static THROTTLED: Mutex<BTreeMap<String, Instant>> = Mutex::new(BTreeMap::new());
+
+pub fn configure(config: &Config) -> Result<()> {
+    let bucket = match config.rate_limit.trim() {
+        "" | "off" => None,
+        limit => {
+            let (requests, secs) = limit
+                .split_once('/')
+                .context("rate_limit is <requests>/<secs>")?;
+            let requests: u32 = requests
+                .trim()
+                .parse()
+                .context("invalid rate_limit count")?;
+            let secs: u32 = secs.trim().parse().context("invalid rate_limit window")?;
+            ensure!(requests > 0 && secs > 0, "rate_limit can't be zero");
+            Some(Bucket {
+                capacity: requests.into(),
+                per_sec: f64::from(requests) / f64::from(secs),
+                tokens: requests.into(),
+                refilled: Instant::now(),
+            })
+        }
+    };
+    *BUCKET.lock().unwrap() = bucket;
+    Ok(())
+}
+
+/// Waits for a token for a request from `client`, an error if the wait
+/// would be too long and the request is dropped.
+pub async fn acquire(client: &str) -> Result<()> {
+    let wait = reserve(client, MAX_WAIT)?;
+    if !wait.is_zero() {
+        runtime::sleep(wait).await;
+    }
+    Ok(())
+}
+
+/// `acquire()` for a request to `host`, after what its server asked for;
+/// an error if that's more than `MAX_WAIT` away.
+pub async fn acquire_for(client: &str, host: &str) -> Result<()> {
+    let until = THROTTLED.lock().unwrap().get(host).copied();
+    if let Some(wait) = until.and_then(|until| until.checked_duration_since(Instant::now())) {
+        if wait > MAX_WAIT {
+            OUTBOUND_LIMITED.inc(&[("client", client), ("outcome", "throttled")]);
+            let err = anyhow!("{host} asked for no requests for {}s more", wait.as_secs());
+            return Err(FirmwareError::Http(Failure::retryable(err)).into());
+        }
+        log::debug!("{client} request held {wait:?} for {host}");
+        runtime::sleep(wait).await;
+    }
+    acquire(client).await
+}
+
+/// Throttles `host` for as long as `head`, its answer, asks.
+pub fn observe(host: &str, head: &Head) {
+    let delay = match head.status {
+        429 => Some(retry_after(head).unwrap_or(DEFAULT_THROTTLE)),
+        503 => retry_after(head),
+        _ => None,
+    };
+    if let Some(delay) = delay.or_else(|| exhausted(head)) {
+        throttle(host, delay.min(MAX_THROTTLE));
+    }
+}
+
+/// `Retry-After`, seconds or an HTTP date.
+fn retry_after(head: &Head) -> Option<Duration> {
+    let value = head.get("retry-after")?;
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    // a date is only as good as the clock, a past one says nothing
+    let date = crate::clock::parse_http_date(value)?;
+    Some(Duration::from_secs(date.checked_sub(unix_now())?))
+}
+
+/// Until the reset, if the rate limit headers say no requests are left.
+fn exhausted(head: &Head) -> Option<Duration> {
+    let header = |name: &str| {
+        head.get(&format!("ratelimit-{name}"))
+            .or_else(|| head.get(&format!("x-ratelimit-{name}")))
+    };
+    if header("remaining")?.parse::<u64>().ok()? > 0 {
+        return None;
+    }
+    let reset: u64 = header("reset")?.parse().ok()?;
+    if reset < EPOCH_RESET {
+        return Some(Duration::from_secs(reset));
+    }
+    Some(Duration::from_secs(reset.checked_sub(unix_now())?))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn throttle(host: &str, delay: Duration) {
+    if delay.is_zero() {
+        return;
+    }
+    let until = Instant::now() + delay;
+    {
+        let mut throttled = THROTTLED.lock().unwrap();
+        let kept = throttled.entry(host.to_owned()).or_insert(until);
+        // a later answer can't shorten what an earlier one asked for
+        if *kept > until {
+            return;
+        }
+        *kept = until;
+    }
+    log::warn!("{host} throttled for {}s", delay.as_secs());
+    events::publish(Event::Throttled {
+        host: host.to_owned(),
+        secs: delay.as_secs(),
+    });
+    runtime::spawn(async move {
+        runtime::sleep(delay).await;
+        let mut throttled = THROTTLED.lock().unwrap();
+        let now = Instant::now();
+        let before = throttled.len();
+        throttled.retain(|_, until| *until > now);
+        // a later throttle's own task ends that one
+        if throttled.is_empty() && before > 0 {
+            drop(throttled);
+            events::publish(Event::Unthrottled);
+        }
+    });
+}
+
+/// A token now or an error, for publishers that can't wait.
+pub fn try_acquire(client: &str) -> Result<()> {
+    reserve(client, Duration::ZERO).map(drop)
+}
+
+/// Takes a token, ahead of its refill if it's at most `max_wait` away, and
+/// says how long to wait for it.
+fn reserve(client: &str, max_wait: Duration) -> Result<Duration> {
+    let mut bucket = BUCKET.lock().unwrap();
+    let Some(bucket) = bucket.as_mut() else {
+        return Ok(Duration::ZERO);
+    };
+    let now = Instant::now();
+    let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * bucket.per_sec).min(bucket.capacity);
+    bucket.refilled = now;
+
+    let wait = Duration::from_secs_f64((1.0 - bucket.tokens).max(0.0) / bucket.per_sec);
+    if wait > max_wait {
+        OUTBOUND_LIMITED.inc(&[("client", client), ("outcome", "dropped")]);
+        bail!("{client} request dropped by the rate limit");
+    }
+    bucket.tokens -= 1.0;
+    if !wait.is_zero() {
+        OUTBOUND_LIMITED.inc(&[("client", client), ("outcome", "deferred")]);
+        log::debug!("{client} request held {wait:?} by the rate limit");
+    }
+    Ok(wait)
+}
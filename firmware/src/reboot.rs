@@ -0,0 +1,134 @@
+//! Scheduled reboots, against the slow leaks a device running for months
+//! builds up: `reboot_at` is a local time by `utc_offset`, `HH:MM` for
+//! every day or `<day> HH:MM` for once a week, such as `sun 04:00`. Each
+//! device goes up to `reboot_jitter` minutes later, by its id, so a fleet
+//! doesn't come back to the backend all in the same second.
+//!
+//! A boot younger than `MIN_UPTIME` lets the time pass, which keeps a
+//! clock stepped back by NTP from rebooting twice. `restart()` is the
+//! clean way down: a last telemetry report, the batched NVS writes
+//! committed, then the reset.
+
+use crate::{config::Config, device, events, power, quiet, runtime};
+use anyhow::{bail, Context, Result};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAY_SECS: i64 = 24 * 60 * 60;
+const WEEK_SECS: i64 = 7 * DAY_SECS;
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+/// A boot this young doesn't reboot on schedule.
+const MIN_UPTIME: Duration = Duration::from_secs(60 * 60);
+/// Longest the schedule sleeps before looking at the clock again, which
+/// NTP may have moved.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// When to reboot, local seconds into the week or into the day.
+#[derive(Clone, Copy, Debug)]
+struct Schedule {
+    at: i64,
+    period: i64,
+}
+
+impl Schedule {
+    fn parse(value: &str) -> Result<Option<Self>> {
+        let value = value.trim();
+        if value.is_empty() {
+            return Ok(None);
+        }
+        let (day, time) = match value.split_once(' ') {
+            Some((day, time)) => {
+                let day = DAYS
+                    .iter()
+                    .position(|name| day.trim().eq_ignore_ascii_case(name))
+                    .with_context(|| format!("reboot_at day {day} isn't one of mon..sun"))?;
+                (Some(day as i64), time)
+            }
+            None => (None, value),
+        };
+        let minute = i64::from(quiet::minute(time).context("invalid reboot_at")?);
+        Ok(Some(match day {
+            Some(day) => Self {
+                at: day * DAY_SECS + minute * 60,
+                period: WEEK_SECS,
+            },
+            None => Self {
+                at: minute * 60,
+                period: DAY_SECS,
+            },
+        }))
+    }
+
+    /// Seconds from `local`, Unix seconds in local time, to the next time
+    /// it's due, a period at most.
+    fn until(&self, local: i64) -> i64 {
+        // 1970-01-01 was a Thursday, 3 days into a week from Monday
+        let into = match self.period {
+            WEEK_SECS => (local + 3 * DAY_SECS).rem_euclid(WEEK_SECS),
+            period => local.rem_euclid(period),
+        };
+        match self.at - into {
+            wait if wait > 0 => wait,
+            wait => wait + self.period,
+        }
+    }
+}
+
+/// This device's share of `minutes`, the same on every boot.
+fn jitter(minutes: u16) -> i64 {
+    let spread = i64::from(minutes) * 60 + 1;
+    // FNV-1a over the id, which is all it needs to spread a fleet
+    let hash = device::id()
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    (hash % spread as u64) as i64
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// Starts the schedule in `reboot_at`, if there is one.
+pub fn start(config: &Config) -> Result<()> {
+    let Some(schedule) = Schedule::parse(&config.reboot_at)? else {
+        return Ok(());
+    };
+    if config.reboot_jitter > 24 * 60 {
+        bail!("reboot_jitter {} is over a day", config.reboot_jitter);
+    }
+    let offset = quiet::offset(&config.utc_offset)? * 60;
+    let jitter = jitter(config.reboot_jitter);
+    let config = config.clone();
+
+    runtime::spawn(async move {
+        events::wait_until(|state| state.time_synced).await;
+        loop {
+            let due = now() + schedule.until(now() + offset) + jitter;
+            log::info!("scheduled reboot in {}s", due - now());
+            while now() < due {
+                let left = Duration::from_secs((due - now()).max(1) as u64);
+                runtime::sleep(left.min(CHECK_INTERVAL)).await;
+            }
+            if device::uptime() < MIN_UPTIME {
+                log::info!("scheduled reboot skipped, up for {:?}", device::uptime());
+                continue;
+            }
+            restart(&config, "scheduled").await;
+        }
+    });
+    Ok(())
+}
+
+/// Reboots cleanly: the last telemetry report goes out and the held NVS
+/// writes are committed before the reset.
+pub async fn restart(config: &Config, reason: &str) -> ! {
+    log::warn!("rebooting, {reason}");
+    crate::telemetry::set("reboot", reason);
+    power::last_report(config).await;
+    crate::config::batch::flush_all();
+    esp_idf_hal::reset::restart()
+}
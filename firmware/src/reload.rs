@@ -0,0 +1,160 @@
+//! Config changes applied while running. On every `ConfigChanged` the
+//! config is read back from NVS and compared with the one before: the
+//! `LIVE` fields take effect right away, anything else that changed is
+//! logged as waiting for a reboot. Switching `environment`, from the
+//! console or with an `env <name>` command, is a change like any other:
+//! the fields the bundle sets are compared one by one.
+
+#[cfg(feature = "early-data")]
+use crate::http;
+use crate::{
+    config::Config,
+    events::{self, Event},
+    flags,
+    jobs::{Scheduler, Timers},
+    poller, ratelimit, runtime, telemetry, tls,
+};
+use anyhow::{Context, Result};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use log::LevelFilter;
+
+/// The fields a change to applies without a reboot.
+pub const LIVE: &[&str] = &[
+    "log_level",
+    "poll_urls",
+    "poll_limit",
+    "influx_url",
+    "influx_backup",
+    "influx_token",
+    "influx_encoding",
+    "backup_failures",
+    "backup_recheck",
+    "udp_collector",
+    "rate_limit",
+    "tls_allowlist",
+    "tls_report",
+    "tls_ocsp",
+    "early_data",
+    "flags",
+    "cohort",
+    // what they select is compared field by field
+    "environment",
+    "environments",
+];
+
+pub fn start(config: &Config, nvs: EspDefaultNvsPartition, jobs: Scheduler<impl Timers>) {
+    let mut current = config.clone();
+
+    let commands = nvs.clone();
+    runtime::spawn(async move {
+        let mut events = events::subscribe();
+        loop {
+            let Ok(Event::Command(command)) = events.recv().await else {
+                continue;
+            };
+            let mut words = command.split_whitespace();
+            let (Some("env"), Some(name), None) = (words.next(), words.next(), words.next()) else {
+                continue;
+            };
+            let (nvs, name) = (commands.clone(), name.to_owned());
+            match runtime::run_blocking(move || switch_environment(nvs, &name)).await {
+                Ok(Ok(reply)) => log::info!("{reply}"),
+                Ok(Err(err)) | Err(err) => log::warn!("{err:#}"),
+            }
+        }
+    });
+
+    runtime::spawn(async move {
+        let mut events = events::subscribe();
+        loop {
+            let Ok(Event::ConfigChanged) = events.recv().await else {
+                continue;
+            };
+            let nvs = nvs.clone();
+            let config = match runtime::run_blocking(move || Config::load(nvs)).await {
+                Ok(Ok(config)) => config,
+                Ok(Err(err)) | Err(err) => {
+                    log::warn!("couldn't reload the config: {err:#}");
+                    continue;
+                }
+            };
+
+            let (live, later): (Vec<_>, Vec<_>) = changed(&current, &config)
+                .into_iter()
+                .partition(|field| LIVE.contains(&field.as_str()));
+            for field in &live {
+                match apply(field, &config, &jobs) {
+                    Ok(()) => log::info!("config {field} applied"),
+                    Err(err) => log::warn!("config {field} not applied: {err:#}"),
+                }
+            }
+            if !later.is_empty() {
+                log::info!("config {} applies after a reboot", later.join(", "));
+            }
+            current = config;
+        }
+    });
+}
+
+/// Makes `name` the `environment`, or none for `none`, and applies it.
+pub fn switch_environment(nvs: EspDefaultNvsPartition, name: &str) -> Result<String> {
+    let value = if name == "none" { "" } else { name };
+    let mut changes = serde_json::Map::new();
+    changes.insert("environment".into(), value.into());
+    if Config::apply_remote(nvs, &changes)
+        .with_context(|| format!("couldn't switch to environment {name}"))?
+        .is_empty()
+    {
+        return Ok(format!("environment {name} already in effect"));
+    }
+    events::publish(Event::ConfigChanged);
+    Ok(format!("switched to environment {name}"))
+}
+
+/// Sets the level `log_level` names.
+pub fn set_log_level(config: &Config) -> Result<()> {
+    let level: LevelFilter = config
+        .log_level
+        .parse()
+        .ok()
+        .with_context(|| format!("unknown log level {}", config.log_level))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+fn apply(field: &str, config: &Config, jobs: &Scheduler<impl Timers>) -> Result<()> {
+    match field {
+        "log_level" => set_log_level(config),
+        "poll_urls" | "poll_limit" => poller::start(config, jobs),
+        #[cfg(feature = "http-reqwest")]
+        "influx_url" | "influx_backup" | "influx_token" | "influx_encoding" => {
+            telemetry::influx::start(config)
+        }
+        "backup_failures" | "backup_recheck" => {
+            telemetry::endpoints::configure(config);
+            Ok(())
+        }
+        "udp_collector" => telemetry::udp::start(config),
+        "rate_limit" => ratelimit::configure(config),
+        "tls_allowlist" => tls::allowlist::configure(config),
+        "tls_report" => tls::chain::configure(config),
+        "tls_ocsp" => tls::ocsp::configure(config),
+        #[cfg(feature = "early-data")]
+        "early_data" => http::early::configure(config),
+        "flags" | "cohort" => flags::configure(config),
+        _ => Ok(()),
+    }
+}
+
+/// The fields whose values differ.
+fn changed(old: &Config, new: &Config) -> Vec<String> {
+    let (Ok(serde_json::Value::Object(old)), Ok(serde_json::Value::Object(new))) =
+        (serde_json::to_value(old), serde_json::to_value(new))
+    else {
+        return Vec::new();
+    };
+    new.into_iter()
+        .filter(|(field, value)| old.get(field) != Some(value))
+        .map(|(field, _)| field)
+        .collect()
+}
@@ -0,0 +1,139 @@
+//! Config pushed from the backend. The document is a JSON object of config
+//! fields, `{"mqtt_broker": "..."}`, and the response carries a hex Ed25519
+//! signature over the exact body bytes in `X-Signature`. Each document
+//! has a `version` that goes up with every one the backend publishes; a
+//! device only takes a document newer than the last it applied, so an old,
+//! validly signed one can't be replayed to it.
+//! Staged rollouts go the same way, as a document changing `flags`, see
+//! `flags`.
+
+use crate::{
+    config::Config,
+    events::{self, Event},
+    runtime, telemetry,
+};
+use anyhow::{bail, Context, Result};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use reqwest::{header, StatusCode};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use std::sync::Mutex;
+
+const SIGNATURE_HEADER: &str = "x-signature";
+const NAMESPACE: &str = "remote_config";
+/// Version of the last document applied.
+const VERSION_KEY: &str = "version";
+
+pub struct Poller {
+    url: String,
+    key: Vec<u8>,
+    nvs: EspDefaultNvsPartition,
+    client: reqwest::Client,
+    /// ETag of the last document applied, so unchanged ones aren't sent again.
+    etag: Mutex<Option<String>>,
+}
+
+impl Poller {
+    pub fn new(config: &Config, nvs: EspDefaultNvsPartition) -> Result<Self> {
+        let key = hex(&config.config_key).context("config_key isn't hex")?;
+        if key.len() != 32 {
+            bail!("config_key has to be a 32 byte Ed25519 public key");
+        }
+        Ok(Self {
+            url: config.config_url.clone(),
+            key,
+            nvs,
+            client: crate::http::client()?,
+            etag: Mutex::new(None),
+        })
+    }
+
+    pub async fn poll(&self) -> Result<()> {
+        events::wait_until(|state| state.net_up && state.time_synced).await;
+
+        let mut request = self.client.get(&self.url);
+        if let Some(etag) = self.etag.lock().unwrap().clone() {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        crate::ratelimit::acquire("remote-config").await?;
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            log::debug!("remote config unchanged");
+            return Ok(());
+        }
+        let response = response.error_for_status()?;
+
+        let etag = response
+            .headers()
+            .get(header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(String::from);
+        let signature = response
+            .headers()
+            .get(SIGNATURE_HEADER)
+            .context("remote config isn't signed")?
+            .to_str()
+            .ok()
+            .and_then(hex)
+            .context("malformed remote config signature")?;
+        let body = response.bytes().await?;
+
+        UnparsedPublicKey::new(&ED25519, &self.key)
+            .verify(&body, &signature)
+            .map_err(|_| anyhow::anyhow!("bad remote config signature"))?;
+        let mut changes: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_slice(&body).context("remote config isn't a JSON object")?;
+        let version = changes
+            .remove("version")
+            .and_then(|version| version.as_u64())
+            .context("remote config has no version")?;
+
+        let nvs = self.nvs.clone();
+        let applied =
+            runtime::run_blocking(move || -> Result<_> { Ok(open(nvs)?.get_u64(VERSION_KEY)?) })
+                .await??;
+        match applied {
+            Some(applied) if version == applied => {
+                log::debug!("remote config {version} already applied");
+                *self.etag.lock().unwrap() = etag;
+                return Ok(());
+            }
+            Some(applied) if version < applied => {
+                bail!("remote config {version} is older than {applied}, the one applied")
+            }
+            _ => {}
+        }
+
+        let nvs = self.nvs.clone();
+        let changed = runtime::run_blocking(move || -> Result<_> {
+            let changed = Config::apply_remote(nvs.clone(), &changes)?;
+            open(nvs)?.set_u64(VERSION_KEY, version)?;
+            Ok(changed)
+        })
+        .await??;
+        *self.etag.lock().unwrap() = etag.clone();
+        telemetry::set("remote_config", etag.unwrap_or_default());
+
+        if changed.is_empty() {
+            log::info!("remote config {version} applied, nothing changed");
+        } else {
+            // running services keep the config they started with, the
+            // subscribers decide what needs a restart
+            log::info!("remote config {version} changed {}", changed.join(", "));
+            events::publish(Event::ConfigChanged);
+        }
+        Ok(())
+    }
+}
+
+fn open(partition: EspDefaultNvsPartition) -> Result<EspNvs<NvsDefault>> {
+    EspNvs::new(partition, NAMESPACE, true).context("couldn't open remote config nvs")
+}
+
+fn hex(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim();
+    // an odd length runs past the end on the last pair
+    (0..text.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(text.get(at..at + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,160 @@
+//! The last good body of every poll, kept on flash for when there's no
+//! network. While the device is offline a poll replays it once through its
+//! pipeline, so the display and the other sinks have something to show
+//! right after a boot without WiFi, and `GET /api/last` serves it too.
+//! Both say it's stale and how old: the display puts its age in front of
+//! the line, the server sends `Age` and `X-Stale`.
+//!
+//! A body is written out only when it changed, polls that keep returning
+//! the same one cost no flash wear.
+
+use crate::{
+    events, fs,
+    http::{Consumer, Head},
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{sync::Mutex, time::Duration};
+
+/// Largest body kept, larger ones are polled as usual but not replayed.
+const MAX_KEPT: usize = 16 * 1024;
+
+/// What's known of a kept body, the first line of its file.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Kept {
+    pub url: String,
+    pub content_type: Option<String>,
+    /// Unix seconds, `None` when the clock wasn't set.
+    pub fetched: Option<i64>,
+}
+
+/// The polls kept this boot or found on flash, in the order first seen.
+static KEPT: Mutex<Vec<Kept>> = Mutex::new(Vec::new());
+
+/// Collects a body to keep, giving up past `MAX_KEPT` or when its head
+/// wasn't a 2xx.
+#[derive(Default)]
+pub struct Collect {
+    body: Vec<u8>,
+    too_large: bool,
+    /// `None` from a fetcher without heads, CoAP, which fails on an error.
+    status: Option<u16>,
+}
+
+impl Consumer for Collect {
+    fn head(&mut self, head: &Head) -> Result<()> {
+        self.status = Some(head.status);
+        Ok(())
+    }
+
+    fn chunk(&mut self, chunk: &[u8]) -> Result<()> {
+        if self.body.len() + chunk.len() > MAX_KEPT {
+            self.too_large = true;
+            self.body = Vec::new();
+        } else if !self.too_large {
+            self.body.extend_from_slice(chunk);
+        }
+        Ok(())
+    }
+}
+
+impl Collect {
+    pub fn into_body(self) -> Option<Vec<u8>> {
+        // an error page is no good body to show offline
+        let good = self
+            .status
+            .map_or(true, |status| (200..300).contains(&status));
+        (!self.too_large && good).then_some(self.body)
+    }
+}
+
+/// For file names, urls can hold anything.
+fn file(url: &str) -> String {
+    let hash = url.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("last-{hash:016x}")
+}
+
+fn now() -> Option<i64> {
+    events::state()
+        .time_synced
+        .then(|| time::UtcDateTime::now().unix_timestamp())
+}
+
+/// How long ago something fetched at unix time `fetched` was, `None`
+/// without a clock to tell.
+pub fn age(fetched: Option<i64>) -> Option<Duration> {
+    Some(Duration::from_secs(
+        now()?.saturating_sub(fetched?).max(0) as u64
+    ))
+}
+
+/// Replaces what's kept of `url` with `body`.
+pub async fn keep(url: &str, content_type: Option<String>, body: Vec<u8>) -> Result<()> {
+    let kept = Kept {
+        url: url.to_owned(),
+        content_type,
+        fetched: now(),
+    };
+    let mut data = serde_json::to_vec(&kept)?;
+    data.push(b'\n');
+    data.extend_from_slice(&body);
+    fs::write(&file(url), data).await?;
+    remember(kept);
+    Ok(())
+}
+
+fn remember(kept: Kept) {
+    let mut all = KEPT.lock().unwrap();
+    match all.iter_mut().find(|known| known.url == kept.url) {
+        Some(known) => *known = kept,
+        None => all.push(kept),
+    }
+}
+
+fn split(data: &[u8]) -> Result<(Kept, &[u8])> {
+    let end = data
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .context("kept body without its header")?;
+    let kept = serde_json::from_slice(&data[..end]).context("unreadable kept body header")?;
+    Ok((kept, &data[end + 1..]))
+}
+
+/// Runs what's kept of `url` through `consumer`, with an `Age` header when
+/// it's known; `false` when there's nothing.
+pub async fn replay(url: &str, consumer: &mut impl Consumer) -> Result<bool> {
+    let Ok(data) = fs::read(&file(url)).await else {
+        return Ok(false);
+    };
+    let (kept, body) = split(&data)?;
+    let age = age(kept.fetched).map(|age| age.as_secs().to_string());
+    consumer.head(&Head::new(
+        200,
+        age.as_deref().map(|age| ("age", age)).into_iter().chain(
+            kept.content_type
+                .as_deref()
+                .map(|content_type| ("content-type", content_type)),
+        ),
+    ))?;
+    consumer.chunk(body)?;
+    consumer.finish()?;
+    #[cfg(feature = "display")]
+    crate::display::set_stale(true, kept.fetched);
+    remember(kept);
+    Ok(true)
+}
+
+/// What's kept, for listing.
+pub fn kept() -> Vec<Kept> {
+    KEPT.lock().unwrap().clone()
+}
+
+/// The kept body of `url` as it is on flash, for the server's thread.
+pub fn read(url: &str) -> Result<(Kept, Vec<u8>)> {
+    let data =
+        std::fs::read(fs::path(&file(url))).with_context(|| format!("nothing kept of {url}"))?;
+    let (kept, body) = split(&data)?;
+    Ok((kept, body.to_vec()))
+}
@@ -0,0 +1,105 @@
+//! DS3231 battery-backed clock on the peripheral bus. It seeds the system
+//! time at boot, before SNTP gets a chance, and takes the SNTP time back
+//! after each sync, so the clock is right on networks that block NTP.
+
+use crate::i2c;
+use anyhow::{bail, Context, Result};
+use esp_idf_hal::delay::BLOCK;
+use time::{Date, Month, OffsetDateTime, PrimitiveDateTime, Time};
+
+pub const ADDRESS: u8 = 0x68;
+const TIME_REGISTER: u8 = 0x00;
+const STATUS_REGISTER: u8 = 0x0f;
+/// Oscillator stop flag: the clock lost power and its time is invalid.
+const STATUS_OSF: u8 = 0x80;
+/// Century bit in the month register, the year register only holds 00-99.
+const CENTURY: u8 = 0x80;
+
+/// Sets the system time from the RTC; false when there's no RTC or its
+/// time isn't valid.
+pub async fn seed() -> bool {
+    match read().await {
+        Ok(now) => {
+            let tv = esp_idf_sys::timeval {
+                tv_sec: now.unix_timestamp() as _,
+                tv_usec: 0,
+            };
+            if unsafe { esp_idf_sys::settimeofday(&tv, std::ptr::null()) } != 0 {
+                log::warn!("rtc: couldn't set the system time");
+                return false;
+            }
+            log::info!("time from rtc: {}", crate::clock::format_time());
+            true
+        }
+        Err(err) => {
+            log::warn!("rtc: {err:#}");
+            false
+        }
+    }
+}
+
+/// Writes the system time to the RTC, after SNTP set it.
+pub fn store() {
+    if let Err(err) = write(OffsetDateTime::now_utc()) {
+        log::warn!("rtc: couldn't store the time: {err:#}");
+    }
+}
+
+async fn read() -> Result<OffsetDateTime> {
+    let (status, raw) = {
+        let mut bus = i2c::lock().await?;
+        let mut status = [0];
+        bus.write_read(ADDRESS, &[STATUS_REGISTER], &mut status, BLOCK)
+            .context("no ds3231 on the i2c bus")?;
+        let mut raw = [0; 7];
+        bus.write_read(ADDRESS, &[TIME_REGISTER], &mut raw, BLOCK)?;
+        (status[0], raw)
+    };
+    if status & STATUS_OSF != 0 {
+        bail!("clock stopped at some point, its time isn't valid");
+    }
+
+    let year = 2000 + i32::from(bcd(raw[6])) + if raw[5] & CENTURY != 0 { 100 } else { 0 };
+    let date = Date::from_calendar_date(
+        year,
+        Month::try_from(bcd(raw[5] & !CENTURY))?,
+        bcd(raw[4] & 0x3f),
+    )?;
+    // always written in 24 hour mode
+    let time = Time::from_hms(bcd(raw[2] & 0x3f), bcd(raw[1] & 0x7f), bcd(raw[0] & 0x7f))?;
+    Ok(PrimitiveDateTime::new(date, time).assume_utc())
+}
+
+fn write(now: OffsetDateTime) -> Result<()> {
+    let year = now.year() - 2000;
+    if !(0..200).contains(&year) {
+        bail!("year {} out of the ds3231's range", now.year());
+    }
+    let century = if year >= 100 { CENTURY } else { 0 };
+    let raw = [
+        TIME_REGISTER,
+        to_bcd(now.second()),
+        to_bcd(now.minute()),
+        to_bcd(now.hour()),
+        now.weekday().number_from_monday(),
+        to_bcd(now.day()),
+        to_bcd(u8::from(now.month())) | century,
+        to_bcd((year % 100) as u8),
+    ];
+    i2c::with(|bus| {
+        bus.write(ADDRESS, &raw, BLOCK)?;
+        // the time is valid again
+        bus.write(ADDRESS, &[STATUS_REGISTER, 0], BLOCK)?;
+        Ok(())
+    })?;
+    log::info!("time stored in rtc");
+    Ok(())
+}
+
+fn bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
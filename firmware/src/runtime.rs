@@ -0,0 +1,167 @@
+use anyhow::{anyhow, Context, Result};
+use esp_idf_hal::{cpu::Core, task::thread::ThreadSpawnConfiguration};
+#[cfg(feature = "tokio-rt")]
+use std::sync::OnceLock;
+use std::{future::Future, time::Duration};
+
+mod blocking;
+mod tasks;
+
+pub use blocking::{run_blocking, wait_blocking};
+pub use tasks::{log_tasks, spawn_named, tasks, TaskState};
+
+#[cfg(all(feature = "tokio-rt", feature = "no-tokio"))]
+compile_error!("features `tokio-rt` and `no-tokio` are mutually exclusive");
+#[cfg(not(any(feature = "tokio-rt", feature = "no-tokio")))]
+compile_error!("one of the `tokio-rt` or `no-tokio` features is required");
+
+/// The runtime `block_on()` runs, so the console threads can spawn on it.
+#[cfg(feature = "tokio-rt")]
+static HANDLE: OnceLock<tokio::runtime::Handle> = OnceLock::new();
+#[cfg(feature = "no-tokio")]
+static EXECUTOR: edge_executor::Executor<'static> = edge_executor::Executor::new();
+
+#[derive(Clone, Copy, Debug)]
+pub struct RuntimeConfig {
+    /// Eventfds to register with the VFS; tokio needs one per runtime driver.
+    pub eventfd_max_fds: usize,
+    /// Stack of the thread running the async main loop. rustls handshakes
+    /// run on this stack, so the sdkconfig main task default is too small.
+    pub main_stack_size: usize,
+    /// Default stack for all other pthreads, including tokio's blocking pool.
+    pub pthread_stack_size: usize,
+    /// Worker threads backing `run_blocking()`.
+    pub blocking_threads: usize,
+    /// Stack of each `run_blocking()` worker; mbedtls and flash calls are
+    /// stack hungry.
+    pub blocking_stack_size: usize,
+    /// Core the async main thread is pinned to, any core when `None`.
+    pub main_core: Option<Core>,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            eventfd_max_fds: 2,
+            main_stack_size: 48 * 1024,
+            pthread_stack_size: 16 * 1024,
+            blocking_threads: 1,
+            blocking_stack_size: 8 * 1024,
+            main_core: crate::chip::runtime_core(),
+        }
+    }
+}
+
+pub struct Runtime {
+    config: RuntimeConfig,
+}
+
+pub fn init(config: RuntimeConfig) -> Result<Runtime> {
+    let eventfd_config = esp_idf_sys::esp_vfs_eventfd_config_t {
+        max_fds: config.eventfd_max_fds,
+        ..Default::default()
+    };
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_vfs_eventfd_register(&eventfd_config) })
+        .context("couldn't register eventfd")?;
+
+    set_pthread_stack_size(config.pthread_stack_size)?;
+    blocking::start(config.blocking_threads, config.blocking_stack_size)?;
+
+    log::info!("runtime initialized: {:?}", config);
+
+    Ok(Runtime { config })
+}
+
+impl Runtime {
+    /// Runs the future returned by `main` to completion on a dedicated thread
+    /// sized by `main_stack_size`.
+    #[cfg(feature = "tokio-rt")]
+    pub fn block_on<F, Fut>(self, main: F) -> Result<Fut::Output>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future,
+        Fut::Output: Send + 'static,
+    {
+        let config = self.config;
+
+        self.spawn_main(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .thread_stack_size(config.pthread_stack_size)
+                .build()?;
+            let _ = HANDLE.set(runtime.handle().clone());
+            Ok(runtime.block_on(main()))
+        })
+    }
+
+    /// Runs the future returned by `main` to completion on a dedicated thread
+    /// sized by `main_stack_size`.
+    #[cfg(feature = "no-tokio")]
+    pub fn block_on<F, Fut>(self, main: F) -> Result<Fut::Output>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        self.spawn_main(move || Ok(edge_executor::block_on(EXECUTOR.run(main()))))
+    }
+
+    fn spawn_main<T: Send + 'static>(
+        self,
+        run: impl FnOnce() -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let config = self.config;
+
+        // pinned while spawning this thread only, the rest run on any core
+        set_spawn_configuration(config.pthread_stack_size, config.main_core)?;
+        let main = std::thread::Builder::new()
+            .name("async-main".into())
+            .stack_size(config.main_stack_size)
+            .spawn(move || {
+                // pthread spawn configuration is per-thread, so apply it again
+                // for the threads this one creates
+                set_pthread_stack_size(config.pthread_stack_size)?;
+                run()
+            });
+        set_pthread_stack_size(config.pthread_stack_size)?;
+
+        main.context("couldn't spawn async main thread")?
+            .join()
+            .map_err(|_| anyhow!("async main thread panicked"))?
+    }
+}
+
+pub fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    #[cfg(feature = "tokio-rt")]
+    match HANDLE.get() {
+        Some(runtime) => drop(runtime.spawn(future)),
+        None => drop(tokio::spawn(future)),
+    }
+    #[cfg(feature = "no-tokio")]
+    EXECUTOR.spawn(future).detach();
+}
+
+pub async fn sleep(duration: Duration) {
+    #[cfg(feature = "tokio-rt")]
+    tokio::time::sleep(duration).await;
+    #[cfg(feature = "no-tokio")]
+    async_io::Timer::after(duration).await;
+}
+
+fn set_pthread_stack_size(stack_size: usize) -> Result<()> {
+    set_spawn_configuration(stack_size, None)
+}
+
+fn set_spawn_configuration(stack_size: usize, pin_to_core: Option<Core>) -> Result<()> {
+    ThreadSpawnConfiguration {
+        stack_size,
+        inherit: true,
+        pin_to_core,
+        ..Default::default()
+    }
+    .set()
+    .context("couldn't set pthread spawn configuration")
+}
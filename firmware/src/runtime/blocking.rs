@@ -0,0 +1,69 @@
+use anyhow::{anyhow, Context, Result};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+static POOL: OnceLock<mpsc::Sender<Job>> = OnceLock::new();
+
+/// Starts the worker threads backing `run_blocking()`.
+pub(super) fn start(threads: usize, stack_size: usize) -> Result<()> {
+    let (tx, rx) = mpsc::channel::<Job>();
+    let rx = Arc::new(Mutex::new(rx));
+
+    for index in 0..threads {
+        let rx = rx.clone();
+        std::thread::Builder::new()
+            .name(format!("blocking-{index}"))
+            .stack_size(stack_size)
+            .spawn(move || loop {
+                let job = rx.lock().unwrap().recv();
+                match job {
+                    Ok(job) => job(),
+                    Err(_) => break,
+                }
+            })
+            .context("couldn't spawn blocking pool thread")?;
+    }
+
+    POOL.set(tx)
+        .map_err(|_| anyhow!("blocking pool already started"))
+}
+
+/// Runs `f` on the blocking pool so NVS commits, flash writes and other
+/// long synchronous calls don't stall the reactor.
+pub async fn run_blocking<F, T>(f: F) -> Result<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let pool = POOL.get().context("blocking pool not started")?;
+    let (tx, rx) = oneshot::channel();
+
+    pool.send(Box::new(move || {
+        let _ = tx.send(f());
+    }))
+    .map_err(|_| anyhow!("blocking pool stopped"))?;
+
+    rx.await.context("blocking job panicked")
+}
+
+/// `run_blocking()` for a caller that can't await, like rustls's signer
+/// inside a handshake. The caller still waits for `f`, but `f` runs on the
+/// pool, in turn with the other bus and flash work there. Never call it
+/// from the pool itself, a single worker would wait on its own job.
+pub fn wait_blocking<F, T>(f: F) -> Result<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let pool = POOL.get().context("blocking pool not started")?;
+    let (tx, rx) = mpsc::sync_channel(1);
+
+    pool.send(Box::new(move || {
+        let _ = tx.send(f());
+    }))
+    .map_err(|_| anyhow!("blocking pool stopped"))?;
+
+    rx.recv().context("blocking job panicked")
+}
@@ -0,0 +1,90 @@
+use anyhow::Result;
+use std::{
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+static TASKS: Mutex<Vec<TaskInfo>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskState {
+    Running,
+    Restarting,
+    Finished,
+}
+
+#[derive(Clone, Debug)]
+pub struct TaskInfo {
+    pub name: &'static str,
+    pub spawned_at: Instant,
+    pub state: TaskState,
+    pub restarts: u32,
+    pub last_error: Option<String>,
+}
+
+/// Spawns a task registered by `name` in the task table. Whenever the
+/// future returned by `task` fails it is recorded and restarted after a delay;
+/// a task returning `Ok` is marked finished.
+pub fn spawn_named<F, Fut>(name: &'static str, mut task: F)
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let index = {
+        let mut tasks = TASKS.lock().unwrap();
+        tasks.push(TaskInfo {
+            name,
+            spawned_at: Instant::now(),
+            state: TaskState::Running,
+            restarts: 0,
+            last_error: None,
+        });
+        tasks.len() - 1
+    };
+
+    super::spawn(async move {
+        loop {
+            match task().await {
+                Ok(()) => {
+                    update(index, |info| info.state = TaskState::Finished);
+                    break;
+                }
+                Err(err) => {
+                    log::warn!("task {name} failed, restarting: {err:#}");
+                    update(index, |info| {
+                        info.state = TaskState::Restarting;
+                        info.restarts += 1;
+                        info.last_error = Some(format!("{err:#}"));
+                    });
+                }
+            }
+
+            super::sleep(RESTART_DELAY).await;
+            update(index, |info| info.state = TaskState::Running);
+        }
+    });
+}
+
+pub fn tasks() -> Vec<TaskInfo> {
+    TASKS.lock().unwrap().clone()
+}
+
+pub fn log_tasks() {
+    for task in tasks() {
+        log::info!(
+            "task {}: {:?}, up {}s, {} restarts, last error: {}",
+            task.name,
+            task.state,
+            task.spawned_at.elapsed().as_secs(),
+            task.restarts,
+            task.last_error.as_deref().unwrap_or("none")
+        );
+    }
+}
+
+fn update(index: usize, f: impl FnOnce(&mut TaskInfo)) {
+    f(&mut TASKS.lock().unwrap()[index]);
+}
@@ -0,0 +1,52 @@
+use serde::{Serialize, Serializer};
+use std::fmt;
+use zeroize::Zeroize;
+
+/// A password, token or key: `Debug` prints it redacted, and the memory is
+/// wiped when it's dropped so it can't turn up in a core dump later. The
+/// value only comes out through `expose()`, which makes every use easy to
+/// find.
+///
+/// It serializes as the value itself, `Config::redacted()` is what masks
+/// the secret fields in anything logged or served.
+#[derive(Clone, Default)]
+pub struct Secret<T: Zeroize>(T);
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+
+    /// For filling the value in place, so no copy is left behind.
+    pub fn expose_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<T: Zeroize> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl<T: Zeroize + Serialize> Serialize for Secret<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+/// Compares a given password or token with the secret one in time that
+/// doesn't depend on where they differ.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
@@ -0,0 +1,89 @@
+//! Whether the flash can keep a secret. Flash encryption covers the app
+//! and the partitions marked encrypted; NVS isn't one of them, it's only
+//! encrypted with `CONFIG_NVS_ENCRYPTION` and keys that are themselves
+//! protected, by flash encryption or an HMAC eFuse key.
+//!
+//! A release build refuses to write keys and passwords into NVS that isn't
+//! encrypted; debug builds store them anyway, with a warning.
+
+use crate::config::Config;
+use anyhow::{bail, Result};
+use esp_idf_sys::security;
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlashEncryption {
+    Disabled,
+    /// Reflashing plaintext over UART still works.
+    Development,
+    Release,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Status {
+    pub flash_encryption: FlashEncryption,
+    pub secure_boot: bool,
+    pub nvs_encryption: bool,
+    /// Whether `ensure_secret_storage()` lets secrets into NVS.
+    pub secrets_storable: bool,
+}
+
+pub fn status() -> Status {
+    Status {
+        flash_encryption: flash_encryption(),
+        secure_boot: unsafe { security::esp_secure_boot_enabled() },
+        nvs_encryption: nvs_encrypted(),
+        secrets_storable: cfg!(debug_assertions) || nvs_encrypted(),
+    }
+}
+
+pub fn flash_encryption() -> FlashEncryption {
+    if !unsafe { security::esp_flash_encryption_enabled() } {
+        return FlashEncryption::Disabled;
+    }
+    match unsafe { security::esp_get_flash_encryption_mode() } {
+        security::esp_flash_enc_mode_t_ESP_FLASH_ENC_MODE_RELEASE => FlashEncryption::Release,
+        _ => FlashEncryption::Development,
+    }
+}
+
+/// NVS encryption with its keys out of reach: the keys partition is only
+/// safe under flash encryption, unless an HMAC eFuse key derives them.
+fn nvs_encrypted() -> bool {
+    cfg!(esp_idf_nvs_encryption)
+        && (cfg!(esp_idf_nvs_sec_key_protect_using_hmac)
+            || flash_encryption() != FlashEncryption::Disabled)
+}
+
+/// Errors in a release build when a secret written to NVS now would sit
+/// there in plaintext.
+pub fn ensure_secret_storage() -> Result<()> {
+    if nvs_encrypted() {
+        return Ok(());
+    }
+    if cfg!(debug_assertions) {
+        log::warn!("storing a secret in plaintext nvs, a release build would refuse");
+        return Ok(());
+    }
+    bail!("nvs isn't encrypted, a release build won't store secrets in it")
+}
+
+/// Logs the state and what it leaves exposed.
+pub fn start(config: &Config) {
+    let status = status();
+    log::info!(
+        "flash encryption {:?}, secure boot {}, nvs encryption {}",
+        status.flash_encryption,
+        status.secure_boot,
+        status.nvs_encryption
+    );
+    if status.flash_encryption == FlashEncryption::Disabled && !cfg!(debug_assertions) {
+        log::warn!(
+            "flash encryption is off, the compiled in wifi password is readable off the flash"
+        );
+    }
+    if !status.nvs_encryption && config.has_secrets() {
+        log::warn!("secrets are stored in plaintext nvs, set them again once it's encrypted");
+    }
+}
@@ -0,0 +1,207 @@
+//! The end-of-line test manufacturing runs on every board: NVS written and
+//! read back, the I2C parts this build expects, a WiFi scan, a DNS lookup,
+//! a TLS handshake with a well-known host and the clock from SNTP. Each
+//! check passes, fails or is skipped when the build or board doesn't have
+//! it, and the board passes when nothing failed.
+//!
+//! `selftest` on the console or as a plain MQTT command logs the report and
+//! puts it in the `selftest` telemetry field; `{"command": "selftest"}` on
+//! MQTT also answers with it.
+
+use crate::{
+    console,
+    events::{self, Event},
+    runtime, telemetry,
+};
+use anyhow::{bail, ensure, Context, Result};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use serde_json::{json, Map, Value};
+use std::{future::Future, sync::OnceLock, time::Duration};
+
+const COMMAND: &str = "selftest";
+/// Its own namespace, so the test can never touch real settings.
+const NVS_NAMESPACE: &str = "selftest";
+/// Answers on every network the boards ship to.
+const KNOWN_HOST: &str = "www.google.com";
+/// Bounds each check, a hung one fails rather than stalls the line.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(20);
+
+static NVS: OnceLock<EspDefaultNvsPartition> = OnceLock::new();
+
+enum Outcome {
+    Pass(String),
+    Fail(String),
+    Skip(&'static str),
+}
+
+pub fn start(nvs: &EspDefaultNvsPartition) {
+    let _ = NVS.set(nvs.clone());
+    console::register(console::Command {
+        name: COMMAND,
+        usage: "selftest",
+        summary: "end-of-line test of nvs, i2c, wifi, dns, tls and sntp",
+        run: |_, _| {
+            events::publish(Event::Command(String::from(COMMAND)));
+            Ok(String::from("self-test started, the report is logged"))
+        },
+    });
+
+    runtime::spawn(async {
+        let mut events = events::subscribe();
+        loop {
+            let Ok(Event::Command(command)) = events.recv().await else {
+                continue;
+            };
+            if command == COMMAND {
+                run().await;
+            }
+        }
+    });
+}
+
+/// Runs every check and reports, `passed` false if any failed.
+pub async fn run() -> Value {
+    let checks = [
+        ("nvs", check(nvs()).await),
+        ("i2c", check(i2c()).await),
+        ("wifi", check(wifi()).await),
+        ("dns", check(dns()).await),
+        ("tls", check(tls()).await),
+        ("sntp", check(sntp()).await),
+    ];
+
+    let passed = !checks
+        .iter()
+        .any(|(_, outcome)| matches!(outcome, Outcome::Fail(_)));
+    let mut report = Map::new();
+    for (name, outcome) in checks {
+        let (line, value) = match outcome {
+            Outcome::Pass(detail) => (format!("PASS {name}: {detail}"), json!("pass")),
+            Outcome::Fail(error) => (
+                format!("FAIL {name}: {error}"),
+                json!(format!("fail: {error}")),
+            ),
+            Outcome::Skip(reason) => (format!("SKIP {name}: {reason}"), json!("skipped")),
+        };
+        log::info!("selftest {line}");
+        report.insert(name.into(), value);
+    }
+    log::info!("selftest {}", if passed { "PASSED" } else { "FAILED" });
+
+    let summary = json!({ "passed": passed, "checks": report });
+    telemetry::set("selftest", summary.clone());
+    summary
+}
+
+/// A check fails with `Err`, and is skipped with `Ok(Err(reason))`.
+async fn check(test: impl Future<Output = Result<Result<String, &'static str>>>) -> Outcome {
+    match tokio::time::timeout(CHECK_TIMEOUT, test).await {
+        Ok(Ok(Ok(detail))) => Outcome::Pass(detail),
+        Ok(Ok(Err(reason))) => Outcome::Skip(reason),
+        Ok(Err(err)) => Outcome::Fail(format!("{err:#}")),
+        Err(_) => Outcome::Fail(format!("timed out after {CHECK_TIMEOUT:?}")),
+    }
+}
+
+async fn nvs() -> Result<Result<String, &'static str>> {
+    let partition = NVS.get().cloned().context("self-test not started")?;
+    runtime::run_blocking(move || {
+        let mut nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+        let written = crate::device::random();
+        nvs.set_u32("probe", written)?;
+        let read = nvs.get_u32("probe")?;
+        nvs.remove("probe")?;
+        ensure!(read == Some(written), "wrote {written}, read back {read:?}");
+        Ok(Ok(String::from("written and read back")))
+    })
+    .await?
+}
+
+async fn i2c() -> Result<Result<String, &'static str>> {
+    #[cfg(any(feature = "sensors", feature = "rtc", feature = "atecc608"))]
+    {
+        // the ATECC608 sleeps between commands and won't answer a probe
+        let found = runtime::run_blocking(crate::i2c::devices).await??;
+        // nothing pushes in an atecc608-only build
+        #[allow(unused_mut)]
+        let mut missing: Vec<&str> = Vec::new();
+        #[cfg(feature = "rtc")]
+        if !found.contains(&crate::rtc::ADDRESS) {
+            missing.push("DS3231");
+        }
+        #[cfg(feature = "sensors")]
+        if !crate::sensors::BME280_ADDRESSES
+            .iter()
+            .chain(&crate::sensors::SHT3X_ADDRESSES)
+            .any(|address| found.contains(address))
+        {
+            missing.push("BME280 or SHT3x");
+        }
+        ensure!(missing.is_empty(), "missing {}", missing.join(", "));
+        Ok(Ok(format!("{} device(s) answered", found.len())))
+    }
+    #[cfg(not(any(feature = "sensors", feature = "rtc", feature = "atecc608")))]
+    Ok(Err("no i2c parts in this build"))
+}
+
+async fn wifi() -> Result<Result<String, &'static str>> {
+    #[cfg(feature = "wifi")]
+    {
+        let records = runtime::run_blocking(|| crate::net::scan(8)).await??;
+        ensure!(!records.is_empty(), "no access point in range");
+        let strongest = records
+            .iter()
+            .map(|record| record.rssi)
+            .max()
+            .unwrap_or_default();
+        Ok(Ok(format!(
+            "{} access point(s), strongest {strongest} dBm",
+            records.len()
+        )))
+    }
+    #[cfg(not(feature = "wifi"))]
+    Ok(Err("no wifi in this build"))
+}
+
+async fn dns() -> Result<Result<String, &'static str>> {
+    if !events::state().net_up {
+        bail!("network not up");
+    }
+    let ips = crate::dns::resolve(KNOWN_HOST).await?;
+    ensure!(!ips.is_empty(), "{KNOWN_HOST} has no address");
+    Ok(Ok(format!("{KNOWN_HOST} is {}", ips[0])))
+}
+
+async fn tls() -> Result<Result<String, &'static str>> {
+    if !events::state().time_synced {
+        bail!("no clock to verify the certificate with");
+    }
+    let connector = tokio_rustls::TlsConnector::from(crate::tls::client_config());
+    let started = std::time::Instant::now();
+    let stream = crate::net::socks::connect_tls(KNOWN_HOST, 443, &connector, "selftest").await?;
+    let version = stream
+        .get_ref()
+        .1
+        .protocol_version()
+        .map_or_else(|| String::from("?"), |version| format!("{version:?}"));
+    Ok(Ok(format!(
+        "{KNOWN_HOST} in {} ms over {version}",
+        started.elapsed().as_millis()
+    )))
+}
+
+async fn sntp() -> Result<Result<String, &'static str>> {
+    #[cfg(feature = "sntp")]
+    {
+        // boot's sync owns the SNTP client, the check waits on it
+        let synced = tokio::time::timeout(
+            CHECK_TIMEOUT / 2,
+            events::wait_until(|state| state.time_synced),
+        )
+        .await;
+        ensure!(synced.is_ok(), "clock not synced");
+        Ok(Ok(crate::clock::format_time()))
+    }
+    #[cfg(not(feature = "sntp"))]
+    Ok(Err("no sntp in this build"))
+}
@@ -0,0 +1,166 @@
+//! Environment sensors on the peripheral bus: a BME280 (temperature,
+//! humidity, pressure) or an SHT3x (temperature, humidity), whichever
+//! answers first. Readings land in telemetry, which the UDP uploader and
+//! the MQTT telemetry job send along.
+
+use crate::{bus, i2c, telemetry};
+use anyhow::{anyhow, bail, Context, Result};
+use bme280::i2c::BME280;
+use esp_idf_hal::{
+    delay::{Delay, BLOCK},
+    i2c::I2cDriver,
+};
+use std::time::Duration;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+const STACK_SIZE: usize = 4096;
+
+pub const BME280_ADDRESSES: [u8; 2] = [0x76, 0x77];
+const BME280_CHIP_ID_REGISTER: u8 = 0xd0;
+const BME280_CHIP_ID: u8 = 0x60;
+pub const SHT3X_ADDRESSES: [u8; 2] = [0x44, 0x45];
+const SHT3X_READ_STATUS: [u8; 2] = [0xf3, 0x2d];
+/// Single shot, high repeatability, no clock stretching.
+const SHT3X_MEASURE: [u8; 2] = [0x24, 0x00];
+const SHT3X_MEASURE_TIME: Duration = Duration::from_millis(16);
+
+#[derive(Clone, Copy, Debug)]
+enum Sensor {
+    Bme280 { address: u8 },
+    Sht3x { address: u8 },
+}
+
+/// A sensor ready to sample.
+enum Reader {
+    Bme280(BME280<bus::Device<I2cDriver<'static>>>),
+    Sht3x { address: u8 },
+}
+
+#[derive(Debug)]
+struct Reading {
+    temperature_c: f32,
+    humidity_pct: f32,
+    pressure_hpa: Option<f32>,
+}
+
+/// Samples the first sensor found every `SAMPLE_INTERVAL` on its own
+/// thread, into telemetry as `temperature_c`, `humidity_pct` and, from a
+/// BME280, `pressure_hpa`.
+pub fn start() -> Result<()> {
+    std::thread::Builder::new()
+        .name("sensors".into())
+        .stack_size(STACK_SIZE)
+        .spawn(|| {
+            if let Err(err) = run() {
+                log::error!("sensors stopped: {err:#}");
+            }
+        })
+        .context("couldn't spawn sensors")?;
+    Ok(())
+}
+
+fn run() -> Result<()> {
+    let Some(sensor) = i2c::with(|i2c| Ok(detect(i2c)))? else {
+        log::warn!("no BME280 or SHT3x on the i2c bus");
+        return Ok(());
+    };
+    log::info!("sensor {sensor:?}");
+    let mut reader = open(sensor)?;
+
+    loop {
+        match read(&mut reader) {
+            Ok(reading) => {
+                log::debug!("{reading:?}");
+                telemetry::set("temperature_c", reading.temperature_c);
+                telemetry::set("humidity_pct", reading.humidity_pct);
+                if let Some(pressure) = reading.pressure_hpa {
+                    telemetry::set("pressure_hpa", pressure);
+                }
+            }
+            Err(err) => log::warn!("sensor read failed: {err:#}"),
+        }
+        std::thread::sleep(SAMPLE_INTERVAL);
+    }
+}
+
+fn detect(i2c: &mut I2cDriver<'static>) -> Option<Sensor> {
+    for address in BME280_ADDRESSES {
+        let mut id = [0];
+        if i2c
+            .write_read(address, &[BME280_CHIP_ID_REGISTER], &mut id, BLOCK)
+            .is_ok()
+            && id[0] == BME280_CHIP_ID
+        {
+            return Some(Sensor::Bme280 { address });
+        }
+    }
+    SHT3X_ADDRESSES
+        .into_iter()
+        .find(|&address| i2c.write(address, &SHT3X_READ_STATUS, BLOCK).is_ok())
+        .map(|address| Sensor::Sht3x { address })
+}
+
+fn open(sensor: Sensor) -> Result<Reader> {
+    match sensor {
+        Sensor::Bme280 { address } => {
+            // a handle of its own on the bus, so the calibration init()
+            // reads stays with the driver
+            let mut bme = if address == BME280_ADDRESSES[0] {
+                BME280::new_primary(i2c::device())
+            } else {
+                BME280::new_secondary(i2c::device())
+            };
+            bme.init(&mut Delay::new_default())
+                .map_err(|err| anyhow!("bme280 init failed: {err:?}"))?;
+            Ok(Reader::Bme280(bme))
+        }
+        Sensor::Sht3x { address } => Ok(Reader::Sht3x { address }),
+    }
+}
+
+fn read(reader: &mut Reader) -> Result<Reading> {
+    match reader {
+        Reader::Bme280(bme) => {
+            let measurements = bme
+                .measure(&mut Delay::new_default())
+                .map_err(|err| anyhow!("bme280 measurement failed: {err:?}"))?;
+            Ok(Reading {
+                temperature_c: measurements.temperature,
+                humidity_pct: measurements.humidity,
+                pressure_hpa: Some(measurements.pressure / 100.0),
+            })
+        }
+        Reader::Sht3x { address } => i2c::with(|i2c| {
+            i2c.write(*address, &SHT3X_MEASURE, BLOCK)?;
+            std::thread::sleep(SHT3X_MEASURE_TIME);
+            let mut data = [0; 6];
+            i2c.read(*address, &mut data, BLOCK)?;
+            let temperature = sht3x_word(&data[0..3])?;
+            let humidity = sht3x_word(&data[3..6])?;
+            Ok(Reading {
+                temperature_c: -45.0 + 175.0 * f32::from(temperature) / 65535.0,
+                humidity_pct: 100.0 * f32::from(humidity) / 65535.0,
+                pressure_hpa: None,
+            })
+        }),
+    }
+}
+
+/// A big-endian word followed by its CRC-8 (polynomial 0x31, init 0xff).
+fn sht3x_word(bytes: &[u8]) -> Result<u16> {
+    let mut crc = 0xffu8;
+    for &byte in &bytes[..2] {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            };
+        }
+    }
+    if crc != bytes[2] {
+        bail!("sht3x crc mismatch");
+    }
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
@@ -0,0 +1,440 @@
+use crate::{
+    chip::CHIP,
+    config::Config,
+    device, dns,
+    events::{self, Event},
+    heap, logtail,
+    metrics::{self, FREE_HEAP, TASK_RESTARTS, TASK_RUNNING, UPTIME, WIFI_RSSI},
+    net, replay, runtime,
+    secret::{constant_time_eq, Secret},
+    telemetry,
+};
+use anyhow::Result;
+use esp_idf_svc::{
+    http::{
+        server::{Configuration, EspHttpConnection, EspHttpServer, Request},
+        Method,
+    },
+    io::{Read, Write},
+};
+use std::{fmt::Write as _, sync::Arc};
+
+mod api;
+
+pub const PORT: u16 = 80;
+
+/// Actions `POST /trigger` takes, the first word of its body.
+const TRIGGERS: &[&str] = &[
+    "fetch",
+    "timesync",
+    "bench",
+    #[cfg(feature = "incident")]
+    "incident",
+];
+const MAX_TRIGGER: usize = 256;
+
+/// How soon the browser comes back for more, in milliseconds. A
+/// `/logs/stream` response is what's new and done, the server answers a
+/// request at a time and an open page mustn't hold it.
+const STREAM_RETRY: u32 = 2000;
+/// `/logs`, the stream in a page, all a browser needs; opened as
+/// `/logs?secret=<trigger_secret>`, it hands its query on.
+const LOG_PAGE: &str = r#"<!DOCTYPE html><html><head><title>logs</title></head><body>
+<pre id="logs"></pre><script>
+const logs = document.getElementById("logs");
+new EventSource("/logs/stream" + location.search).onmessage = (event) => {
+  logs.textContent += event.data + "\n";
+  window.scrollTo(0, document.body.scrollHeight);
+};
+</script></body></html>"#;
+
+/// Starts the status server: a human readable page at `/`, JSON at
+/// `/api/status`, `/api/config`, `/api/dns`, `/api/firmware` and
+/// `/api/inventory`, the bodies polls kept at `/api/last`, Prometheus
+/// metrics at `/metrics`, `/trigger` for test automation and `/logs`, the
+/// log live from `/logs/stream`, when `trigger_secret` is set, and
+/// `/backup` and `/restore` in builds with `backup` when `backup_secret`
+/// is. `/openapi.json` describes the lot, see `api`.
+pub fn start(config: &Config) -> Result<()> {
+    let mut server = EspHttpServer::new(&Configuration {
+        http_port: PORT,
+        ..Default::default()
+    })?;
+
+    server.fn_handler("/", Method::Get, |request| {
+        let status = serde_json::to_string_pretty(&telemetry::snapshot())?;
+        let page = format!(
+            "<!DOCTYPE html><html><head><title>status</title></head><body><pre>{status}</pre></body></html>"
+        );
+        respond(request, "text/html", page.as_bytes())
+    })?;
+
+    server.fn_handler("/api/status", Method::Get, |request| {
+        let status: api::Status = serde_json::from_value(telemetry::snapshot())?;
+        respond_json(request, &status)
+    })?;
+
+    let redacted = Arc::new(config.redacted());
+    server.fn_handler("/api/config", Method::Get, move |request| {
+        respond_json(request, &redacted)
+    })?;
+
+    server.fn_handler("/api/dns", Method::Get, |request| {
+        respond_json(request, &dns::stats())
+    })?;
+
+    server.fn_handler("/api/firmware", Method::Get, |request| {
+        respond_json(request, &device::firmware_info())
+    })?;
+
+    server.fn_handler("/api/inventory", Method::Get, |request| {
+        respond_json(request, &device::inventory())
+    })?;
+
+    server.fn_handler("/api/last", Method::Get, last)?;
+
+    server.fn_handler("/metrics", Method::Get, |request| {
+        sample();
+        respond(
+            request,
+            "text/plain; version=0.0.4",
+            metrics::render().as_bytes(),
+        )
+    })?;
+
+    let document = api::document().to_string();
+    server.fn_handler("/openapi.json", Method::Get, move |request| {
+        respond(request, "application/json", document.as_bytes())
+    })?;
+
+    if !config.trigger_secret.expose().is_empty() {
+        let secret = config.trigger_secret.clone();
+        server.fn_handler("/trigger", Method::Post, move |request| {
+            trigger(request, &secret)
+        })?;
+        let secret = config.trigger_secret.clone();
+        server.fn_handler("/logs", Method::Get, move |request| {
+            if !log_reader(&request, &secret) {
+                return refuse(request, 401, "wrong or missing secret");
+            }
+            respond(request, "text/html", LOG_PAGE.as_bytes())
+        })?;
+        let secret = config.trigger_secret.clone();
+        server.fn_handler("/logs/stream", Method::Get, move |request| {
+            stream_logs(request, &secret)
+        })?;
+    }
+    #[cfg(feature = "backup")]
+    if !config.backup_secret.expose().is_empty() {
+        let secret = config.backup_secret.clone();
+        server.fn_handler("/backup", Method::Post, move |request| {
+            backup(request, &secret)
+        })?;
+        let secret = config.backup_secret.clone();
+        server.fn_handler("/restore", Method::Post, move |request| {
+            restore(request, &secret)
+        })?;
+    }
+
+    log::info!("status server listening on port {PORT}");
+
+    // the server runs for the lifetime of the firmware and all handlers are
+    // 'static, so there is nothing to tear down
+    core::mem::forget(server);
+
+    Ok(())
+}
+
+pub fn respond_json(
+    request: Request<&mut EspHttpConnection<'_>>,
+    value: &impl serde::Serialize,
+) -> Result<()> {
+    respond(request, "application/json", &serde_json::to_vec(value)?)
+}
+
+pub fn respond(
+    request: Request<&mut EspHttpConnection<'_>>,
+    content_type: &str,
+    body: &[u8],
+) -> Result<()> {
+    respond_with(request, 200, content_type, body)
+}
+
+fn respond_with(
+    request: Request<&mut EspHttpConnection<'_>>,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+) -> Result<()> {
+    let path = request.uri().split('?').next().unwrap_or_default();
+    metrics::HTTP_SERVER_REQUESTS.inc(&[("path", path)]);
+    let mut response = request.into_response(status, None, &[("Content-Type", content_type)])?;
+    response.write_all(body)?;
+    Ok(())
+}
+
+/// An `api::Error` with `status`.
+fn refuse(request: Request<&mut EspHttpConnection<'_>>, status: u16, error: &str) -> Result<()> {
+    let body = serde_json::to_vec(&api::Error::new(error))?;
+    respond_with(request, status, "application/json", &body)
+}
+
+/// The polls `replay` kept a body of, or with `?poll=<n>` the `n`th of
+/// them as it was fetched, `Age` and `X-Stale` saying it's no fresh one.
+fn last(request: Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    let kept = replay::kept();
+    let poll = request
+        .uri()
+        .split_once("?poll=")
+        .map(|(_, poll)| poll.parse::<usize>().ok());
+    let Some(poll) = poll else {
+        let list: Vec<_> = kept
+            .iter()
+            .enumerate()
+            .map(|(poll, kept)| api::KeptPoll {
+                poll,
+                url: kept.url.clone(),
+                fetched: kept.fetched,
+                age: replay::age(kept.fetched).map(|age| age.as_secs()),
+            })
+            .collect();
+        return respond_json(request, &list);
+    };
+    let Some(url) = poll.and_then(|poll| kept.get(poll)).map(|kept| &kept.url) else {
+        return refuse(request, 404, "no such poll, see /api/last");
+    };
+    let (kept, body) = replay::read(url)?;
+    let age = replay::age(kept.fetched).map(|age| age.as_secs().to_string());
+    let content_type = kept
+        .content_type
+        .as_deref()
+        .unwrap_or("application/octet-stream");
+    let mut headers = vec![("Content-Type", content_type), ("X-Stale", "1")];
+    if let Some(age) = &age {
+        headers.push(("Age", age));
+    }
+    metrics::HTTP_SERVER_REQUESTS.inc(&[("path", "/api/last")]);
+    let mut response = request.into_response(200, None, &headers)?;
+    response.write_all(&body)?;
+    Ok(())
+}
+
+/// The ring buffer log as Server-Sent Events, a line an event with its
+/// number for an id, those of `?level=` and more severe, all of them by
+/// default. A response has the lines there are and ends, the reconnect
+/// picks up after `Last-Event-ID`; the lines the ring lost in between are
+/// counted.
+fn stream_logs(
+    request: Request<&mut EspHttpConnection<'_>>,
+    secret: &Secret<String>,
+) -> Result<()> {
+    if !log_reader(&request, secret) {
+        log::warn!("log stream refused, wrong secret");
+        return refuse(request, 401, "wrong or missing secret");
+    }
+    let level = match query(request.uri(), "level") {
+        Some(level) => match level.parse::<log::Level>() {
+            Ok(level) => level,
+            Err(_) => {
+                return refuse(
+                    request,
+                    400,
+                    "level is one of error, warn, info, debug, trace",
+                )
+            }
+        },
+        None => log::Level::Trace,
+    };
+    let resumed = request
+        .header("Last-Event-ID")
+        .and_then(|id| id.parse::<u64>().ok())
+        .map(|id| id + 1);
+
+    metrics::HTTP_SERVER_REQUESTS.inc(&[("path", "/logs/stream")]);
+    let mut response = request.into_response(
+        200,
+        None,
+        &[
+            ("Content-Type", "text/event-stream"),
+            ("Cache-Control", "no-cache"),
+        ],
+    )?;
+    // a fresh stream starts with whatever the ring still has
+    let mut next = resumed.unwrap_or(0);
+    let mut events = format!("retry: {STREAM_RETRY}\n\n");
+    let (first, lines) = logtail::read_from(next);
+    if resumed.is_some() && first > next {
+        let _ = write!(events, "data: ... {} lines dropped\n\n", first - next);
+    }
+    let mut shown = true;
+    for (id, line) in (first..).zip(lines) {
+        shown = logtail::level(&line).is_some_and(|line_level| line_level <= level);
+        if shown {
+            let _ = write!(events, "id: {id}\n");
+            for part in line.split('\n') {
+                let _ = write!(events, "data: {part}\n");
+            }
+            events.push('\n');
+        }
+        next = id + 1;
+    }
+    // an id alone moves the browser's past the lines left out, so coming
+    // back doesn't go over them again
+    if !shown {
+        let _ = write!(events, "id: {}\n\n", next - 1);
+    }
+    // a failed write is the browser gone
+    let _ = response.write_all(events.as_bytes());
+    Ok(())
+}
+
+/// Whether the request carries `secret`, as `X-Trigger-Secret` or, for an
+/// `EventSource` that can't set headers, as `?secret=`.
+fn log_reader(request: &Request<&mut EspHttpConnection<'_>>, secret: &Secret<String>) -> bool {
+    let given = query(request.uri(), "secret")
+        .map(percent_decoded)
+        .or_else(|| request.header("X-Trigger-Secret").map(String::from))
+        .unwrap_or_default();
+    constant_time_eq(given.as_bytes(), secret.expose().as_bytes())
+}
+
+/// The value of query parameter `name` in `uri`, as sent.
+fn query<'a>(uri: &'a str, name: &str) -> Option<&'a str> {
+    let (_, query) = uri.split_once('?')?;
+    query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+}
+
+/// `%xx` and `+` undone; a malformed escape is kept as it is.
+fn percent_decoded(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut at = 0;
+    while at < bytes.len() {
+        let escaped = (bytes[at] == b'%')
+            .then(|| value.get(at + 1..at + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[at], escaped) {
+            (_, Some(byte)) => {
+                decoded.push(byte);
+                at += 3;
+                continue;
+            }
+            (b'+', None) => decoded.push(b' '),
+            (byte, None) => decoded.push(byte),
+        }
+        at += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Runs the command in the body, such as `fetch [url]`, `timesync` or
+/// `bench [<sequential> <concurrent> <bytes>]`, the way the console runs
+/// them: published and answered right away, the outcome logged.
+fn trigger(
+    mut request: Request<&mut EspHttpConnection<'_>>,
+    secret: &Secret<String>,
+) -> Result<()> {
+    if !authorized(&request, "X-Trigger-Secret", secret) {
+        log::warn!("trigger refused, wrong secret");
+        return refuse(request, 401, "wrong or missing X-Trigger-Secret");
+    }
+
+    let mut body = [0; MAX_TRIGGER];
+    let mut len = 0;
+    while len < body.len() {
+        match request.read(&mut body[len..])? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    let command = String::from_utf8_lossy(&body[..len]).trim().to_owned();
+    let action = command.split_whitespace().next().unwrap_or_default();
+    if !TRIGGERS.contains(&action) {
+        let error = format!("unknown trigger, one of {}", TRIGGERS.join(", "));
+        return refuse(request, 400, &error);
+    }
+
+    log::info!("triggered over http: {command}");
+    let accepted = serde_json::to_vec(&api::Accepted {
+        accepted: command.clone(),
+    })?;
+    events::publish(Event::Command(command));
+    respond_with(request, 202, "application/json", &accepted)
+}
+
+/// Whether the request's `header` holds `secret`.
+fn authorized(
+    request: &Request<&mut EspHttpConnection<'_>>,
+    header: &str,
+    secret: &Secret<String>,
+) -> bool {
+    let given = request.header(header).unwrap_or_default();
+    constant_time_eq(given.as_bytes(), secret.expose().as_bytes())
+}
+
+/// The device's backup, sealed under the `X-Backup-Passphrase`.
+#[cfg(feature = "backup")]
+fn backup(request: Request<&mut EspHttpConnection<'_>>, secret: &Secret<String>) -> Result<()> {
+    if !authorized(&request, "X-Backup-Secret", secret) {
+        log::warn!("backup refused, wrong secret");
+        return refuse(request, 401, "wrong or missing X-Backup-Secret");
+    }
+    let passphrase = request.header("X-Backup-Passphrase").unwrap_or_default();
+    match crate::backup::export(passphrase) {
+        Ok(blob) => respond(request, "application/octet-stream", &blob),
+        Err(err) => refuse(request, 400, &format!("{err:#}")),
+    }
+}
+
+/// Restores the backup in the body, opened with the `X-Backup-Passphrase`,
+/// and reboots.
+#[cfg(feature = "backup")]
+fn restore(
+    mut request: Request<&mut EspHttpConnection<'_>>,
+    secret: &Secret<String>,
+) -> Result<()> {
+    if !authorized(&request, "X-Backup-Secret", secret) {
+        log::warn!("restore refused, wrong secret");
+        return refuse(request, 401, "wrong or missing X-Backup-Secret");
+    }
+    let passphrase = request
+        .header("X-Backup-Passphrase")
+        .unwrap_or_default()
+        .to_owned();
+    let mut blob = Vec::new();
+    let mut chunk = [0; 1024];
+    loop {
+        match request.read(&mut chunk)? {
+            0 => break,
+            read => blob.extend_from_slice(&chunk[..read]),
+        }
+        if blob.len() > crate::backup::MAX_BLOB {
+            return refuse(request, 413, "larger than any backup");
+        }
+    }
+    match crate::backup::import(&blob, &passphrase) {
+        Ok(restored) => respond_json(request, &api::Restored { restored }),
+        Err(err) => refuse(request, 400, &format!("{err:#}")),
+    }
+}
+
+/// Reads the gauges `/metrics` reports as of the scrape.
+fn sample() {
+    FREE_HEAP.set(&[("region", "all")], heap::free() as f64);
+    if CHIP.psram {
+        FREE_HEAP.set(&[("region", "internal")], heap::free_internal() as f64);
+    }
+    match net::rssi() {
+        Some(rssi) => WIFI_RSSI.set(&[], rssi.into()),
+        None => WIFI_RSSI.clear(),
+    }
+    UPTIME.set(&[], device::uptime().as_secs_f64());
+    for task in runtime::tasks() {
+        let running = task.state == runtime::TaskState::Running;
+        TASK_RUNNING.set(&[("task", task.name)], if running { 1.0 } else { 0.0 });
+        TASK_RESTARTS.set(&[("task", task.name)], task.restarts.into());
+    }
+}
@@ -0,0 +1,424 @@
+//! The JSON the status server answers with, as the types that make it, and
+//! the OpenAPI document `/openapi.json` serves for tooling to generate
+//! clients from. `api_type!` declares a type and its schema from the same
+//! fields, so one can't change without the other; the types the server
+//! hands on from elsewhere, `Config` and the DNS stats, have theirs read
+//! off what their defaults serialize to.
+
+use crate::{config::Config, device, dns};
+use serde_json::{json, Map, Value};
+
+/// A type with a JSON schema, the OpenAPI 3.0 dialect.
+pub trait Schema {
+    fn schema() -> Value;
+
+    /// Whether an object the type is a field of has to have it.
+    fn required() -> bool {
+        true
+    }
+}
+
+macro_rules! primitive {
+    ($($ty:ty => $schema:tt),* $(,)?) => {
+        $(impl Schema for $ty {
+            fn schema() -> Value {
+                json!($schema)
+            }
+        })*
+    };
+}
+
+primitive! {
+    String => { "type": "string" },
+    bool => { "type": "boolean" },
+    u16 => { "type": "integer", "minimum": 0 },
+    u32 => { "type": "integer", "minimum": 0 },
+    u64 => { "type": "integer", "minimum": 0 },
+    usize => { "type": "integer", "minimum": 0 },
+    i8 => { "type": "integer" },
+    i64 => { "type": "integer" },
+    Value => {},
+}
+
+impl<T: Schema> Schema for Option<T> {
+    fn schema() -> Value {
+        let mut schema = T::schema();
+        schema["nullable"] = true.into();
+        schema
+    }
+
+    fn required() -> bool {
+        false
+    }
+}
+
+impl<T: Schema> Schema for Vec<T> {
+    fn schema() -> Value {
+        json!({ "type": "array", "items": T::schema() })
+    }
+}
+
+/// A struct that serializes as it's declared, with its schema: the doc
+/// comments become descriptions, `Option` fields may be null or missing,
+/// and a trailing `..name` takes the fields not declared into a map.
+macro_rules! api_type {
+    (
+        $(#[doc = $doc:literal])*
+        pub struct $name:ident {
+            $(
+                $(#[doc = $field_doc:literal])*
+                pub $field:ident: $ty:ty,
+            )*
+            $(..$extra:ident)?
+        }
+    ) => {
+        $(#[doc = $doc])*
+        #[derive(Debug, serde::Serialize, serde::Deserialize)]
+        pub struct $name {
+            $(
+                $(#[doc = $field_doc])*
+                pub $field: $ty,
+            )*
+            $(
+                #[serde(flatten)]
+                pub $extra: Map<String, Value>,
+            )?
+        }
+
+        impl Schema for $name {
+            fn schema() -> Value {
+                let mut properties = Map::new();
+                let mut required = Vec::<&str>::new();
+                $(
+                    let mut field = <$ty as Schema>::schema();
+                    let doc = describe(&[$($field_doc),*]);
+                    if !doc.is_empty() {
+                        field["description"] = doc.into();
+                    }
+                    properties.insert(stringify!($field).into(), field);
+                    if <$ty as Schema>::required() {
+                        required.push(stringify!($field));
+                    }
+                )*
+                json!({
+                    "type": "object",
+                    "description": describe(&[$($doc),*]),
+                    "properties": properties,
+                    "required": required,
+                    "additionalProperties": present!($($extra)?),
+                })
+            }
+        }
+    };
+}
+
+/// Whether a `..name` was given.
+macro_rules! present {
+    () => {
+        false
+    };
+    ($extra:ident) => {
+        true
+    };
+}
+
+/// Doc comment lines as one description.
+fn describe(lines: &[&str]) -> String {
+    let lines: Vec<&str> = lines.iter().map(|line| line.trim()).collect();
+    lines.join(" ")
+}
+
+api_type! {
+    /// `/api/status`, the telemetry sample: the fields every sample has,
+    /// then those the subsystems built in set.
+    pub struct Status {
+        pub device_id: String,
+        pub firmware: String,
+        pub chip: String,
+        pub uptime_s: u64,
+        /// Bytes, in every region.
+        pub free_heap: usize,
+        /// Bytes of internal RAM, on chips with PSRAM.
+        pub free_internal: Option<usize>,
+        /// dBm of the WiFi link, when it's up.
+        pub rssi: Option<i8>,
+        ..fields
+    }
+}
+
+api_type! {
+    /// A poll `/api/last` has a body of.
+    pub struct KeptPoll {
+        /// What `?poll=` takes to get the body.
+        pub poll: usize,
+        pub url: String,
+        /// Unix seconds, null when the clock wasn't set.
+        pub fetched: Option<i64>,
+        /// Seconds since, null when the clock isn't set.
+        pub age: Option<u64>,
+    }
+}
+
+api_type! {
+    /// `POST /trigger` took the command.
+    pub struct Accepted {
+        /// The command, as published.
+        pub accepted: String,
+    }
+}
+
+api_type! {
+    /// A request refused, and why.
+    pub struct Error {
+        pub error: String,
+    }
+}
+
+#[cfg(feature = "backup")]
+api_type! {
+    /// `POST /restore` wrote the backup, the device reboots next.
+    pub struct Restored {
+        /// What was restored, from which device.
+        pub restored: String,
+    }
+}
+
+impl Error {
+    pub fn new(error: impl Into<String>) -> Self {
+        Self {
+            error: error.into(),
+        }
+    }
+}
+
+/// The schema of a type defined elsewhere, from a value of it: every field
+/// its default has, typed by what it serializes to.
+fn sampled(value: &Value) -> Value {
+    match value {
+        Value::Null => json!({ "nullable": true }),
+        Value::Bool(_) => json!({ "type": "boolean" }),
+        Value::Number(number) if number.is_f64() => json!({ "type": "number" }),
+        Value::Number(_) => json!({ "type": "integer" }),
+        Value::String(_) => json!({ "type": "string" }),
+        Value::Array(items) => {
+            let items = items.first().map(sampled).unwrap_or_default();
+            json!({ "type": "array", "items": items })
+        }
+        Value::Object(fields) => {
+            let properties: Map<String, Value> = fields
+                .iter()
+                .map(|(name, value)| (name.clone(), sampled(value)))
+                .collect();
+            json!({ "type": "object", "properties": properties })
+        }
+    }
+}
+
+fn reference(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{name}") })
+}
+
+fn content(media: &str, schema: Value) -> Value {
+    json!({ media: { "schema": schema } })
+}
+
+fn json_response(description: &str, schema: Value) -> Value {
+    json!({ "description": description, "content": content("application/json", schema) })
+}
+
+fn text_response(media: &str, description: &str) -> Value {
+    json!({ "description": description, "content": content(media, json!({ "type": "string" })) })
+}
+
+fn get(summary: &str, responses: Value) -> Value {
+    json!({ "get": { "summary": summary, "responses": responses } })
+}
+
+/// The OpenAPI document of the server's routes.
+pub fn document() -> Value {
+    let error = || reference("Error");
+    let object = || json!({ "type": "object" });
+
+    // only the backup routes are added after
+    #[allow(unused_mut)]
+    let mut document = json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "device status server",
+            "version": device::firmware_version(),
+        },
+        "paths": {
+            "/": get("status page", json!({
+                "200": text_response("text/html", "the sample, as a page"),
+            })),
+            "/api/status": get("the telemetry sample", json!({
+                "200": json_response("the sample", reference("Status")),
+            })),
+            "/api/config": get("the config, secrets redacted", json!({
+                "200": json_response("the config", reference("Config")),
+            })),
+            "/api/dns": get("DNS cache counters", json!({
+                "200": json_response("the counters", reference("DnsStats")),
+            })),
+            "/api/firmware": get("version and image protection", json!({
+                "200": json_response("the firmware", object()),
+            })),
+            "/api/inventory": get("features, subsystems and crate versions", json!({
+                "200": json_response("what the build has", object()),
+            })),
+            "/api/last": {
+                "get": {
+                    "summary": "the polls kept a body of, or with poll one's body",
+                    "parameters": [{
+                        "name": "poll",
+                        "in": "query",
+                        "required": false,
+                        "schema": { "type": "integer", "minimum": 0 },
+                    }],
+                    "responses": {
+                        "200": {
+                            "description": "the list, or the body as it was fetched, Age and \
+                                X-Stale set",
+                            "content": {
+                                "application/json": {
+                                    "schema": { "type": "array", "items": reference("KeptPoll") },
+                                },
+                                "*/*": { "schema": { "type": "string", "format": "binary" } },
+                            },
+                        },
+                        "404": json_response("no such poll", error()),
+                    },
+                },
+            },
+            "/logs": {
+                "get": {
+                    "summary": "the log, live in a page, there only when trigger_secret is set",
+                    "security": [{ "triggerSecret": [] }, { "triggerSecretQuery": [] }],
+                    "responses": {
+                        "200": text_response("text/html", "the page"),
+                        "401": json_response("wrong or missing secret", error()),
+                    },
+                },
+            },
+            "/logs/stream": {
+                "get": {
+                    "summary": "the log as server-sent events, a line an event, there only when trigger_secret is set",
+                    "security": [{ "triggerSecret": [] }, { "triggerSecretQuery": [] }],
+                    "parameters": [
+                        {
+                            "name": "level",
+                            "in": "query",
+                            "required": false,
+                            "schema": {
+                                "type": "string",
+                                "enum": ["error", "warn", "info", "debug", "trace"],
+                            },
+                        },
+                        {
+                            "name": "Last-Event-ID",
+                            "in": "header",
+                            "required": false,
+                            "schema": { "type": "integer", "minimum": 0 },
+                        },
+                    ],
+                    "responses": {
+                        "200": text_response("text/event-stream", "the lines since Last-Event-ID, then a retry"),
+                        "400": json_response("unknown level", error()),
+                        "401": json_response("wrong or missing secret", error()),
+                    },
+                },
+            },
+            "/metrics": get("Prometheus metrics", json!({
+                "200": text_response("text/plain", "the exposition format"),
+            })),
+            "/trigger": {
+                "post": {
+                    "summary": "runs a command, there only when trigger_secret is set",
+                    "security": [{ "triggerSecret": [] }],
+                    "requestBody": {
+                        "required": true,
+                        "content": content("text/plain", json!({
+                            "type": "string",
+                            "example": "fetch",
+                        })),
+                    },
+                    "responses": {
+                        "202": json_response("published", reference("Accepted")),
+                        "400": json_response("unknown command", error()),
+                        "401": json_response("wrong or missing secret", error()),
+                    },
+                },
+            },
+            "/openapi.json": get("this document", json!({
+                "200": json_response("the document", object()),
+            })),
+        },
+        "components": {
+            "schemas": {
+                "Status": Status::schema(),
+                "KeptPoll": KeptPoll::schema(),
+                "Accepted": Accepted::schema(),
+                "Error": Error::schema(),
+                "Config": sampled(&Config::default().redacted()),
+                "DnsStats": sampled(&json!(dns::Stats::default())),
+            },
+            "securitySchemes": {
+                "triggerSecret": { "type": "apiKey", "in": "header", "name": "X-Trigger-Secret" },
+                // what an EventSource, which can't set headers, sends
+                "triggerSecretQuery": { "type": "apiKey", "in": "query", "name": "secret" },
+                "backupSecret": { "type": "apiKey", "in": "header", "name": "X-Backup-Secret" },
+            },
+        },
+    });
+    #[cfg(feature = "backup")]
+    backup_paths(&mut document);
+    document
+}
+
+#[cfg(feature = "backup")]
+fn backup_paths(document: &mut Value) {
+    let passphrase = json!({
+        "name": "X-Backup-Passphrase",
+        "in": "header",
+        "required": true,
+        "schema": { "type": "string", "minLength": 8 },
+    });
+    let blob = || json!({ "type": "string", "format": "binary" });
+    let refused = || {
+        json!({
+            "400": json_response("wrong passphrase, or no backup", reference("Error")),
+            "401": json_response("wrong or missing secret", reference("Error")),
+        })
+    };
+
+    let mut responses = refused();
+    responses["200"] = json!({
+        "description": "the backup, sealed under the passphrase",
+        "content": content("application/octet-stream", blob()),
+    });
+    document["paths"]["/backup"] = json!({
+        "post": {
+            "summary": "config, identity and stats, encrypted, there with backup_secret",
+            "security": [{ "backupSecret": [] }],
+            "parameters": [passphrase.clone()],
+            "responses": responses,
+        },
+    });
+
+    let mut responses = refused();
+    responses["200"] = json_response("written, rebooting", reference("Restored"));
+    responses["413"] = json_response("larger than any backup", reference("Error"));
+    document["paths"]["/restore"] = json!({
+        "post": {
+            "summary": "writes a backup from /backup and reboots, there with backup_secret",
+            "security": [{ "backupSecret": [] }],
+            "parameters": [passphrase],
+            "requestBody": {
+                "required": true,
+                "content": content("application/octet-stream", blob()),
+            },
+            "responses": responses,
+        },
+    });
+    document["components"]["schemas"]["Restored"] = Restored::schema();
+}
@@ -0,0 +1,85 @@
+//! What the device gives up when the heap runs low, rather than failing
+//! an allocation somewhere it can't recover from. On `LowHeap` the first
+//! step is taken, and another every `STEP_INTERVAL` the heap stays low:
+//! polls pause, telemetry drops all but the system fields, the idle
+//! connections of the fetch client are closed, and last the device
+//! reboots. Once the heap is back above the threshold by a margin the
+//! polls and telemetry resume. Each step is logged and counted in
+//! `heap_shed_total`.
+
+use crate::{
+    events::{self, Event},
+    heap, metrics, poller, runtime, telemetry,
+};
+use std::time::Duration;
+
+/// How long a step gets to free enough before the next one.
+const STEP_INTERVAL: Duration = Duration::from_secs(20);
+
+#[derive(Clone, Copy, Debug)]
+enum Step {
+    PausePolls,
+    DropTelemetry,
+    CloseIdle,
+    Reboot,
+}
+
+/// In the order they're taken.
+const STEPS: [Step; 4] = [
+    Step::PausePolls,
+    Step::DropTelemetry,
+    Step::CloseIdle,
+    Step::Reboot,
+];
+
+impl Step {
+    fn name(self) -> &'static str {
+        match self {
+            Self::PausePolls => "pause_polls",
+            Self::DropTelemetry => "drop_telemetry",
+            Self::CloseIdle => "close_idle",
+            Self::Reboot => "reboot",
+        }
+    }
+
+    fn take(self) {
+        log::warn!("shed: {} free, {}", heap::available(), self.name());
+        metrics::HEAP_SHED.inc(&[("action", self.name())]);
+        match self {
+            Self::PausePolls => poller::pause(true),
+            Self::DropTelemetry => telemetry::shed(true),
+            Self::CloseIdle => {
+                #[cfg(feature = "http-reqwest")]
+                crate::http::close_idle();
+            }
+            Self::Reboot => esp_idf_hal::reset::restart(),
+        }
+    }
+}
+
+/// Sheds load whenever free heap drops below `threshold`, what
+/// `heap::check()` runs with.
+pub fn start(threshold: usize) {
+    // a quarter above where it started, so it doesn't resume on the edge
+    let recovered = threshold + threshold / 4;
+    runtime::spawn(async move {
+        let mut events = events::subscribe();
+        loop {
+            let Ok(Event::LowHeap { .. }) = events.recv().await else {
+                continue;
+            };
+            for step in STEPS {
+                step.take();
+                runtime::sleep(STEP_INTERVAL).await;
+                if heap::available() >= recovered {
+                    break;
+                }
+            }
+            log::info!("shed: {} free, resuming", heap::available());
+            poller::pause(false);
+            telemetry::shed(false);
+            // what was published meanwhile is about the low this just handled
+            events = events::subscribe();
+        }
+    });
+}
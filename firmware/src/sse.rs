@@ -0,0 +1,118 @@
+use crate::{
+    config::Config,
+    events::{self, Event},
+    http, runtime,
+};
+use anyhow::{bail, Result};
+use futures_util::StreamExt;
+use std::sync::{Arc, Mutex};
+
+/// Keeps a Server-Sent Events stream open to the backend; `message` and
+/// `command` events are published as commands. Reconnects resume from the
+/// last event id the server handed out.
+pub fn start(config: &Config) {
+    let url = config.sse_url.clone();
+    let last_event_id = Arc::new(Mutex::new(None));
+    runtime::spawn_named("sse", move || session(url.clone(), last_event_id.clone()));
+}
+
+async fn session(url: String, last_event_id: Arc<Mutex<Option<String>>>) -> Result<()> {
+    events::wait_until(|state| state.net_up).await;
+    if url.starts_with("https://") {
+        events::wait_until(|state| state.time_synced).await;
+    }
+
+    let mut request = http::client()?
+        .get(&url)
+        .header("Accept", "text/event-stream")
+        .header("Cache-Control", "no-cache");
+    if let Some(id) = last_event_id.lock().unwrap().clone() {
+        request = request.header("Last-Event-ID", id);
+    }
+
+    crate::ratelimit::acquire("sse").await?;
+    let response = request.send().await?.error_for_status()?;
+    log::info!("sse connected to {url}");
+
+    let mut body = response.bytes_stream();
+    let mut parser = Parser::default();
+
+    while let Some(chunk) = body.next().await {
+        for frame in parser.feed(&chunk?) {
+            if let Some(id) = frame.id {
+                *last_event_id.lock().unwrap() = Some(id);
+            }
+            match frame.event.as_deref() {
+                None | Some("message") | Some("command") => {
+                    events::publish(Event::Command(frame.data));
+                }
+                Some(other) => log::debug!("sse: ignoring {other} event"),
+            }
+        }
+    }
+
+    bail!("sse stream ended")
+}
+
+#[derive(Debug, Default)]
+struct Frame {
+    event: Option<String>,
+    data: String,
+    id: Option<String>,
+}
+
+/// Incremental `text/event-stream` parser, frames may be split across any
+/// number of chunks.
+#[derive(Default)]
+struct Parser {
+    line: Vec<u8>,
+    frame: Frame,
+    has_data: bool,
+}
+
+impl Parser {
+    fn feed(&mut self, chunk: &[u8]) -> Vec<Frame> {
+        let mut frames = Vec::new();
+
+        for &byte in chunk {
+            if byte != b'\n' {
+                self.line.push(byte);
+                continue;
+            }
+            if self.line.last() == Some(&b'\r') {
+                self.line.pop();
+            }
+
+            let line = std::mem::take(&mut self.line);
+            if line.is_empty() {
+                let frame = std::mem::take(&mut self.frame);
+                if std::mem::take(&mut self.has_data) {
+                    frames.push(frame);
+                }
+                continue;
+            }
+
+            let line = String::from_utf8_lossy(&line);
+            let (field, value) = match line.split_once(':') {
+                Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+                None => (line.as_ref(), ""),
+            };
+            match field {
+                "event" => self.frame.event = Some(value.to_owned()),
+                "data" => {
+                    if self.has_data {
+                        self.frame.data.push('\n');
+                    }
+                    self.frame.data.push_str(value);
+                    self.has_data = true;
+                }
+                "id" => self.frame.id = Some(value.to_owned()),
+                // comments (empty field) keep the connection alive, retry is
+                // left to the task table's restart delay
+                _ => {}
+            }
+        }
+
+        frames
+    }
+}
@@ -0,0 +1,58 @@
+//! When boot first got through each phase, since reset, summed up in one
+//! line after `boot completed` and in the `boot_ms` telemetry field, so a
+//! firmware version that takes longer to get to data stands out.
+
+use crate::{device, telemetry};
+use serde_json::{Map, Value};
+use std::{fmt::Write, sync::Mutex, time::Duration};
+
+#[derive(Clone, Copy)]
+pub enum Phase {
+    LinkPatches,
+    WifiStart,
+    /// The link has an address, from DHCP or the PPP peer.
+    Dhcp,
+    Sntp,
+    TlsHandshake,
+    /// The first response to an HTTP request.
+    FirstByte,
+}
+
+/// By `Phase`, as they appear in the summary and telemetry.
+const NAMES: [&str; 6] = [
+    "link_patches",
+    "wifi_start",
+    "dhcp",
+    "sntp",
+    "tls",
+    "first_byte",
+];
+
+static MARKS: Mutex<[Option<Duration>; NAMES.len()]> = Mutex::new([None; NAMES.len()]);
+
+/// Records `phase` as reached now, unless it already was: a reconnect or a
+/// later handshake isn't boot.
+pub fn mark(phase: Phase) {
+    MARKS.lock().unwrap()[phase as usize].get_or_insert_with(device::uptime);
+}
+
+/// Logs the summary and sets the telemetry field. Phases this boot didn't
+/// get through, or this build doesn't have, are left out of the field.
+pub fn report() {
+    let marks = *MARKS.lock().unwrap();
+    let mut line = String::new();
+    let mut record = Map::new();
+    for (name, mark) in NAMES.iter().zip(marks) {
+        match mark {
+            Some(at) => {
+                let _ = write!(line, " {name}={}ms", at.as_millis());
+                record.insert((*name).into(), (at.as_millis() as u64).into());
+            }
+            None => {
+                let _ = write!(line, " {name}=-");
+            }
+        }
+    }
+    log::info!("boot phases, {}:{line}", device::firmware_version());
+    telemetry::set("boot_ms", Value::Object(record));
+}
@@ -0,0 +1,56 @@
+use crate::{chip::CHIP, device, heap, net};
+use serde_json::{Map, Value};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+#[cfg(feature = "http-reqwest")]
+mod encoding;
+pub mod endpoints;
+#[cfg(feature = "http-reqwest")]
+pub mod influx;
+pub mod udp;
+
+static FIELDS: Mutex<BTreeMap<String, Value>> = Mutex::new(BTreeMap::new());
+static SHEDDING: AtomicBool = AtomicBool::new(false);
+
+/// Sets a field included in every telemetry sample until it is overwritten.
+pub fn set(name: &str, value: impl Into<Value>) {
+    if SHEDDING.load(Ordering::Relaxed) {
+        return;
+    }
+    FIELDS.lock().unwrap().insert(name.to_owned(), value.into());
+}
+
+/// While `on`, samples carry only the built-in system fields: the others
+/// are dropped and `set()` keeps none, for the heap they hold.
+pub fn shed(on: bool) {
+    SHEDDING.store(on, Ordering::Relaxed);
+    if on {
+        FIELDS.lock().unwrap().clear();
+    }
+}
+
+/// Current telemetry sample: the built-in system fields plus everything
+/// registered through `set()`.
+pub fn snapshot() -> Value {
+    let mut sample: Map<String, Value> = FIELDS.lock().unwrap().clone().into_iter().collect();
+
+    sample.insert("device_id".into(), device::id().into());
+    sample.insert("firmware".into(), device::firmware_version().into());
+    sample.insert("chip".into(), CHIP.name.into());
+    sample.insert("uptime_s".into(), device::uptime().as_secs().into());
+    sample.insert("free_heap".into(), heap::free().into());
+    if CHIP.psram {
+        sample.insert("free_internal".into(), heap::free_internal().into());
+    }
+    if let Some(rssi) = net::rssi() {
+        sample.insert("rssi".into(), rssi.into());
+    }
+
+    Value::Object(sample)
+}
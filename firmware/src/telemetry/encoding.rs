@@ -0,0 +1,148 @@
+//! Content encodings for telemetry batches, to cut the airtime of
+//! deployments sampling often. `gzip` is what stock InfluxDB takes, in
+//! builds with the `gzip` feature; `x-delta-varint` is cheaper to make and
+//! for servers that decode it:
+//!
+//! every line is a zigzag varint of its timestamp less the previous
+//! line's (the first less 0), then the line split at its commas: a varint
+//! count of parts and, for each, a 0 byte when it's the previous line's
+//! part at the same place, or a varint of its length plus one and its
+//! bytes. Tags and fields that didn't change since the last sample cost a
+//! byte each; joined back with commas the lines are what identity sends.
+//!
+//! With `influx_encoding` empty the server picks: batches go out plain
+//! until it lists one of these in an `Accept-Encoding` of its own (RFC
+//! 7694), and a 415 goes back to plain.
+
+use anyhow::{bail, Result};
+use std::fmt::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    DeltaVarint,
+}
+
+/// Best first, for answering an `Accept-Encoding`.
+const PREFERRED: &[Encoding] = &[Encoding::DeltaVarint, Encoding::Gzip];
+
+impl Encoding {
+    /// `influx_encoding`, `None` to leave it to the server.
+    pub fn parse(value: &str) -> Result<Option<Self>> {
+        let encoding = match value.trim() {
+            "" => return Ok(None),
+            "identity" => Self::Identity,
+            "gzip" => Self::Gzip,
+            "x-delta-varint" => Self::DeltaVarint,
+            other => bail!("unknown influx_encoding {other}"),
+        };
+        if !encoding.available() {
+            bail!("influx_encoding {value} needs a build with the gzip feature");
+        }
+        Ok(Some(encoding))
+    }
+
+    /// The `Content-Encoding` value, `None` for a plain body.
+    pub fn name(self) -> Option<&'static str> {
+        match self {
+            Self::Identity => None,
+            Self::Gzip => Some("gzip"),
+            Self::DeltaVarint => Some("x-delta-varint"),
+        }
+    }
+
+    fn available(self) -> bool {
+        self != Self::Gzip || cfg!(feature = "gzip")
+    }
+
+    /// The best of ours a server's `Accept-Encoding` lists, identity if
+    /// none; `q=0` turns one down.
+    pub fn negotiate(accept: &str) -> Self {
+        let listed = |name: &str| {
+            accept.split(',').any(|entry| {
+                let mut parts = entry.split(';').map(str::trim);
+                parts
+                    .next()
+                    .is_some_and(|listed| listed.eq_ignore_ascii_case(name))
+                    && !parts.any(|param| {
+                        param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+                    })
+            })
+        };
+        PREFERRED
+            .iter()
+            .copied()
+            .find(|encoding| encoding.available() && encoding.name().is_some_and(listed))
+            .unwrap_or(Self::Identity)
+    }
+
+    /// `lines`, each with its timestamp, as the body to send.
+    pub fn encode(self, lines: &[(String, u128)]) -> Result<Vec<u8>> {
+        match self {
+            Self::Identity => Ok(plain(lines).into_bytes()),
+            Self::Gzip => gzip(&plain(lines)),
+            Self::DeltaVarint => Ok(delta_varint(lines)),
+        }
+    }
+}
+
+/// Line protocol, a line per sample.
+fn plain(lines: &[(String, u128)]) -> String {
+    let mut body = String::new();
+    for (line, stamp) in lines {
+        let _ = writeln!(body, "{line} {stamp}");
+    }
+    body
+}
+
+#[cfg(feature = "gzip")]
+fn gzip(body: &str) -> Result<Vec<u8>> {
+    use std::io::Write;
+    // the fastest level keeps the compressor's tables small
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    encoder.write_all(body.as_bytes())?;
+    Ok(encoder.finish()?)
+}
+
+#[cfg(not(feature = "gzip"))]
+fn gzip(_: &str) -> Result<Vec<u8>> {
+    bail!("gzip needs a build with the gzip feature")
+}
+
+fn delta_varint(lines: &[(String, u128)]) -> Vec<u8> {
+    let mut body = Vec::new();
+    let mut previous: (i128, Vec<&str>) = (0, Vec::new());
+    for (line, stamp) in lines {
+        let stamp = *stamp as i128;
+        zigzag(&mut body, stamp - previous.0);
+        let parts: Vec<&str> = line.split(',').collect();
+        varint(&mut body, parts.len() as u128);
+        for (at, part) in parts.iter().enumerate() {
+            if previous.1.get(at) == Some(part) {
+                body.push(0);
+            } else {
+                varint(&mut body, part.len() as u128 + 1);
+                body.extend_from_slice(part.as_bytes());
+            }
+        }
+        previous = (stamp, parts);
+    }
+    body
+}
+
+/// LEB128: seven bits a byte, low first, the top bit set on all but the
+/// last.
+fn varint(out: &mut Vec<u8>, mut value: u128) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Small magnitudes of either sign in few bytes: 0, -1, 1, -2, ... as 0,
+/// 1, 2, 3, ...
+fn zigzag(out: &mut Vec<u8>, value: i128) {
+    varint(out, ((value << 1) ^ (value >> 127)) as u128);
+}
@@ -0,0 +1,296 @@
+//! Primary and backup endpoints of what the device sends to or polls, by
+//! name: `influx` for `influx_url` and `influx_backup`, `poll <url>` for a
+//! poll entry that lists a second url. Requests go to the primary until
+//! `backup_failures` of them in a row fail, then to the backup. While the
+//! backup is in use the primary is probed, a plain TCP connect, every
+//! `backup_recheck` seconds and used again after `RECOVERIES` answers in
+//! a row.
+//!
+//! Every endpoint's requests are counted, failed or not, with the last
+//! error and latency, and go out with telemetry as `endpoints`.
+
+use crate::{config::Config, dns, events, runtime};
+use anyhow::{Context, Result};
+use serde_json::{json, Map, Value};
+use std::{
+    future::Future,
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Probes in a row the primary has to answer to be used again.
+const RECOVERIES: u32 = 2;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+static FAILURES: AtomicU32 = AtomicU32::new(3);
+static RECHECK: AtomicU32 = AtomicU32::new(300);
+static PROBING: AtomicBool = AtomicBool::new(false);
+static PAIRS: Mutex<Vec<Pair>> = Mutex::new(Vec::new());
+
+struct Pair {
+    name: String,
+    /// The primary, then the backup if there is one.
+    endpoints: Vec<Endpoint>,
+    /// Index of the endpoint requests go to.
+    active: usize,
+    /// Requests to the active endpoint failed in a row.
+    failures: u32,
+    /// Probes of the primary answered in a row while on the backup.
+    recoveries: u32,
+}
+
+#[derive(Default)]
+struct Endpoint {
+    url: String,
+    ok: u64,
+    failed: u64,
+    last_error: Option<String>,
+    last_ms: Option<u64>,
+}
+
+impl Endpoint {
+    fn new(url: &str) -> Self {
+        Self {
+            url: url.to_owned(),
+            ..Self::default()
+        }
+    }
+
+    fn health(&self) -> Value {
+        json!({
+            "url": self.url,
+            "ok": self.ok,
+            "failed": self.failed,
+            "last_error": self.last_error,
+            "latency_ms": self.last_ms,
+        })
+    }
+}
+
+/// Takes `backup_failures` and `backup_recheck`, and starts probing the
+/// first time.
+pub fn configure(config: &Config) {
+    FAILURES.store(config.backup_failures.max(1).into(), Ordering::Relaxed);
+    RECHECK.store(config.backup_recheck.max(1).into(), Ordering::Relaxed);
+    if PROBING.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    runtime::spawn(async {
+        loop {
+            let recheck = RECHECK.load(Ordering::Relaxed);
+            runtime::sleep(Duration::from_secs(recheck.into())).await;
+            if !events::state().net_up {
+                continue;
+            }
+            for (name, primary) in on_backup() {
+                let result = reachable(&primary).await;
+                if let Err(err) = &result {
+                    log::debug!("endpoints: {name} primary still down: {err:#}");
+                }
+                recovered(&name, result.is_ok());
+            }
+        }
+    });
+}
+
+/// Sets the endpoints of `name`, `backup` empty for none. Its counts are
+/// kept when they're the ones it had.
+pub fn register(name: &str, primary: &str, backup: &str) {
+    let mut endpoints = vec![Endpoint::new(primary)];
+    if !backup.is_empty() {
+        endpoints.push(Endpoint::new(backup));
+    }
+    let mut pairs = PAIRS.lock().unwrap();
+    match pairs.iter_mut().find(|pair| pair.name == name) {
+        Some(pair) if same(&pair.endpoints, &endpoints) => {}
+        Some(pair) => {
+            pair.endpoints = endpoints;
+            pair.active = 0;
+            pair.failures = 0;
+            pair.recoveries = 0;
+        }
+        None => pairs.push(Pair {
+            name: name.to_owned(),
+            endpoints,
+            active: 0,
+            failures: 0,
+            recoveries: 0,
+        }),
+    }
+    report(&pairs);
+}
+
+fn same(old: &[Endpoint], new: &[Endpoint]) -> bool {
+    old.len() == new.len() && old.iter().zip(new).all(|(old, new)| old.url == new.url)
+}
+
+/// Drops the names starting with `prefix` other than `keep`.
+pub fn retain(prefix: &str, keep: &[String]) {
+    let mut pairs = PAIRS.lock().unwrap();
+    pairs.retain(|pair| !pair.name.starts_with(prefix) || keep.contains(&pair.name));
+    report(&pairs);
+}
+
+/// Runs `request` with the url of the endpoint of `name` in use, counting
+/// how it went; `fallback` when `name` isn't registered.
+pub async fn call<T, F, Fut>(name: &str, fallback: &str, request: F) -> Result<T>
+where
+    F: FnOnce(String) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let active = PAIRS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|pair| pair.name == name)
+        .map(|pair| (pair.active, pair.endpoints[pair.active].url.clone()));
+    let Some((index, url)) = active else {
+        return request(fallback.to_owned()).await;
+    };
+    let start = Instant::now();
+    let result = request(url).await;
+    record(name, index, &result, start.elapsed());
+    result
+}
+
+/// Counts a request to endpoint `index` of `name`, switching to the other
+/// after `backup_failures` in a row.
+fn record<T>(name: &str, index: usize, result: &Result<T>, elapsed: Duration) {
+    let mut pairs = PAIRS.lock().unwrap();
+    let Some(pair) = pairs.iter_mut().find(|pair| pair.name == name) else {
+        return;
+    };
+    let Some(endpoint) = pair.endpoints.get_mut(index) else {
+        return;
+    };
+    endpoint.last_ms = Some(elapsed.as_millis().try_into().unwrap_or(u64::MAX));
+    match result {
+        Ok(_) => {
+            endpoint.ok += 1;
+            if index == pair.active {
+                pair.failures = 0;
+            }
+        }
+        Err(err) => {
+            endpoint.failed += 1;
+            endpoint.last_error = Some(format!("{err:#}"));
+            if index == pair.active && pair.endpoints.len() > 1 {
+                pair.failures += 1;
+                if pair.failures >= FAILURES.load(Ordering::Relaxed) {
+                    let other = 1 - pair.active;
+                    log::warn!(
+                        "endpoints: {name} failed {} times, using {}",
+                        pair.failures,
+                        pair.endpoints[other].url
+                    );
+                    switch(pair, other);
+                }
+            }
+        }
+    }
+    report(&pairs);
+}
+
+/// The names on their backup, with their primary to probe.
+fn on_backup() -> Vec<(String, String)> {
+    PAIRS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|pair| pair.active != 0)
+        .map(|pair| (pair.name.clone(), pair.endpoints[0].url.clone()))
+        .collect()
+}
+
+/// Counts a probe of the primary of `name`, back to it after `RECOVERIES`
+/// answers in a row.
+fn recovered(name: &str, ok: bool) {
+    let mut pairs = PAIRS.lock().unwrap();
+    let Some(pair) = pairs
+        .iter_mut()
+        .find(|pair| pair.name == name && pair.active != 0)
+    else {
+        return;
+    };
+    pair.recoveries = if ok { pair.recoveries + 1 } else { 0 };
+    if pair.recoveries >= RECOVERIES {
+        log::info!(
+            "endpoints: {name} primary {} answers again, using it",
+            pair.endpoints[0].url
+        );
+        switch(pair, 0);
+        report(&pairs);
+    }
+}
+
+fn switch(pair: &mut Pair, active: usize) {
+    pair.active = active;
+    pair.failures = 0;
+    pair.recoveries = 0;
+}
+
+fn report(pairs: &[Pair]) {
+    let mut all = Map::new();
+    for pair in pairs {
+        let mut health = Map::new();
+        health.insert(
+            String::from("active"),
+            Value::from(if pair.active == 0 {
+                "primary"
+            } else {
+                "backup"
+            }),
+        );
+        for (role, endpoint) in ["primary", "backup"].into_iter().zip(&pair.endpoints) {
+            health.insert(role.to_owned(), endpoint.health());
+        }
+        all.insert(pair.name.clone(), Value::Object(health));
+    }
+    super::set("endpoints", Value::Object(all));
+}
+
+/// Opens and drops a connection to where `url` points.
+pub async fn reachable(url: &str) -> Result<()> {
+    let (host, port) = address(url).with_context(|| format!("no host in {url}"))?;
+    let addrs = dns::resolve_addrs(host, port).await?;
+    runtime::run_blocking(move || {
+        let mut last = None;
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, PROBE_TIMEOUT) {
+                Ok(_) => return Ok(()),
+                Err(err) => last = Some(err),
+            }
+        }
+        Err(last.map_or_else(|| anyhow::anyhow!("no address"), Into::into))
+    })
+    .await?
+}
+
+/// The host and port of `url`, the scheme's port when it has none.
+pub(crate) fn address(url: &str) -> Option<(&str, u16)> {
+    let (scheme, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let authority = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(v6) => {
+            let (host, rest) = v6.split_once(']')?;
+            (host, rest.strip_prefix(':'))
+        }
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None if scheme == "https" => 443,
+        None => 80,
+    };
+    (!host.is_empty()).then_some((host, port))
+}
@@ -0,0 +1,237 @@
+use super::encoding::Encoding;
+use crate::{config::Config, events, secret::Secret};
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Value};
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const MEASUREMENT: &str = "telemetry";
+/// Snapshot fields that become tags rather than fields.
+const TAGS: &[&str] = &["device_id", "chip", "firmware"];
+
+/// A batch is written once it has this many lines...
+const BATCH_LINES: usize = 12;
+/// ...or its oldest line has waited this long.
+const MAX_BATCH_AGE: Duration = Duration::from_secs(60);
+/// Lines kept while the clock isn't set or the server is unreachable, the
+/// oldest dropped past it.
+const MAX_PENDING: usize = 240;
+
+struct Writer {
+    client: reqwest::Client,
+    url: String,
+    token: Secret<String>,
+    encoding: Encoding,
+    /// Set by `influx_encoding` rather than negotiated.
+    fixed: bool,
+    /// Lines without their timestamp and when they were sampled.
+    pending: VecDeque<(Instant, String)>,
+}
+
+static WRITER: Mutex<Option<Writer>> = Mutex::new(None);
+
+/// Telemetry as InfluxDB line protocol, POSTed in batches to `influx_url`:
+/// a v2 `/api/v2/write?org=..&bucket=..` or v1 `/write?db=..` endpoint,
+/// or `influx_backup` while it's failing.
+/// Called again with a changed config it moves the lines still waiting to
+/// the new endpoint, and an empty url stops the writes.
+pub fn start(config: &Config) -> Result<()> {
+    let mut writer = WRITER.lock().unwrap();
+    if config.influx_url.is_empty() {
+        *writer = None;
+        super::endpoints::retain("influx", &[]);
+        return Ok(());
+    }
+    let fixed = Encoding::parse(&config.influx_encoding)?;
+    super::endpoints::register("influx", &config.influx_url, &config.influx_backup);
+    let pending = writer
+        .take()
+        .map(|writer| writer.pending)
+        .unwrap_or_default();
+    *writer = Some(Writer {
+        client: crate::http::client()?,
+        url: config.influx_url.clone(),
+        token: config.influx_token.clone(),
+        encoding: fixed.unwrap_or(Encoding::Identity),
+        fixed: fixed.is_some(),
+        pending,
+    });
+    Ok(())
+}
+
+/// Queues the current snapshot and writes the batch when it's due. Lines
+/// are stamped with when they were sampled, which is only known once the
+/// clock has been synced, so until then they wait.
+pub async fn sample() -> Result<()> {
+    let line = line(&super::snapshot());
+    let (client, url, token, encoding, batch) = {
+        let mut writer = WRITER.lock().unwrap();
+        let Some(writer) = writer.as_mut() else {
+            return Ok(());
+        };
+        if let Some(line) = line {
+            if writer.pending.len() == MAX_PENDING {
+                writer.pending.pop_front();
+            }
+            writer.pending.push_back((Instant::now(), line));
+        }
+
+        let due = writer.pending.len() >= BATCH_LINES
+            || writer
+                .pending
+                .front()
+                .is_some_and(|(sampled, _)| sampled.elapsed() >= MAX_BATCH_AGE);
+        if !due || !events::state().time_synced {
+            return Ok(());
+        }
+        let batch: Vec<_> = writer.pending.drain(..).collect();
+        (
+            writer.client.clone(),
+            writer.url.clone(),
+            writer.token.clone(),
+            writer.encoding,
+            batch,
+        )
+    };
+
+    // the monotonic sample times stay right however far the clock jumped
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let lines: Vec<(String, u128)> = batch
+        .iter()
+        .map(|(sampled, line)| {
+            let stamp = now.saturating_sub(sampled.elapsed()).as_millis();
+            (line.clone(), stamp)
+        })
+        .collect();
+    let body = encoding.encode(&lines)?;
+    log::debug!(
+        "influx: {} lines in {} bytes {}",
+        lines.len(),
+        body.len(),
+        encoding.name().unwrap_or("plain")
+    );
+
+    let result = super::endpoints::call("influx", &url, |url| async move {
+        let mut request = client.post(&url).query(&[("precision", "ms")]).body(body);
+        if let Some(name) = encoding.name() {
+            request = request.header("Content-Encoding", name);
+        }
+        if !token.expose().is_empty() {
+            request = request.header("Authorization", format!("Token {}", token.expose()));
+        }
+        crate::ratelimit::acquire("influx").await?;
+        let response = request.send().await?;
+        negotiate(&response, encoding);
+        if response.status() == reqwest::StatusCode::UNSUPPORTED_MEDIA_TYPE
+            && encoding != Encoding::Identity
+        {
+            return Err(anyhow!(
+                "server won't take {}",
+                encoding.name().unwrap_or_default()
+            ));
+        }
+        anyhow::Ok(response.error_for_status()?)
+    })
+    .await;
+    if let Err(err) = result {
+        // back in front of whatever was sampled meanwhile, for the next try
+        if let Some(writer) = WRITER.lock().unwrap().as_mut() {
+            for entry in batch.into_iter().rev() {
+                if writer.pending.len() == MAX_PENDING {
+                    break;
+                }
+                writer.pending.push_front(entry);
+            }
+        }
+        return Err(err.context(format!("couldn't write telemetry to {url}")));
+    }
+    Ok(())
+}
+
+/// Follows what the server says it takes, unless `influx_encoding` fixed
+/// the encoding: an `Accept-Encoding` it sent, or plain after a 415 that
+/// leaves it out.
+fn negotiate(response: &reqwest::Response, sent: Encoding) {
+    let accept = response
+        .headers()
+        .get(reqwest::header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok());
+    let next = match accept {
+        Some(accept) => Encoding::negotiate(accept),
+        None if response.status() == reqwest::StatusCode::UNSUPPORTED_MEDIA_TYPE => {
+            Encoding::Identity
+        }
+        None => return,
+    };
+    let mut writer = WRITER.lock().unwrap();
+    let Some(writer) = writer.as_mut().filter(|writer| !writer.fixed) else {
+        return;
+    };
+    if next != sent && writer.encoding == sent {
+        log::info!(
+            "influx: server takes {}, batches go out that way",
+            next.name().unwrap_or("plain")
+        );
+        writer.encoding = next;
+    }
+}
+
+/// The snapshot's line, less the timestamp. `None` when it has no field
+/// line protocol can carry.
+fn line(snapshot: &Value) -> Option<String> {
+    let sample = snapshot.as_object()?;
+    let mut line = String::from(MEASUREMENT);
+    for tag in TAGS {
+        if let Some(Value::String(value)) = sample.get(*tag) {
+            let _ = write!(line, ",{tag}={}", escape(value, &[',', '=', ' ']));
+        }
+    }
+
+    let mut fields = Vec::new();
+    flatten("", sample, &mut fields);
+    if fields.is_empty() {
+        return None;
+    }
+    line.push(' ');
+    line.push_str(&fields.join(","));
+    Some(line)
+}
+
+/// Fields for every value but the tags, nested objects joined with `_`.
+fn flatten(prefix: &str, object: &Map<String, Value>, fields: &mut Vec<String>) {
+    for (name, value) in object {
+        if prefix.is_empty() && TAGS.contains(&name.as_str()) {
+            continue;
+        }
+        let key = format!("{prefix}{}", escape(name, &[',', '=', ' ']));
+        let value = match value {
+            Value::Null => continue,
+            Value::Object(object) => {
+                flatten(&format!("{key}_"), object, fields);
+                continue;
+            }
+            Value::Bool(value) => value.to_string(),
+            Value::Number(number) if number.is_f64() => number.to_string(),
+            Value::Number(number) => format!("{number}i"),
+            Value::String(value) => format!("\"{}\"", escape(value, &['"'])),
+            Value::Array(_) => format!("\"{}\"", escape(&value.to_string(), &['"'])),
+        };
+        fields.push(format!("{key}={value}"));
+    }
+}
+
+/// Backslash escapes `special` and backslashes themselves.
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '\\' || special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -0,0 +1,105 @@
+use crate::{config::Config, runtime};
+use anyhow::{Context, Result};
+use std::{
+    net::UdpSocket,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Upper bound for one datagram, kept under the 1472 byte UDP payload of a
+/// 1500 byte MTU so packets never fragment.
+pub const MAX_PACKET: usize = 512;
+const _: () = assert!(MAX_PACKET <= 1472);
+
+/// A partially filled packet is still sent once it is this old.
+const MAX_BATCH_AGE: Duration = Duration::from_secs(30);
+
+// CBOR indefinite-length array framing around the batched samples
+const ARRAY_START: u8 = 0x9f;
+const ARRAY_END: u8 = 0xff;
+
+struct Sender {
+    socket: UdpSocket,
+    collector: String,
+    batch: Vec<u8>,
+    started: Instant,
+}
+
+static SENDER: Mutex<Option<Sender>> = Mutex::new(None);
+
+/// Fire-and-forget CBOR telemetry to a UDP collector, for metrics sampled
+/// too often to be worth an HTTPS or MQTT round trip each. Again with an
+/// empty `udp_collector` it stops.
+pub fn start(config: &Config) -> Result<()> {
+    if config.udp_collector.is_empty() {
+        *SENDER.lock().unwrap() = None;
+        return Ok(());
+    }
+    let socket = UdpSocket::bind("0.0.0.0:0").context("couldn't bind telemetry socket")?;
+    *SENDER.lock().unwrap() = Some(Sender {
+        socket,
+        collector: config.udp_collector.clone(),
+        batch: vec![ARRAY_START],
+        started: Instant::now(),
+    });
+    Ok(())
+}
+
+/// Adds the current telemetry snapshot to the batch, sending the batch when
+/// the next sample wouldn't fit or it has waited long enough.
+pub async fn sample() -> Result<()> {
+    let mut sample = Vec::new();
+    ciborium::into_writer(&super::snapshot(), &mut sample)?;
+    anyhow::ensure!(
+        sample.len() + 2 <= MAX_PACKET,
+        "telemetry sample of {} bytes exceeds the packet cap",
+        sample.len()
+    );
+
+    let mut packets = Vec::new();
+    {
+        let mut sender = SENDER.lock().unwrap();
+        let Some(sender) = sender.as_mut() else {
+            return Ok(());
+        };
+
+        if sender.batch.len() + sample.len() + 1 > MAX_PACKET {
+            packets.push(sender.take());
+        }
+        sender.batch.extend_from_slice(&sample);
+        if sender.started.elapsed() >= MAX_BATCH_AGE {
+            packets.push(sender.take());
+        }
+    }
+
+    for packet in packets {
+        send(packet).await?;
+    }
+    Ok(())
+}
+
+impl Sender {
+    fn take(&mut self) -> Vec<u8> {
+        self.started = Instant::now();
+        let mut packet = std::mem::replace(&mut self.batch, vec![ARRAY_START]);
+        packet.push(ARRAY_END);
+        packet
+    }
+}
+
+async fn send(packet: Vec<u8>) -> Result<()> {
+    let (socket, collector) = {
+        let sender = SENDER.lock().unwrap();
+        let sender = sender.as_ref().context("udp telemetry not started")?;
+        (sender.socket.try_clone()?, sender.collector.clone())
+    };
+
+    // resolving the collector may block on DNS
+    runtime::run_blocking(move || {
+        socket
+            .send_to(&packet, collector.as_str())
+            .with_context(|| format!("couldn't send telemetry to {collector}"))
+    })
+    .await??;
+    Ok(())
+}
@@ -0,0 +1,61 @@
+use crate::{
+    events::{self, Event},
+    telemetry,
+};
+use anyhow::{Context, Result};
+use esp_idf_hal::temp_sensor::{TempSensor, TempSensorConfig, TempSensorDriver};
+use std::{
+    sync::atomic::{AtomicU16, Ordering},
+    time::Duration,
+};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(30);
+/// The warning rearms once the chip is this far back below the limit.
+const HYSTERESIS_C: f32 = 5.0;
+const STACK_SIZE: usize = 3072;
+/// Until the config is loaded, the same as the config default.
+static LIMIT: AtomicU16 = AtomicU16::new(80);
+
+pub fn configure(config: &crate::config::Config) {
+    LIMIT.store(config.thermal_limit, Ordering::Relaxed);
+}
+
+/// Samples the die temperature every `SAMPLE_INTERVAL` on its own thread,
+/// into telemetry as `chip_temp_c`, and publishes `Overheat` when it
+/// crosses the configured limit.
+pub fn start(sensor: TempSensor) -> Result<()> {
+    // the range with the best accuracy around the warning levels
+    let mut config = TempSensorConfig::new();
+    config.range_min = 20;
+    config.range_max = 100;
+    let mut driver = TempSensorDriver::new(&config, sensor)?;
+    driver.enable()?;
+
+    std::thread::Builder::new()
+        .name("thermal".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            let mut hot = false;
+            loop {
+                match driver.get_celsius() {
+                    Ok(celsius) => {
+                        telemetry::set("chip_temp_c", celsius);
+                        let limit = f32::from(LIMIT.load(Ordering::Relaxed));
+                        if !hot && celsius > limit {
+                            hot = true;
+                            log::warn!("chip at {celsius:.1} C, over the {limit} C limit");
+                            events::publish(Event::Overheat {
+                                celsius: celsius.round() as i16,
+                            });
+                        } else if hot && celsius < limit - HYSTERESIS_C {
+                            hot = false;
+                        }
+                    }
+                    Err(err) => log::warn!("chip temperature read failed: {err}"),
+                }
+                std::thread::sleep(SAMPLE_INTERVAL);
+            }
+        })
+        .context("couldn't spawn thermal monitor")?;
+    Ok(())
+}
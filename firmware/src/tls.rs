@@ -0,0 +1,113 @@
+use std::sync::{Arc, OnceLock};
+
+pub mod allowlist;
+pub mod chain;
+pub mod ocsp;
+#[cfg(feature = "tls-profiles")]
+pub mod profile;
+pub mod suites;
+
+/// The rustls client configuration shared by every TLS client on the device,
+/// so the webpki root store is only parsed once. It only reaches the hosts
+/// `allowlist` lets through.
+pub fn client_config() -> Arc<rustls::ClientConfig> {
+    static CONFIG: OnceLock<Arc<rustls::ClientConfig>> = OnceLock::new();
+
+    CONFIG
+        .get_or_init(|| {
+            let mut config = client_config_with(rustls::RootCertStore::empty());
+            config.dangerous().set_certificate_verifier(verifier());
+            Arc::new(config)
+        })
+        .clone()
+}
+
+/// `client_config()`, or the config of the profile `host` is routed to.
+pub fn client_config_for(host: &str) -> Arc<rustls::ClientConfig> {
+    #[cfg(feature = "tls-profiles")]
+    if let Some(config) = profile::client_config(host) {
+        return config;
+    }
+    #[cfg(not(feature = "tls-profiles"))]
+    let _ = host;
+    client_config()
+}
+
+/// What every outbound connection checks servers with: the webpki roots,
+/// or `tofu` on them, under the profiles, the chain dump and the allowlist.
+fn verifier() -> Arc<dyn rustls::client::danger::ServerCertVerifier> {
+    static VERIFIER: OnceLock<Arc<dyn rustls::client::danger::ServerCertVerifier>> =
+        OnceLock::new();
+
+    VERIFIER
+        .get_or_init(|| {
+            let roots = rustls::RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            #[cfg(feature = "tofu")]
+            let verifier = crate::tofu::verifier(roots);
+            #[cfg(not(feature = "tofu"))]
+            let verifier: Arc<dyn rustls::client::danger::ServerCertVerifier> =
+                rustls::client::WebPkiServerVerifier::builder_with_provider(
+                    Arc::new(roots),
+                    Arc::new(rustls::crypto::ring::default_provider()),
+                )
+                .build()
+                .expect("the webpki roots aren't empty");
+            #[cfg(feature = "tls-profiles")]
+            let verifier = profile::verifier(verifier);
+            allowlist::verifier(chain::verifier(ocsp::verifier(verifier)))
+        })
+        .clone()
+}
+
+/// `host`, lowercase, against a `host` or `*.domain` pattern.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+        None => host == pattern,
+    }
+}
+
+/// The same provider, protocol versions and client certificate as
+/// `client_config()`, trusting `roots` instead of the webpki ones.
+pub fn client_config_with(roots: rustls::RootCertStore) -> rustls::ClientConfig {
+    let builder = rustls::ClientConfig::builder_with_provider(suites::provider())
+        .with_safe_default_protocol_versions()
+        .expect("suites::configure() checked the suites and groups")
+        .with_root_certificates(roots);
+    #[cfg(feature = "atecc608")]
+    if let Some(resolver) = crate::atecc608::client_cert() {
+        return builder.with_client_cert_resolver(resolver);
+    }
+    #[cfg(any(feature = "aws", feature = "azure"))]
+    if let Some(resolver) = crate::cloud::client_cert() {
+        return builder.with_client_cert_resolver(resolver);
+    }
+    builder.with_no_client_auth()
+}
+
+/// A failed handshake as a `FirmwareError::Tls`. A certificate that
+/// doesn't verify won't on the next try either, unless the clock it was
+/// checked against still has to be set; a dropped connection can pass.
+pub fn failure(err: std::io::Error) -> crate::error::FirmwareError {
+    use crate::error::{Failure, FirmwareError};
+    let rejected = err
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+        .is_some_and(|inner| {
+            matches!(
+                inner,
+                rustls::Error::InvalidCertificate(_)
+                    | rustls::Error::NoCertificatesPresented
+                    | rustls::Error::PeerIncompatible(_)
+            )
+        });
+    FirmwareError::Tls(if rejected && crate::events::state().time_synced {
+        Failure::permanent(err)
+    } else {
+        Failure::retryable(err)
+    })
+}
@@ -0,0 +1,100 @@
+//! The hosts outbound TLS may reach, `tls_allowlist` as `;`-separated
+//! patterns: a host name, or `*.` and a domain for any name under it. It's
+//! checked as the server's certificate is, against the name the client
+//! sent as SNI, so a url that remote config or a command pointed somewhere
+//! else fails its handshake before a byte of application data goes out.
+//! Anything goes while the list is empty.
+
+use crate::{config::Config, metrics};
+use anyhow::{ensure, Result};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    DigitallySignedStruct, SignatureScheme,
+};
+use std::sync::{Arc, RwLock};
+
+static PATTERNS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+/// Reads `tls_allowlist`; can be called again to replace it.
+pub fn configure(config: &Config) -> Result<()> {
+    let mut patterns = Vec::new();
+    for pattern in config.tls_allowlist.split(';').map(str::trim) {
+        if pattern.is_empty() {
+            continue;
+        }
+        let name = pattern.strip_prefix("*.").unwrap_or(pattern);
+        ensure!(
+            !name.is_empty() && !name.contains('*'),
+            "invalid tls_allowlist pattern {pattern}"
+        );
+        patterns.push(pattern.to_ascii_lowercase());
+    }
+    if !patterns.is_empty() {
+        log::info!("tls: outbound limited to {}", patterns.join(", "));
+    }
+    *PATTERNS.write().unwrap() = patterns;
+    Ok(())
+}
+
+/// `inner`, refusing servers outside the allowlist first.
+pub fn verifier(inner: Arc<dyn ServerCertVerifier>) -> Arc<dyn ServerCertVerifier> {
+    Arc::new(Verifier { inner })
+}
+
+fn allowed(host: &str) -> bool {
+    let patterns = PATTERNS.read().unwrap();
+    patterns.is_empty()
+        || patterns
+            .iter()
+            .any(|pattern| super::host_matches(pattern, host))
+}
+
+#[derive(Debug)]
+struct Verifier {
+    inner: Arc<dyn ServerCertVerifier>,
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let host = server_name.to_str().to_ascii_lowercase();
+        if !allowed(&host) {
+            log::warn!("tls: refused {host}, not in tls_allowlist");
+            metrics::TLS_REFUSED.inc(&[("host", &host)]);
+            return Err(rustls::Error::General(format!(
+                "{host} isn't in tls_allowlist"
+            )));
+        }
+        self.inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
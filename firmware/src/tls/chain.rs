@@ -0,0 +1,307 @@
+//! What a server presented when its certificate didn't verify: for every
+//! certificate of the chain the subject, issuer, validity and names, next
+//! to the rustls error, logged the first time a host shows that chain. An
+//! "unknown issuer" from the field then says which CA the server, or a
+//! proxy in front of it, chains to. With `tls_report` at 1 the last one
+//! also goes out with telemetry, as `tls_rejected`.
+//!
+//! The certificates are read with a DER walker of its own, just far enough
+//! for those fields; anything it can't read shows as unreadable.
+
+use crate::{config::Config, metrics, telemetry};
+use anyhow::Result;
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    DigitallySignedStruct, SignatureScheme,
+};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    fmt::Write,
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+pub(super) const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+pub(super) const OID: u8 = 0x06;
+pub(super) const OCTET_STRING: u8 = 0x04;
+pub(super) const VERSION: u8 = 0xa0;
+pub(super) const EXTENSIONS: u8 = 0xa3;
+const DNS_NAME: u8 = 0x82;
+const IP_ADDRESS: u8 = 0x87;
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+/// The name attributes shown, by the last byte of their `2.5.4` OID.
+const ATTRIBUTES: &[(u8, &str)] = &[
+    (3, "CN"),
+    (11, "OU"),
+    (10, "O"),
+    (7, "L"),
+    (8, "ST"),
+    (6, "C"),
+];
+
+static REPORT: AtomicBool = AtomicBool::new(false);
+/// A hash of the end entity last dumped, by host.
+static DUMPED: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+/// Reads `tls_report`.
+pub fn configure(config: &Config) -> Result<()> {
+    REPORT.store(config.tls_report == 1, Ordering::Relaxed);
+    Ok(())
+}
+
+/// `inner`, dumping the chain of every server it rejects.
+pub fn verifier(inner: Arc<dyn ServerCertVerifier>) -> Arc<dyn ServerCertVerifier> {
+    Arc::new(Verifier { inner })
+}
+
+#[derive(Debug)]
+struct Verifier {
+    inner: Arc<dyn ServerCertVerifier>,
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let result = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        );
+        if let Err(err) = &result {
+            rejected(&server_name.to_str(), err, end_entity, intermediates);
+        }
+        result
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+fn rejected(
+    host: &str,
+    err: &rustls::Error,
+    end_entity: &CertificateDer<'_>,
+    intermediates: &[CertificateDer<'_>],
+) {
+    metrics::TLS_REJECTED.inc(&[("host", host)]);
+    let mut hasher = DefaultHasher::new();
+    end_entity.hash(&mut hasher);
+    let seen = hasher.finish();
+    // a poll failing on the same chain every minute logs it once
+    if DUMPED.lock().unwrap().insert(host.to_owned(), seen) == Some(seen) {
+        log::warn!("tls: {host} rejected again: {err}");
+        return;
+    }
+
+    let chain: Vec<String> = std::iter::once(end_entity)
+        .chain(intermediates)
+        .map(|cert| describe(cert).unwrap_or_else(|| String::from("unreadable certificate")))
+        .collect();
+    let mut dump = format!("tls: {host} rejected: {err}");
+    for (at, cert) in chain.iter().enumerate() {
+        let _ = write!(dump, "\n  {at}: {cert}");
+    }
+    log::warn!("{dump}");
+
+    if REPORT.load(Ordering::Relaxed) {
+        telemetry::set(
+            "tls_rejected",
+            serde_json::json!({ "host": host, "error": err.to_string(), "chain": chain }),
+        );
+    }
+}
+
+/// One line for `cert`: subject, issuer, validity and alternative names.
+fn describe(cert: &[u8]) -> Option<String> {
+    let (tag, cert) = Der(cert).next()?;
+    if tag != SEQUENCE {
+        return None;
+    }
+    let (_, tbs) = Der(cert).next()?;
+    let mut tbs = Der(tbs);
+    if tbs.next()?.0 == VERSION {
+        // the serial number
+        tbs.next()?;
+    }
+    let _algorithm = tbs.next()?;
+    let (_, issuer) = tbs.next()?;
+    let (_, validity) = tbs.next()?;
+    let (_, subject) = tbs.next()?;
+    let _key = tbs.next()?;
+    let mut names = Vec::new();
+    for (tag, value) in tbs {
+        if tag == EXTENSIONS {
+            names = alt_names(value)?;
+        }
+    }
+    let mut validity = Der(validity);
+    let not_before = time(validity.next()?.1)?;
+    let not_after = time(validity.next()?.1)?;
+
+    let mut line = format!(
+        "{}, issued by {}, valid {not_before} to {not_after}",
+        name(subject)?,
+        name(issuer)?
+    );
+    if !names.is_empty() {
+        let _ = write!(line, ", for {}", names.join(" "));
+    }
+    Some(line)
+}
+
+/// An X.501 name as `CN=..., O=...`, the attributes it has of `ATTRIBUTES`.
+fn name(name: &[u8]) -> Option<String> {
+    let mut parts = Vec::new();
+    for (tag, set) in Der(name) {
+        if tag != SET {
+            return None;
+        }
+        for (_, attribute) in Der(set) {
+            let mut attribute = Der(attribute);
+            let (tag, oid) = attribute.next()?;
+            let (_, value) = attribute.next()?;
+            let (OID, &[0x55, 0x04, kind]) = (tag, oid) else {
+                continue;
+            };
+            if let Some((_, label)) = ATTRIBUTES.iter().find(|(known, _)| *known == kind) {
+                parts.push(format!("{label}={}", String::from_utf8_lossy(value)));
+            }
+        }
+    }
+    Some(if parts.is_empty() {
+        String::from("(no name)")
+    } else {
+        parts.join(", ")
+    })
+}
+
+/// The DNS names and addresses of the subjectAltName extension, none
+/// without one.
+fn alt_names(extensions: &[u8]) -> Option<Vec<String>> {
+    let (_, extensions) = Der(extensions).next()?;
+    for (_, extension) in Der(extensions) {
+        let mut extension = Der(extension);
+        let (_, oid) = extension.next()?;
+        if oid != SUBJECT_ALT_NAME {
+            continue;
+        }
+        // past the critical flag, if there is one, to the octet string
+        let (_, value) = extension.find(|(tag, _)| *tag == OCTET_STRING)?;
+        let (_, names) = Der(value).next()?;
+        let mut found = Vec::new();
+        for (tag, name) in Der(names) {
+            match (tag, name.len()) {
+                (DNS_NAME, _) => found.push(String::from_utf8_lossy(name).into_owned()),
+                (IP_ADDRESS, 4) => {
+                    let octets: [u8; 4] = name.try_into().ok()?;
+                    found.push(std::net::Ipv4Addr::from(octets).to_string());
+                }
+                (IP_ADDRESS, 16) => {
+                    let octets: [u8; 16] = name.try_into().ok()?;
+                    found.push(std::net::Ipv6Addr::from(octets).to_string());
+                }
+                _ => {}
+            }
+        }
+        return Some(found);
+    }
+    Some(Vec::new())
+}
+
+/// A UTCTime or GeneralizedTime as `YYYY-MM-DD HH:MM:SS`.
+fn time(value: &[u8]) -> Option<String> {
+    let text = std::str::from_utf8(value).ok()?.strip_suffix('Z')?;
+    let full = match text.len() {
+        // UTCTime: years from 50 on are the 1900s
+        12 => {
+            let year: u32 = text[..2].parse().ok()?;
+            format!("{}{text}", if year >= 50 { "19" } else { "20" })
+        }
+        14 => text.to_owned(),
+        _ => return None,
+    };
+    if !full.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    Some(format!(
+        "{}-{}-{} {}:{}:{}",
+        &full[..4],
+        &full[4..6],
+        &full[6..8],
+        &full[8..10],
+        &full[10..12],
+        &full[12..14]
+    ))
+}
+
+/// DER elements one after another, tag and contents.
+pub(super) struct Der<'a>(pub(super) &'a [u8]);
+
+impl<'a> Der<'a> {
+    /// The next element whole, with its tag and length, for what's signed.
+    pub(super) fn raw(&mut self) -> Option<&'a [u8]> {
+        let before = self.0;
+        self.next()?;
+        Some(&before[..before.len() - self.0.len()])
+    }
+}
+
+impl<'a> Iterator for Der<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&tag, rest) = self.0.split_first()?;
+        let (&len, mut rest) = rest.split_first()?;
+        let len = if len & 0x80 == 0 {
+            usize::from(len)
+        } else {
+            let count = usize::from(len & 0x7f);
+            if count == 0 || count > 4 || rest.len() < count {
+                return None;
+            }
+            let (bytes, after) = rest.split_at(count);
+            rest = after;
+            bytes
+                .iter()
+                .fold(0, |len, byte| len << 8 | usize::from(*byte))
+        };
+        if rest.len() < len {
+            return None;
+        }
+        let (value, rest) = rest.split_at(len);
+        self.0 = rest;
+        Some((tag, value))
+    }
+}
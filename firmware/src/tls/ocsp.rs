@@ -0,0 +1,473 @@
+//! Stapled OCSP responses, checked once the chain verified, since fetching
+//! CRLs is out of the question on the device. `tls_ocsp` is `off`, `soft`
+//! or `hard`:
+//!
+//! - `soft`, the default: a certificate whose staple verifies and says it
+//!   was revoked is rejected. A staple that's missing, unreadable, not
+//!   signed by the issuer or out of date is counted and the connection
+//!   goes on, as most servers don't staple at all.
+//! - `hard`: anything short of a verified `good` rejects the certificate,
+//!   for backends known to staple.
+//!
+//! A response counts when the certificate's issuer signed it, or a
+//! responder certificate the issuer signed for OCSP. The issuer has to be
+//! among the intermediates, so a leaf right under a root can't be checked.
+//! Every outcome is counted in `tls_ocsp_total`.
+
+use super::chain::{Der, EXTENSIONS, OCTET_STRING, OID, SEQUENCE, VERSION};
+use crate::{config::Config, metrics};
+use anyhow::{bail, Result};
+use ring::digest;
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    CertificateError, DigitallySignedStruct, SignatureScheme,
+};
+use std::sync::{
+    atomic::{AtomicU8, Ordering},
+    Arc,
+};
+
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const ENUMERATED: u8 = 0x0a;
+const GENERALIZED_TIME: u8 = 0x18;
+/// `[0]`, constructed: what's explicitly tagged 0 in the response.
+const EXPLICIT_0: u8 = 0xa0;
+const GOOD: u8 = 0x80;
+const REVOKED: u8 = 0xa1;
+
+const OCSP_BASIC: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+const SHA1: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+const SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+const EXTENDED_KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x25];
+const OCSP_SIGNING: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x09];
+
+/// How far the responder's clock may be off ours.
+const SLACK_SECS: u64 = 5 * 60;
+/// How old a response without a `nextUpdate` may be.
+const MAX_AGE_SECS: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    Off = 0,
+    Soft = 1,
+    Hard = 2,
+}
+
+static MODE: AtomicU8 = AtomicU8::new(Mode::Soft as u8);
+
+/// Reads `tls_ocsp`; can be called again to change it.
+pub fn configure(config: &Config) -> Result<()> {
+    let mode = match config.tls_ocsp.as_str() {
+        "off" => Mode::Off,
+        "" | "soft" => Mode::Soft,
+        "hard" => Mode::Hard,
+        other => bail!("tls_ocsp {other} isn't off, soft or hard"),
+    };
+    MODE.store(mode as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+fn mode() -> Mode {
+    match MODE.load(Ordering::Relaxed) {
+        0 => Mode::Off,
+        2 => Mode::Hard,
+        _ => Mode::Soft,
+    }
+}
+
+/// `inner`, with the staple checked after it accepted the chain.
+pub fn verifier(inner: Arc<dyn ServerCertVerifier>) -> Arc<dyn ServerCertVerifier> {
+    Arc::new(Verifier { inner })
+}
+
+#[derive(Debug)]
+struct Verifier {
+    inner: Arc<dyn ServerCertVerifier>,
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        let mode = mode();
+        if mode == Mode::Off {
+            return Ok(verified);
+        }
+
+        let host = server_name.to_str();
+        let status = check(end_entity, intermediates, ocsp_response, now.as_secs());
+        let outcome = match &status {
+            Ok(Status::Good) => "good",
+            Ok(Status::Revoked) => "revoked",
+            Ok(Status::Unknown) => "unknown",
+            Err(_) if ocsp_response.is_empty() => "missing",
+            Err(_) => "invalid",
+        };
+        metrics::TLS_OCSP.inc(&[("host", &host), ("status", outcome)]);
+        match status {
+            Ok(Status::Good) => Ok(verified),
+            Ok(Status::Revoked) => {
+                log::warn!("tls: {host} presented a revoked certificate");
+                Err(CertificateError::Revoked.into())
+            }
+            Ok(Status::Unknown) | Err(_) if mode == Mode::Soft => {
+                log::debug!("tls: {host} ocsp {outcome}, let through: {status:?}");
+                Ok(verified)
+            }
+            Ok(Status::Unknown) | Err(_) => {
+                log::warn!("tls: {host} ocsp {outcome}, rejected: {status:?}");
+                Err(CertificateError::UnknownRevocationStatus.into())
+            }
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Status {
+    Good,
+    Revoked,
+    Unknown,
+}
+
+/// What the staple says of `end_entity`, or why it says nothing.
+fn check(
+    end_entity: &[u8],
+    intermediates: &[CertificateDer<'_>],
+    response: &[u8],
+    now: u64,
+) -> Result<Status, &'static str> {
+    if response.is_empty() {
+        return Err("not stapled");
+    }
+    let basic = Basic::parse(response).ok_or("unreadable or unsuccessful response")?;
+    let leaf = Cert::parse(end_entity).ok_or("unreadable certificate")?;
+    let single = basic
+        .single(leaf.serial)
+        .ok_or("no response for the certificate")?;
+    let issuer = intermediates
+        .iter()
+        .filter_map(|cert| Cert::parse(cert))
+        .find(|issuer| single.issued(issuer))
+        .ok_or("issuer not among the intermediates")?;
+    if !basic.signed_for(&issuer) {
+        return Err("not signed by the issuer or its responder");
+    }
+
+    let this_update = seconds(single.this_update).ok_or("unreadable thisUpdate")?;
+    if this_update > now + SLACK_SECS {
+        return Err("issued in the future");
+    }
+    let expired = match single.next_update {
+        Some(next_update) => {
+            seconds(next_update).ok_or("unreadable nextUpdate")? + SLACK_SECS < now
+        }
+        None => this_update + MAX_AGE_SECS < now,
+    };
+    if expired {
+        return Err("out of date");
+    }
+    Ok(match single.status {
+        GOOD => Status::Good,
+        REVOKED => Status::Revoked,
+        _ => Status::Unknown,
+    })
+}
+
+/// The contents of the next element if it's a `tag`.
+fn expect<'a>(der: &mut Der<'a>, tag: u8) -> Option<&'a [u8]> {
+    der.next()
+        .and_then(|(found, value)| (found == tag).then_some(value))
+}
+
+/// A BIT STRING's bits, which are whole bytes in everything read here.
+fn bits(value: &[u8]) -> Option<&[u8]> {
+    match value.split_first()? {
+        (0, bits) => Some(bits),
+        _ => None,
+    }
+}
+
+/// A `BasicOCSPResponse`, out of the `OCSPResponse` around it.
+struct Basic<'a> {
+    /// `tbsResponseData` whole, as it was signed.
+    signed: &'a [u8],
+    /// The `AlgorithmIdentifier` contents.
+    algorithm: &'a [u8],
+    signature: &'a [u8],
+    responses: &'a [u8],
+    certs: Option<&'a [u8]>,
+}
+
+impl<'a> Basic<'a> {
+    fn parse(response: &'a [u8]) -> Option<Self> {
+        let mut outer = Der(expect(&mut Der(response), SEQUENCE)?);
+        // 0 is successful, anything else carries no response
+        if expect(&mut outer, ENUMERATED)? != [0] {
+            return None;
+        }
+        let bytes = expect(&mut outer, EXPLICIT_0)?;
+        let mut bytes = Der(expect(&mut Der(bytes), SEQUENCE)?);
+        if expect(&mut bytes, OID)? != OCSP_BASIC {
+            return None;
+        }
+        let basic = expect(&mut bytes, OCTET_STRING)?;
+        let mut basic = Der(expect(&mut Der(basic), SEQUENCE)?);
+        let signed = basic.raw()?;
+        let algorithm = expect(&mut basic, SEQUENCE)?;
+        let signature = bits(expect(&mut basic, BIT_STRING)?)?;
+        let certs =
+            expect(&mut basic, EXPLICIT_0).and_then(|certs| expect(&mut Der(certs), SEQUENCE));
+
+        let mut data = Der(expect(&mut Der(signed), SEQUENCE)?);
+        // the version, if it's there, then the responder id
+        if data.next()?.0 == VERSION {
+            data.next()?;
+        }
+        let _produced_at = expect(&mut data, GENERALIZED_TIME)?;
+        let responses = expect(&mut data, SEQUENCE)?;
+        Some(Self {
+            signed,
+            algorithm,
+            signature,
+            responses,
+            certs,
+        })
+    }
+
+    /// The `SingleResponse` for the certificate with `serial`.
+    fn single(&self, serial: &[u8]) -> Option<Single<'a>> {
+        for (tag, single) in Der(self.responses) {
+            if tag != SEQUENCE {
+                return None;
+            }
+            let mut single = Der(single);
+            let mut id = Der(expect(&mut single, SEQUENCE)?);
+            let hash = expect(&mut Der(expect(&mut id, SEQUENCE)?), OID)?;
+            let name_hash = expect(&mut id, OCTET_STRING)?;
+            let key_hash = expect(&mut id, OCTET_STRING)?;
+            if expect(&mut id, INTEGER)? != serial {
+                continue;
+            }
+            let (status, _) = single.next()?;
+            let this_update = expect(&mut single, GENERALIZED_TIME)?;
+            let next_update = expect(&mut single, EXPLICIT_0)
+                .and_then(|next_update| expect(&mut Der(next_update), GENERALIZED_TIME));
+            return Some(Single {
+                hash,
+                name_hash,
+                key_hash,
+                status,
+                this_update,
+                next_update,
+            });
+        }
+        None
+    }
+
+    /// Whether `issuer` signed the response, itself or through a responder.
+    fn signed_for(&self, issuer: &Cert<'_>) -> bool {
+        if issuer
+            .key
+            .verifies(self.algorithm, self.signed, self.signature)
+        {
+            return true;
+        }
+        let Some(certs) = self.certs else {
+            return false;
+        };
+        let mut certs = Der(certs);
+        while let Some(cert) = certs.raw() {
+            let Some(responder) = Cert::parse(cert) else {
+                continue;
+            };
+            if responder.ocsp_signing()
+                && issuer
+                    .key
+                    .verifies(responder.algorithm, responder.signed, responder.signature)
+                && responder
+                    .key
+                    .verifies(self.algorithm, self.signed, self.signature)
+            {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+struct Single<'a> {
+    /// The OID of the hash in the `CertID`.
+    hash: &'a [u8],
+    name_hash: &'a [u8],
+    key_hash: &'a [u8],
+    /// The tag of `certStatus`.
+    status: u8,
+    this_update: &'a [u8],
+    next_update: Option<&'a [u8]>,
+}
+
+impl Single<'_> {
+    /// Whether the `CertID` names `issuer` as the issuer.
+    fn issued(&self, issuer: &Cert<'_>) -> bool {
+        let algorithm = match self.hash {
+            SHA1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+            SHA256 => &digest::SHA256,
+            _ => return false,
+        };
+        digest::digest(algorithm, issuer.subject).as_ref() == self.name_hash
+            && digest::digest(algorithm, issuer.key.bits).as_ref() == self.key_hash
+    }
+}
+
+/// The parts of a certificate the check needs.
+struct Cert<'a> {
+    /// `tbsCertificate` whole, as it was signed.
+    signed: &'a [u8],
+    algorithm: &'a [u8],
+    signature: &'a [u8],
+    serial: &'a [u8],
+    /// The subject name whole, as the `CertID` hashes it.
+    subject: &'a [u8],
+    key: Key<'a>,
+    extensions: Option<&'a [u8]>,
+}
+
+impl<'a> Cert<'a> {
+    fn parse(cert: &'a [u8]) -> Option<Self> {
+        let mut outer = Der(expect(&mut Der(cert), SEQUENCE)?);
+        let signed = outer.raw()?;
+        let algorithm = expect(&mut outer, SEQUENCE)?;
+        let signature = bits(expect(&mut outer, BIT_STRING)?)?;
+
+        let mut tbs = Der(expect(&mut Der(signed), SEQUENCE)?);
+        let mut serial = tbs.next()?;
+        if serial.0 == VERSION {
+            serial = tbs.next()?;
+        }
+        if serial.0 != INTEGER {
+            return None;
+        }
+        let _algorithm = tbs.next()?;
+        let _issuer = tbs.next()?;
+        let _validity = tbs.next()?;
+        let subject = tbs.raw()?;
+        let mut spki = Der(expect(&mut tbs, SEQUENCE)?);
+        let key = Key {
+            algorithm: expect(&mut spki, SEQUENCE)?,
+            bits: bits(expect(&mut spki, BIT_STRING)?)?,
+        };
+        let extensions = tbs
+            .find(|(tag, _)| *tag == EXTENSIONS)
+            .map(|(_, extensions)| extensions);
+        Some(Self {
+            signed,
+            algorithm,
+            signature,
+            serial: serial.1,
+            subject,
+            key,
+            extensions,
+        })
+    }
+
+    /// Whether its extended key usage has OCSP signing.
+    fn ocsp_signing(&self) -> bool {
+        let Some(extensions) = self
+            .extensions
+            .and_then(|extensions| expect(&mut Der(extensions), SEQUENCE))
+        else {
+            return false;
+        };
+        Der(extensions).any(|(_, extension)| {
+            let mut extension = Der(extension);
+            expect(&mut extension, OID) == Some(EXTENDED_KEY_USAGE)
+                && extension
+                    .find(|(tag, _)| *tag == OCTET_STRING)
+                    .and_then(|(_, usages)| expect(&mut Der(usages), SEQUENCE))
+                    .is_some_and(|usages| {
+                        Der(usages).any(|(tag, usage)| tag == OID && usage == OCSP_SIGNING)
+                    })
+        })
+    }
+}
+
+struct Key<'a> {
+    /// The `AlgorithmIdentifier` contents of the key.
+    algorithm: &'a [u8],
+    bits: &'a [u8],
+}
+
+impl Key<'_> {
+    /// Whether `signature`, by the `algorithm` identifier contents, over
+    /// `message` is this key's, by the algorithms rustls verifies with.
+    fn verifies(&self, algorithm: &[u8], message: &[u8], signature: &[u8]) -> bool {
+        rustls::crypto::ring::default_provider()
+            .signature_verification_algorithms
+            .all
+            .iter()
+            .any(|verifier| {
+                verifier.public_key_alg_id().as_ref() == self.algorithm
+                    && verifier.signature_alg_id().as_ref() == algorithm
+                    && verifier
+                        .verify_signature(self.bits, message, signature)
+                        .is_ok()
+            })
+    }
+}
+
+/// A GeneralizedTime, `YYYYMMDDHHMMSSZ`, in Unix seconds.
+fn seconds(value: &[u8]) -> Option<u64> {
+    let text = std::str::from_utf8(value).ok()?.strip_suffix('Z')?;
+    if text.len() != 14 || !text.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let field = |at: usize| text[at..at + 2].parse::<u8>().ok();
+    let date = time::Date::from_calendar_date(
+        text[..4].parse().ok()?,
+        time::Month::try_from(field(4)?).ok()?,
+        field(6)?,
+    )
+    .ok()?;
+    let time = time::Time::from_hms(field(8)?, field(10)?, field(12)?).ok()?;
+    date.with_time(time)
+        .assume_utc()
+        .unix_timestamp()
+        .try_into()
+        .ok()
+}
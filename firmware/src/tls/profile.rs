@@ -0,0 +1,291 @@
+//! Named trust settings per host. `tls_profiles` defines them as
+//! `<name>:<option>,...;...`, with the options
+//!
+//! - `ca=<base64 DER>`, as often as needed: trust these roots instead of
+//!   the webpki ones
+//! - `roots=none`: no chain is verified at all, only the pins
+//! - `pin=<hex SHA-256 of the server certificate>`, as often as needed:
+//!   the server must show one of these, on top of a chain that verifies
+//! - `nocert`: leave the device's client certificate out
+//! - `alpn=<protocol>[+<protocol>...]`, `h2+http/1.1` say
+//!
+//! and `tls_routes` sends hosts to them, `<host>=<profile>;...` with the
+//! host patterns `tls_allowlist` takes. The first route that matches wins,
+//! any other host keeps the default trust.
+//!
+//! Trust applies to every client, since it hangs off the server name the
+//! verifier is handed. The client certificate and ALPN are a matter of the
+//! config the connection starts with, which only the clients that dial a
+//! host of their own have, through `tls::client_config_for()`: MQTT, the
+//! WebSocket, the lite HTTP client and the SOCKS relay. reqwest keeps the
+//! shared config and negotiates ALPN itself.
+
+use super::host_matches;
+use anyhow::{bail, ensure, Context, Result};
+use base64::Engine;
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use std::{
+    fmt::Write,
+    sync::{Arc, OnceLock},
+};
+
+struct Profile {
+    name: String,
+    /// `None` for `roots=none`.
+    roots: Option<Arc<WebPkiServerVerifier>>,
+    pins: Vec<String>,
+    client_cert: bool,
+    alpn: Vec<Vec<u8>>,
+    /// Built on first use, the verifier has to exist first.
+    config: OnceLock<Arc<ClientConfig>>,
+}
+
+struct Profiles {
+    profiles: Vec<Profile>,
+    /// Host pattern and the index of its profile.
+    routes: Vec<(String, usize)>,
+}
+
+static PROFILES: OnceLock<Profiles> = OnceLock::new();
+
+/// Parses `tls_profiles` and `tls_routes`, before the first TLS client.
+pub fn configure(config: &crate::config::Config) -> Result<()> {
+    let mut profiles = Vec::new();
+    for entry in config.tls_profiles.split(';').map(str::trim) {
+        if entry.is_empty() {
+            continue;
+        }
+        let profile = parse(entry).with_context(|| {
+            let name = entry.split(':').next().unwrap_or(entry);
+            format!("invalid tls profile {name}")
+        })?;
+        ensure!(
+            !profiles
+                .iter()
+                .any(|known: &Profile| known.name == profile.name),
+            "tls profile {} defined twice",
+            profile.name
+        );
+        profiles.push(profile);
+    }
+
+    let mut routes = Vec::new();
+    for route in config.tls_routes.split(';').map(str::trim) {
+        if route.is_empty() {
+            continue;
+        }
+        let (pattern, name) = route
+            .split_once('=')
+            .with_context(|| format!("tls route {route} isn't <host>=<profile>"))?;
+        let index = profiles
+            .iter()
+            .position(|profile| profile.name == name.trim())
+            .with_context(|| format!("tls route {route} names no profile"))?;
+        routes.push((pattern.trim().to_ascii_lowercase(), index));
+    }
+
+    if !routes.is_empty() {
+        log::info!(
+            "tls: {} profiles for {} routes",
+            profiles.len(),
+            routes.len()
+        );
+    }
+    let _ = PROFILES.set(Profiles { profiles, routes });
+    Ok(())
+}
+
+fn parse(entry: &str) -> Result<Profile> {
+    let (name, options) = entry.split_once(':').unwrap_or((entry, ""));
+    let name = name.trim();
+    ensure!(!name.is_empty(), "a profile needs a name");
+
+    let (mut cas, mut no_roots) = (Vec::new(), false);
+    let mut profile = Profile {
+        name: name.to_owned(),
+        roots: None,
+        pins: Vec::new(),
+        client_cert: true,
+        alpn: Vec::new(),
+        config: OnceLock::new(),
+    };
+    for option in options.split(',').map(str::trim) {
+        match option.split_once('=') {
+            None if option.is_empty() => {}
+            None if option == "nocert" => profile.client_cert = false,
+            Some(("ca", ca)) => cas.push(
+                base64::engine::general_purpose::STANDARD
+                    .decode(ca.trim())
+                    .context("ca isn't base64")?,
+            ),
+            Some(("roots", "none")) => no_roots = true,
+            Some(("roots", "webpki")) => {}
+            Some(("pin", pin)) => {
+                let pin = pin.trim().to_ascii_lowercase();
+                ensure!(
+                    pin.len() == 64 && pin.bytes().all(|byte| byte.is_ascii_hexdigit()),
+                    "pin {pin} isn't a hex SHA-256"
+                );
+                profile.pins.push(pin);
+            }
+            Some(("alpn", protocols)) => {
+                profile.alpn = protocols
+                    .split('+')
+                    .map(|protocol| protocol.trim().as_bytes().to_vec())
+                    .collect();
+            }
+            _ => bail!("unknown option {option}"),
+        }
+    }
+
+    if no_roots {
+        ensure!(cas.is_empty(), "roots=none and ca contradict each other");
+        ensure!(
+            !profile.pins.is_empty(),
+            "roots=none takes at least one pin"
+        );
+    } else {
+        let mut roots = if cas.is_empty() {
+            RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            }
+        } else {
+            RootCertStore::empty()
+        };
+        for ca in cas {
+            roots.add(CertificateDer::from(ca)).context("invalid ca")?;
+        }
+        profile.roots = Some(
+            WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider())
+                .build()
+                .context("couldn't build the verifier")?,
+        );
+    }
+    Ok(profile)
+}
+
+fn route(host: &str) -> Option<&'static Profile> {
+    let profiles = PROFILES.get()?;
+    let host = host.to_ascii_lowercase();
+    profiles
+        .routes
+        .iter()
+        .find(|(pattern, _)| host_matches(pattern, &host))
+        .map(|(_, index)| &profiles.profiles[*index])
+}
+
+/// The config for connections to `host`, when it's routed to a profile.
+pub fn client_config(host: &str) -> Option<Arc<ClientConfig>> {
+    let profile = route(host)?;
+    Some(
+        profile
+            .config
+            .get_or_init(|| {
+                let mut config = if profile.client_cert {
+                    super::client_config_with(RootCertStore::empty())
+                } else {
+                    ClientConfig::builder_with_provider(provider())
+                        .with_safe_default_protocol_versions()
+                        .expect("ring supports the default protocol versions")
+                        .with_root_certificates(RootCertStore::empty())
+                        .with_no_client_auth()
+                };
+                config.alpn_protocols = profile.alpn.clone();
+                // the routing verifier, so the profile's trust applies
+                config
+                    .dangerous()
+                    .set_certificate_verifier(super::verifier());
+                Arc::new(config)
+            })
+            .clone(),
+    )
+}
+
+/// `default`, except for hosts routed to a profile, which are checked
+/// against its roots and pins instead.
+pub fn verifier(default: Arc<dyn ServerCertVerifier>) -> Arc<dyn ServerCertVerifier> {
+    Arc::new(Verifier { default })
+}
+
+#[derive(Debug)]
+struct Verifier {
+    default: Arc<dyn ServerCertVerifier>,
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let Some(profile) = route(&server_name.to_str()) else {
+            return self.default.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            );
+        };
+
+        if let Some(roots) = &profile.roots {
+            roots.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+        if !profile.pins.is_empty() && !profile.pins.contains(&fingerprint(end_entity)) {
+            return Err(rustls::Error::General(format!(
+                "{} doesn't show a certificate tls profile {} pins",
+                server_name.to_str(),
+                profile.name
+            )));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    // signatures only depend on the algorithms, the same for every profile
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.default.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.default.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.default.supported_verify_schemes()
+    }
+}
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    super::suites::provider()
+}
+
+fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, cert.as_ref());
+    digest
+        .as_ref()
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
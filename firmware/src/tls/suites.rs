@@ -0,0 +1,134 @@
+//! The cipher suites and key exchange groups TLS clients offer, by
+//! `tls_ciphers` and `tls_groups`: `;`-separated rustls names in order of
+//! preference, such as `TLS13_AES_128_GCM_SHA256` and `X25519`, case
+//! doesn't matter. Either left empty keeps ring's defaults. Narrowing down
+//! to X25519 and AES-128-GCM saves the P-256 key share and the larger
+//! ciphers' work on every handshake, at the price of the servers that
+//! don't offer them.
+//!
+//! What each connection settles on is logged and counted in
+//! `tls_negotiated_total` by `observe()`, which the clients holding their
+//! session call after the handshake; reqwest keeps its sessions to itself,
+//! so its connections aren't among them.
+
+use crate::{
+    config::Config,
+    flags::{self, Flag},
+    metrics,
+};
+use anyhow::{bail, Context, Result};
+use rustls::{
+    crypto::{ring, CryptoProvider, SupportedKxGroup},
+    CommonState, SupportedCipherSuite,
+};
+use std::sync::{Arc, Mutex};
+
+static SUITES: Mutex<Option<Vec<SupportedCipherSuite>>> = Mutex::new(None);
+static GROUPS: Mutex<Option<Vec<&'static dyn SupportedKxGroup>>> = Mutex::new(None);
+
+/// Reads `tls_ciphers` and `tls_groups`, before the first client config is
+/// built; the configs already made keep what they had. With the
+/// `tls_suites` flag off both are left at the defaults.
+pub fn configure(config: &Config) -> Result<()> {
+    let (ciphers, groups) = if flags::on(Flag::TlsSuites) {
+        (config.tls_ciphers.as_str(), config.tls_groups.as_str())
+    } else {
+        ("", "")
+    };
+    let suites = pick("tls_ciphers", ciphers, ring::ALL_CIPHER_SUITES, |suite| {
+        format!("{:?}", suite.suite())
+    })?;
+    let groups = pick("tls_groups", groups, ring::ALL_KX_GROUPS, |group| {
+        format!("{:?}", group.name())
+    })?;
+
+    // the same check the client configs would panic on
+    rustls::ClientConfig::builder_with_provider(restricted(suites.as_deref(), groups.as_deref()))
+        .with_safe_default_protocol_versions()
+        .context("tls_ciphers and tls_groups leave nothing to handshake with")?;
+
+    *SUITES.lock().unwrap() = suites;
+    *GROUPS.lock().unwrap() = groups;
+    Ok(())
+}
+
+/// The entries of `all` that `value` names, in its order; `None` when it's
+/// empty.
+fn pick<T: Copy>(
+    field: &str,
+    value: &str,
+    all: &[T],
+    name: impl Fn(&T) -> String,
+) -> Result<Option<Vec<T>>> {
+    let mut picked = Vec::new();
+    for wanted in value
+        .split(';')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        let Some(entry) = all
+            .iter()
+            .find(|entry| name(entry).eq_ignore_ascii_case(wanted))
+        else {
+            let known: Vec<String> = all.iter().map(&name).collect();
+            bail!("{field}: no {wanted}, there's {}", known.join(", "));
+        };
+        picked.push(*entry);
+    }
+    Ok((!picked.is_empty()).then_some(picked))
+}
+
+/// ring with only the configured suites and groups.
+pub fn provider() -> Arc<CryptoProvider> {
+    restricted(
+        SUITES.lock().unwrap().as_deref(),
+        GROUPS.lock().unwrap().as_deref(),
+    )
+}
+
+fn restricted(
+    suites: Option<&[SupportedCipherSuite]>,
+    groups: Option<&[&'static dyn SupportedKxGroup]>,
+) -> Arc<CryptoProvider> {
+    let mut provider = ring::default_provider();
+    if let Some(suites) = suites {
+        provider.cipher_suites = suites.to_vec();
+    }
+    if let Some(groups) = groups {
+        provider.kx_groups = groups.to_vec();
+    }
+    Arc::new(provider)
+}
+
+/// What a handshake settled on, as the benchmark reports it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Negotiated {
+    pub suite: String,
+    pub group: String,
+}
+
+/// Logs and counts what `session` negotiated.
+pub fn observe(client: &str, host: &str, session: &CommonState) -> Negotiated {
+    let negotiated = Negotiated {
+        suite: session.negotiated_cipher_suite().map_or_else(
+            || String::from("none"),
+            |suite| format!("{:?}", suite.suite()),
+        ),
+        // none for a TLS 1.2 resumption, which doesn't exchange keys again
+        group: session.negotiated_key_exchange_group().map_or_else(
+            || String::from("none"),
+            |group| format!("{:?}", group.name()),
+        ),
+    };
+    log::info!(
+        "{client}: {host} negotiated {} over {}",
+        negotiated.suite,
+        negotiated.group
+    );
+    metrics::TLS_NEGOTIATED.inc(&[
+        ("client", client),
+        ("suite", &negotiated.suite),
+        ("group", &negotiated.group),
+    ]);
+    negotiated
+}
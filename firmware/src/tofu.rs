@@ -0,0 +1,214 @@
+//! Trust on first use, for devices that may boot without any network time.
+//! Once a certificate has been verified with the clock synced, the server's
+//! end-entity certificate fingerprint is pinned by host, and the latest
+//! `Date` an HTTPS server answered with is kept, both on flash.
+//!
+//! Until NTP succeeds on a later boot, a server is only trusted if it shows
+//! the pinned certificate, and the chain still has to verify at the kept
+//! date rather than the 1970 the clock starts at: a certificate that had
+//! already expired by the last contact stays rejected. A server that has
+//! since rotated its certificate is waited out until the clock is set.
+
+use crate::{events, fs, runtime};
+use anyhow::Result;
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+const FILE: &str = "tofu.json";
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Record {
+    /// Unix seconds, 0 before any server was heard from.
+    date: u64,
+    /// Hex SHA-256 of the end-entity certificate, by host.
+    pins: BTreeMap<String, String>,
+}
+
+static RECORD: Mutex<Option<Record>> = Mutex::new(None);
+
+/// Reads what earlier boots recorded, before the first TLS client is built.
+pub async fn load() {
+    let record = match fs::read(FILE).await {
+        Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|err| {
+            log::warn!("tofu: ignoring {FILE}: {err}");
+            Record::default()
+        }),
+        Err(_) => Record::default(),
+    };
+    if record.date > 0 {
+        log::info!(
+            "tofu: {} pinned hosts, last server date {}",
+            record.pins.len(),
+            record.date
+        );
+    }
+    *RECORD.lock().unwrap() = Some(record);
+}
+
+/// Whether an earlier boot left enough to verify servers without a clock.
+pub fn bootstrapped() -> bool {
+    RECORD
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|record| record.date > 0 && !record.pins.is_empty())
+}
+
+/// Keeps `value`, a `Date` header from a verified HTTPS response, if it's
+/// later than what is kept already.
+pub fn record_date(value: &str) {
+    let Some(date) = crate::clock::parse_http_date(value) else {
+        log::debug!("tofu: unparsable date {value}");
+        return;
+    };
+    update(|record| {
+        if date <= record.date {
+            return false;
+        }
+        record.date = date;
+        true
+    });
+}
+
+/// The webpki verifier over `roots`, bounded as above while the clock isn't
+/// synced.
+pub fn verifier(roots: RootCertStore) -> Arc<dyn ServerCertVerifier> {
+    let inner = WebPkiServerVerifier::builder_with_provider(
+        Arc::new(roots),
+        Arc::new(rustls::crypto::ring::default_provider()),
+    )
+    .build()
+    .expect("the webpki roots aren't empty");
+    Arc::new(Verifier { inner })
+}
+
+#[derive(Debug)]
+struct Verifier {
+    inner: Arc<WebPkiServerVerifier>,
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let host = server_name.to_str().into_owned();
+        let fingerprint = fingerprint(end_entity);
+
+        if events::state().time_synced {
+            let verified = self.inner.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            )?;
+            update(|record| {
+                let pinned = record.pins.get(&host) == Some(&fingerprint);
+                if !pinned {
+                    log::info!("tofu: pinned {host}");
+                    record.pins.insert(host, fingerprint);
+                }
+                !pinned
+            });
+            return Ok(verified);
+        }
+
+        let (date, pinned) = match RECORD.lock().unwrap().as_ref() {
+            Some(record) => (record.date, record.pins.get(&host).cloned()),
+            None => (0, None),
+        };
+        if date == 0 {
+            return self.inner.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            );
+        }
+        if pinned.as_ref() != Some(&fingerprint) {
+            return Err(rustls::Error::General(format!(
+                "{host} doesn't show its pinned certificate, waiting for the clock"
+            )));
+        }
+        let floor = UnixTime::since_unix_epoch(Duration::from_secs(date));
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now.max(floor),
+        )
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, cert.as_ref());
+    digest
+        .as_ref()
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+/// Applies `change` and writes the record out if it says it changed it.
+fn update(change: impl FnOnce(&mut Record) -> bool) {
+    let mut guard = RECORD.lock().unwrap();
+    let record = guard.get_or_insert_with(Record::default);
+    if !change(record) {
+        return;
+    }
+    let record = record.clone();
+    drop(guard);
+    runtime::spawn(async move {
+        if let Err(err) = save(&record).await {
+            log::warn!("tofu: couldn't save {FILE}: {err:#}");
+        }
+    });
+}
+
+async fn save(record: &Record) -> Result<()> {
+    Ok(fs::write(FILE, serde_json::to_vec(record)?).await?)
+}
@@ -0,0 +1,61 @@
+//! With `warmup` at 1, every time the network comes up with the clock set
+//! the configured backend hosts are looked up into the DNS cache and a
+//! connection to the download host is opened, TLS and all, and left idle in
+//! the fetch client's pool. The first fetch, often a user's, then costs
+//! neither the lookup nor the handshake.
+
+use crate::{config::Config, dns, events, runtime};
+
+pub fn start(config: &Config) {
+    if config.warmup == 0 {
+        return;
+    }
+
+    let mut hosts: Vec<String> = Vec::new();
+    let urls = [
+        &config.download_url,
+        &config.ws_url,
+        &config.sse_url,
+        &config.grpc_url,
+        &config.config_url,
+        &config.geo_api_url,
+        &config.influx_url,
+        &config.cloud_url,
+    ];
+    for host in urls
+        .into_iter()
+        .filter_map(|url| crate::http::host(url))
+        .chain(Some(config.mqtt_broker.as_str()).filter(|host| !host.is_empty()))
+    {
+        if !hosts.iter().any(|known| known == host) {
+            hosts.push(host.to_owned());
+        }
+    }
+    #[cfg_attr(not(feature = "http-reqwest"), allow(unused_variables))]
+    let url = config.download_url.clone();
+
+    runtime::spawn(async move {
+        loop {
+            events::wait_until(|state| state.net_up && state.time_synced).await;
+            for host in &hosts {
+                if let Err(err) = dns::resolve_addrs(host, 0).await {
+                    log::warn!("warm-up couldn't resolve {host}: {err:#}");
+                }
+            }
+            // the lite client has no pool to leave a connection in
+            #[cfg(feature = "http-reqwest")]
+            match connect(&url).await {
+                Ok(()) => log::info!("warm-up connected to {url}"),
+                Err(err) => log::warn!("warm-up couldn't connect to {url}: {err:#}"),
+            }
+            events::wait_until(|state| !state.net_up).await;
+        }
+    });
+}
+
+/// A HEAD request, for the connection it leaves in the pool.
+#[cfg(feature = "http-reqwest")]
+async fn connect(url: &str) -> anyhow::Result<()> {
+    crate::http::shared()?.head(url).send().await?;
+    Ok(())
+}
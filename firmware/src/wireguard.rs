@@ -0,0 +1,150 @@
+use crate::{config::Config, events, runtime, secret::Secret, telemetry};
+use anyhow::{bail, Context, Result};
+use esp_idf_sys::{self as sys, esp, wireguard};
+use std::{
+    ffi::CString,
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+const DEFAULT_PORT: u16 = 51820;
+const KEEPALIVE: Duration = Duration::from_secs(25);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(15);
+const PEER_POLL: Duration = Duration::from_secs(1);
+const HEALTH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Tunnel settings from the config store, as the C strings esp_wireguard
+/// keeps pointers to for as long as the tunnel is up.
+#[derive(Clone)]
+struct Settings {
+    private_key: Secret<CString>,
+    peer_key: CString,
+    endpoint: CString,
+    port: u16,
+    address: CString,
+    netmask: CString,
+}
+
+/// Brings up a WireGuard tunnel once the network is up and makes it the
+/// default route, so backend traffic goes through the tunnel while local
+/// services stay reachable on the LAN. The session restarts whenever the
+/// peer stops answering or the network drops.
+pub fn start(config: &Config) -> Result<()> {
+    let settings = Settings::parse(config)?;
+    runtime::spawn_named("wireguard", move || session(settings.clone()));
+    Ok(())
+}
+
+impl Settings {
+    fn parse(config: &Config) -> Result<Self> {
+        let (endpoint, port) = match config.wg_endpoint.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().context("invalid wg_endpoint port")?),
+            None => (config.wg_endpoint.as_str(), DEFAULT_PORT),
+        };
+        let (address, prefix) = config
+            .wg_address
+            .split_once('/')
+            .context("wg_address must be ip/prefix")?;
+        let address: Ipv4Addr = address.parse().context("invalid wg_address")?;
+        let prefix: u32 = prefix.parse().context("invalid wg_address prefix")?;
+        if prefix > 32 {
+            bail!("invalid wg_address prefix {prefix}");
+        }
+        let netmask = Ipv4Addr::from(u32::MAX.checked_shl(32 - prefix).unwrap_or(0));
+
+        Ok(Self {
+            private_key: Secret::new(CString::new(config.wg_private_key.expose().as_str())?),
+            peer_key: CString::new(config.wg_peer_key.as_str())?,
+            endpoint: CString::new(endpoint)?,
+            port,
+            address: CString::new(address.to_string())?,
+            netmask: CString::new(netmask.to_string())?,
+        })
+    }
+}
+
+/// esp_wireguard's context plus the config it points into.
+struct Tunnel {
+    _settings: Settings,
+    // boxed so the pointer esp_wireguard keeps to it stays valid
+    _config: Box<wireguard::wireguard_config_t>,
+    ctx: wireguard::wireguard_ctx_t,
+}
+
+// the context only holds lwIP netif pointers, which lwIP's own thread
+// owns; the tunnel is only ever driven from one task at a time
+unsafe impl Send for Tunnel {}
+
+impl Tunnel {
+    fn connect(settings: Settings) -> Result<Self> {
+        let mut config = Box::new(wireguard::wireguard_config_t {
+            private_key: settings.private_key.expose().as_ptr() as *mut _,
+            listen_port: DEFAULT_PORT as _,
+            public_key: settings.peer_key.as_ptr() as *mut _,
+            allowed_ip: settings.address.as_ptr() as *mut _,
+            allowed_ip_mask: settings.netmask.as_ptr() as *mut _,
+            endpoint: settings.endpoint.as_ptr() as *mut _,
+            port: settings.port as _,
+            persistent_keepalive: KEEPALIVE.as_secs() as _,
+            ..Default::default()
+        });
+        let mut ctx = wireguard::wireguard_ctx_t::default();
+
+        esp!(unsafe { wireguard::esp_wireguard_init(&mut *config, &mut ctx) })
+            .context("couldn't init wireguard")?;
+        let mut tunnel = Self {
+            _settings: settings,
+            _config: config,
+            ctx,
+        };
+        esp!(unsafe { wireguard::esp_wireguard_connect(&mut tunnel.ctx) })
+            .context("couldn't start wireguard")?;
+        Ok(tunnel)
+    }
+
+    fn peer_up(&mut self) -> bool {
+        unsafe { wireguard::esp_wireguardif_peer_is_up(&mut self.ctx) == sys::ESP_OK }
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        unsafe { wireguard::esp_wireguard_disconnect(&mut self.ctx) };
+        telemetry::set("wireguard", "down");
+    }
+}
+
+async fn session(settings: Settings) -> Result<()> {
+    events::wait_until(|state| state.net_up).await;
+    // handshakes carry a timestamp the peer rejects unless it only ever grows
+    events::wait_until(|state| state.time_synced).await;
+
+    let endpoint = settings.endpoint.to_string_lossy().into_owned();
+    let mut tunnel = Tunnel::connect(settings)?;
+
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    while !tunnel.peer_up() {
+        if Instant::now() >= deadline {
+            bail!("no handshake from wireguard peer {endpoint}");
+        }
+        runtime::sleep(PEER_POLL).await;
+    }
+
+    esp!(unsafe { wireguard::esp_wireguard_set_default(&mut tunnel.ctx) })
+        .context("couldn't route through the tunnel")?;
+    log::info!("wireguard tunnel to {endpoint} up");
+    telemetry::set("wireguard", "up");
+
+    loop {
+        tokio::select! {
+            _ = events::wait_until(|state| !state.net_up) => {
+                bail!("network down, dropping the tunnel");
+            }
+            _ = runtime::sleep(HEALTH_INTERVAL) => {
+                if !tunnel.peer_up() {
+                    bail!("wireguard peer {endpoint} went away");
+                }
+            }
+        }
+    }
+}
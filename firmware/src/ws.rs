@@ -0,0 +1,80 @@
+use crate::{
+    config::Config,
+    events::{self, Event},
+    logtail,
+    net::socks,
+    runtime, tls,
+};
+use anyhow::{bail, Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use std::time::Duration;
+use tokio_tungstenite::{
+    tungstenite::{client::IntoClientRequest, Message},
+    Connector,
+};
+
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Holds a persistent WebSocket to the backend; text frames are published
+/// as commands, and `logtail` batches go out as text frames. The session
+/// is restarted by the task table whenever it drops or a ping goes
+/// unanswered.
+pub fn start(config: &Config) {
+    let url = config.ws_url.clone();
+    runtime::spawn_named("websocket", move || session(url.clone()));
+}
+
+async fn session(url: String) -> Result<()> {
+    events::wait_until(|state| state.net_up).await;
+    let secure = url.starts_with("wss://");
+    if secure {
+        events::wait_until(|state| state.time_synced).await;
+    }
+
+    let request = url.as_str().into_client_request()?;
+    let host = request.uri().host().context("websocket url has no host")?;
+    let port = request
+        .uri()
+        .port_u16()
+        .unwrap_or(if secure { 443 } else { 80 });
+    let stream = socks::connect(host, port).await?;
+
+    let boost = crate::power::boost();
+    let (mut socket, _) = tokio_tungstenite::client_async_tls_with_config(
+        request,
+        stream,
+        None,
+        Some(Connector::Rustls(tls::client_config_for(host))),
+    )
+    .await?;
+    drop(boost);
+    log::info!("websocket connected to {url}");
+
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    let mut awaiting_pong = false;
+    let mut logs = logtail::subscribe();
+
+    loop {
+        tokio::select! {
+            _ = ping.tick() => {
+                if awaiting_pong {
+                    bail!("websocket pong timed out");
+                }
+                socket.send(Message::Ping(Default::default())).await?;
+                awaiting_pong = true;
+            }
+            // a lagged receiver just skips what it missed
+            Ok(batch) = logs.recv() => socket.send(Message::Text(batch.as_ref().into())).await?,
+            message = socket.next() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    events::publish(Event::Command(text.as_str().to_owned()));
+                }
+                Some(Ok(Message::Pong(_))) => awaiting_pong = false,
+                Some(Ok(Message::Close(frame))) => bail!("websocket closed: {frame:?}"),
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Err(err.into()),
+                None => bail!("websocket stream ended"),
+            }
+        }
+    }
+}
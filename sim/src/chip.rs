@@ -0,0 +1,18 @@
+/// The subset of the firmware's `Capabilities` the shared modules read.
+pub struct Capabilities {
+    pub name: &'static str,
+    pub psram: bool,
+    pub image_id: u16,
+}
+
+/// Takes the images an S3 would, for the image check.
+pub const CHIP: Capabilities = Capabilities {
+    name: "host",
+    psram: false,
+    image_id: 9,
+};
+
+/// v1.0, inside the range of an image that gives none.
+pub fn revision() -> u16 {
+    100
+}
@@ -0,0 +1,26 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+pub fn id() -> &'static str {
+    "esp32-sim"
+}
+
+pub fn uptime() -> Duration {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed()
+}
+
+/// std's randomly keyed hasher stands in for the hardware RNG.
+pub fn random() -> u32 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(uptime().as_nanos() as u64);
+    hasher.finish() as u32
+}
+
+pub fn firmware_version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
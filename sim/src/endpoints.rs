@@ -0,0 +1,64 @@
+//! The host and port telemetry endpoints are probed at, out of their urls.
+
+use crate::telemetry::endpoints::address;
+
+#[test]
+fn takes_the_scheme_port_by_default() {
+    assert_eq!(
+        address("https://example.com/write"),
+        Some(("example.com", 443))
+    );
+    assert_eq!(address("http://example.com"), Some(("example.com", 80)));
+    assert_eq!(address("udp://collector?x=1"), Some(("collector", 80)));
+}
+
+#[test]
+fn takes_an_explicit_port() {
+    assert_eq!(
+        address("https://example.com:8443/api/v2/write?org=a"),
+        Some(("example.com", 8443))
+    );
+    assert_eq!(
+        address("http://10.0.0.5:8086#top"),
+        Some(("10.0.0.5", 8086))
+    );
+}
+
+#[test]
+fn skips_userinfo() {
+    assert_eq!(
+        address("https://user:p@ss@example.com:9000/"),
+        Some(("example.com", 9000))
+    );
+    assert_eq!(
+        address("http://token@example.com"),
+        Some(("example.com", 80))
+    );
+}
+
+#[test]
+fn unbrackets_ipv6() {
+    assert_eq!(
+        address("https://[2001:db8::1]/"),
+        Some(("2001:db8::1", 443))
+    );
+    assert_eq!(
+        address("http://user@[fe80::1]:8080/write"),
+        Some(("fe80::1", 8080))
+    );
+}
+
+#[test]
+fn refuses_what_isnt_an_address() {
+    for url in [
+        "example.com",
+        "https://",
+        "https:///path",
+        "https://example.com:http/",
+        "https://example.com:70000",
+        "https://[2001:db8::1/",
+        "https://user@",
+    ] {
+        assert_eq!(address(url), None, "{url}");
+    }
+}
@@ -0,0 +1,63 @@
+use crate::http::{Consumer, Head, HttpFetcher};
+use anyhow::{Context, Result};
+use std::time::Duration;
+
+/// About what a small HTTPS fetch takes over WiFi.
+const LATENCY: Duration = Duration::from_millis(200);
+
+/// Answers `sim://<body>` urls with the body after `LATENCY`, and
+/// `sim://fail` with an error, for the fetch path without a server.
+pub struct Canned;
+
+impl HttpFetcher for Canned {
+    async fn fetch<C: Consumer>(&self, url: &str, consumer: &mut C) -> Result<()> {
+        tokio::time::sleep(LATENCY).await;
+        let body = url.strip_prefix("sim://").context("not a sim url")?;
+        anyhow::ensure!(body != "fail", "{url} failed, as asked");
+        consumer.head(&Head::new(200, [("content-type", "text/plain")]))?;
+        consumer.chunk(body.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Consumer;
+
+    #[derive(Default)]
+    struct Body {
+        status: Option<u16>,
+        bytes: Vec<u8>,
+    }
+
+    impl Consumer for Body {
+        fn chunk(&mut self, chunk: &[u8]) -> Result<()> {
+            self.bytes.extend_from_slice(chunk);
+            Ok(())
+        }
+
+        fn head(&mut self, head: &Head) -> Result<()> {
+            self.status = Some(head.status);
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn serves_the_body_in_the_url() {
+        let mut body = Body::default();
+        Canned.fetch("sim://hello", &mut body).await.unwrap();
+        assert_eq!(body.status, Some(200));
+        assert_eq!(body.bytes, b"hello");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fails_when_asked() {
+        let mut body = Body::default();
+        assert!(Canned.fetch("sim://fail", &mut body).await.is_err());
+        assert!(Canned
+            .fetch("https://example.com", &mut body)
+            .await
+            .is_err());
+        assert_eq!(body.status, None);
+    }
+}
@@ -0,0 +1,58 @@
+//! The storage partition as a directory on the host, `sim-data/` or
+//! `SIM_DATA`. The helpers behave like the firmware's.
+
+use crate::{
+    error::{Failure, FirmwareError},
+    runtime,
+};
+use anyhow::{Context, Result};
+use std::{path::PathBuf, sync::OnceLock};
+
+const DEFAULT_ROOT: &str = "sim-data";
+
+fn root() -> &'static PathBuf {
+    static ROOT: OnceLock<PathBuf> = OnceLock::new();
+    ROOT.get_or_init(|| {
+        std::env::var_os("SIM_DATA")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_ROOT))
+    })
+}
+
+pub fn mount() -> Result<()> {
+    std::fs::create_dir_all(root()).with_context(|| format!("couldn't create {:?}", root()))?;
+    log::info!("storage in {:?}", root());
+    Ok(())
+}
+
+pub fn path(name: &str) -> PathBuf {
+    root().join(name)
+}
+
+pub async fn read(name: &str) -> Result<Vec<u8>, FirmwareError> {
+    let path = path(name);
+    runtime::run_blocking(move || std::fs::read(&path).with_context(|| format!("{path:?}")))
+        .await
+        .and_then(|result| result)
+        .map_err(|err| FirmwareError::Storage(Failure::io(err)))
+}
+
+pub async fn write(name: &str, data: Vec<u8>) -> Result<(), FirmwareError> {
+    let path = path(name);
+    runtime::run_blocking(move || {
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, data)?;
+        std::fs::rename(&temporary, &path).with_context(|| format!("{path:?}"))
+    })
+    .await
+    .and_then(|result| result)
+    .map_err(|err| FirmwareError::Storage(Failure::io(err)))
+}
+
+pub async fn remove(name: &str) -> Result<(), FirmwareError> {
+    let path = path(name);
+    runtime::run_blocking(move || std::fs::remove_file(&path).with_context(|| format!("{path:?}")))
+        .await
+        .and_then(|result| result)
+        .map_err(|err| FirmwareError::Storage(Failure::io(err)))
+}
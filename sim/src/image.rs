@@ -0,0 +1,155 @@
+//! The firmware's image check against app images laid out the way
+//! `esptool elf2image` writes them: the header, a first segment led by the
+//! app descriptor, a second one of code, the checksum and the digest.
+
+use crate::{chip::CHIP, firmware::ota::ImageCheck};
+use anyhow::Result;
+
+const DESC_MAGIC: u32 = 0xabcd_5432;
+/// How far the checksum is from the end of `Image::bytes()`, the digest
+/// after it.
+const CHECKSUM_FROM_END: usize = 33;
+
+struct Image {
+    chip_id: u16,
+    /// `major * 100 + minor`, 0 for no upper bound.
+    max_revision: u16,
+    project: &'static str,
+}
+
+impl Default for Image {
+    fn default() -> Self {
+        Self {
+            chip_id: CHIP.image_id,
+            max_revision: 0,
+            project: env!("CARGO_PKG_NAME"),
+        }
+    }
+}
+
+fn padded<const N: usize>(text: &str) -> [u8; N] {
+    let mut field = [0; N];
+    field[..text.len()].copy_from_slice(text.as_bytes());
+    field
+}
+
+impl Image {
+    fn bytes(&self) -> Vec<u8> {
+        let mut desc = Vec::with_capacity(256);
+        desc.extend_from_slice(&DESC_MAGIC.to_le_bytes());
+        desc.extend_from_slice(&[0; 12]); // secure version, reserved
+        desc.extend_from_slice(&padded::<32>("0.1.0+1a2b3c4d5e"));
+        desc.extend_from_slice(&padded::<32>(self.project));
+        desc.extend_from_slice(&padded::<16>("13:48:00"));
+        desc.extend_from_slice(&padded::<16>("Oct 14 2026"));
+        desc.extend_from_slice(&padded::<32>("5.3.2"));
+        desc.resize(256, 0);
+        let code: Vec<u8> = (0..1000u32).map(|at| (at * 7 + 3) as u8).collect();
+        let segments = [(0x3c02_0020u32, desc), (0x4200_0020, code)];
+
+        let mut image = vec![0xe9, segments.len() as u8, 0x02, 0x20];
+        image.extend_from_slice(&0x4037_5a3cu32.to_le_bytes());
+        image.extend_from_slice(&[0xee, 0, 0, 0]);
+        image.extend_from_slice(&self.chip_id.to_le_bytes());
+        image.push(0);
+        image.extend_from_slice(&0u16.to_le_bytes());
+        image.extend_from_slice(&self.max_revision.to_le_bytes());
+        image.extend_from_slice(&[0; 4]);
+        image.push(1); // hash appended
+
+        let mut checksum = 0xef;
+        for (load, data) in &segments {
+            image.extend_from_slice(&load.to_le_bytes());
+            image.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            image.extend_from_slice(data);
+            checksum = data.iter().fold(checksum, |sum, byte| sum ^ byte);
+        }
+        while image.len() % 16 != 15 {
+            image.push(0);
+        }
+        image.push(checksum);
+        let digest = ring::digest::digest(&ring::digest::SHA256, &image);
+        image.extend_from_slice(digest.as_ref());
+        image
+    }
+}
+
+/// `image` fed through the check `chunk` bytes at a time.
+fn check(image: &[u8], chunk: usize) -> Result<()> {
+    let mut check = ImageCheck::new();
+    for chunk in image.chunks(chunk) {
+        check.update(chunk)?;
+    }
+    check.finish()
+}
+
+fn error(image: &[u8]) -> String {
+    format!("{:#}", check(image, 64).unwrap_err())
+}
+
+#[test]
+fn takes_an_image_in_any_chunks() {
+    let image = Image::default().bytes();
+    for chunk in [1, 7, 24, 64, 4096] {
+        check(&image, chunk).unwrap();
+    }
+}
+
+#[test]
+fn refuses_another_chip() {
+    let image = Image {
+        chip_id: 5,
+        ..Image::default()
+    }
+    .bytes();
+    assert!(error(&image).contains("chip id 5"));
+}
+
+#[test]
+fn refuses_a_chip_revision_too_new() {
+    let image = Image {
+        max_revision: 99,
+        ..Image::default()
+    }
+    .bytes();
+    assert!(error(&image).contains("chip revisions"));
+}
+
+#[test]
+fn refuses_another_project() {
+    let image = Image {
+        project: "something-else",
+        ..Image::default()
+    }
+    .bytes();
+    assert!(error(&image).contains("something-else"));
+}
+
+#[test]
+fn refuses_a_bad_magic() {
+    let mut image = Image::default().bytes();
+    image[0] = 0xea;
+    assert!(error(&image).contains("not an app image"));
+}
+
+#[test]
+fn refuses_a_bad_checksum() {
+    let mut image = Image::default().bytes();
+    let at = image.len() - CHECKSUM_FROM_END - 100;
+    image[at] ^= 0x01;
+    assert!(error(&image).contains("checksum"));
+}
+
+#[test]
+fn refuses_a_digest_mismatch() {
+    let mut image = Image::default().bytes();
+    *image.last_mut().unwrap() ^= 0x01;
+    assert!(error(&image).contains("sha-256"));
+}
+
+#[test]
+fn refuses_an_image_cut_short() {
+    let image = Image::default().bytes();
+    let image = &image[..image.len() - 1];
+    assert!(error(image).contains("cut short"));
+}
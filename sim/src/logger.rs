@@ -0,0 +1,39 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use std::time::Instant;
+
+/// Lines in the shape of the ESP-IDF logger: level, milliseconds since
+/// start and target.
+struct Logger(Instant);
+
+impl Log for Logger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let level = record.level().as_str().chars().next().unwrap_or('?');
+        eprintln!(
+            "{level} ({}) {}: {}",
+            self.0.elapsed().as_millis(),
+            record.target(),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+/// Debug like the firmware, `RUST_LOG=<level>` for another.
+pub fn init() {
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(LevelFilter::Debug);
+    let logger = Box::leak(Box::new(Logger(Instant::now())));
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(level);
+    }
+}
@@ -0,0 +1,231 @@
+//! The firmware on the host. The config, scheduler, telemetry, DNS cache
+//! and HTTP fetch modules are compiled from `firmware/src` as they are;
+//! WiFi, NVS, SNTP and the rest of the chip are the mocks in here, so that
+//! logic can be run and iterated on without flashing anything.
+//!
+//! `cargo run` in this directory. The config is `sim-config.json`, a JSON
+//! object by NVS key, the cache lives in `sim-data/`, and
+//! `SIM_WIFI_DROP=<secs>` drops the link that often. Where the device
+//! fetches once per boot, the simulator fetches every `FETCH_INTERVAL`;
+//! a `sim://<body>` download url needs no server.
+
+// the shared modules carry more than the simulation calls
+#![allow(dead_code)]
+
+use anyhow::{Context, Result};
+use clock::TimeSource;
+use config::Config;
+use events::Event;
+use jobs::{Job, Scheduler};
+use std::{
+    io::BufRead,
+    time::{Duration, Instant},
+};
+
+#[path = "../../firmware/src"]
+mod firmware {
+    pub mod cache;
+    pub mod clock;
+    pub mod config;
+    pub mod dns;
+    pub mod error;
+    pub mod events;
+    pub mod flags;
+    pub mod http;
+    pub mod jobs;
+    pub mod metrics;
+    pub mod ota;
+    pub mod ratelimit;
+    pub mod secret;
+    pub mod telemetry;
+    pub mod tls;
+}
+use firmware::{
+    cache, clock, config, dns, error, events, flags, http, jobs, metrics, ratelimit, secret,
+    telemetry, tls,
+};
+
+mod chip;
+mod device;
+#[cfg(test)]
+mod endpoints;
+mod fetcher;
+mod fs;
+mod heap;
+#[cfg(test)]
+mod image;
+mod logger;
+mod net;
+#[cfg(test)]
+mod overrides;
+#[cfg(test)]
+mod packets;
+mod power;
+mod runtime;
+mod sntp;
+mod store;
+
+const FETCH_INTERVAL: Duration = Duration::from_secs(60);
+const TELEMETRY_INTERVAL: Duration = Duration::from_secs(60);
+const UDP_SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+const INFLUX_SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+fn main() -> Result<()> {
+    logger::init();
+    runtime::init()?.block_on(run())
+}
+
+async fn run() -> Result<()> {
+    fs::mount()?;
+    let mut store = store::FileStore::open()?;
+    config::recover(&mut store)?;
+    let config = Config::load_from(&store)?;
+    flags::configure(&config)?;
+    dns::configure(&config)?;
+    ratelimit::configure(&config)?;
+    tls::allowlist::configure(&config)?;
+    tls::chain::configure(&config)?;
+    tls::ocsp::configure(&config)?;
+    tls::suites::configure(&config)?;
+    http::cookies::configure(
+        &config,
+        config::batch::Batched::new(store::FileStore::at(fs::path("cookies.json"))?),
+    )?;
+
+    runtime::spawn(net::run(net::SimWifi::from_env()?));
+    let server = config.ntp_server.clone();
+    runtime::spawn(async move {
+        events::wait_until(|state| state.net_up).await;
+        let mut ntp = sntp::Sntp::new(server);
+        if let Err(err) = ntp.sync().await {
+            log::warn!("couldn't update time over {}: {err:#}", ntp.name());
+        }
+    });
+
+    let jobs = Scheduler::new(runtime::TokioTimers);
+    let url = config.download_url.clone();
+    jobs.register(Job::new("fetch", FETCH_INTERVAL), move || {
+        let url = url.clone();
+        async move { download(&url).await }
+    });
+    jobs.register(Job::new("telemetry", TELEMETRY_INTERVAL), || async {
+        log::info!("telemetry {}", telemetry::snapshot());
+        Ok(())
+    });
+    if !config.udp_collector.is_empty() {
+        telemetry::udp::start(&config)?;
+        jobs.register(
+            Job::new("udp-telemetry", UDP_SAMPLE_INTERVAL),
+            telemetry::udp::sample,
+        );
+    }
+    telemetry::endpoints::configure(&config);
+    if !config.influx_url.is_empty() {
+        telemetry::influx::start(&config)?;
+        jobs.register(
+            Job::new("influx-telemetry", INFLUX_SAMPLE_INTERVAL),
+            telemetry::influx::sample,
+        );
+    }
+
+    let url = config.download_url.clone();
+    runtime::spawn(async move {
+        events::wait_until(|state| state.net_up).await;
+        if url.starts_with("https://") {
+            events::wait_until(|state| state.time_synced).await;
+        }
+        if let Err(err) = download(&url).await {
+            log::warn!("couldn't download file: {err:#}");
+        }
+    });
+
+    commands(store, config).await
+}
+
+async fn download(url: &str) -> Result<()> {
+    if url.starts_with("sim://") {
+        return fetch_with(&fetcher::Canned, url).await;
+    }
+    fetch_with(&http::client()?, url).await
+}
+
+/// The firmware's `fetch_with()`, minus the display.
+async fn fetch_with(fetcher: &impl http::HttpFetcher, url: &str) -> Result<()> {
+    let start = Instant::now();
+    events::publish(Event::FetchStarted);
+    let mut consumer = http::LogLines::default();
+    let result = async {
+        fetcher.fetch(url, &mut consumer).await?;
+        http::Consumer::finish(&mut consumer)
+    }
+    .await;
+    telemetry::set("fetch_ms", start.elapsed().as_millis() as u64);
+    events::publish(Event::FetchDone { ok: result.is_ok() });
+    telemetry::set(
+        "last_fetch",
+        match &result {
+            Ok(_) => String::from("ok"),
+            Err(err) => format!("{err:#}"),
+        },
+    );
+    result
+}
+
+/// A few console commands on stdin: `fetch [url]`, `status`, `drop` and
+/// `set <field> <value>`, which goes through `Config::apply_to()` the way
+/// the console's `config set` does.
+async fn commands(mut store: store::FileStore, config: Config) -> Result<()> {
+    let (lines, mut input) = tokio::sync::mpsc::unbounded_channel();
+    std::thread::Builder::new()
+        .name("stdin".into())
+        .spawn(move || {
+            for line in std::io::stdin().lock().lines().map_while(Result::ok) {
+                if lines.send(line).is_err() {
+                    break;
+                }
+            }
+        })
+        .context("couldn't spawn stdin reader")?;
+
+    while let Some(line) = input.recv().await {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {}
+            ["fetch"] => report(download(&config.download_url).await),
+            ["fetch", url] => report(download(url).await),
+            ["status"] => {
+                println!("{:#}", telemetry::snapshot());
+                runtime::log_tasks();
+            }
+            ["drop"] => net::drop_link(),
+            ["set", field, value @ ..] if !value.is_empty() => {
+                let value = value.join(" ");
+                let value = match config.redacted().get(*field) {
+                    Some(serde_json::Value::Number(_)) => match value.parse::<u64>() {
+                        Ok(number) => number.into(),
+                        Err(_) => {
+                            println!("config field {field} takes a number");
+                            continue;
+                        }
+                    },
+                    _ => serde_json::Value::String(value),
+                };
+                let mut changes = serde_json::Map::new();
+                changes.insert(field.to_string(), value);
+                match Config::apply_to(&mut store, &changes) {
+                    Ok(changed) if changed.is_empty() => println!("{field} unchanged"),
+                    Ok(_) => println!("{field} saved, it applies after a restart"),
+                    Err(err) => println!("{err:#}"),
+                }
+            }
+            _ => println!("commands: fetch [url], status, drop, set <field> <value>"),
+        }
+    }
+    Ok(())
+}
+
+fn report(result: Result<()>) {
+    if let Err(err) = result {
+        println!("{err:#}");
+    }
+}
@@ -0,0 +1,253 @@
+//! A pretend WiFi link behind the firmware's `NetworkManager`, and the
+//! bits of `net` the shared modules ask for.
+
+use crate::events::{self, Event};
+use anyhow::{Context, Result};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+#[path = "../../firmware/src/net/supervisor.rs"]
+mod supervisor;
+#[path = "../../firmware/src/net/transport.rs"]
+mod transport;
+
+use supervisor::Supervisor;
+pub use transport::NetworkManager;
+
+/// About what an association and DHCP take.
+const CONNECT_DELAY: Duration = Duration::from_millis(500);
+const RSSI: i8 = -55;
+
+static DROP: Notify = Notify::const_new();
+
+/// Connects after `CONNECT_DELAY` and stays up until dropped, by the
+/// `drop` command or every `SIM_WIFI_DROP` seconds.
+pub struct SimWifi {
+    drop_every: Option<Duration>,
+}
+
+impl SimWifi {
+    pub fn from_env() -> Result<Self> {
+        let drop_every = match std::env::var("SIM_WIFI_DROP") {
+            Ok(secs) => Some(Duration::from_secs(
+                secs.parse().context("SIM_WIFI_DROP takes seconds")?,
+            )),
+            Err(_) => None,
+        };
+        Ok(Self { drop_every })
+    }
+}
+
+impl NetworkManager for SimWifi {
+    fn name(&self) -> &'static str {
+        "sim-wifi"
+    }
+
+    async fn connect(&mut self) -> Result<()> {
+        tokio::time::sleep(CONNECT_DELAY).await;
+        Ok(())
+    }
+
+    async fn wait_disconnected(&mut self) -> Result<()> {
+        match self.drop_every {
+            Some(every) => tokio::select! {
+                () = tokio::time::sleep(every) => {}
+                () = DROP.notified() => {}
+            },
+            None => DROP.notified().await,
+        }
+        Ok(())
+    }
+}
+
+/// The firmware supervisor's loop for one link, with its backoff.
+pub async fn run(mut link: impl NetworkManager) {
+    let mut supervisor = Supervisor::default();
+    loop {
+        supervisor.connecting();
+        match link.connect().await {
+            Ok(()) => {
+                supervisor.connected();
+                log::info!("{} up", link.name());
+                events::publish(Event::NetUp);
+                if let Err(err) = link.wait_disconnected().await {
+                    log::warn!("{}: {err:#}", link.name());
+                }
+                supervisor.disconnected();
+                log::warn!("{} down", link.name());
+                events::publish(Event::NetDown);
+            }
+            Err(err) => {
+                let delay = supervisor.failed(&err);
+                log::warn!(
+                    "{} didn't connect, retrying in {delay:?}: {err:#}",
+                    link.name()
+                );
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+pub fn drop_link() {
+    DROP.notify_one();
+}
+
+pub fn rssi() -> Option<i8> {
+    events::state().net_up.then_some(RSSI)
+}
+
+pub fn family(ip: IpAddr) -> &'static str {
+    if ip.is_ipv6() {
+        "ipv6"
+    } else {
+        "ipv4"
+    }
+}
+
+/// None, so DNS goes through the host's resolver.
+pub fn dns_server() -> Option<Ipv4Addr> {
+    None
+}
+
+pub fn ipv6() -> Option<Ipv6Addr> {
+    None
+}
+
+pub mod socks {
+    /// Never set in the simulator, the host has its own proxy settings.
+    pub struct Proxy(String);
+
+    impl Proxy {
+        pub fn url(&self) -> String {
+            self.0.clone()
+        }
+    }
+
+    pub fn proxy() -> Option<&'static Proxy> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::supervisor::{LinkState, RECONNECT_MAX_DELAY, RECONNECT_MIN_DELAY};
+    use super::*;
+    use crate::error::{Failure, FirmwareError};
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    };
+    use tokio::time::Instant;
+
+    /// Fails with `failures` in turn, then stays up. Each attempt's time
+    /// goes into `attempts`.
+    struct Flaky {
+        failures: VecDeque<anyhow::Error>,
+        attempts: Arc<Mutex<Vec<Instant>>>,
+    }
+
+    impl Flaky {
+        fn new(
+            failures: impl IntoIterator<Item = anyhow::Error>,
+        ) -> (Self, Arc<Mutex<Vec<Instant>>>) {
+            let attempts = Arc::default();
+            let link = Self {
+                failures: failures.into_iter().collect(),
+                attempts: Arc::clone(&attempts),
+            };
+            (link, attempts)
+        }
+    }
+
+    impl NetworkManager for Flaky {
+        fn name(&self) -> &'static str {
+            "flaky"
+        }
+
+        async fn connect(&mut self) -> Result<()> {
+            self.attempts.lock().unwrap().push(Instant::now());
+            match self.failures.pop_front() {
+                Some(err) => Err(err),
+                None => Ok(()),
+            }
+        }
+
+        async fn wait_disconnected(&mut self) -> Result<()> {
+            std::future::pending().await
+        }
+    }
+
+    fn gaps(attempts: &Mutex<Vec<Instant>>) -> Vec<Duration> {
+        let attempts = attempts.lock().unwrap();
+        attempts.windows(2).map(|pair| pair[1] - pair[0]).collect()
+    }
+
+    fn permanent() -> anyhow::Error {
+        FirmwareError::Wifi(Failure::permanent(anyhow::anyhow!("ssid too long"))).into()
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let mut supervisor = Supervisor::default();
+        let delays: Vec<_> = (0..8)
+            .map(|_| supervisor.failed(&anyhow::anyhow!("no ap")))
+            .collect();
+        let secs: Vec<_> = delays.iter().map(Duration::as_secs).collect();
+        assert_eq!(secs, [1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(supervisor.state(), LinkState::Backoff(RECONNECT_MAX_DELAY));
+    }
+
+    #[test]
+    fn permanent_failure_waits_the_longest() {
+        let mut supervisor = Supervisor::default();
+        assert_eq!(supervisor.failed(&permanent()), RECONNECT_MAX_DELAY);
+        assert_eq!(
+            supervisor.failed(&anyhow::anyhow!("no ap")),
+            RECONNECT_MAX_DELAY
+        );
+    }
+
+    #[test]
+    fn connecting_resets_the_backoff() {
+        let mut supervisor = Supervisor::default();
+        assert_eq!(supervisor.state(), LinkState::Connecting);
+        supervisor.failed(&anyhow::anyhow!("no ap"));
+        supervisor.failed(&anyhow::anyhow!("no ap"));
+
+        supervisor.connecting();
+        supervisor.connected();
+        assert_eq!(supervisor.state(), LinkState::Up);
+        supervisor.disconnected();
+        assert_eq!(supervisor.state(), LinkState::Down);
+
+        supervisor.connecting();
+        assert_eq!(
+            supervisor.failed(&anyhow::anyhow!("no ap")),
+            RECONNECT_MIN_DELAY
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_retries_until_the_link_is_up() {
+        let failures = (0..3).map(|_| anyhow::anyhow!("no ap"));
+        let (link, attempts) = Flaky::new(failures);
+        tokio::spawn(run(link));
+
+        events::wait_until(|state| state.net_up).await;
+        let secs: Vec<_> = gaps(&attempts).iter().map(Duration::as_secs).collect();
+        assert_eq!(secs, [1, 2, 4]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_waits_out_a_permanent_failure() {
+        let (link, attempts) = Flaky::new([permanent()]);
+        tokio::spawn(run(link));
+
+        tokio::time::sleep(RECONNECT_MAX_DELAY * 2).await;
+        assert_eq!(gaps(&attempts), [RECONNECT_MAX_DELAY]);
+    }
+}
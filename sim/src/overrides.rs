@@ -0,0 +1,137 @@
+//! What goes in front of the stored config as it's read: the `boot_args`
+//! flags first, then the selected environment, then the stored fields.
+
+use crate::config::{environment::Overlay, Config, Store};
+use anyhow::Result;
+use serde_json::{json, Map, Value};
+
+/// A config namespace in memory.
+#[derive(Default)]
+struct Memory(Map<String, Value>);
+
+impl Memory {
+    fn of(values: Value) -> Self {
+        match values {
+            Value::Object(values) => Self(values),
+            _ => unreachable!("a test config is an object"),
+        }
+    }
+}
+
+impl Store for Memory {
+    fn get_str(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.0.get(key).and_then(Value::as_str).map(String::from))
+    }
+
+    fn get_u16(&self, key: &str) -> Result<Option<u16>> {
+        Ok(self
+            .0
+            .get(key)
+            .and_then(Value::as_u64)
+            .and_then(|value| u16::try_from(value).ok()))
+    }
+
+    fn set_str(&mut self, key: &str, value: &str) -> Result<()> {
+        self.0.insert(key.into(), value.into());
+        Ok(())
+    }
+
+    fn set_u16(&mut self, key: &str, value: u16) -> Result<()> {
+        self.0.insert(key.into(), value.into());
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<()> {
+        self.0.remove(key);
+        Ok(())
+    }
+}
+
+fn flags(args: &str) -> Result<Map<String, Value>> {
+    Config {
+        boot_args: args.into(),
+        ..Config::default()
+    }
+    .boot_flags()
+}
+
+#[test]
+fn flags_set_clear_and_alias_fields() {
+    let flags = flags("--log-level=trace --sleep=30 --no-device-name --bench").unwrap();
+    assert_eq!(flags["log_level"], "trace");
+    assert_eq!(flags["sleep_secs"], 30);
+    assert_eq!(flags["device_name"], "");
+    assert_eq!(flags["bench"], "on");
+}
+
+#[test]
+fn flags_refuse_what_they_cant_set() {
+    for args in [
+        "--console-password=hunter22",
+        "--trigger_secret=x",
+        "--backup-secret=x",
+        "--config-url=https://example.com",
+        "--environment=prod",
+        "--sleep=soon",
+        "--no-such-field",
+        "log-level=trace",
+    ] {
+        assert!(flags(args).is_err(), "{args}");
+    }
+}
+
+#[test]
+fn flags_win_over_the_environment_over_the_store() {
+    let store = Memory::of(json!({
+        "download_url": "https://stored.example.com",
+        "log_level": "warn",
+        "device_name": "stored",
+        "environment": "staging",
+        "environments": json!({
+            "staging": {
+                "download_url": "https://staging.example.com",
+                "log_level": "info",
+            },
+        })
+        .to_string(),
+        "boot_args": "--level=trace",
+    }));
+    let config = Config::load_from(&store).unwrap();
+    assert_eq!(config.log_level, "trace");
+    assert_eq!(config.download_url, "https://staging.example.com");
+    assert_eq!(config.device_name, "stored");
+}
+
+#[test]
+fn bad_boot_args_are_ignored() {
+    let store = Memory::of(json!({ "log_level": "warn", "boot_args": "--jwt-key=x" }));
+    assert_eq!(Config::load_from(&store).unwrap().log_level, "warn");
+}
+
+#[test]
+fn environments_refuse_what_they_cant_set() {
+    let environments = json!({
+        "local": { "config_url": "https://example.com" },
+        "selecting": { "environment": "other" },
+        "unknown": { "no_such_field": "x" },
+        "mistyped": { "sleep_secs": "30" },
+    })
+    .to_string();
+    let store = Memory::of(json!({ "environments": environments }));
+    for name in ["local", "selecting", "unknown", "mistyped", "undefined"] {
+        assert!(Overlay::new(&store, name).is_err(), "{name}");
+    }
+}
+
+#[test]
+fn overlay_reads_through_and_refuses_writes() {
+    let store = Memory::of(json!({ "log_level": "warn", "sleep_secs": 10 }));
+    let mut overlay = Overlay::with(&store, Map::from_iter([("sleep_secs".into(), json!(60))]));
+    assert_eq!(overlay.get_u16("sleep_secs").unwrap(), Some(60));
+    assert_eq!(
+        overlay.get_str("log_level").unwrap().as_deref(),
+        Some("warn")
+    );
+    assert!(overlay.set_str("log_level", "info").is_err());
+    assert!(overlay.remove("log_level").is_err());
+}
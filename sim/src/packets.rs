@@ -0,0 +1,168 @@
+//! The firmware's DNS answer parsing against hand-built responses: the
+//! records it reads, compressed names, and packets cut short or pointing
+//! in circles, which have to fail rather than panic or spin.
+
+use crate::firmware::dns::{self, TYPE_A, TYPE_AAAA};
+use std::{net::IpAddr, time::Duration};
+
+const TYPE_SRV: u16 = 33;
+/// Where the question's name starts, right after the header.
+const QUESTION: u16 = 12;
+
+fn name(labels: &[&str]) -> Vec<u8> {
+    let mut name = Vec::new();
+    for label in labels {
+        name.push(label.len() as u8);
+        name.extend_from_slice(label.as_bytes());
+    }
+    name.push(0);
+    name
+}
+
+fn pointer(to: u16) -> [u8; 2] {
+    (0xc000 | to).to_be_bytes()
+}
+
+/// A response to `question`, its answers named by a pointer back to it.
+fn response(rcode: u8, question: &[&str], answers: &[(u16, u32, Vec<u8>)]) -> Vec<u8> {
+    let mut message = vec![0x12, 0x34, 0x81, 0x80 | rcode, 0, 1];
+    message.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+    message.extend_from_slice(&[0; 4]);
+    message.extend_from_slice(&name(question));
+    message.extend_from_slice(&[0, 1, 0, 1]);
+    for (kind, ttl, data) in answers {
+        message.extend_from_slice(&pointer(QUESTION));
+        message.extend_from_slice(&kind.to_be_bytes());
+        message.extend_from_slice(&[0, 1]);
+        message.extend_from_slice(&ttl.to_be_bytes());
+        message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        message.extend_from_slice(data);
+    }
+    message
+}
+
+fn srv(priority: u16, weight: u16, port: u16, target: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for field in [priority, weight, port] {
+        data.extend_from_slice(&field.to_be_bytes());
+    }
+    data.extend_from_slice(target);
+    data
+}
+
+#[test]
+fn reads_addresses_with_the_shortest_ttl() {
+    let message = response(
+        0,
+        &["example", "com"],
+        &[
+            (TYPE_A, 300, vec![192, 0, 2, 1]),
+            (5, 10, name(&["alias", "example", "com"])),
+            (
+                TYPE_AAAA,
+                60,
+                [[0x20, 0x01, 0x0d, 0xb8], [0; 4], [0; 4], [0, 0, 0, 1]].concat(),
+            ),
+        ],
+    );
+    assert_eq!(dns::answers(&message).unwrap().len(), 3);
+    let (addrs, ttl) = dns::parse(&message).unwrap();
+    assert_eq!(
+        addrs,
+        [
+            "192.0.2.1".parse::<IpAddr>().unwrap(),
+            "2001:db8::1".parse().unwrap()
+        ]
+    );
+    assert_eq!(ttl, Duration::from_secs(60));
+}
+
+#[test]
+fn reads_srv_targets_through_pointers() {
+    // "lan" in front of the question's "example.com", past "_http"
+    let lan = [&name(&["lan"])[..4], &pointer(QUESTION + 6)].concat();
+    let message = response(
+        0,
+        &["_http", "example", "com"],
+        &[
+            (TYPE_SRV, 120, srv(10, 5, 8080, &lan)),
+            (TYPE_SRV, 30, srv(20, 0, 0, &[0])),
+        ],
+    );
+    let (records, ttl) = dns::parse_srv(&message).unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(
+        (records[0].priority, records[0].weight, records[0].port),
+        (10, 5, 8080)
+    );
+    assert_eq!(records[0].target, "lan.example.com");
+    // `.`, the service isn't there
+    assert_eq!(records[1].target, "");
+    assert_eq!(ttl, Duration::from_secs(30));
+}
+
+#[test]
+fn lowercases_names() {
+    let message = response(0, &["Example", "COM"], &[]);
+    assert_eq!(
+        dns::read_name(&message, QUESTION.into()).unwrap(),
+        "example.com"
+    );
+}
+
+#[test]
+fn refuses_an_error_code() {
+    let message = response(3, &["example", "com"], &[]);
+    let err = dns::answers(&message).err().unwrap();
+    assert!(format!("{err:#}").contains("error code 3"));
+}
+
+#[test]
+fn refuses_every_truncation() {
+    let message = response(
+        0,
+        &["_http", "example", "com"],
+        &[
+            (TYPE_A, 300, vec![192, 0, 2, 1]),
+            (TYPE_SRV, 120, srv(10, 5, 8080, &name(&["lan"]))),
+        ],
+    );
+    assert!(dns::parse(&message).is_ok());
+    for len in 0..message.len() {
+        assert!(dns::parse(&message[..len]).is_err(), "cut at {len}");
+        assert!(dns::parse_srv(&message[..len]).is_err(), "cut at {len}");
+    }
+}
+
+#[test]
+fn refuses_a_name_cut_short() {
+    let message = response(0, &["example", "com"], &[]);
+    // into "example", past the end
+    assert!(dns::read_name(&message[..16], QUESTION.into()).is_err());
+    let pointing_out = [&message[..], &pointer(0x3fff)].concat();
+    assert!(dns::read_name(&pointing_out, message.len()).is_err());
+}
+
+#[test]
+fn refuses_compression_loops() {
+    let mut message = response(0, &["example", "com"], &[]);
+    let at = message.len();
+    // a pointer to itself, and two pointing at each other
+    message.extend_from_slice(&pointer(at as u16));
+    message.extend_from_slice(&pointer(at as u16 + 4));
+    message.extend_from_slice(&pointer(at as u16 + 2));
+    for start in [at, at + 2] {
+        let err = dns::read_name(&message, start).unwrap_err();
+        assert!(format!("{err:#}").contains("too many pointers"));
+    }
+
+    // an SRV target pointing at itself
+    let mut message = response(
+        0,
+        &["example", "com"],
+        &[(TYPE_SRV, 60, srv(1, 1, 1, &[0, 0]))],
+    );
+    let at = message.len() - 2;
+    message[at..].copy_from_slice(&pointer(at as u16));
+    assert!(dns::parse_srv(&message).is_err());
+}
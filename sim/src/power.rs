@@ -0,0 +1,17 @@
+/// Only `Event::Wake` needs it, the simulator never sleeps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WakeCause {
+    PowerOn,
+}
+
+/// The CPU runs flat out on the host anyway.
+pub struct Boost(());
+
+pub fn boost() -> Boost {
+    Boost(())
+}
+
+// a guard like the firmware's, which the fetch drops early
+impl Drop for Boost {
+    fn drop(&mut self) {}
+}
@@ -0,0 +1,56 @@
+//! tokio's own multi-threaded runtime in place of the firmware's, with the
+//! same blocking pool and task table.
+
+use crate::jobs::{Timer, Timers};
+use anyhow::Result;
+use std::{future::Future, time::Duration};
+
+#[path = "../../firmware/src/runtime/blocking.rs"]
+mod blocking;
+#[path = "../../firmware/src/runtime/tasks.rs"]
+mod tasks;
+
+pub use blocking::run_blocking;
+pub use tasks::{log_tasks, spawn_named};
+
+const BLOCKING_THREADS: usize = 2;
+const BLOCKING_STACK_SIZE: usize = 256 * 1024;
+
+pub fn init() -> Result<tokio::runtime::Runtime> {
+    blocking::start(BLOCKING_THREADS, BLOCKING_STACK_SIZE)?;
+    Ok(tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?)
+}
+
+pub fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(future);
+}
+
+pub async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await;
+}
+
+/// Job timers on tokio's clock.
+#[derive(Clone, Copy)]
+pub struct TokioTimers;
+
+pub struct TokioTimer;
+
+impl Timers for TokioTimers {
+    type Timer = TokioTimer;
+
+    fn timer(&self) -> Result<TokioTimer> {
+        Ok(TokioTimer)
+    }
+}
+
+impl Timer for TokioTimer {
+    async fn after(&mut self, duration: Duration) -> Result<()> {
+        tokio::time::sleep(duration).await;
+        Ok(())
+    }
+}
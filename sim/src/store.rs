@@ -0,0 +1,76 @@
+use crate::config::Store;
+use anyhow::{Context, Result};
+use serde_json::{Map, Value};
+use std::path::PathBuf;
+
+const DEFAULT_PATH: &str = "sim-config.json";
+
+/// The config NVS namespace as a JSON object on disk, `sim-config.json` or
+/// `SIM_CONFIG`, written back on every change. A missing file is an empty
+/// namespace, so everything starts at its default.
+pub struct FileStore {
+    path: PathBuf,
+    values: Map<String, Value>,
+}
+
+impl FileStore {
+    pub fn open() -> Result<Self> {
+        Self::at(
+            std::env::var_os("SIM_CONFIG")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_PATH)),
+        )
+    }
+
+    /// Another namespace, in its own file.
+    pub fn at(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let values = match std::fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json).with_context(|| format!("{path:?}"))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Map::new(),
+            Err(err) => return Err(err).with_context(|| format!("{path:?}")),
+        };
+        Ok(Self { path, values })
+    }
+
+    fn save(&self) -> Result<()> {
+        let json = serde_json::to_vec_pretty(&self.values)?;
+        std::fs::write(&self.path, json).with_context(|| format!("{:?}", self.path))
+    }
+}
+
+impl Store for FileStore {
+    fn get_str(&self, key: &str) -> Result<Option<String>> {
+        match self.values.get(key) {
+            None => Ok(None),
+            Some(Value::String(value)) => Ok(Some(value.clone())),
+            Some(_) => anyhow::bail!("config key {key} isn't a string"),
+        }
+    }
+
+    fn get_u16(&self, key: &str) -> Result<Option<u16>> {
+        match self.values.get(key) {
+            None => Ok(None),
+            Some(value) => value
+                .as_u64()
+                .and_then(|value| u16::try_from(value).ok())
+                .map(Some)
+                .with_context(|| format!("config key {key} isn't a u16")),
+        }
+    }
+
+    fn set_str(&mut self, key: &str, value: &str) -> Result<()> {
+        self.values.insert(key.into(), value.into());
+        self.save()
+    }
+
+    fn set_u16(&mut self, key: &str, value: u16) -> Result<()> {
+        self.values.insert(key.into(), value.into());
+        self.save()
+    }
+
+    fn remove(&mut self, key: &str) -> Result<()> {
+        self.values.remove(key);
+        self.save()
+    }
+}